@@ -0,0 +1,29 @@
+/* C declarations for the library's C ABI, defined in src/ffi.rs.
+ *
+ * Link against libimage_stats (built by `cargo build --release` as a cdylib). */
+
+#ifndef GEOTIF_H
+#define GEOTIF_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Converts the tif or zip at `input_path` with the options in `options_json`, writing to
+ * `output_path`. `options_json` is a JSON object of the command line's long flag names to
+ * their values, such as {"group": 0.5, "format": "fgb", "keep-zero": true}, or NULL for
+ * the defaults. `output_path` may be NULL for the path the output template gives.
+ *
+ * Returns 0 on success, or -1 with the reason available from geotif_last_error(). */
+int geotif_process(const char *input_path, const char *options_json, const char *output_path);
+
+/* The reason the calling thread's last call to geotif_process() failed, or NULL if it
+ * succeeded. The string is owned by the library and stays valid until the thread's next
+ * call. */
+const char *geotif_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
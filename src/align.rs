@@ -0,0 +1,151 @@
+//! Snapping the grouping grid to an existing dataset with `--align-to`, so that outputs
+//! converted separately share cell positions and can be joined on them.
+
+use crate::{
+    crs::Crs,
+    georef::GeoTransform,
+    group::{CellSize, LonLat},
+    load_tif_contents,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_schema::Schema;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::{fs::File, io::Cursor, path::Path};
+use tiff::decoder::{Decoder, Limits};
+
+/// Metadata key of the grid's cell size on a grouped output's position columns.
+pub const GRID_SIZE: &str = "grid_size";
+/// Metadata key of the grid origin's coordinate along each position column's axis.
+pub const GRID_ORIGIN: &str = "grid_origin";
+
+/// The cell size and origin of the grid `reference` is on: a tif's pixel grid, or the
+/// grid a parquet output of this tool was grouped on. `src_crs` and `dst_crs` are the
+/// conversion's, and a tif must be in the CRS positions are written in.
+pub fn reference_grid(
+    reference: &Path,
+    src_crs: Option<Crs>,
+    dst_crs: Option<Crs>,
+) -> Result<(CellSize, LonLat)> {
+    match reference.extension().and_then(|e| e.to_str()) {
+        Some("parquet") => parquet_grid(reference),
+        _ => tif_grid(reference, src_crs, dst_crs),
+    }
+    .with_context(|| format!("Could not align to {}", reference.display()))
+}
+
+fn tif_grid(path: &Path, src_crs: Option<Crs>, dst_crs: Option<Crs>) -> Result<(CellSize, LonLat)> {
+    let contents = load_tif_contents(path)?;
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    // Positions are lon/lat whenever either CRS is given, so the reference's own CRS is
+    // read from its GeoKeys then too.
+    let dst = dst_crs.or(src_crs.map(|_| Crs::wgs84()));
+    let transform = GeoTransform::resolve(&mut decoder, None, dst)?;
+    if let Some((src, dst)) = transform.crs().filter(|(src, dst)| src != dst) {
+        bail!(
+            "Its pixels are on a grid in {}, but positions are written in {}",
+            src,
+            dst
+        );
+    }
+    let (width, height) = transform.pixel_size();
+    let bounds = transform.bounds();
+    Ok((
+        CellSize {
+            lon: width,
+            lat: height,
+        },
+        LonLat {
+            lon: bounds.west,
+            lat: bounds.south,
+        },
+    ))
+}
+
+fn parquet_grid(path: &Path) -> Result<(CellSize, LonLat)> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    recorded_grid(builder.schema())
+}
+
+/// The cell size and origin of the grid an output was grouped on, from the metadata of
+/// its position columns.
+pub(crate) fn recorded_grid(schema: &Schema) -> Result<(CellSize, LonLat)> {
+    let (x, y) = (schema.field(0), schema.field(1));
+    let missing =
+        || anyhow!("It has no grid metadata, as it wasn't grouped with --group by this version");
+    let number = |key: &str, metadata: &std::collections::HashMap<String, String>| {
+        metadata
+            .get(key)
+            .ok_or_else(missing)?
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Its {} metadata is not a number", key))
+    };
+    Ok((
+        CellSize {
+            lon: number(GRID_SIZE, x.metadata())?,
+            lat: number(GRID_SIZE, y.metadata())?,
+        },
+        LonLat {
+            lon: number(GRID_ORIGIN, x.metadata())?,
+            lat: number(GRID_ORIGIN, y.metadata())?,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reference_grid;
+    use crate::{
+        group::{CellSize, LonLat},
+        processor::Processor,
+    };
+    use std::fs::File;
+    use tiff::encoder::{colortype::GrayI32, TiffEncoder};
+
+    #[test]
+    fn test_reference_grid() {
+        let dir = std::env::temp_dir().join(format!("align-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tif = dir.join("reference.tif");
+        TiffEncoder::new(File::create(&tif).unwrap())
+            .unwrap()
+            .write_image::<GrayI32>(36, 17, &[1; 36 * 17])
+            .unwrap();
+        // Without georeferencing, the tif is a grid over the whole world.
+        let (size, origin) = reference_grid(&tif, None, None).unwrap();
+        assert_eq!(size, CellSize::from(10.0));
+        assert_eq!((origin.lon, origin.lat), (-180.0, -85.0));
+
+        // A grouped output records its grid, which a conversion aligned to it reuses.
+        let output = dir.join("reference.parquet");
+        let origin = LonLat {
+            lon: 0.25,
+            lat: -0.5,
+        };
+        let cells = CellSize {
+            lon: 15.0,
+            lat: 7.5,
+        };
+        Processor::builder()
+            .group(cells)
+            .grid_origin(origin)
+            .build()
+            .unwrap()
+            .process_to(&tif, &output)
+            .unwrap();
+        let (size, origin) = reference_grid(&output, None, None).unwrap();
+        assert_eq!((size, origin.lon, origin.lat), (cells, 0.25, -0.5));
+        let aligned = Processor::builder().align_to(&output).build().unwrap();
+        assert_eq!(aligned.options().group, Some(cells));
+        assert_eq!(aligned.options().grid_origin.lon, 0.25);
+
+        // An ungrouped output has no grid to align to.
+        let pixels = dir.join("pixels.parquet");
+        Processor::builder()
+            .build()
+            .unwrap()
+            .process_to(&tif, &pixels)
+            .unwrap();
+        assert!(reference_grid(&pixels, None, None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
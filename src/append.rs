@@ -0,0 +1,165 @@
+//! Growing a parquet dataset a run at a time with `--append`, such as adding each month's
+//! rasters as they arrive instead of rebuilding the whole dataset. Each input becomes a
+//! new file in the dataset's directory, which must have the same columns as the files
+//! already there. With `--dedupe`, rows at positions the dataset already holds from the
+//! same input, going by the source recorded in each file's footer, are left out.
+
+use crate::manifest::SOURCE_KEY;
+use anyhow::{bail, Context, Result};
+use arrow_array::{cast::as_primitive_array, types::Float32Type, BooleanArray, RecordBatch};
+use arrow_schema::{DataType, Schema};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ProjectionMask},
+    file::reader::{FileReader, SerializedFileReader},
+};
+use std::{
+    collections::HashSet,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+/// How files added to a dataset are named, so that each run adds new ones.
+pub const NAME: &str = "{stem}-{timestamp}.{ext}";
+
+/// The parquet files of the dataset at `dir`, in order of name.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Could not list {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "parquet") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Checks that rows of `schema` can join the dataset at `dir`, whose files must all have
+/// the same column names and types, creating the directory if it doesn't exist yet.
+pub fn check_schema(dir: &Path, schema: &Schema) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    let Some(existing) = files(dir)?.into_iter().next() else {
+        return Ok(());
+    };
+    let columns = |schema: &Schema| -> Vec<(String, DataType)> {
+        schema
+            .fields()
+            .iter()
+            .map(|field| (field.name().clone(), field.data_type().clone()))
+            .collect()
+    };
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&existing)?)?;
+    let (theirs, ours) = (columns(builder.schema()), columns(schema));
+    if theirs != ours {
+        let describe = |columns: &[(String, DataType)]| {
+            columns
+                .iter()
+                .map(|(name, data_type)| format!("{} {}", name, data_type))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        bail!(
+            "The output's columns ({}) don't match those of {} in the dataset ({})",
+            describe(&ours),
+            existing.display(),
+            describe(&theirs)
+        );
+    }
+    Ok(())
+}
+
+/// The positions of the rows the dataset at `dir` holds from the input named `source`.
+pub fn seen(dir: &Path, source: &str) -> Result<HashSet<(u32, u32)>> {
+    let mut seen = HashSet::new();
+    for path in files(dir)? {
+        let reader = SerializedFileReader::new(File::open(&path)?)?;
+        let footer = reader.metadata().file_metadata().key_value_metadata();
+        let from_source = footer.is_some_and(|pairs| {
+            pairs
+                .iter()
+                .any(|pair| pair.key == SOURCE_KEY && pair.value.as_deref() == Some(source))
+        });
+        if !from_source {
+            continue;
+        }
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?;
+        let mask = ProjectionMask::roots(builder.parquet_schema(), [0, 1]);
+        for batch in builder.with_projection(mask).build()? {
+            seen.extend(positions(&batch?)?);
+        }
+    }
+    Ok(seen)
+}
+
+/// The bits of the positions in the first two columns of `batch`.
+fn positions(batch: &RecordBatch) -> Result<impl Iterator<Item = (u32, u32)>> {
+    let float = |i: usize| arrow_cast::cast(batch.column(i), &DataType::Float32);
+    let (xs, ys) = (float(0)?, float(1)?);
+    let (xs, ys) = (
+        as_primitive_array::<Float32Type>(&xs).values().to_vec(),
+        as_primitive_array::<Float32Type>(&ys).values().to_vec(),
+    );
+    Ok(xs
+        .into_iter()
+        .zip(ys)
+        .map(|(x, y)| (x.to_bits(), y.to_bits())))
+}
+
+/// Leaves out the rows of `batch` at positions in `seen`.
+pub fn dedupe(batch: &RecordBatch, seen: &HashSet<(u32, u32)>) -> Result<RecordBatch> {
+    if seen.is_empty() {
+        return Ok(batch.clone());
+    }
+    let keep: BooleanArray = positions(batch)?
+        .map(|position| Some(!seen.contains(&position)))
+        .collect();
+    Ok(arrow_select::filter::filter_record_batch(batch, &keep)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_schema, dedupe, seen};
+    use crate::output::{write_parquet_with_footer, Codec};
+    use arrow_array::{ArrayRef, Float32Array, Float64Array, RecordBatch};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_append() {
+        let dir = std::env::temp_dir().join(format!("append-test-{}", std::process::id()));
+        let column = |values: Vec<f32>| Arc::new(Float32Array::from(values)) as ArrayRef;
+        let batch = RecordBatch::try_from_iter([
+            ("lon", column(vec![1.0, 2.0, 3.0])),
+            ("lat", column(vec![4.0, 5.0, 6.0])),
+            ("value", column(vec![7.0, 8.0, 9.0])),
+        ])
+        .unwrap();
+        check_schema(&dir, &batch.schema()).unwrap();
+        let source = |name: &str| vec![("image_stats:source".to_string(), name.to_string())];
+        write_parquet_with_footer(
+            &dir.join("a-1.parquet"),
+            &batch.slice(0, 2),
+            Codec::Snappy.into(),
+            source("a.tif"),
+        )
+        .unwrap();
+
+        // Only rows from the same source are duplicates.
+        assert!(seen(&dir, "b.tif").unwrap().is_empty());
+        let kept = dedupe(&batch, &seen(&dir, "a.tif").unwrap()).unwrap();
+        assert_eq!(kept.num_rows(), 1);
+
+        check_schema(&dir, &batch.schema()).unwrap();
+        let other = RecordBatch::try_from_iter([(
+            "lon",
+            Arc::new(Float64Array::from(vec![1.0])) as ArrayRef,
+        )])
+        .unwrap();
+        assert!(check_schema(&dir, &other.schema()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,533 @@
+//! Reading the tifs inside `.zip`, `.tar`, `.tar.gz` and `.7z` inputs, and choosing among
+//! them for providers that bundle data and quality rasters together, and inflating
+//! `.tif.gz` inputs. A tif inside an archive is given as a path through it, such as `data.zip/rasters/qa.tif`, and its outputs are
+//! named `data.qa.parquet`.
+
+use crate::{
+    failure::{
+        Classified,
+        FailureClass::{BadInput, Unsupported},
+    },
+    metadata::key_matches,
+    tar,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use sevenz_rust::{Password, SevenZReader};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+use zip::ZipArchive;
+
+/// The kinds of archive inputs can be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+    SevenZip,
+}
+
+impl Kind {
+    const SUFFIXES: [(&'static str, Kind); 5] = [
+        (".zip", Kind::Zip),
+        (".tar", Kind::Tar),
+        (".tar.gz", Kind::TarGz),
+        (".tgz", Kind::TarGz),
+        (".7z", Kind::SevenZip),
+    ];
+
+    /// The kind of archive at `path`, by its file name.
+    pub fn of(path: &Path) -> Option<Kind> {
+        split_name(path).map(|(_, kind)| kind)
+    }
+
+    /// The kind of archive `contents` is, by its first bytes, for stdin, which has no file
+    /// name to tell by.
+    pub fn sniff(contents: &[u8]) -> Option<Kind> {
+        match contents {
+            [b'P', b'K', 3, 4, ..] => Some(Kind::Zip),
+            // A gzipped tar, rather than a gzipped tif, has a tar header once inflated.
+            [0x1f, 0x8b, ..] => {
+                let mut header = vec![];
+                let _ = GzDecoder::new(contents).take(262).read_to_end(&mut header);
+                (header.get(257..262) == Some(b"ustar")).then_some(Kind::TarGz)
+            }
+            [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c, ..] => Some(Kind::SevenZip),
+            _ if contents.get(257..262) == Some(b"ustar") => Some(Kind::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Zip => "zip",
+            Kind::Tar => "tar",
+            Kind::TarGz => "tar.gz",
+            Kind::SevenZip => "7z",
+        }
+    }
+}
+
+/// Splits a file name into its stem and the kind of archive its suffix names.
+fn split_name(path: &Path) -> Option<(String, Kind)> {
+    let name = path.file_name()?.to_string_lossy();
+    let lower = name.to_ascii_lowercase();
+    Kind::SUFFIXES.iter().find_map(|&(suffix, kind)| {
+        lower
+            .ends_with(suffix)
+            .then(|| (name[..name.len() - suffix.len()].to_string(), kind))
+    })
+}
+
+/// Whether `path` names a gzipped tif, such as `data.tif.gz`.
+pub fn is_gzipped_tif(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy().to_ascii_lowercase();
+        name.ends_with(".tif.gz") || name.ends_with(".tiff.gz")
+    })
+}
+
+/// Whether `contents` is gzipped.
+pub fn is_gzip(contents: &[u8]) -> bool {
+    contents.starts_with(&[0x1f, 0x8b])
+}
+
+/// Inflates the gzipped `reader` into memory as it is read, as the decoder needs to seek
+/// around the whole tif.
+pub fn gunzip(reader: impl Read) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    if let Err(err) = GzDecoder::new(reader).read_to_end(&mut contents) {
+        bail!(Classified::new(
+            BadInput,
+            format!("Could not inflate the gzipped tif: {}", err)
+        ));
+    }
+    Ok(contents)
+}
+
+/// Whether `name`, inside an archive, is a tif.
+pub fn is_tif(name: &str) -> bool {
+    name.ends_with(".tif") || name.ends_with(".tiff")
+}
+
+/// Splits a path through an archive into the archive and the name of the member inside.
+pub fn split(path: &Path) -> Option<(&Path, String)> {
+    let archive = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| Kind::of(ancestor).is_some() && ancestor.is_file())?;
+    let member = path
+        .strip_prefix(archive)
+        .ok()?
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?
+        .join("/");
+    Some((archive, member))
+}
+
+/// The file on disk that holds `path`: the archive for a member, or else `path` itself.
+pub fn file(path: &Path) -> &Path {
+    split(path).map_or(path, |(archive, _)| archive)
+}
+
+/// The path outputs of `input_path` are named after: `data.qa.tif` next to `data.zip` for
+/// its member `rasters/qa.tif`, `data.tif` for the single tif in `data.tar.gz` or for
+/// `data.tif.gz`, or else the input.
+pub fn name(input_path: &Path) -> Cow<'_, Path> {
+    if is_gzipped_tif(input_path) {
+        return Cow::Owned(input_path.with_extension(""));
+    }
+    let (archive, member) = match split(input_path) {
+        Some((archive, member)) => (archive, Some(member)),
+        None => (input_path, None),
+    };
+    let Some((stem, _)) = split_name(archive) else {
+        return Cow::Borrowed(input_path);
+    };
+    let name = match member.as_deref().map(Path::new).and_then(Path::file_stem) {
+        Some(member) => format!("{}.{}.tif", stem, member.to_string_lossy()),
+        None => format!("{}.tif", stem),
+    };
+    Cow::Owned(archive.with_file_name(name))
+}
+
+/// Reads the member `name` of the archive at `path`.
+pub fn read_member(path: &Path, name: &str) -> Result<Vec<u8>> {
+    let kind = Kind::of(path).context("Not an archive")?;
+    match read(kind, open(path)?, Some(name))? {
+        Some(contents) => Ok(contents),
+        None => bail!(Classified::new(
+            BadInput,
+            format!("{} holds no {}", path.display(), name)
+        )),
+    }
+}
+
+/// Reads the single tif in an archive, or the member `name` if given, which is `None` if
+/// there is no such member.
+pub fn read(kind: Kind, reader: impl Read + Seek, name: Option<&str>) -> Result<Option<Vec<u8>>> {
+    let mut contents = None;
+    match kind {
+        Kind::Zip => {
+            let mut zip = ZipArchive::new(reader)?;
+            let members = zip_members(&zip);
+            let name = match name {
+                Some(name) => name.to_string(),
+                None => single(
+                    members
+                        .iter()
+                        .map(|(member, _)| member)
+                        .filter(|n| is_tif(n))
+                        .cloned(),
+                )?,
+            };
+            let Some((_, stored)) = members.iter().find(|(member, _)| *member == name) else {
+                return Ok(None);
+            };
+            let mut member = zip.by_name(stored)?;
+            let mut data = vec![];
+            member.read_to_end(&mut data)?;
+            contents = Some(data);
+        }
+        Kind::Tar | Kind::TarGz | Kind::SevenZip => each_member(kind, reader, |member, data| {
+            if name.map_or(!is_tif(member), |name| member != name) {
+                return Ok(false);
+            }
+            if contents.is_some() {
+                return Err(multiple_tifs());
+            }
+            let mut tif = vec![];
+            data.read_to_end(&mut tif)?;
+            contents = Some(tif);
+            // Without a name, read on to make sure no other tif follows.
+            Ok(name.is_some())
+        })?,
+    }
+    if name.is_none() && contents.is_none() {
+        return Err(no_tifs());
+    }
+    Ok(contents)
+}
+
+/// The one name of `names`, failing if there isn't exactly one.
+fn single(mut names: impl Iterator<Item = String>) -> Result<String> {
+    match (names.next(), names.next()) {
+        (None, _) => Err(no_tifs()),
+        (Some(name), None) => Ok(name),
+        (Some(_), Some(_)) => Err(multiple_tifs()),
+    }
+}
+
+fn no_tifs() -> anyhow::Error {
+    anyhow!(Classified::new(BadInput, "No tif files found archive"))
+}
+
+fn multiple_tifs() -> anyhow::Error {
+    anyhow!(Classified::new(
+        BadInput,
+        "Multiple tif files found in archive, pick one with --archive-member or pass \
+         --all-members"
+    ))
+}
+
+/// The names of the tifs in an archive.
+fn tifs(kind: Kind, reader: impl Read + Seek) -> Result<Vec<String>> {
+    match kind {
+        Kind::Zip => Ok(zip_members(&ZipArchive::new(reader)?)
+            .into_iter()
+            .map(|(member, _)| member)
+            .filter(|n| is_tif(n))
+            .collect()),
+        Kind::Tar | Kind::TarGz => {
+            let mut names = vec![];
+            each_member(kind, reader, |name, _| {
+                if is_tif(name) {
+                    names.push(name.to_string());
+                }
+                Ok(false)
+            })?;
+            Ok(names)
+        }
+        // 7z archives list their files up front, so they needn't be decompressed.
+        Kind::SevenZip => Ok(seven_zip(reader)?
+            .archive()
+            .files
+            .iter()
+            .filter(|file| !file.is_directory)
+            .filter_map(|file| member_name(&file.name))
+            .filter(|name| is_tif(name))
+            .collect()),
+    }
+}
+
+/// The files in a zip by their [`member_name`], each with the name the zip stores it under.
+/// Those whose names lead outside the archive are left out.
+fn zip_members<R: Read + Seek>(zip: &ZipArchive<R>) -> Vec<(String, String)> {
+    zip.file_names()
+        .filter(|stored| !stored.ends_with('/'))
+        .filter_map(|stored| Some((member_name(stored)?, stored.to_string())))
+        .collect()
+}
+
+/// Calls `visit` with the name of each file in a tar or 7z archive and a reader of its
+/// contents, until `visit` returns true or the archive ends.
+fn each_member(
+    kind: Kind,
+    reader: impl Read + Seek,
+    mut visit: impl FnMut(&str, &mut dyn Read) -> Result<bool>,
+) -> Result<()> {
+    match kind {
+        Kind::TarGz => tar::each(GzDecoder::new(reader), visit),
+        Kind::SevenZip => {
+            let mut outcome = Ok(false);
+            seven_zip(reader)?
+                .for_each_entries(|file, data| {
+                    if file.is_directory {
+                        return Ok(true);
+                    }
+                    outcome = match member_name(&file.name) {
+                        Some(name) => visit(&name, data),
+                        None => Ok(false),
+                    };
+                    // Files in a solid block follow each other, so whatever of one wasn't
+                    // read has to be before the next can be.
+                    if matches!(outcome, Ok(false)) {
+                        io::copy(data, &mut io::sink())?;
+                    }
+                    Ok(matches!(outcome, Ok(false)))
+                })
+                .map_err(seven_zip_error)?;
+            outcome.map(|_| ())
+        }
+        _ => tar::each(reader, visit),
+    }
+}
+
+fn seven_zip<R: Read + Seek>(mut reader: R) -> Result<SevenZReader<R>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+    SevenZReader::new(reader, length, Password::empty()).map_err(seven_zip_error)
+}
+
+fn seven_zip_error(error: sevenz_rust::Error) -> anyhow::Error {
+    use sevenz_rust::Error as E;
+    match error {
+        E::Io(io, _) | E::FileOpen(io, _) => {
+            anyhow::Error::new(io).context("Could not read the 7z archive")
+        }
+        error => {
+            let class = match error {
+                E::UnsupportedVersion { .. }
+                | E::ExternalUnsupported
+                | E::UnsupportedCompressionMethod(_)
+                | E::PasswordRequired
+                | E::MaybeBadPassword(_)
+                | E::Unsupported(_) => Unsupported,
+                _ => BadInput,
+            };
+            anyhow!(Classified::new(
+                class,
+                format!("Could not read the 7z archive: {}", error)
+            ))
+        }
+    }
+}
+
+/// The name of an archive's member as a path through the archive, with any leading `./`
+/// left off, or `None` if it leads outside the archive: up through `..` or from the root.
+pub(crate) fn member_name(name: &str) -> Option<String> {
+    let name = name.replace('\\', "/");
+    if name.starts_with('/') || name.contains(':') {
+        return None;
+    }
+    let parts: Vec<&str> = name
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    match parts.is_empty() || parts.contains(&"..") {
+        true => None,
+        false => Some(parts.join("/")),
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Replaces each archive in `paths` with its tifs whose names match one of `patterns`, or
+/// all of them without any patterns, given as paths through the archive. In a pattern,
+/// `*` stands for any run of characters, `/` included. Unless `all` is set, an archive
+/// must hold exactly one tif that matches. Other paths are kept as given.
+pub fn members(paths: &[PathBuf], patterns: &[String], all: bool) -> Result<Vec<PathBuf>> {
+    let mut expanded = vec![];
+    for path in paths {
+        let Some(kind) = Kind::of(path).filter(|_| path.is_file()) else {
+            expanded.push(path.clone());
+            continue;
+        };
+        let mut names = tifs(kind, open(path)?)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        names.retain(|name| patterns.is_empty() || key_matches(patterns, name));
+        names.sort();
+        match names[..] {
+            [] if patterns.is_empty() => bail!(Classified::new(
+                BadInput,
+                format!("{} holds no tif files", path.display())
+            )),
+            [] => bail!(Classified::new(
+                BadInput,
+                format!("No tif in {} matches --archive-member", path.display())
+            )),
+            [_, _, ..] if !all => bail!(Classified::new(
+                BadInput,
+                format!(
+                    "{} tifs in {} match --archive-member, narrow it or pass --all-members",
+                    names.len(),
+                    path.display()
+                )
+            )),
+            _ => expanded.extend(names.into_iter().map(|name| path.join(name))),
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file, member_name, members, name, split, Kind};
+    use crate::{failure::FailureClass, load_tif_contents};
+    use flate2::{write::GzEncoder, Compression};
+    use sevenz_rust::{SevenZArchiveEntry, SevenZWriter};
+    use std::{fs, io::Write, path::Path};
+    use zip::{write::FileOptions, ZipWriter};
+
+    /// A tar holding `files`, as the tar tests write them.
+    fn tar(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut tar = vec![];
+        for (name, contents) in files {
+            let mut header = vec![0; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+            header[156] = b'0';
+            header[257..263].copy_from_slice(b"ustar\0");
+            header[148..156].fill(b' ');
+            let sum: u32 = header.iter().map(|&b| b as u32).sum();
+            header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+            tar.extend(header);
+            tar.extend(contents.as_bytes());
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        }
+        tar
+    }
+
+    #[test]
+    fn test_members() {
+        let dir = std::env::temp_dir().join(format!("archive-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("rasters/data.tif", "data"),
+            ("rasters/qa.tif", "qa"),
+            ("README.txt", "text"),
+        ];
+        // Zip files whose names lead outside the archive are left out.
+        let path = dir.join("data.zip");
+        let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+        for (name, contents) in files.iter().chain(&[("../up.tif", "up")]) {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let all = members(std::slice::from_ref(&path), &[], true).unwrap();
+        assert_eq!(
+            all,
+            [path.join("rasters/data.tif"), path.join("rasters/qa.tif")]
+        );
+        // Without --all-members, the pattern must pick out a single tif.
+        assert!(members(std::slice::from_ref(&path), &[], false).is_err());
+        let qa = members(std::slice::from_ref(&path), &["*qa*".to_string()], false).unwrap();
+        assert_eq!(qa, [path.join("rasters/qa.tif")]);
+        assert!(members(std::slice::from_ref(&path), &["*.tiff".to_string()], true).is_err());
+
+        assert_eq!(
+            split(&qa[0]),
+            Some((path.as_path(), "rasters/qa.tif".to_string()))
+        );
+        assert_eq!(file(&qa[0]), path);
+        assert_eq!(name(&qa[0]), dir.join("data.qa.tif"));
+        assert_eq!(name(Path::new("a/b.tif")), Path::new("a/b.tif"));
+        assert_eq!(load_tif_contents(&qa[0]).unwrap(), b"qa");
+        assert!(load_tif_contents(&path.join("rasters/missing.tif")).is_err());
+
+        // Tars read the same way, gzipped or not.
+        let tgz = dir.join("bundle.tar.gz");
+        let mut gz = GzEncoder::new(fs::File::create(&tgz).unwrap(), Compression::fast());
+        gz.write_all(&tar(&files)).unwrap();
+        gz.finish().unwrap();
+        let qa = members(std::slice::from_ref(&tgz), &["*qa*".to_string()], false).unwrap();
+        assert_eq!(qa, [tgz.join("rasters/qa.tif")]);
+        assert_eq!(name(&qa[0]), dir.join("bundle.qa.tif"));
+        assert_eq!(load_tif_contents(&qa[0]).unwrap(), b"qa");
+        assert!(load_tif_contents(&tgz).is_err());
+        let single = dir.join("single.tar");
+        fs::write(&single, tar(&[("only.tif", "only"), ("notes.txt", "")])).unwrap();
+        assert_eq!(load_tif_contents(&single).unwrap(), b"only");
+        assert_eq!(name(&single), dir.join("single.tif"));
+
+        assert_eq!(Kind::sniff(&tar(&files)), Some(Kind::Tar));
+        assert_eq!(Kind::sniff(&fs::read(&tgz).unwrap()), Some(Kind::TarGz));
+
+        // A gzipped tif isn't an archive, and is inflated as it's read.
+        let gzipped = dir.join("plain.tif.gz");
+        let mut gz = GzEncoder::new(fs::File::create(&gzipped).unwrap(), Compression::fast());
+        gz.write_all(b"II*\0 tif").unwrap();
+        gz.finish().unwrap();
+        assert_eq!(load_tif_contents(&gzipped).unwrap(), b"II*\0 tif");
+        assert_eq!(Kind::sniff(&fs::read(&gzipped).unwrap()), None);
+        assert_eq!(name(&gzipped), dir.join("plain.tif"));
+        assert_eq!(Kind::sniff(b"PK\x03\x04"), Some(Kind::Zip));
+
+        // 7z archives too, leaving out files whose names lead outside them.
+        let seven = dir.join("bundle.7z");
+        let mut writer = SevenZWriter::new(fs::File::create(&seven).unwrap()).unwrap();
+        for (name, contents) in files.iter().chain(&[("../up.tif", "up")]) {
+            let mut entry = SevenZArchiveEntry::new();
+            entry.name = name.to_string();
+            entry.has_stream = true;
+            writer
+                .push_archive_entry(entry, Some(contents.as_bytes()))
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        let all = members(std::slice::from_ref(&seven), &[], true).unwrap();
+        assert_eq!(
+            all,
+            [seven.join("rasters/data.tif"), seven.join("rasters/qa.tif")]
+        );
+        assert_eq!(load_tif_contents(&all[1]).unwrap(), b"qa");
+        assert_eq!(name(&all[1]), dir.join("bundle.qa.tif"));
+        assert_eq!(
+            Kind::sniff(&fs::read(&seven).unwrap()),
+            Some(Kind::SevenZip)
+        );
+        fs::write(dir.join("bad.7z"), b"7z\xbc\xaf\x27\x1c").unwrap();
+        let bad = members(&[dir.join("bad.7z")], &[], true).unwrap_err();
+        assert_eq!(FailureClass::of(&bad), FailureClass::BadInput);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(member_name("./a/./b.tif").as_deref(), Some("a/b.tif"));
+        assert_eq!(member_name("a\\b.tif").as_deref(), Some("a/b.tif"));
+        for outside in ["../a.tif", "a/../../b.tif", "/etc/a.tif", "C:/a.tif", "."] {
+            assert_eq!(member_name(outside), None, "{}", outside);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Reading ESRI ASCII grids, the `.asc` files Arc/Info exported and older datasets still
+//! ship as: a header of `ncols`, `nrows`, the lower left corner or center, `cellsize` and
+//! an optional `NODATA_value`, then the values as text, row by row from the north. The
+//! grid becomes a tif the rest of the conversion reads like any other, with its CRS taken
+//! from a `.prj` beside it when that is one of the known ones.
+
+use crate::{
+    crs::Crs,
+    failure::{Classified, FailureClass::BadInput},
+    grid::Grid,
+};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Whether `path` is an ESRI ASCII grid, by its extension.
+pub fn is_asc(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("asc"))
+}
+
+/// Reads the grid at `path`, and the `.prj` beside it if there is one, into a tif.
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    let prj = std::fs::read_to_string(path.with_extension("prj")).ok();
+    to_tif(&std::fs::read(path)?, prj.as_deref())
+}
+
+fn bad(message: impl Into<String>) -> anyhow::Error {
+    Classified::new(BadInput, message.into()).into()
+}
+
+/// Turns the ESRI ASCII grid `contents` into a tif, in the CRS of the WKT `prj` if given.
+pub fn to_tif(contents: &[u8], prj: Option<&str>) -> Result<Vec<u8>> {
+    let Ok(text) = std::str::from_utf8(contents) else {
+        bail!(bad("The ASCII grid isn't text"));
+    };
+    let mut tokens = text.split_ascii_whitespace().peekable();
+    let (mut ncols, mut nrows, mut x, mut y) = (None, None, None, None);
+    let (mut dx, mut dy, mut nodata) = (None, None, None);
+    // The header is keyword and value pairs until the first value of the grid.
+    while let Some(key) = tokens.next_if(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+        let Some(value) = tokens.next().and_then(|v| v.parse::<f64>().ok()) else {
+            bail!(bad(format!("The ASCII grid's {} has no number", key)));
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "ncols" => ncols = Some(value),
+            "nrows" => nrows = Some(value),
+            "xllcorner" => x = Some((value, false)),
+            "xllcenter" => x = Some((value, true)),
+            "yllcorner" => y = Some((value, false)),
+            "yllcenter" => y = Some((value, true)),
+            "cellsize" => (dx, dy) = (Some(value), Some(value)),
+            // GDAL writes these for pixels that aren't square.
+            "dx" => dx = Some(value),
+            "dy" => dy = Some(value),
+            "nodata_value" => nodata = Some(value),
+            _ => bail!(bad(format!("The ASCII grid has an unknown header {}", key))),
+        }
+    }
+    let size = |n: Option<f64>, key| match n {
+        Some(n) if n >= 1.0 && n.fract() == 0.0 && n <= u32::MAX as f64 => Ok(n as u64),
+        _ => Err(bad(format!(
+            "The ASCII grid needs a positive whole {}",
+            key
+        ))),
+    };
+    let (width, height) = (size(ncols, "ncols")?, size(nrows, "nrows")?);
+    let (Some((west, x_center)), Some((south, y_center)), Some(dx), Some(dy)) = (x, y, dx, dy)
+    else {
+        bail!(bad(
+            "The ASCII grid needs xllcorner or xllcenter, yllcorner or yllcenter, and cellsize"
+        ));
+    };
+    if dx <= 0.0 || dy <= 0.0 {
+        bail!(bad("The ASCII grid's cellsize must be positive"));
+    }
+
+    let pixels = width * height;
+    let mut values = Vec::with_capacity(pixels.min(1 << 24) as usize);
+    for token in tokens {
+        let Ok(value) = token.parse::<f64>() else {
+            bail!(bad(format!("The ASCII grid has a value {}", token)));
+        };
+        values.push(match Some(value) == nodata {
+            true => f64::NAN,
+            false => value,
+        });
+    }
+    if values.len() as u64 != pixels {
+        bail!(bad(format!(
+            "The ASCII grid has {} values, not {} by {}",
+            values.len(),
+            width,
+            height
+        )));
+    }
+
+    // Centers are half a pixel in from the corner.
+    let corner = |at: f64, center: bool, size: f64| match center {
+        true => at - size / 2.0,
+        false => at,
+    };
+    let (west, south) = (corner(west, x_center, dx), corner(south, y_center, dy));
+    Grid {
+        width,
+        height,
+        values,
+        extent: Some([west, south + height as f64 * dy, dx, dy]),
+        crs: prj.and_then(Crs::from_wkt),
+        ..Grid::default()
+    }
+    .to_tif()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_tif;
+    use crate::{crs::Crs, georef::GeoTransform};
+    use std::io::Cursor;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    #[test]
+    fn test_to_tif() {
+        let asc = b"ncols 3\nNROWS 2\nxllcenter 0.5\nyllcenter -9.5\ncellsize 1\n\
+            NODATA_value -9999\n1 2 -9999\n4 5 6\n";
+        let tif = to_tif(asc, Crs::wgs84().esri_wkt()).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(&tif)).unwrap();
+        let DecodingResult::F64(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected F64 pixels");
+        };
+        assert_eq!(pixels, [1.0, 2.0, f64::MIN, 4.0, 5.0, 6.0]);
+        // The CRS comes from the `.prj`.
+        let bounds = GeoTransform::resolve(&mut decoder, None, Some(Crs::wgs84()))
+            .unwrap()
+            .bounds();
+        assert_eq!(
+            (bounds.west, bounds.south, bounds.east, bounds.north),
+            (0.0, -10.0, 3.0, -8.0)
+        );
+
+        assert!(to_tif(
+            b"ncols 2\nnrows 2\nxllcorner 0\nyllcorner 0\ncellsize 1\n1 2 3",
+            None
+        )
+        .is_err());
+        assert!(to_tif(b"ncols 1\nnrows 1\ncellsize 1\n1", None).is_err());
+        assert!(to_tif(
+            b"ncols 1\nnrows 1\nxllcorner 0\nyllcorner 0\ncellsize 1\nx",
+            None
+        )
+        .is_err());
+    }
+}
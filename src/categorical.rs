@@ -0,0 +1,190 @@
+//! `--categorical`: grouping rasters whose values are class codes, such as land cover or
+//! soil types, which sums and means make no sense of. Each cell takes its most common
+//! class, or with `--class-counts` has a row for each class in it holding its pixel count.
+//! `--labels` names the classes, and palette tifs' colors are added for them.
+
+use crate::group::{self, Aggregation, Binned, Binning};
+use anyhow::{bail, Context, Result};
+use arrow_array::{Array, ArrayRef, UInt32Array};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    str::FromStr,
+};
+
+/// Names of class codes, read from a CSV file of `code,label` lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Labels(HashMap<i64, String>);
+
+impl FromStr for Labels {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let path = Path::new(s);
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let mut labels = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = line
+                .split_once(',')
+                .and_then(|(code, label)| Some((code.trim().parse().ok()?, label.trim())));
+            match parsed {
+                Some((code, label)) => {
+                    labels.insert(code, label.trim_matches('"').to_string());
+                }
+                // A header.
+                None if i == 0 => {}
+                None => bail!("Line {} of {} isn't `code,label`", i + 1, path.display()),
+            }
+        }
+        Ok(Labels(labels))
+    }
+}
+
+impl Labels {
+    pub fn get(&self, code: i64) -> Option<&str> {
+        self.0.get(&code).map(String::as_str)
+    }
+}
+
+/// The `#rrggbb` color of `code` in a ColorMap tag's red, green and blue levels.
+pub fn color(color_map: &[u32], code: i64) -> Option<String> {
+    let levels = color_map.len() / 3;
+    let code = usize::try_from(code).ok().filter(|&code| code < levels)?;
+    let [red, green, blue] = [0, 1, 2].map(|channel| color_map[channel * levels + code] >> 8);
+    Some(format!("#{:02x}{:02x}{:02x}", red, green, blue))
+}
+
+/// Groups rows whose values are class codes into cells, each taking the class most of its
+/// pixels have, the lowest where there is a tie, as its value. With `counts`, each cell
+/// instead has a row for each class in it with its pixel count as the value. Returns the
+/// class of each row too.
+pub fn bin(
+    data: &[(f64, f64, f64)],
+    binning: &Binning,
+    counts: bool,
+) -> Result<(Binned, Vec<i64>)> {
+    let mut by_class: BTreeMap<i64, Vec<(f64, f64, f64)>> = BTreeMap::new();
+    for &(lon, lat, value) in data {
+        by_class
+            .entry(value as i64)
+            .or_default()
+            .push((lon, lat, value));
+    }
+    let binned: Vec<(i64, Binned)> = by_class
+        .iter()
+        .map(|(class, rows)| {
+            let binned = group::bin(rows, binning, Aggregation::Count, |_, _| 1.0);
+            (*class, binned)
+        })
+        .collect();
+    if binned.is_empty() {
+        // Binning nothing still gives the key columns their types.
+        return Ok((
+            group::bin(&[], binning, Aggregation::Count, |_, _| 1.0),
+            vec![],
+        ));
+    }
+
+    // The rows kept, as which class's binning they're in and where.
+    let mut kept: Vec<(usize, usize)> = match counts {
+        true => binned
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, b))| (0..b.rows.len()).map(move |row| (i, row)))
+            .collect(),
+        false => {
+            let mut most = HashMap::<(u64, u64), (f64, usize, usize)>::new();
+            for (i, (_, b)) in binned.iter().enumerate() {
+                for (row, &(lon, lat, count)) in b.rows.iter().enumerate() {
+                    let cell = most
+                        .entry((lon.to_bits(), lat.to_bits()))
+                        .or_insert((count, i, row));
+                    // Classes are visited in order, so ties go to the lowest.
+                    if count > cell.0 {
+                        *cell = (count, i, row);
+                    }
+                }
+            }
+            most.into_values().map(|(_, i, row)| (i, row)).collect()
+        }
+    };
+    kept.sort_unstable();
+
+    let rows = kept
+        .iter()
+        .map(|&(i, row)| {
+            let (class, b) = &binned[i];
+            let (lon, lat, count) = b.rows[row];
+            (lon, lat, if counts { count } else { *class as f64 })
+        })
+        .collect();
+    let classes = kept.iter().map(|&(i, _)| binned[i].0).collect();
+    // Each class's key columns are joined, then the kept rows taken from them.
+    let mut starts = vec![0];
+    for (_, b) in &binned {
+        starts.push(starts.last().unwrap() + b.rows.len());
+    }
+    let taken =
+        UInt32Array::from_iter_values(kept.iter().map(|&(i, row)| (starts[i] + row) as u32));
+    let columns = (0..binned[0].1.columns.len())
+        .map(|c| {
+            let parts: Vec<&dyn Array> = binned
+                .iter()
+                .map(|(_, b)| b.columns[c].1.as_ref())
+                .collect();
+            let joined = arrow_select::concat::concat(&parts)?;
+            let column: ArrayRef = arrow_select::take::take(&joined, &taken, None)?;
+            Ok((binned[0].1.columns[c].0, column))
+        })
+        .collect::<Result<_>>()?;
+    Ok((Binned { rows, columns }, classes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bin, color, Labels};
+    use crate::group::{Align, Binning, Grid, LonLat};
+
+    #[test]
+    fn test_categorical() {
+        let binning = Binning::Grid(Grid {
+            size: 10.0.into(),
+            origin: LonLat { lon: 0.0, lat: 0.0 },
+            align: Align::Corner,
+            wraps: false,
+        });
+        // Two cells: one mostly class 2, and one tied between 5 and 3.
+        let data = [
+            (1.0, 1.0, 2.0),
+            (2.0, 1.0, 2.0),
+            (3.0, 1.0, 7.0),
+            (11.0, 1.0, 5.0),
+            (12.0, 1.0, 3.0),
+        ];
+        let (binned, classes) = bin(&data, &binning, false).unwrap();
+        let mut modes: Vec<_> = binned.rows.iter().map(|r| (r.0, r.2)).collect();
+        modes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(modes, vec![(0.0, 2.0), (10.0, 3.0)]);
+        assert_eq!(classes.len(), 2);
+
+        let (binned, classes) = bin(&data, &binning, true).unwrap();
+        assert_eq!(classes, vec![2, 3, 5, 7]);
+        assert_eq!(binned.rows[0].2, 2.0);
+
+        let path = std::env::temp_dir().join(format!("labels-test-{}.csv", std::process::id()));
+        std::fs::write(&path, "code,label\n2,Forest\n3,\"Water\"\n").unwrap();
+        let labels: Labels = path.to_str().unwrap().parse().unwrap();
+        assert_eq!(labels.get(3), Some("Water"));
+        assert_eq!(labels.get(4), None);
+        std::fs::remove_file(&path).unwrap();
+
+        // Two palette entries: black, then full red.
+        let color_map = [0, 65535, 0, 0, 0, 0];
+        assert_eq!(color(&color_map, 1).as_deref(), Some("#ff0000"));
+        assert_eq!(color(&color_map, 2), None);
+    }
+}
@@ -0,0 +1,311 @@
+//! Checking inputs against the checksums their providers publish, with
+//! `--verify-checksum` or `--sha256`, before anything is decoded, so a truncated or
+//! corrupted download fails up front instead of converting into wrong values.
+//!
+//! Checksums are looked for beside the input with `.sha256` or `.md5` added to its name,
+//! such as `dem.tif.sha256`, or in place of its extension, as `dem.sha256`. They may
+//! hold just the digest, or the lines `sha256sum` and `md5sum` print in either their own
+//! or BSD style. An input inside an archive is checked through the archive, which is
+//! what was downloaded.
+
+use crate::{
+    archive,
+    failure::{Classified, FailureClass::BadInput},
+    stdin,
+};
+use anyhow::{bail, Context, Result};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Length of the algorithm's digests in hex.
+    fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha256 => 64,
+        }
+    }
+}
+
+/// An input's digest, found to match the one published for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verified {
+    pub algorithm: Algorithm,
+    pub digest: String,
+    /// Where the published digest came from: its sidecar's path, or `--sha256`.
+    pub expected_from: String,
+}
+
+/// Parses a `--sha256` digest, in either case.
+pub fn parse_sha256(s: &str) -> Result<String, String> {
+    let hex = s.trim().to_ascii_lowercase();
+    match is_digest(&hex, Algorithm::Sha256) {
+        true => Ok(hex),
+        false => Err(format!("expected 64 hex digits, not {:?}", s)),
+    }
+}
+
+fn is_digest(s: &str, algorithm: Algorithm) -> bool {
+    s.len() == algorithm.hex_len() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The checksum sidecar beside `path`, if there is one.
+fn sidecar(path: &Path) -> Option<(PathBuf, Algorithm)> {
+    if stdin::is_stdin(path) {
+        return None;
+    }
+    let mut candidates = vec![];
+    for algorithm in [Algorithm::Sha256, Algorithm::Md5] {
+        let mut added = path.as_os_str().to_owned();
+        added.push(format!(".{}", algorithm.name()));
+        candidates.push((PathBuf::from(added), algorithm));
+        candidates.push((path.with_extension(algorithm.name()), algorithm));
+    }
+    candidates
+        .into_iter()
+        .find(|(sidecar, _)| sidecar.is_file())
+}
+
+/// The digest a sidecar's `text` publishes for the file named `name`: that of the line
+/// naming it, or of the only line with a digest.
+fn published(text: &str, algorithm: Algorithm, name: &str) -> Option<String> {
+    let mut found = vec![];
+    for line in text.lines() {
+        let words: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '='))
+            .filter(|word| !word.is_empty())
+            .collect();
+        let Some(digest) = words.iter().find(|word| is_digest(word, algorithm)) else {
+            continue;
+        };
+        let names = words
+            .iter()
+            .any(|word| word.trim_start_matches('*') == name);
+        found.push((digest.to_ascii_lowercase(), names));
+    }
+    match found.iter().find(|(_, names)| *names) {
+        Some((digest, _)) => Some(digest.clone()),
+        None if found.len() == 1 => Some(found.remove(0).0),
+        None => None,
+    }
+}
+
+/// Checks `bytes`, downloaded as the file `name`, against the SHA-256 digest `text`
+/// publishes for it, failing if there is none or they differ.
+pub fn verify_published(bytes: &[u8], text: &str, name: &str) -> Result<String> {
+    let Some(expected) = published(text, Algorithm::Sha256, name) else {
+        bail!("No sha256 digest of {} is published", name);
+    };
+    let digest = hash(bytes, Algorithm::Sha256)?;
+    if digest != expected {
+        bail!(
+            "{} has sha256 {} but {} is published; it may be truncated or tampered with",
+            name,
+            digest,
+            expected
+        );
+    }
+    Ok(digest)
+}
+
+/// Checks `input_path` against `sha256`, or else the checksum published beside it,
+/// failing as a bad input if they differ or there is no checksum to check.
+pub fn verify(input_path: &Path, sha256: Option<&str>) -> Result<Verified> {
+    let downloaded = archive::file(input_path);
+    let (expected, algorithm, expected_from) = match sha256 {
+        Some(digest) => (
+            digest.to_string(),
+            Algorithm::Sha256,
+            "--sha256".to_string(),
+        ),
+        None => {
+            let Some((path, algorithm)) = sidecar(downloaded) else {
+                bail!(Classified::new(
+                    BadInput,
+                    format!(
+                        "No .sha256 or .md5 checksum beside {} to verify it with",
+                        downloaded.display()
+                    )
+                ));
+            };
+            let name = downloaded
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let Some(expected) = published(&text, algorithm, &name) else {
+                bail!(Classified::new(
+                    BadInput,
+                    format!(
+                        "{} holds no {} digest of {}",
+                        path.display(),
+                        algorithm.name(),
+                        name
+                    )
+                ));
+            };
+            (expected, algorithm, path.display().to_string())
+        }
+    };
+    let reader: Box<dyn Read> = match stdin::is_stdin(input_path) {
+        true => Box::new(stdin::contents()?),
+        false => Box::new(File::open(downloaded)?),
+    };
+    let digest = hash(reader, algorithm)?;
+    if digest != expected {
+        bail!(Classified::new(
+            BadInput,
+            format!(
+                "{} has {} {} but {} gives {}; it may be truncated or corrupt",
+                downloaded.display(),
+                algorithm.name(),
+                digest,
+                expected_from,
+                expected
+            )
+        ));
+    }
+    Ok(Verified {
+        algorithm,
+        digest,
+        expected_from,
+    })
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+/// The digest of everything `reader` holds, in hex.
+pub fn hash(mut reader: impl Read, algorithm: Algorithm) -> Result<String> {
+    let mut hasher = match algorithm {
+        Algorithm::Md5 => Hasher::Md5(Md5::new()),
+        Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+    };
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        match &mut hasher {
+            Hasher::Md5(md5) => md5.update(&buffer[..read]),
+            Hasher::Sha256(sha256) => sha256.update(&buffer[..read]),
+        }
+    }
+    let digest = match hasher {
+        Hasher::Md5(md5) => md5.finalize().to_vec(),
+        Hasher::Sha256(sha256) => sha256.finalize().to_vec(),
+    };
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, parse_sha256, published, verify, Algorithm};
+    use crate::failure::FailureClass;
+
+    #[test]
+    fn test_hash() {
+        // Fed through a reader in uneven pieces, to cross block boundaries.
+        let hex = |data: &[u8], algorithm| {
+            let reader = std::io::BufReader::with_capacity(7, data);
+            hash(reader, algorithm).unwrap()
+        };
+        assert_eq!(
+            hex(b"", Algorithm::Sha256),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                Algorithm::Sha256
+            ),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(hex(b"", Algorithm::Md5), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            hex(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                Algorithm::Md5
+            ),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+        // Lengths either side of where padding spills into another block.
+        for (length, md5, sha256) in [
+            (
+                55,
+                "ef1772b6dff9a122358552954ad0df65",
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "3b0c8ac703f828b04c6c197006d17218",
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                64,
+                "014842d480b571495a4a0363793f7367",
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+        ] {
+            let data = vec![b'a'; length];
+            assert_eq!(hex(&data, Algorithm::Md5), md5, "{} bytes", length);
+            assert_eq!(hex(&data, Algorithm::Sha256), sha256, "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let md5 = "900150983cd24fb0d6963f7d28e17f72";
+        let listing = format!("{}  other.tif\n{} *a.tif\n", "0".repeat(32), md5);
+        assert_eq!(
+            published(&listing, Algorithm::Md5, "a.tif").as_deref(),
+            Some(md5)
+        );
+        let bsd = format!("MD5 (a.tif) = {}", md5.to_uppercase());
+        assert_eq!(
+            published(&bsd, Algorithm::Md5, "a.tif").as_deref(),
+            Some(md5)
+        );
+        assert_eq!(
+            published(md5, Algorithm::Md5, "a.tif").as_deref(),
+            Some(md5)
+        );
+        assert_eq!(published(&listing, Algorithm::Md5, "b.tif"), None);
+
+        let dir = std::env::temp_dir().join(format!("checksum-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("a.tif");
+        std::fs::write(&input, "abc").unwrap();
+        let error = verify(&input, None).unwrap_err();
+        assert_eq!(FailureClass::of(&error), FailureClass::BadInput);
+        std::fs::write(dir.join("a.tif.md5"), format!("{}  a.tif\n", md5)).unwrap();
+        assert_eq!(verify(&input, None).unwrap().digest, md5);
+        let sha256 = parse_sha256(&"A".repeat(64)).unwrap();
+        let error = verify(&input, Some(&sha256)).unwrap_err();
+        assert_eq!(FailureClass::of(&error), FailureClass::BadInput);
+        assert!(parse_sha256("abc").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Reading tifs compressed with codecs the `tiff` crate doesn't decode, as many modern
+//! COGs are. JPEG, LZW, Deflate and PackBits are decoded by the `tiff` crate itself.
+//!
+//! ZSTD strips or tiles are decompressed here and appended to the tif, and its first
+//! directory is rewritten to point at them uncompressed, keeping any predictor for the
+//! `tiff` crate to undo. LERC is only read through GDAL, with the `gdal` feature.
+
+use crate::{
+    failure::{Classified, FailureClass::Unsupported},
+    ifd::{
+        Change, Directory, COMPRESSION, LONG, SHORT, STRIP_BYTE_COUNTS, STRIP_OFFSETS,
+        TILE_BYTE_COUNTS, TILE_OFFSETS,
+    },
+};
+use anyhow::{bail, Context, Result};
+
+const LERC: u64 = 34887;
+const ZSTD: u64 = 50000;
+
+/// Whether the tif's first image is compressed with LERC, which only GDAL reads.
+#[cfg(feature = "gdal")]
+pub fn needs_gdal(contents: &[u8]) -> Result<bool> {
+    let directory = Directory::read(contents)?;
+    Ok(directory.value(contents, COMPRESSION)? == Some(LERC))
+}
+
+/// The tif with its first image decompressed if the `tiff` crate can't decode it as it
+/// is, or `None` if it can.
+pub fn decompress(contents: &[u8]) -> Result<Option<Vec<u8>>> {
+    let directory = Directory::read(contents)?;
+    match directory.value(contents, COMPRESSION)? {
+        Some(ZSTD) => {}
+        Some(LERC) => bail!(Classified::new(
+            Unsupported,
+            "LERC compressed tifs are only read when built with the gdal feature; \
+             recompress with `gdal_translate -co COMPRESS=ZSTD` to read them without it"
+        )),
+        _ => return Ok(None),
+    }
+    let (offsets_tag, counts_tag) = match directory.has(TILE_OFFSETS) {
+        true => (TILE_OFFSETS, TILE_BYTE_COUNTS),
+        false => (STRIP_OFFSETS, STRIP_BYTE_COUNTS),
+    };
+    let (Some(offsets), Some(counts)) = (
+        directory.values_of(contents, offsets_tag)?,
+        directory.values_of(contents, counts_tag)?,
+    ) else {
+        bail!("The tif has no strip or tile offsets and byte counts");
+    };
+    if offsets.len() != counts.len() {
+        bail!(
+            "The tif has {} strip or tile offsets but {} byte counts",
+            offsets.len(),
+            counts.len()
+        );
+    }
+
+    let mut decompressed = contents.to_vec();
+    let (mut new_offsets, mut new_counts) = (vec![], vec![]);
+    for (i, (&offset, &count)) in offsets.iter().zip(&counts).enumerate() {
+        // Sparse COGs leave out empty chunks, which the `tiff` crate reads as zeros.
+        if count == 0 {
+            new_offsets.push(offset);
+            new_counts.push(0);
+            continue;
+        }
+        let Some(chunk) = contents.get(offset as usize..(offset + count) as usize) else {
+            bail!("Chunk {} of the tif runs past its end", i);
+        };
+        let chunk = zstd::stream::decode_all(chunk)
+            .with_context(|| format!("Could not decompress chunk {} of the tif", i))?;
+        new_offsets.push(decompressed.len() as u64);
+        new_counts.push(chunk.len() as u64);
+        decompressed.extend(chunk);
+    }
+
+    let rewritten = directory.rewrite(&decompressed, |entry| {
+        // Offsets past 4 GB need a BigTIFF, whose offsets are already 8 bytes.
+        let kind = match entry.kind {
+            SHORT => LONG,
+            kind => kind,
+        };
+        let fits = |values: &[u64]| kind != LONG || values.iter().all(|&v| v <= u32::MAX as u64);
+        Ok(match entry.tag {
+            COMPRESSION => Change::Replace(SHORT, vec![1]),
+            tag if tag == offsets_tag || tag == counts_tag => {
+                let values = match tag == offsets_tag {
+                    true => &new_offsets,
+                    false => &new_counts,
+                };
+                if !fits(values) {
+                    bail!("The decompressed tif is too large for a classic tif");
+                }
+                Change::Replace(kind, values.clone())
+            }
+            _ => Change::Keep,
+        })
+    })?;
+    Ok(Some(rewritten.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress;
+    use crate::failure::{FailureClass, FailureClass::Unsupported};
+    use std::io::Cursor;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    /// A classic little endian tif of 4x2 u8 pixels in two strips of one row, each
+    /// compressed with `compression`, with horizontal differencing.
+    fn compressed_tif(compression: u32) -> Vec<u8> {
+        let rows: [&[u8]; 2] = [&[1, 1, 1, 1], &[10, 10, 10, 10]];
+        let strips: Vec<Vec<u8>> = rows
+            .iter()
+            .map(|row| zstd::stream::encode_all(*row, 3).unwrap())
+            .collect();
+        let (first, second) = (strips[0].len() as u32, strips[1].len() as u32);
+        let tags: [(u16, u16, u32, u32); 10] = [
+            (256, 3, 1, 4),
+            (257, 3, 1, 2),
+            (258, 3, 1, 8),
+            (259, 3, 1, compression),
+            (262, 3, 1, 1),
+            (273, 4, 2, 200),
+            (277, 3, 1, 1),
+            (278, 3, 1, 1),
+            (279, 4, 2, 208),
+            (317, 3, 1, 2),
+        ];
+        let mut tif = b"II*\0\x08\0\0\0".to_vec();
+        tif.extend((tags.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in tags {
+            tif.extend(tag.to_le_bytes());
+            tif.extend(kind.to_le_bytes());
+            tif.extend(count.to_le_bytes());
+            tif.extend(value.to_le_bytes());
+        }
+        tif.extend(0u32.to_le_bytes());
+        tif.resize(200, 0);
+        let offsets = [216, 216 + first, first, second];
+        tif.extend(offsets.iter().flat_map(|v| v.to_le_bytes()));
+        tif.extend(strips.concat());
+        tif
+    }
+
+    #[test]
+    fn test_decompress() {
+        let tif = compressed_tif(50000);
+        let decompressed = decompress(&tif).unwrap().unwrap();
+        let mut decoder = Decoder::new(Cursor::new(decompressed)).unwrap();
+        let DecodingResult::U8(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected u8 samples");
+        };
+        // Each row's differences are added back up by the `tiff` crate.
+        assert_eq!(pixels, vec![1, 2, 3, 4, 10, 20, 30, 40]);
+
+        let lerc = compressed_tif(34887);
+        let err = decompress(&lerc).unwrap_err();
+        assert_eq!(FailureClass::of(&err), Unsupported);
+        assert!(decompress(&compressed_tif(1)).unwrap().is_none());
+    }
+}
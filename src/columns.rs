@@ -0,0 +1,113 @@
+//! Renaming output columns with `--columns` and `--value-name`, for downstream schemas
+//! that expect names such as `longitude` or `population`. Columns are renamed last, once
+//! filters and transforms have seen them by their usual names, so every format writes
+//! the same names.
+
+use anyhow::{anyhow, bail, Result};
+use arrow_array::RecordBatch;
+use arrow_schema::{Field, Schema};
+use std::{str::FromStr, sync::Arc};
+
+/// A `--columns` entry, `from=to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for Rename {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected a column rename as `from=to`, not `{}`", s))?;
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() {
+            bail!("Expected a column rename as `from=to`, not `{}`", s);
+        }
+        Ok(Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+}
+
+/// The name `renames` give the column named `name`.
+pub fn renamed<'a>(renames: &'a [Rename], name: &'a str) -> &'a str {
+    renames
+        .iter()
+        .find(|rename| rename.from == name)
+        .map_or(name, |rename| rename.to.as_str())
+}
+
+/// Renames the columns of `batch`, keeping their metadata. Every column renamed must
+/// exist, and no two columns may end up with the same name.
+pub fn rename(batch: RecordBatch, renames: &[Rename]) -> Result<RecordBatch> {
+    if renames.is_empty() {
+        return Ok(batch);
+    }
+    let schema = batch.schema();
+    for rename in renames {
+        if schema.field_with_name(&rename.from).is_err() {
+            let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+            bail!(
+                "There is no {} column to rename, only {}",
+                rename.from,
+                names.join(", ")
+            );
+        }
+    }
+    let fields: Vec<Field> = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            field
+                .as_ref()
+                .clone()
+                .with_name(renamed(renames, field.name()).to_string())
+        })
+        .collect();
+    for (i, field) in fields.iter().enumerate() {
+        if fields[..i].iter().any(|other| other.name() == field.name()) {
+            bail!("Renaming gives two columns the name {}", field.name());
+        }
+    }
+    let schema = Schema::new(fields).with_metadata(schema.metadata().clone());
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        batch.columns().to_vec(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rename, Rename};
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rename() {
+        let column = || Arc::new(Float32Array::from(vec![1.0])) as ArrayRef;
+        let batch =
+            RecordBatch::try_from_iter([("lon", column()), ("lat", column()), ("value", column())])
+                .unwrap();
+        let renames: Vec<Rename> = ["lon=longitude", " value = population"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let renamed = rename(batch.clone(), &renames).unwrap();
+        let names: Vec<_> = renamed
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        assert_eq!(names, ["longitude", "lat", "population"]);
+
+        assert!("lon".parse::<Rename>().is_err());
+        assert!("lon=".parse::<Rename>().is_err());
+        assert!(rename(batch.clone(), &["time=t".parse().unwrap()]).is_err());
+        assert!(rename(batch, &["lon=lat".parse().unwrap()]).is_err());
+    }
+}
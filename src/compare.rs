@@ -0,0 +1,192 @@
+//! Comparing two parquet outputs of the tool, such as before and after an upgrade.
+
+use crate::{explain, json::Value, output};
+use anyhow::{bail, Result};
+use arrow_array::{cast::as_primitive_array, types::Float64Type, Array, Float64Array, RecordBatch};
+use arrow_schema::DataType;
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct CompareArgs {
+    /// The output of the earlier version.
+    a: PathBuf,
+    /// The output of the later version.
+    b: PathBuf,
+    /// Largest difference in a value or coordinate that still counts as the same.
+    #[arg(long = "tolerance", default_value_t = 0.0)]
+    tolerance: f64,
+    /// Print the report as JSON instead of text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+pub fn run(args: &CompareArgs) -> Result<()> {
+    let (report, same) = compare(
+        &output::read_parquet(&args.a)?,
+        &output::read_parquet(&args.b)?,
+        args.tolerance,
+    )?;
+    if args.json {
+        println!("{}", report.pretty());
+    } else {
+        print!("{}", explain::to_text(&report, 0));
+    }
+    if !same {
+        bail!(
+            "{} and {} differ by more than the tolerance of {}",
+            args.a.display(),
+            args.b.display(),
+            args.tolerance
+        );
+    }
+    Ok(())
+}
+
+/// Reports how `b` differs from `a`, and whether every difference is within `tolerance`.
+///
+/// Rows are matched by position, as the tool writes them in a fixed order. Numeric columns
+/// are compared by their largest deviation, with `lon` and `lat` reported as coordinate
+/// shifts, and other columns by whether they are identical.
+fn compare(a: &RecordBatch, b: &RecordBatch, tolerance: f64) -> Result<(Value, bool)> {
+    let (schema_a, schema_b) = (a.schema(), b.schema());
+    let field_text = |name: &str, data_type: &DataType| format!("{}: {:?}", name, data_type);
+    let mut added = vec![];
+    let mut removed = vec![];
+    let mut changed = vec![];
+    for field in schema_a.fields() {
+        match schema_b.field_with_name(field.name()) {
+            Err(_) => removed.push(field_text(field.name(), field.data_type())),
+            Ok(other) if other.data_type() != field.data_type() => changed.push(format!(
+                "{}: {:?} -> {:?}",
+                field.name(),
+                field.data_type(),
+                other.data_type()
+            )),
+            Ok(_) => {}
+        }
+    }
+    for field in schema_b.fields() {
+        if schema_a.field_with_name(field.name()).is_err() {
+            added.push(field_text(field.name(), field.data_type()));
+        }
+    }
+    let mut same = added.is_empty() && removed.is_empty() && changed.is_empty();
+
+    let rows = a.num_rows().min(b.num_rows());
+    same &= a.num_rows() == b.num_rows();
+    let mut coordinates = vec![];
+    let mut values = vec![];
+    for field in schema_a.fields() {
+        let name = field.name();
+        let (Some(column_a), Some(column_b)) = (a.column_by_name(name), b.column_by_name(name))
+        else {
+            continue;
+        };
+        let (column_a, column_b) = (column_a.slice(0, rows), column_b.slice(0, rows));
+        let numeric = field.data_type().is_numeric() && column_b.data_type().is_numeric();
+        if !numeric {
+            let identical = column_a.to_data() == column_b.to_data();
+            same &= identical;
+            values.push((
+                name.clone(),
+                Value::object([("identical", identical.into())]),
+            ));
+            continue;
+        }
+        let column_a = arrow_cast::cast(&column_a, &DataType::Float64)?;
+        let column_b = arrow_cast::cast(&column_b, &DataType::Float64)?;
+        let (column_a, column_b) = (
+            as_primitive_array::<Float64Type>(&column_a),
+            as_primitive_array::<Float64Type>(&column_b),
+        );
+        let mut deviation: f64 = 0.0;
+        let mut beyond = 0u64;
+        for i in 0..rows {
+            let value = |column: &Float64Array| column.is_valid(i).then(|| column.value(i));
+            // A null or NaN on only one side is an unbounded difference.
+            let difference = match (value(column_a), value(column_b)) {
+                (Some(x), Some(y)) if x == y || (x.is_nan() && y.is_nan()) => 0.0,
+                (Some(x), Some(y)) if (y - x).is_finite() => (y - x).abs(),
+                (None, None) => 0.0,
+                _ => f64::INFINITY,
+            };
+            deviation = deviation.max(difference);
+            beyond += (difference > tolerance) as u64;
+        }
+        same &= beyond == 0;
+        let entry = Value::object([
+            (
+                "max_deviation",
+                deviation.is_finite().then_some(deviation).into(),
+            ),
+            ("beyond_tolerance", beyond.into()),
+        ]);
+        match name.as_str() {
+            "lon" | "lat" => coordinates.push((name.clone(), entry)),
+            _ => values.push((name.clone(), entry)),
+        }
+    }
+
+    let report = Value::object([
+        (
+            "schema",
+            Value::object([
+                ("added", added.into()),
+                ("removed", removed.into()),
+                ("changed", changed.into()),
+            ]),
+        ),
+        (
+            "rows",
+            Value::object([
+                ("a", (a.num_rows() as u64).into()),
+                ("b", (b.num_rows() as u64).into()),
+                ("delta", (b.num_rows() as f64 - a.num_rows() as f64).into()),
+            ]),
+        ),
+        ("coordinate_shift", Value::Object(coordinates)),
+        ("values", Value::Object(values)),
+        ("tolerance", tolerance.into()),
+        ("same", same.into()),
+    ]);
+    Ok((report, same))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compare;
+    use arrow_array::{ArrayRef, Float32Array, Float64Array, RecordBatch, StringArray};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_compare() {
+        let batch = |lat: f32, value: f64, extra: bool| {
+            let mut columns: Vec<(&str, ArrayRef)> = vec![
+                ("lon", Arc::new(Float32Array::from(vec![1.0, 2.0]))),
+                ("lat", Arc::new(Float32Array::from(vec![lat, 5.0]))),
+                ("value", Arc::new(Float64Array::from(vec![10.0, value]))),
+                ("name", Arc::new(StringArray::from(vec!["a", "b"]))),
+            ];
+            if extra {
+                columns.push(("level", Arc::new(Float64Array::from(vec![0.0, 0.0]))));
+            }
+            RecordBatch::try_from_iter(columns).unwrap()
+        };
+        let a = batch(5.0, 20.0, false);
+        let (report, same) = compare(&a, &a, 0.0).unwrap();
+        assert!(same);
+        assert_eq!(report.get("same"), Some(&true.into()));
+
+        let (report, same) = compare(&a, &batch(5.5, 20.000001, true), 1e-3).unwrap();
+        assert!(!same);
+        let schema = report.get("schema").unwrap();
+        assert_eq!(schema.get("added"), Some(&vec!["level: Float64"].into()));
+        let lat = report.get("coordinate_shift").unwrap().get("lat").unwrap();
+        assert_eq!(lat.get("max_deviation").and_then(|v| v.as_f64()), Some(0.5));
+        let value = report.get("values").unwrap().get("value").unwrap();
+        assert_eq!(
+            value.get("beyond_tolerance").and_then(|v| v.as_f64()),
+            Some(0.0)
+        );
+    }
+}
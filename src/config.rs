@@ -0,0 +1,263 @@
+//! Profiles of options kept in a TOML file, read with `--config` or from `geotif.toml` in
+//! the current directory.
+//!
+//! Each key is one of the command line's long flag names, and `inputs` lists the input
+//! files, directories or globs, relative to the file:
+//!
+//! ```toml
+//! inputs = ["scenes/*.tif"]
+//! group = 0.5
+//! min-value = 1
+//! format = "gpkg"
+//! compression = "zstd"
+//! transform = ["scale:0.001", "clamp:0,100"]
+//! ```
+//!
+//! Flags given on the command line win over the file's, including over file options they
+//! conflict with, and inputs given on the command line or with `--files-from` replace the
+//! file's. Only the top level of TOML is read: tables, dotted keys and dates are refused.
+
+use crate::{json::Value, processor::flag_arguments};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{parser::ValueSource, Arg};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::Spanned;
+
+/// The file read when `--config` isn't given, if it exists.
+pub const DEFAULT_PATH: &str = "geotif.toml";
+
+/// Inserts the options of the config file into `args`, ahead of those given so that the
+/// command line's win. `command` must have an `input_path` positional and a `--config`
+/// option. Arguments that don't parse, or that run a subcommand, are returned unchanged.
+pub fn apply(command: clap::Command, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let Ok(matches) = command.clone().try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    if matches.subcommand().is_some() {
+        return Ok(args);
+    }
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None if Path::new(DEFAULT_PATH).is_file() => PathBuf::from(DEFAULT_PATH),
+        None => return Ok(args),
+    };
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("Could not read config {}", path.display()))?;
+    let entries = parse(&text).with_context(|| format!("In config {}", path.display()))?;
+
+    let given =
+        |arg: &Arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+    let conflicts = |a: &Arg, b: &Arg| {
+        command
+            .get_arg_conflicts_with(a)
+            .iter()
+            .any(|c| c.get_id() == b.get_id())
+    };
+    let mut options = vec![];
+    let mut inputs = vec![];
+    for (key, value) in entries {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| match long.as_str() {
+                "inputs" => arg.get_id() == "input_path",
+                _ => arg.get_long() == Some(long.as_str()) && long != "config",
+            })
+            .ok_or_else(|| anyhow!("Unknown option {} in config {}", key, path.display()))?;
+        // Inputs listed with `--files-from` replace the file's as well.
+        let overridden = given(arg)
+            || command.get_arguments().any(|other| {
+                given(other)
+                    && (conflicts(arg, other)
+                        || conflicts(other, arg)
+                        || long == "inputs" && other.get_long() == Some("files-from"))
+            });
+        if overridden {
+            continue;
+        }
+        if long == "inputs" {
+            let base = path.parent().unwrap_or(Path::new(""));
+            inputs.extend(globs(&value)?.into_iter().map(|glob| base.join(glob)));
+        } else {
+            options.extend(
+                flag_arguments(&command, &key, value)
+                    .with_context(|| format!("In config {}", path.display()))?,
+            );
+        }
+    }
+
+    let mut args = args.into_iter();
+    Ok(args
+        .next()
+        .into_iter()
+        .chain(options.into_iter().map(OsString::from))
+        .chain(inputs.into_iter().map(OsString::from))
+        .chain(args)
+        .collect())
+}
+
+fn globs(value: &Value) -> Result<Vec<&str>> {
+    let items = match value {
+        Value::String(glob) => return Ok(vec![glob]),
+        Value::Array(items) => items,
+        _ => bail!("Expected `inputs` to be a glob or a list of globs"),
+    };
+    items
+        .iter()
+        .map(|item| {
+            item.as_str()
+                .ok_or_else(|| anyhow!("Expected `inputs` to be a glob or a list of globs"))
+        })
+        .collect()
+}
+
+/// Reads the top level keys of a TOML document, in the order they are written.
+fn parse(text: &str) -> Result<Vec<(String, Value)>> {
+    let table: BTreeMap<String, Spanned<toml::Value>> =
+        toml::from_str(text).map_err(|error| match error.span() {
+            Some(span) => anyhow!("Line {}: {}", line(text, span.start), error.message()),
+            None => anyhow!("{}", error.message()),
+        })?;
+    let mut entries: Vec<_> = table.into_iter().collect();
+    entries.sort_by_key(|(_, value)| value.span().start);
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            let at = line(text, value.span().start);
+            let value = convert(value.into_inner())
+                .map_err(|error| anyhow!("Line {}: {}: {}", at, key, error))?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// The line of the byte at `pos`, counting from 1.
+fn line(text: &str, pos: usize) -> usize {
+    text[..pos.min(text.len())].matches('\n').count() + 1
+}
+
+/// The TOML values options can take, as the JSON values flags are built from.
+fn convert(value: toml::Value) -> Result<Value> {
+    Ok(match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(n) => Value::Number(n as f64),
+        toml::Value::Float(n) if n.is_finite() => Value::Number(n),
+        toml::Value::Float(n) => bail!("{} isn't a number an option can take", n),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Array(items) => {
+            Value::Array(items.into_iter().map(convert).collect::<Result<_>>()?)
+        }
+        toml::Value::Datetime(_) => bail!("dates aren't supported"),
+        toml::Value::Table(_) => bail!("tables aren't supported; set options at the top level"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, parse};
+    use crate::{json::Value, processor::Options};
+    use clap::{Arg, Args, Command};
+    use std::{ffi::OsString, fs, path::PathBuf};
+
+    #[test]
+    fn test_config() {
+        let entries = parse(
+            "# profile\ngroup = 0.5 # degrees\n\"keep-zero\" = true\ntransform = [\n  'scale:0.001',\n  \"clamp:0,\\u0031\", # trailing\n]\n",
+        )
+        .unwrap();
+        assert_eq!(entries[0], ("group".to_string(), Value::Number(0.5)));
+        assert_eq!(entries[1], ("keep-zero".to_string(), Value::Bool(true)));
+        assert_eq!(
+            entries[2].1,
+            Value::Array(vec!["scale:0.001".into(), "clamp:0,1".into()])
+        );
+        let error = parse("format = \"gpkg\"\n[output]\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Line 2: output: tables aren't supported; set options at the top level"
+        );
+        assert_eq!(
+            parse("group = 0.5\nsample.seed = 1\n")
+                .unwrap_err()
+                .to_string(),
+            "Line 2: sample: tables aren't supported; set options at the top level"
+        );
+        let error = parse("\ntime = 2024-01-01\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 2: time: dates aren't supported");
+        assert!(parse("group = 0.5\ngroup = 1")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Line 2: "));
+        assert!(parse("group = half").is_err());
+        assert!(parse("group = nan").is_err());
+        assert_eq!(
+            parse("description = \"\"\"\nfirst\nsecond\"\"\"\n").unwrap()[0].1,
+            Value::from("first\nsecond")
+        );
+
+        let dir = std::env::temp_dir().join(format!("config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("profile.toml");
+        fs::write(
+            &config,
+            "inputs = \"*.tif\"\ngroup = 0.5\nformat = \"gpkg\"\ntransform = [\"scale:2\"]\n",
+        )
+        .unwrap();
+        let command = Options::augment_args(
+            Command::new("image-stats")
+                .arg(
+                    Arg::new("input_path")
+                        .num_args(0..)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        );
+        let args = |given: &[&str]| -> Vec<String> {
+            let given = ["image-stats", "--config", config.to_str().unwrap()]
+                .iter()
+                .chain(given)
+                .map(OsString::from)
+                .collect();
+            apply(command.clone(), given)
+                .unwrap()
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect()
+        };
+        let tif = |name| dir.join(name).to_str().unwrap().to_string();
+        assert_eq!(
+            args(&[])[1..5],
+            [
+                "--group=0.5".to_string(),
+                "--format=gpkg".to_string(),
+                "--transform=scale:2".to_string(),
+                tif("*.tif"),
+            ]
+        );
+        // The command line's inputs, format and s2 level replace the file's, and s2
+        // conflicts with grouping.
+        assert_eq!(
+            args(&["--s2", "10", "--format", "fgb", "c.tif"])[1..],
+            [
+                "--transform=scale:2",
+                "--config",
+                config.to_str().unwrap(),
+                "--s2",
+                "10",
+                "--format",
+                "fgb",
+                "c.tif"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
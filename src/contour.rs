@@ -0,0 +1,257 @@
+//! Isolines through a raster's values, traced with marching squares and written as
+//! GeoJSON.
+
+use crate::{
+    crs::Crs,
+    georef::GeoTransform,
+    json::Value,
+    load_tif_contents,
+    metadata::SourceMetadata,
+    raster,
+    raster::{ChunkSize, Layout},
+    sidecar, DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{Context, Result};
+use std::{collections::HashMap, io::Cursor, path::PathBuf};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct ContoursArgs {
+    /// The raster to trace.
+    raster: PathBuf,
+    /// Values to draw isolines at, as a comma separated list.
+    #[arg(long = "levels", value_delimiter = ',', required = true)]
+    levels: Vec<f64>,
+    /// Where to write the GeoJSON. Defaults to the raster's path with a `.geojson`
+    /// extension.
+    #[arg(long = "output", short = 'o')]
+    output: Option<PathBuf>,
+    /// CRS of the raster, read with its GeoTIFF tags. Without it the raster is taken to be
+    /// a global grid.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+}
+
+/// The raster's values, row by row, with `NaN` for nodata. Unlike conversion, zero and
+/// negative values are kept, since isolines of elevation cross them.
+struct Grid {
+    width: u32,
+    height: u32,
+    values: Vec<f64>,
+}
+
+/// An edge between two neighbouring pixel centers: the one from `(x, y)` to `(x + 1, y)`
+/// if `across`, otherwise the one from `(x, y)` to `(x, y + 1)`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct Edge {
+    across: bool,
+    x: u32,
+    y: u32,
+}
+
+impl Grid {
+    fn get(&self, x: u32, y: u32) -> f64 {
+        self.values[y as usize * self.width as usize + x as usize]
+    }
+
+    /// Where the isoline at `level` crosses `edge`, in pixel coordinates.
+    fn crossing(&self, edge: Edge, level: f64) -> (f64, f64) {
+        let (x1, y1) = if edge.across {
+            (edge.x + 1, edge.y)
+        } else {
+            (edge.x, edge.y + 1)
+        };
+        let (a, b) = (self.get(edge.x, edge.y), self.get(x1, y1));
+        let t = if a == b { 0.5 } else { (level - a) / (b - a) };
+        // Pixel values are sampled at pixel centers.
+        (
+            edge.x as f64 + 0.5 + t * (x1 as f64 - edge.x as f64),
+            edge.y as f64 + 0.5 + t * (y1 as f64 - edge.y as f64),
+        )
+    }
+
+    /// The pieces of the isoline at `level` in each square between four pixel centers, as
+    /// the pair of edges each piece joins. Squares with a nodata corner have none.
+    fn segments(&self, level: f64) -> Vec<(Edge, Edge)> {
+        let mut segments = vec![];
+        for y in 0..self.height.saturating_sub(1) {
+            for x in 0..self.width.saturating_sub(1) {
+                let corners = [
+                    self.get(x, y),
+                    self.get(x + 1, y),
+                    self.get(x + 1, y + 1),
+                    self.get(x, y + 1),
+                ];
+                if corners.iter().any(|v| v.is_nan()) {
+                    continue;
+                }
+                let top = Edge { across: true, x, y };
+                let right = Edge {
+                    across: false,
+                    x: x + 1,
+                    y,
+                };
+                let bottom = Edge {
+                    across: true,
+                    x,
+                    y: y + 1,
+                };
+                let left = Edge {
+                    across: false,
+                    x,
+                    y,
+                };
+                let above = |i: usize| corners[i] >= level;
+                let case = (above(0) as u8) << 3
+                    | (above(1) as u8) << 2
+                    | (above(2) as u8) << 1
+                    | above(3) as u8;
+                // Saddles are split by whether the square's center is above the level.
+                let center_above = corners.iter().sum::<f64>() / 4.0 >= level;
+                match case {
+                    1 | 14 => segments.push((left, bottom)),
+                    2 | 13 => segments.push((bottom, right)),
+                    3 | 12 => segments.push((left, right)),
+                    4 | 11 => segments.push((top, right)),
+                    6 | 9 => segments.push((top, bottom)),
+                    7 | 8 => segments.push((left, top)),
+                    5 if center_above => segments.extend([(left, top), (bottom, right)]),
+                    5 => segments.extend([(left, bottom), (top, right)]),
+                    10 if center_above => segments.extend([(left, bottom), (top, right)]),
+                    10 => segments.extend([(left, top), (bottom, right)]),
+                    _ => {}
+                }
+            }
+        }
+        segments
+    }
+}
+
+/// Joins segments sharing an edge into lines, each a list of the edges it crosses. A
+/// closed ring ends with its first edge.
+fn chain(segments: &[(Edge, Edge)]) -> Vec<Vec<Edge>> {
+    let mut by_edge = HashMap::<Edge, Vec<usize>>::new();
+    for (i, (a, b)) in segments.iter().enumerate() {
+        by_edge.entry(*a).or_default().push(i);
+        by_edge.entry(*b).or_default().push(i);
+    }
+    let mut used = vec![false; segments.len()];
+    // Follows unused segments on from the line's last edge, appending the edges reached.
+    let extend = |line: &mut Vec<Edge>, used: &mut [bool]| loop {
+        let edge = *line.last().unwrap();
+        let next = by_edge[&edge].iter().copied().find(|&i| !used[i]);
+        let Some(i) = next else { break };
+        used[i] = true;
+        let (a, b) = segments[i];
+        line.push(if a == edge { b } else { a });
+    };
+    let mut lines = vec![];
+    for start in 0..segments.len() {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        let mut line = vec![segments[start].0, segments[start].1];
+        extend(&mut line, &mut used);
+        if line.first() != line.last() {
+            line.reverse();
+            extend(&mut line, &mut used);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+pub fn run(args: &ContoursArgs) -> Result<()> {
+    let contents = load_tif_contents(&args.raster)?;
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let source = SourceMetadata::read(&mut decoder)?;
+    let src_crs = sidecar::src_crs(&args.raster, args.src_crs, &mut decoder)?;
+    let transform = GeoTransform::resolve(&mut decoder, src_crs, None)?;
+    let (width, height) = decoder.dimensions()?;
+    let nodata = source
+        .nodata
+        .as_deref()
+        .and_then(|n| n.trim().parse::<f64>().ok());
+    let mut grid = Grid {
+        width,
+        height,
+        values: vec![f64::NAN; width as usize * height as usize],
+    };
+    let pixels = raster::read_pixels(
+        &contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |_, _, _, _| true,
+        |_| {},
+        |x, y, value| (value.is_finite() && Some(value) != nodata).then_some((x, y, value)),
+    )?;
+    for (x, y, value) in pixels {
+        grid.values[y as usize * width as usize + x as usize] = value;
+    }
+
+    let mut features = vec![];
+    for &level in &args.levels {
+        for line in chain(&grid.segments(level)) {
+            let coordinates = line
+                .into_iter()
+                .map(|edge| {
+                    let (x, y) = grid.crossing(edge, level);
+                    let (lon, lat) = transform.position(x, y);
+                    Value::from(vec![lon, lat])
+                })
+                .collect();
+            features.push(Value::object([
+                ("type", Value::from("Feature")),
+                ("properties", Value::object([("level", Value::from(level))])),
+                (
+                    "geometry",
+                    Value::object([
+                        ("type", Value::from("LineString")),
+                        ("coordinates", Value::Array(coordinates)),
+                    ]),
+                ),
+            ]));
+        }
+    }
+    let collection = Value::object([
+        ("type", Value::from("FeatureCollection")),
+        ("features", Value::Array(features)),
+    ]);
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.raster.with_extension("geojson"));
+    std::fs::write(&output, collection.to_string())
+        .with_context(|| format!("Could not write {}", output.to_string_lossy()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chain, Grid};
+
+    #[test]
+    fn test_ring_around_peak() {
+        #[rustfmt::skip]
+        let grid = Grid {
+            width: 3,
+            height: 3,
+            values: vec![
+                0.0, 0.0, 0.0,
+                0.0, 4.0, 0.0,
+                0.0, 0.0, 0.0,
+            ],
+        };
+        let lines = chain(&grid.segments(2.0));
+        assert_eq!(lines.len(), 1);
+        let ring = &lines[0];
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring.first(), ring.last());
+        for edge in ring {
+            let (x, y) = grid.crossing(*edge, 2.0);
+            assert_eq!((x - 1.5).abs() + (y - 1.5).abs(), 0.5);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Checking outputs against a `--contract`, a JSON description of the columns, metadata
+//! and sort order that consumers of the output rely on, so a change in the tool or its
+//! options can't silently change the interface.
+//!
+//! ```json
+//! {
+//!   "columns": [
+//!     {"name": "lon", "type": "Float32", "metadata": {"unit": "degrees"}},
+//!     {"name": "lat", "type": "Float32"},
+//!     {"name": "value", "type": "Float32"}
+//!   ],
+//!   "metadata": {"source": "population"},
+//!   "sorted_by": ["lat", "lon"]
+//! }
+//! ```
+//!
+//! Types are named as arrow displays them. Metadata lists keys that must be present with
+//! these values, and others may be present too.
+
+use crate::json::{self, Value};
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_array::{
+    cast::{as_primitive_array, as_string_array},
+    types::Float64Type,
+    Array, ArrayRef, Float64Array, RecordBatch, StringArray,
+};
+use arrow_schema::{DataType, Schema};
+use std::{cmp::Ordering, collections::HashMap, path::Path};
+
+#[derive(Debug)]
+pub struct Contract {
+    columns: Vec<Column>,
+    metadata: Vec<(String, String)>,
+    sorted_by: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Column {
+    name: String,
+    data_type: String,
+    metadata: Vec<(String, String)>,
+}
+
+impl Contract {
+    pub fn load(path: &Path) -> Result<Contract> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read contract {}", path.display()))?;
+        json::parse(&text)
+            .and_then(|value| Contract::from_json(&value))
+            .with_context(|| format!("In contract {}", path.display()))
+    }
+
+    fn from_json(value: &Value) -> Result<Contract> {
+        let columns = value
+            .get("columns")
+            .and_then(|columns| columns.as_array())
+            .ok_or_else(|| anyhow!("It has no \"columns\" array"))?
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                let text = |key: &str| {
+                    column
+                        .get(key)
+                        .and_then(|text| text.as_str())
+                        .map(str::to_string)
+                        .ok_or_else(|| anyhow!("Column {} has no \"{}\" string", i, key))
+                };
+                Ok(Column {
+                    name: text("name")?,
+                    data_type: text("type")?,
+                    metadata: metadata(column.get("metadata"))?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let sorted_by = match value.get("sorted_by") {
+            None => vec![],
+            Some(names) => names
+                .as_array()
+                .ok_or_else(|| anyhow!("\"sorted_by\" is not an array"))?
+                .iter()
+                .map(|name| match name.as_str() {
+                    Some(name) if columns.iter().any(|column| column.name == name) => {
+                        Ok(name.to_string())
+                    }
+                    Some(name) => bail!("It is sorted by {}, which is not a column", name),
+                    None => bail!("\"sorted_by\" holds {}, not a column name", name),
+                })
+                .collect::<Result<_>>()?,
+        };
+        Ok(Contract {
+            columns,
+            metadata: metadata(value.get("metadata"))?,
+            sorted_by,
+        })
+    }
+
+    /// Fails, listing every difference, unless `batch` meets the contract.
+    pub fn check(&self, batch: &RecordBatch) -> Result<()> {
+        self.check_schema(&batch.schema())?;
+        self.check_sorted(batch)
+    }
+
+    /// Fails, listing every difference, unless a table with `schema` could meet the
+    /// contract, whatever its rows.
+    pub fn check_schema(&self, schema: &Schema) -> Result<()> {
+        let mut problems = vec![];
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        let expected: Vec<_> = self.columns.iter().map(|c| c.name.as_str()).collect();
+        if names != expected {
+            problems.push(format!(
+                "The columns are {}, but should be {}",
+                names.join(", "),
+                expected.join(", ")
+            ));
+        }
+        for column in &self.columns {
+            let Ok(field) = schema.field_with_name(&column.name) else {
+                continue;
+            };
+            let data_type = field.data_type().to_string();
+            if data_type != column.data_type {
+                problems.push(format!(
+                    "Column {} is {}, but should be {}",
+                    column.name, data_type, column.data_type
+                ));
+            }
+            problems.extend(metadata_problems(
+                &format!("Column {}", column.name),
+                field.metadata(),
+                &column.metadata,
+            ));
+        }
+        problems.extend(metadata_problems(
+            "The table",
+            schema.metadata(),
+            &self.metadata,
+        ));
+        match problems.is_empty() {
+            true => Ok(()),
+            false => bail!(
+                "The output breaks its contract:\n  {}",
+                problems.join("\n  ")
+            ),
+        }
+    }
+
+    fn check_sorted(&self, batch: &RecordBatch) -> Result<()> {
+        let keys = self
+            .sorted_by
+            .iter()
+            .map(|name| {
+                let column = batch
+                    .column_by_name(name)
+                    .ok_or_else(|| anyhow!("The output has no column {} to sort by", name))?;
+                SortKey::new(column).with_context(|| format!("Could not compare {}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for row in 1..batch.num_rows() {
+            let order = keys
+                .iter()
+                .map(|key| key.compare(row - 1, row))
+                .find(|order| order.is_ne())
+                .unwrap_or(Ordering::Equal);
+            if order.is_gt() {
+                bail!(
+                    "The output breaks its contract:\n  Row {} is out of order, as rows should \
+                     be sorted by {}",
+                    row,
+                    self.sorted_by.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The string values of a JSON object of metadata, or none if it is missing.
+fn metadata(value: Option<&Value>) -> Result<Vec<(String, String)>> {
+    match value {
+        None => Ok(vec![]),
+        Some(Value::Object(entries)) => entries
+            .iter()
+            .map(|(key, value)| match value.as_str() {
+                Some(text) => Ok((key.clone(), text.to_string())),
+                None => bail!("Metadata {} is {}, not a string", key, value),
+            })
+            .collect(),
+        Some(other) => bail!("Metadata {} is not an object", other),
+    }
+}
+
+fn metadata_problems(
+    owner: &str,
+    actual: &HashMap<String, String>,
+    expected: &[(String, String)],
+) -> Vec<String> {
+    expected
+        .iter()
+        .filter_map(|(key, value)| match actual.get(key) {
+            Some(actual) if actual == value => None,
+            Some(actual) => Some(format!(
+                "{} has {} metadata {:?}, but should have {:?}",
+                owner, key, actual, value
+            )),
+            None => Some(format!(
+                "{} has no {} metadata, but should have {:?}",
+                owner, key, value
+            )),
+        })
+        .collect()
+}
+
+/// A column's values in a form rows can be ordered by, with nulls first.
+enum SortKey {
+    Numbers(Float64Array),
+    Strings(StringArray),
+}
+
+impl SortKey {
+    fn new(column: &ArrayRef) -> Result<SortKey> {
+        let data_type = column.data_type();
+        Ok(if data_type.is_numeric() {
+            let numbers = arrow_cast::cast(column, &DataType::Float64)?;
+            SortKey::Numbers(as_primitive_array::<Float64Type>(&numbers).clone())
+        } else if data_type.is_temporal() {
+            let numbers = arrow_cast::cast(column, &DataType::Int64)?;
+            let numbers = arrow_cast::cast(&numbers, &DataType::Float64)?;
+            SortKey::Numbers(as_primitive_array::<Float64Type>(&numbers).clone())
+        } else if data_type == &DataType::Utf8 {
+            SortKey::Strings(as_string_array(column).clone())
+        } else {
+            bail!("Rows can't be ordered by {} values", data_type)
+        })
+    }
+
+    fn compare(&self, a: usize, b: usize) -> Ordering {
+        match self {
+            SortKey::Numbers(numbers) => {
+                let value = |i| (!numbers.is_null(i)).then(|| numbers.value(i));
+                match (value(a), value(b)) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (a, b) => a.is_some().cmp(&b.is_some()),
+                }
+            }
+            SortKey::Strings(strings) => {
+                let value = |i| (!strings.is_null(i)).then(|| strings.value(i));
+                value(a).cmp(&value(b))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Contract;
+    use crate::json;
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use arrow_schema::{DataType, Field, Schema};
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_contract() {
+        let contract = Contract::from_json(
+            &json::parse(
+                r#"{"columns": [
+                    {"name": "lon", "type": "Float32", "metadata": {"unit": "degrees"}},
+                    {"name": "value", "type": "Float32"}
+                ], "sorted_by": ["lon"]}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let batch = |unit: &str, lons: Vec<f32>| {
+            let metadata = HashMap::from([("unit".to_string(), unit.to_string())]);
+            let schema = Schema::new(vec![
+                Field::new("lon", DataType::Float32, false).with_metadata(metadata),
+                Field::new("value", DataType::Float32, false),
+            ]);
+            let values = Float32Array::from(vec![1.0; lons.len()]);
+            let columns: Vec<ArrayRef> = vec![Arc::new(Float32Array::from(lons)), Arc::new(values)];
+            RecordBatch::try_new(Arc::new(schema), columns).unwrap()
+        };
+        assert!(contract
+            .check(&batch("degrees", vec![1.0, 2.0, 2.0]))
+            .is_ok());
+        let error = contract.check(&batch("meters", vec![1.0])).unwrap_err();
+        assert!(error.to_string().contains("unit metadata \"meters\""));
+        let error = contract
+            .check(&batch("degrees", vec![2.0, 1.0]))
+            .unwrap_err();
+        assert!(error.to_string().contains("Row 1 is out of order"));
+
+        // Sorting by a column the contract doesn't have is a mistake in the contract.
+        let unknown = json::parse(r#"{"columns": [], "sorted_by": ["lat"]}"#).unwrap();
+        assert!(Contract::from_json(&unknown).is_err());
+    }
+}
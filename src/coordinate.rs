@@ -0,0 +1,101 @@
+//! Sharing a batch between workers through lock files in a common directory.
+//!
+//! Each input is claimed by creating its lock file, which only one worker can do. Locks
+//! are named after a hash of the input's path relative to the directory or glob it was
+//! found in, so workers that mount the inputs in different places still share them. The
+//! file stays once the input is written, so reruns skip it, and is removed if processing
+//! fails so another worker can retry. A worker that dies mid-input leaves its lock behind;
+//! delete it to let the input be claimed again.
+
+use crate::checksum::{self, Algorithm};
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// An input this worker has claimed.
+pub struct Lock {
+    path: PathBuf,
+    file: File,
+}
+
+/// Claims the input at `key`, its path relative to its root, in `dir`, or returns `None`
+/// if another worker already has.
+pub fn claim(dir: &Path, key: &Path) -> Result<Option<Lock>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create lock directory {}", dir.display()))?;
+    let path = dir.join(lock_name(key)?);
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Could not create lock {}", path.display()))
+        }
+    };
+    writeln!(
+        file,
+        "claimed by pid {} at {}",
+        std::process::id(),
+        unix_time()
+    )?;
+    Ok(Some(Lock { path, file }))
+}
+
+impl Lock {
+    /// Marks the input as written, keeping the lock so it is never claimed again.
+    pub fn complete(mut self) -> Result<()> {
+        writeln!(self.file, "completed at {}", unix_time())?;
+        Ok(())
+    }
+
+    /// Gives the input up so another worker can claim it.
+    pub fn release(self) -> Result<()> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Could not remove lock {}", self.path.display()))
+    }
+}
+
+/// Names the lock file after the SHA-256 of `key`, so names have the same length however
+/// deep the input is, and inputs with the same file name in different directories don't
+/// share a lock.
+fn lock_name(key: &Path) -> Result<String> {
+    let key = key.to_string_lossy().replace('\\', "/");
+    Ok(checksum::hash(key.as_bytes(), Algorithm::Sha256)? + ".lock")
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{claim, lock_name};
+    use std::path::Path;
+
+    #[test]
+    fn test_claim_once() {
+        let dir = std::env::temp_dir().join(format!("image-stats-locks-{}", std::process::id()));
+        let input = dir.join("does/not/exist.tif");
+        let lock = claim(&dir, &input).unwrap().unwrap();
+        assert!(claim(&dir, &input).unwrap().is_none());
+        lock.release().unwrap();
+        let lock = claim(&dir, &input).unwrap().unwrap();
+        lock.complete().unwrap();
+        assert!(claim(&dir, &input).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_name() {
+        let short = lock_name(Path::new("a.tif")).unwrap();
+        let deep = lock_name(&Path::new("dir/").join("x".repeat(300)).join("a.tif")).unwrap();
+        assert_eq!(short.len(), 64 + ".lock".len());
+        assert_eq!(deep.len(), short.len());
+        assert_ne!(lock_name(Path::new("b/a.tif")).unwrap(), short);
+    }
+}
@@ -0,0 +1,543 @@
+//! The coordinate reference systems pixels can be converted between, projected with
+//! proj4rs.
+//!
+//! A CRS is given by a PROJ string, or by an EPSG code, which is looked up among the
+//! common ones listed here, including every WGS 84, ETRS89 and NAD83 UTM zone.
+
+use anyhow::{anyhow, bail, Result};
+use proj4rs::{transform::transform, Proj};
+use std::{
+    fmt::{self, Debug, Display},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+use tiff::{decoder::Decoder, tags::Tag};
+
+/// A coordinate reference system. CRSs are only parsed once, so copies are cheap and equal
+/// ones share their definition.
+#[derive(Clone, Copy)]
+pub struct Crs(&'static Definition);
+
+struct Definition {
+    epsg: Option<u32>,
+    proj_string: String,
+    proj: Proj,
+}
+
+/// The PROJ strings of EPSG codes other than UTM zones.
+const EPSG: [(u32, &str); 15] = [
+    (4326, "+proj=longlat +datum=WGS84 +no_defs"),
+    (
+        4258,
+        "+proj=longlat +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +no_defs",
+    ),
+    (4269, "+proj=longlat +datum=NAD83 +no_defs"),
+    (
+        4283,
+        "+proj=longlat +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +no_defs",
+    ),
+    (
+        3857,
+        "+proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +k=1 +units=m \
+         +nadgrids=@null +no_defs",
+    ),
+    (
+        3395,
+        "+proj=merc +lon_0=0 +k=1 +x_0=0 +y_0=0 +datum=WGS84 +units=m +no_defs",
+    ),
+    (
+        3035,
+        "+proj=laea +lat_0=52 +lon_0=10 +x_0=4321000 +y_0=3210000 +ellps=GRS80 \
+         +towgs84=0,0,0,0,0,0,0 +units=m +no_defs",
+    ),
+    (
+        27700,
+        "+proj=tmerc +lat_0=49 +lon_0=-2 +k=0.9996012717 +x_0=400000 +y_0=-100000 +ellps=airy \
+         +towgs84=446.448,-125.157,542.06,0.15,0.247,0.842,-20.489 +units=m +no_defs",
+    ),
+    (
+        2154,
+        "+proj=lcc +lat_0=46.5 +lon_0=3 +lat_1=49 +lat_2=44 +x_0=700000 +y_0=6600000 \
+         +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs",
+    ),
+    (
+        28992,
+        "+proj=sterea +lat_0=52.1561605555556 +lon_0=5.38763888888889 +k=0.9999079 \
+         +x_0=155000 +y_0=463000 +ellps=bessel \
+         +towgs84=565.417,50.3319,465.552,-0.398957,0.343988,-1.8774,4.0725 +units=m +no_defs",
+    ),
+    (
+        5070,
+        "+proj=aea +lat_0=23 +lon_0=-96 +lat_1=29.5 +lat_2=45.5 +x_0=0 +y_0=0 +datum=NAD83 \
+         +units=m +no_defs",
+    ),
+    (
+        3577,
+        "+proj=aea +lat_0=0 +lon_0=132 +lat_1=-18 +lat_2=-36 +x_0=0 +y_0=0 +ellps=GRS80 \
+         +towgs84=0,0,0,0,0,0,0 +units=m +no_defs",
+    ),
+    (
+        3031,
+        "+proj=stere +lat_0=-90 +lat_ts=-71 +lon_0=0 +x_0=0 +y_0=0 +datum=WGS84 +units=m \
+         +no_defs",
+    ),
+    (
+        3413,
+        "+proj=stere +lat_0=90 +lat_ts=70 +lon_0=-45 +x_0=0 +y_0=0 +datum=WGS84 +units=m \
+         +no_defs",
+    ),
+    (
+        6933,
+        "+proj=cea +lat_ts=30 +lon_0=0 +x_0=0 +y_0=0 +datum=WGS84 +units=m +no_defs",
+    ),
+];
+
+/// The PROJ string of an EPSG code, if it is listed or a UTM zone.
+fn epsg_proj_string(code: u32) -> Option<String> {
+    if let Some((_, proj_string)) = EPSG.iter().find(|(c, _)| *c == code) {
+        return Some(proj_string.to_string());
+    }
+    let (zone, datum) = match code {
+        32601..=32660 => (code - 32600, "+datum=WGS84"),
+        32701..=32760 => (code - 32700, "+south +datum=WGS84"),
+        25828..=25838 => (code - 25800, "+ellps=GRS80 +towgs84=0,0,0,0,0,0,0"),
+        26901..=26923 => (code - 26900, "+datum=NAD83"),
+        _ => return None,
+    };
+    Some(format!(
+        "+proj=utm +zone={} {} +units=m +no_defs",
+        zone, datum
+    ))
+}
+
+impl FromStr for Crs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let code = s.trim();
+        if code.starts_with('+') {
+            return Crs::from_proj_string(code);
+        }
+        let code = code
+            .strip_prefix("EPSG:")
+            .or(code.strip_prefix("epsg:"))
+            .unwrap_or(code);
+        Crs::from_epsg(code.parse().map_err(|_| {
+            anyhow!(
+                "Expected a CRS like `EPSG:4326` or a PROJ string like `+proj=utm +zone=33` \
+                 but got {}",
+                s
+            )
+        })?)
+    }
+}
+
+impl Display for Crs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.epsg {
+            Some(code) => write!(f, "EPSG:{}", code),
+            None => write!(f, "{}", self.0.proj_string),
+        }
+    }
+}
+
+impl Debug for Crs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Crs({})", self)
+    }
+}
+
+impl PartialEq for Crs {
+    fn eq(&self, other: &Crs) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+/// Semi-major axis shared by WGS 84 and GRS 1980.
+const A: f64 = 6_378_137.0;
+/// First eccentricity of the GRS 1980 ellipsoid.
+const GRS80_E: f64 = 0.081_819_191_042_815_79;
+
+impl Crs {
+    /// EPSG:4326, lon/lat in degrees on WGS 84.
+    pub fn wgs84() -> Crs {
+        static WGS84: OnceLock<Crs> = OnceLock::new();
+        *WGS84.get_or_init(|| Crs::from_epsg(4326).expect("EPSG:4326 is listed"))
+    }
+
+    pub fn from_epsg(code: u32) -> Result<Crs> {
+        let Some(proj_string) = epsg_proj_string(code) else {
+            bail!(
+                "Unsupported CRS EPSG:{}, give it as a PROJ string such as `+proj=utm +zone=33 \
+                 +datum=WGS84` instead",
+                code
+            );
+        };
+        Crs::define(Some(code), proj_string)
+    }
+
+    pub fn from_proj_string(proj_string: &str) -> Result<Crs> {
+        let proj_string = proj_string.split_whitespace().collect::<Vec<_>>().join(" ");
+        Crs::define(None, proj_string)
+    }
+
+    /// The CRS of `proj_string`, parsed the first time it is asked for.
+    fn define(epsg: Option<u32>, proj_string: String) -> Result<Crs> {
+        static DEFINITIONS: Mutex<Vec<&'static Definition>> = Mutex::new(vec![]);
+        let mut definitions = DEFINITIONS.lock().unwrap_or_else(|e| e.into_inner());
+        let defined = definitions
+            .iter()
+            .find(|d| d.epsg == epsg && d.proj_string == proj_string);
+        if let Some(definition) = defined {
+            return Ok(Crs(definition));
+        }
+        let proj = Proj::from_proj_string(&proj_string)
+            .map_err(|e| anyhow!("Could not read the CRS {}: {}", proj_string, e))?;
+        let definition = Box::leak(Box::new(Definition {
+            epsg,
+            proj_string,
+            proj,
+        }));
+        definitions.push(definition);
+        Ok(Crs(definition))
+    }
+
+    /// The CRS's EPSG code, or `None` if it was given as a PROJ string.
+    pub fn epsg(self) -> Option<u32> {
+        self.0.epsg
+    }
+
+    /// The CRS in the ESRI flavour of WKT that shapefile `.prj` files use, for the CRSs
+    /// it is known for.
+    pub fn esri_wkt(self) -> Option<&'static str> {
+        Some(match self.epsg()? {
+            4326 => concat!(
+                r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984","#,
+                r#"SPHEROID["WGS_1984",6378137.0,298.257223563]],"#,
+                r#"PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]]"#
+            ),
+            3857 => concat!(
+                r#"PROJCS["WGS_1984_Web_Mercator_Auxiliary_Sphere","#,
+                r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984","#,
+                r#"SPHEROID["WGS_1984",6378137.0,298.257223563]],"#,
+                r#"PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]],"#,
+                r#"PROJECTION["Mercator_Auxiliary_Sphere"],PARAMETER["False_Easting",0.0],"#,
+                r#"PARAMETER["False_Northing",0.0],PARAMETER["Central_Meridian",0.0],"#,
+                r#"PARAMETER["Standard_Parallel_1",0.0],PARAMETER["Auxiliary_Sphere_Type",0.0],"#,
+                r#"UNIT["Meter",1.0]]"#
+            ),
+            3035 => concat!(
+                r#"PROJCS["ETRS_1989_LAEA",GEOGCS["GCS_ETRS_1989",DATUM["D_ETRS_1989","#,
+                r#"SPHEROID["GRS_1980",6378137.0,298.257222101]],"#,
+                r#"PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]],"#,
+                r#"PROJECTION["Lambert_Azimuthal_Equal_Area"],"#,
+                r#"PARAMETER["False_Easting",4321000.0],PARAMETER["False_Northing",3210000.0],"#,
+                r#"PARAMETER["Central_Meridian",10.0],PARAMETER["Latitude_Of_Origin",52.0],"#,
+                r#"UNIT["Meter",1.0]]"#
+            ),
+            _ => return None,
+        })
+    }
+
+    /// The CRS of WKT such as a `.prj` file's, by the last EPSG authority it cites, which
+    /// is the outermost, or else by its name. `None` if it isn't one of the known CRSs.
+    pub fn from_wkt(wkt: &str) -> Option<Crs> {
+        const AUTHORITY: &str = "AUTHORITY[\"EPSG\",";
+        if let Some(start) = wkt.rfind(AUTHORITY) {
+            let code = wkt[start + AUTHORITY.len()..]
+                .trim_start_matches('"')
+                .split(|c: char| !c.is_ascii_digit())
+                .next()?;
+            return Crs::from_epsg(code.parse().ok()?).ok();
+        }
+        let name = |wkt: &str| wkt.split('"').nth(1).map(|n| n.replace(' ', "_"));
+        let wkt_name = name(wkt)?;
+        let code = [4326, 3857, 3035]
+            .into_iter()
+            .find(|&code| {
+                let esri_wkt = Crs::from_epsg(code).ok().and_then(Crs::esri_wkt);
+                esri_wkt.and_then(name).as_ref() == Some(&wkt_name)
+            })
+            .or(match wkt_name.as_str() {
+                "WGS_84" => Some(4326),
+                "WGS_84_/_Pseudo-Mercator" => Some(3857),
+                "ETRS89_/_LAEA_Europe" => Some(3035),
+                _ => None,
+            })?;
+        Crs::from_epsg(code).ok()
+    }
+
+    pub fn is_geographic(self) -> bool {
+        self.0.proj.is_latlong()
+    }
+
+    /// Whether the CRS's projection keeps areas, so its units' squares are the same area
+    /// everywhere.
+    fn is_equal_area(self) -> bool {
+        matches!(self.0.proj.projname(), "laea" | "aea" | "cea" | "moll")
+    }
+
+    /// Area of a pixel of a raster in this CRS at WGS 84 `(lon, lat)`, relative to one at
+    /// the equator, or anywhere for equal area projections.
+    pub fn relative_pixel_area(self, lon: f64, lat: f64) -> f64 {
+        if self.is_geographic() {
+            return lat.to_radians().cos();
+        }
+        if self.is_equal_area() {
+            return 1.0;
+        }
+        self.area_scale(lon, lat) / self.area_scale(lon, 0.0)
+    }
+
+    /// True area in square metres of the rectangle from `(west, south)` to `(east, north)`
+    /// in this CRS's coordinates, on the GRS 1980 ellipsoid, which WGS 84 matches to well
+    /// under a square metre per square kilometre. Rectangles in projections that don't
+    /// keep areas are taken to be small enough for their scale not to change across them.
+    pub fn rect_area(self, west: f64, south: f64, east: f64, north: f64) -> f64 {
+        if self.is_geographic() {
+            return lon_lat_area(west, south, east, north);
+        }
+        let area = (east - west).abs() * (north - south).abs();
+        if self.is_equal_area() {
+            return area * self.0.proj.to_meter().powi(2);
+        }
+        let (lon, lat) = self.to_lon_lat((west + east) / 2.0, (south + north) / 2.0);
+        area * self.area_scale(lon, lat)
+    }
+
+    /// The true area in square metres of a square of this CRS's units at WGS 84
+    /// `(lon, lat)`, from the area a small lon/lat cell there is projected to.
+    fn area_scale(self, lon: f64, lat: f64) -> f64 {
+        const STEP: f64 = 1e-3;
+        let (x0, y0) = self.project(lon - STEP, lat - STEP);
+        let (x1, y1) = self.project(lon + STEP, lat - STEP);
+        let (x2, y2) = self.project(lon - STEP, lat + STEP);
+        let projected = ((x1 - x0) * (y2 - y0) - (y1 - y0) * (x2 - x0)).abs();
+        lon_lat_area(lon - STEP, lat - STEP, lon + STEP, lat + STEP) / projected
+    }
+
+    /// Converts coordinates in this CRS to WGS 84 `(lon, lat)`, or NaNs where the CRS's
+    /// projection isn't defined.
+    pub fn to_lon_lat(self, x: f64, y: f64) -> (f64, f64) {
+        let wgs84 = Crs::wgs84();
+        if self == wgs84 {
+            return (x, y);
+        }
+        let (x, y) = self.radians(x, y);
+        let (lon, lat) = convert(self, wgs84, x, y);
+        (lon.to_degrees(), lat.to_degrees())
+    }
+
+    /// Converts WGS 84 `(lon, lat)` to coordinates in this CRS, or NaNs where its
+    /// projection isn't defined.
+    pub fn project(self, lon: f64, lat: f64) -> (f64, f64) {
+        let wgs84 = Crs::wgs84();
+        if self == wgs84 {
+            return (lon, lat);
+        }
+        let (x, y) = convert(wgs84, self, lon.to_radians(), lat.to_radians());
+        match self.is_geographic() {
+            true => (x.to_degrees(), y.to_degrees()),
+            false => (x, y),
+        }
+    }
+
+    /// Coordinates in this CRS as proj4rs takes them, with angles in radians.
+    fn radians(self, x: f64, y: f64) -> (f64, f64) {
+        match self.is_geographic() {
+            true => (x.to_radians(), y.to_radians()),
+            false => (x, y),
+        }
+    }
+}
+
+fn convert(src: Crs, dst: Crs, x: f64, y: f64) -> (f64, f64) {
+    let mut point = (x, y, 0.0);
+    match transform(&src.0.proj, &dst.0.proj, &mut point) {
+        Ok(()) => (point.0, point.1),
+        Err(_) => (f64::NAN, f64::NAN),
+    }
+}
+
+/// True area in square metres of the lon/lat rectangle from `(west, south)` to
+/// `(east, north)` on the GRS 1980 ellipsoid.
+fn lon_lat_area(west: f64, south: f64, east: f64, north: f64) -> f64 {
+    let q = |lat: f64| authalic_q(GRS80_E, lat.to_radians());
+    A * A / 2.0 * (east - west).to_radians().abs() * (q(north) - q(south)).abs()
+}
+
+/// The `q` term of the authalic latitude of `phi` on an ellipsoid of eccentricity `e`.
+fn authalic_q(e: f64, phi: f64) -> f64 {
+    let sin = phi.sin();
+    (1.0 - e * e)
+        * (sin / (1.0 - e * e * sin * sin)
+            - 1.0 / (2.0 * e) * ((1.0 - e * sin) / (1.0 + e * sin)).ln())
+}
+
+/// The GeoKey directory declaring `crs`: pixels as areas, and its EPSG code, if it has
+/// one, as a geographic or projected CRS.
+pub fn geokeys(crs: Crs) -> Vec<u16> {
+    let (model, key) = match crs.is_geographic() {
+        true => (2, 2048),
+        false => (1, 3072),
+    };
+    let mut keys = vec![[1024, 0, 1, model], [1025, 0, 1, 1]];
+    keys.extend(crs.epsg().map(|code| [key, 0, 1, code as u16]));
+    let header = [1, 1, 0, keys.len() as u16];
+    [&[header][..], &keys].concat().concat()
+}
+
+/// Reads the EPSG code of the CRS declared in a GeoTIFF's GeoKey directory, if any.
+pub fn read_geokeys<R: std::io::Read + std::io::Seek>(
+    decoder: &mut Decoder<R>,
+) -> Result<Option<u32>> {
+    const GEOGRAPHIC_TYPE: u16 = 2048;
+    const PROJECTED_CS_TYPE: u16 = 3072;
+    /// Code GeoTIFF uses for "user defined", i.e. not an EPSG code.
+    const USER_DEFINED: u16 = 32767;
+
+    let mut code = None;
+    for (id, value) in read_inline_geokeys(decoder)? {
+        if value == USER_DEFINED {
+            continue;
+        }
+        match id {
+            PROJECTED_CS_TYPE => return Ok(Some(value as u32)),
+            GEOGRAPHIC_TYPE => code = Some(value as u32),
+            _ => {}
+        }
+    }
+    Ok(code)
+}
+
+/// Reads the `(id, value)` of each GeoKey stored inline in the GeoKey directory. Keys
+/// with a location point into other tags and are left out; the CRS codes are always
+/// inline.
+pub fn read_inline_geokeys<R: std::io::Read + std::io::Seek>(
+    decoder: &mut Decoder<R>,
+) -> Result<Vec<(u16, u16)>> {
+    let Some(directory) = decoder.find_tag(Tag::GeoKeyDirectoryTag)? else {
+        return Ok(vec![]);
+    };
+    let directory = directory.into_u16_vec()?;
+    // A header of four shorts, then a key id, location, count and value for each key.
+    Ok(directory
+        .get(4..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .filter(|key| key[1] == 0)
+        .map(|key| (key[0], key[3]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crs;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64), tolerance: f64) {
+        assert!(
+            (actual.0 - expected.0).abs() < tolerance && (actual.1 - expected.1).abs() < tolerance,
+            "{:?} should be within {} of {:?}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_laea() {
+        // The worked example from IOGP guidance note 7-2.
+        let laea: Crs = "EPSG:3035".parse().unwrap();
+        assert_close(laea.project(5.0, 50.0), (3_962_799.45, 2_999_718.85), 0.01);
+        assert_close(
+            laea.to_lon_lat(3_962_799.45, 2_999_718.85),
+            (5.0, 50.0),
+            1e-7,
+        );
+    }
+
+    #[test]
+    fn test_web_mercator() {
+        let mercator = Crs::from_epsg(3857).unwrap();
+        let (x, y) = mercator.project(13.405, 52.52);
+        assert_close((x, y), (1_492_237.8, 6_894_699.8), 0.1);
+        assert_close(mercator.to_lon_lat(x, y), (13.405, 52.52), 1e-9);
+        assert!("EPSG:9999".parse::<Crs>().is_err());
+    }
+
+    #[test]
+    fn test_utm() {
+        // On the central meridian at the equator, UTM is at the false easting.
+        let utm: Crs = "EPSG:32633".parse().unwrap();
+        assert_close(utm.project(15.0, 0.0), (500_000.0, 0.0), 1e-6);
+        let south = Crs::from_epsg(32733).unwrap();
+        assert_close(south.project(15.0, 0.0), (500_000.0, 10_000_000.0), 1e-6);
+        // A PROJ string of the same zone projects as its EPSG code does.
+        let proj: Crs = "+proj=utm  +zone=33 +datum=WGS84".parse().unwrap();
+        assert_eq!(proj.to_string(), "+proj=utm +zone=33 +datum=WGS84");
+        assert_eq!(proj.epsg(), None);
+        assert_eq!(proj, "+proj=utm +zone=33 +datum=WGS84".parse().unwrap());
+        assert_ne!(proj, utm);
+        let (x, y) = utm.project(13.405, 52.52);
+        assert_close(proj.project(13.405, 52.52), (x, y), 1e-6);
+        assert_close(proj.to_lon_lat(x, y), (13.405, 52.52), 1e-9);
+        assert!("+proj=nonesuch".parse::<Crs>().is_err());
+    }
+
+    #[test]
+    fn test_datum_shift() {
+        // The British National Grid is on OSGB 1936, about 100 m from WGS 84.
+        let bng = Crs::from_epsg(27700).unwrap();
+        let (x, y) = bng.project(-0.1276, 51.5072);
+        assert!((x - 530_000.0).abs() < 1_000.0 && (y - 180_400.0).abs() < 1_000.0);
+        assert_close(bng.to_lon_lat(x, y), (-0.1276, 51.5072), 1e-6);
+    }
+
+    #[test]
+    fn test_from_wkt() {
+        for code in [4326, 3857, 3035] {
+            let crs = Crs::from_epsg(code).unwrap();
+            assert_eq!(Crs::from_wkt(crs.esri_wkt().unwrap()), Some(crs));
+        }
+        let ogc = r#"PROJCS["WGS 84 / Pseudo-Mercator",GEOGCS["WGS 84",AUTHORITY["EPSG","4326"]],AUTHORITY["EPSG","3857"]]"#;
+        assert_eq!(Crs::from_wkt(ogc), Some(Crs::from_epsg(3857).unwrap()));
+        assert_eq!(
+            Crs::from_wkt(r#"GEOGCS["WGS 84",DATUM["WGS_1984"]]"#),
+            Some(Crs::wgs84())
+        );
+        assert_eq!(Crs::from_wkt(r#"PROJCS["NAD_1983_UTM_Zone_10N"]"#), None);
+        assert_eq!(Crs::from_wkt("not wkt"), None);
+    }
+
+    #[test]
+    fn test_rect_area() {
+        let km2 = |crs: Crs, rect: (f64, f64, f64, f64)| {
+            crs.rect_area(rect.0, rect.1, rect.2, rect.3) / 1e6
+        };
+        assert!((km2(Crs::wgs84(), (-180.0, -90.0, 180.0, 90.0)) - 510_065_621.7).abs() < 1.0);
+        assert!((km2(Crs::wgs84(), (0.0, 0.0, 1.0, 1.0)) - 12_308.46).abs() < 0.01);
+        assert_eq!(
+            km2(Crs::from_epsg(3035).unwrap(), (0.0, 0.0, 1000.0, 2000.0)),
+            2.0
+        );
+        // A small mercator rectangle covers the lon/lat one its corners project from.
+        let mercator = Crs::from_epsg(3857).unwrap();
+        let (west, south) = mercator.project(10.0, 60.0);
+        let (east, north) = mercator.project(10.01, 60.01);
+        let lon_lat = km2(Crs::wgs84(), (10.0, 60.0, 10.01, 60.01));
+        assert!((km2(mercator, (west, south, east, north)) / lon_lat - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_relative_pixel_area() {
+        let mercator = Crs::from_epsg(3857).unwrap();
+        assert!((mercator.relative_pixel_area(10.0, 60.0) - 0.25).abs() < 0.005);
+        assert!((Crs::wgs84().relative_pixel_area(10.0, 60.0) - 0.5).abs() < 1e-12);
+        assert_eq!(
+            Crs::from_epsg(3035)
+                .unwrap()
+                .relative_pixel_area(10.0, 60.0),
+            1.0
+        );
+    }
+}
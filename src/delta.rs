@@ -0,0 +1,851 @@
+//! `--format delta`: adding each input's rows to the Delta Lake table at `--table-uri` as a
+//! new commit, so lakehouse engines pick them up as they would rows from any other
+//! writer. The rows are written as parquet files, split into directories by the
+//! `--partition-by` columns, then listed in the next numbered JSON file of the table's
+//! `_delta_log`, which is only created if no other writer has taken its number. The first
+//! commit creates the table; later ones must have its columns and partitioning.
+//!
+//! Tables at `s3://bucket/prefix` URIs are reached through the `aws` CLI, which must be on
+//! the `PATH`. Data files are written to the temporary directory and uploaded, and each
+//! commit is put with `If-None-Match: *`, so S3 refuses it if another writer has already
+//! put that version.
+//!
+//! Delta has no unsigned types, so unsigned columns are widened to the next signed type,
+//! and 64 bit ones such as `s2_cell` keep their bits as a `long`.
+
+use crate::{
+    json::{self, Value},
+    output::{self, Compression},
+    time, upload,
+};
+use anyhow::{bail, Context, Result};
+use arrow_array::{
+    cast::{as_list_array, as_primitive_array, as_string_array},
+    types::{Int32Type, TimestampMicrosecondType, UInt64Type},
+    Array, ArrayRef, Int64Array, RecordBatch, StructArray, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+    process::Output,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The Delta protocol versions commits are written for. Tables needing a later writer
+/// are left alone.
+const READER_VERSION: u64 = 1;
+const WRITER_VERSION: u64 = 2;
+
+/// Versions tried before giving up on committing, each taken by another writer first.
+const COMMIT_ATTEMPTS: u32 = 100;
+
+/// The directory of the rows whose partition value is null, as Hive names it.
+const NULL_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Where a table's files are kept.
+enum Table {
+    Local(PathBuf),
+    /// The bucket, and the prefix of the keys of the table's files, ending in `/`.
+    S3 {
+        bucket: String,
+        prefix: String,
+    },
+}
+
+/// Checks a `--table-uri`, which is a local path or an `s3://` URI.
+pub fn parse_table_uri(s: &str) -> Result<PathBuf, String> {
+    Table::at(Path::new(s)).map_err(|e| e.to_string())?;
+    Ok(PathBuf::from(s))
+}
+
+impl Table {
+    fn at(uri: &Path) -> Result<Table> {
+        let uri = uri.to_string_lossy();
+        let Some((scheme, path)) = uri.split_once("://") else {
+            return Ok(Table::Local(PathBuf::from(&*uri)));
+        };
+        if scheme != "s3" {
+            bail!(
+                "Delta tables can be written at local paths or s3:// URIs, not {}",
+                uri
+            );
+        }
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            bail!("{} names no bucket", uri);
+        }
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+        Ok(Table::S3 {
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+
+    /// The names of the files in the table's `_delta_log`, creating it if it's local.
+    fn log_names(&self) -> Result<Vec<String>> {
+        match self {
+            Table::Local(dir) => {
+                let log = dir.join("_delta_log");
+                std::fs::create_dir_all(&log)
+                    .with_context(|| format!("Could not create {}", log.display()))?;
+                let mut names = vec![];
+                for entry in std::fs::read_dir(&log)
+                    .with_context(|| format!("Could not list {}", log.display()))?
+                {
+                    names.push(entry?.file_name().to_string_lossy().to_string());
+                }
+                Ok(names)
+            }
+            Table::S3 { bucket, prefix } => {
+                let log = format!("{}_delta_log/", prefix);
+                let listing = aws(&[
+                    "s3api",
+                    "list-objects-v2",
+                    "--bucket",
+                    bucket,
+                    "--prefix",
+                    &log,
+                    "--query",
+                    "Contents[].Key",
+                    "--output",
+                    "text",
+                ])?;
+                Ok(names_listed(&String::from_utf8_lossy(&listing), &log))
+            }
+        }
+    }
+
+    /// Reads the file `name` of the table's `_delta_log`.
+    fn read_log(&self, name: &str) -> Result<Vec<u8>> {
+        match self {
+            Table::Local(dir) => {
+                let path = dir.join("_delta_log").join(name);
+                std::fs::read(&path).with_context(|| format!("Could not read {}", path.display()))
+            }
+            Table::S3 { .. } => {
+                let url = self.url(&format!("_delta_log/{}", name));
+                aws(&["s3", "cp", "--only-show-errors", &url, "-"])
+                    .with_context(|| format!("Could not read {}", url))
+            }
+        }
+    }
+
+    /// Where the data file at `relative` is written before it is stored, creating its
+    /// directory.
+    fn stage(&self, relative: &str) -> Result<PathBuf> {
+        let path = match self {
+            Table::Local(dir) => dir.join(relative),
+            Table::S3 { .. } => std::env::temp_dir().join(format!("delta-{}.parquet", random_id())),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+        Ok(path)
+    }
+
+    /// Stores the data file written to `staged` at `relative` in the table.
+    fn store(&self, relative: &str, staged: &Path) -> Result<()> {
+        match self {
+            Table::Local(_) => Ok(()),
+            Table::S3 { .. } => {
+                let url = self.url(relative);
+                let staged_path = staged.to_string_lossy();
+                let uploaded = aws(&["s3", "cp", "--only-show-errors", &staged_path, &url]);
+                std::fs::remove_file(staged)?;
+                uploaded
+                    .map(|_| ())
+                    .with_context(|| format!("Could not upload {}", url))
+            }
+        }
+    }
+
+    /// Writes `text` as the log's commit of `version`, returning false if another writer
+    /// has already committed it.
+    fn put_commit(&self, version: u64, text: &str) -> Result<bool> {
+        let name = format!("{:020}.json", version);
+        match self {
+            Table::Local(dir) => {
+                // Written in full under another name, then linked to the version's name,
+                // which fails if another writer has already taken it.
+                let log = dir.join("_delta_log");
+                let path = log.join(&name);
+                let partial = log.join(format!(".{}.{}.partial", name, random_id()));
+                std::fs::write(&partial, text)
+                    .with_context(|| format!("Could not write {}", partial.display()))?;
+                let linked = std::fs::hard_link(&partial, &path);
+                std::fs::remove_file(&partial)?;
+                match linked {
+                    Ok(()) => Ok(true),
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+                    Err(e) => {
+                        Err(e).with_context(|| format!("Could not commit {}", path.display()))
+                    }
+                }
+            }
+            Table::S3 { bucket, prefix } => {
+                let key = format!("{}_delta_log/{}", prefix, name);
+                let partial = std::env::temp_dir().join(format!("delta-{}.json", random_id()));
+                std::fs::write(&partial, text)
+                    .with_context(|| format!("Could not write {}", partial.display()))?;
+                let body = partial.to_string_lossy();
+                let put = upload::aws(&[
+                    "s3api",
+                    "put-object",
+                    "--bucket",
+                    bucket,
+                    "--key",
+                    &key,
+                    "--body",
+                    &body,
+                    "--if-none-match",
+                    "*",
+                ])
+                .output();
+                std::fs::remove_file(&partial)?;
+                let put = put.context("Could not run aws to commit")?;
+                let error = String::from_utf8_lossy(&put.stderr);
+                match put.status.success() {
+                    true => Ok(true),
+                    // Refused as another writer has put the key, or is putting it now.
+                    false
+                        if error.contains("PreconditionFailed")
+                            || error.contains("ConditionalRequestConflict") =>
+                    {
+                        Ok(false)
+                    }
+                    false => bail!(
+                        "Could not commit {}: {}",
+                        self.url(&key[prefix.len()..]),
+                        error.trim()
+                    ),
+                }
+            }
+        }
+    }
+
+    /// The URL of the table's file at `relative`.
+    fn url(&self, relative: &str) -> String {
+        match self {
+            Table::Local(dir) => dir.join(relative).display().to_string(),
+            Table::S3 { bucket, prefix } => format!("s3://{}/{}{}", bucket, prefix, relative),
+        }
+    }
+}
+
+/// Runs the `aws` CLI with `args`, returning what it prints.
+fn aws(args: &[&str]) -> Result<Vec<u8>> {
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = upload::aws(args).output().context("Could not run aws")?;
+    if !status.success() {
+        bail!("{}", String::from_utf8_lossy(&stderr).trim());
+    }
+    Ok(stdout)
+}
+
+/// The names below `prefix` of the keys `aws s3api list-objects-v2` listed as text.
+fn names_listed(listing: &str, prefix: &str) -> Vec<String> {
+    listing
+        .split_whitespace()
+        .filter_map(|key| key.strip_prefix(prefix))
+        .map(str::to_string)
+        .collect()
+}
+
+/// What the log says of the table as of its latest version.
+struct Snapshot {
+    version: u64,
+    schema: Value,
+    partition_columns: Vec<String>,
+    writer_version: u64,
+}
+
+/// Adds `batch`'s rows to the table at `table` as a new commit, creating the table if
+/// there is none yet, and returns the commit's version. Each data file's footer holds
+/// the key value pairs of `footer`.
+pub fn commit(
+    table: &Path,
+    batch: &RecordBatch,
+    partition_by: &[String],
+    compression: Compression,
+    footer: Vec<(String, String)>,
+) -> Result<u64> {
+    let uri = table;
+    let table = Table::at(uri)?;
+    let batch = with_delta_types(batch)?;
+    let schema = schema_value(&batch.schema())?;
+    let mut snapshot = read_snapshot(&table)?;
+    let partition_columns = match &snapshot {
+        Some(snapshot) => snapshot.partition_columns.clone(),
+        None => partition_by.to_vec(),
+    };
+    if let Some(snapshot) = &snapshot {
+        check(snapshot, &schema, partition_by)?;
+    }
+    for column in &partition_columns {
+        if batch.schema().column_with_name(column).is_none() {
+            bail!("There is no {} column to partition the table by", column);
+        }
+    }
+    let adds = write_files(&table, &batch, &partition_columns, compression, footer)?;
+
+    for _ in 0..COMMIT_ATTEMPTS {
+        let version = snapshot.as_ref().map_or(0, |s| s.version + 1);
+        let mut actions = vec![commit_info(&partition_columns)];
+        if snapshot.is_none() {
+            actions.push(Value::object([(
+                "protocol",
+                Value::object([
+                    ("minReaderVersion", Value::from(READER_VERSION)),
+                    ("minWriterVersion", Value::from(WRITER_VERSION)),
+                ]),
+            )]));
+            actions.push(metadata(&schema, &partition_columns));
+        }
+        actions.extend(adds.iter().cloned());
+        let text: String = actions.iter().map(|a| format!("{}\n", a)).collect();
+        if table.put_commit(version, &text)? {
+            return Ok(version);
+        }
+        snapshot = read_snapshot(&table)?;
+        if let Some(snapshot) = &snapshot {
+            check(snapshot, &schema, &partition_columns)?;
+        }
+    }
+    bail!(
+        "Other writers took the next {} versions of {}",
+        COMMIT_ATTEMPTS,
+        uri.display()
+    )
+}
+
+/// Checks that rows with the columns of `schema` can be added to the table, partitioned
+/// by `partition_by` unless it is empty.
+fn check(snapshot: &Snapshot, schema: &Value, partition_by: &[String]) -> Result<()> {
+    if snapshot.writer_version > WRITER_VERSION {
+        bail!(
+            "The table needs writers of Delta protocol version {}, but this writes version {}",
+            snapshot.writer_version,
+            WRITER_VERSION
+        );
+    }
+    if snapshot.schema.to_string().contains("delta.invariants") {
+        bail!("The table has column invariants, which aren't checked here");
+    }
+    if !partition_by.is_empty() && partition_by != snapshot.partition_columns {
+        bail!(
+            "The table is partitioned by [{}], not [{}]",
+            snapshot.partition_columns.join(", "),
+            partition_by.join(", ")
+        );
+    }
+    let (theirs, ours) = (fields(&snapshot.schema), fields(schema));
+    let fits = theirs.len() == ours.len()
+        && ours.iter().all(|(name, kind, nullable)| {
+            theirs
+                .iter()
+                .any(|(their_name, their_kind, their_nullable)| {
+                    their_name == name && their_kind == kind && (*their_nullable || !nullable)
+                })
+        });
+    if !fits {
+        let describe = |fields: &[(String, String, bool)]| {
+            fields
+                .iter()
+                .map(|(name, kind, _)| format!("{} {}", name, kind))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        bail!(
+            "The output's columns ({}) don't match those of the Delta table ({})",
+            describe(&ours),
+            describe(&theirs)
+        );
+    }
+    Ok(())
+}
+
+/// The name, type and nullability of each field of a Delta schema.
+fn fields(schema: &Value) -> Vec<(String, String, bool)> {
+    let fields = schema
+        .get("fields")
+        .and_then(Value::as_array)
+        .unwrap_or(&[]);
+    fields
+        .iter()
+        .map(|field| {
+            let name = field.get("name").and_then(Value::as_str).unwrap_or("");
+            let kind = match field.get("type") {
+                Some(Value::String(kind)) => kind.clone(),
+                kind => kind.map(Value::to_string).unwrap_or_default(),
+            };
+            let nullable = !matches!(field.get("nullable"), Some(Value::Bool(false)));
+            (name.to_string(), kind, nullable)
+        })
+        .collect()
+}
+
+/// Reads the table's latest version, schema and partitioning from its log, or `None` if
+/// nothing has been committed.
+fn read_snapshot(table: &Table) -> Result<Option<Snapshot>> {
+    let mut versions = vec![];
+    let names = table.log_names()?;
+    for name in &names {
+        if let Some(version) = name.strip_suffix(".json").filter(|v| v.len() == 20) {
+            versions.extend(version.parse::<u64>());
+        }
+    }
+    versions.sort_unstable();
+    let Some(&version) = versions.last() else {
+        return Ok(None);
+    };
+
+    // The latest metadata and protocol, in the newest commit holding each.
+    let (mut metadata, mut writer_version) = (None, None);
+    for version in versions.iter().rev() {
+        let name = format!("{:020}.json", version);
+        let text = String::from_utf8(table.read_log(&name)?)?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let action = json::parse(line)
+                .with_context(|| format!("In {}", table.url(&format!("_delta_log/{}", name))))?;
+            if let Some(action) = action.get("metaData").filter(|_| metadata.is_none()) {
+                let schema = action.get("schemaString").and_then(Value::as_str);
+                let partition_columns = action.get("partitionColumns").and_then(Value::as_array);
+                let columns = partition_columns.unwrap_or(&[]).iter();
+                let columns = columns.filter_map(|c| c.as_str().map(str::to_string));
+                metadata = Some((json::parse(schema.unwrap_or("{}"))?, columns.collect()));
+            }
+            if let Some(action) = action.get("protocol").filter(|_| writer_version.is_none()) {
+                let version = action.get("minWriterVersion").and_then(Value::as_f64);
+                writer_version = Some(version.unwrap_or(0.0) as u64);
+            }
+        }
+        if metadata.is_some() && writer_version.is_some() {
+            break;
+        }
+    }
+    // Commits older than the last checkpoint may have been cleaned up.
+    if (metadata.is_none() || writer_version.is_none())
+        && names.iter().any(|name| name == "_last_checkpoint")
+    {
+        let (checkpoint_metadata, checkpoint_writer_version) = read_checkpoint(table)?;
+        metadata = metadata.or(checkpoint_metadata);
+        writer_version = writer_version.or(checkpoint_writer_version);
+    }
+    let Some((schema, partition_columns)) = metadata else {
+        bail!(
+            "{} has commits but no table metadata",
+            table.url("_delta_log")
+        );
+    };
+    Ok(Some(Snapshot {
+        version,
+        schema,
+        partition_columns,
+        writer_version: writer_version.unwrap_or(WRITER_VERSION),
+    }))
+}
+
+/// The schema, partitioning and writer version in the log's last checkpoint.
+#[allow(clippy::type_complexity)]
+fn read_checkpoint(table: &Table) -> Result<(Option<(Value, Vec<String>)>, Option<u64>)> {
+    let text = String::from_utf8(table.read_log("_last_checkpoint")?)?;
+    let last = json::parse(&text)
+        .with_context(|| format!("In {}", table.url("_delta_log/_last_checkpoint")))?;
+    if last.get("parts").is_some() {
+        bail!("The table's checkpoint is split into parts, which can't be read here");
+    }
+    let version = last.get("version").and_then(Value::as_f64).unwrap_or(0.0) as u64;
+    let name = format!("{:020}.checkpoint.parquet", version);
+    let batch = output::read_parquet_bytes(table.read_log(&name)?)?;
+    let actions = |name: &str| {
+        let column = batch.column_by_name(name)?;
+        let actions = column.as_any().downcast_ref::<StructArray>()?;
+        let row = (0..actions.len()).find(|&row| actions.is_valid(row))?;
+        Some((actions, row))
+    };
+    let metadata = match actions("metaData") {
+        Some((metadata, row)) => {
+            let (Some(schema), Some(columns)) = (
+                metadata.column_by_name("schemaString"),
+                metadata.column_by_name("partitionColumns"),
+            ) else {
+                bail!("The table's checkpoint has no schema");
+            };
+            let schema = json::parse(as_string_array(schema).value(row))?;
+            let columns = as_list_array(columns).value(row);
+            let columns = as_string_array(&columns);
+            let columns = (0..columns.len()).map(|i| columns.value(i).to_string());
+            Some((schema, columns.collect()))
+        }
+        None => None,
+    };
+    let writer_version = actions("protocol").and_then(|(protocol, row)| {
+        let version = protocol.column_by_name("minWriterVersion")?;
+        Some(as_primitive_array::<Int32Type>(version).value(row) as u64)
+    });
+    Ok((metadata, writer_version))
+}
+
+/// Writes a parquet file of `batch`'s rows for each combination of values of the
+/// `partition_columns`, leaving those columns out as Delta does, and returns the `add`
+/// actions listing them.
+fn write_files(
+    table: &Table,
+    batch: &RecordBatch,
+    partition_columns: &[String],
+    compression: Compression,
+    footer: Vec<(String, String)>,
+) -> Result<Vec<Value>> {
+    let keys: Vec<ArrayRef> = partition_columns
+        .iter()
+        .filter_map(|name| batch.column_by_name(name).cloned())
+        .collect();
+    let mut partitions: BTreeMap<Vec<Option<String>>, Vec<u32>> = BTreeMap::new();
+    for row in 0..batch.num_rows() {
+        let values = keys
+            .iter()
+            .map(|key| partition_value(key, row))
+            .collect::<Result<_>>()?;
+        partitions.entry(values).or_default().push(row as u32);
+    }
+    let kept: Vec<usize> = (0..batch.num_columns())
+        .filter(|&i| !partition_columns.contains(batch.schema().field(i).name()))
+        .collect();
+    let data = batch.project(&kept)?;
+
+    let id = random_id();
+    let mut adds = vec![];
+    for (i, (values, rows)) in partitions.into_iter().enumerate() {
+        let rows = UInt32Array::from(rows);
+        let columns = data
+            .columns()
+            .iter()
+            .map(|column| Ok(arrow_select::take::take(column, &rows, None)?))
+            .collect::<Result<_>>()?;
+        let rows = RecordBatch::try_new(data.schema(), columns)?;
+        let dir: String = partition_columns
+            .iter()
+            .zip(&values)
+            .map(|(column, value)| {
+                let value = value.as_deref().map_or(NULL_PARTITION.to_string(), escape);
+                format!("{}={}/", escape(column), value)
+            })
+            .collect();
+        let relative = format!("{}part-{:05}-{}.c000.parquet", dir, i, id);
+        let path = table.stage(&relative)?;
+        output::write_parquet_with_footer(&path, &rows, compression, footer.clone())?;
+        let size = std::fs::metadata(&path)?.len();
+        table.store(&relative, &path)?;
+        let stats = Value::object([("numRecords", Value::from(rows.num_rows() as u64))]);
+        adds.push(Value::object([(
+            "add",
+            Value::object([
+                // Paths are URIs, so the escapes in directory names are escaped again.
+                ("path", Value::from(relative.replace('%', "%25"))),
+                (
+                    "partitionValues",
+                    Value::object(
+                        partition_columns
+                            .iter()
+                            .cloned()
+                            .zip(values.into_iter().map(Value::from)),
+                    ),
+                ),
+                ("size", Value::from(size)),
+                ("modificationTime", Value::from(now_millis())),
+                ("dataChange", Value::from(true)),
+                ("stats", Value::from(stats.to_string())),
+            ]),
+        )]));
+    }
+    Ok(adds)
+}
+
+/// The value of `column` at `row` as Delta records partition values, or `None` if null.
+fn partition_value(column: &ArrayRef, row: usize) -> Result<Option<String>> {
+    if column.is_null(row) {
+        return Ok(None);
+    }
+    Ok(Some(match column.data_type() {
+        DataType::Timestamp(_, _) => {
+            let micros = as_primitive_array::<TimestampMicrosecondType>(column).value(row);
+            let seconds = time::iso8601(micros.div_euclid(1_000_000));
+            seconds.trim_end_matches('Z').replace('T', " ")
+        }
+        _ => arrow_cast::display::array_value_to_string(column, row)?,
+    }))
+}
+
+/// Escapes characters other than letters, digits, `-`, `_` and `.` in a directory name,
+/// as Hive partition directories are.
+fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                escaped.push(byte as char)
+            }
+            byte => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Casts the columns of `batch` whose types Delta lacks to ones it has.
+fn with_delta_types(batch: &RecordBatch) -> Result<RecordBatch> {
+    let schema = batch.schema();
+    let mut fields = vec![];
+    let mut columns = vec![];
+    for (field, column) in schema.fields().iter().zip(batch.columns()) {
+        let to = match field.data_type() {
+            DataType::UInt8 => Some(DataType::Int16),
+            DataType::UInt16 => Some(DataType::Int32),
+            DataType::UInt32 => Some(DataType::Int64),
+            DataType::Timestamp(TimeUnit::Microsecond, Some(zone)) if &**zone == "UTC" => None,
+            DataType::Timestamp(_, _) => Some(DataType::Timestamp(
+                TimeUnit::Microsecond,
+                Some("UTC".into()),
+            )),
+            DataType::Dictionary(_, _) => Some(DataType::Utf8),
+            _ => None,
+        };
+        let column = match (field.data_type(), &to) {
+            // Cast, the largest ids wouldn't fit in a `long`, so their bits are kept instead.
+            (DataType::UInt64, _) => {
+                let values = as_primitive_array::<UInt64Type>(column);
+                Arc::new(
+                    values
+                        .iter()
+                        .map(|v| v.map(|v| v as i64))
+                        .collect::<Int64Array>(),
+                )
+            }
+            (_, Some(to)) => arrow_cast::cast(column, to)?,
+            (_, None) => column.clone(),
+        };
+        fields.push(
+            Field::new(
+                field.name(),
+                column.data_type().clone(),
+                field.is_nullable(),
+            )
+            .with_metadata(field.metadata().clone()),
+        );
+        columns.push(column);
+    }
+    let schema = Schema::new(fields).with_metadata(schema.metadata().clone());
+    Ok(RecordBatch::try_new(Arc::new(schema), columns)?)
+}
+
+/// The Delta schema of rows with `schema`, with each column's metadata.
+fn schema_value(schema: &Schema) -> Result<Value> {
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let kind = match field.data_type() {
+                DataType::Boolean => "boolean",
+                DataType::Int8 => "byte",
+                DataType::Int16 => "short",
+                DataType::Int32 => "integer",
+                DataType::Int64 => "long",
+                DataType::Float32 => "float",
+                DataType::Float64 => "double",
+                DataType::Utf8 | DataType::LargeUtf8 => "string",
+                DataType::Binary | DataType::LargeBinary => "binary",
+                DataType::Date32 => "date",
+                DataType::Timestamp(_, _) => "timestamp",
+                other => bail!(
+                    "Delta tables can't hold the {} column's {} values",
+                    field.name(),
+                    other
+                ),
+            };
+            let metadata = field
+                .metadata()
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::from(value.clone())));
+            let mut metadata: Vec<(String, Value)> = metadata.collect();
+            metadata.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(Value::object([
+                ("name", Value::from(field.name().clone())),
+                ("type", Value::from(kind)),
+                ("nullable", Value::from(field.is_nullable())),
+                ("metadata", Value::Object(metadata)),
+            ]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Value::object([
+        ("type", Value::from("struct")),
+        ("fields", Value::Array(fields)),
+    ]))
+}
+
+fn metadata(schema: &Value, partition_columns: &[String]) -> Value {
+    Value::object([(
+        "metaData",
+        Value::object([
+            ("id", Value::from(random_id())),
+            (
+                "format",
+                Value::object([
+                    ("provider", Value::from("parquet")),
+                    ("options", Value::object::<String>([])),
+                ]),
+            ),
+            ("schemaString", Value::from(schema.to_string())),
+            ("partitionColumns", Value::from(partition_columns.to_vec())),
+            ("configuration", Value::object::<String>([])),
+            ("createdTime", Value::from(now_millis())),
+        ]),
+    )])
+}
+
+fn commit_info(partition_columns: &[String]) -> Value {
+    let partition_by = Value::from(partition_columns.to_vec()).to_string();
+    Value::object([(
+        "commitInfo",
+        Value::object([
+            ("timestamp", Value::from(now_millis())),
+            ("operation", Value::from("WRITE")),
+            (
+                "operationParameters",
+                Value::object([
+                    ("mode", Value::from("Append")),
+                    ("partitionBy", Value::from(partition_by)),
+                ]),
+            ),
+            ("isBlindAppend", Value::from(true)),
+            (
+                "engineInfo",
+                format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).into(),
+            ),
+        ]),
+    )])
+}
+
+fn now_millis() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_millis() as u64
+}
+
+/// A random version 4 UUID, from the random keys std seeds its hash maps with.
+fn random_id() -> String {
+    let random = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        hasher.finish()
+    };
+    let (high, low) = (random(), random());
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xfff,
+        (low >> 48) & 0x3fff | 0x8000,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{commit, names_listed, parse_table_uri, read_snapshot, Table};
+    use crate::output::{read_parquet, Codec};
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, UInt8Array};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_commit() {
+        let table = std::env::temp_dir().join(format!("delta-test-{}", std::process::id()));
+        let batch = RecordBatch::try_from_iter([
+            (
+                "lon",
+                Arc::new(Float64Array::from(vec![1.0, 2.0, 3.0])) as ArrayRef,
+            ),
+            (
+                "class",
+                Arc::new(UInt8Array::from(vec![Some(1), Some(1), None])),
+            ),
+        ])
+        .unwrap();
+        let partition_by = ["class".to_string()];
+        assert_eq!(
+            commit(&table, &batch, &partition_by, Codec::Snappy.into(), vec![]).unwrap(),
+            0
+        );
+        assert_eq!(
+            commit(&table, &batch, &[], Codec::Snappy.into(), vec![]).unwrap(),
+            1
+        );
+
+        let snapshot = read_snapshot(&Table::Local(table.clone()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(snapshot.version, 1);
+        assert_eq!(snapshot.partition_columns, partition_by);
+        let log =
+            std::fs::read_to_string(table.join("_delta_log/00000000000000000001.json")).unwrap();
+        assert!(log.contains(r#""partitionValues":{"class":"1"}"#));
+        assert!(log.contains(r#""partitionValues":{"class":null}"#));
+        let part = std::fs::read_dir(table.join("class=1"))
+            .unwrap()
+            .next()
+            .unwrap();
+        let rows = read_parquet(&part.unwrap().path()).unwrap();
+        assert_eq!(rows.num_rows(), 2);
+        assert!(rows.column_by_name("class").is_none());
+
+        // Rows without the table's columns, or partitioned otherwise, don't fit.
+        let other = batch.project(&[0]).unwrap();
+        assert!(commit(&table, &other, &[], Codec::Snappy.into(), vec![]).is_err());
+        let lon = ["lon".to_string()];
+        assert!(commit(&table, &batch, &lon, Codec::Snappy.into(), vec![]).is_err());
+        std::fs::remove_dir_all(&table).unwrap();
+    }
+
+    #[test]
+    fn test_s3_table() {
+        let Ok(Table::S3 { bucket, prefix }) = Table::at("s3://bucket/lake/points".as_ref()) else {
+            panic!("not an S3 table");
+        };
+        assert_eq!((&*bucket, &*prefix), ("bucket", "lake/points/"));
+        let table = Table::S3 { bucket, prefix };
+        assert_eq!(
+            table.url("_delta_log/00000000000000000000.json"),
+            "s3://bucket/lake/points/_delta_log/00000000000000000000.json"
+        );
+        let log = "lake/points/_delta_log/";
+        let listing = "lake/points/_delta_log/00000000000000000000.json\tlake/points/_delta_log/_last_checkpoint\nlake/points/_delta_log/00000000000000000001.json\n";
+        assert_eq!(
+            names_listed(listing, log),
+            [
+                "00000000000000000000.json",
+                "_last_checkpoint",
+                "00000000000000000001.json"
+            ]
+        );
+        assert!(names_listed("None\n", log).is_empty());
+
+        assert!(parse_table_uri("tables/points").is_ok());
+        assert!(parse_table_uri("s3://bucket").is_ok());
+        assert!(parse_table_uri("s3:///points").is_err());
+        assert!(parse_table_uri("gs://bucket/points").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//! Comparing two rasters of the same product, cell by cell.
+
+use crate::{
+    crs::Crs,
+    explain,
+    georef::GeoTransform,
+    group::{self, Aggregation, Align, Binning, Grid, LonLat},
+    json::Value,
+    load_tif_contents,
+    output::{self, Compression},
+    raster::{self, ChunkSize, Layout},
+    sidecar, DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{bail, Result};
+use arrow_array::{ArrayRef, Float32Array, Float64Array, RecordBatch};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct DiffArgs {
+    /// The earlier raster.
+    a: PathBuf,
+    /// The later raster, whose values have `a`'s subtracted.
+    b: PathBuf,
+    /// Where to write the differences. Defaults to `a`'s path with a `.diff.parquet`
+    /// extension.
+    #[arg(long = "output", short = 'o')]
+    output: Option<PathBuf>,
+    /// Compare the rasters on a grid of cells this many degrees across, needed when their
+    /// pixels don't line up.
+    #[arg(long = "group")]
+    group: Option<f64>,
+    /// How the pixels in each `--group` cell are combined.
+    #[arg(long = "agg", value_enum, default_value_t = Aggregation::Sum, requires = "group")]
+    agg: Aggregation,
+    /// CRS of both rasters, read with their GeoTIFF tags. Without it both are taken to be
+    /// global grids.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+    /// Print the summary of the change as JSON instead of text.
+    #[arg(long = "json")]
+    json: bool,
+    #[command(flatten)]
+    compression: Compression,
+    /// Write output even when it looks like it will not fit on disk.
+    #[arg(long = "force")]
+    force: bool,
+}
+
+/// One compared cell, with zero for a raster that has no value there.
+struct Cell {
+    lon: f64,
+    lat: f64,
+    a: f64,
+    b: f64,
+}
+
+pub fn run(args: &DiffArgs) -> Result<()> {
+    let (transform_a, rows_a) = read_rows(&args.a, args.src_crs)?;
+    let (transform_b, rows_b) = read_rows(&args.b, args.src_crs)?;
+    let (rows_a, rows_b) = match args.group {
+        Some(size) => {
+            let binning = Binning::Grid(Grid {
+                size: size.into(),
+                origin: LonLat { lon: 0.0, lat: 0.0 },
+                align: Align::Corner,
+                wraps: false,
+            });
+            (
+                group::bin(&rows_a, &binning, args.agg, |x, y| {
+                    transform_a.pixel_weight(x, y)
+                })
+                .rows,
+                group::bin(&rows_b, &binning, args.agg, |x, y| {
+                    transform_b.pixel_weight(x, y)
+                })
+                .rows,
+            )
+        }
+        None => {
+            if transform_a != transform_b {
+                bail!(
+                    "{} and {} have different grids, pass --group to compare them on a \
+                     common one",
+                    args.a.to_string_lossy(),
+                    args.b.to_string_lossy()
+                );
+            }
+            (rows_a, rows_b)
+        }
+    };
+
+    let cells = join(&rows_a, &rows_b);
+    let batch = build_batch(&cells)?;
+    let output_path = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.a.with_extension("diff.parquet"));
+    let estimate = output::estimate_parquet_size(&batch, args.compression.codec);
+    output::check_free_space(&output_path, estimate, args.force)?;
+    output::write_parquet(&output_path, &batch, args.compression)?;
+
+    let summary = summarize(&cells);
+    if args.json {
+        println!("{}", summary.pretty());
+    } else {
+        print!("{}", explain::to_text(&summary, 0));
+    }
+    Ok(())
+}
+
+/// `(lon, lat, value)` rows of a raster.
+type Rows = Vec<(f64, f64, f64)>;
+
+/// Reads a raster's pixels with values above zero.
+fn read_rows(path: &Path, src_crs: Option<Crs>) -> Result<(GeoTransform, Rows)> {
+    let contents = load_tif_contents(path)?;
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let src_crs = sidecar::src_crs(path, src_crs, &mut decoder)?;
+    let transform = GeoTransform::resolve(&mut decoder, src_crs, None)?;
+    let rows = raster::read_pixels(
+        &contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |_, _, _, _| true,
+        |_| {},
+        |x, y, value| {
+            (value > 0.0 && value.is_finite()).then(|| {
+                let (lon, lat) = transform.position(x as f64, y as f64);
+                (lon, lat, value)
+            })
+        },
+    )?;
+    Ok((transform, rows))
+}
+
+/// Matches rows of the two rasters at the same position, from north to south and west
+/// to east.
+fn join(rows_a: &[(f64, f64, f64)], rows_b: &[(f64, f64, f64)]) -> Vec<Cell> {
+    let mut cells = HashMap::<(u64, u64), Cell>::new();
+    for (rows, is_a) in [(rows_a, true), (rows_b, false)] {
+        for &(lon, lat, value) in rows {
+            let cell = cells.entry((lon.to_bits(), lat.to_bits())).or_insert(Cell {
+                lon,
+                lat,
+                a: 0.0,
+                b: 0.0,
+            });
+            if is_a {
+                cell.a += value;
+            } else {
+                cell.b += value;
+            }
+        }
+    }
+    let mut cells: Vec<Cell> = cells.into_values().collect();
+    cells.sort_by(|p, q| q.lat.total_cmp(&p.lat).then(p.lon.total_cmp(&q.lon)));
+    cells
+}
+
+fn build_batch(cells: &[Cell]) -> Result<RecordBatch> {
+    let float32 = |f: fn(&Cell) -> f64| {
+        Arc::new(Float32Array::from_iter_values(
+            cells.iter().map(|c| f(c) as f32),
+        )) as ArrayRef
+    };
+    Ok(RecordBatch::try_from_iter([
+        ("lon", float32(|c| c.lon)),
+        ("lat", float32(|c| c.lat)),
+        (
+            "a",
+            Arc::new(Float64Array::from_iter_values(cells.iter().map(|c| c.a))) as ArrayRef,
+        ),
+        (
+            "b",
+            Arc::new(Float64Array::from_iter_values(cells.iter().map(|c| c.b))),
+        ),
+        (
+            "diff",
+            Arc::new(Float64Array::from_iter_values(
+                cells.iter().map(|c| c.b - c.a),
+            )),
+        ),
+    ])?)
+}
+
+/// Totals and extremes of the change, and how many cells appeared, disappeared or changed.
+fn summarize(cells: &[Cell]) -> Value {
+    let count = |f: fn(&Cell) -> bool| cells.iter().filter(|c| f(c)).count() as u64;
+    let total_a: f64 = cells.iter().map(|c| c.a).sum();
+    let total_b: f64 = cells.iter().map(|c| c.b).sum();
+    let diffs = cells.iter().map(|c| c.b - c.a);
+    let smallest = diffs.clone().fold(f64::INFINITY, f64::min);
+    let largest = diffs.fold(f64::NEG_INFINITY, f64::max);
+    let finite = |v: f64| v.is_finite().then_some(v);
+    Value::object([
+        ("cells", Value::from(cells.len() as u64)),
+        ("changed", count(|c| c.a != c.b).into()),
+        ("only_in_a", count(|c| c.b == 0.0).into()),
+        ("only_in_b", count(|c| c.a == 0.0).into()),
+        ("total_a", total_a.into()),
+        ("total_b", total_b.into()),
+        ("total_change", (total_b - total_a).into()),
+        (
+            "mean_change",
+            finite((total_b - total_a) / cells.len() as f64).into(),
+        ),
+        ("min_change", finite(smallest).into()),
+        ("max_change", finite(largest).into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join, summarize};
+
+    #[test]
+    fn test_join_and_summarize() {
+        let a = [(0.0, 1.0, 2.0), (1.0, 1.0, 3.0)];
+        let b = [(1.0, 1.0, 5.0), (0.0, 0.0, 1.0)];
+        let cells = join(&a, &b);
+        assert_eq!(cells.len(), 3);
+        assert_eq!((cells[0].lon, cells[0].a, cells[0].b), (0.0, 2.0, 0.0));
+        let summary = summarize(&cells);
+        let number = |key| summary.get(key).and_then(|v| v.as_f64());
+        assert_eq!(number("changed"), Some(3.0));
+        assert_eq!(number("only_in_a"), Some(1.0));
+        assert_eq!(number("total_change"), Some(1.0));
+        assert_eq!(number("max_change"), Some(2.0));
+    }
+}
@@ -0,0 +1,497 @@
+//! Distance from each output row to the nearest feature of a vector file, for
+//! `--distance-to`, such as to find the population within some distance of a coast.
+//!
+//! The features' edges are packed into an R-tree, searched nearest first. Distances are
+//! great-circle distances on a sphere of the earth's mean radius, which are within 0.5% of
+//! distances on the ellipsoid.
+
+use crate::{json, mask::Mask};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    ops::Range,
+    path::Path,
+};
+
+/// Mean radius of the earth, in kilometres.
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// Most children of each R-tree node.
+const NODE_CAPACITY: usize = 16;
+
+/// A `(lon, lat)` position in degrees.
+type Point = (f64, f64);
+
+/// The features of a vector file, indexed for nearest-feature queries.
+pub struct Features {
+    /// Edges of lines and polygon rings, with points as edges of zero length, in the
+    /// R-tree's order.
+    edges: Vec<(Point, Point)>,
+    /// The R-tree's nodes, level by level from the ones grouping edges up to the root.
+    /// Each node covers a range of the level below, or of `edges` for the first level.
+    levels: Vec<Vec<(Rect, Range<usize>)>>,
+    /// The polygons, inside which the distance is zero.
+    polygons: Option<Mask>,
+}
+
+#[derive(Clone, Copy)]
+struct Rect {
+    west: f64,
+    south: f64,
+    east: f64,
+    north: f64,
+}
+
+impl Features {
+    /// Reads every point, line and polygon in a `.geojson`/`.json` or `.shp` file.
+    pub fn load(path: &Path) -> Result<Features> {
+        let shapes = match path.extension().and_then(|e| e.to_str()) {
+            Some("geojson" | "json") => read_geojson(&std::fs::read_to_string(path)?),
+            Some("shp") => read_shapefile(&std::fs::read(path)?),
+            _ => bail!(
+                "Unsupported feature file {}, expected .geojson or .shp",
+                path.to_string_lossy()
+            ),
+        }
+        .with_context(|| format!("Could not read features from {}", path.to_string_lossy()))?;
+        Features::new(shapes)
+    }
+
+    fn new(shapes: Shapes) -> Result<Features> {
+        let mut edges = vec![];
+        for line in shapes.lines.iter().chain(shapes.polygons.iter().flatten()) {
+            match line.as_slice() {
+                [] => {}
+                [point] => edges.push((*point, *point)),
+                _ => edges.extend(line.windows(2).map(|pair| (pair[0], pair[1]))),
+            }
+        }
+        for ring in shapes.polygons.iter().flatten() {
+            if let (Some(first), Some(last)) = (ring.first(), ring.last()) {
+                if first != last {
+                    edges.push((*last, *first));
+                }
+            }
+        }
+        if edges.is_empty() {
+            bail!("No features found");
+        }
+        let polygons = match shapes.polygons.is_empty() {
+            true => None,
+            false => Some(Mask::new(&shapes.polygons)?),
+        };
+
+        // Sort-tile-recursive packing: sort by longitude into vertical slices, then each
+        // slice by latitude into nodes, so nodes are compact and barely overlap.
+        let center = |r: &Rect| ((r.west + r.east) / 2.0, (r.south + r.north) / 2.0);
+        let pack = |items: &mut Vec<(Rect, usize)>| -> Vec<(Rect, Range<usize>)> {
+            let node_count = items.len().div_ceil(NODE_CAPACITY);
+            let slice_len = (node_count as f64).sqrt().ceil() as usize * NODE_CAPACITY;
+            items.sort_by(|a, b| center(&a.0).0.total_cmp(&center(&b.0).0));
+            for slice in items.chunks_mut(slice_len) {
+                slice.sort_by(|a, b| center(&a.0).1.total_cmp(&center(&b.0).1));
+            }
+            let mut nodes = vec![];
+            let mut start = 0;
+            for slice in items.chunks(slice_len) {
+                for node in slice.chunks(NODE_CAPACITY) {
+                    let bounds = node
+                        .iter()
+                        .map(|(rect, _)| *rect)
+                        .reduce(Rect::union)
+                        .expect("chunks are not empty");
+                    nodes.push((bounds, start..start + node.len()));
+                    start += node.len();
+                }
+            }
+            nodes
+        };
+
+        let mut items: Vec<(Rect, usize)> = edges
+            .iter()
+            .enumerate()
+            .map(|(i, &(a, b))| (Rect::around(a, b), i))
+            .collect();
+        let mut levels = vec![pack(&mut items)];
+        edges = items.iter().map(|&(_, i)| edges[i]).collect();
+        while levels.last().expect("a level was added").len() > 1 {
+            let below = levels.last_mut().expect("a level was added");
+            let mut items: Vec<(Rect, usize)> = below
+                .iter()
+                .enumerate()
+                .map(|(i, (rect, _))| (*rect, i))
+                .collect();
+            let level = pack(&mut items);
+            let reordered = items.iter().map(|&(_, i)| below[i].clone()).collect();
+            *below = reordered;
+            levels.push(level);
+        }
+        Ok(Features {
+            edges,
+            levels,
+            polygons,
+        })
+    }
+
+    /// Distance in kilometres from `lon`, `lat` to the nearest feature.
+    pub fn distance_km(&self, lon: f64, lat: f64) -> f64 {
+        if self
+            .polygons
+            .as_ref()
+            .is_some_and(|polygons| polygons.contains(lon, lat))
+        {
+            return 0.0;
+        }
+        let mut nearest = f64::INFINITY;
+        let top = self.levels.len() - 1;
+        let mut queue: BinaryHeap<Reverse<(Bound, usize, usize)>> = self.levels[top]
+            .iter()
+            .enumerate()
+            .map(|(i, (rect, _))| Reverse((Bound(rect.distance_at_least(lon, lat)), top, i)))
+            .collect();
+        while let Some(Reverse((Bound(bound), level, node))) = queue.pop() {
+            if bound >= nearest {
+                break;
+            }
+            let children = self.levels[level][node].1.clone();
+            if level == 0 {
+                for &(a, b) in &self.edges[children] {
+                    nearest = nearest.min(edge_distance(lon, lat, a, b));
+                }
+                continue;
+            }
+            for child in children {
+                let rect = self.levels[level - 1][child].0;
+                let bound = rect.distance_at_least(lon, lat);
+                if bound < nearest {
+                    queue.push(Reverse((Bound(bound), level - 1, child)));
+                }
+            }
+        }
+        nearest * EARTH_RADIUS_KM
+    }
+}
+
+impl Rect {
+    fn around(a: Point, b: Point) -> Rect {
+        Rect {
+            west: a.0.min(b.0),
+            south: a.1.min(b.1),
+            east: a.0.max(b.0),
+            north: a.1.max(b.1),
+        }
+    }
+
+    fn union(self, other: Rect) -> Rect {
+        Rect {
+            west: self.west.min(other.west),
+            south: self.south.min(other.south),
+            east: self.east.max(other.east),
+            north: self.north.max(other.north),
+        }
+    }
+
+    /// A lower bound on the angular distance, in radians, from `lon`, `lat` to any point
+    /// in the rectangle. A path must cover the difference in latitude, and a path from
+    /// outside the rectangle's longitudes must cross the great circle of one of the
+    /// meridians bounding them, unless it can pass over a pole from the opposite side.
+    fn distance_at_least(&self, lon: f64, lat: f64) -> f64 {
+        let across_lat = (self.south - lat)
+            .max(lat - self.north)
+            .max(0.0)
+            .to_radians();
+        let spans = |lon: f64| (lon - self.west).rem_euclid(360.0) <= self.east - self.west;
+        let to_meridians = if self.east - self.west >= 180.0 || spans(lon) || spans(lon + 180.0) {
+            0.0
+        } else {
+            let cos_lat = lat.to_radians().cos();
+            [self.west, self.east]
+                .map(|meridian| {
+                    let sin_lon = (lon - meridian).to_radians().sin().abs();
+                    (cos_lat * sin_lon).min(1.0).asin()
+                })
+                .into_iter()
+                .fold(f64::INFINITY, f64::min)
+        };
+        across_lat.max(to_meridians)
+    }
+}
+
+/// Orders the R-tree search queue by distance.
+#[derive(PartialEq)]
+struct Bound(f64);
+
+impl Eq for Bound {}
+
+impl PartialOrd for Bound {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Bound {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Angular distance, in radians, between two positions.
+fn great_circle(a: Point, b: Point) -> f64 {
+    let (lat_a, lat_b) = (a.1.to_radians(), b.1.to_radians());
+    let half_lat = (lat_b - lat_a) / 2.0;
+    let half_lon = (b.0 - a.0).to_radians() / 2.0;
+    let h = half_lat.sin().powi(2) + lat_a.cos() * lat_b.cos() * half_lon.sin().powi(2);
+    2.0 * h.sqrt().min(1.0).asin()
+}
+
+/// Angular distance, in radians, from `lon`, `lat` to the nearest point of the edge from
+/// `a` to `b`. The nearest point is found on a plane tangent at `lon`, `lat`, which is
+/// exact for points and close for edges short enough to follow their great circle. The
+/// edge is drawn as in the file, so it only crosses the antimeridian if it runs past 180,
+/// and is tried a turn of the globe to either side.
+fn edge_distance(lon: f64, lat: f64, a: Point, b: Point) -> f64 {
+    let scale = lat.to_radians().cos();
+    let (dx, dy) = ((b.0 - a.0) * scale, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    let wrapped = (a.0 - lon + 180.0).rem_euclid(360.0) - 180.0;
+    [wrapped - 360.0, wrapped, wrapped + 360.0]
+        .map(|offset| {
+            let (ax, ay) = (offset * scale, a.1 - lat);
+            let t = match length > 0.0 {
+                true => (-(ax * dx + ay * dy) / length).clamp(0.0, 1.0),
+                false => 0.0,
+            };
+            great_circle((lon, lat), (a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1)))
+        })
+        .into_iter()
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// The geometry of a feature file: points are lines of one position.
+#[derive(Default)]
+struct Shapes {
+    lines: Vec<Vec<Point>>,
+    polygons: Vec<Vec<Vec<Point>>>,
+}
+
+fn read_geojson(text: &str) -> Result<Shapes> {
+    let mut shapes = Shapes::default();
+    collect_geometry(&json::parse(text)?, &mut shapes)?;
+    Ok(shapes)
+}
+
+fn collect_geometry(value: &json::Value, shapes: &mut Shapes) -> Result<()> {
+    let kind = value
+        .get("type")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| anyhow!("GeoJSON object without a type"))?;
+    let coordinates = || {
+        value
+            .get("coordinates")
+            .ok_or_else(|| anyhow!("{} without coordinates", kind))
+    };
+    match kind {
+        "FeatureCollection" => {
+            for feature in value.get("features").map(nested).into_iter().flatten() {
+                collect_geometry(feature, shapes)?;
+            }
+        }
+        "Feature" => match value.get("geometry") {
+            Some(json::Value::Null) | None => {}
+            Some(geometry) => collect_geometry(geometry, shapes)?,
+        },
+        "GeometryCollection" => {
+            for geometry in value.get("geometries").map(nested).into_iter().flatten() {
+                collect_geometry(geometry, shapes)?;
+            }
+        }
+        "Point" => shapes.lines.push(vec![position(coordinates()?)?]),
+        "MultiPoint" => {
+            for point in nested(coordinates()?) {
+                shapes.lines.push(vec![position(point)?]);
+            }
+        }
+        "LineString" => shapes.lines.push(positions(coordinates()?)?),
+        "MultiLineString" => {
+            for line in nested(coordinates()?) {
+                shapes.lines.push(positions(line)?);
+            }
+        }
+        "Polygon" => shapes.polygons.push(rings(coordinates()?)?),
+        "MultiPolygon" => {
+            for polygon in nested(coordinates()?) {
+                shapes.polygons.push(rings(polygon)?);
+            }
+        }
+        other => bail!("Unsupported GeoJSON type {}", other),
+    }
+    Ok(())
+}
+
+fn nested(value: &json::Value) -> std::slice::Iter<'_, json::Value> {
+    value.as_array().unwrap_or_default().iter()
+}
+
+fn position(value: &json::Value) -> Result<Point> {
+    let invalid = || anyhow!("Invalid coordinates");
+    let point = value.as_array().ok_or_else(invalid)?;
+    match (point.first(), point.get(1)) {
+        (Some(lon), Some(lat)) => Ok((
+            lon.as_f64().ok_or_else(invalid)?,
+            lat.as_f64().ok_or_else(invalid)?,
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+fn positions(value: &json::Value) -> Result<Vec<Point>> {
+    let invalid = || anyhow!("Invalid coordinates");
+    value
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(position)
+        .collect()
+}
+
+fn rings(value: &json::Value) -> Result<Vec<Vec<Point>>> {
+    let invalid = || anyhow!("Invalid coordinates");
+    value
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(positions)
+        .collect()
+}
+
+/// Reads the point, multipoint, polyline and polygon records of an ESRI shapefile.
+/// Coordinates are assumed to be lon/lat, since the `.prj` is not consulted.
+fn read_shapefile(bytes: &[u8]) -> Result<Shapes> {
+    let truncated = || anyhow!("Shapefile is truncated");
+    let le_i32 = |at: usize| -> Result<i32> {
+        Ok(i32::from_le_bytes(
+            bytes.get(at..at + 4).ok_or_else(truncated)?.try_into()?,
+        ))
+    };
+    let be_i32 = |at: usize| -> Result<i32> {
+        Ok(i32::from_be_bytes(
+            bytes.get(at..at + 4).ok_or_else(truncated)?.try_into()?,
+        ))
+    };
+    let le_f64 = |at: usize| -> Result<f64> {
+        Ok(f64::from_le_bytes(
+            bytes.get(at..at + 8).ok_or_else(truncated)?.try_into()?,
+        ))
+    };
+    let point = |at: usize| -> Result<Point> { Ok((le_f64(at)?, le_f64(at + 8)?)) };
+
+    if be_i32(0)? != 9994 {
+        bail!("Not a shapefile");
+    }
+
+    let mut shapes = Shapes::default();
+    let mut offset = 100;
+    while offset + 8 <= bytes.len() {
+        let content_length = be_i32(offset + 4)? as usize * 2;
+        let content = offset + 8;
+        // The Z and M variants of each shape type share its leading layout.
+        match le_i32(content)? {
+            0 => {}
+            1 | 11 | 21 => shapes.lines.push(vec![point(content + 4)?]),
+            8 | 18 | 28 => {
+                let count = le_i32(content + 36)? as usize;
+                for i in 0..count {
+                    shapes.lines.push(vec![point(content + 40 + 16 * i)?]);
+                }
+            }
+            shape @ (3 | 13 | 23 | 5 | 15 | 25) => {
+                let part_count = le_i32(content + 36)? as usize;
+                let point_count = le_i32(content + 40)? as usize;
+                let parts = content + 44;
+                let points = parts + 4 * part_count;
+                let mut starts = (0..part_count)
+                    .map(|i| Ok(le_i32(parts + 4 * i)? as usize))
+                    .collect::<Result<Vec<_>>>()?;
+                starts.push(point_count);
+                let mut parts = starts
+                    .windows(2)
+                    .map(|range| {
+                        (range[0]..range[1])
+                            .map(|i| point(points + 16 * i))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                match shape % 10 {
+                    3 => shapes.lines.append(&mut parts),
+                    _ => shapes.polygons.push(parts),
+                }
+            }
+            other => bail!("Unsupported shape type {}", other),
+        }
+        offset = content + content_length;
+    }
+    Ok(shapes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_geojson, Features};
+
+    #[test]
+    fn test_distance() {
+        let features = Features::new(
+            read_geojson(
+                r#"{"type": "FeatureCollection", "features": [
+                    {"type": "Feature", "geometry": {"type": "LineString",
+                     "coordinates": [[0, 0], [0, 10]]}},
+                    {"type": "Feature", "geometry": {"type": "Point", "coordinates": [179, 50]}},
+                    {"type": "Feature", "geometry": {"type": "Polygon", "coordinates":
+                     [[[20, 20], [30, 20], [30, 30], [20, 20]]]}}
+                ]}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        // A degree of longitude to the side of the line.
+        let degree_km = 111.195;
+        let beside = features.distance_km(1.0, 5.0);
+        assert!((beside - degree_km * 5f64.to_radians().cos()).abs() < 0.01);
+        // Beyond the line's end, the nearest point is the end.
+        assert!((features.distance_km(0.0, -2.0) - 2.0 * degree_km).abs() < 0.01);
+        // Along a line drawn across the whole globe.
+        let equator = Features::new(super::Shapes {
+            lines: vec![vec![(-180.0, 0.0), (180.0, 0.0)]],
+            polygons: vec![],
+        })
+        .unwrap();
+        assert!(equator.distance_km(170.0, 0.0) < 1e-9);
+        // Across the antimeridian.
+        let across = features.distance_km(-179.0, 50.0);
+        assert!((across - 2.0 * degree_km * 50f64.to_radians().cos()).abs() < 0.5);
+        // Inside a polygon.
+        assert_eq!(features.distance_km(28.0, 22.0), 0.0);
+
+        // The R-tree finds the same nearest edge as checking every one.
+        let lines: Vec<Vec<(f64, f64)>> = (0..500)
+            .map(|i| {
+                let (lon, lat) = ((i * 37 % 360) as f64 - 180.0, (i * 13 % 160) as f64 - 80.0);
+                vec![(lon, lat), (lon + 1.5, lat + 0.5)]
+            })
+            .collect();
+        let many = Features::new(super::Shapes {
+            lines: lines.clone(),
+            polygons: vec![],
+        })
+        .unwrap();
+        assert!(many.levels.len() > 2);
+        for (lon, lat) in [(0.3, 0.2), (-120.0, 45.0), (170.0, -60.0), (12.0, 85.0)] {
+            let brute = lines
+                .iter()
+                .map(|line| super::edge_distance(lon, lat, line[0], line[1]))
+                .fold(f64::INFINITY, f64::min)
+                * super::EARTH_RADIUS_KM;
+            assert!((many.distance_km(lon, lat) - brute).abs() < 1e-9);
+        }
+    }
+}
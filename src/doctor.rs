@@ -0,0 +1,322 @@
+//! Finding out where and why a damaged tif stops decoding, and keeping what still does.
+
+use crate::{
+    crs::Crs,
+    explain,
+    georef::GeoTransform,
+    inspect,
+    json::Value,
+    load_tif_contents,
+    output::{self, Compression},
+    raster::{self, Layout},
+    sidecar,
+};
+use anyhow::{bail, Result};
+use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+use rayon::prelude::*;
+use std::{io::Cursor, path::PathBuf, sync::Arc};
+use tiff::{
+    decoder::{ChunkType, Decoder, DecodingResult, Limits},
+    tags::Tag,
+};
+
+#[derive(clap::Args)]
+pub struct DoctorArgs {
+    /// The raster to examine.
+    raster: PathBuf,
+    /// Write the pixels of the first image's readable strips or tiles to this parquet
+    /// file, leaving out the damaged ones.
+    #[arg(long = "salvage")]
+    salvage: Option<PathBuf>,
+    /// CRS of the raster, read with its GeoTIFF tags, for positioning salvaged pixels.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+    #[command(flatten)]
+    compression: Compression,
+    /// Print the findings as JSON instead of text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+/// How many damaged chunks of an image are listed before the rest are only counted.
+const LISTED_PROBLEMS: usize = 20;
+
+/// What went wrong with one strip or tile.
+struct Problem {
+    chunk: u32,
+    origin: (u32, u32),
+    reason: String,
+}
+
+/// What was found in one image file directory.
+struct Image {
+    width: u32,
+    height: u32,
+    compression: String,
+    chunk_type: &'static str,
+    chunks: u32,
+    problems: Vec<Problem>,
+}
+
+pub fn run(args: &DoctorArgs) -> Result<()> {
+    let contents = load_tif_contents(&args.raster)?;
+    let mut report = vec![
+        (
+            "path",
+            Value::from(args.raster.to_string_lossy().to_string()),
+        ),
+        ("file_size", (contents.len() as u64).into()),
+    ];
+    let mut healthy = true;
+
+    let mut decoder = match Decoder::new(Cursor::new(&contents[..])) {
+        Ok(decoder) => decoder.with_limits(Limits::unlimited()),
+        Err(err) => {
+            report.push(("header", format!("unreadable: {}", err).into()));
+            print(args, Value::object(report));
+            bail!("{} has no readable image", args.raster.to_string_lossy());
+        }
+    };
+    report.push(("header", "ok".into()));
+
+    let mut images = vec![];
+    let mut ifd = 0;
+    loop {
+        let entry = match examine(&mut decoder, &contents, ifd) {
+            Ok(image) => {
+                healthy &= image.problems.is_empty();
+                describe(&image)
+            }
+            Err(err) => {
+                healthy = false;
+                Value::object([("error", Value::from(format!("{:#}", err)))])
+            }
+        };
+        images.push((format!("ifd {}", ifd), entry));
+        if !decoder.more_images() {
+            break;
+        }
+        ifd += 1;
+        if let Err(err) = decoder.next_image() {
+            healthy = false;
+            images.push((
+                format!("ifd {}", ifd),
+                Value::object([("error", Value::from(format!("unreadable: {}", err)))]),
+            ));
+            break;
+        }
+    }
+    report.push(("images", Value::object(images)));
+
+    if let Some(path) = &args.salvage {
+        let rows = salvage(&contents, args)?;
+        report.push((
+            "salvaged",
+            Value::object([
+                ("path", Value::from(path.to_string_lossy().to_string())),
+                ("rows", (rows as u64).into()),
+            ]),
+        ));
+    }
+    print(args, Value::object(report));
+    if !healthy {
+        bail!("{} is damaged", args.raster.to_string_lossy());
+    }
+    Ok(())
+}
+
+fn print(args: &DoctorArgs, report: Value) {
+    if args.json {
+        println!("{}", report.pretty());
+    } else {
+        print!("{}", explain::to_text(&report, 0));
+    }
+}
+
+/// Checks that every strip or tile of the decoder's current image lies within the file,
+/// then decodes each one that does.
+fn examine(decoder: &mut Decoder<Cursor<&[u8]>>, contents: &[u8], ifd: usize) -> Result<Image> {
+    let (width, height) = decoder.dimensions()?;
+    let compression = decoder
+        .find_tag_unsigned::<u16>(Tag::Compression)?
+        .map_or("none".to_string(), inspect::compression_name);
+    let layout = Layout::from_decoder(decoder)?;
+    let (offsets_tag, counts_tag) = match decoder.get_chunk_type() {
+        ChunkType::Strip => (Tag::StripOffsets, Tag::StripByteCounts),
+        ChunkType::Tile => (Tag::TileOffsets, Tag::TileByteCounts),
+    };
+    let offsets = decoder.get_tag_u64_vec(offsets_tag)?;
+    let counts = decoder.get_tag_u64_vec(counts_tag)?;
+    let chunks = layout.chunk_count();
+    if offsets.len() != chunks as usize || counts.len() != chunks as usize {
+        bail!(
+            "expected {} {} offsets and byte counts but found {} and {}",
+            chunks,
+            layout.chunk_type(),
+            offsets.len(),
+            counts.len()
+        );
+    }
+
+    let file_size = contents.len() as u64;
+    let problems = (0..chunks)
+        .into_par_iter()
+        .filter_map(|chunk| {
+            let (offset, count) = (offsets[chunk as usize], counts[chunk as usize]);
+            let reason = if offset == 0 || count == 0 {
+                Some("missing: its offset or byte count is 0".to_string())
+            } else if offset.saturating_add(count) > file_size {
+                Some(format!(
+                    "truncated: bytes {}..{} run past the end of the file at {}",
+                    offset,
+                    offset.saturating_add(count),
+                    file_size
+                ))
+            } else {
+                decode_chunk(contents, ifd, chunk)
+                    .err()
+                    .map(|err| format!("does not decode: {}", err))
+            };
+            reason.map(|reason| Problem {
+                chunk,
+                origin: layout.origin(chunk),
+                reason,
+            })
+        })
+        .collect();
+    Ok(Image {
+        width,
+        height,
+        compression,
+        chunk_type: layout.chunk_type(),
+        chunks,
+        problems,
+    })
+}
+
+/// Decodes one strip or tile with a decoder of its own, so a failure leaves no state
+/// behind for the next. Returns the width of the decoded pixels, which is narrower than
+/// the tile width for tiles at the right edge.
+fn decode_chunk(contents: &[u8], ifd: usize, chunk: u32) -> Result<(u32, DecodingResult)> {
+    let mut decoder = Decoder::new(Cursor::new(contents))?.with_limits(Limits::unlimited());
+    decoder.seek_to_image(ifd)?;
+    let (width, _) = decoder.chunk_data_dimensions(chunk);
+    Ok((width, raster::widen(decoder.read_chunk(chunk)?)))
+}
+
+fn describe(image: &Image) -> Value {
+    let mut problems: Vec<Value> = image
+        .problems
+        .iter()
+        .take(LISTED_PROBLEMS)
+        .map(|problem| {
+            format!(
+                "{} {} at ({}, {}): {}",
+                image.chunk_type, problem.chunk, problem.origin.0, problem.origin.1, problem.reason
+            )
+            .into()
+        })
+        .collect();
+    if image.problems.len() > LISTED_PROBLEMS {
+        problems.push(format!("and {} more", image.problems.len() - LISTED_PROBLEMS).into());
+    }
+    Value::object([
+        ("width", Value::from(image.width)),
+        ("height", image.height.into()),
+        ("compression", image.compression.clone().into()),
+        ("chunk_type", image.chunk_type.into()),
+        ("chunks", image.chunks.into()),
+        (
+            "readable_chunks",
+            (image.chunks - image.problems.len() as u32).into(),
+        ),
+        ("problems", Value::Array(problems)),
+    ])
+}
+
+/// Writes the pixels above zero of each strip or tile of the first image that decodes,
+/// returning how many there were.
+fn salvage(contents: &[u8], args: &DoctorArgs) -> Result<usize> {
+    let Some(path) = &args.salvage else {
+        return Ok(0);
+    };
+    let mut decoder = Decoder::new(Cursor::new(contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let src_crs = sidecar::src_crs(&args.raster, args.src_crs, &mut decoder)?;
+    let transform = GeoTransform::resolve(&mut decoder, src_crs, None)?;
+    let rows: Vec<(f64, f64, f64)> = (0..layout.chunk_count())
+        .into_par_iter()
+        .filter_map(|chunk| decode_chunk(contents, 0, chunk).ok().map(|d| (chunk, d)))
+        .map(|(chunk, (width, pixels))| {
+            let pixels: Vec<f64> = match pixels {
+                DecodingResult::I32(pixels) => pixels.into_iter().map(f64::from).collect(),
+                DecodingResult::F32(pixels) => pixels.into_iter().map(f64::from).collect(),
+                DecodingResult::F64(pixels) => pixels,
+                other => bail!(
+                    "Can only salvage integer or float pixels but got {}",
+                    raster::decoding_result_type(&other)
+                ),
+            };
+            let (x0, y0) = layout.origin(chunk);
+            let width = width as usize;
+            Ok(pixels
+                .into_iter()
+                .enumerate()
+                .filter(|(_, value)| *value > 0.0)
+                .map(|(i, value)| {
+                    let x = x0 + (i % width) as u32;
+                    let y = y0 + (i / width) as u32;
+                    let (lon, lat) = transform.position(x as f64, y as f64);
+                    (lon, lat, value)
+                })
+                .collect::<Vec<_>>())
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let (x_name, y_name) = match transform.crs() {
+        Some((_, dst)) if !dst.is_geographic() => ("easting", "northing"),
+        _ => ("lon", "lat"),
+    };
+    let column = |f: fn(&(f64, f64, f64)) -> f64| {
+        Arc::new(Float32Array::from_iter_values(
+            rows.iter().map(|r| f(r) as f32),
+        )) as ArrayRef
+    };
+    let batch = RecordBatch::try_from_iter([
+        (x_name, column(|r| r.0)),
+        (y_name, column(|r| r.1)),
+        ("value", column(|r| r.2)),
+    ])?;
+    output::write_parquet(path, &batch, args.compression)?;
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{examine, Image};
+    use std::io::Cursor;
+    use tiff::{
+        decoder::Decoder,
+        encoder::{colortype::GrayI32, TiffEncoder},
+    };
+
+    #[test]
+    fn test_examine() {
+        let mut contents = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut contents).unwrap();
+        let mut image = encoder.new_image::<GrayI32>(3, 4).unwrap();
+        image.rows_per_strip(2).unwrap();
+        image.write_data(&[1; 12]).unwrap();
+        let contents = contents.into_inner();
+
+        let mut decoder = Decoder::new(Cursor::new(&contents[..])).unwrap();
+        let Image {
+            chunks, problems, ..
+        } = examine(&mut decoder, &contents, 0).unwrap();
+        assert_eq!(chunks, 2);
+        assert!(problems.is_empty());
+    }
+}
@@ -0,0 +1,711 @@
+//! Describes what a conversion would do without running it.
+
+use crate::{
+    archive::{self, Kind},
+    columns,
+    contract::Contract,
+    crs::Crs,
+    georef::{GeoTransform, Priority},
+    group::{Align, Binning},
+    json::Value,
+    manifest::manifest_path,
+    mmap::TifContents,
+    output::{Compression, OutputFormat, Target},
+    processor::{build_batch, priority_path, Options, Rows},
+    raster::{self, Layout},
+    sidecar, stdin,
+    transform::Transform,
+};
+use anyhow::Result;
+use arrow_array::{new_null_array, ArrayRef, Float64Array, RecordBatch};
+use clap::ValueEnum;
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum ExplainFormat {
+    Text,
+    Json,
+}
+
+/// Resolves the pipeline for one input and prints it to stdout.
+pub fn explain(input_path: &Path, options: &Options, format: ExplainFormat) -> Result<()> {
+    let plan = plan(input_path, options)?;
+    match format {
+        ExplainFormat::Json => println!("{}", plan.pretty()),
+        ExplainFormat::Text => print!("{}", to_text(&plan, 0)),
+    }
+    Ok(())
+}
+
+fn plan(input_path: &Path, options: &Options) -> Result<Value> {
+    let (tif_contents, band) = options.read_band(input_path)?;
+    let mut decoder = Decoder::new(Cursor::new(&tif_contents))?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let layout = Layout::from_decoder(&mut decoder)?.with_band(band)?;
+    let source = options.source_metadata(&mut decoder)?;
+    let src_crs = sidecar::src_crs(input_path, options.src_crs, &mut decoder)?;
+    let source_transform = GeoTransform::resolve(&mut decoder, src_crs, options.dst_crs)?;
+    let transform = source_transform.resampled(options.resample.unwrap_or(1));
+    let bounds = source_transform.bounds();
+    let (pixel_lon, pixel_lat) = source_transform.pixel_size();
+
+    let input = Value::object([
+        (
+            "path",
+            Value::from(input_path.to_string_lossy().to_string()),
+        ),
+        ("container", Value::from(container(input_path)?)),
+        (
+            "loaded",
+            Value::from(match tif_contents {
+                TifContents::Mapped(_) => "mapped from the file",
+                TifContents::Owned(_) => "read into memory",
+                TifContents::Stdin(_) => "read from stdin into memory",
+            }),
+        ),
+        ("width", width.into()),
+        ("height", height.into()),
+        ("band", (options.band as u32).into()),
+        ("sample_type", raster::sample_type(&mut decoder)?.into()),
+        (
+            "sample_format",
+            match options.sample_format {
+                None => "as tagged".into(),
+                Some(format) => format!("{} (overridden)", value_name(&format)).into(),
+            },
+        ),
+        ("chunk_type", layout.chunk_type().into()),
+        ("chunk_width", layout.chunk_dimensions().0.into()),
+        ("chunk_height", layout.chunk_dimensions().1.into()),
+        ("chunks", layout.chunk_count().into()),
+        (
+            "work_units",
+            (layout
+                .units(options.unit_size(&tif_contents, &layout, width)?)
+                .len() as u64)
+                .into(),
+        ),
+    ]);
+
+    let (source_crs, output_crs) = transform.crs().unwrap_or((Crs::wgs84(), Crs::wgs84()));
+    let georeferencing = Value::object([
+        (
+            "source",
+            Value::from(match transform.crs() {
+                None => "assumed global grid (no georeferencing tags are read)",
+                Some(_) => "GeoTIFF ModelPixelScale and ModelTiepoint tags",
+            }),
+        ),
+        ("crs", source_crs.to_string().into()),
+        ("output_crs", output_crs.to_string().into()),
+        (
+            "bounds",
+            vec![bounds.west, bounds.south, bounds.east, bounds.north].into(),
+        ),
+        ("pixel_size", vec![pixel_lon, pixel_lat].into()),
+        (
+            "pixel_position",
+            match options.registration {
+                Align::Corner => "top left corner",
+                Align::Center => "center",
+            }
+            .into(),
+        ),
+    ]);
+
+    let resample = match options.resample {
+        None => Value::Null,
+        Some(factor) => Value::object([
+            ("factor", factor.into()),
+            ("method", value_name(&options.resample_method).into()),
+            ("pixel_size", {
+                let (x, y) = transform.pixel_size();
+                vec![x, y].into()
+            }),
+        ]),
+    };
+
+    let sample = match options.sample {
+        None => Value::Null,
+        Some(fraction) => Value::object([
+            ("fraction", Value::from(fraction)),
+            ("seed", options.seed.into()),
+        ]),
+    };
+
+    let stride = match options.stride {
+        None => Value::Null,
+        Some(stride) => Value::object([
+            ("every", stride.into()),
+            ("spacing", {
+                let (x, y) = source_transform.pixel_size();
+                vec![x * stride as f64, y * stride as f64].into()
+            }),
+        ]),
+    };
+
+    let multires = match options.multires {
+        None => Value::Null,
+        Some(count) => Value::object([
+            ("levels", Value::from(count as u32 + 1)),
+            ("method", value_name(&options.resample_method).into()),
+            (
+                "pixel_sizes",
+                Value::Array(
+                    (0..=count)
+                        .map(|level| {
+                            let (x, y) = source_transform.resampled(1 << level).pixel_size();
+                            vec![x, y].into()
+                        })
+                        .collect(),
+                ),
+            ),
+        ]),
+    };
+
+    let scaling = match options.scaling(&source) {
+        None => Value::Null,
+        Some((scale, offset)) => {
+            Value::object([("scale", Value::from(scale)), ("offset", offset.into())])
+        }
+    };
+
+    let expression = match &options.expr {
+        None => Value::Null,
+        Some(expr) => expr.to_string().into(),
+    };
+
+    let transforms: Vec<Value> = options
+        .transforms
+        .iter()
+        .map(|builtin| builtin.describe().into())
+        .collect();
+
+    let mut filters = vec![Value::from(match (options.keep_zero, options.min_value) {
+        (false, None) => "stored value > 0",
+        (true, None) => "stored value >= 0",
+        (false, Some(_)) => "stored value != 0",
+        (true, Some(_)) => "any stored value",
+    })];
+    if !options.keep_nan {
+        filters.push("float value isn't NaN or infinite".into());
+    }
+    if options.expr.is_some() {
+        filters.push("expression gives a number".into());
+    }
+    if let Some(min) = options.min_value {
+        filters.push(format!("value >= {}", min).into());
+    }
+    if let Some(max) = options.max_value {
+        filters.push(format!("value <= {}", max).into());
+    }
+    if let Some(bbox) = options.bbox {
+        filters.push(
+            format!(
+                "inside bbox {},{},{},{} (chunks outside are not decoded)",
+                bbox.west, bbox.south, bbox.east, bbox.north
+            )
+            .into(),
+        );
+    }
+
+    if let Some(mask) = &options.mask {
+        filters.push(format!("pixel center inside polygons of {}", mask.to_string_lossy()).into());
+    }
+
+    let per_area = match options.per_area_to_total {
+        false => Value::Null,
+        true => "value × pixel area in km² on the GRS 1980 ellipsoid, after filters".into(),
+    };
+
+    let time = match options.time(input_path)? {
+        None => Value::Null,
+        Some(time) => crate::time::iso8601(time).into(),
+    };
+
+    let thinning = match options.thin {
+        None => Value::Null,
+        Some(tolerance) => Value::object([
+            ("tolerance", Value::from(tolerance)),
+            ("keeps", "the largest value in each square".into()),
+        ]),
+    };
+
+    let row_filter = match &options.row_filter {
+        None => Value::Null,
+        Some(condition) => format!("{} (checked on output rows)", condition).into(),
+    };
+
+    let stratification = match &options.stratify_by {
+        None => Value::Null,
+        Some(path) => Value::object([
+            ("raster", Value::from(path.display().to_string())),
+            ("strata", crate::strata::labels(&options.strata).into()),
+        ]),
+    };
+
+    let reclass = match &options.reclass {
+        None => Value::Null,
+        Some(classes) => Value::object([
+            ("classes", Value::from(classes.to_string())),
+            ("column", "class".into()),
+        ]),
+    };
+
+    let distance = match &options.distance_to {
+        None => Value::Null,
+        Some(path) => Value::object([
+            ("features", Value::from(path.display().to_string())),
+            ("column", "distance_km".into()),
+            ("measured", "great-circle, 0 inside polygons".into()),
+        ]),
+    };
+
+    let aggregation = match options.binning() {
+        None => Value::Null,
+        Some(binning) => {
+            let mut entries = match binning {
+                Binning::Grid(grid) => vec![
+                    ("binning", Value::from("grid")),
+                    (
+                        "size",
+                        match grid.size.lon == grid.size.lat {
+                            true => grid.size.lon.into(),
+                            false => vec![grid.size.lon, grid.size.lat].into(),
+                        },
+                    ),
+                    ("origin", vec![grid.origin.lon, grid.origin.lat].into()),
+                    (
+                        "align",
+                        match grid.align {
+                            Align::Corner => "corner",
+                            Align::Center => "center",
+                        }
+                        .into(),
+                    ),
+                    (
+                        "aligned_to",
+                        options
+                            .align_to
+                            .as_ref()
+                            .map(|path| path.display().to_string())
+                            .into(),
+                    ),
+                ],
+                Binning::S2(level) => vec![
+                    ("binning", Value::from("s2")),
+                    ("level", (level as u32).into()),
+                ],
+                Binning::H3(resolution) => vec![
+                    ("binning", Value::from("h3")),
+                    ("resolution", (resolution as u32).into()),
+                ],
+                Binning::Tile { zoom, quadkey } => vec![
+                    ("binning", Value::from("web mercator tile")),
+                    ("zoom", (zoom as u32).into()),
+                    ("quadkey", quadkey.into()),
+                ],
+            };
+            let function = match (options.categorical, options.class_counts) {
+                (true, false) => "most common class".into(),
+                (true, true) => "pixels of each class".into(),
+                (false, _) => value_name(&options.agg).into(),
+            };
+            entries.push(("function", function));
+            Value::object(entries)
+        }
+    };
+
+    let empty = build_batch(
+        Rows::Pixels(vec![]),
+        vec![],
+        vec![],
+        vec![],
+        options,
+        &source,
+        &transform,
+        options.time(input_path)?,
+    )?;
+    let schema = columns::rename(empty, &options.renames())?.schema();
+    let mut output = vec![
+        (
+            "path",
+            Value::from(
+                options
+                    .output_path(input_path)?
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        ),
+        ("format", value_name(&options.format).into()),
+        (
+            "if_exists",
+            match (options.skip_existing, options.overwrite) {
+                _ if options.output == Some(Target::Stdout) => "write to stdout",
+                _ if options.output.is_some() => "replace",
+                _ if matches!(options.format, OutputFormat::Delta) => "add a commit",
+                _ if options.postgres.is_some() => "add rows",
+                (false, false) => "fail",
+                (false, true) => "replace",
+                (true, false) => "skip if newer than the input, otherwise fail",
+                (true, true) => "skip if newer than the input, otherwise replace",
+            }
+            .into(),
+        ),
+        (
+            "manifest",
+            match options.manifest {
+                true => Value::from(
+                    manifest_path(&options.output_path(input_path)?)
+                        .display()
+                        .to_string(),
+                ),
+                false => Value::Null,
+            },
+        ),
+    ];
+    if let Some(Priority::BBox(region)) = options.stream_priority {
+        let priority_path = priority_path(&options.output_path(input_path)?);
+        output.push((
+            "priority",
+            Value::object([
+                (
+                    "path",
+                    Value::from(priority_path.to_string_lossy().to_string()),
+                ),
+                (
+                    "bbox",
+                    vec![region.west, region.south, region.east, region.north].into(),
+                ),
+                ("written", "before the rest of the image is decoded".into()),
+            ]),
+        ));
+    }
+    if let Some(budget) = options.budget(&tif_contents)? {
+        let rows = width as u64 * height as u64;
+        let bands = budget.bands(width, height, layout.chunk_dimensions().1);
+        let band_rows = bands[0].len() as u64 * width as u64;
+        let written = match (rows <= budget.rows(), options.streams_bands()) {
+            (true, _) => "whole".to_string(),
+            (false, true) if band_rows > budget.rows() && !options.force => {
+                "fails, as even one band of whole strips or tiles may not fit".to_string()
+            }
+            (false, true) => format!("in {} bands of image rows", bands.len()),
+            (false, false) if options.force => "whole, though it may not fit".to_string(),
+            (false, false) => "fails, as it may not fit and must be built whole".to_string(),
+        };
+        output.push((
+            "memory",
+            Value::object([
+                (
+                    "max_bytes",
+                    Value::from(options.max_memory.unwrap_or_default()),
+                ),
+                ("tif_bytes", (tif_contents.len() as u64).into()),
+                ("rows_held", budget.rows().into()),
+                ("written", written.into()),
+            ]),
+        ));
+    }
+    if let Some(path) = &options.contract {
+        // Sort order depends on the rows, so only the schema is checked here.
+        let schema_check = match Contract::load(path)?.check_schema(&schema) {
+            Ok(()) => Value::from("matches"),
+            Err(error) => format!("{:#}", error).into(),
+        };
+        output.push((
+            "contract",
+            Value::object([
+                ("path", Value::from(path.display().to_string())),
+                ("schema", schema_check),
+            ]),
+        ));
+    }
+    match options.format {
+        OutputFormat::Parquet => {
+            output.push(("compression", compression_name(options.compression).into()));
+        }
+        OutputFormat::ArrowStream => {
+            output.push(("compression", "uncompressed".into()));
+        }
+        OutputFormat::Delta => {
+            output.push(("compression", compression_name(options.compression).into()));
+            output.push(("partition_by", options.partition_by.clone().into()));
+        }
+        OutputFormat::Fgb => {
+            output.push(("geometry", value_name(&options.geometry).into()));
+            output.push(("spatial_index", "packed Hilbert R-tree".into()));
+        }
+        OutputFormat::Shp => {
+            output.push(("geometry", value_name(&options.geometry).into()));
+            output.push(("split", "into numbered files past 2 GB".into()));
+        }
+        OutputFormat::Gpkg => {
+            output.push(("layer", options.layer_name(input_path).into()));
+            output.push(("geometry", value_name(&options.geometry).into()));
+            output.push(("spatial_index", "SQLite R-tree".into()));
+        }
+        OutputFormat::Mvt | OutputFormat::Mbtiles => {
+            output.push(("layer", options.layer_name(input_path).into()));
+            output.push(("geometry", value_name(&options.geometry).into()));
+            output.push((
+                "zooms",
+                vec![options.min_zoom as f64, options.max_zoom as f64].into(),
+            ));
+            if let Some(max) = options.max_tile_features {
+                output.push(("max_tile_features", max.into()));
+            }
+        }
+    }
+    if let Some(style_path) = &options.style_out {
+        output.push((
+            "style",
+            Value::object([
+                (
+                    "path",
+                    Value::from(style_path.to_string_lossy().to_string()),
+                ),
+                ("classes", options.style_classes.into()),
+                ("breaks", "quantiles of value".into()),
+            ]),
+        ));
+    }
+    output.push((
+        "file_metadata",
+        Value::Array(
+            schema
+                .metadata()
+                .keys()
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .map(|key| Value::from(key.as_str()))
+                .collect(),
+        ),
+    ));
+    output.push((
+        "schema",
+        Value::Array(
+            schema
+                .fields()
+                .iter()
+                .map(|field| {
+                    Value::object([
+                        ("name", Value::from(field.name().as_str())),
+                        ("type", field.data_type().to_string().into()),
+                        (
+                            "metadata",
+                            Value::object(
+                                field
+                                    .metadata()
+                                    .iter()
+                                    .collect::<std::collections::BTreeMap<_, _>>()
+                                    .into_iter()
+                                    .map(|(k, v)| (k.clone(), Value::from(v.as_str()))),
+                            ),
+                        ),
+                    ])
+                })
+                .collect(),
+        ),
+    ));
+
+    Ok(Value::object([
+        ("input", input),
+        ("georeferencing", georeferencing),
+        ("scaling", scaling),
+        ("sample", sample),
+        ("stride", stride),
+        ("resample", resample),
+        ("multires", multires),
+        ("expression", expression),
+        ("transforms", Value::Array(transforms)),
+        ("filters", Value::Array(filters)),
+        ("per_area_to_total", per_area),
+        ("stratification", stratification),
+        ("reclass", reclass),
+        ("distance_to", distance),
+        ("aggregation", aggregation),
+        ("thinning", thinning),
+        ("row_filter", row_filter),
+        ("time", time),
+        ("output", Value::object(output)),
+    ]))
+}
+
+/// What converting an input would write, estimated from its header without decoding it.
+pub struct OutputEstimate {
+    pub path: PathBuf,
+    pub sample_type: String,
+    /// An upper bound, as pixels that are empty or filtered out aren't known yet.
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+/// What holds the tif: an archive, gzip, or nothing, going by the input's name, or for
+/// stdin by its first bytes.
+fn container(input_path: &Path) -> Result<Option<&str>> {
+    if stdin::is_stdin(input_path) {
+        let contents = stdin::contents()?;
+        return Ok(Some(match Kind::sniff(contents) {
+            Some(kind) => kind.name(),
+            None if archive::is_gzip(contents) => "tif.gz",
+            None => "tif",
+        }));
+    }
+    Ok(match Kind::of(input_path) {
+        Some(kind) => Some(kind.name()),
+        None if archive::is_gzipped_tif(input_path) => Some("tif.gz"),
+        None => input_path.extension().and_then(|e| e.to_str()),
+    })
+}
+
+/// Rows of made-up values the output size is extrapolated from.
+const SAMPLE_ROWS: usize = 1024;
+
+pub fn estimate_output(input_path: &Path, options: &Options) -> Result<OutputEstimate> {
+    let (tif_contents, _) = options.read_band(input_path)?;
+    let mut decoder = Decoder::new(Cursor::new(&tif_contents))?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let source = options.source_metadata(&mut decoder)?;
+    let src_crs = sidecar::src_crs(input_path, options.src_crs, &mut decoder)?;
+    let source_transform = GeoTransform::resolve(&mut decoder, src_crs, options.dst_crs)?;
+    let factor = options.resample.or(options.stride).unwrap_or(1) as u64;
+    let pixels = |factor: u64| (width as u64).div_ceil(factor) * (height as u64).div_ceil(factor);
+    let mut rows = (pixels(factor) as f64 * options.sample.unwrap_or(1.0)).ceil() as u64;
+    for level in 1..=options.multires.unwrap_or(0) {
+        rows += pixels(factor << level);
+    }
+    if let Some(cells) = options.max_cells(&source_transform) {
+        rows = rows.min(cells);
+    }
+
+    // Every format's size grows with its rows past a fixed overhead, which a table of
+    // zeros in the output's columns measures.
+    let transform = source_transform.resampled(options.resample.unwrap_or(1));
+    let time = options.time(input_path)?;
+    let empty = build_batch(
+        Rows::Pixels(vec![]),
+        vec![],
+        vec![],
+        vec![],
+        options,
+        &source,
+        &transform,
+        time,
+    )?;
+    let zeros: ArrayRef = Arc::new(Float64Array::from(vec![0.0; SAMPLE_ROWS]));
+    let columns = empty
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| {
+            arrow_cast::cast(&zeros, field.data_type())
+                .unwrap_or_else(|_| new_null_array(field.data_type(), SAMPLE_ROWS))
+        })
+        .collect();
+    let sample = RecordBatch::try_new(empty.schema(), columns)?;
+    let (fixed, sample_size) = (
+        options.estimate_size(&empty),
+        options.estimate_size(&sample),
+    );
+    Ok(OutputEstimate {
+        path: options.output_path(input_path)?,
+        sample_type: raster::sample_type(&mut decoder)?,
+        rows,
+        bytes: fixed + sample_size.saturating_sub(fixed) * rows / SAMPLE_ROWS as u64,
+    })
+}
+
+/// The codec, and its level when one was given, as `zstd` or `zstd level 19`.
+fn compression_name(compression: Compression) -> String {
+    match compression.level {
+        Some(level) => format!("{} level {}", value_name(&compression.codec), level),
+        None => value_name(&compression.codec),
+    }
+}
+
+pub fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Lays the plan out as indented `key: value` lines.
+pub fn to_text(value: &Value, indent: usize) -> String {
+    let pad = "  ".repeat(indent);
+    let mut out = String::new();
+    match value {
+        Value::Object(entries) => {
+            for (key, value) in entries {
+                match value {
+                    Value::Object(_) => {
+                        out.push_str(&format!("{}{}:\n{}", pad, key, to_text(value, indent + 1)))
+                    }
+                    Value::Array(items) if items.iter().any(|i| matches!(i, Value::Object(_))) => {
+                        out.push_str(&format!("{}{}:\n{}", pad, key, to_text(value, indent + 1)))
+                    }
+                    _ => out.push_str(&format!("{}{}: {}\n", pad, key, inline(value))),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                match item {
+                    Value::Object(entries) => {
+                        let fields: Vec<String> = entries.iter().map(|(_, v)| inline(v)).collect();
+                        out.push_str(&format!("{}- {}\n", pad, fields.join(" ")));
+                    }
+                    _ => out.push_str(&format!("{}- {}\n", pad, inline(item))),
+                }
+            }
+        }
+        _ => out.push_str(&format!("{}{}\n", pad, inline(value))),
+    }
+    out
+}
+
+fn inline(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "none".to_string(),
+        Value::Array(items) if items.iter().all(|i| matches!(i, Value::String(_))) => {
+            items.iter().map(inline).collect::<Vec<_>>().join("; ")
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::estimate_output;
+    use crate::processor::Processor;
+    use std::fs::File;
+    use tiff::encoder::{colortype::GrayI32, TiffEncoder};
+
+    #[test]
+    fn test_estimate_output() {
+        let path = std::env::temp_dir().join(format!("explain-test-{}.tif", std::process::id()));
+        TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<GrayI32>(4, 3, &[1; 12])
+            .unwrap();
+        let estimate = |builder: crate::processor::ProcessorBuilder| {
+            estimate_output(&path, builder.build().unwrap().options()).unwrap()
+        };
+        let pixels = estimate(Processor::builder());
+        assert_eq!(pixels.path, path.with_extension("parquet"));
+        assert_eq!((pixels.sample_type.as_str(), pixels.rows), ("I32", 12));
+        // Grouping into half-world cells can't give more rows than the cells covering it.
+        let grouped = estimate(Processor::builder().group(180.0));
+        assert_eq!(grouped.rows, 6);
+        assert!(grouped.bytes < pixels.bytes);
+        assert!(!path.with_extension("parquet").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
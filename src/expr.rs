@@ -0,0 +1,465 @@
+//! A small arithmetic language for rewriting pixel values, such as `log(value + 1)` or
+//! `value * 0.02 - 273.15`, and for picking rows, such as `value > 10 && lat > 0`.
+//!
+//! Expressions are made of numbers, the variables `value`, `lon` and `lat`, the operators
+//! `+ - * / ^` with the usual precedence, parentheses, and the functions in [`FUNCTIONS`].
+//! The comparisons `< <= > >= == !=`, and `&&`, `||` and `!` below them, give 1 for true
+//! and 0 for false, and take any number but 0 and NaN as true.
+
+use anyhow::{anyhow, bail, Result};
+use arrow_array::{
+    cast::as_primitive_array, types::Float64Type, ArrayRef, BooleanArray, RecordBatch,
+};
+use arrow_schema::DataType;
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// The functions expressions may call, with how many arguments each takes.
+const FUNCTIONS: [(&str, usize); 11] = [
+    ("abs", 1),
+    ("sqrt", 1),
+    ("exp", 1),
+    ("log", 1),
+    ("log10", 1),
+    ("log2", 1),
+    ("floor", 1),
+    ("ceil", 1),
+    ("round", 1),
+    ("min", 2),
+    ("max", 2),
+];
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Number(f64),
+    Value,
+    Lon,
+    Lat,
+    Negate(Box<Node>),
+    Not(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Compare(&'static str, Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Call(&'static str, Vec<Node>),
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            bail!("Unexpected {} in expression {}", token, s);
+        }
+        Ok(Expr {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression for one pixel.
+    pub fn eval(&self, value: f64, lon: f64, lat: f64) -> f64 {
+        self.root.eval(value, lon, lat)
+    }
+
+    /// Whether the expression is true for one pixel or row.
+    pub fn test(&self, value: f64, lon: f64, lat: f64) -> bool {
+        truth(self.eval(value, lon, lat))
+    }
+
+    /// Keeps the rows of `batch` the expression is true for, taking `lon` and `lat` from
+    /// its first two columns, whatever their units, and `value` from its `value` column.
+    pub fn filter(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        let floats = |column: &ArrayRef| -> Result<Vec<f64>> {
+            let column = arrow_cast::cast(column, &DataType::Float64)?;
+            Ok(as_primitive_array::<Float64Type>(&column)
+                .iter()
+                .map(|v| v.unwrap_or(f64::NAN))
+                .collect())
+        };
+        let Some(values) = batch.column_by_name("value") else {
+            bail!("There is no value column to filter rows by");
+        };
+        let (values, xs, ys) = (
+            floats(values)?,
+            floats(batch.column(0))?,
+            floats(batch.column(1))?,
+        );
+        let keep = BooleanArray::from_iter(
+            (0..batch.num_rows()).map(|i| Some(self.test(values[i], xs[i], ys[i]))),
+        );
+        Ok(arrow_select::filter::filter_record_batch(batch, &keep)?)
+    }
+}
+
+fn truth(x: f64) -> bool {
+    x != 0.0 && !x.is_nan()
+}
+
+fn number(b: bool) -> f64 {
+    match b {
+        true => 1.0,
+        false => 0.0,
+    }
+}
+
+impl Node {
+    fn eval(&self, value: f64, lon: f64, lat: f64) -> f64 {
+        let eval = |node: &Node| node.eval(value, lon, lat);
+        match self {
+            Node::Number(n) => *n,
+            Node::Value => value,
+            Node::Lon => lon,
+            Node::Lat => lat,
+            Node::Negate(node) => -eval(node),
+            Node::Not(node) => number(!truth(eval(node))),
+            Node::And(left, right) => number(truth(eval(left)) && truth(eval(right))),
+            Node::Or(left, right) => number(truth(eval(left)) || truth(eval(right))),
+            Node::Compare(op, left, right) => {
+                let (left, right) = (eval(left), eval(right));
+                number(match *op {
+                    "<" => left < right,
+                    "<=" => left <= right,
+                    ">" => left > right,
+                    ">=" => left >= right,
+                    "==" => left == right,
+                    "!=" => left != right,
+                    _ => unreachable!("only comparisons are parsed into compare nodes"),
+                })
+            }
+            Node::Binary(op, left, right) => {
+                let (left, right) = (eval(left), eval(right));
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    '/' => left / right,
+                    '^' => left.powf(right),
+                    _ => unreachable!("only operators are parsed into binary nodes"),
+                }
+            }
+            Node::Call(name, args) => {
+                let args: Vec<f64> = args.iter().map(eval).collect();
+                match (*name, &args[..]) {
+                    ("abs", [x]) => x.abs(),
+                    ("sqrt", [x]) => x.sqrt(),
+                    ("exp", [x]) => x.exp(),
+                    ("log", [x]) => x.ln(),
+                    ("log10", [x]) => x.log10(),
+                    ("log2", [x]) => x.log2(),
+                    ("floor", [x]) => x.floor(),
+                    ("ceil", [x]) => x.ceil(),
+                    ("round", [x]) => x.round(),
+                    ("min", [x, y]) => x.min(*y),
+                    ("max", [x, y]) => x.max(*y),
+                    _ => unreachable!("argument counts are checked when parsing"),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+    Operator(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Name(name) => write!(f, "`{}`", name),
+            Token::Symbol(c) => write!(f, "`{}`", c),
+            Token::Operator(op) => write!(f, "`{}`", op),
+        }
+    }
+}
+
+/// Comparison and logical operators, longest first so `<=` isn't read as `<`.
+const OPERATORS: [&str; 9] = ["<=", ">=", "==", "!=", "&&", "||", "<", ">", "!"];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                // Allow exponents like `1e-3`.
+                let exponent_sign = (c == '-' || c == '+') && s[..i].ends_with(['e', 'E']);
+                if !(c.is_ascii_alphanumeric() || c == '.' || exponent_sign) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let text = &s[start..end];
+            tokens.push(Token::Number(text.parse().map_err(|_| {
+                anyhow!("Invalid number `{}` in expression {}", text, s)
+            })?));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Name(s[start..end].to_string()));
+        } else if let Some(op) = OPERATORS.iter().find(|op| s[start..].starts_with(**op)) {
+            tokens.push(Token::Operator(op));
+            for _ in 0..op.len() {
+                chars.next();
+            }
+        } else if "+-*/^(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            bail!("Unexpected `{}` in expression {}", c, s);
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser, with one method per precedence level.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.tokens.get(self.position) == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            Some(token) => bail!("Expected `{}` but found {}", symbol, token),
+            None => bail!("Expected `{}` but the expression ended", symbol),
+        }
+    }
+
+    fn eat_operator(&mut self, op: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(found)) if *found == op => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Node> {
+        let mut node = self.and()?;
+        while self.eat_operator("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node> {
+        let mut node = self.not()?;
+        while self.eat_operator("&&") {
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node> {
+        if self.eat_operator("!") {
+            return Ok(Node::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    /// Comparisons don't chain, so `1 < value < 3` is an error rather than a surprise.
+    fn comparison(&mut self) -> Result<Node> {
+        let node = self.sum()?;
+        match ["<=", ">=", "==", "!=", "<", ">"]
+            .into_iter()
+            .find(|op| self.eat_operator(op))
+        {
+            Some(op) => Ok(Node::Compare(op, Box::new(node), Box::new(self.sum()?))),
+            None => Ok(node),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Node> {
+        let mut node = self.product()?;
+        loop {
+            let op = match () {
+                _ if self.eat('+') => '+',
+                _ if self.eat('-') => '-',
+                _ => return Ok(node),
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Node> {
+        let mut node = self.unary()?;
+        loop {
+            let op = match () {
+                _ if self.eat('*') => '*',
+                _ if self.eat('/') => '/',
+                _ => return Ok(node),
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        if self.eat('-') {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    /// `^` binds tighter than unary minus on its left and is right associative, so
+    /// `-2^2` is -4 and `2^3^2` is 512.
+    fn power(&mut self) -> Result<Node> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Node::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Symbol('(')) => {
+                let node = self.or()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(Token::Name(name)) => match name.as_str() {
+                "value" => Ok(Node::Value),
+                "lon" => Ok(Node::Lon),
+                "lat" => Ok(Node::Lat),
+                _ => {
+                    let Some(&(function, arity)) = FUNCTIONS.iter().find(|(f, _)| *f == name)
+                    else {
+                        bail!(
+                            "Unknown name `{}`, expected `value`, `lon`, `lat` or a function",
+                            name
+                        );
+                    };
+                    self.expect('(')?;
+                    let mut args = vec![self.or()?];
+                    while self.eat(',') {
+                        args.push(self.or()?);
+                    }
+                    self.expect(')')?;
+                    if args.len() != arity {
+                        bail!(
+                            "`{}` takes {} argument(s) but was given {}",
+                            function,
+                            arity,
+                            args.len()
+                        );
+                    }
+                    Ok(Node::Call(function, args))
+                }
+            },
+            Some(token) => bail!("Unexpected {}", token),
+            None => bail!("Expression ended early"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expr;
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use std::sync::Arc;
+
+    fn eval(s: &str) -> f64 {
+        s.parse::<Expr>().unwrap().eval(10.0, 2.0, -3.0)
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("value * 0.02 - 273.15"), 10.0 * 0.02 - 273.15);
+        assert_eq!(eval("log(value + 1)"), 11f64.ln());
+        assert_eq!(eval("-2^2 + 2^3^2"), -4.0 + 512.0);
+        assert_eq!(eval("max(lon, lat) / (1 - -1)"), 1.0);
+        assert_eq!(eval("1.5e-1 * 2e1"), 3.0);
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert_eq!(eval("value > 5 && lat < 0"), 1.0);
+        assert_eq!(eval("value <= 5 || !(lon == 2)"), 0.0);
+        assert_eq!(eval("(value != 10) + 1"), 1.0);
+        assert_eq!(eval("1 + 1 >= 2 && 2^2 == 4"), 1.0);
+
+        let column = |values: Vec<f32>| Arc::new(Float32Array::from(values)) as ArrayRef;
+        let batch = RecordBatch::try_from_iter([
+            ("lon", column(vec![0.0, 1.0, 2.0])),
+            ("lat", column(vec![-1.0, 1.0, 2.0])),
+            ("value", column(vec![20.0, 30.0, 5.0])),
+        ])
+        .unwrap();
+        let expr: Expr = "value > 10 && lat > 0".parse().unwrap();
+        let kept = expr.filter(&batch).unwrap();
+        assert_eq!(kept.num_rows(), 1);
+        assert_eq!(kept.column(0).as_ref(), column(vec![1.0]).as_ref());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for bad in [
+            "1 < value < 3",
+            "value &&",
+            "value = 1",
+            "value +",
+            "foo(1)",
+            "min(1)",
+            "(value",
+            "value value",
+            "3 % 2",
+        ] {
+            assert!(bad.parse::<Expr>().is_err(), "{} should not parse", bad);
+        }
+    }
+}
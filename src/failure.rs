@@ -0,0 +1,188 @@
+//! Sorting failures into classes that orchestration can act on differently, each with
+//! its own exit code, and the records `--errors-json` writes for the inputs that failed.
+
+use crate::json::Value;
+use anyhow::{Context, Result};
+use std::{
+    fmt::{self, Display},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tiff::TiffError;
+use zip::result::ZipError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureClass {
+    /// The input is damaged or isn't what its name says.
+    BadInput,
+    /// The input is valid, but uses a format or feature this tool doesn't read.
+    Unsupported,
+    /// Reading or writing a file failed, whatever was in it.
+    Io,
+    /// The conversion ran past a limit on its memory, CPU time or running time.
+    Resource,
+    /// Anything else, such as options that don't fit the input.
+    Other,
+}
+
+impl FailureClass {
+    /// The class of the outermost cause of `error` whose type says what went wrong.
+    pub fn of(error: &anyhow::Error) -> FailureClass {
+        for cause in error.chain() {
+            if let Some(classified) = cause.downcast_ref::<Classified>() {
+                return classified.class;
+            }
+            if let Some(tiff) = cause.downcast_ref::<TiffError>() {
+                return match tiff {
+                    TiffError::FormatError(_) | TiffError::IntSizeError => FailureClass::BadInput,
+                    TiffError::UnsupportedError(_) | TiffError::LimitsExceeded => {
+                        FailureClass::Unsupported
+                    }
+                    TiffError::IoError(io) => io_class(io.kind()),
+                    TiffError::UsageError(_) => FailureClass::Other,
+                };
+            }
+            if let Some(zip) = cause.downcast_ref::<ZipError>() {
+                return match zip {
+                    ZipError::InvalidArchive(_) | ZipError::FileNotFound => FailureClass::BadInput,
+                    ZipError::UnsupportedArchive(_) => FailureClass::Unsupported,
+                    ZipError::Io(io) => io_class(io.kind()),
+                };
+            }
+            if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+                return io_class(io.kind());
+            }
+        }
+        // Errors about what this tool can't read are worded alike throughout.
+        match error
+            .chain()
+            .any(|cause| cause.to_string().starts_with("Unsupported "))
+        {
+            true => FailureClass::Unsupported,
+            false => FailureClass::Other,
+        }
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureClass::Other => 1,
+            FailureClass::BadInput => 3,
+            FailureClass::Unsupported => 4,
+            FailureClass::Io => 5,
+            FailureClass::Resource => 6,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureClass::BadInput => "bad_input",
+            FailureClass::Unsupported => "unsupported",
+            FailureClass::Io => "io",
+            FailureClass::Resource => "resource",
+            FailureClass::Other => "other",
+        }
+    }
+}
+
+/// Truncated or garbled data reads as a bad input rather than a failing disk.
+fn io_class(kind: ErrorKind) -> FailureClass {
+    match kind {
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => FailureClass::BadInput,
+        _ => FailureClass::Io,
+    }
+}
+
+/// An error whose class is known where it is raised.
+#[derive(Debug)]
+pub struct Classified {
+    pub class: FailureClass,
+    message: String,
+}
+
+impl Classified {
+    pub fn new(class: FailureClass, message: impl Into<String>) -> Classified {
+        Classified {
+            class,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Classified {}
+
+/// The class of a batch in which each input in `classes` failed: theirs if they agree,
+/// and [`FailureClass::Other`] if they don't.
+pub fn batch_class(classes: &[FailureClass]) -> FailureClass {
+    match classes.split_first() {
+        Some((first, rest)) if rest.iter().all(|class| class == first) => *first,
+        _ => FailureClass::Other,
+    }
+}
+
+/// Writes `--errors-json`: an array with a record per input that failed, which is empty
+/// when none did.
+pub fn write_records(path: &Path, failures: &[(PathBuf, anyhow::Error)]) -> Result<()> {
+    let records = failures
+        .iter()
+        .map(|(input, error)| {
+            let class = FailureClass::of(error);
+            Value::object([
+                ("input", Value::from(input.display().to_string())),
+                ("class", class.name().into()),
+                ("exit_code", (class.exit_code() as u32).into()),
+                ("error", format!("{:#}", error).into()),
+                (
+                    "causes",
+                    error
+                        .chain()
+                        .map(|cause| cause.to_string())
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+            ])
+        })
+        .collect();
+    std::fs::write(path, Value::Array(records).pretty() + "\n")
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_class, Classified, FailureClass};
+    use crate::load_tif_contents;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_failure_classes() {
+        let dir = std::env::temp_dir().join(format!("failure-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let missing = load_tif_contents(&dir.join("missing.tif")).unwrap_err();
+        assert_eq!(FailureClass::of(&missing), FailureClass::Io);
+        std::fs::write(dir.join("bad.zip"), b"not a zip").unwrap();
+        let bad = load_tif_contents(&dir.join("bad.zip"))
+            .context("Could not convert")
+            .unwrap_err();
+        assert_eq!(FailureClass::of(&bad), FailureClass::BadInput);
+        // Built with the `gdal` feature, other formats are for GDAL to read.
+        #[cfg(not(feature = "gdal"))]
+        {
+            let png = load_tif_contents(std::path::Path::new("a.png")).unwrap_err();
+            assert_eq!(FailureClass::of(&png), FailureClass::Unsupported);
+        }
+        let sample = anyhow!("Unsupported sample format 3");
+        assert_eq!(FailureClass::of(&sample).exit_code(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        use FailureClass::*;
+        assert_eq!(batch_class(&[Io, Io]), Io);
+        assert_eq!(batch_class(&[Io, BadInput]), Other);
+        let summary = anyhow!(Classified::new(Io, "2 of 2 inputs failed"));
+        assert_eq!(FailureClass::of(&summary), Io);
+    }
+}
@@ -0,0 +1,126 @@
+//! A C ABI for running conversions from other languages, declared in `include/geotif.h`.
+//!
+//! Options are passed as a JSON object whose keys are the command line's long flag names,
+//! so `{"group": 0.5, "format": "fgb", "keep-zero": true}` means
+//! `--group 0.5 --format fgb --keep-zero`. Underscores may be used in place of dashes, and
+//! a list gives a repeatable option each of its values.
+
+use crate::{
+    json,
+    processor::{Options, ProcessorBuilder},
+};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Converts the tif or zip at `input_path` with the options in `options_json`, writing to
+/// `output_path`. `options_json` may be null for the defaults and `output_path` null for
+/// the path the output template gives.
+///
+/// Returns 0 on success, or -1 with the reason available from [`geotif_last_error`].
+///
+/// # Safety
+///
+/// Each non-null argument must point to a NUL terminated UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn geotif_process(
+    input_path: *const c_char,
+    options_json: *const c_char,
+    output_path: *const c_char,
+) -> c_int {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<()> {
+        let Some(input_path) = (unsafe { string(input_path) })? else {
+            bail!("No input path given");
+        };
+        let options = match unsafe { string(options_json) }? {
+            Some(text) => Options::from_json(json::parse(text)?)?,
+            None => Options::default(),
+        };
+        let processor = ProcessorBuilder::from_options(options).build()?;
+        match unsafe { string(output_path) }? {
+            Some(output_path) => processor.process_to(input_path.as_ref(), Path::new(output_path)),
+            None => processor.process(input_path.as_ref()),
+        }?;
+        Ok(())
+    }))
+    .unwrap_or_else(|_| Err(anyhow!("The conversion panicked")));
+    let error = result.err().map(|e| {
+        CString::new(format!("{:#}", e).replace('\0', " ")).expect("NUL bytes were replaced")
+    });
+    let status = if error.is_some() { -1 } else { 0 };
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+    status
+}
+
+/// The reason the calling thread's last call to [`geotif_process`] failed, or null if it
+/// succeeded. The string stays valid until the thread's next call.
+#[no_mangle]
+pub extern "C" fn geotif_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+unsafe fn string<'a>(pointer: *const c_char) -> Result<Option<&'a str>> {
+    if pointer.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(unsafe { CStr::from_ptr(pointer) }.to_str()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{geotif_last_error, geotif_process};
+    use crate::{json, processor::Options};
+    use std::{
+        ffi::{CStr, CString},
+        fs::File,
+        ptr,
+    };
+    use tiff::encoder::{colortype::GrayI32, TiffEncoder};
+
+    #[test]
+    fn test_geotif_process() {
+        let options = Options::from_json(json::parse(r#"{"group": 0.5, "keep_zero": true, "format": "fgb", "metadata-filter": ["gdal:*", "xmp:*"], "transform": ["clamp:0,1", "scale:2"]}"#).unwrap()).unwrap();
+        assert_eq!(options.group, Some(0.5.into()));
+        assert!(options.keep_zero);
+        assert_eq!(options.metadata_filter, vec!["gdal:*", "xmp:*"]);
+        assert_eq!(options.transforms.len(), 2);
+        let parse = |text| Options::from_json(json::parse(text).unwrap());
+        assert!(parse(r#"{"no-such-flag": 1}"#).is_err());
+        assert!(parse("[]").is_err());
+
+        let dir = std::env::temp_dir().join(format!("ffi-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.tif");
+        let output = dir.join("pixels.parquet");
+        TiffEncoder::new(File::create(&input).unwrap())
+            .unwrap()
+            .write_image::<GrayI32>(2, 2, &[1, 2, 3, 4])
+            .unwrap();
+        let input_c = CString::new(input.to_str().unwrap()).unwrap();
+        let output_c = CString::new(output.to_str().unwrap()).unwrap();
+        let status = unsafe { geotif_process(input_c.as_ptr(), ptr::null(), output_c.as_ptr()) };
+        assert_eq!(status, 0);
+        assert!(geotif_last_error().is_null());
+        assert!(output.exists());
+
+        let bad = CString::new(r#"{"format": "xls"}"#).unwrap();
+        let status = unsafe { geotif_process(input_c.as_ptr(), bad.as_ptr(), output_c.as_ptr()) };
+        assert_eq!(status, -1);
+        let error = unsafe { CStr::from_ptr(geotif_last_error()) };
+        assert!(error.to_str().unwrap().contains("xls"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let header = include_str!("../include/geotif.h");
+        assert!(header.contains("int geotif_process("));
+        assert!(header.contains("const char *geotif_last_error(void);"));
+    }
+}
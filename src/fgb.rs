@@ -0,0 +1,402 @@
+//! FlatGeobuf output with a packed Hilbert R-tree, written directly with the flatbuffers
+//! builder against the FlatGeobuf v3 schema.
+
+use crate::{
+    crs::Crs,
+    geometry::{self, GeometryKind, Pixel},
+    group::Binning,
+    sort::hilbert,
+    time::iso8601,
+    timing::TimedFile,
+};
+use anyhow::{bail, Result};
+use arrow_array::{
+    Array, Float32Array, Float64Array, RecordBatch, StringArray, TimestampSecondArray, UInt32Array,
+    UInt64Array, UInt8Array,
+};
+use arrow_schema::{DataType, TimeUnit};
+use flatbuffers::FlatBufferBuilder;
+use std::{
+    io::{BufWriter, Write},
+    ops::Range,
+    path::Path,
+};
+
+const MAGIC: [u8; 8] = *b"fgb\x03fgb\x00";
+
+/// Entries per R-tree node, the FlatGeobuf default.
+const NODE_SIZE: usize = 16;
+
+/// Bytes per R-tree node: four f64 bounds and a u64 offset.
+const NODE_BYTES: usize = 40;
+
+// Enum values from the FlatGeobuf schema.
+const GEOMETRY_POINT: u8 = 1;
+const GEOMETRY_POLYGON: u8 = 3;
+const COLUMN_UBYTE: u8 = 1;
+const COLUMN_UINT: u8 = 6;
+const COLUMN_ULONG: u8 = 8;
+const COLUMN_FLOAT: u8 = 9;
+const COLUMN_DOUBLE: u8 = 10;
+const COLUMN_STRING: u8 = 11;
+const COLUMN_DATETIME: u8 = 13;
+
+#[derive(Clone, Copy)]
+struct Rect {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl Rect {
+    const EMPTY: Rect = Rect {
+        min_x: f64::INFINITY,
+        min_y: f64::INFINITY,
+        max_x: f64::NEG_INFINITY,
+        max_y: f64::NEG_INFINITY,
+    };
+
+    fn expand(&mut self, other: &Rect) {
+        self.min_x = self.min_x.min(other.min_x);
+        self.min_y = self.min_y.min(other.min_y);
+        self.max_x = self.max_x.max(other.max_x);
+        self.max_y = self.max_y.max(other.max_y);
+    }
+}
+
+/// Writes every row of the batch as a feature. The geometry comes from the first two
+/// columns, the row's position in `crs`, and all columns, including those two, become
+/// properties.
+pub fn write_fgb(
+    path: &Path,
+    batch: &RecordBatch,
+    kind: GeometryKind,
+    binning: Option<&Binning>,
+    pixel: Pixel,
+    crs: Crs,
+) -> Result<()> {
+    let column = |index: usize| -> Result<&Float32Array> {
+        match batch
+            .columns()
+            .get(index)
+            .and_then(|c| c.as_any().downcast_ref::<Float32Array>())
+        {
+            Some(array) => Ok(array),
+            None => bail!("Output has no position column {}", index),
+        }
+    };
+    let (lons, lats) = (column(0)?, column(1)?);
+    let column_types = batch
+        .columns()
+        .iter()
+        .map(|c| column_type(c.data_type()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let rings: Vec<Vec<(f64, f64)>> = (0..batch.num_rows())
+        .map(|row| {
+            let (lon, lat) = (lons.value(row) as f64, lats.value(row) as f64);
+            match kind {
+                GeometryKind::Point => vec![(lon, lat)],
+                GeometryKind::Cell => geometry::footprint(lon, lat, binning, pixel),
+            }
+        })
+        .collect();
+    let rects: Vec<Rect> = rings
+        .iter()
+        .map(|ring| {
+            let mut rect = Rect::EMPTY;
+            for &(x, y) in ring {
+                rect.expand(&Rect {
+                    min_x: x,
+                    min_y: y,
+                    max_x: x,
+                    max_y: y,
+                });
+            }
+            rect
+        })
+        .collect();
+    let mut extent = Rect::EMPTY;
+    for rect in &rects {
+        extent.expand(rect);
+    }
+
+    let mut order: Vec<usize> = (0..rects.len()).collect();
+    order.sort_by_cached_key(|&i| std::cmp::Reverse(hilbert_key(&rects[i], &extent)));
+
+    let mut builder = FlatBufferBuilder::new();
+    let mut features = Vec::with_capacity(order.len());
+    let mut properties = vec![];
+    for &row in &order {
+        properties.clear();
+        for (index, array) in batch.columns().iter().enumerate() {
+            properties.extend((index as u16).to_le_bytes());
+            write_value(array.as_ref(), row, &mut properties);
+        }
+        features.push(feature(&mut builder, &rings[row], &properties));
+    }
+
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let geometry_type = match kind {
+        GeometryKind::Point => GEOMETRY_POINT,
+        GeometryKind::Cell => GEOMETRY_POLYGON,
+    };
+    let header = header(
+        &mut builder,
+        &name,
+        (!rects.is_empty()).then_some(&extent),
+        geometry_type,
+        batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .zip(column_types),
+        rects.len() as u64,
+        // 0 for CRSs given without an EPSG code, which FlatGeobuf takes as unknown.
+        crs.epsg().unwrap_or(0) as i32,
+    );
+
+    let mut writer = BufWriter::new(TimedFile::create(path)?);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&header)?;
+    if !rects.is_empty() {
+        let mut offset = 0;
+        let leaves = order.iter().zip(&features).map(|(&row, feature)| {
+            let leaf = (rects[row], offset);
+            offset += feature.len() as u64;
+            leaf
+        });
+        for (rect, offset) in packed_rtree(leaves.collect()) {
+            for v in [rect.min_x, rect.min_y, rect.max_x, rect.max_y] {
+                writer.write_all(&v.to_le_bytes())?;
+            }
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+    }
+    for feature in features {
+        writer.write_all(&feature)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A rough guess at the file size, for the free space check.
+pub fn estimate_size(batch: &RecordBatch, kind: GeometryKind) -> u64 {
+    let properties: usize = batch
+        .columns()
+        .iter()
+        .map(|c| c.get_buffer_memory_size())
+        .sum();
+    let rows = batch.num_rows() as u64;
+    let coordinates = match kind {
+        GeometryKind::Point => 16,
+        GeometryKind::Cell => 5 * 16,
+    };
+    // Flatbuffer tables, vtables and size prefixes, the u16 property column indices,
+    // and the leaves plus roughly one parent per node of the index.
+    let per_feature = 64 + 2 * batch.num_columns() as u64 + NODE_BYTES as u64 * 17 / 16;
+    properties as u64 + rows * (coordinates + per_feature) + 4096
+}
+
+fn column_type(data_type: &DataType) -> Result<u8> {
+    Ok(match data_type {
+        DataType::UInt8 => COLUMN_UBYTE,
+        DataType::UInt32 => COLUMN_UINT,
+        DataType::UInt64 => COLUMN_ULONG,
+        DataType::Float32 => COLUMN_FLOAT,
+        DataType::Float64 => COLUMN_DOUBLE,
+        DataType::Utf8 => COLUMN_STRING,
+        DataType::Timestamp(TimeUnit::Second, _) => COLUMN_DATETIME,
+        other => bail!("Cannot write {} columns to FlatGeobuf", other),
+    })
+}
+
+/// Appends a property value in FlatGeobuf's little endian encoding. The array's type
+/// must be one `column_type` accepts.
+fn write_value(array: &dyn Array, row: usize, out: &mut Vec<u8>) {
+    fn typed<T: 'static>(array: &dyn Array) -> &T {
+        array
+            .as_any()
+            .downcast_ref()
+            .expect("checked by column_type")
+    }
+    match array.data_type() {
+        DataType::UInt8 => out.push(typed::<UInt8Array>(array).value(row)),
+        DataType::UInt32 => out.extend(typed::<UInt32Array>(array).value(row).to_le_bytes()),
+        DataType::UInt64 => out.extend(typed::<UInt64Array>(array).value(row).to_le_bytes()),
+        DataType::Float32 => out.extend(typed::<Float32Array>(array).value(row).to_le_bytes()),
+        DataType::Float64 => out.extend(typed::<Float64Array>(array).value(row).to_le_bytes()),
+        DataType::Utf8 => {
+            let value = typed::<StringArray>(array).value(row);
+            out.extend((value.len() as u32).to_le_bytes());
+            out.extend(value.as_bytes());
+        }
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            let value = iso8601(typed::<TimestampSecondArray>(array).value(row));
+            out.extend((value.len() as u32).to_le_bytes());
+            out.extend(value.as_bytes());
+        }
+        _ => unreachable!("checked by column_type"),
+    }
+}
+
+/// Builds a size prefixed `Feature` table holding a single point or ring.
+fn feature(builder: &mut FlatBufferBuilder, ring: &[(f64, f64)], properties: &[u8]) -> Vec<u8> {
+    builder.reset();
+    let xy: Vec<f64> = ring.iter().flat_map(|&(x, y)| [x, y]).collect();
+    let xy = builder.create_vector(&xy);
+    let properties = builder.create_vector(properties);
+
+    let start = builder.start_table();
+    builder.push_slot_always(6, xy);
+    let geometry = builder.end_table(start);
+
+    let start = builder.start_table();
+    builder.push_slot_always(4, geometry);
+    builder.push_slot_always(6, properties);
+    let feature = builder.end_table(start);
+    builder.finish_size_prefixed(feature, None);
+    builder.finished_data().to_vec()
+}
+
+/// Builds the size prefixed `Header` table.
+fn header<'a>(
+    builder: &mut FlatBufferBuilder,
+    name: &str,
+    envelope: Option<&Rect>,
+    geometry_type: u8,
+    columns: impl Iterator<Item = (&'a str, u8)>,
+    features_count: u64,
+    epsg: i32,
+) -> Vec<u8> {
+    builder.reset();
+    let name = builder.create_string(name);
+    let envelope = envelope.map(|r| builder.create_vector(&[r.min_x, r.min_y, r.max_x, r.max_y]));
+    let columns: Vec<_> = columns
+        .map(|(column_name, column_type)| {
+            let column_name = builder.create_string(column_name);
+            let start = builder.start_table();
+            builder.push_slot_always(4, column_name);
+            builder.push_slot::<u8>(6, column_type, 0);
+            builder.push_slot::<bool>(18, false, true);
+            builder.end_table(start)
+        })
+        .collect();
+    let columns = builder.create_vector(&columns);
+    let org = builder.create_string("EPSG");
+    let start = builder.start_table();
+    builder.push_slot_always(4, org);
+    builder.push_slot::<i32>(6, epsg, 0);
+    let crs = builder.end_table(start);
+
+    let start = builder.start_table();
+    builder.push_slot_always(4, name);
+    if let Some(envelope) = envelope {
+        builder.push_slot_always(6, envelope);
+    }
+    builder.push_slot::<u8>(8, geometry_type, 0);
+    builder.push_slot_always(18, columns);
+    builder.push_slot::<u64>(20, features_count, 0);
+    let node_size = if features_count > 0 { NODE_SIZE } else { 0 };
+    builder.push_slot::<u16>(22, node_size as u16, 16);
+    builder.push_slot_always(24, crs);
+    let header = builder.end_table(start);
+    builder.finish_size_prefixed(header, None);
+    builder.finished_data().to_vec()
+}
+
+/// Ranges of node indices for each level of the tree, leaves first. The root is stored
+/// at the start of the index and the leaves at the end.
+fn level_bounds(leaf_count: usize) -> Vec<Range<usize>> {
+    let mut level_sizes = vec![leaf_count];
+    let mut n = leaf_count;
+    loop {
+        n = n.div_ceil(NODE_SIZE);
+        level_sizes.push(n);
+        if n == 1 {
+            break;
+        }
+    }
+    let mut end: usize = level_sizes.iter().sum();
+    level_sizes
+        .into_iter()
+        .map(|size| {
+            end -= size;
+            end..end + size
+        })
+        .collect()
+}
+
+/// Lays out the R-tree over leaves already in Hilbert order. Leaf offsets are byte
+/// offsets into the features, and parent offsets are the index of their first child.
+fn packed_rtree(leaves: Vec<(Rect, u64)>) -> Vec<(Rect, u64)> {
+    let levels = level_bounds(leaves.len());
+    let mut nodes = vec![(Rect::EMPTY, 0); levels[0].end];
+    nodes[levels[0].clone()].copy_from_slice(&leaves);
+    for pair in levels.windows(2) {
+        let (children, parents) = (&pair[0], &pair[1]);
+        for (parent, first) in parents.clone().zip(children.clone().step_by(NODE_SIZE)) {
+            let mut rect = Rect::EMPTY;
+            for (child, _) in &nodes[first..(first + NODE_SIZE).min(children.end)] {
+                rect.expand(child);
+            }
+            nodes[parent] = (rect, first as u64);
+        }
+    }
+    nodes
+}
+
+/// Position of the rect's center along a Hilbert curve filling the extent.
+fn hilbert_key(rect: &Rect, extent: &Rect) -> u32 {
+    let scale = |v: f64, min: f64, max: f64| {
+        if max > min {
+            (65535.0 * (v - min) / (max - min)).floor() as u32
+        } else {
+            0
+        }
+    };
+    hilbert(
+        scale((rect.min_x + rect.max_x) / 2.0, extent.min_x, extent.max_x),
+        scale((rect.min_y + rect.max_y) / 2.0, extent.min_y, extent.max_y),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{level_bounds, packed_rtree, Rect, NODE_SIZE};
+
+    #[test]
+    fn test_packed_rtree() {
+        assert_eq!(level_bounds(1), vec![1..2, 0..1]);
+        assert_eq!(level_bounds(300), vec![22..322, 3..22, 1..3, 0..1]);
+
+        let leaves: Vec<_> = (0..NODE_SIZE + 1)
+            .map(|i| {
+                let v = i as f64;
+                (
+                    Rect {
+                        min_x: v,
+                        min_y: -v,
+                        max_x: v + 1.0,
+                        max_y: -v + 1.0,
+                    },
+                    i as u64 * 100,
+                )
+            })
+            .collect();
+        let nodes = packed_rtree(leaves);
+        assert_eq!(nodes.len(), 1 + 2 + NODE_SIZE + 1);
+        let (root, first_child) = nodes[0];
+        assert_eq!(first_child, 1);
+        assert_eq!((root.min_x, root.max_x), (0.0, 17.0));
+        assert_eq!(nodes[1].1, 3);
+        assert_eq!(nodes[2].1, 3 + NODE_SIZE as u64);
+        assert_eq!(nodes[2].0.min_x, 16.0);
+        assert_eq!(nodes[3 + 16].1, 1600);
+    }
+}
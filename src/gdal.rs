@@ -0,0 +1,177 @@
+//! Reading the long tail of rasters GDAL knows and this tool doesn't, such as JPEG2000,
+//! ERDAS `.img` and MrSID, when built with the `gdal` feature. `gdal_translate`, which
+//! must be on the `PATH`, copies the band being read into a GeoTIFF of floats with any
+//! scale and offset applied, which is then stored the way NetCDF and ASCII grids are.
+
+use crate::{
+    archive, asc,
+    crs::{self, Crs},
+    failure::{
+        Classified,
+        FailureClass::{BadInput, Unsupported},
+    },
+    grid::Grid,
+    metadata::SourceMetadata,
+    netcdf, stdin,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    io::{self, Cursor},
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tiff::{
+    decoder::{Decoder, DecodingResult, Limits},
+    tags::Tag,
+};
+
+/// Whether `path` is in a format that only GDAL reads.
+pub fn is_other(path: &Path) -> bool {
+    !(stdin::is_stdin(path)
+        || archive::split(path).is_some()
+        || archive::Kind::of(path).is_some()
+        || archive::is_gzipped_tif(path)
+        || netcdf::is_netcdf(path)
+        || asc::is_asc(path)
+        || path.extension().is_some_and(|e| e == "tif"))
+}
+
+/// Turns band `band` of the raster at `path`, counting from 1, into a tif.
+pub fn to_tif(path: &Path, band: u16) -> Result<Vec<u8>> {
+    static CONVERSIONS: AtomicU64 = AtomicU64::new(0);
+    let copy = std::env::temp_dir().join(format!(
+        "image-stats-gdal-{}-{}.tif",
+        std::process::id(),
+        CONVERSIONS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let output = Command::new("gdal_translate")
+        .args(["-q", "-of", "GTiff", "-ot", "Float64", "-unscale"])
+        .args(["-b", &band.to_string()])
+        .args(["-co", "BIGTIFF=IF_SAFER"])
+        .arg(path)
+        .arg(&copy)
+        .output()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => anyhow!(Classified::new(
+                Unsupported,
+                "Reading formats other than tifs needs GDAL's gdal_translate on the PATH"
+            )),
+            _ => anyhow!(err).context("Could not run gdal_translate"),
+        })?;
+    let contents = std::fs::read(&copy);
+    let _ = std::fs::remove_file(&copy);
+    if !output.status.success() {
+        bail!(Classified::new(
+            BadInput,
+            format!(
+                "GDAL could not read {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        ));
+    }
+    from_float_tif(&contents?, band)
+}
+
+/// Stores the floats of the GeoTIFF `gdal_translate` wrote as the band `band` of a tif
+/// conversion reads, keeping its georeferencing, nodata and units.
+fn from_float_tif(contents: &[u8], band: u16) -> Result<Vec<u8>> {
+    let mut decoder = Decoder::new(Cursor::new(contents))?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let nodata = match decoder.find_tag(Tag::GdalNodata)? {
+        Some(value) => value.into_string()?.trim().parse::<f64>().ok(),
+        None => None,
+    };
+    let f64s = |decoder: &mut Decoder<_>, tag| -> Result<Option<Vec<f64>>> {
+        Ok(match decoder.find_tag(tag)? {
+            Some(value) => Some(value.into_f64_vec()?),
+            None => None,
+        })
+    };
+    let extent = match (
+        f64s(&mut decoder, Tag::ModelPixelScaleTag)?.as_deref(),
+        f64s(&mut decoder, Tag::ModelTiepointTag)?.as_deref(),
+    ) {
+        (Some([dx, dy, ..]), Some([i, j, _, x, y, ..])) => Some([x - i * dx, y + j * dy, *dx, *dy]),
+        _ => None,
+    };
+    let crs = crs::read_geokeys(&mut decoder)?.and_then(|code| Crs::from_epsg(code).ok());
+    let metadata = SourceMetadata::read(&mut decoder)?;
+    let values = match decoder.read_image()? {
+        DecodingResult::F64(values) => values,
+        DecodingResult::F32(values) => values.into_iter().map(f64::from).collect(),
+        _ => bail!("gdal_translate didn't write floats"),
+    };
+    Grid {
+        width: width as u64,
+        height: height as u64,
+        values: values
+            .into_iter()
+            .map(|v| match Some(v) == nodata {
+                true => f64::NAN,
+                false => v,
+            })
+            .collect(),
+        extent,
+        crs,
+        units: metadata.band_item("units").map(str::to_string),
+        description: metadata.band_item("description").map(str::to_string),
+        sample: band - 1,
+        ..Grid::default()
+    }
+    .to_tif()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_float_tif, is_other};
+    use crate::metadata::SourceMetadata;
+    use std::{io::Cursor, path::Path};
+    use tiff::{
+        decoder::{Decoder, DecodingResult},
+        encoder::{colortype::Gray64Float, TiffEncoder},
+        tags::Tag,
+    };
+
+    #[test]
+    fn test_from_float_tif() {
+        assert!(is_other(Path::new("scene.jp2")));
+        assert!(!is_other(Path::new("scene.tif")));
+        assert!(!is_other(Path::new("grid.asc")));
+
+        let mut tif = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut tif).unwrap();
+        let mut image = encoder.new_image::<Gray64Float>(2, 2).unwrap();
+        let directory = image.encoder();
+        directory
+            .write_tag(Tag::ModelPixelScaleTag, &[0.5, 0.5, 0.0][..])
+            .unwrap();
+        directory
+            .write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, 10.0, 50.0, 0.0][..])
+            .unwrap();
+        directory.write_tag(Tag::GdalNodata, "-9999").unwrap();
+        directory
+            .write_tag(
+                Tag::Unknown(42112),
+                "<GDALMetadata><Item name=\"units\" sample=\"0\" role=\"units\">m</Item></GDALMetadata>",
+            )
+            .unwrap();
+        image.write_data(&[1.25, -9999.0, 3.0, 4.0]).unwrap();
+
+        let stored = from_float_tif(tif.get_ref(), 2).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(&stored)).unwrap();
+        let DecodingResult::F64(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected F64 pixels");
+        };
+        let source = SourceMetadata {
+            band: 1,
+            ..SourceMetadata::read(&mut decoder).unwrap()
+        };
+        assert_eq!(pixels, [1.25, f64::MIN, 3.0, 4.0]);
+        assert_eq!(source.scale_offset(), (None, None));
+        assert_eq!(source.band_item("units"), Some("m"));
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap();
+        assert_eq!(tiepoint[3..5], [10.0, 50.0]);
+    }
+}
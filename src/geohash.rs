@@ -0,0 +1,48 @@
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Encodes a point as a geohash of `precision` characters.
+pub fn encode(lon: f64, lat: f64, precision: usize) -> String {
+    let mut lon_range = (-180.0, 180.0);
+    let mut lat_range = (-90.0, 90.0);
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let mut bits = 0;
+    let mut index = 0;
+
+    while hash.len() < precision {
+        let (range, value) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        index <<= 1;
+        if value >= mid {
+            index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(-5.6, 42.6, 5), "ezs42");
+        assert_eq!(encode(10.40744, 57.64911, 11), "u4pruydqqvj");
+    }
+}
@@ -0,0 +1,62 @@
+//! The shapes output rows stand for, for formats that carry real geometries.
+
+use crate::{georef::LonRange, group::Align, group::Binning, h3, s2, tile};
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum GeometryKind {
+    /// The row's lon/lat as a point.
+    Point,
+    /// The area the row covers: the pixel, grid cell, S2 or H3 cell, or tile.
+    Cell,
+}
+
+/// The pixels of rows that weren't grouped: their width and height, and whether a row's
+/// position is a pixel's center or its top left corner.
+#[derive(Clone, Copy)]
+pub struct Pixel {
+    pub size: (f64, f64),
+    pub registration: Align,
+}
+
+/// Returns the closed outline of the area a row covers, counter-clockwise.
+///
+/// `pixel` is only used for rows that weren't grouped.
+pub fn footprint(lon: f64, lat: f64, binning: Option<&Binning>, pixel: Pixel) -> Vec<(f64, f64)> {
+    let (width, height) = pixel.size;
+    let (west, north) = match pixel.registration {
+        Align::Corner => (lon, lat),
+        Align::Center => (lon - width / 2.0, lat + height / 2.0),
+    };
+    let (west, south, east, north) = match binning {
+        None => (west, north - height, west + width, north),
+        Some(Binning::Grid(grid)) => {
+            let (west, south) = match grid.align {
+                Align::Corner => (lon, lat),
+                Align::Center => (lon - grid.size.lon / 2.0, lat - grid.size.lat / 2.0),
+            };
+            (west, south, west + grid.size.lon, south + grid.size.lat)
+        }
+        Some(Binning::Tile { zoom, .. }) => {
+            let (x, y) = tile::tile_for(lon, lat, *zoom);
+            tile::tile_bounds(x, y, *zoom)
+        }
+        Some(Binning::S2(level)) => {
+            let mut ring = s2::cell_vertices(s2::cell_id(lon, lat, *level)).to_vec();
+            ring.push(ring[0]);
+            return ring;
+        }
+        Some(Binning::H3(resolution)) => {
+            let id = h3::cell_id(LonRange::Signed.wrap(lon), lat, *resolution);
+            let mut ring = h3::cell_vertices(id);
+            ring.push(ring[0]);
+            return ring;
+        }
+    };
+    vec![
+        (west, south),
+        (east, south),
+        (east, north),
+        (west, north),
+        (west, south),
+    ]
+}
@@ -0,0 +1,483 @@
+use crate::crs::{self, Crs};
+use anyhow::{anyhow, bail, Result};
+use std::str::FromStr;
+use tiff::{decoder::Decoder, tags::Tag};
+
+/// A lon/lat rectangle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl FromStr for BBox {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split(',')
+            .map(|p| p.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let [west, south, east, north] = parts[..] else {
+            bail!("Expected `minLon,minLat,maxLon,maxLat` but got {}", s);
+        };
+        if west > east || south > north {
+            bail!("Bounding box {} has its minimums above its maximums", s);
+        }
+        Ok(BBox {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+}
+
+impl BBox {
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        self.west <= lon && lon <= self.east && self.south <= lat && lat <= self.north
+    }
+
+    /// Whether all of `other` lies inside this box.
+    pub fn covers(&self, other: &BBox) -> bool {
+        self.west <= other.west
+            && other.east <= self.east
+            && self.south <= other.south
+            && other.north <= self.north
+    }
+
+    pub fn intersects(&self, other: &BBox) -> bool {
+        self.west <= other.east
+            && other.west <= self.east
+            && self.south <= other.north
+            && other.south <= self.north
+    }
+}
+
+/// The range longitudes are written in, for rasters and regions across the antimeridian.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LonRange {
+    #[value(name = "-180..180")]
+    Signed,
+    #[value(name = "0..360")]
+    Unsigned,
+}
+
+impl LonRange {
+    fn west(self) -> f64 {
+        match self {
+            LonRange::Signed => -180.0,
+            LonRange::Unsigned => 0.0,
+        }
+    }
+
+    /// The same longitude within the range, which includes its west end but not its east.
+    pub fn wrap(self, lon: f64) -> f64 {
+        (lon - self.west()).rem_euclid(360.0) + self.west()
+    }
+
+    /// The box covering `bounds` in the range: moved into it, or taking in every
+    /// longitude if it then runs past the east end.
+    pub fn wrap_bounds(self, bounds: BBox) -> BBox {
+        let west = self.wrap(bounds.west);
+        let east = west + (bounds.east - bounds.west);
+        match east < self.west() + 360.0 {
+            true => BBox {
+                west,
+                east,
+                ..bounds
+            },
+            false => BBox {
+                west: self.west(),
+                east: self.west() + 360.0,
+                ..bounds
+            },
+        }
+    }
+}
+
+/// A region whose pixels are converted and written before the rest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    BBox(BBox),
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("bbox", bbox)) => Ok(Priority::BBox(bbox.parse()?)),
+            _ => bail!(
+                "Expected a region like `bbox:minLon,minLat,maxLon,maxLat` but got {}",
+                s
+            ),
+        }
+    }
+}
+
+/// Maps pixel coordinates of an image to lon/lat, or to another output CRS.
+#[derive(PartialEq)]
+pub struct GeoTransform {
+    width: u32,
+    height: u32,
+    /// The image's extent in the source CRS.
+    bounds: BBox,
+    /// The source and output CRS, unless the image is assumed to be a global grid.
+    crs: Option<(Crs, Crs)>,
+}
+
+impl GeoTransform {
+    /// The layout all our inputs have so far: the whole world, clipped at 85° north and south.
+    pub fn global(width: u32, height: u32) -> GeoTransform {
+        GeoTransform {
+            width,
+            height,
+            bounds: BBox {
+                west: -180.0,
+                south: -85.0,
+                east: 180.0,
+                north: 85.0,
+            },
+            crs: None,
+        }
+    }
+
+    /// Works out where an image's pixels are.
+    ///
+    /// Without any CRS the image is taken to be [`GeoTransform::global`]. Otherwise its
+    /// extent comes from the GeoTIFF model tags, the source CRS defaults to the one in
+    /// its GeoKeys and the output CRS to EPSG:4326.
+    pub fn resolve<R: std::io::Read + std::io::Seek>(
+        decoder: &mut Decoder<R>,
+        src_crs: Option<Crs>,
+        dst_crs: Option<Crs>,
+    ) -> Result<GeoTransform> {
+        let (width, height) = decoder.dimensions()?;
+        if src_crs.is_none() && dst_crs.is_none() {
+            return Ok(GeoTransform::global(width, height));
+        }
+        let src = match src_crs {
+            Some(crs) => crs,
+            None => Crs::from_epsg(crs::read_geokeys(decoder)?.ok_or_else(|| {
+                anyhow!("The tif declares no CRS in its GeoKeys, pass --src-crs")
+            })?)?,
+        };
+        let dst = dst_crs.unwrap_or(Crs::wgs84());
+
+        let f64s = |decoder: &mut Decoder<R>, tag| -> Result<Option<Vec<f64>>> {
+            Ok(match decoder.find_tag(tag)? {
+                Some(value) => Some(value.into_f64_vec()?),
+                None => None,
+            })
+        };
+        let scale = f64s(decoder, Tag::ModelPixelScaleTag)?;
+        let tiepoint = f64s(decoder, Tag::ModelTiepointTag)?;
+        let (Some([scale_x, scale_y, ..]), Some([i, j, _, x, y, ..])) =
+            (scale.as_deref(), tiepoint.as_deref())
+        else {
+            bail!("Reprojecting needs the tif's ModelPixelScale and ModelTiepoint tags");
+        };
+        let west = x - i * scale_x;
+        let north = y + j * scale_y;
+        Ok(GeoTransform {
+            width,
+            height,
+            bounds: BBox {
+                west,
+                south: north - height as f64 * scale_y,
+                east: west + width as f64 * scale_x,
+                north,
+            },
+            crs: Some((src, dst)),
+        })
+    }
+
+    /// The transform of the raster made by combining `factor` by `factor` blocks of this
+    /// one's pixels. Partial blocks at the right and bottom edges cover whole blocks' area.
+    pub fn resampled(&self, factor: u32) -> GeoTransform {
+        let (pixel_x, pixel_y) = self.pixel_size();
+        let width = self.width.div_ceil(factor);
+        let height = self.height.div_ceil(factor);
+        GeoTransform {
+            width,
+            height,
+            bounds: BBox {
+                west: self.bounds.west,
+                south: self.bounds.north - (height * factor) as f64 * pixel_y,
+                east: self.bounds.west + (width * factor) as f64 * pixel_x,
+                north: self.bounds.north,
+            },
+            crs: self.crs,
+        }
+    }
+
+    pub fn bounds(&self) -> BBox {
+        self.bounds
+    }
+
+    /// The source and output CRS, or `None` for the assumed global grid.
+    pub fn crs(&self) -> Option<(Crs, Crs)> {
+        self.crs
+    }
+
+    fn reprojects(&self) -> bool {
+        self.crs.is_some_and(|(src, dst)| src != dst)
+    }
+
+    /// Relative area of the source pixel at an output position, for weighting pixels
+    /// against each other.
+    pub fn pixel_weight(&self, x: f64, y: f64) -> f64 {
+        match self.crs {
+            None => Crs::wgs84().relative_pixel_area(x, y),
+            Some((src, dst)) => {
+                let (lon, lat) = dst.to_lon_lat(x, y);
+                src.relative_pixel_area(lon, lat)
+            }
+        }
+    }
+
+    /// True area of pixel `(x, y)` in square kilometres.
+    pub fn pixel_area_km2(&self, x: u32, y: u32) -> f64 {
+        let (pixel_x, pixel_y) = self.pixel_size();
+        let west = self.bounds.west + x as f64 * pixel_x;
+        let north = self.bounds.north - y as f64 * pixel_y;
+        let src = self.crs.map_or(Crs::wgs84(), |(src, _)| src);
+        src.rect_area(west, north - pixel_y, west + pixel_x, north) / 1e6
+    }
+
+    /// Where `other`'s top left pixel falls on this image's pixel grid, as whole
+    /// `(column, row)` offsets. Fails unless both share a CRS and pixel size and their
+    /// pixels line up.
+    pub fn grid_offset(&self, other: &GeoTransform) -> Result<(i64, i64)> {
+        if self.crs != other.crs {
+            bail!("Images are in different CRSs");
+        }
+        let (pixel_x, pixel_y) = self.pixel_size();
+        let (other_x, other_y) = other.pixel_size();
+        let close = |a: f64, b: f64, size: f64| (a - b).abs() <= size * 1e-6;
+        if !close(pixel_x, other_x, pixel_x) || !close(pixel_y, other_y, pixel_y) {
+            bail!(
+                "Images have different pixel sizes, {}x{} and {}x{}",
+                pixel_x,
+                pixel_y,
+                other_x,
+                other_y
+            );
+        }
+        let column = (other.bounds.west - self.bounds.west) / pixel_x;
+        let row = (self.bounds.north - other.bounds.north) / pixel_y;
+        if !close(column, column.round(), 1.0) || !close(row, row.round(), 1.0) {
+            bail!("Images' pixels don't line up");
+        }
+        Ok((column.round() as i64, row.round() as i64))
+    }
+
+    /// Size of one pixel in the source CRS's units, as `(x, y)`.
+    pub fn pixel_size(&self) -> (f64, f64) {
+        (
+            (self.bounds.east - self.bounds.west) / self.width as f64,
+            (self.bounds.north - self.bounds.south) / self.height as f64,
+        )
+    }
+
+    /// Size of the pixel at the center of the image in the output CRS's units. This is
+    /// only approximate when reprojecting, since pixel sizes then vary across the image.
+    pub fn output_pixel_size(&self) -> (f64, f64) {
+        let (cx, cy) = (self.width as f64 / 2.0, self.height as f64 / 2.0);
+        let (x0, y0) = self.position(cx, cy);
+        let (x1, y1) = self.position(cx + 1.0, cy + 1.0);
+        ((x1 - x0).abs(), (y1 - y0).abs())
+    }
+
+    /// Maps a pixel coordinate to the output CRS.
+    pub fn position(&self, x: f64, y: f64) -> (f64, f64) {
+        let lon = lerp(
+            x,
+            (0.0, self.width as f64),
+            (self.bounds.west, self.bounds.east),
+        );
+        let lat = lerp(
+            y,
+            (0.0, self.height as f64),
+            (self.bounds.north, self.bounds.south),
+        );
+        match self.crs {
+            Some((src, dst)) if src != dst => {
+                let (lon, lat) = src.to_lon_lat(lon, lat);
+                dst.project(lon, lat)
+            }
+            _ => (lon, lat),
+        }
+    }
+
+    /// Maps a position in the output CRS back to a pixel coordinate, undoing
+    /// [`GeoTransform::position`].
+    pub fn pixel_at(&self, x: f64, y: f64) -> (f64, f64) {
+        let (x, y) = match self.crs {
+            Some((src, dst)) if src != dst => {
+                let (lon, lat) = dst.to_lon_lat(x, y);
+                src.project(lon, lat)
+            }
+            _ => (x, y),
+        };
+        (
+            lerp(
+                x,
+                (self.bounds.west, self.bounds.east),
+                (0.0, self.width as f64),
+            ),
+            lerp(
+                y,
+                (self.bounds.north, self.bounds.south),
+                (0.0, self.height as f64),
+            ),
+        )
+    }
+
+    /// The area covered by a rectangle of whole pixels, in the output CRS.
+    pub fn rect_bounds(&self, x: u32, y: u32, width: u32, height: u32) -> BBox {
+        // Edges curve when reprojected, so follow them rather than just the corners.
+        let steps = if self.reprojects() { 16 } else { 1 };
+        let (x, y, width, height) = (x as f64, y as f64, width as f64, height as f64);
+        let mut bounds = BBox {
+            west: f64::INFINITY,
+            south: f64::INFINITY,
+            east: f64::NEG_INFINITY,
+            north: f64::NEG_INFINITY,
+        };
+        for step in 0..=steps {
+            let t = step as f64 / steps as f64;
+            for (px, py) in [
+                (x + t * width, y),
+                (x + t * width, y + height),
+                (x, y + t * height),
+                (x + width, y + t * height),
+            ] {
+                let (lon, lat) = self.position(px, py);
+                bounds.west = bounds.west.min(lon);
+                bounds.east = bounds.east.max(lon);
+                bounds.south = bounds.south.min(lat);
+                bounds.north = bounds.north.max(lat);
+            }
+        }
+        bounds
+    }
+}
+
+pub fn lerp(v: f64, domain: (f64, f64), range: (f64, f64)) -> f64 {
+    (v - domain.0) / (domain.1 - domain.0) * (range.1 - range.0) + range.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lerp, BBox, GeoTransform, LonRange};
+    use crate::crs::Crs;
+
+    fn assert_approx(actual: f64, expected: f64) {
+        assert!(
+            (expected - actual).abs() < 0.001,
+            "{} should be approximately to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_lerp() {
+        assert_approx(lerp(0.5, (0.0, 1.0), (-10.0, 10.0)), 0.0);
+        assert_approx(lerp(0.2, (0.0, 2.0), (-3.0, 5.0)), -2.2);
+        assert_approx(lerp(0.75, (-1.0, 1.0), (-10.0, 10.0)), 7.5);
+    }
+
+    #[test]
+    fn test_lerp_reverse() {
+        assert_approx(lerp(0.1, (0.0, 1.0), (100.0, 0.0)), 90.0);
+        assert_approx(lerp(0.9, (0.0, 1.0), (100.0, 0.0)), 10.0);
+    }
+
+    #[test]
+    fn test_lon_range() {
+        assert_eq!(LonRange::Signed.wrap(190.0), -170.0);
+        assert_eq!(LonRange::Signed.wrap(180.0), -180.0);
+        assert_eq!(LonRange::Unsigned.wrap(-170.0), 190.0);
+        assert_eq!(LonRange::Unsigned.wrap(10.0), 10.0);
+        let pacific: BBox = "170,-10,190,10".parse().unwrap();
+        // Moved into the range if it fits, or else every longitude.
+        let east: BBox = "185,-10,190,10".parse().unwrap();
+        assert_eq!(LonRange::Signed.wrap_bounds(east).west, -175.0);
+        assert_eq!(LonRange::Unsigned.wrap_bounds(pacific), pacific);
+        let all = LonRange::Signed.wrap_bounds(pacific);
+        assert_eq!((all.west, all.east), (-180.0, 180.0));
+    }
+
+    #[test]
+    fn test_rect_bounds() {
+        let transform = GeoTransform::global(360, 170);
+        let bounds = transform.rect_bounds(180, 0, 10, 85);
+        assert_eq!(
+            bounds,
+            BBox {
+                west: 0.0,
+                south: 0.0,
+                east: 10.0,
+                north: 85.0
+            }
+        );
+        let clip: BBox = "5,-10,20,1".parse().unwrap();
+        assert!(clip.intersects(&bounds));
+        assert!(!clip.intersects(&transform.rect_bounds(0, 0, 10, 10)));
+    }
+
+    #[test]
+    fn test_grid_offset() {
+        let world = GeoTransform::global(360, 170);
+        let east = GeoTransform {
+            bounds: BBox {
+                west: 0.0,
+                south: -5.0,
+                east: 10.0,
+                north: 5.0,
+            },
+            ..GeoTransform::global(10, 10)
+        };
+        assert_eq!(world.grid_offset(&east).unwrap(), (180, 80));
+        assert!(world.grid_offset(&GeoTransform::global(100, 100)).is_err());
+    }
+
+    #[test]
+    fn test_resampled() {
+        let transform = GeoTransform::global(360, 170).resampled(4);
+        assert_eq!(transform.pixel_size(), (4.0, 4.0));
+        assert_eq!(transform.bounds().south, -87.0);
+        assert_eq!(transform.position(45.0, 10.0), (0.0, 45.0));
+    }
+
+    #[test]
+    fn test_reprojected_position() {
+        // A 100 km square centered on the EPSG:3035 projection origin.
+        let transform = GeoTransform {
+            width: 100,
+            height: 100,
+            bounds: BBox {
+                west: 4_271_000.0,
+                south: 3_160_000.0,
+                east: 4_371_000.0,
+                north: 3_260_000.0,
+            },
+            crs: Some((Crs::from_epsg(3035).unwrap(), Crs::wgs84())),
+        };
+        let (lon, lat) = transform.position(50.0, 50.0);
+        assert_approx(lon, 10.0);
+        assert_approx(lat, 52.0);
+        let (px, py) = transform.pixel_at(lon, lat);
+        assert_approx(px, 50.0);
+        assert_approx(py, 50.0);
+        let bounds = transform.rect_bounds(0, 0, 100, 100);
+        assert!(bounds.south < 51.6 && bounds.north > 52.4);
+        assert!(bounds.contains(lon, lat));
+        assert_approx(transform.pixel_weight(lon, lat), 1.0);
+    }
+}
@@ -0,0 +1,454 @@
+//! GeoPackage output: a single SQLite file holding one feature table, its R-tree spatial
+//! index and the metadata tables GIS tools look for.
+
+use crate::{
+    crs::Crs,
+    geometry::{self, GeometryKind, Pixel},
+    group::Binning,
+    time::iso8601,
+};
+use anyhow::{bail, Result};
+use arrow_array::{
+    Array, Float32Array, Float64Array, RecordBatch, StringArray, TimestampSecondArray, UInt32Array,
+    UInt64Array, UInt8Array,
+};
+use arrow_schema::{DataType, TimeUnit};
+use rusqlite::{params, types::Value, Connection};
+use std::path::Path;
+
+/// `GPKG` as a big endian integer, stored as the SQLite application id.
+const APPLICATION_ID: i32 = 0x4750_4B47;
+/// GeoPackage 1.2.
+const USER_VERSION: i32 = 10200;
+
+const WKB_POINT: u32 = 1;
+const WKB_POLYGON: u32 = 3;
+
+/// Writes every row of the batch as a feature of the `layer` table. The geometry comes
+/// from the first two columns, the row's position in `crs`, and all columns become
+/// attributes.
+pub fn write_gpkg(
+    path: &Path,
+    layer: &str,
+    batch: &RecordBatch,
+    kind: GeometryKind,
+    binning: Option<&Binning>,
+    pixel: Pixel,
+    crs: Crs,
+) -> Result<()> {
+    let column = |index: usize| -> Result<&Float32Array> {
+        match batch
+            .columns()
+            .get(index)
+            .and_then(|c| c.as_any().downcast_ref::<Float32Array>())
+        {
+            Some(array) => Ok(array),
+            None => bail!("Output has no position column {}", index),
+        }
+    };
+    let (lons, lats) = (column(0)?, column(1)?);
+    let column_types = batch
+        .columns()
+        .iter()
+        .map(|c| column_type(c.data_type()))
+        .collect::<Result<Vec<_>>>()?;
+
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let mut connection = Connection::open(path)?;
+    connection.pragma_update(None, "application_id", APPLICATION_ID)?;
+    connection.pragma_update(None, "user_version", USER_VERSION)?;
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(METADATA_TABLES)?;
+
+    // Readers look CRSs up by their EPSG code, so the ESRI WKT is only informative.
+    let mut insert_srs = transaction.prepare(
+        "INSERT OR IGNORE INTO gpkg_spatial_ref_sys
+         VALUES (?1, ?2, 'EPSG', ?2, ?3, NULL)",
+    )?;
+    for srs in [Crs::wgs84(), crs] {
+        if let Some(code) = srs.epsg() {
+            let wkt = srs.esri_wkt().unwrap_or("undefined");
+            insert_srs.execute(params![srs.to_string(), code, wkt])?;
+        }
+    }
+    drop(insert_srs);
+
+    let table = quote(layer);
+    let columns = batch
+        .schema()
+        .fields()
+        .iter()
+        .zip(&column_types)
+        .map(|(field, column_type)| format!("{} {} NOT NULL", quote(field.name()), column_type))
+        .collect::<Vec<_>>();
+    let geometry_type = match kind {
+        GeometryKind::Point => "POINT",
+        GeometryKind::Cell => "POLYGON",
+    };
+    transaction.execute_batch(&format!(
+        "CREATE TABLE {table} (
+             fid INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+             geom {geometry_type},
+             {columns}
+         );
+         CREATE VIRTUAL TABLE {rtree} USING rtree(id, minx, maxx, miny, maxy);",
+        columns = columns.join(",\n"),
+        rtree = quote(&rtree_name(layer)),
+    ))?;
+
+    let mut extent = [
+        f64::INFINITY,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        f64::NEG_INFINITY,
+    ];
+    {
+        let placeholders = (0..=batch.num_columns())
+            .map(|i| format!("?{}", i + 1))
+            .collect::<Vec<_>>();
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO {table} VALUES (NULL, {})",
+            placeholders.join(", ")
+        ))?;
+        let mut insert_rtree = transaction.prepare(&format!(
+            "INSERT INTO {} VALUES (?1, ?2, ?3, ?4, ?5)",
+            quote(&rtree_name(layer))
+        ))?;
+        let mut values = Vec::with_capacity(batch.num_columns() + 1);
+        for row in 0..batch.num_rows() {
+            let (lon, lat) = (lons.value(row) as f64, lats.value(row) as f64);
+            let ring = match kind {
+                GeometryKind::Point => vec![(lon, lat)],
+                GeometryKind::Cell => geometry::footprint(lon, lat, binning, pixel),
+            };
+            let mut bounds = [
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ];
+            for &(x, y) in &ring {
+                bounds = [
+                    bounds[0].min(x),
+                    bounds[1].min(y),
+                    bounds[2].max(x),
+                    bounds[3].max(y),
+                ];
+            }
+            extent = [
+                extent[0].min(bounds[0]),
+                extent[1].min(bounds[1]),
+                extent[2].max(bounds[2]),
+                extent[3].max(bounds[3]),
+            ];
+            values.clear();
+            values.push(Value::Blob(geometry_blob(&ring, bounds, srs_id(crs))));
+            values.extend(batch.columns().iter().map(|a| sql_value(a.as_ref(), row)));
+            insert.execute(rusqlite::params_from_iter(&values))?;
+            let fid = transaction.last_insert_rowid();
+            insert_rtree.execute(params![fid, bounds[0], bounds[2], bounds[1], bounds[3]])?;
+        }
+    }
+    let extent = (batch.num_rows() > 0).then_some(extent);
+
+    transaction.execute(
+        "INSERT INTO gpkg_contents (table_name, data_type, identifier, min_x, min_y, max_x,
+             max_y, srs_id)
+         VALUES (?1, 'features', ?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            layer,
+            extent.map(|e| e[0]),
+            extent.map(|e| e[1]),
+            extent.map(|e| e[2]),
+            extent.map(|e| e[3]),
+            srs_id(crs),
+        ],
+    )?;
+    transaction.execute(
+        "INSERT INTO gpkg_geometry_columns VALUES (?1, 'geom', ?2, ?3, 0, 0)",
+        params![layer, geometry_type, srs_id(crs)],
+    )?;
+    create_rtree_triggers(&transaction, layer)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// A rough guess at the file size, for the free space check.
+pub fn estimate_size(batch: &RecordBatch, kind: GeometryKind) -> u64 {
+    let attributes: usize = batch
+        .columns()
+        .iter()
+        .map(|c| c.get_buffer_memory_size())
+        .sum();
+    let geometry = match kind {
+        GeometryKind::Point => 8 + 21,
+        GeometryKind::Cell => 8 + 32 + 13 + 5 * 16,
+    };
+    // Record headers and B-tree pages for the feature table, plus the R-tree's
+    // five values per row and its own pages.
+    let per_row = 16 + batch.num_columns() as u64 + 48;
+    attributes as u64 + batch.num_rows() as u64 * (geometry + per_row) + 64 * 1024
+}
+
+/// The `srs_id` of `crs`: its EPSG code, or one of the undefined CRSs for CRSs given as
+/// PROJ strings.
+fn srs_id(crs: Crs) -> i32 {
+    match crs.epsg() {
+        Some(code) => code as i32,
+        None if crs.is_geographic() => 0,
+        None => -1,
+    }
+}
+
+/// The tables every GeoPackage has, with the undefined CRSs the spec requires.
+const METADATA_TABLES: &str = "
+    CREATE TABLE gpkg_spatial_ref_sys (
+        srs_name TEXT NOT NULL,
+        srs_id INTEGER PRIMARY KEY,
+        organization TEXT NOT NULL,
+        organization_coordsys_id INTEGER NOT NULL,
+        definition TEXT NOT NULL,
+        description TEXT
+    );
+    INSERT INTO gpkg_spatial_ref_sys VALUES
+        ('Undefined cartesian SRS', -1, 'NONE', -1, 'undefined', NULL),
+        ('Undefined geographic SRS', 0, 'NONE', 0, 'undefined', NULL);
+    CREATE TABLE gpkg_contents (
+        table_name TEXT NOT NULL PRIMARY KEY,
+        data_type TEXT NOT NULL,
+        identifier TEXT UNIQUE,
+        description TEXT DEFAULT '',
+        last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+        min_x DOUBLE,
+        min_y DOUBLE,
+        max_x DOUBLE,
+        max_y DOUBLE,
+        srs_id INTEGER,
+        CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+    );
+    CREATE TABLE gpkg_geometry_columns (
+        table_name TEXT NOT NULL,
+        column_name TEXT NOT NULL,
+        geometry_type_name TEXT NOT NULL,
+        srs_id INTEGER NOT NULL,
+        z TINYINT NOT NULL,
+        m TINYINT NOT NULL,
+        CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+        CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+        CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys (srs_id)
+    );
+    CREATE TABLE gpkg_extensions (
+        table_name TEXT,
+        column_name TEXT,
+        extension_name TEXT NOT NULL,
+        definition TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        CONSTRAINT ge_tce UNIQUE (table_name, column_name, extension_name)
+    );
+";
+
+/// Registers the `gpkg_rtree_index` extension on the layer, adding the triggers that
+/// keep its R-tree in sync if the table is edited later.
+fn create_rtree_triggers(connection: &Connection, layer: &str) -> Result<()> {
+    let table = quote(layer);
+    let rtree = quote(&rtree_name(layer));
+    let trigger = |suffix: &str| quote(&format!("rtree_{}_geom_{}", layer, suffix));
+    let bounds = "NEW.fid, ST_MinX(NEW.geom), ST_MaxX(NEW.geom), ST_MinY(NEW.geom), \
+                  ST_MaxY(NEW.geom)";
+    connection.execute_batch(&format!(
+        "CREATE TRIGGER {insert} AFTER INSERT ON {table}
+         WHEN (NEW.geom NOT NULL AND NOT ST_IsEmpty(NEW.geom))
+         BEGIN
+             INSERT OR REPLACE INTO {rtree} VALUES ({bounds});
+         END;
+         CREATE TRIGGER {update1} AFTER UPDATE OF geom ON {table}
+         WHEN OLD.fid = NEW.fid AND (NEW.geom NOTNULL AND NOT ST_IsEmpty(NEW.geom))
+         BEGIN
+             INSERT OR REPLACE INTO {rtree} VALUES ({bounds});
+         END;
+         CREATE TRIGGER {update2} AFTER UPDATE OF geom ON {table}
+         WHEN OLD.fid = NEW.fid AND (NEW.geom ISNULL OR ST_IsEmpty(NEW.geom))
+         BEGIN
+             DELETE FROM {rtree} WHERE id = OLD.fid;
+         END;
+         CREATE TRIGGER {update3} AFTER UPDATE ON {table}
+         WHEN OLD.fid != NEW.fid AND (NEW.geom NOTNULL AND NOT ST_IsEmpty(NEW.geom))
+         BEGIN
+             DELETE FROM {rtree} WHERE id = OLD.fid;
+             INSERT OR REPLACE INTO {rtree} VALUES ({bounds});
+         END;
+         CREATE TRIGGER {update4} AFTER UPDATE ON {table}
+         WHEN OLD.fid != NEW.fid AND (NEW.geom ISNULL OR ST_IsEmpty(NEW.geom))
+         BEGIN
+             DELETE FROM {rtree} WHERE id IN (OLD.fid, NEW.fid);
+         END;
+         CREATE TRIGGER {delete} AFTER DELETE ON {table}
+         WHEN OLD.geom NOT NULL
+         BEGIN
+             DELETE FROM {rtree} WHERE id = OLD.fid;
+         END;",
+        insert = trigger("insert"),
+        update1 = trigger("update1"),
+        update2 = trigger("update2"),
+        update3 = trigger("update3"),
+        update4 = trigger("update4"),
+        delete = trigger("delete"),
+    ))?;
+    connection.execute(
+        "INSERT INTO gpkg_extensions VALUES (?1, 'geom', 'gpkg_rtree_index',
+             'http://www.geopackage.org/spec120/#extension_rtree', 'write-only')",
+        [layer],
+    )?;
+    Ok(())
+}
+
+/// A GeoPackage geometry blob: the `GP` header, with an envelope for polygons, followed
+/// by little endian WKB of the point or single ring polygon. `bounds` is the ring's
+/// `[min_x, min_y, max_x, max_y]`.
+fn geometry_blob(ring: &[(f64, f64)], bounds: [f64; 4], srs_id: i32) -> Vec<u8> {
+    let mut blob = b"GP\x00".to_vec();
+    if let [(x, y)] = ring {
+        // Little endian, no envelope.
+        blob.push(0b0000_0001);
+        blob.extend(srs_id.to_le_bytes());
+        blob.push(1);
+        blob.extend(WKB_POINT.to_le_bytes());
+        blob.extend(x.to_le_bytes());
+        blob.extend(y.to_le_bytes());
+        return blob;
+    }
+    // Little endian, with a `minx, maxx, miny, maxy` envelope.
+    blob.push(0b0000_0011);
+    blob.extend(srs_id.to_le_bytes());
+    for v in [bounds[0], bounds[2], bounds[1], bounds[3]] {
+        blob.extend(v.to_le_bytes());
+    }
+    blob.push(1);
+    blob.extend(WKB_POLYGON.to_le_bytes());
+    blob.extend(1u32.to_le_bytes());
+    blob.extend((ring.len() as u32).to_le_bytes());
+    for (x, y) in ring {
+        blob.extend(x.to_le_bytes());
+        blob.extend(y.to_le_bytes());
+    }
+    blob
+}
+
+pub(crate) fn column_type(data_type: &DataType) -> Result<&'static str> {
+    Ok(match data_type {
+        DataType::UInt8 => "SMALLINT",
+        DataType::UInt32 | DataType::UInt64 => "INTEGER",
+        DataType::Float32 => "FLOAT",
+        DataType::Float64 => "DOUBLE",
+        DataType::Utf8 => "TEXT",
+        DataType::Timestamp(TimeUnit::Second, _) => "DATETIME",
+        other => bail!("Cannot write {} columns to a GeoPackage", other),
+    })
+}
+
+/// The value of a cell. The array's type must be one `column_type` accepts. SQLite
+/// integers are signed, so `u64` ids past `i64::MAX` are stored as their two's
+/// complement, the same bits parquet writes for them.
+pub(crate) fn sql_value(array: &dyn Array, row: usize) -> Value {
+    fn typed<T: 'static>(array: &dyn Array) -> &T {
+        array
+            .as_any()
+            .downcast_ref()
+            .expect("checked by column_type")
+    }
+    match array.data_type() {
+        DataType::UInt8 => Value::Integer(typed::<UInt8Array>(array).value(row) as i64),
+        DataType::UInt32 => Value::Integer(typed::<UInt32Array>(array).value(row) as i64),
+        DataType::UInt64 => Value::Integer(typed::<UInt64Array>(array).value(row) as i64),
+        DataType::Float32 => Value::Real(typed::<Float32Array>(array).value(row) as f64),
+        DataType::Float64 => Value::Real(typed::<Float64Array>(array).value(row)),
+        DataType::Utf8 => Value::Text(typed::<StringArray>(array).value(row).to_string()),
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            Value::Text(iso8601(typed::<TimestampSecondArray>(array).value(row)))
+        }
+        _ => unreachable!("checked by column_type"),
+    }
+}
+
+fn rtree_name(layer: &str) -> String {
+    format!("rtree_{}_geom", layer)
+}
+
+/// Quotes a name for use as an SQL identifier.
+pub(crate) fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_gpkg;
+    use crate::{
+        crs::Crs,
+        geometry::{GeometryKind, Pixel},
+        group::Align,
+    };
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use rusqlite::Connection;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_write_gpkg() {
+        let column = |values: Vec<f32>| Arc::new(Float32Array::from(values)) as ArrayRef;
+        let batch = RecordBatch::try_from_iter([
+            ("lon", column(vec![1.0, 3.0])),
+            ("lat", column(vec![4.0, 6.0])),
+            ("value", column(vec![7.5, 8.0])),
+        ])
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("gpkg-test-{}.gpkg", std::process::id()));
+        write_gpkg(
+            &path,
+            "ship \"density\"",
+            &batch,
+            GeometryKind::Cell,
+            None,
+            Pixel {
+                size: (0.5, 0.5),
+                registration: Align::Corner,
+            },
+            Crs::wgs84(),
+        )
+        .unwrap();
+
+        let connection = Connection::open(&path).unwrap();
+        let application_id: i32 = connection
+            .pragma_query_value(None, "application_id", |row| row.get(0))
+            .unwrap();
+        assert_eq!(application_id.to_be_bytes(), *b"GPKG");
+        let extent: (f64, f64, f64, f64) = connection
+            .query_row(
+                "SELECT min_x, min_y, max_x, max_y FROM gpkg_contents",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(extent, (1.0, 3.5, 3.5, 6.0));
+        let (geom, value): (Vec<u8>, f64) = connection
+            .query_row(
+                r#"SELECT geom, value FROM "ship ""density""" WHERE fid = 2"#,
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(value, 8.0);
+        assert_eq!(&geom[..8], b"GP\x00\x03\xe6\x10\x00\x00");
+        assert_eq!(geom.len(), 8 + 32 + 13 + 5 * 16);
+        let found: i64 = connection
+            .query_row(
+                r#"SELECT id FROM "rtree_ship ""density""_geom"
+                   WHERE minx > 2.0 AND maxy <= 6.0"#,
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(found, 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Writing grids read from other raster formats, such as NetCDF and ESRI ASCII grids, as
+//! the single band tifs the rest of the conversion reads. Their extent and CRS become
+//! GeoTIFF tags, and their units, description, scale and offset GDAL metadata items.
+
+use crate::crs::{self, Crs};
+use anyhow::Result;
+use std::io::Cursor;
+use tiff::{
+    encoder::{colortype::Gray64Float, TiffEncoder, TiffKind},
+    tags::Tag,
+};
+
+const GDAL_METADATA: Tag = Tag::Unknown(42112);
+/// The stored value of missing pixels, given as the GDAL nodata value.
+const NODATA: f64 = f64::MIN;
+
+/// A grid of values, with what is known of where they are and what they measure.
+#[derive(Default)]
+pub(crate) struct Grid {
+    pub width: u64,
+    pub height: u64,
+    /// The values row by row, from the north, with NaN for missing ones and infinities for
+    /// ones that aren't finite numbers.
+    pub values: Vec<f64>,
+    /// The west and north edges of the grid, and the width and height of its pixels.
+    pub extent: Option<[f64; 4]>,
+    pub crs: Option<Crs>,
+    pub units: Option<String>,
+    pub description: Option<String>,
+    /// What values are multiplied by, and then added to, to give what they measure.
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    /// The band the metadata items are given for, counting from 0.
+    pub sample: u16,
+}
+
+impl Grid {
+    /// Encodes the grid as a tif.
+    ///
+    /// Values are stored as 64-bit floats, as they are, with missing ones as the nodata
+    /// value and ones that aren't finite numbers as infinities.
+    pub fn to_tif(&self) -> Result<Vec<u8>> {
+        let stored: Vec<f64> = self
+            .values
+            .iter()
+            .map(|&v| if v.is_nan() { NODATA } else { v })
+            .collect();
+        let mut items = vec![];
+        for (role, value) in [
+            ("units", self.units.clone()),
+            ("description", self.description.clone()),
+            ("scale", self.scale.map(|s| s.to_string())),
+            ("offset", self.offset.map(|o| o.to_string())),
+        ] {
+            if let Some(value) = value {
+                items.push(format!(
+                    "  <Item name=\"{}\" sample=\"{}\" role=\"{}\">{}</Item>\n",
+                    role,
+                    self.sample,
+                    role,
+                    escape(&value)
+                ));
+            }
+        }
+        let metadata = format!("<GDALMetadata>\n{}</GDALMetadata>", items.concat());
+
+        let mut tif = Cursor::new(vec![]);
+        // Classic TIFF offsets are 32 bits, so larger rasters need BigTIFF.
+        if self.width * self.height * 8 < u32::MAX as u64 / 2 {
+            self.write(TiffEncoder::new(&mut tif)?, &stored, &metadata)?;
+        } else {
+            self.write(TiffEncoder::new_big(&mut tif)?, &stored, &metadata)?;
+        }
+        Ok(tif.into_inner())
+    }
+
+    fn write<K: TiffKind>(
+        &self,
+        mut encoder: TiffEncoder<&mut Cursor<Vec<u8>>, K>,
+        stored: &[f64],
+        metadata: &str,
+    ) -> Result<()> {
+        let mut image = encoder.new_image::<Gray64Float>(self.width as u32, self.height as u32)?;
+        let directory = image.encoder();
+        if let Some([west, north, dx, dy]) = self.extent {
+            directory.write_tag(Tag::ModelPixelScaleTag, &[dx, dy, 0.0][..])?;
+            directory.write_tag(
+                Tag::ModelTiepointTag,
+                &[0.0, 0.0, 0.0, west, north, 0.0][..],
+            )?;
+        }
+        if let Some(crs) = self.crs {
+            directory.write_tag(Tag::GeoKeyDirectoryTag, &crs::geokeys(crs)[..])?;
+        }
+        directory.write_tag(Tag::GdalNodata, &*format!("{:e}", NODATA))?;
+        directory.write_tag(GDAL_METADATA, metadata)?;
+        image.write_data(stored)?;
+        Ok(())
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,480 @@
+use crate::{georef::LonRange, h3, s2, tile};
+use anyhow::{anyhow, bail, Result};
+use arrow_array::{ArrayRef, StringArray, UInt32Array, UInt64Array, UInt8Array};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LonLat {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+impl FromStr for LonLat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (lon, lat) = s
+            .split_once(',')
+            .ok_or_else(|| anyhow!("Expected `lon,lat` but got {}", s))?;
+        Ok(LonLat {
+            lon: lon.trim().parse()?,
+            lat: lat.trim().parse()?,
+        })
+    }
+}
+
+/// How large grid cells are, `lon` across and `lat` up, in the output CRS's units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CellSize {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+impl From<f64> for CellSize {
+    /// Square cells `size` across.
+    fn from(size: f64) -> Self {
+        CellSize {
+            lon: size,
+            lat: size,
+        }
+    }
+}
+
+impl FromStr for CellSize {
+    type Err = anyhow::Error;
+
+    /// Parses `0.5` for square cells, or `0.1x0.05` for cells 0.1 across and 0.05 up.
+    fn from_str(s: &str) -> Result<Self> {
+        let size = match s.split_once('x') {
+            Some((lon, lat)) => CellSize {
+                lon: lon.trim().parse()?,
+                lat: lat.trim().parse()?,
+            },
+            None => CellSize::from(s.trim().parse::<f64>()?),
+        };
+        if !(size.lon > 0.0 && size.lat > 0.0 && size.lon.is_finite() && size.lat.is_finite()) {
+            bail!("Cells must have a positive size, not {}", s);
+        }
+        Ok(size)
+    }
+}
+
+impl fmt::Display for CellSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.lon == self.lat {
+            true => write!(f, "{}", self.lon),
+            false => write!(f, "{}x{}", self.lon, self.lat),
+        }
+    }
+}
+
+/// Where in a grid cell, or a pixel, its position is placed.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Align {
+    Corner,
+    Center,
+}
+
+impl Align {
+    /// How far into the cell the position is, as a fraction of its size.
+    pub fn offset(self) -> f64 {
+        match self {
+            Align::Corner => 0.0,
+            Align::Center => 0.5,
+        }
+    }
+}
+
+pub struct Grid {
+    pub size: CellSize,
+    pub origin: LonLat,
+    pub align: Align,
+    /// Whether longitudes wrap around the world, so a cell across the antimeridian is one
+    /// cell. Only grids that fit the world a whole number of times across wrap.
+    pub wraps: bool,
+}
+
+impl Grid {
+    fn cell(&self, lon: f64, lat: f64) -> (i32, i32) {
+        let lon_index = ((lon - self.origin.lon) / self.size.lon).floor() as i32;
+        let around = 360.0 / self.size.lon;
+        let lon_index = match self.wraps && (around - around.round()).abs() < 1e-9 {
+            true => lon_index.rem_euclid(around.round() as i32),
+            false => lon_index,
+        };
+        (
+            lon_index,
+            ((lat - self.origin.lat) / self.size.lat).floor() as i32,
+        )
+    }
+
+    fn position(&self, (lon_index, lat_index): (i32, i32)) -> (f64, f64) {
+        let offset = self.align.offset();
+        (
+            self.origin.lon + (lon_index as f64 + offset) * self.size.lon,
+            self.origin.lat + (lat_index as f64 + offset) * self.size.lat,
+        )
+    }
+}
+
+/// How pixels are combined into the cells they fall in.
+///
+/// `sum` and `mean` weight each pixel by its relative area, which for lon/lat rasters is
+/// the cosine of its latitude.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Aggregation {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+}
+
+pub enum Binning {
+    Grid(Grid),
+    S2(u8),
+    /// H3 cells of a resolution, identified by an `h3_cell` column.
+    H3(u8),
+    /// Web mercator tiles, identified by `z`/`x`/`y` columns or a single quadkey column.
+    Tile {
+        zoom: u8,
+        quadkey: bool,
+    },
+}
+
+/// Grouped rows, plus any columns identifying the cell each row came from.
+pub struct Binned {
+    pub rows: Vec<(f64, f64, f64)>,
+    pub columns: Vec<(&'static str, ArrayRef)>,
+}
+
+/// Groups rows into cells, weighting each by `weight` of its position.
+pub fn bin(
+    data: &[(f64, f64, f64)],
+    binning: &Binning,
+    aggregation: Aggregation,
+    weight: impl Fn(f64, f64) -> f64,
+) -> Binned {
+    let mut cells = Cells::default();
+    for &(lon, lat, value) in data {
+        cells.add(binning, lon, lat, value, weight(lon, lat));
+    }
+    cells.finish(binning, aggregation)
+}
+
+/// Rows added to the cells they fall in as they come, so grouping holds one
+/// [`Accumulator`] per cell rather than every row. Each cell is keyed by its grid column
+/// and row, S2 cell id, H3 cell index, or tile `x` and `y`, packed into a `u64`.
+#[derive(Default)]
+pub struct Cells {
+    cells: HashMap<u64, Accumulator>,
+    rows: u64,
+}
+
+impl Cells {
+    pub fn add(&mut self, binning: &Binning, lon: f64, lat: f64, value: f64, weight: f64) {
+        let key = match binning {
+            Binning::Grid(grid) => pack(grid.cell(lon, lat)),
+            Binning::S2(level) => s2::cell_id(lon, lat, *level),
+            Binning::H3(resolution) => h3::cell_id(LonRange::Signed.wrap(lon), lat, *resolution),
+            // Tiles are numbered from the antimeridian, so longitudes past it wrap.
+            Binning::Tile { zoom, .. } => {
+                let (x, y) = tile::tile_for(LonRange::Signed.wrap(lon), lat, *zoom);
+                pack((x as i32, y as i32))
+            }
+        };
+        self.cells.entry(key).or_default().add(value, weight);
+        self.rows += 1;
+    }
+
+    /// How many rows have been added.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Adds the cells of `other`, as if its rows had been added to these.
+    pub fn merge(mut self, mut other: Cells) -> Cells {
+        if self.cells.len() < other.cells.len() {
+            std::mem::swap(&mut self, &mut other);
+        }
+        for (key, acc) in other.cells {
+            self.cells.entry(key).or_default().merge(&acc);
+        }
+        self.rows += other.rows;
+        self
+    }
+
+    /// A row for each cell, positioned as `binning` places it.
+    pub fn finish(self, binning: &Binning, aggregation: Aggregation) -> Binned {
+        let cells = self.cells.into_iter();
+        match binning {
+            Binning::Grid(grid) => Binned {
+                rows: cells
+                    .map(|(key, acc)| {
+                        let (lon, lat) = grid.position(unpack(key));
+                        (lon, lat, acc.finish(aggregation))
+                    })
+                    .collect(),
+                columns: vec![],
+            },
+            Binning::S2(_) => {
+                let (ids, rows): (Vec<u64>, Vec<_>) = cells
+                    .map(|(id, acc)| {
+                        let (lon, lat) = s2::cell_center(id);
+                        (id, (lon, lat, acc.finish(aggregation)))
+                    })
+                    .unzip();
+                Binned {
+                    rows,
+                    columns: vec![("s2_cell", Arc::new(UInt64Array::from(ids)) as ArrayRef)],
+                }
+            }
+            Binning::H3(_) => {
+                let (ids, rows): (Vec<u64>, Vec<_>) = cells
+                    .map(|(id, acc)| {
+                        let (lon, lat) = h3::cell_center(id);
+                        (id, (lon, lat, acc.finish(aggregation)))
+                    })
+                    .unzip();
+                Binned {
+                    rows,
+                    columns: vec![("h3_cell", Arc::new(UInt64Array::from(ids)) as ArrayRef)],
+                }
+            }
+            Binning::Tile { zoom, quadkey } => {
+                let (tiles, rows): (Vec<(u32, u32)>, Vec<_>) = cells
+                    .map(|(key, acc)| {
+                        let (x, y) = unpack(key);
+                        let (x, y) = (x as u32, y as u32);
+                        let (lon, lat) = tile::tile_center(x, y, *zoom);
+                        ((x, y), (lon, lat, acc.finish(aggregation)))
+                    })
+                    .unzip();
+                let columns = if *quadkey {
+                    let keys = tiles.iter().map(|(x, y)| tile::quadkey(*x, *y, *zoom));
+                    vec![(
+                        "quadkey",
+                        Arc::new(StringArray::from_iter_values(keys)) as ArrayRef,
+                    )]
+                } else {
+                    vec![
+                        (
+                            "z",
+                            Arc::new(UInt8Array::from(vec![*zoom; tiles.len()])) as ArrayRef,
+                        ),
+                        (
+                            "x",
+                            Arc::new(UInt32Array::from_iter_values(tiles.iter().map(|t| t.0))),
+                        ),
+                        (
+                            "y",
+                            Arc::new(UInt32Array::from_iter_values(tiles.iter().map(|t| t.1))),
+                        ),
+                    ]
+                };
+                Binned { rows, columns }
+            }
+        }
+    }
+}
+
+fn pack((x, y): (i32, i32)) -> u64 {
+    (x as u32 as u64) << 32 | y as u32 as u64
+}
+
+fn unpack(key: u64) -> (i32, i32) {
+    ((key >> 32) as u32 as i32, key as u32 as i32)
+}
+
+/// Running statistics of the pixels in one cell or zone.
+#[derive(Clone)]
+pub struct Accumulator {
+    weighted_sum: f64,
+    weight: f64,
+    min: f64,
+    max: f64,
+    count: u64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Accumulator {
+            weighted_sum: 0.0,
+            weight: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            count: 0,
+        }
+    }
+}
+
+impl Accumulator {
+    pub fn add(&mut self, value: f64, weight: f64) {
+        self.weighted_sum += value * weight;
+        self.weight += weight;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Adds the pixels `other` has seen.
+    pub fn merge(&mut self, other: &Accumulator) {
+        self.weighted_sum += other.weighted_sum;
+        self.weight += other.weight;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+    }
+
+    pub fn finish(&self, aggregation: Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Sum => self.weighted_sum,
+            Aggregation::Mean => self.weighted_sum / self.weight,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Count => self.count as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bin, Aggregation, Align, Binning, CellSize, Cells, Grid, LonLat};
+    use crate::h3;
+    use arrow_array::{Array, UInt64Array};
+
+    fn assert_approx(actual: f64, expected: f64) {
+        assert!(
+            (expected - actual).abs() < 0.001,
+            "{} should be approximately to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_grid_center_alignment() {
+        let grid = Grid {
+            size: 0.25.into(),
+            origin: LonLat { lon: 0.0, lat: 0.0 },
+            align: Align::Center,
+            wraps: false,
+        };
+        let cell = grid.cell(0.2, -0.1);
+        assert_eq!(cell, (0, -1));
+        let (lon, lat) = grid.position(cell);
+        assert_approx(lon, 0.125);
+        assert_approx(lat, -0.125);
+    }
+
+    #[test]
+    fn test_grid_origin() {
+        let grid = Grid {
+            size: 1.0.into(),
+            origin: LonLat { lon: 0.5, lat: 0.5 },
+            align: Align::Corner,
+            wraps: false,
+        };
+        let cell = grid.cell(0.25, 1.75);
+        assert_eq!(cell, (-1, 1));
+        let (lon, lat) = grid.position(cell);
+        assert_approx(lon, -0.5);
+        assert_approx(lat, 1.5);
+    }
+
+    #[test]
+    fn test_grid_wraps() {
+        let grid = Grid {
+            size: 1.0.into(),
+            origin: LonLat { lon: 0.5, lat: 0.0 },
+            align: Align::Center,
+            wraps: true,
+        };
+        // The cell from 179.5° east to 179.5° west is one cell however it is reached.
+        assert_eq!(grid.cell(179.8, 0.0), grid.cell(-179.8, 0.0));
+        assert_eq!(grid.cell(-179.8, 0.0), grid.cell(180.2, 0.0));
+        let data = [(179.8, 0.5, 1.0), (-179.8, 0.5, 2.0), (10.0, 0.5, 4.0)];
+        let binned = bin(&data, &Binning::Grid(grid), Aggregation::Sum, |_, _| 1.0);
+        assert_eq!(binned.rows.len(), 2);
+        assert!(binned.rows.contains(&(180.0, 0.5, 3.0)));
+    }
+
+    #[test]
+    fn test_rectangular_grid() {
+        let size: CellSize = "0.1x0.05".parse().unwrap();
+        assert_eq!(size.to_string(), "0.1x0.05");
+        assert_eq!("0.5".parse::<CellSize>().unwrap().to_string(), "0.5");
+        assert!("0.1x0".parse::<CellSize>().is_err());
+        let grid = Grid {
+            size,
+            origin: LonLat { lon: 0.0, lat: 0.0 },
+            align: Align::Center,
+            wraps: false,
+        };
+        let cell = grid.cell(0.25, 0.12);
+        assert_eq!(cell, (2, 2));
+        let (lon, lat) = grid.position(cell);
+        assert_approx(lon, 0.25);
+        assert_approx(lat, 0.125);
+    }
+
+    #[test]
+    fn test_s2_aggregations() {
+        let data = [(10.0, 0.0, 2.0), (10.0001, 0.0, 4.0)];
+        let binned = bin(&data, &Binning::S2(10), Aggregation::Mean, |_, lat| {
+            lat.to_radians().cos()
+        });
+        assert_eq!(binned.rows.len(), 1);
+        assert_approx(binned.rows[0].2, 3.0);
+        assert_eq!(binned.columns[0].0, "s2_cell");
+        let binned = bin(&data, &Binning::S2(10), Aggregation::Count, |_, _| 1.0);
+        assert_approx(binned.rows[0].2, 2.0);
+    }
+
+    #[test]
+    fn test_h3_aggregations() {
+        // Rows either side of the antimeridian, and past it, share a cell.
+        let data = [
+            (179.9999, 10.0, 2.0),
+            (-179.9999, 10.0, 4.0),
+            (180.0001, 10.0, 6.0),
+        ];
+        let binned = bin(&data, &Binning::H3(4), Aggregation::Mean, |_, _| 1.0);
+        assert_eq!(binned.rows.len(), 1);
+        assert_approx(binned.rows[0].2, 4.0);
+        assert_eq!(binned.columns[0].0, "h3_cell");
+        let ids = binned.columns[0]
+            .1
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(ids.value(0), h3::cell_id(179.9999, 10.0, 4));
+    }
+
+    #[test]
+    fn test_cells_merge() {
+        let binning = Binning::Tile {
+            zoom: 2,
+            quadkey: false,
+        };
+        let (mut left, mut right) = (Cells::default(), Cells::default());
+        left.add(&binning, 10.0, 10.0, 1.0, 1.0);
+        left.add(&binning, -100.0, -60.0, 5.0, 1.0);
+        right.add(&binning, 20.0, 20.0, 3.0, 1.0);
+        let cells = left.merge(right);
+        assert_eq!(cells.rows(), 3);
+        // Split rows give the cells they would have together.
+        let binned = cells.finish(&binning, Aggregation::Max);
+        let whole = [(10.0, 10.0, 1.0), (-100.0, -60.0, 5.0), (20.0, 20.0, 3.0)];
+        let mut expected = bin(&whole, &binning, Aggregation::Max, |_, _| 1.0).rows;
+        let mut rows = binned.rows;
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(rows, expected);
+        assert_eq!(rows.len(), 2);
+    }
+}
@@ -0,0 +1,106 @@
+//! Binning points into H3 cells, by way of h3o.
+
+use h3o::{CellIndex, LatLng, Resolution};
+
+/// Returns the index of the cell at `resolution` containing the point.
+pub fn cell_id(lon: f64, lat: f64, resolution: u8) -> u64 {
+    let resolution = Resolution::try_from(resolution).expect("resolution is at most 15");
+    let point = LatLng::new(lat, lon).expect("pixel positions are finite");
+    point.to_cell(resolution).into()
+}
+
+/// Returns the center of a cell as `(lon, lat)`.
+pub fn cell_center(id: u64) -> (f64, f64) {
+    let center = LatLng::from(cell(id));
+    (center.lng(), center.lat())
+}
+
+/// Returns the corners of a cell as `(lon, lat)`, counter-clockwise: six for hexagons and
+/// five for pentagons, plus those where the cell crosses an icosahedron edge.
+///
+/// Longitudes are kept within 180° of the center, so cells on the antimeridian run past it
+/// rather than wrapping around the world.
+pub fn cell_vertices(id: u64) -> Vec<(f64, f64)> {
+    let (lon, _) = cell_center(id);
+    cell(id)
+        .boundary()
+        .iter()
+        .map(|vertex| {
+            let offset = (vertex.lng() - lon + 540.0).rem_euclid(360.0) - 180.0;
+            (lon + offset, vertex.lat())
+        })
+        .collect()
+}
+
+fn cell(id: u64) -> CellIndex {
+    CellIndex::try_from(id).expect("ids come from cell_id")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cell, cell_center, cell_id, cell_vertices};
+    use h3o::CellIndex;
+
+    #[test]
+    fn test_cell_id() {
+        assert_eq!(cell_id(-122.388903, 37.769377, 9), 0x89283082e73ffff);
+        assert_eq!(cell_id(-74.044444, 40.689167, 10), 0x8a2a1072b59ffff);
+        assert_eq!(
+            cell_id(23.03222744086644, 28.173218757257807, 3),
+            0x833e00fffffffff
+        );
+        // Coarser cells contain the finer ones, down to the base cell.
+        let fine = cell(cell_id(-122.388903, 37.769377, 15));
+        for resolution in 0..=15 {
+            let parent = fine.parent(resolution.try_into().unwrap()).unwrap();
+            assert_eq!(
+                cell_id(-122.388903, 37.769377, resolution),
+                u64::from(parent)
+            );
+        }
+        assert_eq!(cell_id(-122.388903, 37.769377, 0), 0x8029fffffffffff);
+    }
+
+    #[test]
+    fn test_cell_center_round_trip() {
+        for resolution in [0, 5, 9, 15] {
+            let id = cell_id(-122.4194, 37.7749, resolution);
+            let (lon, lat) = cell_center(id);
+            assert_eq!(cell_id(lon, lat, resolution), id);
+        }
+    }
+
+    #[test]
+    fn test_pentagons() {
+        let pentagons = CellIndex::base_cells().filter(|cell| cell.is_pentagon());
+        for base in pentagons {
+            let (lon, lat) = cell_center(base.into());
+            for resolution in [0, 2, 7, 15] {
+                let id = cell_id(lon, lat, resolution);
+                assert!(cell(id).is_pentagon());
+                // Odd resolutions are rotated against the icosahedron, so their pentagons
+                // gain a corner on each edge they cross.
+                let corners = if resolution % 2 == 0 { 5 } else { 10 };
+                assert_eq!(cell_vertices(id).len(), corners);
+            }
+        }
+    }
+
+    #[test]
+    fn test_antimeridian() {
+        // Either side of the antimeridian, and past it, land in the same cell.
+        let id = cell_id(179.9999, 10.0, 4);
+        assert_eq!(cell_id(-179.9999, 10.0, 4), id);
+        assert_eq!(cell_id(180.0001, 10.0, 4), id);
+        let (lon, _) = cell_center(id);
+        let vertices = cell_vertices(id);
+        let min_lon = vertices.iter().map(|v| v.0).fold(f64::INFINITY, f64::min);
+        let max_lon = vertices
+            .iter()
+            .map(|v| v.0)
+            .fold(f64::NEG_INFINITY, f64::max);
+        assert!(min_lon < lon && lon < max_lon);
+        assert!(max_lon - min_lon < 5.0);
+        assert!(min_lon < 180.0 && max_lon > 180.0 || min_lon < -180.0 && max_lon > -180.0);
+    }
+}
@@ -0,0 +1,498 @@
+//! Reading a tif's first image file directory and presenting the tif with a rewritten one,
+//! for images the `tiff` crate can decode once some of their tags are changed.
+//!
+//! The original bytes are kept and the new directory is placed after them, so strips,
+//! tiles, tag values and any later images such as overviews stay where they were.
+
+use crate::failure::{Classified, FailureClass::BadInput};
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub const NEW_SUBFILE_TYPE: u16 = 254;
+pub const IMAGE_WIDTH: u16 = 256;
+pub const IMAGE_LENGTH: u16 = 257;
+pub const BITS_PER_SAMPLE: u16 = 258;
+pub const COMPRESSION: u16 = 259;
+pub const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+pub const FILL_ORDER: u16 = 266;
+pub const STRIP_OFFSETS: u16 = 273;
+pub const SAMPLES_PER_PIXEL: u16 = 277;
+pub const ROWS_PER_STRIP: u16 = 278;
+pub const STRIP_BYTE_COUNTS: u16 = 279;
+pub const PLANAR_CONFIGURATION: u16 = 284;
+pub const PREDICTOR: u16 = 317;
+pub const TILE_WIDTH: u16 = 322;
+pub const TILE_LENGTH: u16 = 323;
+pub const TILE_OFFSETS: u16 = 324;
+pub const TILE_BYTE_COUNTS: u16 = 325;
+pub const EXTRA_SAMPLES: u16 = 338;
+pub const SAMPLE_FORMAT: u16 = 339;
+pub const MODEL_PIXEL_SCALE: u16 = 33550;
+pub const MODEL_TIEPOINT: u16 = 33922;
+pub const MODEL_TRANSFORMATION: u16 = 34264;
+pub const GEO_KEY_DIRECTORY: u16 = 34735;
+
+pub const SHORT: u16 = 3;
+pub const LONG: u16 = 4;
+/// Set as the bits of each `f64`.
+pub const DOUBLE: u16 = 12;
+
+/// More images than any real tif has, counting overviews and masks.
+const MAX_DIRECTORIES: usize = 1024;
+
+/// The byte order and offset size of a tif, classic or BigTIFF.
+#[derive(Clone, Copy)]
+struct Format {
+    little_endian: bool,
+    big_tiff: bool,
+}
+
+/// One tag of an image file directory, with its value or the offset to it as stored.
+pub struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    count: u64,
+    field: Vec<u8>,
+}
+
+/// What becomes of an entry in the rewritten directory.
+pub enum Change {
+    Keep,
+    Drop,
+    /// New values of the given field type.
+    Replace(u16, Vec<u64>),
+}
+
+/// An entry as placed in a rewritten directory.
+enum Written<'e> {
+    Kept(&'e Entry),
+    New(u16, Vec<u64>),
+}
+
+/// The first image file directory of a tif.
+pub struct Directory {
+    format: Format,
+    entries: Vec<Entry>,
+    next: u64,
+    /// Tags given new values by [`Directory::set`], as `(tag, type, values)`.
+    set: Vec<(u16, u16, Vec<u64>)>,
+}
+
+/// The tif with a rewritten first directory, read without copying the original bytes.
+pub struct Overlay<'a> {
+    contents: &'a [u8],
+    /// The header, pointing at the new directory.
+    header: Vec<u8>,
+    /// The new directory and its out of line values, placed at `tail_at`.
+    tail: Vec<u8>,
+    tail_at: u64,
+    position: u64,
+}
+
+fn past_the_end() -> Classified {
+    Classified::new(
+        BadInput,
+        "Image file directory runs past the end of the tif",
+    )
+}
+
+impl Format {
+    fn of(contents: &[u8]) -> Result<Format> {
+        let little_endian = match contents.get(..2) {
+            Some(b"II") => true,
+            Some(b"MM") => false,
+            _ => bail!(Classified::new(
+                BadInput,
+                "Not a tif: missing byte order mark"
+            )),
+        };
+        let mut format = Format {
+            little_endian,
+            big_tiff: false,
+        };
+        format.big_tiff = match format.read(contents, 2, 2)? {
+            42 => false,
+            43 => true,
+            other => bail!(Classified::new(
+                BadInput,
+                format!("Not a tif: unknown version {}", other)
+            )),
+        };
+        Ok(format)
+    }
+
+    fn read(&self, bytes: &[u8], at: usize, len: usize) -> Result<u64> {
+        let bytes = at
+            .checked_add(len)
+            .and_then(|end| bytes.get(at..end))
+            .ok_or_else(past_the_end)?;
+        let value = |acc: u64, &b: &u8| acc << 8 | b as u64;
+        Ok(if self.little_endian {
+            bytes.iter().rev().fold(0, value)
+        } else {
+            bytes.iter().fold(0, value)
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>, value: u64, len: usize) {
+        let bytes = &value.to_le_bytes()[..len];
+        if self.little_endian {
+            out.extend(bytes);
+        } else {
+            out.extend(bytes.iter().rev());
+        }
+    }
+
+    /// Bytes in an offset, and in an entry's value field.
+    fn offset_len(&self) -> usize {
+        if self.big_tiff {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Where the header holds the offset of the first directory.
+    fn header_offset_at(&self) -> usize {
+        if self.big_tiff {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Bytes in a directory's entry count.
+    fn count_len(&self) -> usize {
+        if self.big_tiff {
+            8
+        } else {
+            2
+        }
+    }
+}
+
+/// Bytes in one value of a TIFF field type, for the unsigned integer types.
+fn unsigned_size(kind: u16) -> Option<usize> {
+    match kind {
+        // BYTE, ASCII and UNDEFINED.
+        1 | 2 | 7 => Some(1),
+        3 => Some(2),
+        4 => Some(4),
+        16 => Some(8),
+        _ => None,
+    }
+}
+
+/// Bytes in one value of a TIFF field type that can be written.
+fn written_size(kind: u16) -> Option<usize> {
+    match kind {
+        DOUBLE => Some(8),
+        kind => unsigned_size(kind),
+    }
+}
+
+impl Directory {
+    pub fn read(contents: &[u8]) -> Result<Directory> {
+        let format = Format::of(contents)?;
+        let at = format.read(contents, format.header_offset_at(), format.offset_len())?;
+        Directory::read_at(format, contents, at as usize)
+    }
+
+    /// Every image file directory of the tif in order, the first image's followed by
+    /// those of any masks and overviews.
+    pub fn read_all(contents: &[u8]) -> Result<Vec<Directory>> {
+        let first = Directory::read(contents)?;
+        let (format, mut next) = (first.format, first.next);
+        let mut directories = vec![first];
+        while next != 0 {
+            // A directory pointing back at an earlier one would loop forever.
+            if directories.len() >= MAX_DIRECTORIES {
+                bail!(Classified::new(
+                    BadInput,
+                    format!("The tif has more than {} images", MAX_DIRECTORIES)
+                ));
+            }
+            let directory = Directory::read_at(format, contents, next as usize)?;
+            next = directory.next;
+            directories.push(directory);
+        }
+        Ok(directories)
+    }
+
+    fn read_at(format: Format, contents: &[u8], mut at: usize) -> Result<Directory> {
+        let offset_len = format.offset_len();
+        let count = format.read(contents, at, format.count_len())?;
+        at += format.count_len();
+        let mut entries = vec![];
+        for _ in 0..count {
+            let field_at = at + 4 + offset_len;
+            entries.push(Entry {
+                tag: format.read(contents, at, 2)? as u16,
+                kind: format.read(contents, at + 2, 2)? as u16,
+                count: format.read(contents, at + 4, offset_len)?,
+                field: contents
+                    .get(field_at..field_at + offset_len)
+                    .ok_or_else(past_the_end)?
+                    .to_vec(),
+            });
+            at = field_at + offset_len;
+        }
+        let next = format.read(contents, at, offset_len)?;
+        Ok(Directory {
+            format,
+            entries,
+            next,
+            set: vec![],
+        })
+    }
+
+    /// Whether the tif's bytes are in little endian (`II`) rather than big endian (`MM`)
+    /// order.
+    pub fn little_endian(&self) -> bool {
+        self.format.little_endian
+    }
+
+    /// The values of an entry of an unsigned integer type.
+    pub fn values(&self, contents: &[u8], entry: &Entry) -> Result<Vec<u64>> {
+        let Some(size) = unsigned_size(entry.kind) else {
+            bail!(
+                "Tag {} has type {}, not an unsigned integer",
+                entry.tag,
+                entry.kind
+            );
+        };
+        self.read_values(contents, entry, size)
+    }
+
+    /// The values of `tag` if the directory has it, which must be of type DOUBLE.
+    pub fn doubles(&self, contents: &[u8], tag: u16) -> Result<Option<Vec<f64>>> {
+        let Some(entry) = self.entries.iter().find(|e| e.tag == tag) else {
+            return Ok(None);
+        };
+        if entry.kind != DOUBLE {
+            bail!("Tag {} has type {}, not a double", entry.tag, entry.kind);
+        }
+        let values = self.read_values(contents, entry, 8)?;
+        Ok(Some(values.into_iter().map(f64::from_bits).collect()))
+    }
+
+    fn read_values(&self, contents: &[u8], entry: &Entry, size: usize) -> Result<Vec<u64>> {
+        let len = (entry.count as usize)
+            .checked_mul(size)
+            .context("Tag value is too large")?;
+        let (bytes, start) = if len <= entry.field.len() {
+            (&entry.field[..], 0)
+        } else {
+            let offset = self.format.read(&entry.field, 0, entry.field.len())?;
+            (contents, offset as usize)
+        };
+        (0..entry.count as usize)
+            .map(|i| self.format.read(bytes, start + i * size, size))
+            .collect()
+    }
+
+    /// The first value of `tag`, if the directory has it.
+    pub fn value(&self, contents: &[u8], tag: u16) -> Result<Option<u64>> {
+        match self.entries.iter().find(|e| e.tag == tag) {
+            Some(entry) => Ok(self.values(contents, entry)?.first().copied()),
+            None => Ok(None),
+        }
+    }
+
+    /// All the values of `tag`, if the directory has it.
+    pub fn values_of(&self, contents: &[u8], tag: u16) -> Result<Option<Vec<u64>>> {
+        match self.entries.iter().find(|e| e.tag == tag) {
+            Some(entry) => Ok(Some(self.values(contents, entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The entry of `tag`, if the directory has it.
+    pub fn entry(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+
+    /// Whether the directory has `tag`.
+    pub fn has(&self, tag: u16) -> bool {
+        self.entries.iter().any(|e| e.tag == tag)
+    }
+
+    /// Gives `tag` these values of the given field type when the directory is rewritten,
+    /// adding it if the directory doesn't have it.
+    pub fn set(&mut self, tag: u16, kind: u16, values: Vec<u64>) {
+        self.set.retain(|(t, _, _)| *t != tag);
+        self.set.push((tag, kind, values));
+    }
+
+    /// Presents `contents` with this directory as its first, each entry changed as
+    /// `change` says. Tags given values with [`Directory::set`] aren't passed to `change`.
+    pub fn rewrite<'a>(
+        &self,
+        contents: &'a [u8],
+        change: impl Fn(&Entry) -> Result<Change>,
+    ) -> Result<Overlay<'a>> {
+        let mut rewritten = vec![];
+        for entry in &self.entries {
+            if self.set.iter().any(|(tag, _, _)| *tag == entry.tag) {
+                continue;
+            }
+            match change(entry)? {
+                Change::Keep => rewritten.push((entry.tag, Written::Kept(entry))),
+                Change::Drop => {}
+                Change::Replace(kind, values) => {
+                    rewritten.push((entry.tag, Written::New(kind, values)))
+                }
+            }
+        }
+        for (tag, kind, values) in &self.set {
+            rewritten.push((*tag, Written::New(*kind, values.clone())));
+        }
+        // Readers expect a directory's tags in ascending order.
+        rewritten.sort_by_key(|(tag, _)| *tag);
+
+        let format = &self.format;
+        let offset_len = format.offset_len();
+        let tail_at = (contents.len() as u64).next_multiple_of(8);
+        let directory_len = format.count_len() + rewritten.len() * (4 + 2 * offset_len);
+        let mut extra_at = tail_at + (directory_len + offset_len) as u64;
+        let mut tail = vec![];
+        let mut extra = vec![];
+        format.write(&mut tail, rewritten.len() as u64, format.count_len());
+        for (tag, replaced) in rewritten {
+            format.write(&mut tail, tag as u64, 2);
+            match replaced {
+                Written::Kept(entry) => {
+                    format.write(&mut tail, entry.kind as u64, 2);
+                    format.write(&mut tail, entry.count, offset_len);
+                    tail.extend(&entry.field);
+                }
+                Written::New(kind, values) => {
+                    let Some(size) = written_size(kind) else {
+                        bail!("Tag {} can't be rewritten as type {}", tag, kind);
+                    };
+                    format.write(&mut tail, kind as u64, 2);
+                    format.write(&mut tail, values.len() as u64, offset_len);
+                    let mut data = vec![];
+                    for value in values {
+                        format.write(&mut data, value, size);
+                    }
+                    if data.len() <= offset_len {
+                        data.resize(offset_len, 0);
+                        tail.extend(data);
+                    } else {
+                        format.write(&mut tail, extra_at, offset_len);
+                        data.resize(data.len().next_multiple_of(8), 0);
+                        extra_at += data.len() as u64;
+                        extra.extend(data);
+                    }
+                }
+            }
+        }
+        format.write(&mut tail, self.next, offset_len);
+        tail.extend(extra);
+
+        let header_at = format.header_offset_at();
+        let mut header = contents[..header_at].to_vec();
+        format.write(&mut header, tail_at, offset_len);
+        Ok(Overlay {
+            contents,
+            header,
+            tail,
+            tail_at,
+            position: 0,
+        })
+    }
+}
+
+impl Overlay<'_> {
+    fn len(&self) -> u64 {
+        self.tail_at + self.tail.len() as u64
+    }
+
+    /// The whole rewritten tif.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = self.contents.to_vec();
+        out[..self.header.len()].copy_from_slice(&self.header);
+        out.resize(self.tail_at as usize, 0);
+        out.extend(&self.tail);
+        out
+    }
+}
+
+impl Read for Overlay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let at = self.position;
+        let (source, start): (&[u8], u64) = if at < self.header.len() as u64 {
+            (&self.header, 0)
+        } else if at < self.contents.len() as u64 {
+            (self.contents, 0)
+        } else if at < self.tail_at {
+            // Padding between the original bytes and the new directory.
+            let len = ((self.tail_at - at) as usize).min(buf.len());
+            buf[..len].fill(0);
+            self.position += len as u64;
+            return Ok(len);
+        } else {
+            (&self.tail, self.tail_at)
+        };
+        let available = source.get((at - start) as usize..).unwrap_or_default();
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for Overlay<'_> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the tif",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Directory, IMAGE_WIDTH, SHORT};
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use tiff::{
+        decoder::Decoder,
+        encoder::{colortype::Gray8, TiffEncoder},
+    };
+
+    #[test]
+    fn test_rewrite() {
+        let mut contents = Cursor::new(vec![]);
+        TiffEncoder::new(&mut contents)
+            .unwrap()
+            .write_image::<Gray8>(4, 2, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        let contents = contents.into_inner();
+        let directory = Directory::read(&contents).unwrap();
+        assert_eq!(directory.value(&contents, IMAGE_WIDTH).unwrap(), Some(4));
+
+        let mut overlay = directory
+            .rewrite(&contents, |entry| {
+                Ok(match entry.tag {
+                    IMAGE_WIDTH => Change::Replace(SHORT, vec![2]),
+                    _ => Change::Keep,
+                })
+            })
+            .unwrap();
+        let mut bytes = vec![];
+        overlay.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, overlay.to_vec());
+        overlay.seek(SeekFrom::Start(0)).unwrap();
+        let mut decoder = Decoder::new(overlay).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 2));
+    }
+}
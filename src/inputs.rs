@@ -0,0 +1,269 @@
+//! Expanding directories and glob patterns given as inputs into the files they hold, for
+//! shells that don't expand globs and directories too large to list on a command line, and
+//! reading lists of inputs from a file or stdin.
+
+use crate::metadata::key_matches;
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Replaces each directory in `paths` with the files under it, at any depth, whose
+/// extension is one of `extensions`, which may have several parts such as `tar.gz`, and
+/// each glob with the matching files of those extensions. In a glob, `*` stands for any run of characters within a path component and
+/// `**` for any number of directories. Other paths are kept as given.
+///
+/// The files of each directory or glob are in path order, and a file reached more than
+/// once is kept only the first time.
+pub fn expand(paths: &[PathBuf], extensions: &[String]) -> Result<Vec<PathBuf>> {
+    let wanted = |path: &Path| {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        extensions
+            .iter()
+            .any(|x| name.ends_with(&format!(".{}", x.to_ascii_lowercase())))
+    };
+    let mut seen = HashSet::new();
+    let mut expanded = vec![];
+    for path in paths {
+        let is_glob = path.to_string_lossy().contains('*');
+        let mut found = if is_glob {
+            glob(path)?
+        } else if path.is_dir() {
+            let mut files = vec![];
+            walk(path, &mut files)?;
+            files
+        } else {
+            if seen.insert(path.clone()) {
+                expanded.push(path.clone());
+            }
+            continue;
+        };
+        found.retain(|path| path.is_file() && wanted(path));
+        if found.is_empty() {
+            bail!(
+                "{} holds no .{} files",
+                path.display(),
+                extensions.join(" or .")
+            );
+        }
+        found.sort();
+        expanded.extend(found.into_iter().filter(|path| seen.insert(path.clone())));
+    }
+    Ok(expanded)
+}
+
+/// Returns the path of `path`, one of the files `expand` found in `roots`, relative to the
+/// directory or glob it was found in, below that directory's name or the glob's leading
+/// directories' last one, so the same file has the same key wherever the roots are
+/// mounted. Inputs given as files are kept as given.
+pub fn key(roots: &[PathBuf], path: &Path) -> PathBuf {
+    for root in roots {
+        let base: PathBuf = if root.to_string_lossy().contains('*') {
+            root.components()
+                .take_while(|c| !c.as_os_str().to_string_lossy().contains('*'))
+                .collect()
+        } else if root.is_dir() {
+            root.clone()
+        } else {
+            continue;
+        };
+        if let Ok(rest) = path.strip_prefix(&base) {
+            return match base.file_name() {
+                Some(name) => Path::new(name).join(rest),
+                None => rest.to_path_buf(),
+            };
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Reads the inputs listed one per line in `source`, or on stdin if it is `-`. Blank lines
+/// and lines starting with `#` are skipped. Paths are relative to the current directory,
+/// and `file://` URIs are taken as paths.
+pub fn read_list(source: &Path) -> Result<Vec<PathBuf>> {
+    let text = if source == Path::new("-") {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("Could not read the inputs from stdin")?;
+        text
+    } else {
+        fs::read_to_string(source)
+            .with_context(|| format!("Could not read the inputs in {}", source.display()))?
+    };
+    parse_list(&text)
+}
+
+fn parse_list(text: &str) -> Result<Vec<PathBuf>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once("://") {
+            None => Ok(PathBuf::from(line)),
+            Some(("file", path)) => Ok(PathBuf::from(path)),
+            Some(_) => bail!("Only local files can be read, not {}", line),
+        })
+        .collect()
+}
+
+/// Adds every file under `dir` to `files`. Symbolic links to directories are not followed,
+/// so links back up the tree can't loop.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Could not list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn glob(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let name = component.as_os_str().to_string_lossy();
+        if !name.contains('*') {
+            paths.iter_mut().for_each(|path| path.push(component));
+            continue;
+        }
+        let mut matched = vec![];
+        for dir in paths {
+            let listing = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &dir
+            };
+            if !listing.is_dir() {
+                continue;
+            }
+            if name == "**" {
+                matched.push(dir.clone());
+                let mut below = vec![];
+                subdirectories(listing, &mut below)?;
+                // Keep paths relative when the glob is.
+                let below = below
+                    .into_iter()
+                    .map(|path| match dir.as_os_str().is_empty() {
+                        true => path
+                            .strip_prefix(".")
+                            .map(Path::to_path_buf)
+                            .unwrap_or(path),
+                        false => path,
+                    });
+                matched.extend(below);
+                continue;
+            }
+            let patterns = [name.to_string()];
+            for entry in fs::read_dir(listing)? {
+                let file_name = entry?.file_name();
+                if key_matches(&patterns, &file_name.to_string_lossy()) {
+                    matched.push(dir.join(file_name));
+                }
+            }
+        }
+        paths = matched;
+    }
+    Ok(paths)
+}
+
+fn subdirectories(dir: &Path, dirs: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+            subdirectories(&entry.path(), dirs)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, key, parse_list};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn test_expand() {
+        let dir = std::env::temp_dir().join(format!("inputs-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("2023/06")).unwrap();
+        for name in [
+            "b.tif",
+            "a.ZIP",
+            "notes.txt",
+            "2023/c.zip",
+            "2023/06/d.tar.gz",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let extensions = ["tif".to_string(), "zip".to_string(), "tar.gz".to_string()];
+        let names = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths
+                .iter()
+                .map(|p| p.strip_prefix(&dir).unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let all = expand(std::slice::from_ref(&dir), &extensions).unwrap();
+        assert_eq!(
+            names(all),
+            ["2023/06/d.tar.gz", "2023/c.zip", "a.ZIP", "b.tif"]
+        );
+        let zips = expand(&[dir.join("**/*.zip")], &extensions).unwrap();
+        assert_eq!(names(zips), ["2023/c.zip"]);
+        // A file reached twice is kept once, and plain paths are kept as given.
+        let listed = expand(
+            &[dir.join("b.tif"), dir.join("*"), dir.join("missing.tif")],
+            &extensions,
+        )
+        .unwrap();
+        assert_eq!(names(listed), ["b.tif", "a.ZIP", "missing.tif"]);
+        assert!(expand(&[dir.join("*.txt")], &extensions).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key() {
+        let dir = std::env::temp_dir().join(format!("inputs-key-{}", std::process::id()));
+        let mounts = [dir.join("mnt/a/data"), dir.join("data")];
+        for root in &mounts {
+            fs::create_dir_all(root.join("2023")).unwrap();
+            fs::write(root.join("2023/c.tif"), b"").unwrap();
+        }
+        // The same file has the same key under either mount, from a directory or a glob.
+        for root in &mounts {
+            let file = root.join("2023/c.tif");
+            assert_eq!(
+                key(std::slice::from_ref(root), &file),
+                Path::new("data/2023/c.tif")
+            );
+            let glob = root.join("*/*.tif");
+            assert_eq!(key(&[glob], &file), Path::new("data/2023/c.tif"));
+        }
+        // Files given as such are kept as given.
+        let file = mounts[1].join("2023/c.tif");
+        assert_eq!(key(std::slice::from_ref(&file), &file), file);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let paths = parse_list("a.tif\r\n\n# skipped\n  file:///data/b.zip\n").unwrap();
+        assert_eq!(
+            paths,
+            [PathBuf::from("a.tif"), PathBuf::from("/data/b.zip")]
+        );
+        assert!(parse_list("s3://bucket/c.tif").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Describes a raster's layout and georeferencing from its tags, without decoding pixels.
+
+use crate::{
+    crs::{self, Crs},
+    explain,
+    ifd::Directory,
+    json::Value,
+    load_tif_contents,
+    metadata::SourceMetadata,
+    planar,
+    raster::Layout,
+    stats,
+};
+use anyhow::Result;
+use std::{borrow::Cow, io::Cursor, path::PathBuf};
+use tiff::{
+    decoder::{Decoder, Limits},
+    tags::Tag,
+};
+
+#[derive(clap::Args)]
+pub struct InspectArgs {
+    /// The raster to describe.
+    raster: PathBuf,
+    /// Add each band's min, max, mean and nodata fraction, estimated as `stats --quick`
+    /// does.
+    #[arg(long = "stats")]
+    stats: bool,
+    /// Print the description as JSON instead of text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+pub fn run(args: &InspectArgs) -> Result<()> {
+    let contents = load_tif_contents(&args.raster)?;
+    // The decoder can't open band separate images whole, so they are described through
+    // their first band.
+    let separate = planar::separate_bands(&contents)?;
+    let first = match separate {
+        Some(_) => Cow::Owned(planar::band_view(&contents, 0)?),
+        None => Cow::Borrowed(&contents[..]),
+    };
+    let mut decoder = Decoder::new(Cursor::new(&first[..]))?.with_limits(Limits::unlimited());
+    let mut description = describe(&mut decoder, separate)?;
+    if let Value::Object(entries) = &mut description {
+        entries.insert(
+            0,
+            (
+                "path".into(),
+                args.raster.to_string_lossy().to_string().into(),
+            ),
+        );
+        let byte_order = match Directory::read(&contents)?.little_endian() {
+            true => "little endian (II)",
+            false => "big endian (MM)",
+        };
+        entries.insert(1, ("byte_order".into(), byte_order.into()));
+        if args.stats {
+            entries.push(("bands".into(), stats::quick_band_stats(&contents)?));
+        }
+    }
+    if args.json {
+        println!("{}", description.pretty());
+    } else {
+        print!("{}", explain::to_text(&description, 0));
+    }
+    Ok(())
+}
+
+/// Collects what the tif's first image says about itself, then counts the overviews
+/// among the images after it. `separate` is the number of bands of a band separate image
+/// the decoder holds one band of.
+fn describe<R: std::io::Read + std::io::Seek>(
+    decoder: &mut Decoder<R>,
+    separate: Option<u16>,
+) -> Result<Value> {
+    let (width, height) = decoder.dimensions()?;
+    let layout = Layout::from_decoder(decoder)?;
+    let source = SourceMetadata::read(decoder)?;
+    let mut bits: Vec<u32> = decoder
+        .find_tag_unsigned_vec(Tag::BitsPerSample)?
+        .unwrap_or(vec![1]);
+    let (samples, interleave) = match separate {
+        Some(bands) => {
+            bits = vec![bits[0]; bands as usize];
+            (bands as u32, Some("band"))
+        }
+        None => {
+            let samples = decoder
+                .find_tag_unsigned::<u32>(Tag::SamplesPerPixel)?
+                .unwrap_or(1);
+            (samples, (samples > 1).then_some("pixel"))
+        }
+    };
+    // Interleaved images give one format for each sample, which the decoder requires to
+    // be the same.
+    let format = decoder
+        .find_tag_unsigned_vec::<u16>(Tag::SampleFormat)?
+        .and_then(|formats| formats.first().copied());
+    let sample_format = match format {
+        None | Some(1) => "unsigned integer".to_string(),
+        Some(2) => "signed integer".to_string(),
+        Some(3) => "floating point".to_string(),
+        Some(other) => format!("unknown ({})", other),
+    };
+    let compression = decoder
+        .find_tag_unsigned::<u16>(Tag::Compression)?
+        .map(compression_name);
+    let predictor = match decoder.find_tag_unsigned::<u16>(Tag::Predictor)? {
+        None | Some(1) => "none".to_string(),
+        Some(2) => "horizontal".to_string(),
+        Some(3) => "floating point".to_string(),
+        Some(other) => format!("unknown ({})", other),
+    };
+
+    let code = crs::read_geokeys(decoder)?;
+    let geokeys: Vec<Value> = crs::read_inline_geokeys(decoder)?
+        .into_iter()
+        .map(|(id, value)| format!("{} = {}", geokey_name(id), value).into())
+        .collect();
+    let f64s = |decoder: &mut Decoder<R>, tag| -> Result<Option<Vec<f64>>> {
+        Ok(match decoder.find_tag(tag)? {
+            Some(value) => Some(value.into_f64_vec()?),
+            None => None,
+        })
+    };
+    let pixel_scale = f64s(decoder, Tag::ModelPixelScaleTag)?;
+    let tiepoint = f64s(decoder, Tag::ModelTiepointTag)?;
+    let (scale, offset) = source.scale_offset();
+
+    let mut overviews = 0u32;
+    while decoder.more_images() {
+        decoder.next_image()?;
+        // Bit 0 marks a reduced resolution copy and bit 2 a transparency mask.
+        let kind = decoder
+            .find_tag_unsigned::<u32>(Tag::NewSubfileType)?
+            .unwrap_or(0);
+        if kind & 1 != 0 && kind & 4 == 0 {
+            overviews += 1;
+        }
+    }
+
+    Ok(Value::object([
+        ("width", Value::from(width)),
+        ("height", height.into()),
+        ("samples_per_pixel", samples.into()),
+        ("interleave", interleave.into()),
+        (
+            "bits_per_sample",
+            bits.iter().map(|&b| b as f64).collect::<Vec<_>>().into(),
+        ),
+        ("sample_format", sample_format.into()),
+        ("compression", compression.into()),
+        ("predictor", predictor.into()),
+        (
+            "layout",
+            Value::object([
+                ("chunk_type", Value::from(layout.chunk_type())),
+                ("chunk_width", layout.chunk_dimensions().0.into()),
+                ("chunk_height", layout.chunk_dimensions().1.into()),
+                ("chunks", layout.chunk_count().into()),
+            ]),
+        ),
+        (
+            "crs",
+            code.map(|code| match Crs::from_epsg(code) {
+                Ok(crs) => crs.to_string(),
+                Err(_) => format!("EPSG:{} (not supported for reprojection)", code),
+            })
+            .into(),
+        ),
+        ("geokeys", Value::Array(geokeys)),
+        ("pixel_scale", pixel_scale.into()),
+        ("tiepoint", tiepoint.into()),
+        ("nodata", source.nodata.clone().into()),
+        ("units", source.band_item("units").into()),
+        ("scale", scale.into()),
+        ("offset", offset.into()),
+        ("description", source.description.clone().into()),
+        (
+            "metadata",
+            Value::object(
+                source
+                    .items()
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::from(value))),
+            ),
+        ),
+        ("overviews", overviews.into()),
+    ]))
+}
+
+pub fn compression_name(code: u16) -> String {
+    match code {
+        1 => "none".to_string(),
+        5 => "LZW".to_string(),
+        6 | 7 => "JPEG".to_string(),
+        8 | 32946 => "deflate".to_string(),
+        32773 => "PackBits".to_string(),
+        34887 => "LERC".to_string(),
+        34925 => "LZMA".to_string(),
+        50000 => "ZSTD".to_string(),
+        50001 => "WebP".to_string(),
+        other => format!("unknown ({})", other),
+    }
+}
+
+/// Names the GeoKeys GDAL commonly writes, falling back to the numeric id.
+fn geokey_name(id: u16) -> String {
+    match id {
+        1024 => "GTModelTypeGeoKey".to_string(),
+        1025 => "GTRasterTypeGeoKey".to_string(),
+        2048 => "GeographicTypeGeoKey".to_string(),
+        2054 => "GeogAngularUnitsGeoKey".to_string(),
+        3072 => "ProjectedCSTypeGeoKey".to_string(),
+        3076 => "ProjLinearUnitsGeoKey".to_string(),
+        4096 => "VerticalCSTypeGeoKey".to_string(),
+        other => format!("GeoKey {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compression_name, geokey_name};
+
+    #[test]
+    fn test_names() {
+        assert_eq!(compression_name(8), "deflate");
+        assert_eq!(compression_name(9), "unknown (9)");
+        assert_eq!(geokey_name(3072), "ProjectedCSTypeGeoKey");
+        assert_eq!(geokey_name(1), "GeoKey 1");
+    }
+}
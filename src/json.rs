@@ -0,0 +1,284 @@
+//! A small JSON value type, enough for the reports this tool prints and the files it reads,
+//! read and written with serde_json. Objects keep their entries in order, so reports
+//! print their fields as they were built.
+
+use anyhow::Result;
+use serde::{
+    de::{MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt::{self, Display};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Builds an object from `(key, value)` pairs, keeping their order.
+    pub fn object<K: Into<String>>(entries: impl IntoIterator<Item = (K, Value)>) -> Value {
+        Value::Object(entries.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Renders the value across multiple lines with two-space indentation.
+    pub fn pretty(&self) -> String {
+        serde_json::to_string_pretty(self).expect("values always serialize")
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// Parses a complete JSON document.
+pub fn parse(text: &str) -> Result<Value> {
+    Ok(serde_json::from_str(text)?)
+}
+
+/// The largest magnitude below which every whole `f64` is exact, so it prints as an
+/// integer.
+const MAX_EXACT: f64 = (1u64 << 53) as f64;
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            // Whole numbers print without a fraction, and JSON has no NaN or infinity.
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < MAX_EXACT => {
+                serializer.serialize_i64(*n as i64)
+            }
+            Value::Number(n) if n.is_finite() => serializer.serialize_f64(*n),
+            Value::Number(_) => serializer.serialize_unit(),
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Array(items) => serializer.collect_seq(items),
+            Value::Object(entries) => {
+                serializer.collect_map(entries.iter().map(|(key, value)| (key, value)))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Bool(b))
+    }
+
+    fn visit_i64<E>(self, n: i64) -> Result<Value, E> {
+        Ok(Value::Number(n as f64))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<Value, E> {
+        Ok(Value::Number(n as f64))
+    }
+
+    fn visit_f64<E>(self, n: f64) -> Result<Value, E> {
+        Ok(Value::Number(n))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Value, E> {
+        Ok(Value::String(s.to_string()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<Value, E> {
+        Ok(Value::String(s))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = vec![];
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Value::Array(items))
+    }
+
+    // Entries are kept in the order they were written, as objects are throughout.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = vec![];
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Object(entries))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Value};
+
+    #[test]
+    fn test_display() {
+        let value = Value::object([
+            ("name", Value::from("a \"quoted\"\nline")),
+            ("sizes", Value::from(vec![1u32, 2])),
+            ("missing", Value::Null),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"quoted\"\nline","sizes":[1,2],"missing":null}"#
+        );
+        assert_eq!(Value::from(vec![0.5]).pretty(), "[\n  0.5\n]");
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let text = r#"{"a":[1,-2.5e3,true,null],"b":{"c":"x\"y\u00e9"}}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(
+            value.get("b").unwrap().get("c").unwrap().as_str(),
+            Some("x\"yé")
+        );
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+        assert!(parse("[1,]").is_err());
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        // Control characters are escaped, and escapes of every kind read back.
+        let text = Value::from("tab\tbell\u{7}\u{1f}/\\");
+        assert_eq!(text.to_string(), r#""tab\tbell\u0007\u001f/\\""#);
+        assert_eq!(parse(&text.to_string()).unwrap(), text);
+        assert_eq!(
+            parse(r#""\b\f\/\u0041""#).unwrap().as_str(),
+            Some("\u{8}\u{c}/A")
+        );
+        // Characters beyond the basic plane are read from surrogate pair escapes, which
+        // must come whole.
+        assert_eq!(parse(r#""\ud83c\udf0d""#).unwrap().as_str(), Some("🌍"));
+        assert!(parse(r#""\ud83c""#).is_err());
+        assert!(parse(r#""\ud83c\u0041""#).is_err());
+        assert!(parse(r#""\x""#).is_err());
+        assert!(parse("\"unterminated").is_err());
+
+        // Whole numbers print as integers while they are exact, and what JSON can't hold
+        // prints as null.
+        let numbers = Value::from(vec![
+            0.0,
+            -3.0,
+            0.1,
+            1e-7,
+            9007199254740991.0,
+            1e300,
+            f64::NAN,
+            f64::INFINITY,
+        ]);
+        assert_eq!(
+            numbers.to_string(),
+            "[0,-3,0.1,1e-7,9007199254740991,1e+300,null,null]"
+        );
+        for text in ["-0.5", "1E+2", "18446744073709551616", "2.5e-3"] {
+            let n = parse(text).unwrap().as_f64().unwrap();
+            assert_eq!(n, text.parse::<f64>().unwrap());
+        }
+        for text in ["01", "1.", ".5", "+1", "-", "1e", "NaN", "Infinity"] {
+            assert!(parse(text).is_err(), "{}", text);
+        }
+
+        // Objects keep their order, duplicates and all, and nesting is bounded.
+        let object = parse(r#"{"b":1,"a":2,"b":3}"#).unwrap();
+        assert_eq!(object.to_string(), r#"{"b":1,"a":2,"b":3}"#);
+        assert_eq!(Value::object::<&str>([]).pretty(), "{}");
+        assert!(parse(&"[".repeat(100_000)).is_err());
+    }
+}
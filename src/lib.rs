@@ -0,0 +1,158 @@
+//! Converting GeoTIFF pixels to tables of positions and values, and the tools around it.
+//!
+//! [`processor::Processor`] runs the conversion; the other public modules back the
+//! `image-stats` subcommands.
+
+pub mod align;
+pub mod append;
+pub mod archive;
+pub mod asc;
+pub mod categorical;
+pub mod checksum;
+mod codec;
+pub mod columns;
+pub mod compare;
+pub mod config;
+pub mod contour;
+pub mod contract;
+pub mod coordinate;
+pub mod crs;
+pub mod delta;
+pub mod diff;
+pub mod distance;
+pub mod doctor;
+pub mod explain;
+pub mod expr;
+pub mod failure;
+pub mod ffi;
+mod fgb;
+#[cfg(feature = "gdal")]
+pub mod gdal;
+mod geohash;
+pub mod geometry;
+pub mod georef;
+mod gpkg;
+mod grid;
+pub mod group;
+mod h3;
+mod ifd;
+pub mod inputs;
+pub mod inspect;
+mod json;
+pub mod logging;
+pub mod manifest;
+mod mask;
+pub mod memory;
+mod metadata;
+pub mod mmap;
+pub mod mosaic;
+mod mvt;
+pub mod netcdf;
+pub mod notify;
+pub mod numa;
+pub mod order;
+pub mod output;
+mod overview;
+mod packed;
+mod planar;
+pub mod plugin;
+pub mod postgis;
+pub mod priority;
+pub mod processor;
+pub mod pyramid;
+pub mod query;
+pub mod raster;
+pub mod rasterize;
+pub mod reclass;
+pub mod release;
+pub mod render;
+pub mod resample;
+pub mod roundtrip;
+mod s2;
+mod sample;
+pub mod sandbox;
+pub mod schedule;
+pub mod serve;
+mod shp;
+mod sidecar;
+pub mod sort;
+pub mod state;
+pub mod stats;
+pub mod stdin;
+pub mod strata;
+mod style;
+pub mod synth;
+mod tar;
+pub mod template;
+mod thin;
+mod tile;
+pub mod time;
+pub mod timing;
+pub mod transform;
+pub mod upload;
+pub mod validate;
+pub mod watch;
+pub mod watchdog;
+pub mod zones;
+
+use anyhow::{bail, Result};
+use failure::{Classified, FailureClass::Unsupported};
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read},
+    path::Path,
+};
+
+pub const DEFAULT_CHUNK_ROWS: u32 = 1024;
+
+/// Reads a `.tif`, a `.tif.gz`, or the single tif inside a `.zip`, `.tar` or `.tar.gz`,
+/// into memory, or the tif made from the single grid of a `.nc` or an `.asc`. The path
+/// `-` reads any of them but grids from stdin, and a path through an archive, such as
+/// `data.zip/qa.tif`, that member. A plain tif is placed by any world file or `.prj`
+/// beside it. Built with the `gdal` feature, any other raster GDAL reads is converted to
+/// a tif.
+pub fn load_tif_contents(path: &Path) -> Result<Vec<u8>> {
+    if stdin::is_stdin(path) {
+        let contents = stdin::contents()?;
+        return match archive::Kind::sniff(contents) {
+            Some(kind) => Ok(archive::read(kind, Cursor::new(contents), None)?.unwrap_or_default()),
+            None if archive::is_gzip(contents) => archive::gunzip(contents),
+            None => Ok(contents.to_vec()),
+        };
+    }
+    if let Some((archive, member)) = archive::split(path) {
+        return archive::read_member(archive, &member);
+    }
+    if let Some(kind) = archive::Kind::of(path) {
+        let file = BufReader::new(File::open(path)?);
+        return Ok(archive::read(kind, file, None)?.unwrap_or_default());
+    }
+    if netcdf::is_netcdf(path) {
+        return netcdf::to_tif(&std::fs::read(path)?, None, 1);
+    }
+    if asc::is_asc(path) {
+        return asc::read(path);
+    }
+    if archive::is_gzipped_tif(path) {
+        return archive::gunzip(BufReader::new(File::open(path)?));
+    }
+    #[cfg(feature = "gdal")]
+    if gdal::is_other(path) {
+        return gdal::to_tif(path, 1);
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("tif") => {
+            let mut tif_contents: Vec<u8> = vec![];
+            File::open(path)?.read_to_end(&mut tif_contents)?;
+            sidecar::apply(path, tif_contents)
+        }
+        Some(ext) => bail!(Classified::new(
+            Unsupported,
+            format!("Unexpected file extension {}", ext)
+        )),
+        None => bail!(Classified::new(
+            Unsupported,
+            format!("No file extension on {}", path.to_string_lossy())
+        )),
+    }
+}
@@ -0,0 +1,203 @@
+//! Reporting each input's start and finish as `tracing` events, written as text beside the
+//! progress bars or as one JSON object per line for log aggregators, with `--log-format`,
+//! `--quiet`, `--verbose` and `--no-progress`.
+
+use crate::notify::Outcome;
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use std::{io, path::Path, time::Duration};
+use tracing::{level_filters::LevelFilter, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Progress bars, with a line per event under `--verbose`.
+    Text,
+    /// No progress bars, and a JSON object per event on stderr.
+    Json,
+}
+
+/// How much is logged.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Only failures, and no progress bars.
+    Quiet,
+    Normal,
+    /// Also when each input starts.
+    Verbose,
+}
+
+/// Installs the subscriber the events are written through, and returns the progress bars,
+/// drawn when `progress` is set unless quiet or logging JSON. Hidden bars draw nothing.
+pub fn init(format: LogFormat, verbosity: Verbosity, progress: bool) -> MultiProgress {
+    let bars = match (format, verbosity) {
+        (LogFormat::Text, Verbosity::Normal | Verbosity::Verbose) if progress => {
+            MultiProgress::new()
+        }
+        _ => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+    };
+    let subscriber = subscriber(format, verbosity, AboveBars(bars.clone()));
+    // Only the first call sets it; later ones, such as from tests, keep that one.
+    let _ = tracing::subscriber::set_global_default(subscriber);
+    bars
+}
+
+fn subscriber<W>(
+    format: LogFormat,
+    verbosity: Verbosity,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let level = match (format, verbosity) {
+        (_, Verbosity::Verbose) => LevelFilter::DEBUG,
+        // Text failures are reported once the batch stops, so they are only repeated as
+        // they happen when asked.
+        (LogFormat::Text, _) => LevelFilter::OFF,
+        (LogFormat::Json, Verbosity::Normal) => LevelFilter::INFO,
+        (LogFormat::Json, Verbosity::Quiet) => LevelFilter::ERROR,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false)
+        .with_ansi(false)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+pub fn started(input: &Path) {
+    tracing::debug!(event = "start", input = %input.display());
+}
+
+pub fn finished(input: &Path, outcome: &Outcome, elapsed: Duration) {
+    let input = input.display();
+    let elapsed_seconds = elapsed.as_secs_f64();
+    match outcome {
+        Outcome::Written { output, rows } => tracing::info!(
+            event = "finish",
+            %input,
+            elapsed_seconds,
+            status = "written",
+            output = %output.display(),
+            rows = *rows as u64
+        ),
+        Outcome::Failed(error) => {
+            tracing::error!(event = "error", %input, elapsed_seconds, error = error.as_str())
+        }
+        Outcome::Skipped => {
+            tracing::info!(event = "finish", %input, elapsed_seconds, status = "skipped")
+        }
+        Outcome::Taken => {
+            tracing::info!(event = "finish", %input, elapsed_seconds, status = "taken")
+        }
+        Outcome::UpToDate { output } => tracing::info!(
+            event = "finish",
+            %input,
+            elapsed_seconds,
+            status = "up_to_date",
+            output = %output.display()
+        ),
+    }
+}
+
+/// Writes each event to stderr, above the progress bars when they are drawn so they
+/// aren't torn.
+struct AboveBars(MultiProgress);
+
+impl<'a> MakeWriter<'a> for AboveBars {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Line {
+        Line(self.0.clone(), vec![])
+    }
+}
+
+/// An event as it is formatted, printed whole once it is dropped.
+struct Line(MultiProgress, Vec<u8>);
+
+impl io::Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.1);
+        let line = line.trim_end_matches('\n');
+        if self.0.is_hidden() {
+            eprintln!("{}", line);
+        } else {
+            let _ = self.0.println(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{finished, started, subscriber, LogFormat, Verbosity};
+    use crate::{json, notify::Outcome};
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_event_lines() {
+        let logged = |format: LogFormat, verbosity: Verbosity| {
+            let lines = Arc::new(Mutex::new(vec![]));
+            let writer = {
+                let lines = lines.clone();
+                move || Writer(lines.clone())
+            };
+            tracing::subscriber::with_default(subscriber(format, verbosity, writer), || {
+                started(Path::new("a b.zip"));
+                let failure = Outcome::Failed("invalid Zip archive".into());
+                finished(Path::new("a b.zip"), &failure, Duration::from_millis(1500));
+            });
+            let lines = String::from_utf8(lines.lock().unwrap().clone()).unwrap();
+            lines.lines().map(String::from).collect::<Vec<_>>()
+        };
+
+        let lines = logged(LogFormat::Json, Verbosity::Normal);
+        assert_eq!(lines.len(), 1);
+        let parsed = json::parse(&lines[0]).unwrap();
+        assert_eq!(parsed.get("event").and_then(|e| e.as_str()), Some("error"));
+        assert_eq!(
+            parsed.get("input").and_then(|e| e.as_str()),
+            Some("a b.zip")
+        );
+        assert_eq!(
+            parsed.get("elapsed_seconds").and_then(|e| e.as_f64()),
+            Some(1.5)
+        );
+        assert_eq!(logged(LogFormat::Json, Verbosity::Verbose).len(), 2);
+        assert!(logged(LogFormat::Text, Verbosity::Normal).is_empty());
+        let lines = logged(LogFormat::Text, Verbosity::Verbose);
+        assert!(lines[1].ends_with(
+            "event=\"error\" input=a b.zip elapsed_seconds=1.5 error=\"invalid Zip archive\""
+        ));
+    }
+
+    struct Writer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -1,173 +1,694 @@
-#[allow(unused_imports)]
 use anyhow::{anyhow, bail, Result};
-use arrow_array::{ArrayRef, Float32Array, RecordBatch};
-use clap::Parser;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle, ProgressIterator};
-use parquet::arrow::ArrowWriter;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use image_stats::{
+    archive, compare, config, contour, coordinate, diff, doctor,
+    explain::{self, ExplainFormat},
+    failure::{self, Classified, FailureClass},
+    inputs, inspect,
+    logging::{self, LogFormat, Verbosity},
+    memory, mosaic,
+    notify::{self, OnComplete, Outcome},
+    numa::{self, NumaPolicy},
+    order,
+    output::Target,
+    plugin, priority,
+    processor::{Options, Processor, ProcessorBuilder},
+    pyramid, query, rasterize, release, render, roundtrip,
+    sandbox::{self, Limits},
+    schedule, serve,
+    state::{self, State},
+    stats, stdin, synth, timing, upload, validate, watch,
+    watchdog::{self, Watchdog},
+    zones,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use semver::VersionReq;
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{Cursor, Read},
+    io::IsTerminal,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use tiff::decoder::{DecodingResult, Limits};
-use zip::ZipArchive;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Tifs or zips to convert, directories or globs of them, or `-` for one piped on stdin.
     input_path: Vec<PathBuf>,
-    #[arg(long = "group")]
-    group: Option<f64>,
+    /// The inputs as given, before directories and globs were expanded.
+    #[arg(skip)]
+    input_roots: Vec<PathBuf>,
+    #[command(flatten)]
+    options: Options,
+    /// Also read inputs from this file, one path per line, or from stdin if it is `-`, for
+    /// lists too long for the command line.
+    #[arg(long = "files-from", value_name = "FILE")]
+    files_from: Option<PathBuf>,
+    /// Extensions of the files taken from inputs that are directories, which are searched
+    /// at any depth, or globs such as `data/**/*.zip`.
+    #[arg(
+        long = "extensions",
+        value_delimiter = ',',
+        default_value = "tif,tif.gz,zip,tar,tar.gz,tgz,7z,nc,asc"
+    )]
+    extensions: Vec<String>,
+    /// Convert the tif inside each archive input whose name matches this glob, where `*` stands
+    /// for any run of characters, for zips that hold more than one. Can be repeated.
+    #[arg(long = "archive-member", value_name = "GLOB")]
+    archive_member: Vec<String>,
+    /// Convert each tif inside archive inputs, or each matching --archive-member, to an
+    /// output of its own named `<archive>.<tif>`.
+    #[arg(long = "all-members")]
+    all_members: bool,
+    /// Read options from this TOML file of long flag names to values, with `inputs` as a
+    /// list of globs. Without it, `geotif.toml` is read from the current directory if it
+    /// exists. Flags and inputs given on the command line override the file's.
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Fail before doing anything unless this is a release meeting this requirement, such
+    /// as `>=0.5` or `>=0.5, <1`, so pipelines can pin the versions they produce data with.
+    /// With a subcommand, it goes after the subcommand's name.
+    #[arg(long = "require-version", value_name = "REQ", global = true)]
+    require_version: Option<VersionReq>,
+    /// Carry on with the remaining inputs when one fails, then list every input that
+    /// failed and why, exiting with an error if any did.
+    #[arg(long = "keep-going")]
+    keep_going: bool,
+    /// Stop the batch once it has run this long, such as `30m` or `2h`: the inputs being
+    /// converted are stopped and those not yet started fail.
+    #[arg(
+        long = "timeout",
+        value_name = "DURATION",
+        value_parser = watchdog::parse_duration,
+        conflicts_with = "watch"
+    )]
+    timeout: Option<Duration>,
+    /// Fail an input when one stage of its conversion, such as decoding its pixels or
+    /// writing its output, runs longer than this, such as `90s` or `10m`. With
+    /// `--keep-going`, the batch moves on to the next input. A decode stuck inside one
+    /// strip or tile is left to finish in the background, and writes nothing.
+    #[arg(
+        long = "stage-timeout",
+        value_name = "DURATION",
+        value_parser = watchdog::parse_duration
+    )]
+    stage_timeout: Option<Duration>,
+    /// Write a JSON array to this file with a record per input that failed: its path, the
+    /// class of failure and its exit code, and the error with each of its causes. The
+    /// process exits with 3 for a bad input file, 4 for an unsupported format, 5 for an
+    /// I/O error, 6 for a conversion stopped by a memory, CPU or time limit and 1 for
+    /// anything else or a mix of these.
+    #[arg(long = "errors-json", value_name = "PATH")]
+    errors_json: Option<PathBuf>,
+    /// How progress is reported: progress bars, or a JSON object on stderr per input
+    /// started, finished or failed, for log aggregators.
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Show no progress bars and log only failures.
+    #[arg(long = "quiet", short = 'q', conflicts_with = "verbose")]
+    quiet: bool,
+    /// Show no progress bars, keeping logs such as those of CI and cron jobs readable.
+    /// They are left out anyway when stderr isn't a terminal.
+    #[arg(long = "no-progress")]
+    no_progress: bool,
+    /// Also log when each input starts, and with text logs, a line for each input as it
+    /// finishes or fails.
+    #[arg(long = "verbose", short = 'v')]
+    verbose: bool,
+    /// Place the transform workers across NUMA nodes (Linux only).
+    #[arg(long = "numa", value_enum)]
+    numa: Option<NumaPolicy>,
+    /// Run at idle CPU and IO priority so the conversion yields to interactive work.
+    #[arg(long = "nice")]
+    nice: bool,
+    /// Convert each input in a child process that can't use the network, run programs or
+    /// write outside the output's directory, held to `--sandbox-memory`, `--sandbox-cpu`
+    /// and `--sandbox-timeout`, so a malicious input only fails itself (Linux only).
+    #[arg(long = "sandbox")]
+    sandbox: bool,
+    /// Memory a sandboxed conversion may allocate, such as `512M` or `4G`. Unless
+    /// `--max-memory` is given, the conversion is kept to half of it.
+    #[arg(
+        long = "sandbox-memory",
+        value_name = "BYTES",
+        default_value = "4G",
+        value_parser = memory::parse_bytes,
+        requires = "sandbox"
+    )]
+    sandbox_memory: u64,
+    /// Seconds of CPU time a sandboxed conversion may use, summed across its threads.
+    #[arg(long = "sandbox-cpu", value_name = "SECONDS", requires = "sandbox")]
+    sandbox_cpu: Option<u64>,
+    /// Seconds a sandboxed conversion may run before it is killed.
+    #[arg(
+        long = "sandbox-timeout",
+        value_name = "SECONDS",
+        default_value_t = 600.0,
+        requires = "sandbox"
+    )]
+    sandbox_timeout: f64,
+    /// Run sandboxed conversions where the kernel's landlock can confine only some of
+    /// their file access, or none of it, with a warning rather than failing them.
+    #[arg(long = "sandbox-best-effort", requires = "sandbox")]
+    sandbox_best_effort: bool,
+    /// Print the resolved pipeline for each input instead of running it, as `text` (the
+    /// default) or `--explain=json`.
+    #[arg(
+        long = "explain",
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    explain: Option<ExplainFormat>,
+    /// Order the inputs are processed in, instead of the order they were given.
+    #[arg(long = "order", value_enum)]
+    order: Option<order::Order>,
+    /// Number of inputs converted at once. The inputs are split between the jobs by a
+    /// cost estimated from their headers, so that the jobs finish around the same time.
+    #[arg(
+        long = "jobs",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "order"
+    )]
+    jobs: u64,
+    /// Print how the inputs would be split between jobs, with their estimated costs, and
+    /// the output each would write with its estimated rows and size, instead of
+    /// converting them. Only the inputs' headers are read.
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+    /// Share the inputs with other workers given the same directory: each input is
+    /// processed by whichever worker first creates its lock file there, and inputs whose
+    /// lock already exists are skipped.
+    #[arg(long = "coordinate", value_name = "LOCKDIR")]
+    coordinate: Option<PathBuf>,
+    /// Record each converted input's path, SHA-256, options and output in this state file,
+    /// a SQLite database or a JSON file if it ends in `.json`, and leave inputs it shows
+    /// were converted as they are now with the same options, to an output that is still
+    /// there, alone. Outputs of inputs that have changed are replaced only with
+    /// `--overwrite`.
+    #[arg(long = "state", value_name = "PATH")]
+    state: Option<PathBuf>,
+    /// When the batch finishes or fails, send a JSON report of each input's outcome to
+    /// this webhook URL, or run `command:<cmd>` in a shell with the report on its stdin.
+    #[arg(long = "on-complete")]
+    on_complete: Option<OnComplete>,
+    /// Keep running and convert each `.tif` or `.zip` file that appears in this directory,
+    /// including those already there, once it has stopped changing. A file that fails is
+    /// reported and retried only if it changes. With `--on-complete`, a report is sent
+    /// for each file.
+    #[arg(
+        long = "watch",
+        value_name = "DIR",
+        conflicts_with_all = ["input_path", "files_from", "jobs", "dry_run", "order", "explain", "keep_going", "errors_json"]
+    )]
+    watch: Option<PathBuf>,
+    /// Seconds a watched file's size and modification time must stay the same before it
+    /// is converted.
+    #[arg(long = "debounce", default_value_t = 10.0, requires = "watch")]
+    debounce: f64,
+    /// Seconds between listings of the watched directory when no change in it is reported.
+    /// Changes on local disks are reported as they happen, but network mounts may report
+    /// none, so lower this to pick up their files sooner.
+    #[arg(long = "poll-interval", default_value_t = 30.0, requires = "watch")]
+    poll_interval: f64,
+    /// Move each watched file into this directory once it has converted.
+    #[arg(long = "done-dir", requires = "watch")]
+    done_dir: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let multi_bar = MultiProgress::new();
-    cli.input_path
-        .into_iter()
-        .map(|input_path| process_one(multi_bar.clone(), input_path, cli.group))
-        .collect::<Result<Vec<_>>>()?;
-    Ok(())
+impl Cli {
+    fn sandbox_limits(&self) -> Result<Limits> {
+        if !(self.sandbox_timeout > 0.0 && self.sandbox_timeout.is_finite()) {
+            bail!(
+                "--sandbox-timeout must be a positive number of seconds, not {}",
+                self.sandbox_timeout
+            );
+        }
+        Ok(Limits {
+            memory: self.sandbox_memory,
+            cpu_seconds: self.sandbox_cpu,
+            timeout: Duration::from_secs_f64(self.sandbox_timeout),
+            best_effort: self.sandbox_best_effort,
+        })
+    }
 }
 
-fn process_one(multi_bar: MultiProgress, input_path: PathBuf, group: Option<f64>) -> Result<()> {
-    let bar = multi_bar.add(ProgressBar::new_spinner());
-    bar.set_style(ProgressStyle::with_template("{prefix:<30} {msg}")?);
-    bar.set_prefix(input_path.to_string_lossy().to_string());
-    bar.set_message("reading file");
-    let tif_contents = load_tif_contents(input_path.as_path())?;
-
-    bar.set_message("decoding tif");
-    let mut decoder =
-        tiff::decoder::Decoder::new(Cursor::new(tif_contents))?.with_limits(Limits::unlimited());
-    let (width, height) = decoder.dimensions()?;
-    let image = decoder.read_image()?;
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Summarize a raster's pixels inside each polygon of a `.geojson` or `.shp` file.
+    Zones(zones::ZonesArgs),
+    /// Print a raster's dimensions, sample type, layout, georeferencing and overviews.
+    Inspect(inspect::InspectArgs),
+    /// Check that each input holds one decodable GeoTIFF of a supported type and estimate
+    /// its output size, failing if any would not convert.
+    Validate(validate::ValidateArgs),
+    /// Print the min, max, mean, standard deviation, percentiles and a histogram of a
+    /// raster's pixel values.
+    Stats(stats::StatsArgs),
+    /// Write the per-cell differences between two rasters to parquet and summarize the
+    /// change.
+    Diff(diff::DiffArgs),
+    /// Report how two parquet outputs differ in schema, rows, values and coordinates,
+    /// failing if any difference is beyond the tolerance.
+    CompareOutputs(compare::CompareArgs),
+    /// Run an SQL query over a parquet output as the table `output`, such as
+    /// `SELECT count(*), sum(value) WHERE lat > 0`.
+    Query(query::QueryArgs),
+    /// Combine georeferenced tiles of one product into a single parquet table.
+    Mosaic(mosaic::MosaicArgs),
+    /// Convert a raster's pixels to rows and back, reporting how far positions and values
+    /// drift.
+    Roundtrip(roundtrip::RoundtripArgs),
+    /// Write a table of positions and values, such as a parquet output, back into a
+    /// GeoTIFF.
+    Rasterize(rasterize::RasterizeArgs),
+    /// Draw a raster's values, or their totals on a grid, to a PNG with a colormap.
+    Render(render::RenderArgs),
+    /// Write a raster of a known pattern, for tests, benchmarks and reproducing problems.
+    Synth(synth::SynthArgs),
+    /// Report which image, strip or tile of a damaged raster fails to decode and why,
+    /// optionally salvaging the readable pixels.
+    Doctor(doctor::DoctorArgs),
+    /// Draw a raster into a `z/x/y.png` pyramid of web mercator tiles with a colormap.
+    Tiles(pyramid::TilesArgs),
+    /// Trace isolines through a raster at given values and write them as GeoJSON.
+    Contours(contour::ContoursArgs),
+    /// Serve conversions over HTTP: POST a tif to `/convert` with options as query
+    /// parameters and get the output back, with `/health` for load balancers.
+    Serve(serve::ServeArgs),
+    /// Replace this binary with the latest GitHub release built for this platform, or the
+    /// release given with `--to`.
+    SelfUpdate(release::SelfUpdateArgs),
+    /// Report which inputs a `--state` file shows were converted as they are now with the
+    /// given options, and which are new, have changed or have lost their output since.
+    Status(Box<state::StatusArgs>),
+    /// List the `geotif-<name>` executables on the PATH, each of which runs as the
+    /// subcommand `<name>`.
+    Plugins,
+}
 
-    if let DecodingResult::I32(pixels) = image {
-        bar.set_message("processing image");
-        bar.set_length(width as u64 * height as u64);
-        bar.set_style(ProgressStyle::with_template(
-            "{prefix:<30} {msg} {percent}% {elapsed_precise} {bar_wide}",
-        )?);
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("Error: {:?}", error);
+        std::process::exit(FailureClass::of(&error).exit_code());
+    }
+}
 
-        let mut data: Vec<(f64, f64, f64)> = pixels
+fn run() -> Result<()> {
+    let args: Vec<_> = std::env::args_os().collect();
+    if let Some(path) = plugin::requested(&Cli::command(), &args) {
+        std::process::exit(plugin::run(&path, &args[2..])?);
+    }
+    let matches = Cli::command().get_matches_from(config::apply(Cli::command(), args)?);
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if let Some(requirement) = &cli.require_version {
+        release::require(requirement)?;
+    }
+    match &cli.command {
+        Some(Command::Zones(args)) => return zones::run(args),
+        Some(Command::Inspect(args)) => return inspect::run(args),
+        Some(Command::Validate(args)) => return validate::run(args),
+        Some(Command::Stats(args)) => return stats::run(args),
+        Some(Command::Diff(args)) => return diff::run(args),
+        Some(Command::CompareOutputs(args)) => return compare::run(args),
+        Some(Command::Query(args)) => return query::run(args),
+        Some(Command::Mosaic(args)) => return mosaic::run(args),
+        Some(Command::Roundtrip(args)) => return roundtrip::run(args),
+        Some(Command::Rasterize(args)) => return rasterize::run(args),
+        Some(Command::Render(args)) => return render::run(args),
+        Some(Command::Synth(args)) => return synth::run(args),
+        Some(Command::Doctor(args)) => return doctor::run(args),
+        Some(Command::Tiles(args)) => return pyramid::run(args),
+        Some(Command::Contours(args)) => return contour::run(args),
+        Some(Command::Serve(args)) => return serve::run(args),
+        Some(Command::SelfUpdate(args)) => return release::run(args),
+        Some(Command::Status(args)) => {
+            let matches = matches.subcommand_matches("status");
+            return state::run(args, matches.expect("status was given"));
+        }
+        Some(Command::Plugins) => {
+            plugin::list();
+            return Ok(());
+        }
+        None => {}
+    }
+    let limits = cli.sandbox_limits()?;
+    if let Some(input_path) = sandbox::child_input() {
+        return sandbox::convert(&input_path, cli.options, &limits);
+    }
+    if cli.sandbox {
+        sandbox::check_supported()?;
+        if cli.options.timing.is_some() {
+            bail!("--timing can't see into --sandbox conversions");
+        }
+        let bucket_table = cli.options.table_uri.as_deref().is_some_and(upload::is_url);
+        if cli.options.output.is_some() || cli.options.postgres.is_some() || bucket_table {
+            bail!("--sandbox conversions can only write next to their input, not to --output, --postgres or an s3:// --table-uri");
+        }
+    }
+    if let Some(source) = &cli.files_from {
+        if stdin::is_stdin(source) && cli.input_path.iter().any(|p| stdin::is_stdin(p)) {
+            bail!("stdin can't hold both the list of --files-from and an input");
+        }
+        cli.input_path.extend(inputs::read_list(source)?);
+    }
+    cli.input_roots = cli.input_path.clone();
+    cli.input_path = inputs::expand(&cli.input_path, &cli.extensions)?;
+    if cli.all_members || !cli.archive_member.is_empty() {
+        cli.input_path = archive::members(&cli.input_path, &cli.archive_member, cli.all_members)?;
+    }
+    let processor = Arc::new(ProcessorBuilder::from_options(cli.options.clone()).build()?);
+    if let Some(format) = cli.explain {
+        for input_path in &cli.input_path {
+            explain::explain(input_path, processor.options(), format)?;
+        }
+        return Ok(());
+    }
+    if cli.options.style_out.is_some() && cli.input_path.len() > 1 {
+        bail!(
+            "--style-out styles a single output, but {} inputs were given",
+            cli.input_path.len()
+        );
+    }
+    if cli.options.output == Some(Target::Stdout) {
+        if cli.input_path.len() > 1 {
+            bail!(
+                "-o - writes a single output to stdout, but {} inputs were given",
+                cli.input_path.len()
+            );
+        }
+        if cli.watch.is_some() {
+            bail!("--watch converts any number of inputs, so can't write them to stdout");
+        }
+    }
+    if cli.options.sha256.is_some() && cli.input_path.len() > 1 {
+        bail!(
+            "--sha256 is the digest of a single input, but {} inputs were given",
+            cli.input_path.len()
+        );
+    }
+    if cli.options.output_template.is_some() {
+        let mut seen = std::collections::HashSet::new();
+        for input_path in &cli.input_path {
+            let output_path = processor.options().output_path(input_path)?;
+            if !seen.insert(output_path.clone()) {
+                bail!(
+                    "--output-template names more than one output {}",
+                    output_path.to_string_lossy()
+                );
+            }
+        }
+    }
+    if cli.nice {
+        priority::lower()?;
+    }
+    if let Some(policy) = cli.numa {
+        numa::configure_pool(policy)?;
+    }
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+    let progress = !cli.no_progress && std::io::stderr().is_terminal();
+    let bars = logging::init(cli.log_format, verbosity, progress);
+    let state = match &cli.state {
+        Some(path) => Some(State::open(path, state::options_hash(&matches)?)?),
+        None => None,
+    };
+    let state = state.as_ref();
+    if let Some(dir) = &cli.watch {
+        return watch_dir(dir, &cli, &processor, &bars, state);
+    }
+    let mut input_paths = cli.input_path.clone();
+    if let Some(order) = cli.order {
+        order::sort(&mut input_paths, order);
+    }
+    let jobs: Vec<Vec<PathBuf>> = if cli.jobs > 1 || cli.dry_run {
+        let estimates = input_paths.iter().map(|p| schedule::estimate(p)).collect();
+        let slots = schedule::assign(estimates, cli.jobs as usize);
+        if cli.dry_run {
+            print!(
+                "{}",
+                schedule::describe(&slots, |input_path| {
+                    match explain::estimate_output(input_path, processor.options()) {
+                        Ok(output) => format!(
+                            "-> {}: {} samples, at most {} rows, about {} bytes",
+                            output.path.display(),
+                            output.sample_type,
+                            output.rows,
+                            output.bytes
+                        ),
+                        Err(error) => format!("-> no output, {:#}", error),
+                    }
+                })
+            );
+            return Ok(());
+        }
+        slots
             .into_iter()
-            .progress_with(bar.clone())
-            .enumerate()
-            .filter(|(_, value)| *value > 0)
-            .map(|(idx, value)| (idx % width as usize, idx / width as usize, value))
-            .map(|(x, y, value)| {
-                let lon = lerp(x as f64, (0.0, width as f64), (-180.0, 180.0));
-                let lat = lerp(y as f64, (0.0, height as f64), (85.0, -85.0));
-                (lon, lat, value as f64)
+            .map(|slot| slot.into_iter().map(|e| e.path).collect())
+            .collect()
+    } else {
+        vec![input_paths]
+    };
+
+    let started = Instant::now();
+    let deadline = cli.timeout.map(|timeout| (started + timeout, timeout));
+    let failed = AtomicBool::new(false);
+    let results: Vec<(PathBuf, Result<Outcome>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = jobs
+            .iter()
+            .map(|job| {
+                scope.spawn(|| run_job(&bars, job, &cli, &processor, state, deadline, &failed))
             })
             .collect();
-
-        if let Some(group) = group {
-            let mut grouped = HashMap::<(i32, i32), (f64, f64, f64)>::new();
-            for (lon, lat, value) in data.iter() {
-                let lon_index = (lon / group).floor() as i32;
-                let lat_index = (lat / group).floor() as i32;
-                let grouped_lon = lon_index as f64 * group;
-                let grouped_lat = lat_index as f64 * group;
-                let entry = grouped.entry((lon_index, lat_index)).or_insert((
-                    grouped_lon,
-                    grouped_lat,
-                    0.0,
-                ));
-                let scaled = *value * lat.to_radians().cos();
-                entry.2 += scaled;
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("job thread panicked"))
+            .collect()
+    });
+    let mut outcomes = vec![];
+    let mut failures = vec![];
+    for (input_path, outcome) in results {
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                let outcome = Outcome::Failed(format!("{:#}", err));
+                failures.push((input_path.clone(), err));
+                outcome
             }
-            data = grouped.into_values().collect();
-        }
-
-        let lon_col = Float32Array::from_iter(data.iter().map(|r| r.1 as f32));
-        let lat_col = Float32Array::from_iter(data.iter().map(|r| r.0 as f32));
-        let value_col = Float32Array::from_iter(data.iter().map(|r| r.2 as f32));
-
-        let batch = RecordBatch::try_from_iter(vec![
-            ("lon", Arc::new(lon_col) as ArrayRef),
-            ("lat", Arc::new(lat_col) as ArrayRef),
-            ("value", Arc::new(value_col) as ArrayRef),
-        ])?;
-
-        let output_file = File::create(&input_path.with_extension("parquet"))?;
-        let mut writer = ArrowWriter::try_new(output_file, batch.schema(), None)?;
-        writer.write(&batch)?;
-        writer.close()?;
-    } else {
-        let image_type = match &image {
-            DecodingResult::U8(_) => "U8",
-            DecodingResult::U16(_) => "U16",
-            DecodingResult::U32(_) => "U32",
-            DecodingResult::U64(_) => "U64",
-            DecodingResult::F32(_) => "F32",
-            DecodingResult::F64(_) => "F64",
-            DecodingResult::I8(_) => "I8",
-            DecodingResult::I16(_) => "I16",
-            DecodingResult::I32(_) => "I32",
-            DecodingResult::I64(_) => "I64",
         };
-
-        anyhow::bail!("Unexpected image type. Expected I32 but got {}", image_type);
+        outcomes.push((input_path, outcome));
     }
-
-    bar.finish_with_message("done");
-    Ok(())
+    if let Some(path) = &cli.errors_json {
+        failure::write_records(path, &failures)?;
+    }
+    if let Some(format) = cli.options.timing {
+        print!("{}", timing::report(&processor.take_timings(), format));
+    }
+    let classes: Vec<_> = failures
+        .iter()
+        .map(|(_, err)| FailureClass::of(err))
+        .collect();
+    let mut result = match failures.into_iter().next() {
+        Some((_, err)) => Err(err),
+        None => Ok(()),
+    };
+    if cli.keep_going {
+        if let Some(summary) = notify::failures(&outcomes) {
+            result = Err(anyhow!(Classified::new(
+                failure::batch_class(&classes),
+                summary
+            )));
+        }
+    }
+    if let Some(hook) = &cli.on_complete {
+        let report = notify::report(&outcomes, started.elapsed());
+        match (hook.send(&report), &result) {
+            (Err(err), Ok(())) => return Err(err),
+            // The batch's own failure matters more than the hook's.
+            (Err(err), Err(_)) => eprintln!("{:#}", err),
+            (Ok(()), _) => {}
+        }
+    }
+    result
 }
 
-fn load_tif_contents(path: &Path) -> Result<Vec<u8>> {
-    let mut tif_contents: Vec<u8> = vec![];
-    match path.extension().and_then(|e| e.to_str()) {
-        Some(ext) if ext == "zip" => {
-            let zip_file = File::open(path)?;
-            let mut archive = ZipArchive::new(zip_file)?;
-            let tif_names = archive
-                .file_names()
-                .filter(|n| n.ends_with(".tif") || n.ends_with(".tiff"))
-                .map(|n| n.to_string())
-                .collect::<Vec<_>>();
-            match &tif_names[..] {
-                [] => bail!("No tif files found archive"),
-                [tif_name] => archive.by_name(tif_name)?.read_to_end(&mut tif_contents)?,
-                _ => bail!("Multiple tif files found in archive"),
+/// Converts files as they arrive in `dir`, until the process is stopped.
+fn watch_dir(
+    dir: &Path,
+    cli: &Cli,
+    processor: &Arc<Processor>,
+    bars: &MultiProgress,
+    state: Option<&State>,
+) -> Result<()> {
+    if !(cli.debounce >= 0.0 && cli.debounce.is_finite()) {
+        bail!(
+            "--debounce must be a number of seconds, not {}",
+            cli.debounce
+        );
+    }
+    if !(cli.poll_interval > 0.0 && cli.poll_interval.is_finite()) {
+        bail!(
+            "--poll-interval must be a positive number of seconds, not {}",
+            cli.poll_interval
+        );
+    }
+    watch::run(
+        dir,
+        Duration::from_secs_f64(cli.debounce),
+        Duration::from_secs_f64(cli.poll_interval),
+        cli.done_dir.as_deref(),
+        |input_path| {
+            let started = Instant::now();
+            let processed = process_changed(bars, input_path, cli, processor, state, None);
+            let (outcome, result) = match processed {
+                Ok(outcome) => {
+                    let converted = matches!(outcome, Outcome::Written { .. });
+                    (outcome, Ok(converted))
+                }
+                Err(err) => (Outcome::Failed(format!("{:#}", err)), Err(err)),
+            };
+            logging::finished(input_path, &outcome, started.elapsed());
+            if let Some(format) = cli.options.timing {
+                print!("{}", timing::report(&processor.take_timings(), format));
             }
-        }
-        Some(ext) if ext == "tif" => File::open(path)?.read_to_end(&mut tif_contents)?,
-        Some(ext) => bail!("Unexpected file extension {}", ext),
-        None => bail!("No file extension on {}", path.to_string_lossy()),
-    };
-    Ok(tif_contents)
+            if let Some(hook) = &cli.on_complete {
+                let report =
+                    notify::report(&[(input_path.to_path_buf(), outcome)], started.elapsed());
+                if let Err(err) = hook.send(&report) {
+                    eprintln!("{:#}", err);
+                }
+            }
+            result
+        },
+    )
 }
 
-fn lerp(v: f64, domain: (f64, f64), range: (f64, f64)) -> f64 {
-    (v - domain.0) / (domain.1 - domain.0) * (range.1 - range.0) + range.0
+/// Processes a job's inputs in turn, skipping the rest once any job has failed unless
+/// `--keep-going` was given.
+fn run_job(
+    bars: &MultiProgress,
+    inputs: &[PathBuf],
+    cli: &Cli,
+    processor: &Arc<Processor>,
+    state: Option<&State>,
+    deadline: Option<(Instant, Duration)>,
+    failed: &AtomicBool,
+) -> Vec<(PathBuf, Result<Outcome>)> {
+    inputs
+        .iter()
+        .map(|input_path| {
+            if failed.load(Ordering::Relaxed) {
+                return (input_path.clone(), Ok(Outcome::Skipped));
+            }
+            let started = Instant::now();
+            let outcome = process_changed(bars, input_path, cli, processor, state, deadline);
+            match &outcome {
+                Ok(outcome) => logging::finished(input_path, outcome, started.elapsed()),
+                Err(err) => {
+                    let failure = Outcome::Failed(format!("{:#}", err));
+                    logging::finished(input_path, &failure, started.elapsed());
+                    if !cli.keep_going {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+            (input_path.clone(), outcome)
+        })
+        .collect()
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::lerp;
-
-    fn assert_approx(actual: f64, expected: f64) {
-        assert!(
-            (expected - actual).abs() < 0.001,
-            "{} should be approximately to {}",
-            actual,
-            expected
-        );
+/// Processes one input unless the `--state` shows it was converted as it is now with the
+/// same options, recording it there once it has converted.
+fn process_changed(
+    bars: &MultiProgress,
+    input_path: &Path,
+    cli: &Cli,
+    processor: &Arc<Processor>,
+    state: Option<&State>,
+    deadline: Option<(Instant, Duration)>,
+) -> Result<Outcome> {
+    // What is piped on stdin is new each time, so is never recorded.
+    let Some(state) = state.filter(|_| !stdin::is_stdin(input_path)) else {
+        return claim_and_process(bars, input_path, cli, processor, deadline);
+    };
+    let (status, fingerprint) = state.check(input_path)?;
+    if let state::Status::UpToDate { output } = status {
+        return Ok(Outcome::UpToDate { output });
     }
+    let outcome = claim_and_process(bars, input_path, cli, processor, deadline)?;
+    if let Outcome::Written { output, .. } = &outcome {
+        state.record(input_path, fingerprint, output)?;
+    }
+    Ok(outcome)
+}
 
-    #[test]
-    fn test_lerp() {
-        assert_approx(lerp(0.5, (0.0, 1.0), (-10.0, 10.0)), 0.0);
-        assert_approx(lerp(0.2, (0.0, 2.0), (-3.0, 5.0)), -2.2);
-        assert_approx(lerp(0.75, (-1.0, 1.0), (-10.0, 10.0)), 7.5);
+/// Processes one input, first claiming it when workers are coordinating through
+/// `--coordinate`.
+fn claim_and_process(
+    bars: &MultiProgress,
+    input_path: &Path,
+    cli: &Cli,
+    processor: &Arc<Processor>,
+    deadline: Option<(Instant, Duration)>,
+) -> Result<Outcome> {
+    let Some(dir) = &cli.coordinate else {
+        return process_one(bars, input_path, cli, processor, deadline);
+    };
+    let Some(lock) = coordinate::claim(dir, &inputs::key(&cli.input_roots, input_path))? else {
+        return Ok(Outcome::Taken);
+    };
+    match process_one(bars, input_path, cli, processor, deadline) {
+        Ok(outcome) => {
+            lock.complete()?;
+            Ok(outcome)
+        }
+        Err(err) => {
+            if let Err(release_err) = lock.release() {
+                eprintln!("{:#}", release_err);
+            }
+            Err(err)
+        }
     }
+}
 
-    #[test]
-    fn test_lerp_reverse() {
-        assert_approx(lerp(0.1, (0.0, 1.0), (100.0, 0.0)), 90.0);
-        assert_approx(lerp(0.9, (0.0, 1.0), (100.0, 0.0)), 10.0);
+fn process_one(
+    bars: &MultiProgress,
+    input_path: &Path,
+    cli: &Cli,
+    processor: &Arc<Processor>,
+    deadline: Option<(Instant, Duration)>,
+) -> Result<Outcome> {
+    let watchdog = Arc::new(Watchdog::new(cli.stage_timeout, deadline));
+    // Inputs not started before the batch's --timeout fail with it.
+    watchdog.check()?;
+    logging::started(input_path);
+    let bar = bars.add(ProgressBar::new_spinner());
+    bar.set_style(ProgressStyle::with_template("{prefix:<30} {msg}")?);
+    bar.set_prefix(input_path.to_string_lossy().to_string());
+    if cli.sandbox {
+        let mut limits = cli.sandbox_limits()?;
+        if let Some((deadline, _)) = deadline {
+            limits.timeout = limits
+                .timeout
+                .min(deadline.saturating_duration_since(Instant::now()));
+        }
+        return sandbox::process(input_path, &limits, &bar);
+    }
+    if cli.stage_timeout.is_none() && deadline.is_none() {
+        return processor.process_with_progress(input_path, &bar);
     }
+    let (processor, input_path) = (processor.clone(), input_path.to_path_buf());
+    watchdog::run(watchdog.clone(), move || {
+        processor.process_watched(&input_path, &bar, &watchdog)
+    })
 }
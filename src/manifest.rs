@@ -0,0 +1,247 @@
+//! Sidecar manifests recording where an output came from and what it holds, written with
+//! `--manifest` as `<output>.manifest.json` so downstream users can audit and validate it.
+
+use crate::{
+    archive,
+    checksum::{self, Algorithm, Verified},
+    crs::Crs,
+    group::Align,
+    json::Value,
+    stdin,
+};
+use anyhow::{Context, Result};
+use arrow_array::{cast::as_primitive_array, types::Float64Type, ArrayRef, RecordBatch};
+use arrow_schema::DataType;
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Where the manifest of `output_path` is written.
+pub fn manifest_path(output_path: &Path) -> PathBuf {
+    let mut path = OsString::from(output_path.as_os_str());
+    path.push(".manifest.json");
+    PathBuf::from(path)
+}
+
+/// Running totals of the output's rows, values and extent.
+pub(crate) struct Summary {
+    rows: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    /// `[west, south, east, north]` in the position columns' units.
+    bounds: [f64; 4],
+}
+
+impl Summary {
+    pub(crate) fn new() -> Summary {
+        Summary {
+            rows: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            bounds: [
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ],
+        }
+    }
+
+    /// Adds a table whose first two columns are its positions and whose values are in the
+    /// column named `value`.
+    pub(crate) fn add(&mut self, batch: &RecordBatch, value: &str) {
+        self.rows += batch.num_rows() as u64;
+        let floats = |column: &ArrayRef| -> Vec<f64> {
+            match arrow_cast::cast(column, &DataType::Float64) {
+                Ok(column) => as_primitive_array::<Float64Type>(&column)
+                    .iter()
+                    .flatten()
+                    .collect(),
+                Err(_) => vec![],
+            }
+        };
+        if let Some(values) = batch.column_by_name(value) {
+            for value in floats(values).into_iter().filter(|v| !v.is_nan()) {
+                self.min = self.min.min(value);
+                self.max = self.max.max(value);
+                self.sum += value;
+            }
+        }
+        for x in floats(batch.column(0)) {
+            self.bounds[0] = self.bounds[0].min(x);
+            self.bounds[2] = self.bounds[2].max(x);
+        }
+        for y in floats(batch.column(1)) {
+            self.bounds[1] = self.bounds[1].min(y);
+            self.bounds[3] = self.bounds[3].max(y);
+        }
+    }
+
+    /// Adds the totals of another part of the output.
+    pub(crate) fn merge(&mut self, other: &Summary) {
+        self.rows += other.rows;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        for i in 0..2 {
+            self.bounds[i] = self.bounds[i].min(other.bounds[i]);
+            self.bounds[i + 2] = self.bounds[i + 2].max(other.bounds[i + 2]);
+        }
+    }
+
+    pub(crate) fn rows(&self) -> u64 {
+        self.rows
+    }
+}
+
+/// The footer key of the name of the input an output came from.
+pub(crate) const SOURCE_KEY: &str = "image_stats:source";
+
+/// The name `input_path` is recorded under: its file name, or for a tif in an archive,
+/// the member's name.
+pub(crate) fn source_name(input_path: &Path) -> String {
+    match archive::split(input_path) {
+        Some((_, name)) => name,
+        None => input_path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string()),
+    }
+}
+
+/// The key value pairs a parquet output of `input_path` records in its footer, so that
+/// catalogs and query engines can tell what it covers and where it came from without
+/// its manifest: its bbox in the position columns' `crs`, the input's file name, and the
+/// tool and arguments that wrote it.
+pub(crate) fn footer(input_path: &Path, summary: &Summary, crs: Crs) -> Vec<(String, String)> {
+    let mut footer = vec![
+        (SOURCE_KEY, source_name(input_path)),
+        ("image_stats:crs", crs.to_string()),
+        (
+            "image_stats:version",
+            format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        ),
+        (
+            "image_stats:arguments",
+            Value::from(std::env::args().skip(1).collect::<Vec<_>>()).to_string(),
+        ),
+    ];
+    if summary.rows > 0 {
+        footer.push((
+            "image_stats:bbox",
+            Value::from(summary.bounds.to_vec()).to_string(),
+        ));
+    }
+    footer
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+}
+
+/// Writes the manifest of converting `input_path` to `output_path`, whose rows are pixels
+/// placed with `registration`, or grouped cells if it is `None`. `verified` is the
+/// checksum the input was checked against, if it was.
+pub(crate) fn write(
+    input_path: &Path,
+    output_path: &Path,
+    summary: &Summary,
+    registration: Option<Align>,
+    verified: Option<&Verified>,
+    elapsed: Duration,
+) -> Result<()> {
+    let finite = |x: f64| x.is_finite().then_some(x);
+    let manifest = Value::object([
+        (
+            "input",
+            Value::object([
+                ("path", Value::from(input_path.display().to_string())),
+                ("sha256", sha256_file(input_path)?.into()),
+                (
+                    "checksum",
+                    match verified {
+                        Some(verified) => Value::object([
+                            ("algorithm", Value::from(verified.algorithm.name())),
+                            ("digest", verified.digest.clone().into()),
+                            ("expected_from", verified.expected_from.clone().into()),
+                        ]),
+                        None => Value::Null,
+                    },
+                ),
+            ]),
+        ),
+        ("output", output_path.display().to_string().into()),
+        (
+            "tool",
+            Value::object([
+                ("name", Value::from(env!("CARGO_PKG_NAME"))),
+                ("version", env!("CARGO_PKG_VERSION").into()),
+            ]),
+        ),
+        (
+            "arguments",
+            std::env::args().skip(1).collect::<Vec<_>>().into(),
+        ),
+        ("rows", summary.rows.into()),
+        (
+            "value",
+            Value::object([
+                ("min", finite(summary.min).into()),
+                ("max", finite(summary.max).into()),
+                ("sum", summary.sum.into()),
+            ]),
+        ),
+        (
+            "bbox",
+            match summary.rows {
+                0 => Value::Null,
+                _ => summary.bounds.to_vec().into(),
+            },
+        ),
+        (
+            "registration",
+            match registration {
+                Some(Align::Center) => "center".into(),
+                Some(Align::Corner) => "corner".into(),
+                None => Value::Null,
+            },
+        ),
+        ("processing_seconds", elapsed.as_secs_f64().into()),
+    ]);
+    let path = manifest_path(output_path);
+    std::fs::write(&path, manifest.pretty() + "\n")
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// The SHA-256 digest of a file, of a tif inside an archive, or of what was piped on
+/// stdin, in hex.
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let member;
+    let file: Box<dyn Read> = match archive::split(path) {
+        _ if stdin::is_stdin(path) => Box::new(stdin::contents()?),
+        Some((archive, name)) => {
+            member = archive::read_member(archive, &name)?;
+            Box::new(&member[..])
+        }
+        None => Box::new(File::open(path)?),
+    };
+    checksum::hash(file, Algorithm::Sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::manifest_path;
+    use std::path::Path;
+
+    #[test]
+    fn test_manifest_path() {
+        assert_eq!(
+            manifest_path(Path::new("out/a.parquet")),
+            Path::new("out/a.parquet.manifest.json")
+        );
+    }
+}
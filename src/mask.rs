@@ -0,0 +1,396 @@
+//! Polygon masks loaded from GeoJSON or shapefiles, with a banded index for fast
+//! point-in-polygon tests.
+
+use crate::{georef::BBox, json};
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::Path;
+
+/// A closed ring of `(lon, lat)` points.
+type Ring = Vec<(f64, f64)>;
+
+/// A polygon as its outer ring and any holes. Containment is decided by the even-odd
+/// rule across all rings, so ring order and winding don't matter.
+pub type Polygon = Vec<Ring>;
+
+/// Number of latitude bands per edge in the index; more bands means fewer edges to test
+/// per point at the cost of edges spanning several bands being stored more than once.
+const BANDS_PER_EDGE: f64 = 0.25;
+
+pub struct Mask {
+    bounds: BBox,
+    band_height: f64,
+    /// For each latitude band, the edges that overlap it and which polygon they belong to.
+    bands: Vec<Vec<(u32, Edge)>>,
+}
+
+#[derive(Clone, Copy)]
+struct Edge {
+    a: (f64, f64),
+    b: (f64, f64),
+}
+
+/// One feature of a polygon file: zones are numbered by their position in the file, so
+/// features without polygons are kept, with an empty polygon.
+pub struct Zone {
+    pub name: Option<String>,
+    pub polygon: Polygon,
+}
+
+/// Reads every feature in a `.geojson`/`.json` or `.shp` file. The parts of multipolygons
+/// are merged into a single polygon.
+pub fn read_zones(path: &Path) -> Result<Vec<Zone>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("geojson" | "json") => read_geojson(&std::fs::read_to_string(path)?),
+        Some("shp") => read_shapefile(&std::fs::read(path)?),
+        _ => bail!(
+            "Unsupported polygon file {}, expected .geojson or .shp",
+            path.to_string_lossy()
+        ),
+    }
+    .with_context(|| format!("Could not read polygons from {}", path.to_string_lossy()))
+}
+
+impl Mask {
+    /// Loads every polygon in a `.geojson`/`.json` or `.shp` file.
+    pub fn load(path: &Path) -> Result<Mask> {
+        let zones = read_zones(path)?;
+        Mask::new(&zones.into_iter().map(|z| z.polygon).collect::<Vec<_>>())
+    }
+
+    pub fn new(polygons: &[Polygon]) -> Result<Mask> {
+        let mut edges = vec![];
+        for (id, polygon) in polygons.iter().enumerate() {
+            for ring in polygon {
+                for pair in ring.windows(2) {
+                    edges.push((
+                        id as u32,
+                        Edge {
+                            a: pair[0],
+                            b: pair[1],
+                        },
+                    ));
+                }
+                if let (Some(first), Some(last)) = (ring.first(), ring.last()) {
+                    if first != last {
+                        edges.push((
+                            id as u32,
+                            Edge {
+                                a: *last,
+                                b: *first,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        if edges.is_empty() {
+            bail!("No polygons found");
+        }
+
+        let mut bounds = BBox {
+            west: f64::INFINITY,
+            south: f64::INFINITY,
+            east: f64::NEG_INFINITY,
+            north: f64::NEG_INFINITY,
+        };
+        for (_, edge) in &edges {
+            for (lon, lat) in [edge.a, edge.b] {
+                bounds.west = bounds.west.min(lon);
+                bounds.east = bounds.east.max(lon);
+                bounds.south = bounds.south.min(lat);
+                bounds.north = bounds.north.max(lat);
+            }
+        }
+
+        let band_count = ((edges.len() as f64 * BANDS_PER_EDGE).ceil() as usize).max(1);
+        let band_height = ((bounds.north - bounds.south) / band_count as f64).max(f64::EPSILON);
+        let mut bands = vec![vec![]; band_count];
+        for (id, edge) in edges {
+            let low = edge.a.1.min(edge.b.1);
+            let high = edge.a.1.max(edge.b.1);
+            let first = ((low - bounds.south) / band_height) as usize;
+            let last = ((high - bounds.south) / band_height) as usize;
+            for band in &mut bands[first.min(band_count - 1)..=last.min(band_count - 1)] {
+                band.push((id, edge));
+            }
+        }
+
+        Ok(Mask {
+            bounds,
+            band_height,
+            bands,
+        })
+    }
+
+    pub fn bounds(&self) -> BBox {
+        self.bounds
+    }
+
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        !self.polygons_at(lon, lat).is_empty()
+    }
+
+    /// Returns the indexes of the polygons containing the point.
+    pub fn polygons_at(&self, lon: f64, lat: f64) -> Vec<u32> {
+        if !self.bounds.contains(lon, lat) {
+            return vec![];
+        }
+        let band =
+            (((lat - self.bounds.south) / self.band_height) as usize).min(self.bands.len() - 1);
+
+        // Cast a ray east from the point and count crossings per polygon.
+        let mut crossings: Vec<u32> = self.bands[band]
+            .iter()
+            .filter(|(_, edge)| {
+                let (a, b) = (edge.a, edge.b);
+                (a.1 > lat) != (b.1 > lat) && lon < a.0 + (lat - a.1) / (b.1 - a.1) * (b.0 - a.0)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        crossings.sort_unstable();
+        crossings
+            .chunk_by(|a, b| a == b)
+            .filter(|run| run.len() % 2 == 1)
+            .map(|run| run[0])
+            .collect()
+    }
+}
+
+fn read_geojson(text: &str) -> Result<Vec<Zone>> {
+    let mut zones = vec![];
+    collect_features(&json::parse(text)?, &mut zones)?;
+    Ok(zones)
+}
+
+fn collect_features(value: &json::Value, zones: &mut Vec<Zone>) -> Result<()> {
+    match value.get("type").and_then(|t| t.as_str()) {
+        Some("FeatureCollection") => {
+            for feature in value
+                .get("features")
+                .and_then(|f| f.as_array())
+                .unwrap_or_default()
+            {
+                collect_features(feature, zones)?;
+            }
+        }
+        Some("Feature") => {
+            let mut polygon = vec![];
+            match value.get("geometry") {
+                Some(json::Value::Null) | None => {}
+                Some(geometry) => collect_rings(geometry, &mut polygon)?,
+            }
+            let name = match value
+                .get("properties")
+                .and_then(|p| p.get("name"))
+                .or(value.get("id"))
+            {
+                Some(json::Value::String(name)) => Some(name.clone()),
+                Some(json::Value::Number(id)) => Some(id.to_string()),
+                _ => None,
+            };
+            zones.push(Zone { name, polygon });
+        }
+        _ => {
+            let mut polygon = vec![];
+            collect_rings(value, &mut polygon)?;
+            zones.push(Zone {
+                name: None,
+                polygon,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn collect_rings(value: &json::Value, polygon: &mut Polygon) -> Result<()> {
+    let kind = value
+        .get("type")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| anyhow!("GeoJSON object without a type"))?;
+    let coordinates = || {
+        value
+            .get("coordinates")
+            .ok_or_else(|| anyhow!("{} without coordinates", kind))
+    };
+    match kind {
+        "GeometryCollection" => {
+            for geometry in value
+                .get("geometries")
+                .and_then(|g| g.as_array())
+                .unwrap_or_default()
+            {
+                collect_rings(geometry, polygon)?;
+            }
+        }
+        "Polygon" => polygon.extend(geojson_polygon(coordinates()?)?),
+        "MultiPolygon" => {
+            for part in coordinates()?.as_array().unwrap_or_default() {
+                polygon.extend(geojson_polygon(part)?);
+            }
+        }
+        // Points and lines have no area, so they can't contain any pixels.
+        _ => {}
+    }
+    Ok(())
+}
+
+fn geojson_polygon(value: &json::Value) -> Result<Polygon> {
+    let invalid = || anyhow!("Invalid polygon coordinates");
+    value
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|ring| {
+            ring.as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|point| {
+                    let point = point.as_array().ok_or_else(invalid)?;
+                    match (point.first(), point.get(1)) {
+                        (Some(lon), Some(lat)) => Ok((
+                            lon.as_f64().ok_or_else(invalid)?,
+                            lat.as_f64().ok_or_else(invalid)?,
+                        )),
+                        _ => Err(invalid()),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Reads the polygon records of an ESRI shapefile. Coordinates are assumed to be
+/// lon/lat, since the `.prj` is not consulted.
+fn read_shapefile(bytes: &[u8]) -> Result<Vec<Zone>> {
+    let truncated = || anyhow!("Shapefile is truncated");
+    let le_i32 = |at: usize| -> Result<i32> {
+        Ok(i32::from_le_bytes(
+            bytes.get(at..at + 4).ok_or_else(truncated)?.try_into()?,
+        ))
+    };
+    let be_i32 = |at: usize| -> Result<i32> {
+        Ok(i32::from_be_bytes(
+            bytes.get(at..at + 4).ok_or_else(truncated)?.try_into()?,
+        ))
+    };
+    let le_f64 = |at: usize| -> Result<f64> {
+        Ok(f64::from_le_bytes(
+            bytes.get(at..at + 8).ok_or_else(truncated)?.try_into()?,
+        ))
+    };
+
+    if be_i32(0)? != 9994 {
+        bail!("Not a shapefile");
+    }
+
+    let mut zones = vec![];
+    let mut offset = 100;
+    while offset + 8 <= bytes.len() {
+        let content_length = be_i32(offset + 4)? as usize * 2;
+        let content = offset + 8;
+        match le_i32(content)? {
+            // Null shapes carry no geometry.
+            0 => zones.push(Zone {
+                name: None,
+                polygon: vec![],
+            }),
+            // Polygon, PolygonZ and PolygonM share the same leading layout.
+            5 | 15 | 25 => {
+                let part_count = le_i32(content + 36)? as usize;
+                let point_count = le_i32(content + 40)? as usize;
+                let parts = content + 44;
+                let points = parts + 4 * part_count;
+                let mut starts = (0..part_count)
+                    .map(|i| Ok(le_i32(parts + 4 * i)? as usize))
+                    .collect::<Result<Vec<_>>>()?;
+                starts.push(point_count);
+                let polygon = starts
+                    .windows(2)
+                    .map(|range| {
+                        (range[0]..range[1])
+                            .map(|i| Ok((le_f64(points + 16 * i)?, le_f64(points + 16 * i + 8)?)))
+                            .collect::<Result<Ring>>()
+                    })
+                    .collect::<Result<Polygon>>()?;
+                zones.push(Zone {
+                    name: None,
+                    polygon,
+                });
+            }
+            other => bail!("Unsupported shape type {}, expected polygons", other),
+        }
+        offset = content + content_length;
+    }
+    Ok(zones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_geojson, read_shapefile, Mask, Zone};
+
+    fn polygons(zones: Vec<Zone>) -> Vec<super::Polygon> {
+        zones.into_iter().map(|z| z.polygon).collect()
+    }
+
+    #[test]
+    fn test_geojson_with_hole() {
+        let zones = read_geojson(
+            r#"{"type": "FeatureCollection", "features": [{"type": "Feature", "properties": {},
+                "geometry": {"type": "Polygon", "coordinates": [
+                    [[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]],
+                    [[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]
+                ]}}]}"#,
+        )
+        .unwrap();
+        let mask = Mask::new(&polygons(zones)).unwrap();
+        assert!(mask.contains(1.0, 1.0));
+        assert!(mask.contains(9.0, 5.0));
+        assert!(!mask.contains(5.0, 5.0));
+        assert!(!mask.contains(11.0, 5.0));
+    }
+
+    #[test]
+    fn test_geojson_zones() {
+        let zones = read_geojson(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "id": 7, "geometry": null},
+                {"type": "Feature", "properties": {"name": "islands"},
+                 "geometry": {"type": "MultiPolygon", "coordinates": [
+                    [[[0, 0], [1, 0], [1, 1], [0, 0]]],
+                    [[[5, 5], [6, 5], [6, 6], [5, 5]]]
+                ]}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones[0].name.as_deref(), Some("7"));
+        assert_eq!(zones[1].name.as_deref(), Some("islands"));
+        let mask = Mask::new(&polygons(zones)).unwrap();
+        assert_eq!(mask.polygons_at(5.9, 5.1), vec![1]);
+        assert_eq!(mask.polygons_at(0.9, 0.1), vec![1]);
+        assert!(mask.polygons_at(3.0, 3.0).is_empty());
+    }
+
+    #[test]
+    fn test_shapefile_polygon() {
+        let ring = [(0.0, 0.0), (0.0, 2.0), (2.0, 2.0), (2.0, 0.0), (0.0, 0.0)];
+        let mut content = vec![];
+        content.extend(5i32.to_le_bytes());
+        content.extend([0u8; 32]);
+        content.extend(1i32.to_le_bytes());
+        content.extend((ring.len() as i32).to_le_bytes());
+        content.extend(0i32.to_le_bytes());
+        for (x, y) in ring {
+            content.extend(f64::to_le_bytes(x));
+            content.extend(f64::to_le_bytes(y));
+        }
+        let mut file = vec![0u8; 100];
+        file[..4].copy_from_slice(&9994i32.to_be_bytes());
+        file.extend(1i32.to_be_bytes());
+        file.extend(((content.len() / 2) as i32).to_be_bytes());
+        file.extend(content);
+
+        let mask = Mask::new(&polygons(read_shapefile(&file).unwrap())).unwrap();
+        assert!(mask.contains(1.0, 1.0));
+        assert!(!mask.contains(3.0, 1.0));
+    }
+}
@@ -0,0 +1,138 @@
+//! Keeping a conversion within `--max-memory`: strips and tiles are decoded in units sized
+//! to the budget, and parquet outputs of pixels are written a band of image rows at a
+//! time when their rows wouldn't all fit.
+
+use anyhow::{bail, Result};
+use std::ops::Range;
+
+/// Bytes a kept row takes from when it is decoded until it is written: its position and
+/// value, the output columns built from them and the parquet writer's buffers.
+pub const ROW_BYTES: u64 = 64;
+
+/// Why rows that can't be written a band at a time must all be held at once.
+pub const WHOLE: &str = "and only parquet outputs of pixels can be written a part at a time";
+
+/// Why a band of rows can't be made smaller.
+pub const SMALLEST_BAND: &str = "in the smallest band of whole strips or tiles";
+
+/// Share of what the tif leaves of the budget that decoded strips and tiles may take,
+/// across all threads. The rest holds rows.
+const DECODE_SHARE: f64 = 0.25;
+
+/// Parses a size such as `512M`, `4G` or `1500000`, in bytes or binary multiples of them.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = &s[digits.len()..];
+    let shift = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" | "KI" => 10,
+        "M" | "MI" => 20,
+        "G" | "GI" => 30,
+        "T" | "TI" => 40,
+        _ => return Err(format!("unknown unit {:?}, expected K, M, G or T", unit)),
+    };
+    let number: f64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a size such as 512M or 4G, not {:?}", s))?;
+    if !(number > 0.0 && number.is_finite()) {
+        return Err(format!("expected a positive size, not {:?}", s));
+    }
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// What is left of `--max-memory` once an input's tif is loaded.
+pub struct Budget {
+    max: u64,
+    available: u64,
+}
+
+impl Budget {
+    pub fn new(max: u64, tif_bytes: u64) -> Result<Budget> {
+        // Without room for one row per pixel of a strip, nothing could be converted.
+        if tif_bytes >= max {
+            bail!(
+                "The tif takes {} bytes in memory, more than --max-memory allows ({} bytes)",
+                tif_bytes,
+                max
+            );
+        }
+        Ok(Budget {
+            max,
+            available: max - tif_bytes,
+        })
+    }
+
+    /// Image rows each thread decodes at once, for images `width` pixels across with
+    /// `samples` 4 byte samples each.
+    pub fn chunk_rows(&self, width: u32, samples: u32) -> u32 {
+        let threads = rayon::current_num_threads().max(1) as u64;
+        let row = (width as u64 * samples as u64 * 4).max(1);
+        let decode = (self.available as f64 * DECODE_SHARE) as u64;
+        (decode / threads / row).clamp(1, u32::MAX as u64) as u32
+    }
+
+    /// How many rows can be held at once.
+    pub fn rows(&self) -> u64 {
+        (self.available as f64 * (1.0 - DECODE_SHARE)) as u64 / ROW_BYTES
+    }
+
+    /// Splits an image's `height` rows into bands whose pixels fit as rows, each a whole
+    /// number of `chunk_height` strips or tiles so none is decoded twice.
+    pub fn bands(&self, width: u32, height: u32, chunk_height: u32) -> Vec<Range<u32>> {
+        let chunk_height = chunk_height.max(1);
+        let fitting = (self.rows() / width.max(1) as u64).min(height as u64) as u32;
+        let per_band = (fitting / chunk_height * chunk_height).max(chunk_height);
+        (0..height)
+            .step_by(per_band as usize)
+            .map(|start| start..(start + per_band).min(height))
+            .collect()
+    }
+
+    /// Fails if `rows` might not fit, or only warns when `force` is set, saying `why` they
+    /// can't be held fewer at a time.
+    pub fn check_rows(&self, rows: u64, why: &str, force: bool) -> Result<()> {
+        if rows <= self.rows() {
+            return Ok(());
+        }
+        let message = format!(
+            "Up to {} rows may need about {} bytes, more than --max-memory allows ({} bytes), \
+             {}",
+            rows,
+            rows * ROW_BYTES,
+            self.max,
+            why
+        );
+        if !force {
+            bail!("{} (use --force to convert anyway)", message);
+        }
+        eprintln!("Warning: {}", message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bytes, Budget, ROW_BYTES};
+
+    #[test]
+    fn test_budget() {
+        assert_eq!(parse_bytes("512M"), Ok(512 << 20));
+        assert_eq!(parse_bytes("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_bytes("4096"), Ok(4096));
+        assert!(parse_bytes("4X").is_err());
+        assert!(parse_bytes("0").is_err());
+        assert!(Budget::new(100, 100).is_err());
+
+        // Room for 3000 rows once the tif and decoding are accounted for.
+        let budget = Budget::new(1000 + 3000 * ROW_BYTES * 4 / 3, 1000).unwrap();
+        assert_eq!(budget.rows(), 3000);
+        // 300 pixel rows of 10 fit in each band, rounded down to whole strips of 16.
+        let bands = budget.bands(10, 700, 16);
+        assert_eq!(bands, vec![0..288, 288..576, 576..700]);
+        assert!(budget.check_rows(3000, "", false).is_ok());
+        assert!(budget.check_rows(3001, "", false).is_err());
+        assert!(budget.check_rows(3001, "", true).is_ok());
+    }
+}
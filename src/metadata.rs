@@ -0,0 +1,561 @@
+//! Descriptive metadata read from the tif and attached to output columns.
+
+use crate::{
+    align,
+    group::{Align, Binning},
+    processor::Options,
+    raster::Color,
+};
+use anyhow::Result;
+use std::collections::HashMap;
+use tiff::{
+    decoder::{ifd, Decoder},
+    tags::Tag,
+};
+
+const GDAL_METADATA: Tag = Tag::Unknown(42112);
+const XMP: Tag = Tag::Unknown(700);
+
+/// What the tif says about the values it holds.
+#[derive(Default)]
+pub struct SourceMetadata {
+    pub description: Option<String>,
+    pub nodata: Option<String>,
+    pub gdal_items: Vec<GdalItem>,
+    /// The XMP packet, as XML.
+    pub xmp: Option<String>,
+    /// The band being read, counting from zero, whose per-band items are used.
+    pub band: u32,
+    /// A palette image's ColorMap tag: the red, then green, then blue level of each value.
+    pub color_map: Option<Vec<u32>>,
+    /// How a true color image's samples are packed into values.
+    pub color: Option<Color>,
+}
+
+/// One `<Item>` of the XML GDAL stores in its private metadata tag.
+#[derive(Debug, PartialEq)]
+pub struct GdalItem {
+    pub name: String,
+    pub sample: Option<u32>,
+    pub role: Option<String>,
+    pub value: String,
+}
+
+impl SourceMetadata {
+    pub fn read<R: std::io::Read + std::io::Seek>(
+        decoder: &mut Decoder<R>,
+    ) -> Result<SourceMetadata> {
+        let ascii = |decoder: &mut Decoder<R>, tag| -> Result<Option<String>> {
+            Ok(match decoder.find_tag(tag)? {
+                Some(value) => Some(value.into_string()?.trim_end_matches('\0').to_string()),
+                None => None,
+            })
+        };
+        Ok(SourceMetadata {
+            description: ascii(decoder, Tag::ImageDescription)?,
+            nodata: ascii(decoder, Tag::GdalNodata)?,
+            gdal_items: ascii(decoder, GDAL_METADATA)?
+                .map(|xml| parse_gdal_metadata(&xml))
+                .unwrap_or_default(),
+            xmp: match decoder.find_tag(XMP)? {
+                Some(value) => Some(String::from_utf8_lossy(&bytes(value)?).into_owned()),
+                None => None,
+            },
+            band: 0,
+            color_map: match decoder.find_tag(Tag::ColorMap)? {
+                Some(value) => Some(value.into_u32_vec()?),
+                None => None,
+            },
+            color: Color::of(decoder)?,
+        })
+    }
+
+    /// Every piece of auxiliary metadata as `(key, value)` pairs. Keys are namespaced by
+    /// where they came from: `tiff:ImageDescription`, `gdal:NAME` for dataset items,
+    /// `gdal:bandN:NAME` for band items, and `xmp:prefix:name` for XMP properties.
+    pub fn items(&self) -> Vec<(String, String)> {
+        let mut items = vec![];
+        if let Some(description) = &self.description {
+            items.push(("tiff:ImageDescription".to_string(), description.clone()));
+        }
+        for item in &self.gdal_items {
+            let key = match item.sample {
+                Some(sample) => format!("gdal:band{}:{}", sample + 1, item.name),
+                None => format!("gdal:{}", item.name),
+            };
+            items.push((key, item.value.clone()));
+        }
+        if let Some(xmp) = &self.xmp {
+            items.extend(
+                parse_xmp(xmp)
+                    .into_iter()
+                    .map(|(name, value)| (format!("xmp:{}", name), value)),
+            );
+        }
+        items
+    }
+
+    /// The stored value marking missing pixels, from the GDAL nodata tag.
+    pub fn nodata_value(&self) -> Option<f64> {
+        self.nodata.as_deref()?.trim().parse().ok()
+    }
+
+    /// The band's GDAL `scale` and `offset`, which turn stored values into physical
+    /// ones as `value * scale + offset`.
+    pub fn scale_offset(&self) -> (Option<f64>, Option<f64>) {
+        let number = |role| self.band_item(role).and_then(|v| v.trim().parse().ok());
+        (number("scale"), number("offset"))
+    }
+
+    /// Finds a per-band item of the band being read by its role, such as `units` or
+    /// `scale`.
+    pub fn band_item(&self, role: &str) -> Option<&str> {
+        self.gdal_items
+            .iter()
+            .find(|item| item.sample == Some(self.band) && item.role.as_deref() == Some(role))
+            .map(|item| item.value.as_str())
+    }
+}
+
+/// Pulls the `<Item>`s out of GDAL's metadata XML.
+///
+/// This isn't a general XML parser; GDAL writes a flat list of items with plain text
+/// content, and that is all this understands.
+pub fn parse_gdal_metadata(xml: &str) -> Vec<GdalItem> {
+    let mut items = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find("<Item") {
+        rest = &rest[start + 5..];
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let attributes = &rest[..tag_end];
+        rest = &rest[tag_end + 1..];
+        let Some(close) = rest.find("</Item>") else {
+            break;
+        };
+        let value = unescape(rest[..close].trim());
+        rest = &rest[close + 7..];
+
+        let attribute = |name: &str| {
+            let key = format!("{}=\"", name);
+            let start = attributes.find(&key)? + key.len();
+            let end = attributes[start..].find('"')?;
+            Some(unescape(&attributes[start..start + end]))
+        };
+        if let Some(name) = attribute("name") {
+            items.push(GdalItem {
+                name,
+                sample: attribute("sample").and_then(|s| s.parse().ok()),
+                role: attribute("role"),
+                value,
+            });
+        }
+    }
+    items
+}
+
+/// Pulls the simple properties out of an XMP packet: attributes of `rdf:Description`
+/// elements, and elements holding only text. Structured properties such as lists are
+/// skipped.
+pub fn parse_xmp(xml: &str) -> Vec<(String, String)> {
+    let mut properties = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let tag = rest[..tag_end].trim_end_matches('/');
+        rest = &rest[tag_end + 1..];
+        if tag.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        if name == "rdf:Description" {
+            let mut attributes = attributes;
+            while let Some((key, after)) = attributes.split_once("=\"") {
+                let Some((value, after)) = after.split_once('"') else {
+                    break;
+                };
+                let key = key.trim();
+                if !key.starts_with("xmlns") && !key.starts_with("rdf:") && key.contains(':') {
+                    properties.push((key.to_string(), unescape(value)));
+                }
+                attributes = after;
+            }
+        } else if name.contains(':') && !name.starts_with("rdf:") && !name.starts_with("x:") {
+            let close = format!("</{}>", name);
+            if let Some(end) = rest.find(&close) {
+                let text = &rest[..end];
+                if !text.contains('<') && !text.trim().is_empty() {
+                    properties.push((name.to_string(), unescape(text.trim())));
+                } else if let Some(items) = rdf_items(text) {
+                    properties.push((name.to_string(), items));
+                }
+            }
+        }
+    }
+    properties
+}
+
+/// The `rdf:li` items of an XMP list or alternative holding only text, joined with `; `.
+fn rdf_items(xml: &str) -> Option<String> {
+    let mut items = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find("<rdf:li") {
+        rest = &rest[start..];
+        let text_start = rest.find('>')? + 1;
+        let text_end = rest.find("</rdf:li>")?;
+        let text = rest.get(text_start..text_end)?;
+        if text.contains('<') {
+            return None;
+        }
+        items.push(unescape(text.trim()));
+        rest = &rest[text_end..];
+    }
+    (!items.is_empty()).then(|| items.join("; "))
+}
+
+/// The bytes of a BYTE or UNDEFINED tag, which the decoder reads as different values.
+fn bytes(value: ifd::Value) -> Result<Vec<u8>> {
+    let items = match value {
+        ifd::Value::List(items) => items,
+        other => vec![other],
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            ifd::Value::Byte(byte) => Ok(byte),
+            other => Ok(other.into_u32()? as u8),
+        })
+        .collect()
+}
+
+/// Whether `key` matches any of `patterns`, in which `*` stands for any run of
+/// characters.
+pub fn key_matches(patterns: &[String], key: &str) -> bool {
+    fn matches(pattern: &str, key: &str) -> bool {
+        match pattern.split_once('*') {
+            None => pattern == key,
+            Some((prefix, rest)) => {
+                let Some(key) = key.strip_prefix(prefix) else {
+                    return false;
+                };
+                (0..=key.len())
+                    .filter(|&i| key.is_char_boundary(i))
+                    .any(|i| matches(rest, &key[i..]))
+            }
+        }
+    }
+    patterns.iter().any(|pattern| matches(pattern, key))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Builds the Arrow field metadata for an output column.
+pub fn column_metadata(
+    name: &str,
+    options: &Options,
+    source: &SourceMetadata,
+) -> HashMap<String, String> {
+    let binning = options.binning();
+    let position = match &binning {
+        None => match options.registration {
+            Align::Corner => "the pixel's top left corner".to_string(),
+            Align::Center => "the pixel's center".to_string(),
+        },
+        Some(Binning::Grid(grid)) => match grid.align {
+            Align::Corner => "the grid cell's lower left corner".to_string(),
+            Align::Center => "the grid cell's center".to_string(),
+        },
+        Some(Binning::S2(_)) => "the S2 cell's center".to_string(),
+        Some(Binning::H3(_)) => "the H3 cell's center".to_string(),
+        Some(Binning::Tile { .. }) => "the tile's center".to_string(),
+    };
+
+    let mut metadata = HashMap::new();
+    let mut set = |key: &str, value: String| {
+        metadata.insert(key.to_string(), value);
+    };
+    // The grid is recorded so that `--align-to` can group other inputs on it.
+    if let Some(Binning::Grid(grid)) = &binning {
+        let axis = match name {
+            "lon" | "easting" => Some((grid.size.lon, grid.origin.lon)),
+            "lat" | "northing" => Some((grid.size.lat, grid.origin.lat)),
+            _ => None,
+        };
+        if let Some((size, origin)) = axis {
+            set(align::GRID_SIZE, size.to_string());
+            set(align::GRID_ORIGIN, origin.to_string());
+        }
+    }
+    match name {
+        "lon" => {
+            set("unit", "degrees_east".into());
+            set("description", format!("Longitude of {}", position));
+        }
+        "lat" => {
+            set("unit", "degrees_north".into());
+            set("description", format!("Latitude of {}", position));
+        }
+        "easting" | "northing" => {
+            set("unit", "metre".into());
+            if let Some(crs) = options.dst_crs {
+                set("crs", crs.to_string());
+            }
+            let axis = if name == "easting" {
+                "Easting"
+            } else {
+                "Northing"
+            };
+            set("description", format!("{} of {}", axis, position));
+        }
+        "value" => {
+            if let Some(unit) = options
+                .unit
+                .clone()
+                .or(source.band_item("units").map(Into::into))
+            {
+                set("unit", unit);
+            }
+            let description = options
+                .description
+                .clone()
+                .or(source.band_item("description").map(Into::into))
+                .or(source
+                    .color
+                    .map(|_| "Pixel color packed as 0xRRGGBB".into()))
+                .or(source.description.clone());
+            if let Some(description) = description {
+                set("description", description);
+            }
+            set("source_band", (source.band + 1).to_string());
+            if let Some((scale, offset)) = options.scaling(source) {
+                set("scale", scale.to_string());
+                set("offset", offset.to_string());
+            }
+            if options.per_area_to_total {
+                set(
+                    "per_area_to_total",
+                    "densities per km² multiplied by each pixel's area in km²".into(),
+                );
+            }
+            let mut policy = match (options.keep_zero, options.min_value) {
+                (false, None) => "pixels with stored values <= 0 are dropped".to_string(),
+                (true, None) => "pixels with stored values < 0 are dropped".to_string(),
+                (false, Some(_)) => "pixels with stored values of 0 are dropped".to_string(),
+                (true, Some(_)) => "no pixels are dropped by their stored value".to_string(),
+            };
+            if let Some(min) = options.min_value {
+                policy = format!("{}; values below {} are dropped", policy, min);
+            }
+            if let Some(max) = options.max_value {
+                policy = format!("{}; values above {} are dropped", policy, max);
+            }
+            if let Some(nodata) = source.nodata_value() {
+                policy = format!(
+                    "{}; pixels of the nodata value {} are dropped",
+                    policy, nodata
+                );
+            }
+            if options.keep_nan {
+                policy = format!("{}; NaN and infinite pixels are kept as nulls", policy);
+            }
+            if options.dense {
+                policy = format!("{}; dropped pixels are kept as nulls", policy);
+            }
+            set("nodata_policy", policy);
+            if binning.is_some() {
+                set("aggregation", crate::explain::value_name(&options.agg));
+            }
+        }
+        "col" | "row" => {
+            let of = match options.resample.or(options.multires.map(|_| 1)) {
+                None => "the pixel",
+                Some(_) => "the block of pixels",
+            };
+            let axis = match name {
+                "col" => "Column",
+                _ => "Row",
+            };
+            set(
+                "description",
+                format!(
+                    "{} of {} in the image, counting from 0 at the top left",
+                    axis, of
+                ),
+            );
+        }
+        "s2_cell" => {
+            if let Some(Binning::S2(level)) = binning {
+                set("description", format!("S2 cell id at level {}", level));
+            }
+        }
+        "h3_cell" => {
+            if let Some(Binning::H3(resolution)) = binning {
+                set(
+                    "description",
+                    format!("H3 cell index at resolution {}", resolution),
+                );
+            }
+        }
+        "level" => {
+            set(
+                "description",
+                "Resolution level: 0 for native pixels, then blocks 2^level pixels across".into(),
+            );
+            set(
+                "aggregation",
+                crate::explain::value_name(&options.resample_method),
+            );
+        }
+        "stratum" => {
+            if let Some(path) = &options.stratify_by {
+                set(
+                    "description",
+                    format!(
+                        "Range of {} values the row's pixels fall in",
+                        path.display()
+                    ),
+                );
+            }
+        }
+        "class" => match &options.reclass {
+            Some(classes) => set(
+                "description",
+                format!("Class code of the row's value range, from {}", classes),
+            ),
+            None => set(
+                "description",
+                "Class whose pixels the row counts, as the value".into(),
+            ),
+        },
+        "r" | "g" | "b" => set(
+            "description",
+            format!(
+                "{} level of the pixel's color, from 0 to 255",
+                match name {
+                    "r" => "Red",
+                    "g" => "Green",
+                    _ => "Blue",
+                }
+            ),
+        ),
+        "label" => set(
+            "description",
+            "Name of the row's class, from --labels".into(),
+        ),
+        "color" => set(
+            "description",
+            "Color of the row's class in the tif's palette".into(),
+        ),
+        "distance_km" => {
+            if let Some(path) = &options.distance_to {
+                set(
+                    "description",
+                    format!(
+                        "Great-circle distance in km from the row's position to the nearest feature of {}",
+                        path.display()
+                    ),
+                );
+            }
+        }
+        "z" => set("description", "Web mercator tile zoom".into()),
+        "x" => set("description", "Web mercator tile column".into()),
+        "y" => set("description", "Web mercator tile row".into()),
+        "quadkey" => set("description", "Web mercator tile quadkey".into()),
+        "time" => set(
+            "description",
+            match options.time_from_filename {
+                Some(_) => "Time of the input, read from its file name",
+                None => "Time of the input, as given with --time",
+            }
+            .into(),
+        ),
+        "geohash" => set(
+            "description",
+            format!("Geohash of the row's lon/lat, i.e. of {}", position),
+        ),
+        _ => {}
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_matches, parse_gdal_metadata, parse_xmp, GdalItem, SourceMetadata};
+
+    #[test]
+    fn test_parse_gdal_metadata() {
+        let items = parse_gdal_metadata(
+            r#"<GDALMetadata>
+  <Item name="AREA_OR_POINT">Area</Item>
+  <Item name="units" sample="0" role="units">ships &amp; boats / km²</Item>
+</GDALMetadata>"#,
+        );
+        assert_eq!(
+            items,
+            vec![
+                GdalItem {
+                    name: "AREA_OR_POINT".into(),
+                    sample: None,
+                    role: None,
+                    value: "Area".into()
+                },
+                GdalItem {
+                    name: "units".into(),
+                    sample: Some(0),
+                    role: Some("units".into()),
+                    value: "ships & boats / km²".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scale_offset() {
+        let source = SourceMetadata {
+            gdal_items: parse_gdal_metadata(
+                r#"<GDALMetadata>
+  <Item name="SCALE" sample="0" role="scale">0.02</Item>
+  <Item name="OFFSET" sample="0" role="offset">-273.15</Item>
+</GDALMetadata>"#,
+            ),
+            ..Default::default()
+        };
+        assert_eq!(source.scale_offset(), (Some(0.02), Some(-273.15)));
+        assert_eq!(SourceMetadata::default().scale_offset(), (None, None));
+    }
+
+    #[test]
+    fn test_parse_xmp() {
+        let properties = parse_xmp(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF>
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreatorTool="GDAL &amp; co">
+    <dc:format>image/tiff</dc:format>
+    <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Density</rdf:li></rdf:Alt></dc:title>
+  </rdf:Description>
+</rdf:RDF></x:xmpmeta>"#,
+        );
+        assert_eq!(
+            properties,
+            vec![
+                ("xmp:CreatorTool".to_string(), "GDAL & co".to_string()),
+                ("dc:format".to_string(), "image/tiff".to_string()),
+                ("dc:title".to_string(), "Density".to_string()),
+            ]
+        );
+        let patterns = vec!["gdal:*".to_string(), "xmp:dc:*".to_string()];
+        assert!(key_matches(&patterns, "gdal:band1:units"));
+        assert!(key_matches(&patterns, "xmp:dc:title"));
+        assert!(!key_matches(&patterns, "xmp:xmp:CreatorTool"));
+        assert!(!key_matches(&["".to_string()], "gdal:SOURCE"));
+    }
+}
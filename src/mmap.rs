@@ -0,0 +1,172 @@
+//! Reading plain `.tif` inputs through a memory map with `--mmap`, so large uncompressed
+//! files are decoded straight from the page cache instead of being copied into memory
+//! first.
+
+use crate::{archive, load_tif_contents, sidecar, stdin};
+use anyhow::Result;
+use std::{ops::Deref, path::Path};
+
+/// A loaded tif: read into memory, mapped from its file, or piped on stdin.
+pub enum TifContents {
+    Owned(Vec<u8>),
+    Mapped(Mapping),
+    Stdin(&'static [u8]),
+}
+
+impl TifContents {
+    /// Loads `path` as [`load_tif_contents`] does, mapping it instead when `mmap` is set
+    /// and it is a plain `.tif`.
+    pub fn load(path: &Path, mmap: bool) -> Result<TifContents> {
+        // Sidecars are read as tags added to a copy of the tif.
+        let plain = path.extension().and_then(|e| e.to_str()) == Some("tif")
+            && archive::split(path).is_none()
+            && !sidecar::exists(path);
+        if mmap && plain {
+            return Ok(TifContents::Mapped(Mapping::open(path)?));
+        }
+        if stdin::is_stdin(path) {
+            let contents = stdin::contents()?;
+            if archive::Kind::sniff(contents).is_none() && !archive::is_gzip(contents) {
+                return Ok(TifContents::Stdin(contents));
+            }
+        }
+        Ok(TifContents::Owned(load_tif_contents(path)?))
+    }
+
+    /// Bytes of the process's own memory the contents take, which for a mapping is none:
+    /// its pages belong to the page cache and can be dropped and read again.
+    pub fn resident_bytes(&self) -> u64 {
+        match self {
+            TifContents::Owned(contents) => contents.len() as u64,
+            TifContents::Mapped(_) => 0,
+            TifContents::Stdin(contents) => contents.len() as u64,
+        }
+    }
+}
+
+impl Deref for TifContents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            TifContents::Owned(contents) => contents,
+            TifContents::Mapped(mapping) => mapping,
+            TifContents::Stdin(contents) => contents,
+        }
+    }
+}
+
+impl AsRef<[u8]> for TifContents {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// A file mapped read only. It must not be truncated while mapped, or reading the lost
+/// pages kills the process.
+#[cfg(unix)]
+pub struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read only, so it can be read from any thread.
+#[cfg(unix)]
+unsafe impl Send for Mapping {}
+#[cfg(unix)]
+unsafe impl Sync for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    fn open(path: &Path) -> Result<Mapping> {
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // Empty files can't be mapped, and hold no tif anyway.
+        if len == 0 {
+            return Ok(Mapping {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Mapping { ptr, len })
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+/// Where files can't be mapped, they are read into memory as without `--mmap`.
+#[cfg(not(unix))]
+pub struct Mapping(Vec<u8>);
+
+#[cfg(not(unix))]
+impl Mapping {
+    fn open(path: &Path) -> Result<Mapping> {
+        Ok(Mapping(std::fs::read(path)?))
+    }
+}
+
+#[cfg(not(unix))]
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TifContents;
+
+    #[test]
+    fn test_mapped_contents() {
+        let dir = std::env::temp_dir().join(format!("mmap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.tif");
+        std::fs::write(&path, b"II*\0 some bytes").unwrap();
+        let mapped = TifContents::load(&path, true).unwrap();
+        assert!(matches!(mapped, TifContents::Mapped(_)));
+        assert_eq!(&mapped[..], b"II*\0 some bytes");
+        assert_eq!(mapped.resident_bytes(), 0);
+        let read = TifContents::load(&path, false).unwrap();
+        assert_eq!(read.resident_bytes(), 15);
+        std::fs::write(dir.join("empty.tif"), b"").unwrap();
+        assert!(TifContents::load(&dir.join("empty.tif"), true)
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
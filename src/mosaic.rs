@@ -0,0 +1,194 @@
+//! Combining tiles of one product into a single table, placed by their georeferencing.
+
+use crate::{
+    crs::Crs,
+    failure::{Classified, FailureClass::BadInput},
+    georef::GeoTransform,
+    load_tif_contents,
+    output::{self, Compression},
+    raster::{self, ChunkSize, Layout},
+    sidecar, DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{bail, Context, Result};
+use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{collections::HashMap, io::Cursor, path::PathBuf, sync::Arc};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct MosaicArgs {
+    /// The tiles to combine. They must share a CRS and pixel size, with pixels that line up.
+    #[arg(required = true)]
+    tiles: Vec<PathBuf>,
+    /// Where to write the combined table.
+    #[arg(long = "output", short = 'o', default_value = "mosaic.parquet")]
+    output: PathBuf,
+    /// Which value a pixel covered by several tiles gets, such as one in the collar of
+    /// pixels neighbouring tiles often share, which adding up would count twice.
+    #[arg(long = "overlap", value_enum, default_value_t = Overlap::First)]
+    overlap: Overlap,
+    /// CRS of the tiles. Defaults to the one in their GeoKeys.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+    /// CRS to write positions in, EPSG:4326 unless given.
+    #[arg(long = "dst-crs")]
+    dst_crs: Option<Crs>,
+    #[command(flatten)]
+    compression: Compression,
+    /// Write output even when it looks like it will not fit on disk.
+    #[arg(long = "force")]
+    force: bool,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Overlap {
+    /// The value from the earliest tile given.
+    First,
+    /// The value from the latest tile given.
+    Last,
+    /// The mean of every tile's value.
+    Mean,
+    /// Fail, for tiles that should never overlap.
+    Error,
+}
+
+/// A pixel of the mosaic: its position, and the total and number of values it was given.
+struct Pixel {
+    x: f64,
+    y: f64,
+    total: f64,
+    count: u32,
+}
+
+impl Pixel {
+    /// Gives the pixel another tile's `value`, returning whether it already had one.
+    fn add(&mut self, value: f64, overlap: Overlap) -> Result<bool> {
+        let overlaps = self.count > 0;
+        match overlap {
+            Overlap::First if overlaps => {}
+            Overlap::Error if overlaps => bail!(Classified::new(
+                BadInput,
+                format!("Tiles overlap at {}, {}", self.x, self.y)
+            )),
+            Overlap::First | Overlap::Last | Overlap::Error => {
+                self.total = value;
+                self.count = 1;
+            }
+            Overlap::Mean => {
+                self.total += value;
+                self.count += 1;
+            }
+        }
+        Ok(overlaps)
+    }
+}
+
+pub fn run(args: &MosaicArgs) -> Result<()> {
+    let bar = ProgressBar::new(args.tiles.len() as u64);
+    bar.set_style(ProgressStyle::with_template(
+        "{prefix:<30} {msg} {pos}/{len} {elapsed_precise} {bar_wide}",
+    )?);
+    bar.set_prefix(args.output.to_string_lossy().to_string());
+    bar.set_message("placing tiles");
+
+    let dst_crs = args.dst_crs.unwrap_or(Crs::wgs84());
+    let mut grid: Option<GeoTransform> = None;
+    let mut pixels = HashMap::<(i64, i64), Pixel>::new();
+    let mut overlapping = 0;
+    for tile in &args.tiles {
+        let contents = load_tif_contents(tile)?;
+        let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+        let layout = Layout::from_decoder(&mut decoder)?;
+        let src_crs = sidecar::src_crs(tile, args.src_crs, &mut decoder)?;
+        let transform = GeoTransform::resolve(&mut decoder, src_crs, Some(dst_crs))?;
+        let (column, row) = match &grid {
+            None => (0, 0),
+            Some(grid) => grid
+                .grid_offset(&transform)
+                .with_context(|| format!("Could not place {}", tile.to_string_lossy()))?,
+        };
+        let values = raster::read_pixels(
+            &contents,
+            &layout,
+            ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+            |_, _, _, _| true,
+            |_| {},
+            |x, y, value| (value > 0.0 && value.is_finite()).then_some((x, y, value)),
+        )?;
+        for (x, y, value) in values {
+            let key = (column + x as i64, row + y as i64);
+            let pixel = pixels.entry(key).or_insert_with(|| {
+                let (x, y) = transform.position(x as f64, y as f64);
+                Pixel {
+                    x,
+                    y,
+                    total: 0.0,
+                    count: 0,
+                }
+            });
+            if pixel
+                .add(value, args.overlap)
+                .with_context(|| format!("Could not add {}", tile.to_string_lossy()))?
+            {
+                overlapping += 1;
+            }
+        }
+        grid.get_or_insert(transform);
+        bar.inc(1);
+    }
+    let Some(grid) = grid else {
+        bail!("No tiles given");
+    };
+
+    let mut pixels: Vec<((i64, i64), Pixel)> = pixels.into_iter().collect();
+    pixels.sort_by_key(|((column, row), _)| (*row, *column));
+    let (x_name, y_name) = match grid.crs() {
+        Some((_, dst)) if !dst.is_geographic() => ("easting", "northing"),
+        _ => ("lon", "lat"),
+    };
+    let column = |f: fn(&Pixel) -> f64| {
+        Arc::new(Float32Array::from_iter_values(
+            pixels.iter().map(|(_, p)| f(p) as f32),
+        )) as ArrayRef
+    };
+    let batch = RecordBatch::try_from_iter([
+        (x_name, column(|p| p.x)),
+        (y_name, column(|p| p.y)),
+        ("value", column(|p| p.total / p.count as f64)),
+    ])?;
+
+    let estimate = output::estimate_parquet_size(&batch, args.compression.codec);
+    output::check_free_space(&args.output, estimate, args.force)?;
+    bar.set_message("writing parquet");
+    output::write_parquet(&args.output, &batch, args.compression)?;
+    match overlapping {
+        0 => bar.finish_with_message("done"),
+        n => bar.finish_with_message(format!("done, {} overlapping pixels resolved", n)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overlap, Pixel};
+
+    #[test]
+    fn test_overlap() {
+        let resolve = |overlap| {
+            let mut pixel = Pixel {
+                x: 0.0,
+                y: 0.0,
+                total: 0.0,
+                count: 0,
+            };
+            assert!(!pixel.add(1.0, overlap).unwrap());
+            let overlaps = pixel.add(3.0, overlap)?;
+            assert!(overlaps);
+            Ok::<_, anyhow::Error>(pixel.total / pixel.count as f64)
+        };
+        assert_eq!(resolve(Overlap::First).unwrap(), 1.0);
+        assert_eq!(resolve(Overlap::Last).unwrap(), 3.0);
+        assert_eq!(resolve(Overlap::Mean).unwrap(), 2.0);
+        assert!(resolve(Overlap::Error).is_err());
+    }
+}
@@ -0,0 +1,578 @@
+//! Mapbox Vector Tile output: every row drawn into the web mercator tiles it touches at
+//! each zoom, written as a `z/x/y.mvt` directory or a single MBTiles file.
+
+use crate::{
+    crs::Crs,
+    geometry::{self, GeometryKind, Pixel},
+    group::Binning,
+    json::Value as Json,
+    tile,
+    time::iso8601,
+};
+use anyhow::{bail, Result};
+use arrow_array::{
+    Array, Float32Array, Float64Array, RecordBatch, StringArray, TimestampSecondArray, UInt32Array,
+    UInt64Array, UInt8Array,
+};
+use arrow_schema::{DataType, TimeUnit};
+use rusqlite::{params, Connection};
+use std::{collections::HashMap, ops::RangeInclusive, path::Path};
+
+/// Size of a tile in its own integer coordinates, the MVT default.
+const EXTENT: u32 = 4096;
+
+/// How far past a tile's edges polygons are kept, so renderers don't draw seams.
+const BUFFER: f64 = 64.0;
+
+// Enum values and field numbers from the vector tile protobuf schema.
+const GEOM_POINT: u64 = 1;
+const GEOM_POLYGON: u64 = 3;
+const COMMAND_MOVE_TO: u32 = 1;
+const COMMAND_LINE_TO: u32 = 2;
+const COMMAND_CLOSE_PATH: u32 = 7;
+
+/// Where the tiles are written.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileStore {
+    /// A directory of `z/x/y.mvt` files.
+    Directory,
+    /// An MBTiles SQLite file.
+    MbTiles,
+}
+
+/// Writes every row of the batch into the tiles of each zoom in `zooms`. The geometry
+/// comes from the first two columns, the row's position in `crs`, and all columns become
+/// properties of a single `layer`.
+///
+/// Geometries are snapped to the tile grid, so polygons smaller than a tile unit at low
+/// zooms are left out of those tiles. With `max_features`, tiles holding more features
+/// than that keep only the ones with the largest values.
+#[allow(clippy::too_many_arguments)]
+pub fn write_tiles(
+    path: &Path,
+    store: TileStore,
+    layer: &str,
+    batch: &RecordBatch,
+    kind: GeometryKind,
+    binning: Option<&Binning>,
+    pixel: Pixel,
+    crs: Crs,
+    zooms: RangeInclusive<u8>,
+    max_features: Option<usize>,
+) -> Result<()> {
+    let column = |index: usize| -> Result<&Float32Array> {
+        match batch
+            .columns()
+            .get(index)
+            .and_then(|c| c.as_any().downcast_ref::<Float32Array>())
+        {
+            Some(array) => Ok(array),
+            None => bail!("Output has no position column {}", index),
+        }
+    };
+    let (xs, ys, values) = (column(0)?, column(1)?, column(2)?);
+    for array in batch.columns() {
+        value_type(array.data_type())?;
+    }
+
+    let rings: Vec<Vec<(f64, f64)>> = (0..batch.num_rows())
+        .map(|row| {
+            let (x, y) = (xs.value(row) as f64, ys.value(row) as f64);
+            let ring = match kind {
+                GeometryKind::Point => vec![(x, y)],
+                GeometryKind::Cell => geometry::footprint(x, y, binning, pixel),
+            };
+            ring.into_iter()
+                .map(|(x, y)| crs.to_lon_lat(x, y))
+                .collect()
+        })
+        .collect();
+    let mut bounds = [
+        f64::INFINITY,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        f64::NEG_INFINITY,
+    ];
+    for &(lon, lat) in rings.iter().flatten() {
+        bounds = [
+            bounds[0].min(lon),
+            bounds[1].min(lat),
+            bounds[2].max(lon),
+            bounds[3].max(lat),
+        ];
+    }
+
+    if rings.is_empty() {
+        bounds = [-180.0, -85.0, 180.0, 85.0];
+    }
+
+    let mut sink = match store {
+        TileStore::Directory => Sink::Directory(path),
+        TileStore::MbTiles => Sink::MbTiles(create_mbtiles(path)?),
+    };
+    for zoom in zooms.clone() {
+        let mut tiles = HashMap::<(u32, u32), Vec<(usize, TileGeometry)>>::new();
+        for (row, ring) in rings.iter().enumerate() {
+            let world: Vec<(f64, f64)> = ring
+                .iter()
+                .map(|&(lon, lat)| {
+                    let (x, y) = tile::tile_coordinates(lon, lat, zoom);
+                    (x * EXTENT as f64, y * EXTENT as f64)
+                })
+                .collect();
+            for (x, y, geometry) in clip_to_tiles(&world, zoom) {
+                tiles.entry((x, y)).or_default().push((row, geometry));
+            }
+        }
+        for ((x, y), mut features) in tiles {
+            if let Some(max) = max_features.filter(|&max| features.len() > max) {
+                features.sort_by(|a, b| values.value(b.0).total_cmp(&values.value(a.0)));
+                features.truncate(max);
+            }
+            let mut builder = LayerBuilder::new(layer, batch);
+            for (row, geometry) in &features {
+                builder.add(batch, *row, geometry);
+            }
+            sink.write(zoom, x, y, &builder.finish())?;
+        }
+    }
+
+    if let Sink::MbTiles(connection) = sink {
+        let schema = batch.schema();
+        let fields = schema.fields().iter().map(|field| {
+            let kind = match field.data_type() {
+                DataType::Utf8 | DataType::Timestamp(..) => "String",
+                _ => "Number",
+            };
+            (field.name().clone(), Json::from(kind))
+        });
+        let vector_layers = Json::object([(
+            "vector_layers",
+            Json::Array(vec![Json::object([
+                ("id", Json::from(layer)),
+                ("fields", Json::object(fields)),
+                ("minzoom", (*zooms.start() as u32).into()),
+                ("maxzoom", (*zooms.end() as u32).into()),
+            ])]),
+        )]);
+        let center_zoom = *zooms.start();
+        let metadata = [
+            ("name", layer.to_string()),
+            ("format", "pbf".to_string()),
+            ("type", "overlay".to_string()),
+            ("minzoom", zooms.start().to_string()),
+            ("maxzoom", zooms.end().to_string()),
+            (
+                "bounds",
+                format!("{},{},{},{}", bounds[0], bounds[1], bounds[2], bounds[3]),
+            ),
+            (
+                "center",
+                format!(
+                    "{},{},{}",
+                    (bounds[0] + bounds[2]) / 2.0,
+                    (bounds[1] + bounds[3]) / 2.0,
+                    center_zoom
+                ),
+            ),
+            ("json", vector_layers.to_string()),
+        ];
+        for (name, value) in metadata {
+            connection.execute("INSERT INTO metadata VALUES (?1, ?2)", [name, &value])?;
+        }
+        connection.execute_batch("COMMIT")?;
+    }
+    Ok(())
+}
+
+/// A rough guess at the combined size of the tiles, for the free space check. Points
+/// land in one tile per zoom, and cells mostly do too.
+pub fn estimate_size(batch: &RecordBatch, kind: GeometryKind, zooms: RangeInclusive<u8>) -> u64 {
+    let geometry = match kind {
+        GeometryKind::Point => 4,
+        GeometryKind::Cell => 16,
+    };
+    // Each feature's id, type and tags, plus roughly one distinct value per property.
+    let per_feature = 8 + geometry + 10 * batch.num_columns() as u64;
+    let zoom_count = zooms.count() as u64;
+    batch.num_rows() as u64 * per_feature * zoom_count + 4096
+}
+
+enum Sink<'a> {
+    Directory(&'a Path),
+    MbTiles(Connection),
+}
+
+impl Sink<'_> {
+    fn write(&mut self, zoom: u8, x: u32, y: u32, data: &[u8]) -> Result<()> {
+        match self {
+            Sink::Directory(path) => {
+                let dir = path.join(zoom.to_string()).join(x.to_string());
+                std::fs::create_dir_all(&dir)?;
+                std::fs::write(dir.join(format!("{}.mvt", y)), data)?;
+            }
+            Sink::MbTiles(connection) => {
+                // MBTiles numbers rows from the south, as in TMS.
+                let row = (1u32 << zoom) - 1 - y;
+                connection
+                    .prepare_cached("INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)")?
+                    .execute(params![zoom, x, row, data])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn create_mbtiles(path: &Path) -> Result<Connection> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let connection = Connection::open(path)?;
+    connection.execute_batch(
+        "BEGIN;
+         CREATE TABLE metadata (name TEXT, value TEXT);
+         CREATE TABLE tiles (
+             zoom_level INTEGER,
+             tile_column INTEGER,
+             tile_row INTEGER,
+             tile_data BLOB
+         );
+         CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+    )?;
+    Ok(connection)
+}
+
+/// A feature's geometry in one tile's coordinates.
+enum TileGeometry {
+    Point(i32, i32),
+    Polygon(Vec<(i32, i32)>),
+}
+
+/// Finds the tiles at `zoom` a point or ring, in world coordinates of `EXTENT` units per
+/// tile, falls in, and returns its geometry in each of them.
+fn clip_to_tiles(world: &[(f64, f64)], zoom: u8) -> Vec<(u32, u32, TileGeometry)> {
+    let tile_count = 1i64 << zoom;
+    let extent = EXTENT as f64;
+    if let [(x, y)] = world {
+        let (tile_x, tile_y) = ((x / extent).floor() as i64, (y / extent).floor() as i64);
+        if !(0..tile_count).contains(&tile_x) || !(0..tile_count).contains(&tile_y) {
+            return vec![];
+        }
+        let local = |v: f64, tile: i64| (v - (tile as f64 * extent)).round() as i32;
+        return vec![(
+            tile_x as u32,
+            tile_y as u32,
+            TileGeometry::Point(local(*x, tile_x), local(*y, tile_y)),
+        )];
+    }
+
+    let tile_range = |values: &mut dyn Iterator<Item = f64>| {
+        let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+        let first = ((min - BUFFER) / extent).floor() as i64;
+        let last = ((max + BUFFER) / extent).floor() as i64;
+        first.max(0)..=last.min(tile_count - 1)
+    };
+    let mut clipped = vec![];
+    for tile_y in tile_range(&mut world.iter().map(|p| p.1)) {
+        for tile_x in tile_range(&mut world.iter().map(|p| p.0)) {
+            let (offset_x, offset_y) = (tile_x as f64 * extent, tile_y as f64 * extent);
+            let local: Vec<(f64, f64)> = world
+                .iter()
+                .map(|&(x, y)| (x - offset_x, y - offset_y))
+                .collect();
+            let ring = clip_ring(&local, -BUFFER, extent + BUFFER);
+            if let Some(ring) = quantize_ring(&ring) {
+                clipped.push((tile_x as u32, tile_y as u32, TileGeometry::Polygon(ring)));
+            }
+        }
+    }
+    clipped
+}
+
+/// Clips a closed ring to the square from `min` to `max` on both axes, with one
+/// Sutherland-Hodgman pass per edge. The result is not closed.
+fn clip_ring(ring: &[(f64, f64)], min: f64, max: f64) -> Vec<(f64, f64)> {
+    let mut points: Vec<(f64, f64)> = match ring {
+        [rest @ .., last] if ring.first() == Some(last) => rest.to_vec(),
+        _ => ring.to_vec(),
+    };
+    // Each edge as the axis it bounds, the bound, and whether points must be below it.
+    for (axis, bound, below) in [
+        (0, min, false),
+        (0, max, true),
+        (1, min, false),
+        (1, max, true),
+    ] {
+        let get = |p: (f64, f64)| if axis == 0 { p.0 } else { p.1 };
+        let inside = |p: (f64, f64)| (get(p) <= bound) == below || get(p) == bound;
+        let mut next = vec![];
+        for (i, &current) in points.iter().enumerate() {
+            let previous = points[(i + points.len() - 1) % points.len()];
+            if inside(current) != inside(previous) {
+                let t = (bound - get(previous)) / (get(current) - get(previous));
+                next.push((
+                    previous.0 + t * (current.0 - previous.0),
+                    previous.1 + t * (current.1 - previous.1),
+                ));
+            }
+            if inside(current) {
+                next.push(current);
+            }
+        }
+        points = next;
+    }
+    points
+}
+
+/// Snaps an unclosed ring to integer tile coordinates, dropping repeated points, or
+/// returns `None` if nothing with any area is left. The ring is turned to have the
+/// positive area MVT wants for outer rings, which is clockwise with y pointing down.
+fn quantize_ring(ring: &[(f64, f64)]) -> Option<Vec<(i32, i32)>> {
+    let mut points: Vec<(i32, i32)> = vec![];
+    for &(x, y) in ring {
+        let point = (x.round() as i32, y.round() as i32);
+        if points.last() != Some(&point) {
+            points.push(point);
+        }
+    }
+    while points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let doubled_area: i64 = (0..points.len())
+        .map(|i| {
+            let (x0, y0) = points[i];
+            let (x1, y1) = points[(i + 1) % points.len()];
+            x0 as i64 * y1 as i64 - x1 as i64 * y0 as i64
+        })
+        .sum();
+    if doubled_area < 0 {
+        points.reverse();
+    }
+    (points.len() >= 3 && doubled_area != 0).then_some(points)
+}
+
+/// One tile's layer, with the keys and de-duplicated values its features' tags point to.
+struct LayerBuilder {
+    name: String,
+    keys: Vec<String>,
+    values: Vec<Vec<u8>>,
+    value_indices: HashMap<Vec<u8>, u32>,
+    features: Vec<u8>,
+}
+
+impl LayerBuilder {
+    fn new(name: &str, batch: &RecordBatch) -> LayerBuilder {
+        LayerBuilder {
+            name: name.to_string(),
+            keys: batch
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| f.name().clone())
+                .collect(),
+            values: vec![],
+            value_indices: HashMap::new(),
+            features: vec![],
+        }
+    }
+
+    fn add(&mut self, batch: &RecordBatch, row: usize, geometry: &TileGeometry) {
+        let mut tags = vec![];
+        for (key, array) in batch.columns().iter().enumerate() {
+            let value = encode_value(array.as_ref(), row);
+            let next = self.values.len() as u32;
+            let index = *self.value_indices.entry(value.clone()).or_insert(next);
+            if index == next {
+                self.values.push(value);
+            }
+            write_varint(&mut tags, key as u64);
+            write_varint(&mut tags, index as u64);
+        }
+
+        let (geometry_type, commands) = match geometry {
+            TileGeometry::Point(x, y) => (
+                GEOM_POINT,
+                vec![command(COMMAND_MOVE_TO, 1), zigzag(*x), zigzag(*y)],
+            ),
+            TileGeometry::Polygon(ring) => {
+                let mut commands = vec![command(COMMAND_MOVE_TO, 1)];
+                let mut cursor = (0, 0);
+                for (i, &(x, y)) in ring.iter().enumerate() {
+                    if i == 1 {
+                        commands.push(command(COMMAND_LINE_TO, ring.len() as u32 - 1));
+                    }
+                    commands.extend([zigzag(x - cursor.0), zigzag(y - cursor.1)]);
+                    cursor = (x, y);
+                }
+                commands.push(command(COMMAND_CLOSE_PATH, 1));
+                (GEOM_POLYGON, commands)
+            }
+        };
+        let mut packed = vec![];
+        for c in commands {
+            write_varint(&mut packed, c as u64);
+        }
+
+        let mut feature = vec![];
+        write_varint_field(&mut feature, 1, row as u64);
+        write_bytes_field(&mut feature, 2, &tags);
+        write_varint_field(&mut feature, 3, geometry_type);
+        write_bytes_field(&mut feature, 4, &packed);
+        write_bytes_field(&mut self.features, 2, &feature);
+    }
+
+    /// Encodes the `Tile` message holding just this layer.
+    fn finish(self) -> Vec<u8> {
+        let mut layer = vec![];
+        write_varint_field(&mut layer, 15, 2);
+        write_bytes_field(&mut layer, 1, self.name.as_bytes());
+        layer.extend(self.features);
+        for key in &self.keys {
+            write_bytes_field(&mut layer, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            write_bytes_field(&mut layer, 4, value);
+        }
+        write_varint_field(&mut layer, 5, EXTENT as u64);
+        let mut tile = vec![];
+        write_bytes_field(&mut tile, 3, &layer);
+        tile
+    }
+}
+
+fn value_type(data_type: &DataType) -> Result<()> {
+    match data_type {
+        DataType::UInt8
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Utf8
+        | DataType::Timestamp(TimeUnit::Second, _) => Ok(()),
+        other => bail!("Cannot write {} columns to vector tiles", other),
+    }
+}
+
+/// Encodes a cell as a `Value` message. The array's type must be one `value_type`
+/// accepts.
+fn encode_value(array: &dyn Array, row: usize) -> Vec<u8> {
+    fn typed<T: 'static>(array: &dyn Array) -> &T {
+        array
+            .as_any()
+            .downcast_ref()
+            .expect("checked by value_type")
+    }
+    let mut out = vec![];
+    match array.data_type() {
+        DataType::UInt8 => {
+            write_varint_field(&mut out, 5, typed::<UInt8Array>(array).value(row) as u64)
+        }
+        DataType::UInt32 => {
+            write_varint_field(&mut out, 5, typed::<UInt32Array>(array).value(row) as u64)
+        }
+        DataType::UInt64 => write_varint_field(&mut out, 5, typed::<UInt64Array>(array).value(row)),
+        DataType::Float32 => {
+            out.push((2 << 3) | 5);
+            out.extend(typed::<Float32Array>(array).value(row).to_le_bytes());
+        }
+        DataType::Float64 => {
+            out.push((3 << 3) | 1);
+            out.extend(typed::<Float64Array>(array).value(row).to_le_bytes());
+        }
+        DataType::Utf8 => write_bytes_field(
+            &mut out,
+            1,
+            typed::<StringArray>(array).value(row).as_bytes(),
+        ),
+        DataType::Timestamp(TimeUnit::Second, _) => write_bytes_field(
+            &mut out,
+            1,
+            iso8601(typed::<TimestampSecondArray>(array).value(row)).as_bytes(),
+        ),
+        _ => unreachable!("checked by value_type"),
+    }
+    out
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn write_varint_field(out: &mut Vec<u8>, field: u64, v: u64) {
+    write_varint(out, field << 3);
+    write_varint(out, v);
+}
+
+fn write_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(out, (field << 3) | 2);
+    write_varint(out, bytes.len() as u64);
+    out.extend(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clip_ring, clip_to_tiles, quantize_ring, zigzag, TileGeometry, EXTENT};
+
+    #[test]
+    fn test_zigzag() {
+        assert_eq!([0, -1, 1, -2, 2].map(zigzag), [0, 1, 2, 3, 4],);
+    }
+
+    #[test]
+    fn test_clip_ring() {
+        let square = [
+            (-10.0, -10.0),
+            (10.0, -10.0),
+            (10.0, 10.0),
+            (-10.0, 10.0),
+            (-10.0, -10.0),
+        ];
+        let clipped = clip_ring(&square, 0.0, 100.0);
+        assert_eq!(
+            clipped,
+            [(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)]
+        );
+        assert_eq!(
+            quantize_ring(&[(0.0, 10.0), (10.0, 10.0), (10.0, 0.0)]),
+            Some(vec![(10, 0), (10, 10), (0, 10)])
+        );
+        assert_eq!(quantize_ring(&clip_ring(&square, 20.0, 100.0)), None);
+        assert_eq!(quantize_ring(&[(0.2, 0.0), (0.4, 0.1), (0.1, 0.3)]), None);
+    }
+
+    #[test]
+    fn test_clip_to_tiles() {
+        // A ring straddling the corner shared by the four tiles of zoom 1.
+        let e = EXTENT as f64;
+        let ring = [
+            (e - 10.0, e - 10.0),
+            (e + 10.0, e - 10.0),
+            (e + 10.0, e + 10.0),
+            (e - 10.0, e - 10.0),
+        ];
+        let mut tiles: Vec<_> = clip_to_tiles(&ring, 1)
+            .into_iter()
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        tiles.sort();
+        assert_eq!(tiles, [(0, 0), (0, 1), (1, 0), (1, 1)]);
+
+        let point = clip_to_tiles(&[(e + 1.4, 2.6)], 1);
+        assert!(matches!(point[..], [(1, 0, TileGeometry::Point(1, 3))]));
+        assert!(clip_to_tiles(&[(-1.0, 0.0)], 1).is_empty());
+    }
+}
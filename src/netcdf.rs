@@ -0,0 +1,627 @@
+//! Reading grids from NetCDF files, in the classic format and its 64-bit variants, by
+//! turning one 2D slice of a variable into a tif the rest of the conversion reads like any
+//! other. Its regularly spaced coordinate variables become the tif's GeoTIFF tags, and its
+//! `units`, `long_name`, `scale_factor` and `add_offset` attributes GDAL metadata items.
+//! NetCDF-4 files are HDF5 underneath, and can't be read.
+
+use crate::{
+    crs::Crs,
+    failure::{
+        Classified,
+        FailureClass::{BadInput, Unsupported},
+    },
+    grid::Grid,
+};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+const DIMENSION: u32 = 0x0a;
+const VARIABLE: u32 = 0x0b;
+const ATTRIBUTE: u32 = 0x0c;
+
+/// Whether `path` is a NetCDF file, by its extension.
+pub fn is_netcdf(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("nc"))
+}
+
+/// The types NetCDF stores values as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Byte,
+    Char,
+    Short,
+    Int,
+    Float,
+    Double,
+    UByte,
+    UShort,
+    UInt,
+    Int64,
+    UInt64,
+}
+
+impl Type {
+    fn from_code(code: u32) -> Result<Type> {
+        Ok(match code {
+            1 => Type::Byte,
+            2 => Type::Char,
+            3 => Type::Short,
+            4 => Type::Int,
+            5 => Type::Float,
+            6 => Type::Double,
+            7 => Type::UByte,
+            8 => Type::UShort,
+            9 => Type::UInt,
+            10 => Type::Int64,
+            11 => Type::UInt64,
+            code => bail!(Classified::new(
+                BadInput,
+                format!("Unknown NetCDF type {}", code)
+            )),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Type::Byte | Type::Char | Type::UByte => 1,
+            Type::Short | Type::UShort => 2,
+            Type::Int | Type::UInt | Type::Float => 4,
+            Type::Double | Type::Int64 | Type::UInt64 => 8,
+        }
+    }
+
+    /// Reads one big-endian value.
+    fn read(self, bytes: &[u8]) -> f64 {
+        match self {
+            Type::Byte => bytes[0] as i8 as f64,
+            Type::Char | Type::UByte => bytes[0] as f64,
+            Type::Short => i16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            Type::UShort => u16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            Type::Int => i32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Type::UInt => u32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Type::Float => f32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Type::Double => f64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            Type::Int64 => i64::from_be_bytes(bytes[..8].try_into().unwrap()) as f64,
+            Type::UInt64 => u64::from_be_bytes(bytes[..8].try_into().unwrap()) as f64,
+        }
+    }
+
+    /// The value unwritten values are filled with when a variable declares no
+    /// `_FillValue`.
+    fn default_fill(self) -> f64 {
+        match self {
+            Type::Byte => -127.0,
+            Type::Char => 0.0,
+            Type::Short => -32767.0,
+            Type::Int => -2147483647.0,
+            Type::Float => f32::from_bits(0x7cf0_0000) as f64,
+            Type::Double => 9.969_209_968_386_869e36,
+            Type::UByte => 255.0,
+            Type::UShort => 65535.0,
+            Type::UInt => 4294967295.0,
+            Type::Int64 => -9223372036854775806.0,
+            Type::UInt64 => 18446744073709551614.0,
+        }
+    }
+}
+
+enum Attribute {
+    Text(String),
+    Numbers(Vec<f64>),
+}
+
+struct Dimension {
+    name: String,
+    /// 0 for the record dimension, whose length is the number of records.
+    len: u64,
+}
+
+struct Variable {
+    name: String,
+    dims: Vec<usize>,
+    attributes: Vec<(String, Attribute)>,
+    kind: Type,
+    vsize: u64,
+    begin: u64,
+}
+
+impl Variable {
+    fn text(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find_map(|(n, a)| match a {
+            Attribute::Text(text) if n == name => Some(text.as_str()),
+            _ => None,
+        })
+    }
+
+    fn number(&self, name: &str) -> Option<f64> {
+        self.attributes.iter().find_map(|(n, a)| match a {
+            Attribute::Numbers(numbers) if n == name => numbers.first().copied(),
+            _ => None,
+        })
+    }
+}
+
+struct Header {
+    records: u64,
+    dims: Vec<Dimension>,
+    vars: Vec<Variable>,
+}
+
+/// Reads the header, whose sizes and offsets are 32 or 64 bits by the format's version.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u8,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: u64) -> Result<&'a [u8]> {
+        let end = (self.pos as u64).saturating_add(n).min(usize::MAX as u64) as usize;
+        let Some(bytes) = self.data.get(self.pos..end) else {
+            bail!(Classified::new(BadInput, "The NetCDF header is truncated"));
+        };
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A count or length, 64 bits in CDF-5.
+    fn count(&mut self) -> Result<u64> {
+        match self.version {
+            5 => self.u64(),
+            _ => Ok(self.u32()? as u64),
+        }
+    }
+
+    /// A file offset, 64 bits after CDF-1.
+    fn offset(&mut self) -> Result<u64> {
+        match self.version {
+            1 => Ok(self.u32()? as u64),
+            _ => self.u64(),
+        }
+    }
+
+    /// Bytes padded to a multiple of four.
+    fn padded(&mut self, n: u64) -> Result<&'a [u8]> {
+        Ok(&self.bytes(n.saturating_add(3) / 4 * 4)?[..n as usize])
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let n = self.count()?;
+        Ok(String::from_utf8_lossy(self.padded(n)?).into_owned())
+    }
+
+    /// The length of a list with the tag `expected`, which is 0 if it's absent.
+    fn list(&mut self, expected: u32) -> Result<u64> {
+        let tag = self.u32()?;
+        let n = self.count()?;
+        if tag != expected && !(tag == 0 && n == 0) {
+            bail!(Classified::new(BadInput, "The NetCDF header is malformed"));
+        }
+        Ok(n)
+    }
+
+    fn attributes(&mut self) -> Result<Vec<(String, Attribute)>> {
+        (0..self.list(ATTRIBUTE)?)
+            .map(|_| {
+                let name = self.name()?;
+                let kind = Type::from_code(self.u32()?)?;
+                let n = self.count()?;
+                let bytes = self.padded(n.saturating_mul(kind.size() as u64))?;
+                let value = match kind {
+                    Type::Char => Attribute::Text(
+                        String::from_utf8_lossy(bytes)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    ),
+                    _ => Attribute::Numbers(
+                        bytes
+                            .chunks_exact(kind.size())
+                            .map(|b| kind.read(b))
+                            .collect(),
+                    ),
+                };
+                Ok((name, value))
+            })
+            .collect()
+    }
+}
+
+fn parse(data: &[u8]) -> Result<Header> {
+    let version = match data {
+        [b'C', b'D', b'F', version @ (1 | 2 | 5), ..] => *version,
+        [0x89, b'H', b'D', b'F', ..] => bail!(Classified::new(
+            Unsupported,
+            "NetCDF-4 files can't be read, only the classic format; convert with \
+             `nccopy -k classic`"
+        )),
+        _ => bail!(Classified::new(BadInput, "Not a NetCDF file")),
+    };
+    let mut reader = Reader {
+        data,
+        pos: 4,
+        version,
+    };
+    let records = reader.count()?;
+    let dims = (0..reader.list(DIMENSION)?)
+        .map(|_| {
+            Ok(Dimension {
+                name: reader.name()?,
+                len: reader.count()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    reader.attributes()?;
+    let vars = (0..reader.list(VARIABLE)?)
+        .map(|_| {
+            let name = reader.name()?;
+            let dims = (0..reader.count()?)
+                .map(|_| Ok(reader.count()? as usize))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Variable {
+                name,
+                dims,
+                attributes: reader.attributes()?,
+                kind: Type::from_code(reader.u32()?)?,
+                vsize: reader.count()?,
+                begin: reader.offset()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if vars.iter().flat_map(|v| &v.dims).any(|&d| d >= dims.len()) {
+        bail!(Classified::new(BadInput, "The NetCDF header is malformed"));
+    }
+    Ok(Header {
+        // Files still being written mark the number of records as unknown.
+        records: match records {
+            0xffff_ffff => 0,
+            records => records,
+        },
+        dims,
+        vars,
+    })
+}
+
+impl Header {
+    fn len(&self, dim: usize) -> u64 {
+        match self.dims[dim].len {
+            0 => self.records,
+            len => len,
+        }
+    }
+
+    fn is_record(&self, var: &Variable) -> bool {
+        var.dims.first().is_some_and(|&d| self.dims[d].len == 0)
+    }
+
+    /// Bytes between one record of a variable and the next, as the records of all record
+    /// variables are interleaved.
+    fn record_size(&self) -> u64 {
+        let record_vars = self
+            .vars
+            .iter()
+            .filter(|v| self.is_record(v))
+            .collect::<Vec<_>>();
+        match record_vars[..] {
+            // A lone record variable's records aren't padded.
+            [var] => {
+                var.dims[1..].iter().map(|&d| self.len(d)).product::<u64>() * var.kind.size() as u64
+            }
+            _ => record_vars.iter().map(|v| v.vsize).sum(),
+        }
+    }
+
+    /// Reads `count` values of `var` from `start`, counting in row-major order.
+    fn read(&self, data: &[u8], var: &Variable, start: u64, count: u64) -> Result<Vec<f64>> {
+        let size = var.kind.size() as u64;
+        let per_record = var
+            .dims
+            .iter()
+            .skip(1)
+            .map(|&d| self.len(d))
+            .product::<u64>();
+        let record_size = self.record_size();
+        if count.saturating_mul(size) > data.len() as u64 {
+            bail!(Classified::new(
+                BadInput,
+                format!("The NetCDF file is truncated in {}", var.name)
+            ));
+        }
+        let mut values = Vec::with_capacity(count as usize);
+        let mut index = start;
+        while (values.len() as u64) < count {
+            let (offset, run) = match self.is_record(var) {
+                true => (
+                    var.begin + index / per_record * record_size + index % per_record * size,
+                    per_record - index % per_record,
+                ),
+                false => (var.begin + index * size, u64::MAX),
+            };
+            let run = run.min(count - values.len() as u64);
+            let Some(bytes) = data.get(offset as usize..(offset + run * size) as usize) else {
+                bail!(Classified::new(
+                    BadInput,
+                    format!("The NetCDF file is truncated in {}", var.name)
+                ));
+            };
+            values.extend(bytes.chunks_exact(size as usize).map(|b| var.kind.read(b)));
+            index += run;
+        }
+        Ok(values)
+    }
+
+    /// The variables holding grids: those of two or more dimensions, other than the
+    /// bounds of coordinates.
+    fn grids(&self) -> Vec<&Variable> {
+        let bounds = self
+            .vars
+            .iter()
+            .filter_map(|v| v.text("bounds"))
+            .collect::<Vec<_>>();
+        self.vars
+            .iter()
+            .filter(|v| v.dims.len() >= 2 && v.kind != Type::Char && !bounds.contains(&&*v.name))
+            .collect()
+    }
+
+    fn variable(&self, name: Option<&str>) -> Result<&Variable> {
+        let grids = self.grids();
+        let names = || {
+            grids
+                .iter()
+                .map(|v| v.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if let Some(name) = name {
+            return match grids.iter().find(|v| v.name == name) {
+                Some(var) => Ok(var),
+                None => bail!(Classified::new(
+                    BadInput,
+                    format!("The NetCDF file has no grid {}, only {}", name, names())
+                )),
+            };
+        }
+        match grids[..] {
+            [var] => Ok(var),
+            [] => bail!(Classified::new(BadInput, "The NetCDF file holds no grids")),
+            _ => bail!(Classified::new(
+                BadInput,
+                format!(
+                    "The NetCDF file holds several grids, pick one with --variable: {}",
+                    names()
+                )
+            )),
+        }
+    }
+
+    /// The first value and spacing of the coordinate variable of `dim`, if it has one.
+    fn axis(&self, data: &[u8], dim: usize) -> Result<Option<(&Variable, f64, f64)>> {
+        let name = &self.dims[dim].name;
+        let Some(var) = self
+            .vars
+            .iter()
+            .find(|v| v.dims == [dim] && &v.name == name)
+        else {
+            return Ok(None);
+        };
+        let values = self.read(data, var, 0, self.len(dim))?;
+        let [first, .., last] = values[..] else {
+            bail!(Classified::new(
+                Unsupported,
+                format!("{} has a single coordinate, too few for its spacing", name)
+            ));
+        };
+        let step = (last - first) / (values.len() - 1) as f64;
+        let regular = values
+            .iter()
+            .enumerate()
+            .all(|(i, &v)| (v - (first + i as f64 * step)).abs() <= step.abs() * 1e-3);
+        if step == 0.0 || !regular {
+            bail!(Classified::new(
+                Unsupported,
+                format!("{} isn't evenly spaced, so can't be mapped to pixels", name)
+            ));
+        }
+        Ok(Some((var, first, step)))
+    }
+}
+
+/// The number of 2D slices of `variable`, such as time steps, in the NetCDF `contents`,
+/// which are read as its bands.
+pub fn bands(contents: &[u8], variable: Option<&str>) -> Result<u64> {
+    let header = parse(contents)?;
+    let var = header.variable(variable)?;
+    Ok(var.dims[..var.dims.len() - 2]
+        .iter()
+        .map(|&d| header.len(d))
+        .product())
+}
+
+/// Turns band `band` of `variable`, counting from 1, in the NetCDF `contents` into a tif.
+/// The dimensions before the last two, such as time, number the bands. Without a
+/// `variable`, the file must hold a single grid.
+pub fn to_tif(contents: &[u8], variable: Option<&str>, band: u16) -> Result<Vec<u8>> {
+    let header = parse(contents)?;
+    let var = header.variable(variable)?;
+    let [.., y_dim, x_dim] = var.dims[..] else {
+        unreachable!("grids have two or more dimensions")
+    };
+    let (width, height) = (header.len(x_dim), header.len(y_dim));
+    let bands = bands(contents, Some(&var.name))?;
+    if band as u64 > bands {
+        bail!(Classified::new(
+            BadInput,
+            format!("{} has {} bands, not {}", var.name, bands, band)
+        ));
+    }
+    let pixels = width * height;
+    let mut values = header.read(contents, var, (band as u64 - 1) * pixels, pixels)?;
+
+    let mut grid = Grid {
+        width,
+        height,
+        units: var.text("units").map(str::to_string),
+        description: var.text("long_name").map(str::to_string),
+        scale: var.number("scale_factor"),
+        offset: var.number("add_offset"),
+        sample: band - 1,
+        ..Grid::default()
+    };
+    if let (Some((x_var, west, dx)), Some((y_var, top, dy))) =
+        (header.axis(contents, x_dim)?, header.axis(contents, y_dim)?)
+    {
+        if dx < 0.0 {
+            bail!(Classified::new(
+                Unsupported,
+                format!("{} decreases, so can't be mapped to pixels", x_var.name)
+            ));
+        }
+        // Rows run from north to south, so a grid from the south up is flipped.
+        let north = match dy > 0.0 {
+            true => {
+                values = values
+                    .chunks(width as usize)
+                    .rev()
+                    .flatten()
+                    .copied()
+                    .collect();
+                top + (height - 1) as f64 * dy
+            }
+            false => top,
+        };
+        grid.extent = Some([west - dx / 2.0, north + dy.abs() / 2.0, dx, dy.abs()]);
+        let degrees = |v: &Variable| v.text("units").is_some_and(|u| u.starts_with("degree"));
+        grid.crs = (degrees(x_var) && degrees(y_var)).then_some(Crs::wgs84());
+    }
+
+    let fills = [
+        var.number("_FillValue"),
+        var.number("missing_value"),
+        Some(var.kind.default_fill()),
+    ];
+    grid.values = values
+        .into_iter()
+        .map(|v| match fills.contains(&Some(v)) {
+            true => f64::NAN,
+            false => v,
+        })
+        .collect();
+    grid.to_tif()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bands, to_tif};
+    use crate::{georef::GeoTransform, metadata::SourceMetadata};
+    use std::io::Cursor;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    /// A CDF-1 file of `temp(time, lat, lon)` floats with ascending coordinates.
+    fn netcdf() -> Vec<u8> {
+        let mut nc = b"CDF\x01".to_vec();
+        let u32 = |nc: &mut Vec<u8>, n: u32| nc.extend(n.to_be_bytes());
+        let name = |nc: &mut Vec<u8>, name: &str| {
+            u32(nc, name.len() as u32);
+            nc.extend(name.as_bytes());
+            nc.resize(nc.len().div_ceil(4) * 4, 0);
+        };
+        u32(&mut nc, 2); // records
+        u32(&mut nc, 0x0a);
+        u32(&mut nc, 3);
+        for (dim, len) in [("time", 0), ("lat", 2), ("lon", 3)] {
+            name(&mut nc, dim);
+            u32(&mut nc, len);
+        }
+        nc.extend([0; 8]); // no global attributes
+        u32(&mut nc, 0x0b);
+        u32(&mut nc, 3);
+        let mut begins = vec![];
+        for (var, dims, units) in [
+            ("lat", &[1][..], "degrees_north"),
+            ("lon", &[2][..], "degrees_east"),
+            ("temp", &[0, 1, 2][..], "K"),
+        ] {
+            name(&mut nc, var);
+            u32(&mut nc, dims.len() as u32);
+            dims.iter().for_each(|&d| u32(&mut nc, d));
+            u32(&mut nc, 0x0c);
+            u32(&mut nc, 1 + (var == "temp") as u32);
+            name(&mut nc, "units");
+            u32(&mut nc, 2);
+            name(&mut nc, units);
+            if var == "temp" {
+                name(&mut nc, "_FillValue");
+                u32(&mut nc, 5);
+                u32(&mut nc, 1);
+                nc.extend((-1.0f32).to_be_bytes());
+            }
+            u32(&mut nc, 5); // float
+            u32(&mut nc, [8, 12, 24][begins.len()]);
+            begins.push(nc.len());
+            u32(&mut nc, 0);
+        }
+        let mut put = |i: usize, values: &[f32]| {
+            let begin = nc.len() as u32;
+            nc[begins[i]..begins[i] + 4].copy_from_slice(&begin.to_be_bytes());
+            values.iter().for_each(|v| nc.extend(v.to_be_bytes()));
+        };
+        put(0, &[-10.0, 10.0]);
+        put(1, &[0.5, 1.5, 2.5]);
+        // The one record variable's records follow each other unpadded.
+        put(
+            2,
+            &[
+                1.0, 2.0, 3.0, 4.0, -1.0, 6.0, 10.5, 20.5, 30.5, 40.5, 50.5, 60.5,
+            ],
+        );
+        nc
+    }
+
+    #[test]
+    fn test_to_tif() {
+        let nc = netcdf();
+        assert_eq!(bands(&nc, None).unwrap(), 2);
+        let tif = to_tif(&nc, Some("temp"), 1).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(&tif)).unwrap();
+        let DecodingResult::F64(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected F64 pixels");
+        };
+        // Latitude rises, so the rows are flipped to put the north first.
+        assert_eq!(pixels, [4.0, f64::MIN, 6.0, 1.0, 2.0, 3.0]);
+        let transform = GeoTransform::resolve(&mut decoder, None, Some(crate::crs::Crs::wgs84()));
+        let bounds = transform.unwrap().bounds();
+        assert_eq!(
+            (bounds.west, bounds.south, bounds.east, bounds.north),
+            (0.0, -20.0, 3.0, 20.0)
+        );
+        let source = SourceMetadata::read(&mut decoder).unwrap();
+        assert_eq!(source.band_item("units"), Some("K"));
+        assert_eq!(source.scale_offset(), (None, None));
+
+        assert_eq!(source.nodata.as_deref(), Some("-1.7976931348623157e308"));
+
+        // Values that aren't whole are stored as they are.
+        let tif = to_tif(&nc, None, 2).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(&tif)).unwrap();
+        let DecodingResult::F64(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected F64 pixels");
+        };
+        assert_eq!(pixels[0], 40.5);
+        assert_eq!(
+            SourceMetadata::read(&mut decoder).unwrap().scale_offset(),
+            (None, None)
+        );
+        assert!(to_tif(&nc, None, 3).is_err());
+        assert!(to_tif(&nc, Some("lat"), 1).is_err());
+        assert!(to_tif(b"\x89HDF\r\n", None, 1).is_err());
+    }
+}
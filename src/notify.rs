@@ -0,0 +1,223 @@
+//! Telling someone a batch has finished, by posting or piping a JSON report of it.
+
+use crate::json::Value;
+use anyhow::{bail, Context, Result};
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Where the report goes once every input has been handled or one has failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OnComplete {
+    /// An `http://` or `https://` URL the report is POSTed to.
+    Webhook(String),
+    /// A shell command run with the report on its standard input.
+    Command(String),
+}
+
+impl FromStr for OnComplete {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(command) = s.strip_prefix("command:") {
+            if command.trim().is_empty() {
+                bail!("Expected a command after `command:`");
+            }
+            return Ok(OnComplete::Command(command.to_string()));
+        }
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(OnComplete::Webhook(s.to_string()));
+        }
+        bail!("Expected a webhook URL or `command:<cmd>` but got {}", s)
+    }
+}
+
+/// What became of one input of the batch.
+pub enum Outcome {
+    Written {
+        output: PathBuf,
+        rows: usize,
+    },
+    Failed(String),
+    /// Not attempted because an earlier input failed.
+    Skipped,
+    /// Claimed by another worker through `--coordinate`.
+    Taken,
+    /// Left alone by `--skip-existing` because its output is newer than it, or by
+    /// `--state` because it was converted as it is now with the same options.
+    UpToDate {
+        output: PathBuf,
+    },
+}
+
+/// Lays out the batch's report: overall status, how long it took, and each input's outcome.
+pub fn report(inputs: &[(PathBuf, Outcome)], elapsed: Duration) -> Value {
+    let failed = inputs
+        .iter()
+        .any(|(_, outcome)| matches!(outcome, Outcome::Failed(_)));
+    let inputs = inputs
+        .iter()
+        .map(|(input, outcome)| {
+            let mut entry = vec![("input", Value::from(input.to_string_lossy().to_string()))];
+            match outcome {
+                Outcome::Written { output, rows } => {
+                    entry.push(("status", "written".into()));
+                    entry.push(("output", output.to_string_lossy().to_string().into()));
+                    entry.push(("rows", (*rows as u64).into()));
+                }
+                Outcome::Failed(error) => {
+                    entry.push(("status", "failed".into()));
+                    entry.push(("error", error.clone().into()));
+                }
+                Outcome::Skipped => entry.push(("status", "skipped".into())),
+                Outcome::Taken => entry.push(("status", "taken".into())),
+                Outcome::UpToDate { output } => {
+                    entry.push(("status", "up_to_date".into()));
+                    entry.push(("output", output.to_string_lossy().to_string().into()));
+                }
+            }
+            Value::object(entry)
+        })
+        .collect();
+    Value::object([
+        (
+            "status",
+            Value::from(if failed { "failed" } else { "succeeded" }),
+        ),
+        ("elapsed_seconds", elapsed.as_secs_f64().into()),
+        ("inputs", Value::Array(inputs)),
+    ])
+}
+
+/// Lists the inputs that failed and why, for the end of a `--keep-going` run, or `None`
+/// if none did.
+pub fn failures(inputs: &[(PathBuf, Outcome)]) -> Option<String> {
+    let failed: Vec<String> = inputs
+        .iter()
+        .filter_map(|(input, outcome)| match outcome {
+            Outcome::Failed(error) => Some(format!("  {}: {}", input.display(), error)),
+            _ => None,
+        })
+        .collect();
+    if failed.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} of {} inputs failed:\n{}",
+        failed.len(),
+        inputs.len(),
+        failed.join("\n")
+    ))
+}
+
+impl OnComplete {
+    /// Sends the report. Webhooks are posted with `curl`, which must be on the `PATH`.
+    pub fn send(&self, report: &Value) -> Result<()> {
+        let mut command = match self {
+            OnComplete::Webhook(url) => {
+                let mut command = Command::new("curl");
+                command.args([
+                    "--silent",
+                    "--show-error",
+                    "--fail",
+                    "--header",
+                    "Content-Type: application/json",
+                    "--data-binary",
+                    "@-",
+                    url,
+                ]);
+                command
+            }
+            OnComplete::Command(shell) => {
+                let mut command = Command::new("sh");
+                command.args(["-c", shell]);
+                command
+            }
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Could not run the --on-complete hook {:?}", self))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A hook that ignores its input may exit before reading it.
+            let _ = stdin.write_all(report.to_string().as_bytes());
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("The --on-complete hook {:?} failed with {}", self, status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{failures, report, OnComplete, Outcome};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_on_complete() {
+        assert_eq!(
+            "command:mail -s done team".parse::<OnComplete>().unwrap(),
+            OnComplete::Command("mail -s done team".into())
+        );
+        assert_eq!(
+            "https://hooks.example.com/x".parse::<OnComplete>().unwrap(),
+            OnComplete::Webhook("https://hooks.example.com/x".into())
+        );
+        assert!("ftp://example.com".parse::<OnComplete>().is_err());
+    }
+
+    #[test]
+    fn test_report_status() {
+        let inputs = [
+            (
+                "a.tif".into(),
+                Outcome::Written {
+                    output: "a.parquet".into(),
+                    rows: 3,
+                },
+            ),
+            ("b.tif".into(), Outcome::Failed("bad tif".into())),
+            ("c.tif".into(), Outcome::Skipped),
+        ];
+        let report = report(&inputs, Duration::from_secs(2));
+        assert_eq!(
+            report.get("status").and_then(|s| s.as_str()),
+            Some("failed")
+        );
+        let inputs = report.get("inputs").and_then(|i| i.as_array()).unwrap();
+        assert_eq!(inputs[0].get("rows").and_then(|r| r.as_f64()), Some(3.0));
+        assert_eq!(
+            inputs[2].get("status").and_then(|s| s.as_str()),
+            Some("skipped")
+        );
+    }
+
+    #[test]
+    fn test_failures() {
+        let inputs = [
+            (
+                "a.zip".into(),
+                Outcome::Failed("invalid Zip archive".into()),
+            ),
+            (
+                "b.tif".into(),
+                Outcome::Written {
+                    output: "b.parquet".into(),
+                    rows: 3,
+                },
+            ),
+            ("c.tif".into(), Outcome::Failed("bad tif".into())),
+        ];
+        assert_eq!(
+            failures(&inputs).unwrap(),
+            "2 of 3 inputs failed:\n  a.zip: invalid Zip archive\n  c.tif: bad tif"
+        );
+        assert!(failures(&inputs[1..2]).is_none());
+    }
+}
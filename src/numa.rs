@@ -0,0 +1,150 @@
+use anyhow::{bail, Result};
+
+/// How the transform workers are placed across NUMA nodes.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum NumaPolicy {
+    /// Spread each worker's allocations round-robin over every node.
+    Interleave,
+    /// Pin workers round-robin to a node's CPUs and allocate only from that node.
+    Bind,
+}
+
+/// Builds the global rayon pool so its workers follow `policy`.
+pub fn configure_pool(policy: NumaPolicy) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("NUMA placement is only supported on Linux");
+    }
+    let nodes = read_nodes()?;
+    if nodes.is_empty() {
+        bail!("No NUMA nodes found under {}", NODE_DIR);
+    }
+    rayon::ThreadPoolBuilder::new()
+        .start_handler(move |index| {
+            let result = match policy {
+                NumaPolicy::Interleave => sys::interleave(nodes.iter().map(|n| n.id)),
+                NumaPolicy::Bind => {
+                    let node = &nodes[index % nodes.len()];
+                    sys::pin_to_cpus(&node.cpus).and_then(|_| sys::bind(node.id))
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("Could not apply NUMA policy to worker {}: {}", index, err);
+            }
+        })
+        .build_global()?;
+    Ok(())
+}
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+struct Node {
+    id: usize,
+    cpus: Vec<usize>,
+}
+
+fn read_nodes() -> Result<Vec<Node>> {
+    let mut nodes = vec![];
+    for entry in std::fs::read_dir(NODE_DIR)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(id) = name.strip_prefix("node").and_then(|id| id.parse().ok()) else {
+            continue;
+        };
+        let cpus = parse_cpu_list(&std::fs::read_to_string(entry.path().join("cpulist"))?)?;
+        if !cpus.is_empty() {
+            nodes.push(Node { id, cpus });
+        }
+    }
+    nodes.sort_by_key(|n| n.id);
+    Ok(nodes)
+}
+
+/// Parses the kernel's cpulist format, e.g. `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<usize>()?..=end.parse()?),
+            None => cpus.push(part.parse()?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use anyhow::{bail, Result};
+
+    const MPOL_BIND: libc::c_long = 2;
+    const MPOL_INTERLEAVE: libc::c_long = 3;
+
+    pub fn pin_to_cpus(cpus: &[usize]) -> Result<()> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in cpus {
+                libc::CPU_SET(*cpu, &mut set);
+            }
+            if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                bail!(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bind(node: usize) -> Result<()> {
+        set_mempolicy(MPOL_BIND, std::iter::once(node))
+    }
+
+    pub fn interleave(nodes: impl Iterator<Item = usize>) -> Result<()> {
+        set_mempolicy(MPOL_INTERLEAVE, nodes)
+    }
+
+    fn set_mempolicy(mode: libc::c_long, nodes: impl Iterator<Item = usize>) -> Result<()> {
+        let bits = libc::c_ulong::BITS as usize;
+        let mut mask: Vec<libc::c_ulong> = vec![];
+        for node in nodes {
+            if mask.len() <= node / bits {
+                mask.resize(node / bits + 1, 0);
+            }
+            mask[node / bits] |= 1 << (node % bits);
+        }
+        let max_node = (mask.len() * bits + 1) as libc::c_ulong;
+        let result =
+            unsafe { libc::syscall(libc::SYS_set_mempolicy, mode, mask.as_ptr(), max_node) };
+        if result != 0 {
+            bail!(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use anyhow::{bail, Result};
+
+    pub fn pin_to_cpus(_cpus: &[usize]) -> Result<()> {
+        bail!("NUMA placement is only supported on Linux")
+    }
+
+    pub fn bind(_node: usize) -> Result<()> {
+        bail!("NUMA placement is only supported on Linux")
+    }
+
+    pub fn interleave(_nodes: impl Iterator<Item = usize>) -> Result<()> {
+        bail!("NUMA placement is only supported on Linux")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cpu_list;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("").unwrap(), Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,73 @@
+//! The order a batch's inputs are processed in.
+
+use crate::archive;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Order {
+    /// Smallest files first.
+    SizeAsc,
+    /// Largest files first, which keeps one big file from finishing long after the rest.
+    SizeDesc,
+    /// Least recently modified first.
+    Mtime,
+    /// A different random order each run.
+    Shuffle,
+}
+
+/// Reorders `inputs`. Files that can't be read sort as empty and unmodified, so they fail
+/// in their turn rather than here, and tifs inside an archive sort as the archive.
+pub fn sort(inputs: &mut [PathBuf], order: Order) {
+    match order {
+        Order::SizeAsc => inputs.sort_by_cached_key(|path| size(path)),
+        Order::SizeDesc => inputs.sort_by_cached_key(|path| std::cmp::Reverse(size(path))),
+        Order::Mtime => inputs.sort_by_cached_key(|path| {
+            fs::metadata(archive::file(path))
+                .and_then(|m| m.modified())
+                .unwrap_or(UNIX_EPOCH)
+        }),
+        Order::Shuffle => {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            shuffle(inputs, seed);
+        }
+    }
+}
+
+fn size(path: &Path) -> u64 {
+    fs::metadata(archive::file(path)).map_or(0, |m| m.len())
+}
+
+/// Fisher-Yates shuffle driven by a splitmix64 sequence from `seed`.
+fn shuffle<T>(items: &mut [T], mut seed: u64) {
+    let mut next = || {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::shuffle;
+
+    #[test]
+    fn test_shuffle_is_permutation() {
+        let mut items: Vec<u32> = (0..20).collect();
+        shuffle(&mut items, 42);
+        assert_ne!(items, (0..20).collect::<Vec<_>>());
+        items.sort();
+        assert_eq!(items, (0..20).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,556 @@
+use crate::{
+    timing::TimedFile,
+    upload::{self, Destination, Upload},
+};
+use anyhow::{bail, Context, Result};
+use arrow_array::RecordBatch;
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{Schema, SchemaRef};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::{BrotliLevel, Compression as ParquetCompression, GzipLevel, ZstdLevel},
+    file::{
+        metadata::KeyValue,
+        properties::{EnabledStatistics, WriterProperties, WriterPropertiesBuilder},
+        reader::ChunkReader,
+    },
+};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum OutputFormat {
+    Parquet,
+    /// FlatGeobuf, with a spatial index so GIS tools can stream parts of large outputs.
+    Fgb,
+    /// ESRI shapefile, split into several files if it would pass the format's 2 GB limit.
+    Shp,
+    /// GeoPackage, a single SQLite file with an R-tree spatial index.
+    Gpkg,
+    /// Mapbox vector tiles, as a directory of `z/x/y.mvt` files.
+    Mvt,
+    /// Mapbox vector tiles, in a single MBTiles file.
+    Mbtiles,
+    /// A Delta Lake table at `--table-uri`, each input's rows added to it as a new commit.
+    Delta,
+    /// An uncompressed Arrow IPC stream, which `-o -` writes on stdout for tools such as
+    /// DuckDB to read as it arrives.
+    ArrowStream,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Fgb => "fgb",
+            OutputFormat::Shp => "shp",
+            OutputFormat::Gpkg => "gpkg",
+            OutputFormat::Mvt => "mvt",
+            OutputFormat::Mbtiles => "mbtiles",
+            OutputFormat::Delta => "delta",
+            OutputFormat::ArrowStream => "arrows",
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Codec {
+    Uncompressed,
+    Snappy,
+    Gzip,
+    Brotli,
+    Lz4,
+    Zstd,
+}
+
+/// How parquet column chunks are compressed.
+#[derive(clap::Args, Clone, Copy)]
+pub struct Compression {
+    /// Codec used for parquet column chunks.
+    #[arg(long = "compression", value_enum, default_value_t = Codec::Uncompressed)]
+    pub codec: Codec,
+    /// Level of the codec, trading speed for size: 1 to 22 for zstd, 0 to 10 for gzip and
+    /// 0 to 11 for brotli. Defaults to each codec's own.
+    #[arg(long = "compression-level", value_name = "LEVEL")]
+    pub level: Option<u32>,
+}
+
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Self {
+        Compression { codec, level: None }
+    }
+}
+
+impl Compression {
+    /// The parquet writer's compression, failing on a level out of the codec's range or
+    /// given for a codec that has none.
+    pub fn parquet(self) -> Result<ParquetCompression> {
+        let out_of_range = |range: &str| {
+            format!(
+                "--compression-level must be {} for {}, not {}",
+                range,
+                crate::explain::value_name(&self.codec),
+                self.level.unwrap_or_default()
+            )
+        };
+        Ok(match (self.codec, self.level) {
+            (Codec::Uncompressed, None) => ParquetCompression::UNCOMPRESSED,
+            (Codec::Snappy, None) => ParquetCompression::SNAPPY,
+            (Codec::Lz4, None) => ParquetCompression::LZ4_RAW,
+            (Codec::Gzip, level) => ParquetCompression::GZIP(match level {
+                Some(level) => GzipLevel::try_new(level).context(out_of_range("0 to 10"))?,
+                None => GzipLevel::default(),
+            }),
+            (Codec::Brotli, level) => ParquetCompression::BROTLI(match level {
+                Some(level) => BrotliLevel::try_new(level).context(out_of_range("0 to 11"))?,
+                None => BrotliLevel::default(),
+            }),
+            (Codec::Zstd, level) => ParquetCompression::ZSTD(match level {
+                Some(level) => ZstdLevel::try_new(level.try_into().unwrap_or(i32::MAX))
+                    .context(out_of_range("1 to 22"))?,
+                None => ZstdLevel::default(),
+            }),
+            (codec, Some(_)) => bail!(
+                "--compression-level doesn't apply to {}",
+                crate::explain::value_name(&codec)
+            ),
+        })
+    }
+}
+
+impl Codec {
+    /// A rough guess at compressed size over raw size for our mostly-float columns.
+    fn expected_ratio(self) -> f64 {
+        match self {
+            Codec::Uncompressed => 1.0,
+            Codec::Snappy | Codec::Lz4 => 0.75,
+            Codec::Gzip => 0.55,
+            Codec::Brotli | Codec::Zstd => 0.5,
+        }
+    }
+}
+
+pub fn write_parquet(path: &Path, batch: &RecordBatch, compression: Compression) -> Result<()> {
+    write_parquet_with_footer(path, batch, compression, vec![])
+}
+
+/// Writes `batch` with the key value pairs of `footer` added to the file's metadata.
+pub fn write_parquet_with_footer(
+    path: &Path,
+    batch: &RecordBatch,
+    compression: Compression,
+    footer: Vec<(String, String)>,
+) -> Result<()> {
+    write_parquet_with_progress(path, batch, compression, footer, |_| {})
+}
+
+/// Rows handed to the parquet writer at a time by [`write_parquet_with_progress`]. Row
+/// groups are still cut at the writer's own size.
+const PROGRESS_ROWS: usize = 64 * 1024;
+
+/// Like [`write_parquet_with_footer`], telling `progress` how many rows have been written
+/// as the writer gets through them.
+pub fn write_parquet_with_progress(
+    path: &Path,
+    batch: &RecordBatch,
+    compression: Compression,
+    footer: Vec<(String, String)>,
+    progress: impl Fn(u64),
+) -> Result<()> {
+    let props = writer_properties(&batch.schema(), compression)?.build();
+    let sink = TimedFile::new(Sink::create(path)?);
+    let mut writer = ArrowWriter::try_new(sink, batch.schema(), Some(props))?;
+    let mut offset = 0;
+    while offset < batch.num_rows() {
+        let rows = PROGRESS_ROWS.min(batch.num_rows() - offset);
+        writer.write(&batch.slice(offset, rows))?;
+        progress(rows as u64);
+        offset += rows;
+    }
+    close(writer, footer)
+}
+
+fn close(mut writer: ArrowWriter<TimedFile<Sink>>, footer: Vec<(String, String)>) -> Result<()> {
+    for (key, value) in footer {
+        writer.append_key_value_metadata(KeyValue::new(key, value));
+    }
+    writer.into_inner()?.into_inner().finish()
+}
+
+/// The output path that stands for stdout.
+pub const STDOUT: &str = "-";
+
+/// Whether `path` stands for stdout.
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new(STDOUT)
+}
+
+/// Where `--output` sends outputs instead of beside their inputs: stdout, given as `-`,
+/// or a bucket they are uploaded to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Stdout,
+    Bucket(Destination),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            STDOUT => Target::Stdout,
+            _ => Target::Bucket(s.parse()?),
+        })
+    }
+}
+
+/// Where an output's bytes go: a local file, stdout, or an upload to object storage.
+enum Sink {
+    File(File),
+    Stdout(io::Stdout),
+    Upload(Upload),
+}
+
+impl Sink {
+    /// Creates the file at `path`, or starts uploading to it if it is an object's URL.
+    fn create(path: &Path) -> Result<Sink> {
+        Ok(if is_stdout(path) {
+            Sink::Stdout(io::stdout())
+        } else if upload::is_url(path) {
+            Sink::Upload(Upload::start(path)?)
+        } else {
+            Sink::File(File::create(path)?)
+        })
+    }
+
+    /// Finishes the output, waiting for an upload to be stored.
+    fn finish(self) -> Result<()> {
+        match self {
+            Sink::File(_) => Ok(()),
+            Sink::Stdout(mut stdout) => Ok(stdout.flush()?),
+            Sink::Upload(upload) => upload.finish(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::File(file) => file.write(buf),
+            Sink::Stdout(stdout) => stdout.write(buf),
+            Sink::Upload(upload) => upload.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Stdout(stdout) => stdout.flush(),
+            Sink::Upload(upload) => upload.flush(),
+        }
+    }
+}
+
+fn writer_properties(schema: &Schema, compression: Compression) -> Result<WriterPropertiesBuilder> {
+    // Arrow readers find the schema's metadata in the serialized schema; the plain key
+    // value pairs are for everything else.
+    let mut metadata: Vec<KeyValue> = schema
+        .metadata()
+        .iter()
+        .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    metadata.sort_by(|a, b| a.key.cmp(&b.key));
+    // Min and max statistics for every page and column chunk let readers skip those
+    // outside a filter.
+    Ok(WriterProperties::builder()
+        .set_compression(compression.parquet()?)
+        .set_statistics_enabled(EnabledStatistics::Page)
+        .set_key_value_metadata((!metadata.is_empty()).then_some(metadata)))
+}
+
+/// A parquet file written a batch at a time, for outputs too large to build whole.
+pub struct ParquetStream {
+    writer: ArrowWriter<TimedFile<Sink>>,
+}
+
+impl ParquetStream {
+    /// Creates the file, buffering at most `row_group_rows` rows before each row group
+    /// is written out.
+    pub fn create(
+        path: &Path,
+        schema: SchemaRef,
+        compression: Compression,
+        row_group_rows: usize,
+    ) -> Result<ParquetStream> {
+        let props = writer_properties(&schema, compression)?
+            .set_max_row_group_size(row_group_rows.max(1))
+            .build();
+        Ok(ParquetStream {
+            writer: ArrowWriter::try_new(TimedFile::new(Sink::create(path)?), schema, Some(props))?,
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        Ok(self.writer.write(batch)?)
+    }
+
+    /// Finishes the file, adding the key value pairs of `footer` to its metadata.
+    pub fn close(self, footer: Vec<(String, String)>) -> Result<()> {
+        close(self.writer, footer)
+    }
+}
+
+/// Writes `batch` as an Arrow IPC stream, with the key value pairs of `footer` added to
+/// its schema's metadata, telling `progress` how many rows have been sent.
+pub fn write_arrow_stream_with_progress(
+    path: &Path,
+    batch: &RecordBatch,
+    footer: Vec<(String, String)>,
+    progress: impl Fn(u64),
+) -> Result<()> {
+    let mut stream = ArrowStream::create(path, batch.schema(), footer)?;
+    let mut offset = 0;
+    while offset < batch.num_rows() {
+        let rows = PROGRESS_ROWS.min(batch.num_rows() - offset);
+        stream.write(&batch.slice(offset, rows))?;
+        progress(rows as u64);
+        offset += rows;
+    }
+    stream.close()
+}
+
+/// An Arrow IPC stream, each batch sent on as soon as it is written so that readers can
+/// start on it before the last.
+pub struct ArrowStream {
+    writer: StreamWriter<TimedFile<Sink>>,
+}
+
+impl ArrowStream {
+    /// Starts the stream with its schema. A stream has no footer, so the key value pairs
+    /// of `footer` are added to the schema's metadata instead.
+    pub fn create(
+        path: &Path,
+        schema: SchemaRef,
+        footer: Vec<(String, String)>,
+    ) -> Result<ArrowStream> {
+        let mut metadata = schema.metadata().clone();
+        metadata.extend(footer);
+        let schema = Schema::new_with_metadata(schema.fields().clone(), metadata);
+        Ok(ArrowStream {
+            writer: StreamWriter::try_new(TimedFile::new(Sink::create(path)?), &schema)?,
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        Ok(self.writer.write(batch)?)
+    }
+
+    /// Ends the stream.
+    pub fn close(self) -> Result<()> {
+        self.writer.into_inner()?.into_inner().finish()
+    }
+}
+
+/// An output written a batch at a time, for outputs too large to build whole.
+pub enum Stream {
+    Parquet(ParquetStream),
+    Arrow(ArrowStream),
+}
+
+impl Stream {
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        match self {
+            Stream::Parquet(stream) => stream.write(batch),
+            Stream::Arrow(stream) => stream.write(batch),
+        }
+    }
+
+    /// Finishes the output. The key value pairs of `footer` are added to a parquet
+    /// file's metadata; an Arrow stream sent its own with its schema.
+    pub fn close(self, footer: Vec<(String, String)>) -> Result<()> {
+        match self {
+            Stream::Parquet(stream) => stream.close(footer),
+            Stream::Arrow(stream) => stream.close(),
+        }
+    }
+}
+
+/// Reads a whole parquet file into one batch.
+pub fn read_parquet(path: &Path) -> Result<RecordBatch> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    read_parquet_from(file)
+}
+
+/// Reads a whole parquet file held in memory, such as one downloaded, into one batch.
+pub fn read_parquet_bytes(bytes: Vec<u8>) -> Result<RecordBatch> {
+    read_parquet_from(bytes::Bytes::from(bytes))
+}
+
+fn read_parquet_from(reader: impl ChunkReader + 'static) -> Result<RecordBatch> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(reader)?;
+    let schema = builder.schema().clone();
+    let batches = builder.build()?.collect::<Result<Vec<_>, _>>()?;
+    // The batches' schema lacks the file's metadata, which is put back once they're joined.
+    let columns = match batches.first() {
+        Some(first) => arrow_select::concat::concat_batches(&first.schema(), &batches)?
+            .columns()
+            .to_vec(),
+        None => return Ok(RecordBatch::new_empty(schema)),
+    };
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// Fixed cost of the parquet footer and page headers, on top of the column data.
+const PARQUET_OVERHEAD: u64 = 64 * 1024;
+
+pub fn estimate_parquet_size(batch: &RecordBatch, codec: Codec) -> u64 {
+    let raw: usize = batch
+        .columns()
+        .iter()
+        .map(|c| c.get_buffer_memory_size())
+        .sum();
+    estimate_raw_parquet_size(raw as u64, codec)
+}
+
+/// Like [`estimate_parquet_size`], for `raw` bytes of column data that haven't been built.
+pub fn estimate_raw_parquet_size(raw: u64, codec: Codec) -> u64 {
+    (raw as f64 * codec.expected_ratio()) as u64 + PARQUET_OVERHEAD
+}
+
+/// Roughly how many bytes `batch` takes up in an Arrow stream, which holds its buffers as
+/// they are, padded, after a message header for each slice of rows.
+pub fn estimate_arrow_size(batch: &RecordBatch) -> u64 {
+    let raw: usize = batch
+        .columns()
+        .iter()
+        .map(|c| c.get_buffer_memory_size())
+        .sum();
+    let messages = batch.num_rows() / PROGRESS_ROWS + 2;
+    (raw + messages * 1024) as u64
+}
+
+/// Fails if `needed` bytes are unlikely to fit next to `path`, or only warns when `force` is set.
+pub fn check_free_space(path: &Path, needed: u64, force: bool) -> Result<()> {
+    // Uploads and stdout are streamed, so never need room on disk.
+    if upload::is_url(path) || is_stdout(path) {
+        return Ok(());
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(available) = free_space(dir)? else {
+        return Ok(());
+    };
+    if needed > available {
+        let message = format!(
+            "{} needs about {} bytes but only {} are free in {}",
+            path.to_string_lossy(),
+            needed,
+            available,
+            dir.to_string_lossy()
+        );
+        if !force {
+            bail!("{} (use --force to write anyway)", message);
+        }
+        eprintln!("Warning: {}", message);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Result<Option<u64>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        bail!(std::io::Error::last_os_error());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_parquet, write_arrow_stream_with_progress, write_parquet, Codec, Compression, Target,
+    };
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use arrow_ipc::reader::StreamReader;
+    use parquet::basic::{Compression as ParquetCompression, ZstdLevel};
+    use std::{cell::Cell, fs::File, sync::Arc};
+
+    #[test]
+    fn test_arrow_stream() {
+        let path = std::env::temp_dir().join(format!("output-test-{}.arrows", std::process::id()));
+        let batch = RecordBatch::try_from_iter([
+            (
+                "lon",
+                Arc::new(Float32Array::from(vec![1.5, 2.5])) as ArrayRef,
+            ),
+            ("value", Arc::new(Float32Array::from(vec![3.0, 4.0]))),
+        ])
+        .unwrap();
+        let sent = Cell::new(0);
+        write_arrow_stream_with_progress(
+            &path,
+            &batch,
+            vec![("image_stats:crs".to_string(), "EPSG:4326".to_string())],
+            |rows| sent.set(sent.get() + rows),
+        )
+        .unwrap();
+        assert_eq!(sent.get(), 2);
+
+        let reader = StreamReader::try_new(File::open(&path).unwrap(), None).unwrap();
+        assert_eq!(reader.schema().metadata()["image_stats:crs"], "EPSG:4326");
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].columns(), batch.columns());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!("-".parse::<Target>().unwrap(), Target::Stdout);
+        assert!(matches!("s3://bucket/runs".parse(), Ok(Target::Bucket(_))));
+        assert!("runs/".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_compression_level() {
+        let compression = |codec, level| Compression { codec, level }.parquet();
+        assert_eq!(
+            compression(Codec::Zstd, Some(19)).unwrap(),
+            ParquetCompression::ZSTD(ZstdLevel::try_new(19).unwrap())
+        );
+        assert!(compression(Codec::Zstd, Some(0)).is_err());
+        assert!(compression(Codec::Zstd, Some(23)).is_err());
+        assert!(compression(Codec::Gzip, Some(10)).is_ok());
+        assert!(compression(Codec::Gzip, Some(11)).is_err());
+        assert!(compression(Codec::Brotli, Some(11)).is_ok());
+        assert!(compression(Codec::Brotli, Some(12)).is_err());
+        // Codecs without levels refuse one rather than ignoring it.
+        assert!(compression(Codec::Snappy, Some(1)).is_err());
+        assert!(compression(Codec::Lz4, None).is_ok());
+
+        let path = std::env::temp_dir().join(format!("level-test-{}.parquet", std::process::id()));
+        let batch = RecordBatch::try_from_iter([(
+            "value",
+            Arc::new(Float32Array::from(vec![1.0; 1000])) as ArrayRef,
+        )])
+        .unwrap();
+        let zstd = Compression {
+            codec: Codec::Zstd,
+            level: Some(19),
+        };
+        write_parquet(&path, &batch, zstd).unwrap();
+        assert_eq!(read_parquet(&path).unwrap(), batch);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! `--overviews`: reading grouped conversions from the reduced resolution copies of the
+//! image that COGs carry, when the group cells are far larger than the native pixels,
+//! so a coarse output doesn't decode the full image.
+//!
+//! The chosen overview's storage tags replace those of the first image, and the first
+//! image's georeferencing is scaled to the overview's size, so the rest of the conversion
+//! reads the overview as if it were the image.
+
+use crate::{
+    group::CellSize,
+    ifd::{
+        Change, Directory, BITS_PER_SAMPLE, COMPRESSION, DOUBLE, EXTRA_SAMPLES, FILL_ORDER,
+        IMAGE_LENGTH, IMAGE_WIDTH, MODEL_PIXEL_SCALE, MODEL_TIEPOINT, MODEL_TRANSFORMATION,
+        NEW_SUBFILE_TYPE, PHOTOMETRIC_INTERPRETATION, PLANAR_CONFIGURATION, PREDICTOR,
+        ROWS_PER_STRIP, SAMPLES_PER_PIXEL, SAMPLE_FORMAT, STRIP_BYTE_COUNTS, STRIP_OFFSETS,
+        TILE_BYTE_COUNTS, TILE_LENGTH, TILE_OFFSETS, TILE_WIDTH,
+    },
+};
+use anyhow::Result;
+
+/// Pixels an overview must still have across each group cell, so each cell's value comes
+/// from several of them.
+const PIXELS_PER_CELL: f64 = 4.0;
+
+/// Tags for how an image's pixels are stored, which each overview has its own of.
+const STORAGE_TAGS: [u16; 21] = [
+    IMAGE_WIDTH,
+    IMAGE_LENGTH,
+    BITS_PER_SAMPLE,
+    COMPRESSION,
+    PHOTOMETRIC_INTERPRETATION,
+    FILL_ORDER,
+    STRIP_OFFSETS,
+    SAMPLES_PER_PIXEL,
+    ROWS_PER_STRIP,
+    STRIP_BYTE_COUNTS,
+    PLANAR_CONFIGURATION,
+    PREDICTOR,
+    TILE_WIDTH,
+    TILE_LENGTH,
+    TILE_OFFSETS,
+    TILE_BYTE_COUNTS,
+    EXTRA_SAMPLES,
+    SAMPLE_FORMAT,
+    // JPEGTables, YCbCrSubSampling and LercParameters.
+    347,
+    530,
+    50674,
+];
+
+/// The tif with its first image replaced by its coarsest overview that still has
+/// [`PIXELS_PER_CELL`] pixels across and up cells of size `cell`, given the first image's
+/// pixels are `pixel_size` across in the same units, or `None` if no overview is coarse
+/// enough to help.
+pub fn coarsest(
+    contents: &[u8],
+    pixel_size: (f64, f64),
+    cell: CellSize,
+) -> Result<Option<Vec<u8>>> {
+    let mut directories = Directory::read_all(contents)?.into_iter();
+    let Some(mut first) = directories.next() else {
+        return Ok(None);
+    };
+    let dimensions = |directory: &Directory| -> Result<(u64, u64)> {
+        let width = directory.value(contents, IMAGE_WIDTH)?.unwrap_or(0);
+        let height = directory.value(contents, IMAGE_LENGTH)?.unwrap_or(0);
+        Ok((width, height))
+    };
+    let (width, height) = dimensions(&first)?;
+    let mut chosen = None;
+    for directory in directories {
+        // Bit 0 marks a reduced resolution image and bit 2 a transparency mask.
+        let kind = directory.value(contents, NEW_SUBFILE_TYPE)?.unwrap_or(0);
+        let (overview_width, overview_height) = dimensions(&directory)?;
+        if kind & 1 == 0 || kind & 4 != 0 || overview_width == 0 || overview_height == 0 {
+            continue;
+        }
+        let factor = (
+            width as f64 / overview_width as f64,
+            height as f64 / overview_height as f64,
+        );
+        let coarser = chosen
+            .as_ref()
+            .is_none_or(|(_, chosen): &(Directory, (f64, f64))| factor.0 > chosen.0);
+        let fits = pixel_size.0 * factor.0 * PIXELS_PER_CELL <= cell.lon
+            && pixel_size.1 * factor.1 * PIXELS_PER_CELL <= cell.lat;
+        if fits && coarser {
+            chosen = Some((directory, factor));
+        }
+    }
+    let Some((overview, factor)) = chosen else {
+        return Ok(None);
+    };
+
+    let scaled = |tag, scale: &dyn Fn(usize, f64) -> f64| -> Result<Option<Vec<u64>>> {
+        Ok(first.doubles(contents, tag)?.map(|values| {
+            let values = values.into_iter().enumerate();
+            values.map(|(i, v)| scale(i, v).to_bits()).collect()
+        }))
+    };
+    let georeferencing = [
+        // Pixels are this many times larger.
+        scaled(MODEL_PIXEL_SCALE, &|i, v| match i {
+            0 => v * factor.0,
+            1 => v * factor.1,
+            _ => v,
+        })?,
+        // The tie point's pixel is this many times closer to the origin.
+        scaled(MODEL_TIEPOINT, &|i, v| match i % 6 {
+            0 => v / factor.0,
+            1 => v / factor.1,
+            _ => v,
+        })?,
+        // So are the first two columns of the pixel to model transformation.
+        scaled(MODEL_TRANSFORMATION, &|i, v| match i % 4 {
+            0 => v * factor.0,
+            1 => v * factor.1,
+            _ => v,
+        })?,
+    ];
+    let tags = [MODEL_PIXEL_SCALE, MODEL_TIEPOINT, MODEL_TRANSFORMATION];
+    for (tag, values) in tags.into_iter().zip(georeferencing) {
+        if let Some(values) = values {
+            first.set(tag, DOUBLE, values);
+        }
+    }
+    for tag in STORAGE_TAGS {
+        if let Some(entry) = overview.entry(tag) {
+            first.set(tag, entry.kind, overview.values(contents, entry)?);
+        }
+    }
+    let rewritten = first.rewrite(contents, |entry| {
+        // Storage tags the overview has were set above.
+        Ok(match STORAGE_TAGS.contains(&entry.tag) {
+            true => Change::Drop,
+            false => Change::Keep,
+        })
+    })?;
+    Ok(Some(rewritten.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::coarsest;
+    use std::io::Cursor;
+    use tiff::{
+        decoder::{Decoder, DecodingResult},
+        encoder::{colortype::Gray8, TiffEncoder},
+        tags::Tag,
+    };
+
+    /// An 8x8 tif of ones placed with 1 unit pixels, with a 4x4 overview of twos and a
+    /// 2x2 one of threes.
+    fn tif_with_overviews() -> Vec<u8> {
+        let mut tif = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut tif).unwrap();
+        let mut image = encoder.new_image::<Gray8>(8, 8).unwrap();
+        let directory = image.encoder();
+        directory
+            .write_tag(Tag::ModelPixelScaleTag, &[1.0, 1.0, 0.0][..])
+            .unwrap();
+        directory
+            .write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, 10.0, 20.0, 0.0][..])
+            .unwrap();
+        image.write_data(&[1; 64]).unwrap();
+        for (size, value) in [(4, 2), (2, 3)] {
+            let mut image = encoder.new_image::<Gray8>(size, size).unwrap();
+            image
+                .encoder()
+                .write_tag(Tag::NewSubfileType, 1u32)
+                .unwrap();
+            image
+                .write_data(&vec![value; (size * size) as usize])
+                .unwrap();
+        }
+        tif.into_inner()
+    }
+
+    #[test]
+    fn test_coarsest() {
+        let tif = tif_with_overviews();
+        assert!(coarsest(&tif, (1.0, 1.0), 4.0.into()).unwrap().is_none());
+
+        // Cells 8 units across have 4 of the first overview's pixels across.
+        let overview = coarsest(&tif, (1.0, 1.0), 8.0.into()).unwrap().unwrap();
+        let mut decoder = Decoder::new(Cursor::new(overview)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (4, 4));
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).unwrap();
+        assert_eq!(scale, vec![2.0, 2.0, 0.0]);
+        let DecodingResult::U8(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected u8 samples");
+        };
+        assert_eq!(pixels, vec![2; 16]);
+
+        let overview = coarsest(&tif, (1.0, 1.0), 100.0.into()).unwrap().unwrap();
+        let mut decoder = Decoder::new(Cursor::new(overview)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 2));
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag).unwrap();
+        assert_eq!(tiepoint, vec![0.0, 0.0, 0.0, 10.0, 20.0, 0.0]);
+    }
+}
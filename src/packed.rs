@@ -0,0 +1,93 @@
+//! Unpacking samples narrower than a byte, such as 1 bit masks and 4 bit class codes.
+//!
+//! The `tiff` crate can't read rows of such samples, so the image is decoded through a
+//! directory that describes its packed rows as 8 bit samples, and each chunk's bytes are
+//! split into samples afterwards.
+
+use crate::ifd::{
+    Change, Directory, Overlay, BITS_PER_SAMPLE, FILL_ORDER, IMAGE_WIDTH,
+    PHOTOMETRIC_INTERPRETATION, PREDICTOR, SAMPLES_PER_PIXEL, SHORT, TILE_WIDTH,
+};
+use anyhow::{bail, Result};
+
+/// The bits in each sample of the tif's first image if they are packed below a byte.
+pub fn packed_bits(contents: &[u8]) -> Result<Option<u8>> {
+    let directory = Directory::read(contents)?;
+    Ok(
+        match directory.value(contents, BITS_PER_SAMPLE)?.unwrap_or(1) {
+            bits @ (1 | 2 | 4) => Some(bits as u8),
+            _ => None,
+        },
+    )
+}
+
+/// Presents the tif's first image as 8 bit samples, each holding a byte of packed samples.
+pub fn byte_view(contents: &[u8]) -> Result<Overlay<'_>> {
+    let directory = Directory::read(contents)?;
+    let Some(bits) = packed_bits(contents)? else {
+        bail!("The tif's samples are not packed below a byte");
+    };
+    let value =
+        |tag, default| -> Result<u64> { Ok(directory.value(contents, tag)?.unwrap_or(default)) };
+    if value(SAMPLES_PER_PIXEL, 1)? != 1 {
+        bail!("{} bit samples are only read from single band images", bits);
+    }
+    if value(PREDICTOR, 1)? != 1 {
+        bail!("{} bit samples can't be read with a predictor", bits);
+    }
+    if value(FILL_ORDER, 1)? != 1 {
+        bail!(
+            "{} bit samples are only read most significant bit first",
+            bits
+        );
+    }
+    let packed_width = |width: u64| (width * bits as u64).div_ceil(8);
+    directory.rewrite(contents, |entry| {
+        Ok(match entry.tag {
+            BITS_PER_SAMPLE => Change::Replace(SHORT, vec![8]),
+            IMAGE_WIDTH => Change::Replace(entry.kind, vec![packed_width(value(IMAGE_WIDTH, 0)?)]),
+            TILE_WIDTH => {
+                let width = value(TILE_WIDTH, 0)?;
+                if !(width * bits as u64).is_multiple_of(8) {
+                    bail!("Tiles {} pixels wide don't hold whole bytes", width);
+                }
+                Change::Replace(entry.kind, vec![packed_width(width)])
+            }
+            // Stored values are read as they are, not inverted for white is zero or looked up
+            // in a palette.
+            PHOTOMETRIC_INTERPRETATION => Change::Replace(SHORT, vec![1]),
+            _ => Change::Keep,
+        })
+    })
+}
+
+/// Splits the packed rows of a `width` by `height` chunk into samples, most significant
+/// bits first. Each row starts on a new byte.
+pub fn unpack(bytes: &[u8], bits: u8, width: u32, height: u32) -> Vec<i32> {
+    let row_bytes = (width as usize * bits as usize).div_ceil(8);
+    let mask = (1u8 << bits) - 1;
+    let mut samples = Vec::with_capacity(width as usize * height as usize);
+    for row in bytes.chunks(row_bytes).take(height as usize) {
+        for x in 0..width as usize {
+            let bit = x * bits as usize;
+            let byte = row.get(bit / 8).copied().unwrap_or(0);
+            samples.push((byte >> (8 - bits as usize - bit % 8) & mask) as i32);
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unpack;
+
+    #[test]
+    fn test_unpack() {
+        // Rows of 3 one bit samples, each padded to a byte.
+        assert_eq!(
+            unpack(&[0b1010_0000, 0b0110_0000], 1, 3, 2),
+            vec![1, 0, 1, 0, 1, 1]
+        );
+        assert_eq!(unpack(&[0x3f, 0xa0], 4, 3, 1), vec![3, 15, 10]);
+    }
+}
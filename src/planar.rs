@@ -0,0 +1,119 @@
+//! Reading one band of a band separate tif (PlanarConfiguration 2), whose strips or tiles
+//! each hold a single band, as an ordinary single band image.
+//!
+//! The `tiff` crate decodes every strip as if it held all of a pixel's samples, so the
+//! band is given a directory of its own pointing at just its strips or tiles, and the tif
+//! is decoded through that instead.
+
+use crate::ifd::{
+    Change, Directory, BITS_PER_SAMPLE, EXTRA_SAMPLES, PHOTOMETRIC_INTERPRETATION,
+    PLANAR_CONFIGURATION, SAMPLES_PER_PIXEL, SAMPLE_FORMAT, SHORT, STRIP_BYTE_COUNTS,
+    STRIP_OFFSETS, TILE_BYTE_COUNTS, TILE_OFFSETS,
+};
+use anyhow::{bail, Result};
+
+/// The number of bands of the tif's first image if it stores them separately, or `None`
+/// if its samples are interleaved or it has only one.
+pub fn separate_bands(contents: &[u8]) -> Result<Option<u16>> {
+    let directory = Directory::read(contents)?;
+    let samples = directory.value(contents, SAMPLES_PER_PIXEL)?.unwrap_or(1);
+    let planar = directory
+        .value(contents, PLANAR_CONFIGURATION)?
+        .unwrap_or(1);
+    Ok((planar == 2 && samples > 1).then_some(samples as u16))
+}
+
+/// Rewrites a band separate tif so its first image is `band` (counting from zero) alone.
+pub fn band_view(contents: &[u8], band: u16) -> Result<Vec<u8>> {
+    let Some(samples) = separate_bands(contents)? else {
+        bail!("The tif does not store its bands separately");
+    };
+    if band >= samples {
+        bail!("Band {} requested but the tif has {}", band + 1, samples);
+    }
+    let directory = Directory::read(contents)?;
+    let overlay = directory.rewrite(contents, |entry| {
+        Ok(match entry.tag {
+            EXTRA_SAMPLES => Change::Drop,
+            SAMPLES_PER_PIXEL | PLANAR_CONFIGURATION => Change::Replace(SHORT, vec![1]),
+            BITS_PER_SAMPLE | SAMPLE_FORMAT => {
+                let values = directory.values(contents, entry)?;
+                let value = values.get(band as usize).or(values.first()).copied();
+                Change::Replace(SHORT, value.into_iter().collect())
+            }
+            // RGB, CMYK and YCbCr make no sense for a single band.
+            PHOTOMETRIC_INTERPRETATION => match directory.values(contents, entry)?.first() {
+                Some(2 | 5 | 6) => Change::Replace(SHORT, vec![1]),
+                _ => Change::Keep,
+            },
+            STRIP_OFFSETS | STRIP_BYTE_COUNTS | TILE_OFFSETS | TILE_BYTE_COUNTS => {
+                let values = directory.values(contents, entry)?;
+                if values.len() % samples as usize != 0 {
+                    bail!(
+                        "Tag {} has {} values, not a whole number for each of {} bands",
+                        entry.tag,
+                        values.len(),
+                        samples
+                    );
+                }
+                let per_band = values.len() / samples as usize;
+                let start = band as usize * per_band;
+                Change::Replace(entry.kind, values[start..start + per_band].to_vec())
+            }
+            _ => Change::Keep,
+        })
+    })?;
+    Ok(overlay.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{band_view, separate_bands};
+    use std::io::Cursor;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    /// A classic little endian tif of 2x2 pixels with two bands of u8 samples, each band
+    /// in one strip.
+    fn planar_tif() -> Vec<u8> {
+        let tags: [(u16, u16, u32, u32); 9] = [
+            (256, 3, 1, 2),
+            (257, 3, 1, 2),
+            (258, 3, 2, 8 | 8 << 16),
+            (262, 3, 1, 1),
+            (273, 4, 2, 200),
+            (277, 3, 1, 2),
+            (278, 3, 1, 2),
+            (279, 4, 2, 208),
+            (284, 3, 1, 2),
+        ];
+        let mut tif = b"II*\0\x08\0\0\0".to_vec();
+        tif.extend((tags.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in tags {
+            tif.extend(tag.to_le_bytes());
+            tif.extend(kind.to_le_bytes());
+            tif.extend(count.to_le_bytes());
+            tif.extend(value.to_le_bytes());
+        }
+        tif.extend(0u32.to_le_bytes());
+        tif.resize(200, 0);
+        // Strip offsets, strip byte counts, then each band's strip.
+        tif.extend([216u32, 220, 4, 4].iter().flat_map(|v| v.to_le_bytes()));
+        tif.extend([1, 2, 3, 4, 10, 20, 30, 40]);
+        tif
+    }
+
+    #[test]
+    fn test_band_view() {
+        let tif = planar_tif();
+        assert_eq!(separate_bands(&tif).unwrap(), Some(2));
+        let view = band_view(&tif, 1).unwrap();
+        assert_eq!(separate_bands(&view).unwrap(), None);
+        let mut decoder = Decoder::new(Cursor::new(view)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 2));
+        let DecodingResult::U8(pixels) = decoder.read_chunk(0).unwrap() else {
+            panic!("expected u8 samples");
+        };
+        assert_eq!(pixels, vec![10, 20, 30, 40]);
+        assert!(band_view(&tif, 2).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! External subcommands, found on the `PATH` the way git finds its own: `geotif <name>`
+//! runs the executable `geotif-<name>` with the remaining arguments, so teams can add
+//! their own processing steps without forking this tool.
+//!
+//! A plugin is run with its exit code passed through, and with these variables set:
+//!
+//! - `GEOTIF_BIN`: the path of the binary that ran it, for calling back into the tool.
+//! - `GEOTIF_VERSION`: that binary's version, to check compatibility against.
+//!
+//! Plugins written in Rust can depend on this crate and convert inputs exactly as the
+//! tool does. [`Options`](crate::processor::Options) is a set of clap arguments, so a
+//! plugin can take the same flags by flattening it into its own:
+//!
+//! ```no_run
+//! use clap::Parser;
+//! use image_stats::processor::{Options, ProcessorBuilder};
+//!
+//! /// `geotif-count`: prints how many rows each input converts to.
+//! #[derive(Parser)]
+//! struct Args {
+//!     inputs: Vec<std::path::PathBuf>,
+//!     #[command(flatten)]
+//!     options: Options,
+//! }
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let args = Args::parse();
+//!     let processor = ProcessorBuilder::from_options(args.options).build()?;
+//!     for input in &args.inputs {
+//!         println!("{}: {}", input.display(), processor.to_batch(input)?.num_rows());
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use crate::release;
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub const PREFIX: &str = "geotif-";
+
+/// Whether `path` is a file that can be run.
+fn is_executable(path: &Path) -> bool {
+    let Ok(metadata) = path.metadata() else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    metadata.is_file()
+}
+
+/// Every plugin on the `PATH` by name, taking the first of each name as the shell would.
+pub fn discover() -> BTreeMap<String, PathBuf> {
+    let mut plugins = BTreeMap::new();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    for dir in std::env::split_paths(&path) {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(name) = file_name.strip_prefix(PREFIX) else {
+                continue;
+            };
+            let name = name.strip_suffix(".exe").unwrap_or(name).to_string();
+            if !name.is_empty() && is_executable(&entry.path()) {
+                plugins.entry(name).or_insert_with(|| entry.path());
+            }
+        }
+    }
+    plugins
+}
+
+/// The plugin the command line `args` asks for, if its first argument names one rather
+/// than a flag, a built in subcommand or an input.
+pub fn requested(command: &clap::Command, args: &[OsString]) -> Option<PathBuf> {
+    let name = args.get(1)?.to_str()?;
+    if name.starts_with('-') || command.find_subcommand(name).is_some() || Path::new(name).exists()
+    {
+        return None;
+    }
+    discover().remove(name)
+}
+
+/// Runs the plugin at `path` with `args`, returning its exit code.
+pub fn run(path: &Path, args: &[OsString]) -> Result<i32> {
+    let status = Command::new(path)
+        .args(args)
+        .env("GEOTIF_BIN", std::env::current_exe()?)
+        .env("GEOTIF_VERSION", release::CURRENT)
+        .status()
+        .with_context(|| format!("Could not run plugin {}", path.display()))?;
+    // A plugin killed by a signal has no code, and counts as failing.
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Lists the plugins found, one per line, for the `plugins` command.
+pub fn list() {
+    let plugins = discover();
+    if plugins.is_empty() {
+        println!("No {}<name> plugins found on the PATH", PREFIX);
+    }
+    for (name, path) in plugins {
+        println!("{:<20} {}", name, path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discover, requested, run};
+    use clap::{Command, CommandFactory, Parser};
+    use std::{ffi::OsString, os::unix::fs::PermissionsExt};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(subcommand)]
+        command: Option<Sub>,
+    }
+
+    #[derive(clap::Subcommand)]
+    enum Sub {
+        Inspect,
+    }
+
+    #[test]
+    fn test_plugins() {
+        let dir = std::env::temp_dir().join(format!("plugin-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("geotif-hello");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\n[ -n \"$GEOTIF_VERSION\" ] && exit $1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Only executables count.
+        std::fs::write(dir.join("geotif-notes"), "").unwrap();
+        let path = std::env::join_paths(std::iter::once(dir.clone()).chain(std::env::split_paths(
+            &std::env::var_os("PATH").unwrap_or_default(),
+        )))
+        .unwrap();
+        std::env::set_var("PATH", path);
+
+        assert_eq!(discover().get("hello"), Some(&script));
+        assert!(!discover().contains_key("notes"));
+        let command: Command = Cli::command();
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            requested(&command, &args(&["geotif", "hello", "7"])),
+            Some(script.clone())
+        );
+        assert_eq!(requested(&command, &args(&["geotif", "inspect"])), None);
+        assert_eq!(requested(&command, &args(&["geotif", "--hello"])), None);
+        assert_eq!(run(&script, &args(&["7"])).unwrap(), 7);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+//! Loading rows straight into PostgreSQL with `--postgres` and `--table`, so small and
+//! medium rasters land in a PostGIS database without a file in between. Rows are sent
+//! with a binary `COPY` through `psql`, which must be on the `PATH`, each with a `geom`
+//! column holding its `--geometry` in the output CRS. `--create-table` creates the table
+//! first, with column types matching the rows' and a GiST index on `geom`.
+
+use crate::{
+    crs::Crs,
+    geometry::{self, GeometryKind, Pixel},
+    gpkg::quote,
+    group::Binning,
+    timing::TimedFile,
+};
+use anyhow::{bail, Context, Result};
+use arrow_array::{
+    cast::{as_boolean_array, as_primitive_array, as_string_array},
+    types::{
+        Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, TimestampSecondType, UInt32Type,
+        UInt64Type, UInt8Type,
+    },
+    Array, RecordBatch,
+};
+use arrow_schema::{DataType, TimeUnit};
+use std::{
+    io::{BufWriter, Write},
+    process::{Command, Stdio},
+};
+
+/// The name of the column holding each row's geometry.
+const GEOMETRY_COLUMN: &str = "geom";
+
+/// What starts a binary `COPY`, before its flags and header extension length.
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// Seconds from 1970 to 2000, which PostgreSQL counts timestamps from.
+const POSTGRES_EPOCH: i64 = 946_684_800;
+
+/// EWKB geometry types, and the flag saying an SRID follows.
+const WKB_POINT: u32 = 1;
+const WKB_POLYGON: u32 = 3;
+const WKB_SRID: u32 = 0x2000_0000;
+
+/// Where rows are loaded: the database's connection URL, the table, and whether it is
+/// created if missing.
+pub struct Target<'a> {
+    pub url: &'a str,
+    pub table: &'a str,
+    pub create: bool,
+}
+
+/// Adds the rows of `batch` to the target table, each with a geometry of `kind`.
+pub fn copy(
+    target: &Target,
+    batch: &RecordBatch,
+    kind: GeometryKind,
+    binning: Option<&Binning>,
+    pixel: Pixel,
+    crs: Crs,
+) -> Result<()> {
+    let schema = batch.schema();
+    let types = batch
+        .columns()
+        .iter()
+        .map(|c| column_type(c.data_type()))
+        .collect::<Result<Vec<_>>>()?;
+    let table = qualified(target.table);
+    if target.create {
+        let mut columns: Vec<String> = schema
+            .fields()
+            .iter()
+            .zip(&types)
+            .map(|(field, kind)| match field.is_nullable() {
+                true => format!("{} {}", quote(field.name()), kind),
+                false => format!("{} {} NOT NULL", quote(field.name()), kind),
+            })
+            .collect();
+        let shape = match kind {
+            GeometryKind::Point => "Point",
+            GeometryKind::Cell => "Polygon",
+        };
+        columns.push(format!(
+            "{} geometry({}, {})",
+            quote(GEOMETRY_COLUMN),
+            shape,
+            srid(crs)
+        ));
+        let name = target.table.rsplit('.').next().unwrap_or(target.table);
+        psql(
+            target.url,
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {table} ({});
+                 CREATE INDEX IF NOT EXISTS {} ON {table} USING GIST ({})",
+                columns.join(", "),
+                quote(&format!("{}_{}_idx", name, GEOMETRY_COLUMN)),
+                quote(GEOMETRY_COLUMN),
+            ),
+            |_| Ok(()),
+        )?;
+    }
+    let names: Vec<String> = schema
+        .fields()
+        .iter()
+        .map(|field| quote(field.name()))
+        .chain([quote(GEOMETRY_COLUMN)])
+        .collect();
+    psql(
+        target.url,
+        &format!(
+            "COPY {} ({}) FROM STDIN (FORMAT binary)",
+            table,
+            names.join(", ")
+        ),
+        |stdin| write_copy(stdin, batch, kind, binning, pixel, crs),
+    )
+}
+
+/// Runs `sql` with `psql`, with what `input` writes on its standard input.
+fn psql(url: &str, sql: &str, input: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+    let mut child = Command::new("psql")
+        .args(["--no-psqlrc", "--quiet", "--set", "ON_ERROR_STOP=1"])
+        .args(["--dbname", url, "--command", sql])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Could not run psql")?;
+    let stdin = child.stdin.take().expect("piped stdin");
+    let mut stdin = BufWriter::new(TimedFile::new(stdin));
+    let written = input(&mut stdin).and_then(|()| Ok(stdin.flush()?));
+    // Closing its input ends the COPY.
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    // A failed statement stops psql reading, so its error says more than the write's.
+    if !output.status.success() {
+        bail!(
+            "psql failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    written
+}
+
+/// Writes the rows of `batch` in the binary `COPY` format, each followed by its geometry
+/// as EWKB.
+fn write_copy(
+    out: &mut dyn Write,
+    batch: &RecordBatch,
+    kind: GeometryKind,
+    binning: Option<&Binning>,
+    pixel: Pixel,
+    crs: Crs,
+) -> Result<()> {
+    let position = |index: usize| -> Result<Vec<f64>> {
+        let Some(column) = batch.columns().get(index) else {
+            bail!("Output has no position column {}", index);
+        };
+        let column = arrow_cast::cast(column, &DataType::Float64)?;
+        Ok(as_primitive_array::<Float64Type>(&column).values().to_vec())
+    };
+    let (lons, lats) = (position(0)?, position(1)?);
+
+    out.write_all(SIGNATURE)?;
+    // No flags, and no header extension.
+    out.write_all(&0i32.to_be_bytes())?;
+    out.write_all(&0i32.to_be_bytes())?;
+    let mut field = vec![];
+    for row in 0..batch.num_rows() {
+        out.write_all(&(batch.num_columns() as i16 + 1).to_be_bytes())?;
+        for column in batch.columns() {
+            if column.is_null(row) {
+                out.write_all(&(-1i32).to_be_bytes())?;
+                continue;
+            }
+            field.clear();
+            encode(column.as_ref(), row, &mut field);
+            out.write_all(&(field.len() as i32).to_be_bytes())?;
+            out.write_all(&field)?;
+        }
+        let ring = match kind {
+            GeometryKind::Point => vec![(lons[row], lats[row])],
+            GeometryKind::Cell => geometry::footprint(lons[row], lats[row], binning, pixel),
+        };
+        let geometry = ewkb(&ring, srid(crs));
+        out.write_all(&(geometry.len() as i32).to_be_bytes())?;
+        out.write_all(&geometry)?;
+    }
+    out.write_all(&(-1i16).to_be_bytes())?;
+    Ok(())
+}
+
+/// The PostgreSQL type of a column, which the binary `COPY` must match exactly.
+fn column_type(data_type: &DataType) -> Result<&'static str> {
+    Ok(match data_type {
+        DataType::Boolean => "boolean",
+        DataType::UInt8 | DataType::Int16 => "smallint",
+        DataType::Int32 => "integer",
+        DataType::UInt32 | DataType::Int64 | DataType::UInt64 => "bigint",
+        DataType::Float32 => "real",
+        DataType::Float64 => "double precision",
+        DataType::Utf8 => "text",
+        DataType::Timestamp(TimeUnit::Second, _) => "timestamptz",
+        other => bail!("Cannot copy {} columns to PostgreSQL", other),
+    })
+}
+
+/// Appends the binary form of a non-null value. The array's type must be one
+/// `column_type` accepts. `bigint` is signed, so `u64` ids past `i64::MAX` are stored as
+/// their two's complement, the same bits parquet writes for them.
+fn encode(array: &dyn Array, row: usize, out: &mut Vec<u8>) {
+    match array.data_type() {
+        DataType::Boolean => out.push(as_boolean_array(array).value(row) as u8),
+        DataType::UInt8 => {
+            let value = as_primitive_array::<UInt8Type>(array).value(row);
+            out.extend((value as i16).to_be_bytes())
+        }
+        DataType::Int16 => out.extend(
+            as_primitive_array::<Int16Type>(array)
+                .value(row)
+                .to_be_bytes(),
+        ),
+        DataType::Int32 => out.extend(
+            as_primitive_array::<Int32Type>(array)
+                .value(row)
+                .to_be_bytes(),
+        ),
+        DataType::UInt32 => {
+            let value = as_primitive_array::<UInt32Type>(array).value(row);
+            out.extend((value as i64).to_be_bytes())
+        }
+        DataType::Int64 => out.extend(
+            as_primitive_array::<Int64Type>(array)
+                .value(row)
+                .to_be_bytes(),
+        ),
+        DataType::UInt64 => {
+            let value = as_primitive_array::<UInt64Type>(array).value(row);
+            out.extend((value as i64).to_be_bytes())
+        }
+        DataType::Float32 => out.extend(
+            as_primitive_array::<Float32Type>(array)
+                .value(row)
+                .to_be_bytes(),
+        ),
+        DataType::Float64 => out.extend(
+            as_primitive_array::<Float64Type>(array)
+                .value(row)
+                .to_be_bytes(),
+        ),
+        DataType::Utf8 => out.extend(as_string_array(array).value(row).as_bytes()),
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            let seconds = as_primitive_array::<TimestampSecondType>(array).value(row);
+            out.extend(((seconds - POSTGRES_EPOCH) * 1_000_000).to_be_bytes())
+        }
+        _ => unreachable!("checked by column_type"),
+    }
+}
+
+/// The SRID of `crs`: its EPSG code, or 0, PostGIS's unknown SRID, for CRSs given as
+/// PROJ strings.
+fn srid(crs: Crs) -> u32 {
+    crs.epsg().unwrap_or(0)
+}
+
+/// Little endian EWKB of the point or single ring polygon, with its SRID.
+fn ewkb(ring: &[(f64, f64)], srid: u32) -> Vec<u8> {
+    let mut wkb = vec![1];
+    match ring {
+        [(x, y)] => {
+            wkb.extend((WKB_POINT | WKB_SRID).to_le_bytes());
+            wkb.extend(srid.to_le_bytes());
+            wkb.extend(x.to_le_bytes());
+            wkb.extend(y.to_le_bytes());
+        }
+        ring => {
+            wkb.extend((WKB_POLYGON | WKB_SRID).to_le_bytes());
+            wkb.extend(srid.to_le_bytes());
+            wkb.extend(1u32.to_le_bytes());
+            wkb.extend((ring.len() as u32).to_le_bytes());
+            for (x, y) in ring {
+                wkb.extend(x.to_le_bytes());
+                wkb.extend(y.to_le_bytes());
+            }
+        }
+    }
+    wkb
+}
+
+/// Quotes a table name, each part of one qualified with its schema separately.
+fn qualified(table: &str) -> String {
+    table.split('.').map(quote).collect::<Vec<_>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{qualified, write_copy, SIGNATURE};
+    use crate::{
+        crs::Crs,
+        geometry::{GeometryKind, Pixel},
+        group::Align,
+    };
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch, StringArray};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_write_copy() {
+        let batch = RecordBatch::try_from_iter([
+            ("lon", Arc::new(Float32Array::from(vec![1.5])) as ArrayRef),
+            ("lat", Arc::new(Float32Array::from(vec![2.5]))),
+            ("label", Arc::new(StringArray::from(vec![None::<&str>]))),
+        ])
+        .unwrap();
+        let pixel = Pixel {
+            size: (1.0, 1.0),
+            registration: Align::Center,
+        };
+        let mut out = vec![];
+        write_copy(
+            &mut out,
+            &batch,
+            GeometryKind::Point,
+            None,
+            pixel,
+            Crs::wgs84(),
+        )
+        .unwrap();
+        let (header, rest) = out.split_at(SIGNATURE.len() + 8);
+        assert_eq!(&header[..SIGNATURE.len()], SIGNATURE);
+        // Four fields: two reals, a null and the geometry, then the end marker.
+        assert_eq!(rest[..6], [0, 4, 0, 0, 0, 4]);
+        assert_eq!(rest[6..10], 1.5f32.to_be_bytes());
+        assert_eq!(rest[18..22], (-1i32).to_be_bytes());
+        assert_eq!(rest[22..26], 25i32.to_be_bytes());
+        assert_eq!(rest[rest.len() - 2..], (-1i16).to_be_bytes());
+        assert_eq!(rest.len(), 26 + 25 + 2);
+
+        assert_eq!(qualified("public.rasters"), "\"public\".\"rasters\"");
+    }
+}
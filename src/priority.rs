@@ -0,0 +1,68 @@
+use anyhow::Result;
+
+/// Lowers the CPU and IO priority of the process so it yields to interactive work.
+///
+/// This must run before any worker threads are started, since they inherit the
+/// priority of the thread that spawns them.
+pub fn lower() -> Result<()> {
+    sys::lower()
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use anyhow::{bail, Result};
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    pub fn lower() -> Result<()> {
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+                bail!(std::io::Error::last_os_error());
+            }
+            let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+            if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                bail!(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use anyhow::{bail, Result};
+
+    const PRIO_DARWIN_PROCESS: libc::c_int = 4;
+    const PRIO_DARWIN_BG: libc::c_int = 0x1000;
+
+    pub fn lower() -> Result<()> {
+        // Background priority throttles both CPU scheduling and disk IO.
+        if unsafe { libc::setpriority(PRIO_DARWIN_PROCESS, 0, PRIO_DARWIN_BG) } != 0 {
+            bail!(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+mod sys {
+    use anyhow::{bail, Result};
+
+    pub fn lower() -> Result<()> {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+            bail!(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use anyhow::{bail, Result};
+
+    pub fn lower() -> Result<()> {
+        bail!("--nice is not supported on this platform")
+    }
+}
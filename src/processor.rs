@@ -0,0 +1,3036 @@
+//! The conversion pipeline as a library: decoding a tif's pixels, transforming and
+//! grouping them, and writing them to a table.
+//!
+//! ```no_run
+//! use image_stats::{output::OutputFormat, processor::Processor};
+//!
+//! let processor = Processor::builder()
+//!     .group(0.5)
+//!     .format(OutputFormat::Fgb)
+//!     .build()?;
+//! processor.process("emissions.tif".as_ref())?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use crate::{
+    align, append, archive,
+    categorical::{self, Labels},
+    checksum::{self, Verified},
+    codec,
+    columns::{self, Rename},
+    contract::Contract,
+    crs::Crs,
+    delta,
+    distance::Features,
+    explain,
+    expr::Expr,
+    failure::{Classified, FailureClass::Unsupported},
+    fgb, geohash,
+    geometry::{GeometryKind, Pixel},
+    georef::{BBox, GeoTransform, LonRange, Priority},
+    gpkg,
+    group::{self, Aggregation, Align, Binned, Binning, CellSize, Cells, Grid, LonLat},
+    json::Value,
+    manifest::{self, Summary},
+    mask::Mask,
+    memory::{self, Budget},
+    metadata::{self, SourceMetadata},
+    mmap::TifContents,
+    mvt, netcdf,
+    notify::Outcome,
+    output::{self, Compression, OutputFormat, Target},
+    overview, planar, postgis,
+    raster::{self, ChunkSize, Layout, SampleFormat},
+    reclass::Classes,
+    resample::{self, Method},
+    sample, shp, sidecar,
+    sort::{self, Sort},
+    stdin,
+    strata::{self, Strata},
+    style,
+    template::Template,
+    thin,
+    time::{self, TimePattern},
+    timing::{self, PixelClocks, Stage, Timing, TimingFormat},
+    transform::{Builtin, Transform},
+    upload::{self, Destination},
+    watchdog::Watchdog,
+    DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{bail, Result};
+use arrow_array::{
+    Array, ArrayRef, Float32Array, Int32Array, RecordBatch, StringArray, TimestampSecondArray,
+    UInt32Array, UInt8Array,
+};
+use arrow_schema::{Field, Schema};
+use clap::{Args, FromArgMatches};
+use indicatif::{HumanCount, ProgressBar, ProgressState, ProgressStyle};
+use rayon::prelude::*;
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tiff::decoder::{Decoder, Limits};
+
+/// Everything that decides how an input is converted, shared by the command line and
+/// [`ProcessorBuilder`].
+#[derive(Args, Clone)]
+#[command(about = None, long_about = None)]
+pub struct Options {
+    /// Group pixels into grid cells of this size in the output CRS's units, as `0.5` for
+    /// square cells or `0.1x0.05` for cells 0.1 across and 0.05 up.
+    #[arg(
+        long = "group",
+        value_name = "SIZE",
+        group = "grid",
+        conflicts_with_all = ["s2", "h3", "tile_zoom"]
+    )]
+    pub group: Option<CellSize>,
+    /// Point the grouping grid is anchored to, as `lon,lat`.
+    #[arg(
+        long = "grid-origin",
+        default_value = "0,0",
+        requires = "group",
+        allow_hyphen_values = true
+    )]
+    pub grid_origin: LonLat,
+    /// Group on the same grid as a reference dataset, so that separate conversions share
+    /// cell positions: a tif's pixel grid, or the grid a parquet output was grouped on.
+    /// Sets the cell size and origin in place of `--group` and `--grid-origin`.
+    #[arg(
+        long = "align-to",
+        value_name = "REFERENCE",
+        group = "grid",
+        conflicts_with_all = ["s2", "h3", "tile_zoom"]
+    )]
+    pub align_to: Option<PathBuf>,
+    /// Whether grouped points are placed at the corner or center of their cell.
+    #[arg(long = "align", value_enum, default_value_t = Align::Corner, requires = "grid")]
+    pub align: Align,
+    /// Group pixels into the S2 cells of this level, adding an `s2_cell` id column.
+    #[arg(
+        long = "s2",
+        value_parser = clap::value_parser!(u8).range(0..=30),
+        conflicts_with_all = ["h3", "tile_zoom"]
+    )]
+    s2: Option<u8>,
+    /// Group pixels into the H3 cells of this resolution, adding an `h3_cell` index column.
+    #[arg(
+        long = "h3",
+        value_name = "RESOLUTION",
+        value_parser = clap::value_parser!(u8).range(0..=15),
+        conflicts_with = "tile_zoom"
+    )]
+    h3: Option<u8>,
+    /// Group pixels into web mercator tiles of this zoom, adding `z`, `x` and `y` columns.
+    #[arg(long = "tile-zoom", value_parser = clap::value_parser!(u8).range(0..=30))]
+    pub tile_zoom: Option<u8>,
+    /// Identify tiles with a single `quadkey` column instead of `z`, `x` and `y`.
+    #[arg(long = "quadkey", requires = "tile_zoom")]
+    pub quadkey: bool,
+    /// Group from the tif's coarsest internal overview that still has 4 pixels across
+    /// each cell, instead of its full resolution, when the cells are far larger than its
+    /// pixels. Overviews hold averaged pixels, so this needs `--agg mean`.
+    #[arg(
+        long = "overviews",
+        requires = "grid",
+        conflicts_with_all = ["resampling", "stratify_by"]
+    )]
+    pub overviews: bool,
+    /// How the pixels in each group are combined.
+    #[arg(long = "agg", value_enum, default_value_t = Aggregation::Sum)]
+    pub agg: Aggregation,
+    /// Break the output down by strata of a second raster on the same grid, such as a
+    /// DEM, adding a `stratum` column. Groups are aggregated separately for each stratum,
+    /// and pixels outside every stratum are dropped.
+    #[arg(
+        long = "stratify-by",
+        requires = "strata",
+        conflicts_with = "resampling"
+    )]
+    pub stratify_by: Option<PathBuf>,
+    /// Treat values as class codes, such as land cover types: groups take their most
+    /// common class in place of `--agg`, and classes are named by `--labels` and colored
+    /// from a palette tif's color table, in `label` and `color` columns.
+    #[arg(
+        long = "categorical",
+        conflicts_with_all = ["agg", "reclass", "stratify_by", "resampling"]
+    )]
+    pub categorical: bool,
+    /// With `--categorical`, give each group a row for each class in it, with the class
+    /// in a `class` column and its number of pixels as the value.
+    #[arg(long = "class-counts", requires = "categorical")]
+    pub class_counts: bool,
+    /// A CSV file of `code,label` lines naming the `--categorical` classes.
+    #[arg(long = "labels", value_name = "FILE", requires = "categorical")]
+    pub labels: Option<Labels>,
+    /// Map value ranges to integer class codes in a `class` column, as
+    /// `0-10:1,10-50:2,50+:3` or a CSV file of `min,max,class` lines. Ranges hold values
+    /// from their minimum up to but not including their maximum, and pixels outside every
+    /// range are dropped. Groups are aggregated separately for each class.
+    #[arg(
+        long = "reclass",
+        value_name = "CLASSES",
+        conflicts_with_all = ["stratify_by", "resampling", "dense"]
+    )]
+    pub reclass: Option<Classes>,
+    /// Boundaries of the `--stratify-by` strata, in increasing order, such as
+    /// `0,500,1000,2000`. Each stratum holds values from one boundary up to the next.
+    #[arg(
+        long = "strata",
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        requires = "stratify_by"
+    )]
+    pub strata: Vec<f64>,
+    /// CRS of the tif, such as `EPSG:3035` or the PROJ string `+proj=utm +zone=33
+    /// +datum=WGS84`. Defaults to the one in its GeoKeys when `--dst-crs` is given.
+    #[arg(long = "src-crs")]
+    pub src_crs: Option<Crs>,
+    /// CRS to write positions in, EPSG:4326 unless given. With either CRS option the tif's
+    /// extent is read from its GeoTIFF tags instead of assumed to be the whole world, and
+    /// `--bbox`, `--mask` and `--group` are in the output CRS's units.
+    #[arg(long = "dst-crs")]
+    pub dst_crs: Option<Crs>,
+    /// Write longitudes in this range, wrapping those past its ends, so rasters across the
+    /// antimeridian or from 0 to 360° group into whole cells there. `--bbox` is given in
+    /// the same range.
+    #[arg(long = "lon-range", value_enum, allow_hyphen_values = true)]
+    pub lon_range: Option<LonRange>,
+    /// Whether each pixel's position is its center or its top left corner. Grouped
+    /// points are placed by `--align` instead.
+    #[arg(long = "registration", value_enum, default_value_t = Align::Center)]
+    pub registration: Align,
+    /// Only keep pixels inside `minLon,minLat,maxLon,maxLat`. Strips and tiles entirely
+    /// outside the box are not decoded.
+    #[arg(long = "bbox", allow_hyphen_values = true)]
+    pub bbox: Option<BBox>,
+    /// Convert the pixels inside a region first, given as
+    /// `bbox:minLon,minLat,maxLon,maxLat`, and write them to `<output>.priority.parquet`
+    /// before converting the rest into the usual output, so readers of the two files can
+    /// start on the region early. Parquet only, and not with grouping, resampling or
+    /// thinning, which would combine pixels from both sides of the region's edge.
+    #[arg(
+        long = "stream-priority",
+        value_name = "REGION",
+        allow_hyphen_values = true,
+        conflicts_with_all = ["grid", "s2", "h3", "tile_zoom", "resample", "multires", "thin", "style_out"]
+    )]
+    pub stream_priority: Option<Priority>,
+    /// Only keep pixels whose centers fall inside the polygons of a `.geojson` or `.shp` file.
+    #[arg(long = "mask")]
+    pub mask: Option<PathBuf>,
+    /// Add a `distance_km` column holding the great-circle distance from each row's
+    /// position to the nearest point, line or polygon of a `.geojson` or `.shp` file.
+    /// Rows inside a polygon are at distance 0.
+    #[arg(long = "distance-to")]
+    pub distance_to: Option<PathBuf>,
+    /// Band of a multi-band tif to convert, counting from 1. Bands stored pixel interleaved
+    /// and band separate (PlanarConfiguration 2) are both read. The bands of a NetCDF
+    /// variable are its 2D slices, such as time steps.
+    #[arg(long = "band", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub band: u16,
+    /// Variable of NetCDF inputs to convert, for files holding more than one grid.
+    #[arg(long = "variable")]
+    pub variable: Option<String>,
+    /// Read samples as this type instead of what the tif's SampleFormat tag says, for
+    /// files that mistag signed values as unsigned or the reverse.
+    #[arg(long = "sample-format", value_enum)]
+    pub sample_format: Option<SampleFormat>,
+    /// Unit of the pixel values, recorded in the `value` column's metadata. Defaults to the
+    /// units in the tif's GDAL metadata.
+    #[arg(long = "unit")]
+    pub unit: Option<String>,
+    /// Description of the pixel values, recorded in the `value` column's metadata. Defaults
+    /// to the tif's GDAL band description or image description.
+    #[arg(long = "description")]
+    pub description: Option<String>,
+    /// Which of the tif's metadata keys are copied into the output's file metadata, as
+    /// comma separated patterns where `*` matches anything, such as `gdal:*,xmp:dc:*`.
+    /// Keys are listed by `inspect`. Pass an empty string to copy none.
+    #[arg(long = "metadata-filter", value_delimiter = ',', default_value = "*")]
+    pub metadata_filter: Vec<String>,
+    /// Multiply stored pixel values by this to get physical values. Defaults to the scale
+    /// in the tif's GDAL metadata.
+    #[arg(long = "scale", allow_hyphen_values = true)]
+    pub scale: Option<f64>,
+    /// Add this to scaled pixel values. Defaults to the offset in the tif's GDAL metadata.
+    #[arg(long = "offset", allow_hyphen_values = true)]
+    pub offset: Option<f64>,
+    /// Keep stored pixel values as they are, ignoring any scale and offset in the tif.
+    #[arg(long = "no-scale", conflicts_with_all = ["scale", "offset"])]
+    pub no_scale: bool,
+    /// Drop pixels whose value, after any scale, offset and `--expr`, is below this.
+    /// Without it, pixels with negative stored values are dropped.
+    #[arg(long = "min-value", allow_hyphen_values = true)]
+    pub min_value: Option<f64>,
+    /// Drop pixels whose value, after any scale, offset and `--expr`, is above this.
+    #[arg(long = "max-value", allow_hyphen_values = true)]
+    pub max_value: Option<f64>,
+    /// Keep pixels whose stored value is zero, which are otherwise dropped as empty.
+    #[arg(long = "keep-zero")]
+    pub keep_zero: bool,
+    /// Keep pixels of floating point rasters that are NaN or infinite, which are otherwise
+    /// dropped as nodata, as rows with a null value. Parquet only, and not with
+    /// resampling; grouping leaves them out of each cell's value.
+    #[arg(long = "keep-nan", conflicts_with = "resampling")]
+    pub keep_nan: bool,
+    /// Write the colors of true color (RGB or RGBA) tifs only as their values, packed as
+    /// `0xRRGGBB`, without the `r`, `g` and `b` columns of their levels. Pixels whose
+    /// alpha is 0 are dropped as nodata either way.
+    #[arg(long = "packed-color")]
+    pub packed_color: bool,
+    /// Write a row for every pixel, with a null value for those that would be dropped,
+    /// such as nodata, so the grid can be rebuilt exactly. Parquet only, and not with
+    /// grouping, resampling, thinning, `--bbox`, `--mask` or `--stratify-by`.
+    #[arg(
+        long = "dense",
+        conflicts_with_all = ["grid", "s2", "h3", "tile_zoom", "resample", "multires", "thin", "bbox", "mask", "stratify_by"]
+    )]
+    pub dense: bool,
+    /// Add `col` and `row` columns holding the position of each row's pixel in the image,
+    /// counting from 0 at the top left, or of its block with `--resample` and
+    /// `--multires`, to map rows back onto the raster.
+    #[arg(long = "with-indices", conflicts_with_all = ["grid", "s2", "h3", "tile_zoom"])]
+    pub with_indices: bool,
+    /// Treat values as densities per km² and multiply each pixel by its true area on the
+    /// ellipsoid, after `--expr` and the value filters, so sums are real totals. Grouping
+    /// then leaves the pixels unweighted.
+    #[arg(long = "per-area-to-total")]
+    pub per_area_to_total: bool,
+    /// Add a `time` column read from each input's file name with this pattern, such as
+    /// `emissions_%Y_%m.tif`. `%Y`, `%m`, `%d`, `%j`, `%H`, `%M` and `%S` match the parts
+    /// of the date and `*` matches anything.
+    #[arg(long = "time-from-filename", conflicts_with = "time")]
+    pub time_from_filename: Option<TimePattern>,
+    /// Add a `time` column holding this UTC time, such as `2020-01` or
+    /// `2020-01-31T12:00:00Z`, to every row.
+    #[arg(long = "time", value_parser = time::parse_time)]
+    pub time: Option<i64>,
+    /// Rewrite each pixel's value with an expression over `value`, `lon` and `lat`, such
+    /// as `log(value + 1)` or `value * 0.02 - 273.15`, before filtering and grouping.
+    /// Pixels it gives no number for, like `log` of a negative value, are dropped.
+    #[arg(long = "expr", allow_hyphen_values = true)]
+    pub expr: Option<Expr>,
+    /// Transform each pixel's value after `--expr` and before filtering, repeated to chain
+    /// them in order: `scale:F`, `offset:F`, `clamp:MIN,MAX`, `nodata:V` to drop pixels of
+    /// value V, or `reclass:MIN..MAX=V,...` to replace the values in each range.
+    #[arg(
+        long = "transform",
+        value_name = "TRANSFORM",
+        allow_hyphen_values = true
+    )]
+    pub transforms: Vec<Builtin>,
+    /// Keep a random fraction of the pixels, such as `0.01`, for small extracts that
+    /// represent the whole raster. The same `--seed` keeps the same pixels of an image.
+    #[arg(
+        long = "sample",
+        value_name = "FRACTION",
+        value_parser = sample::parse_fraction,
+        conflicts_with = "resampling"
+    )]
+    pub sample: Option<f64>,
+    /// Seed of the pixels `--sample` keeps.
+    #[arg(long = "seed", default_value_t = 0, requires = "sample")]
+    pub seed: u64,
+    /// Keep only every Nth pixel across and down, counting from the top left one, for
+    /// quick looks at large rasters. Strips and tiles holding none of them aren't decoded.
+    #[arg(
+        long = "stride",
+        conflicts_with = "resampling",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub stride: Option<u32>,
+    /// Downsample the raster by combining blocks of this many pixels across and down
+    /// before filtering, grouping or writing.
+    #[arg(
+        long = "resample",
+        group = "resampling",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub resample: Option<u32>,
+    /// Write the native pixels plus this many coarser levels, each combining 2x2 blocks of
+    /// the level before, into one parquet table with a `level` column counting up from 0
+    /// at the native resolution. Can't be used with `--resample`, grouping or `--thin`.
+    #[arg(
+        long = "multires",
+        group = "resampling",
+        conflicts_with_all = ["grid", "s2", "h3", "tile_zoom", "thin"],
+        value_parser = clap::value_parser!(u8).range(1..=16)
+    )]
+    pub multires: Option<u8>,
+    /// How the pixels of each `--resample` or `--multires` block are combined.
+    #[arg(
+        long = "resample-method",
+        value_enum,
+        default_value_t = Method::Average,
+        requires = "resampling"
+    )]
+    pub resample_method: Method,
+    /// Number of image rows decoded and processed together as one unit of work.
+    #[arg(long = "chunk-rows", conflicts_with = "chunk_tiles")]
+    pub chunk_rows: Option<u32>,
+    /// Number of strips or tiles decoded and processed together as one unit of work.
+    #[arg(long = "chunk-tiles")]
+    pub chunk_tiles: Option<u32>,
+    /// Keep each conversion within about this much memory, such as `512M` or `4G`. Strips
+    /// and tiles are decoded in units sized to fit unless `--chunk-rows` or
+    /// `--chunk-tiles` is given, and parquet outputs of pixels are written a band of image
+    /// rows at a time. Other outputs must be built whole, and fail up front when they
+    /// might not fit unless `--force` is given.
+    #[arg(long = "max-memory", value_name = "BYTES", value_parser = memory::parse_bytes)]
+    pub max_memory: Option<u64>,
+    /// Map plain `.tif` inputs into memory instead of reading them, so large uncompressed
+    /// files on local disk are decoded straight from the page cache without a copy, and
+    /// don't count against `--max-memory`. Zipped inputs are still read. An input must not
+    /// be truncated while it is converted.
+    #[arg(long = "mmap")]
+    pub mmap: bool,
+    /// Append a geohash column of this many characters computed from each row's position.
+    #[arg(long = "geohash", value_parser = clap::value_parser!(u8).range(1..=12))]
+    pub geohash: Option<u8>,
+    /// File format written next to each input.
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Parquet)]
+    pub format: OutputFormat,
+    /// Name outputs after this template instead of the input with the format's extension.
+    /// Relative paths are next to the input. Placeholders: `{stem}` (the input's name
+    /// without extension), `{ext}`, `{format}`, `{band}`, `{group}`, `{s2}`, `{h3}`,
+    /// `{zoom}`, `{date}` (UTC `YYYY-MM-DD`) and `{timestamp}` (UTC `YYYYMMDDTHHMMSSZ`).
+    #[arg(long = "output-template")]
+    pub output_template: Option<Template>,
+    /// Upload each parquet or Arrow stream output to this `s3://bucket/prefix/` or
+    /// `gs://bucket/prefix/` instead of writing it to disk, streamed through the `aws` or
+    /// `gcloud` CLI, which must be on the `PATH`. Objects already there are replaced. As
+    /// `-`, the output of a single input is written to stdout instead.
+    #[arg(
+        short = 'o',
+        long = "output",
+        value_name = "URL",
+        conflicts_with_all = ["append", "stream_priority", "skip_existing", "manifest"]
+    )]
+    pub output: Option<Target>,
+    /// Add each input's rows to the parquet dataset in this directory as a new file, named
+    /// `{stem}-{timestamp}.parquet` unless `--output-template` says otherwise. The rows
+    /// must have the same columns as the files already there.
+    #[arg(
+        long = "append",
+        value_name = "DIR",
+        conflicts_with = "stream_priority"
+    )]
+    pub append: Option<PathBuf>,
+    /// Load each input's rows into the PostgreSQL database at this URL, such as
+    /// `postgresql://user@host/db`, instead of writing a file, with a PostGIS `geom`
+    /// column holding each row's `--geometry`. Rows are sent with a binary COPY through
+    /// `psql`, which must be on the `PATH`. Passwords are better kept in `PGPASSWORD` or
+    /// `~/.pgpass` than in the URL, which other users can see in the process list.
+    #[arg(
+        long = "postgres",
+        value_name = "URL",
+        requires = "table",
+        conflicts_with_all = ["output", "append", "stream_priority", "skip_existing", "manifest", "style_out"]
+    )]
+    pub postgres: Option<String>,
+    /// The table `--postgres` adds rows to, as `name` or `schema.name`.
+    #[arg(long = "table", requires = "postgres")]
+    pub table: Option<String>,
+    /// Create the `--table` if it doesn't exist yet, with a column of a matching type for
+    /// each output column, the `geom` column and a GiST index on it.
+    #[arg(long = "create-table", requires = "postgres")]
+    pub create_table: bool,
+    /// The directory or `s3://bucket/prefix` of the Delta table `--format delta` adds rows
+    /// to, created with the first commit.
+    #[arg(long = "table-uri", value_name = "PATH|URI", value_parser = delta::parse_table_uri)]
+    pub table_uri: Option<PathBuf>,
+    /// Split a new Delta table's files into directories by the values of these columns,
+    /// such as `time`. Rows added to an existing table are split as it is.
+    #[arg(
+        long = "partition-by",
+        value_delimiter = ',',
+        value_name = "COLUMN",
+        requires = "table_uri"
+    )]
+    pub partition_by: Vec<String>,
+    /// With `--append`, leave out rows at positions the dataset already holds from an
+    /// input of the same name, so inputs can be added again without doubling rows.
+    #[arg(long = "dedupe", requires = "append")]
+    pub dedupe: bool,
+    /// Name of the GeoPackage table or vector tile layer the rows are written to. Defaults
+    /// to the input's file name without its extension.
+    #[arg(long = "layer")]
+    pub layer: Option<String>,
+    /// Lowest zoom level vector tiles are written for.
+    #[arg(long = "min-zoom", default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=24))]
+    pub min_zoom: u8,
+    /// Highest zoom level vector tiles are written for.
+    #[arg(long = "max-zoom", default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=24))]
+    pub max_zoom: u8,
+    /// Keep at most this many features in each vector tile, dropping those with the
+    /// smallest values, so low zooms stay light.
+    #[arg(long = "max-tile-features", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_tile_features: Option<u64>,
+    /// Thin the output so only the row with the largest value is kept in each square of
+    /// this size, in the units of the position columns.
+    #[arg(long = "thin")]
+    pub thin: Option<f64>,
+    /// Write rows in this order, so that each parquet row group covers a small region and
+    /// readers filtering by position can skip the others by their statistics.
+    #[arg(long = "sort", value_enum)]
+    pub sort: Option<Sort>,
+    /// Keep only the output rows this condition is true for, such as
+    /// `value > 10 && lat > 0`, checked after grouping and transforms. It is an `--expr`
+    /// expression, with the comparisons `< <= > >= == !=` and `&&`, `||` and `!`; `lon`
+    /// and `lat` are the position columns, in metres in projected CRSs.
+    #[arg(long = "where", allow_hyphen_values = true)]
+    pub row_filter: Option<Expr>,
+    /// Rename output columns, as `lon=longitude,lat=latitude,value=population`, in every
+    /// output format.
+    #[arg(long = "columns", value_delimiter = ',', value_name = "FROM=TO")]
+    pub columns: Vec<Rename>,
+    /// Name of the value column, short for `--columns value=NAME`.
+    #[arg(long = "value-name")]
+    pub value_name: Option<String>,
+    /// What each FlatGeobuf, shapefile, GeoPackage or vector tile feature's geometry is:
+    /// the row's position, or the pixel or group cell it covers.
+    #[arg(long = "geometry", value_enum, default_value_t = GeometryKind::Point)]
+    pub geometry: GeometryKind,
+    /// Also write a map style coloring the output by value: a QGIS style for a `.qml`
+    /// path, or a MapLibre style otherwise.
+    #[arg(long = "style-out")]
+    pub style_out: Option<PathBuf>,
+    /// Number of color classes in the `--style-out` style, split at quantiles of the values.
+    #[arg(
+        long = "style-classes",
+        default_value_t = 7,
+        value_parser = clap::value_parser!(u64).range(1..=64),
+        requires = "style_out"
+    )]
+    pub style_classes: u64,
+    #[command(flatten)]
+    pub compression: Compression,
+    /// Write output even when it looks like it will not fit on disk.
+    #[arg(long = "force")]
+    pub force: bool,
+    /// Leave an input alone when its output exists and is newer than it, so that an
+    /// interrupted batch can be run again cheaply.
+    #[arg(long = "skip-existing")]
+    pub skip_existing: bool,
+    /// Replace outputs that already exist, which is otherwise an error.
+    #[arg(long = "overwrite")]
+    pub overwrite: bool,
+    /// Also write `<output>.manifest.json`, recording the input's path and SHA-256, the
+    /// tool's version and arguments, the row count, the values' min, max and sum, the
+    /// bounding box and how long the conversion took.
+    #[arg(long = "manifest")]
+    pub manifest: bool,
+    /// Check each input against the checksum its provider published beside it, as
+    /// `<input>.sha256` or `<input>.md5`, before decoding it, failing if they differ. The
+    /// digest is recorded in the `--manifest`.
+    #[arg(long = "verify-checksum")]
+    pub verify_checksum: bool,
+    /// Check the input against this SHA-256 digest, in hex, before decoding it.
+    #[arg(long = "sha256", value_name = "HEX", value_parser = checksum::parse_sha256)]
+    pub sha256: Option<String>,
+    /// Report on stdout how long each stage took for each input and the whole batch, with
+    /// how much it got through: reading the input, decoding pixels, transforming,
+    /// grouping, encoding the output and writing it. As `text` (the default) or
+    /// `--timing=json`.
+    #[arg(
+        long = "timing",
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    pub timing: Option<TimingFormat>,
+    /// Check each output against this contract of column names, types, metadata and sort
+    /// order before writing it, and fail instead of writing an output that breaks it.
+    #[arg(long = "contract")]
+    pub contract: Option<PathBuf>,
+}
+
+impl Default for Options {
+    /// The options the command line starts from when no flags are given.
+    fn default() -> Self {
+        let command = Options::augment_args(clap::Command::new("image-stats"));
+        Options::from_arg_matches(&command.get_matches_from(["image-stats"]))
+            .expect("every option has a default")
+    }
+}
+
+impl Options {
+    pub fn chunk_size(&self) -> ChunkSize {
+        match (self.chunk_rows, self.chunk_tiles) {
+            (_, Some(tiles)) => ChunkSize::Tiles(tiles),
+            (Some(rows), _) => ChunkSize::Rows(rows),
+            (None, None) => ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        }
+    }
+
+    /// The size of the units `tif`'s image is decoded in: [`Options::chunk_size`], or
+    /// units that fit `--max-memory` when no size is given, though never larger than
+    /// they'd be without it.
+    pub fn unit_size(&self, tif: &TifContents, layout: &Layout, width: u32) -> Result<ChunkSize> {
+        Ok(match self.budget(tif)? {
+            Some(budget) if self.chunk_rows.is_none() && self.chunk_tiles.is_none() => {
+                ChunkSize::Rows(
+                    budget
+                        .chunk_rows(width, layout.samples())
+                        .min(DEFAULT_CHUNK_ROWS),
+                )
+            }
+            _ => self.chunk_size(),
+        })
+    }
+
+    /// Whether the output can be written a band of image rows at a time, as each row
+    /// depends only on its own pixel.
+    pub fn streams_bands(&self) -> bool {
+        // Rows of one band can't be grouped, resampled, thinned, sorted or checked
+        // together with those of the next.
+        matches!(
+            self.format,
+            OutputFormat::Parquet | OutputFormat::ArrowStream
+        ) && self.postgres.is_none()
+            && self.binning().is_none()
+            && self.resample.is_none()
+            && self.multires.is_none()
+            && self.thin.is_none()
+            && self.sort.is_none()
+            && self.stratify_by.is_none()
+            && self.style_out.is_none()
+            && self.stream_priority.is_none()
+            && self.contract.is_none()
+            && self.transforms.is_empty()
+    }
+
+    /// Whether pixels can be added to their group cells as they are decoded instead of
+    /// all being kept until the image is read, as no cell's value depends on another's
+    /// pixels or on how pixels are split between strata or classes.
+    pub fn groups_as_read(&self) -> bool {
+        self.binning().is_some()
+            && self.resample.is_none()
+            && self.multires.is_none()
+            && !self.categorical
+            && self.strata_count().is_none()
+    }
+
+    /// Most cells the pixels of an image positioned by `transform` can be grouped into,
+    /// if that is known before they are read.
+    pub fn max_cells(&self, transform: &GeoTransform) -> Option<u64> {
+        match self.binning()? {
+            Binning::Grid(grid) => {
+                // Cells are only counted when the bounds are in the units they are laid
+                // out in.
+                if transform.crs().is_some_and(|(src, dst)| src != dst) {
+                    return None;
+                }
+                let bounds = transform.bounds();
+                let cells = |span: f64, size: f64| (span / size).ceil() as u64 + 1;
+                Some(
+                    cells(bounds.east - bounds.west, grid.size.lon)
+                        * cells(bounds.north - bounds.south, grid.size.lat),
+                )
+            }
+            // Each level splits the six faces of the cube, and each zoom the world, into
+            // four times as many cells as the last.
+            Binning::S2(level) => Some(6 << (2 * level as u32)),
+            // 122 cells at resolution 0, each resolution splitting them sevenfold.
+            Binning::H3(resolution) => Some(2 + 120 * 7u64.pow(resolution as u32)),
+            Binning::Tile { zoom, .. } => Some(1 << (2 * zoom as u32)),
+        }
+    }
+
+    /// What `--max-memory` leaves once `tif` is loaded, if it is given.
+    pub fn budget(&self, tif: &TifContents) -> Result<Option<Budget>> {
+        self.max_memory
+            .map(|max| Budget::new(max, tif.resident_bytes()))
+            .transpose()
+    }
+
+    /// The tif with its first image replaced by the overview `--overviews` reads for the
+    /// grid's cells, if it is given and the tif has one coarse enough.
+    fn overview(&self, input_path: &Path, contents: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (true, Some(Binning::Grid(grid))) = (self.overviews, self.binning()) else {
+            return Ok(None);
+        };
+        let mut decoder = Decoder::new(Cursor::new(contents))?.with_limits(Limits::unlimited());
+        let src_crs = sidecar::src_crs(input_path, self.src_crs, &mut decoder)?;
+        let transform = GeoTransform::resolve(&mut decoder, src_crs, self.dst_crs)?;
+        overview::coarsest(contents, transform.output_pixel_size(), grid.size)
+    }
+
+    /// Loads `input_path` for reading `--band`, returning the tif and the band to read
+    /// within its first image. `--overviews` may read an overview as the image, a band
+    /// stored separately is given an image of its own, and `--sample-format` replaces the
+    /// image's SampleFormat tag.
+    pub fn read_band(&self, input_path: &Path) -> Result<(TifContents, u32)> {
+        if netcdf::is_netcdf(input_path) {
+            let contents = std::fs::read(input_path)?;
+            let tif = netcdf::to_tif(&contents, self.variable.as_deref(), self.band)?;
+            return Ok((TifContents::Owned(tif), 0));
+        }
+        #[cfg(feature = "gdal")]
+        if crate::gdal::is_other(input_path) {
+            let tif = crate::gdal::to_tif(input_path, self.band)?;
+            return Ok((TifContents::Owned(tif), 0));
+        }
+        let mut contents = TifContents::load(input_path, self.mmap)?;
+        if let Some(overview) = self.overview(input_path, &contents)? {
+            contents = TifContents::Owned(overview);
+        }
+        #[cfg(feature = "gdal")]
+        if codec::needs_gdal(&contents)?
+            && input_path.extension().is_some_and(|e| e == "tif")
+            && archive::split(input_path).is_none()
+        {
+            let tif = crate::gdal::to_tif(input_path, self.band)?;
+            return Ok((TifContents::Owned(tif), 0));
+        }
+        if let Some(decompressed) = codec::decompress(&contents)? {
+            contents = TifContents::Owned(decompressed);
+        }
+        if let Some(format) = self.sample_format {
+            contents = TifContents::Owned(raster::with_sample_format(&contents, format)?);
+        }
+        let band = self.band - 1;
+        Ok(match planar::separate_bands(&contents)? {
+            Some(_) => (TifContents::Owned(planar::band_view(&contents, band)?), 0),
+            None => (contents, band as u32),
+        })
+    }
+
+    /// Reads the tif's metadata, with per-band items taken from `--band`.
+    pub fn source_metadata<R: std::io::Read + std::io::Seek>(
+        &self,
+        decoder: &mut Decoder<R>,
+    ) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            band: self.band as u32 - 1,
+            ..SourceMetadata::read(decoder)?
+        })
+    }
+
+    /// Reads options from a JSON object of long flag names to values, as the C ABI and
+    /// `serve` take them: `{"group": 0.5, "keep-zero": true}` means `--group 0.5
+    /// --keep-zero`. Underscores may be used in place of dashes, and a list gives a
+    /// repeatable option each of its values.
+    pub(crate) fn from_json(value: Value) -> Result<Options> {
+        let Value::Object(entries) = value else {
+            bail!("Options must be a JSON object");
+        };
+        let command = Options::augment_args(clap::Command::new("image-stats"));
+        let mut args = vec!["image-stats".to_string()];
+        for (key, value) in entries {
+            args.extend(flag_arguments(&command, &key, value)?);
+        }
+        Ok(Options::from_arg_matches(
+            &command.try_get_matches_from(args)?,
+        )?)
+    }
+
+    /// Whether a pixel's stored value passes the zero and sign checks.
+    pub fn keeps_stored(&self, value: f64) -> bool {
+        match (value, self.min_value) {
+            (0.0, _) => self.keep_zero,
+            (value, None) => value > 0.0,
+            (_, Some(_)) => true,
+        }
+    }
+
+    /// Whether a pixel's final value is within `--min-value` and `--max-value`.
+    pub fn keeps_value(&self, value: f64) -> bool {
+        !value.is_nan()
+            && self.min_value.is_none_or(|min| value >= min)
+            && self.max_value.is_none_or(|max| value <= max)
+    }
+
+    /// The `(scale, offset)` turning stored values into physical ones, or `None` if they
+    /// are used as they are.
+    pub fn scaling(&self, source: &SourceMetadata) -> Option<(f64, f64)> {
+        if self.no_scale {
+            return None;
+        }
+        let (scale, offset) = source.scale_offset();
+        let scale = self.scale.or(scale).unwrap_or(1.0);
+        let offset = self.offset.or(offset).unwrap_or(0.0);
+        (scale != 1.0 || offset != 0.0).then_some((scale, offset))
+    }
+
+    /// Where the output for `input_path` is written, which is an object's URL or stdout
+    /// with `--output`, or names the table with `--postgres`.
+    pub fn output_path(&self, input_path: &Path) -> Result<PathBuf> {
+        if let Some(table) = &self.table {
+            return Ok(PathBuf::from(format!("postgres table {}", table)));
+        }
+        let path = self.local_output_path(input_path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Ok(match &self.output {
+            None => path,
+            Some(Target::Stdout) => PathBuf::from(output::STDOUT),
+            Some(Target::Bucket(destination)) => PathBuf::from(destination.url(&name)),
+        })
+    }
+
+    fn local_output_path(&self, input_path: &Path) -> Result<PathBuf> {
+        if let (OutputFormat::Delta, Some(table)) = (self.format, &self.table_uri) {
+            return Ok(table.clone());
+        }
+        let input_path = &archive::name(stdin::name(input_path));
+        let template = match (&self.output_template, &self.append) {
+            (Some(template), _) => template.clone(),
+            (None, Some(_)) => append::NAME.parse()?,
+            (None, None) => return Ok(input_path.with_extension(self.format.extension())),
+        };
+        let stem = input_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string());
+        let name = template.render(&[
+            ("stem", stem),
+            ("ext", Some(self.format.extension().to_string())),
+            ("format", Some(explain::value_name(&self.format))),
+            ("band", Some(self.band.to_string())),
+            ("group", self.group.map(|size| size.to_string())),
+            ("s2", self.s2.map(|level| level.to_string())),
+            ("h3", self.h3.map(|resolution| resolution.to_string())),
+            ("zoom", self.tile_zoom.map(|zoom| zoom.to_string())),
+        ])?;
+        let dir = match &self.append {
+            Some(dir) => dir.as_path(),
+            None => input_path.parent().unwrap_or(Path::new("")),
+        };
+        Ok(dir.join(name))
+    }
+
+    /// The time attached to the rows of `input_path`, as seconds since 1970 UTC.
+    pub fn time(&self, input_path: &Path) -> Result<Option<i64>> {
+        let Some(pattern) = &self.time_from_filename else {
+            return Ok(self.time);
+        };
+        let name = input_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match pattern.parse(&name) {
+            Some(time) => Ok(Some(time)),
+            None => bail!("No time matching --time-from-filename in {}", name),
+        }
+    }
+
+    /// The output columns `--columns` and `--value-name` rename.
+    pub fn renames(&self) -> Vec<Rename> {
+        let value = self.value_name.iter().map(|name| Rename {
+            from: "value".to_string(),
+            to: name.clone(),
+        });
+        self.columns.iter().cloned().chain(value).collect()
+    }
+
+    /// The name the value column is written with.
+    pub fn value_column(&self) -> String {
+        columns::renamed(&self.renames(), "value").to_string()
+    }
+
+    pub fn layer_name(&self, input_path: &Path) -> String {
+        match &self.layer {
+            Some(layer) => layer.clone(),
+            None => input_path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Roughly how many bytes `batch` takes up written in the output format.
+    pub fn estimate_size(&self, batch: &RecordBatch) -> u64 {
+        match self.format {
+            OutputFormat::Parquet | OutputFormat::Delta => {
+                output::estimate_parquet_size(batch, self.compression.codec)
+            }
+            OutputFormat::ArrowStream => output::estimate_arrow_size(batch),
+            OutputFormat::Fgb => fgb::estimate_size(batch, self.geometry),
+            OutputFormat::Shp => shp::estimate_size(batch, self.geometry),
+            OutputFormat::Gpkg => gpkg::estimate_size(batch, self.geometry),
+            OutputFormat::Mvt | OutputFormat::Mbtiles => {
+                mvt::estimate_size(batch, self.geometry, self.min_zoom..=self.max_zoom)
+            }
+        }
+    }
+
+    pub fn binning(&self) -> Option<Binning> {
+        match (self.group, self.s2, self.h3, self.tile_zoom) {
+            (Some(size), _, _, _) => Some(Binning::Grid(Grid {
+                size,
+                origin: self.grid_origin,
+                align: self.align,
+                wraps: self.lon_range.is_some(),
+            })),
+            (_, Some(level), _, _) => Some(Binning::S2(level)),
+            (_, _, Some(resolution), _) => Some(Binning::H3(resolution)),
+            (_, _, _, Some(zoom)) => Some(Binning::Tile {
+                zoom,
+                quadkey: self.quadkey,
+            }),
+            (None, None, None, None) => None,
+        }
+    }
+
+    /// Takes the grid's cell size and origin from the `--align-to` reference.
+    fn align_grid(&mut self) -> Result<()> {
+        if let Some(reference) = &self.align_to {
+            let (size, origin) = align::reference_grid(reference, self.src_crs, self.dst_crs)?;
+            self.group = Some(size);
+            self.grid_origin = origin;
+        }
+        Ok(())
+    }
+
+    /// How many `--stratify-by` strata or `--reclass` classes rows are told apart by, if
+    /// they are.
+    fn strata_count(&self) -> Option<usize> {
+        match (&self.stratify_by, &self.reclass) {
+            (Some(_), _) => Some(self.strata.len()),
+            (None, Some(classes)) => Some(classes.count()),
+            (None, None) => None,
+        }
+    }
+
+    /// Rejects combinations of options that can't be converted.
+    fn check(&self) -> Result<()> {
+        // A level out of the codec's range fails before any input is read.
+        self.compression.parquet()?;
+        if let Some(crs) = self.dst_crs.filter(|crs| !crs.is_geographic()) {
+            if self.s2.is_some()
+                || self.h3.is_some()
+                || self.tile_zoom.is_some()
+                || self.geohash.is_some()
+                || self.distance_to.is_some()
+            {
+                bail!(
+                    "--s2, --h3, --tile-zoom, --geohash and --distance-to need lon/lat output, not {}",
+                    crs
+                );
+            }
+        }
+        if let Some(crs) = self.dst_crs.filter(|crs| !crs.is_geographic()) {
+            if self.lon_range.is_some() {
+                bail!("--lon-range needs lon/lat output, not {}", crs);
+            }
+        }
+        if self.multires.is_some() && !matches!(self.format, OutputFormat::Parquet) {
+            bail!(
+                "--multires is only written to parquet, not {}",
+                self.format.extension()
+            );
+        }
+        if self.contract.is_some() && !matches!(self.format, OutputFormat::Parquet) {
+            bail!(
+                "--contract checks parquet schemas, not {}",
+                self.format.extension()
+            );
+        }
+        let renames = self.renames();
+        for (i, rename) in renames.iter().enumerate() {
+            if renames[..i].iter().any(|other| other.from == rename.from) {
+                bail!("The {} column is renamed more than once", rename.from);
+            }
+        }
+        if self.class_counts && self.binning().is_none() {
+            bail!("--class-counts counts the classes in each group, so needs grouping");
+        }
+        if self.overviews && !matches!(self.agg, Aggregation::Mean) {
+            bail!("Overviews hold averaged pixels, so --overviews needs --agg mean");
+        }
+        if self.dense && !matches!(self.format, OutputFormat::Parquet) {
+            bail!(
+                "--dense is only written to parquet, not {}",
+                self.format.extension()
+            );
+        }
+        if self.keep_nan && !matches!(self.format, OutputFormat::Parquet) {
+            bail!(
+                "--keep-nan is only written to parquet, not {}",
+                self.format.extension()
+            );
+        }
+        if self.append.is_some() && !matches!(self.format, OutputFormat::Parquet) {
+            bail!(
+                "--append adds to parquet datasets, not {}",
+                self.format.extension()
+            );
+        }
+        if self.postgres.is_some() && !matches!(self.format, OutputFormat::Parquet) {
+            bail!("--postgres loads rows into a table, so takes no --format");
+        }
+        if matches!(self.format, OutputFormat::Delta) != self.table_uri.is_some() {
+            bail!("--format delta and --table-uri go together");
+        }
+        if self.output.is_some()
+            && !matches!(
+                self.format,
+                OutputFormat::Parquet | OutputFormat::ArrowStream
+            )
+        {
+            bail!(
+                "--output takes parquet or arrow-stream, not {}",
+                self.format.extension()
+            );
+        }
+        if self.output == Some(Target::Stdout) {
+            if self.timing.is_some() {
+                bail!("--timing reports on stdout, where -o - writes the output");
+            }
+            if self.style_out.is_some() {
+                bail!("--style-out styles a file, not an output written to stdout");
+            }
+        }
+        if self.stream_priority.is_some() && !matches!(self.format, OutputFormat::Parquet) {
+            bail!(
+                "--stream-priority is only written to parquet, not {}",
+                self.format.extension()
+            );
+        }
+        if self.stratify_by.is_some() {
+            strata::check_breaks(&self.strata)?;
+        }
+        if self.min_zoom > self.max_zoom {
+            bail!(
+                "--min-zoom {} is above --max-zoom {}",
+                self.min_zoom,
+                self.max_zoom
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Sets up a [`Processor`], starting from the command line's defaults.
+#[derive(Clone, Default)]
+pub struct ProcessorBuilder {
+    options: Options,
+    transforms: Vec<Arc<dyn Transform>>,
+}
+
+impl ProcessorBuilder {
+    /// Starts from already gathered options, such as the command line's.
+    pub fn from_options(options: Options) -> Self {
+        ProcessorBuilder {
+            options,
+            transforms: vec![],
+        }
+    }
+
+    // Input
+
+    /// Decodes this many image rows together as one unit of work.
+    pub fn chunk_rows(mut self, rows: u32) -> Self {
+        self.options.chunk_rows = Some(rows);
+        self.options.chunk_tiles = None;
+        self
+    }
+
+    /// Decodes this many strips or tiles together as one unit of work.
+    pub fn chunk_tiles(mut self, tiles: u32) -> Self {
+        self.options.chunk_tiles = Some(tiles);
+        self.options.chunk_rows = None;
+        self
+    }
+
+    /// Maps plain `.tif` inputs into memory instead of reading them.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.options.mmap = mmap;
+        self
+    }
+
+    /// Keeps each conversion within about `bytes` of memory.
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.options.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn src_crs(mut self, crs: Crs) -> Self {
+        self.options.src_crs = Some(crs);
+        self
+    }
+
+    pub fn dst_crs(mut self, crs: Crs) -> Self {
+        self.options.dst_crs = Some(crs);
+        self
+    }
+
+    /// Converts this band of a multi-band tif, counting from 1.
+    pub fn band(mut self, band: u16) -> Self {
+        self.options.band = band;
+        self
+    }
+
+    /// Converts this variable of NetCDF inputs.
+    pub fn variable(mut self, name: impl Into<String>) -> Self {
+        self.options.variable = Some(name.into());
+        self
+    }
+
+    /// Reads samples as `format` whatever the tif's SampleFormat tag says.
+    pub fn sample_format(mut self, format: SampleFormat) -> Self {
+        self.options.sample_format = Some(format);
+        self
+    }
+
+    // Transforms
+
+    /// Adds a transform after those given with `--transform`, in the order added.
+    pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Replaces the scale and offset in the tif's GDAL metadata.
+    pub fn scaling(mut self, scale: f64, offset: f64) -> Self {
+        self.options.scale = Some(scale);
+        self.options.offset = Some(offset);
+        self.options.no_scale = false;
+        self
+    }
+
+    /// Keeps stored values as they are, ignoring any scale and offset in the tif.
+    pub fn no_scale(mut self) -> Self {
+        self.options.no_scale = true;
+        self.options.scale = None;
+        self.options.offset = None;
+        self
+    }
+
+    pub fn expr(mut self, expr: Expr) -> Self {
+        self.options.expr = Some(expr);
+        self
+    }
+
+    pub fn min_value(mut self, min: f64) -> Self {
+        self.options.min_value = Some(min);
+        self
+    }
+
+    pub fn max_value(mut self, max: f64) -> Self {
+        self.options.max_value = Some(max);
+        self
+    }
+
+    pub fn keep_zero(mut self, keep: bool) -> Self {
+        self.options.keep_zero = keep;
+        self
+    }
+
+    /// Keeps NaN and infinite pixels of float rasters as rows with null values.
+    pub fn keep_nan(mut self, keep: bool) -> Self {
+        self.options.keep_nan = keep;
+        self
+    }
+
+    /// Writes true color pixels only as packed values, without `r`, `g` and `b` columns.
+    pub fn packed_color(mut self, packed: bool) -> Self {
+        self.options.packed_color = packed;
+        self
+    }
+
+    /// Adds `col` and `row` columns holding each row's position in the image.
+    pub fn with_indices(mut self, with: bool) -> Self {
+        self.options.with_indices = with;
+        self
+    }
+
+    /// Writes a row for every pixel, with null values for those that would be dropped.
+    pub fn dense(mut self, dense: bool) -> Self {
+        self.options.dense = dense;
+        self
+    }
+
+    pub fn bbox(mut self, bbox: BBox) -> Self {
+        self.options.bbox = Some(bbox);
+        self
+    }
+
+    /// Converts and writes the pixels inside `region` before the rest.
+    pub fn stream_priority(mut self, region: BBox) -> Self {
+        self.options.stream_priority = Some(Priority::BBox(region));
+        self
+    }
+
+    /// Keeps pixels whose centers are inside the polygons of a `.geojson` or `.shp` file.
+    pub fn mask(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.mask = Some(path.into());
+        self
+    }
+
+    pub fn per_area_to_total(mut self, enabled: bool) -> Self {
+        self.options.per_area_to_total = enabled;
+        self
+    }
+
+    pub fn resample(mut self, factor: u32, method: Method) -> Self {
+        self.options.resample = Some(factor);
+        self.options.resample_method = method;
+        self
+    }
+
+    /// Attaches this time, in seconds since 1970 UTC, to every row.
+    pub fn time(mut self, time: i64) -> Self {
+        self.options.time = Some(time);
+        self.options.time_from_filename = None;
+        self
+    }
+
+    pub fn time_from_filename(mut self, pattern: TimePattern) -> Self {
+        self.options.time_from_filename = Some(pattern);
+        self.options.time = None;
+        self
+    }
+
+    pub fn unit(mut self, unit: impl Into<String>) -> Self {
+        self.options.unit = Some(unit.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.options.description = Some(description.into());
+        self
+    }
+
+    /// Patterns of the tif metadata keys copied into the output's file metadata.
+    pub fn metadata_filter(mut self, patterns: Vec<String>) -> Self {
+        self.options.metadata_filter = patterns;
+        self
+    }
+
+    pub fn geohash(mut self, precision: u8) -> Self {
+        self.options.geohash = Some(precision);
+        self
+    }
+
+    /// Keeps a random `fraction` of the pixels, chosen by `seed`.
+    pub fn sample(mut self, fraction: f64, seed: u64) -> Self {
+        self.options.sample = Some(fraction);
+        self.options.seed = seed;
+        self
+    }
+
+    /// Keeps only every `stride`th pixel across and down.
+    pub fn stride(mut self, stride: u32) -> Self {
+        self.options.stride = Some(stride);
+        self
+    }
+
+    /// Adds `levels` coarser levels of 2x2 blocks below the native pixels, combined with
+    /// `method`.
+    pub fn multires(mut self, levels: u8, method: Method) -> Self {
+        self.options.multires = Some(levels);
+        self.options.resample_method = method;
+        self
+    }
+
+    pub fn thin(mut self, tolerance: f64) -> Self {
+        self.options.thin = Some(tolerance);
+        self
+    }
+
+    /// Writes rows in `order`.
+    pub fn sort(mut self, order: Sort) -> Self {
+        self.options.sort = Some(order);
+        self
+    }
+
+    /// Renames the output column `from` to `to`.
+    pub fn rename_column(mut self, from: &str, to: &str) -> Self {
+        self.options.columns.push(Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        self
+    }
+
+    /// Keeps only the output rows `condition` is true for.
+    pub fn row_filter(mut self, condition: Expr) -> Self {
+        self.options.row_filter = Some(condition);
+        self
+    }
+
+    // Grouping
+
+    /// Groups pixels into grid cells of this size, square unless given as a [`CellSize`].
+    pub fn group(mut self, size: impl Into<CellSize>) -> Self {
+        self.options.group = Some(size.into());
+        self.options.s2 = None;
+        self.options.h3 = None;
+        self.options.tile_zoom = None;
+        self
+    }
+
+    /// Groups from the coarsest internal overview with enough pixels in each cell.
+    pub fn overviews(mut self, with: bool) -> Self {
+        self.options.overviews = with;
+        self
+    }
+
+    /// Breaks the output down by which of the strata between consecutive `breaks` the
+    /// raster at `path` places each pixel in.
+    pub fn stratify_by(mut self, path: &Path, breaks: Vec<f64>) -> Self {
+        self.options.stratify_by = Some(path.to_path_buf());
+        self.options.strata = breaks;
+        self
+    }
+
+    /// Treats values as class codes, grouping them by their most common class, or with
+    /// `counts` by the pixels of each class, and naming them with `labels`.
+    pub fn categorical(mut self, counts: bool, labels: Option<Labels>) -> Self {
+        self.options.categorical = true;
+        self.options.class_counts = counts;
+        self.options.labels = labels;
+        self
+    }
+
+    /// Maps value ranges to class codes in a `class` column.
+    pub fn reclass(mut self, classes: Classes) -> Self {
+        self.options.reclass = Some(classes);
+        self
+    }
+
+    /// Groups on the grid of a reference tif or grouped parquet output, read when the
+    /// processor is built.
+    pub fn align_to(mut self, reference: &Path) -> Self {
+        self.options.align_to = Some(reference.to_path_buf());
+        self.options.s2 = None;
+        self.options.h3 = None;
+        self.options.tile_zoom = None;
+        self
+    }
+
+    pub fn grid_origin(mut self, origin: LonLat) -> Self {
+        self.options.grid_origin = origin;
+        self
+    }
+
+    pub fn align(mut self, align: Align) -> Self {
+        self.options.align = align;
+        self
+    }
+
+    /// Writes longitudes in `range`, wrapping those past its ends.
+    pub fn lon_range(mut self, range: LonRange) -> Self {
+        self.options.lon_range = Some(range);
+        self
+    }
+
+    /// Places each pixel at its center or its top left corner.
+    pub fn registration(mut self, registration: Align) -> Self {
+        self.options.registration = registration;
+        self
+    }
+
+    /// Groups pixels into the S2 cells of this level.
+    pub fn s2(mut self, level: u8) -> Self {
+        self.options.s2 = Some(level);
+        self.options.group = None;
+        self.options.h3 = None;
+        self.options.tile_zoom = None;
+        self
+    }
+
+    /// Groups pixels into the H3 cells of this resolution.
+    pub fn h3(mut self, resolution: u8) -> Self {
+        self.options.h3 = Some(resolution);
+        self.options.group = None;
+        self.options.s2 = None;
+        self.options.tile_zoom = None;
+        self
+    }
+
+    /// Groups pixels into web mercator tiles of this zoom.
+    pub fn tile_zoom(mut self, zoom: u8, quadkey: bool) -> Self {
+        self.options.tile_zoom = Some(zoom);
+        self.options.quadkey = quadkey;
+        self.options.group = None;
+        self.options.s2 = None;
+        self.options.h3 = None;
+        self
+    }
+
+    pub fn agg(mut self, agg: Aggregation) -> Self {
+        self.options.agg = agg;
+        self
+    }
+
+    // Output
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    /// Adds each input's rows to the Delta table at `table` as a new commit, splitting a
+    /// new table's files by the values of `partition_by`.
+    pub fn delta_table(mut self, table: impl Into<PathBuf>, partition_by: &[&str]) -> Self {
+        self.options.format = OutputFormat::Delta;
+        self.options.table_uri = Some(table.into());
+        self.options.partition_by = partition_by.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// Loads each input's rows into `table` of the PostgreSQL database at `url`, creating
+    /// the table first if `create` is set.
+    pub fn postgres(mut self, url: &str, table: &str, create: bool) -> Self {
+        self.options.postgres = Some(url.to_string());
+        self.options.table = Some(table.to_string());
+        self.options.create_table = create;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn output_template(mut self, template: Template) -> Self {
+        self.options.output_template = Some(template);
+        self
+    }
+
+    /// Uploads outputs under `destination` instead of writing them to disk.
+    pub fn output(mut self, destination: Destination) -> Self {
+        self.options.output = Some(Target::Bucket(destination));
+        self
+    }
+
+    /// Writes the output to stdout instead of beside the input.
+    pub fn to_stdout(mut self) -> Self {
+        self.options.output = Some(Target::Stdout);
+        self
+    }
+
+    /// Adds outputs to the parquet dataset in `dir` as new files.
+    pub fn append(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.append = Some(dir.into());
+        self
+    }
+
+    /// Leaves out rows the `append` dataset already holds from an input of the same name.
+    pub fn dedupe(mut self, dedupe: bool) -> Self {
+        self.options.dedupe = dedupe;
+        self
+    }
+
+    pub fn layer(mut self, layer: impl Into<String>) -> Self {
+        self.options.layer = Some(layer.into());
+        self
+    }
+
+    pub fn geometry(mut self, geometry: GeometryKind) -> Self {
+        self.options.geometry = geometry;
+        self
+    }
+
+    /// Zoom levels vector tiles are written for.
+    pub fn zooms(mut self, min: u8, max: u8) -> Self {
+        self.options.min_zoom = min;
+        self.options.max_zoom = max;
+        self
+    }
+
+    pub fn max_tile_features(mut self, max: u64) -> Self {
+        self.options.max_tile_features = Some(max);
+        self
+    }
+
+    /// Also writes a map style with this many color classes to `path`.
+    pub fn style(mut self, path: impl Into<PathBuf>, classes: u64) -> Self {
+        self.options.style_out = Some(path.into());
+        self.options.style_classes = classes;
+        self
+    }
+
+    /// Writes output even when it looks like it will not fit on disk.
+    pub fn force(mut self, force: bool) -> Self {
+        self.options.force = force;
+        self
+    }
+
+    /// Leaves inputs whose output is newer than them alone.
+    pub fn skip_existing(mut self, skip_existing: bool) -> Self {
+        self.options.skip_existing = skip_existing;
+        self
+    }
+
+    /// Replaces outputs that already exist.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.options.overwrite = overwrite;
+        self
+    }
+
+    /// Writes a manifest of provenance and statistics beside each output.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.options.manifest = manifest;
+        self
+    }
+
+    /// Checks each input against the checksum published beside it before decoding it.
+    pub fn verify_checksum(mut self, verify: bool) -> Self {
+        self.options.verify_checksum = verify;
+        self
+    }
+
+    /// Checks the input against this SHA-256 digest, in hex, before decoding it.
+    pub fn sha256(mut self, digest: impl Into<String>) -> Self {
+        self.options.sha256 = Some(digest.into());
+        self
+    }
+
+    /// Checks each output against the contract at `path` before writing it.
+    pub fn contract(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.contract = Some(path.into());
+        self
+    }
+
+    pub fn build(mut self) -> Result<Processor> {
+        self.options.align_grid()?;
+        self.options.check()?;
+        let builtins = self.options.transforms.iter().cloned();
+        let transforms = builtins
+            .map(|builtin| Arc::new(builtin) as Arc<dyn Transform>)
+            .chain(self.transforms)
+            .collect();
+        // Loaded up front so a broken contract fails before any input is read.
+        let contract = self
+            .options
+            .contract
+            .as_deref()
+            .map(Contract::load)
+            .transpose()?;
+        Ok(Processor {
+            options: self.options,
+            transforms,
+            contract,
+            timings: Mutex::new(vec![]),
+        })
+    }
+}
+
+/// Converts tifs to tables with a fixed set of [`Options`].
+pub struct Processor {
+    options: Options,
+    transforms: Vec<Arc<dyn Transform>>,
+    contract: Option<Contract>,
+    /// Each input's stages with `--timing`, in the order they finished.
+    timings: Mutex<Vec<(PathBuf, Timing)>>,
+}
+
+impl Processor {
+    pub fn builder() -> ProcessorBuilder {
+        ProcessorBuilder::default()
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Reads `input_path`'s pixels into the output table without writing it.
+    pub fn to_batch(&self, input_path: &Path) -> Result<RecordBatch> {
+        let tif = self.options.read_band(input_path)?;
+        Ok(self
+            .read(
+                input_path,
+                &tif,
+                &ProgressBar::hidden(),
+                &Watchdog::unlimited(),
+                Part::Whole,
+            )?
+            .0)
+    }
+
+    /// Converts `input_path` and writes the output next to it.
+    pub fn process(&self, input_path: &Path) -> Result<Outcome> {
+        self.process_with_progress(input_path, &ProgressBar::hidden())
+    }
+
+    /// Like [`Processor::process`], reporting each stage on `bar`.
+    pub fn process_with_progress(&self, input_path: &Path, bar: &ProgressBar) -> Result<Outcome> {
+        self.process_watched(input_path, bar, &Watchdog::unlimited())
+    }
+
+    /// Like [`Processor::process_with_progress`], stopping without writing anything once
+    /// `watchdog` expires.
+    pub fn process_watched(
+        &self,
+        input_path: &Path,
+        bar: &ProgressBar,
+        watchdog: &Watchdog,
+    ) -> Result<Outcome> {
+        let output_path = self.options.output_path(input_path)?;
+        self.write(input_path, output_path, bar, watchdog)
+    }
+
+    /// Converts `input_path` and writes the output to `output_path` instead of the path
+    /// the output template gives.
+    pub fn process_to(&self, input_path: &Path, output_path: &Path) -> Result<Outcome> {
+        self.write(
+            input_path,
+            output_path.to_path_buf(),
+            &ProgressBar::hidden(),
+            &Watchdog::unlimited(),
+        )
+    }
+
+    /// With `--append --dedupe`, the positions of the rows the dataset already holds from
+    /// `input_path`.
+    fn seen(&self, input_path: &Path) -> Result<HashSet<(u32, u32)>> {
+        match (&self.options.append, self.options.dedupe) {
+            (Some(dir), true) => append::seen(dir, &manifest::source_name(input_path)),
+            _ => Ok(HashSet::new()),
+        }
+    }
+
+    /// With `--append`, leaves out the rows in `seen` and checks the rest can join the
+    /// dataset.
+    fn join_dataset(&self, batch: RecordBatch, seen: &HashSet<(u32, u32)>) -> Result<RecordBatch> {
+        let Some(dir) = &self.options.append else {
+            return Ok(batch);
+        };
+        let batch = append::dedupe(&batch, seen)?;
+        append::check_schema(dir, &batch.schema())?;
+        Ok(batch)
+    }
+
+    fn check_contract(&self, batch: &RecordBatch) -> Result<()> {
+        match &self.contract {
+            Some(contract) => contract.check(batch),
+            None => Ok(()),
+        }
+    }
+
+    fn write(
+        &self,
+        input_path: &Path,
+        output_path: PathBuf,
+        bar: &ProgressBar,
+        watchdog: &Watchdog,
+    ) -> Result<Outcome> {
+        let options = &self.options;
+        // Delta and PostgreSQL tables are added to, not replaced.
+        let adds = matches!(options.format, OutputFormat::Delta) || options.postgres.is_some();
+        if output_path.exists() && !adds && !output::is_stdout(&output_path) {
+            let modified = |path: &Path| std::fs::metadata(path)?.modified();
+            // What is piped on stdin is new each time, so its output is never up to date.
+            if options.skip_existing
+                && !stdin::is_stdin(input_path)
+                && modified(&output_path)? > modified(archive::file(input_path))?
+            {
+                bar.finish_with_message("up to date");
+                return Ok(Outcome::UpToDate {
+                    output: output_path,
+                });
+            }
+            if !options.overwrite {
+                bail!(
+                    "{} already exists, pass --overwrite to replace it",
+                    output_path.to_string_lossy()
+                );
+            }
+        }
+        let started = Instant::now();
+        let verified = match (options.verify_checksum, &options.sha256) {
+            (false, None) => None,
+            (_, sha256) => {
+                watchdog.stage(bar, "verifying checksum");
+                Some(checksum::verify(input_path, sha256.as_deref())?)
+            }
+        };
+        watchdog.stage(bar, "reading file");
+        let tif = options.read_band(input_path)?;
+        watchdog.record(Stage::Read, started.elapsed(), tif.0.len() as u64);
+        if let Some(bands) = self.bands(input_path, &tif)? {
+            return self.write_bands(
+                input_path,
+                &tif,
+                output_path,
+                bar,
+                watchdog,
+                bands,
+                verified.as_ref(),
+                started,
+            );
+        }
+        let mut summary = Summary::new();
+        let part = match options.stream_priority {
+            None => Part::Whole,
+            Some(Priority::BBox(region)) => {
+                let (batch, transform) =
+                    self.read(input_path, &tif, bar, watchdog, Part::Inside(region))?;
+                watchdog.check()?;
+                self.check_contract(&batch)?;
+                // Written under another name and renamed, so readers never see it half done.
+                let priority_path = priority_path(&output_path);
+                let partial_path = priority_path.with_extension("parquet.partial");
+                output::check_free_space(
+                    &partial_path,
+                    output::estimate_parquet_size(&batch, options.compression.codec),
+                    options.force,
+                )?;
+                summary.add(&batch, &options.value_column());
+                watchdog.stage(bar, "writing priority region");
+                write_bar(bar, Some(batch.num_rows()))?;
+                let encoding = (Instant::now(), timing::writing());
+                output::write_parquet_with_progress(
+                    &partial_path,
+                    &batch,
+                    options.compression,
+                    manifest::footer(
+                        input_path,
+                        &summary,
+                        transform.crs().map_or(Crs::wgs84(), |(_, dst)| dst),
+                    ),
+                    |rows| bar.inc(rows),
+                )?;
+                bar.disable_steady_tick();
+                record_output(watchdog, encoding, batch.num_rows());
+                std::fs::rename(&partial_path, &priority_path)?;
+                Part::Outside(region)
+            }
+        };
+        let (batch, transform) = self.read(input_path, &tif, bar, watchdog, part)?;
+        watchdog.check()?;
+        let batch = self.join_dataset(batch, &self.seen(input_path)?)?;
+        self.check_contract(&batch)?;
+        let mut written = Summary::new();
+        written.add(&batch, &options.value_column());
+        summary.merge(&written);
+        let crs = transform.crs().map_or(Crs::wgs84(), |(_, dst)| dst);
+        let pixel = Pixel {
+            size: transform.output_pixel_size(),
+            registration: options.registration,
+        };
+
+        if let (Some(url), Some(table)) = (&options.postgres, &options.table) {
+            watchdog.stage(bar, "copying rows to postgres");
+            let encoding = (Instant::now(), timing::writing());
+            let target = postgis::Target {
+                url,
+                table,
+                create: options.create_table,
+            };
+            let binning = options.binning();
+            postgis::copy(
+                &target,
+                &batch,
+                options.geometry,
+                binning.as_ref(),
+                pixel,
+                crs,
+            )?;
+            record_output(watchdog, encoding, batch.num_rows());
+            return self.finish(
+                input_path,
+                output_path,
+                bar,
+                watchdog,
+                &summary,
+                verified.as_ref(),
+                started,
+            );
+        }
+        output::check_free_space(&output_path, options.estimate_size(&batch), options.force)?;
+        watchdog.stage(bar, format!("writing {}", options.format.extension()));
+        // Only parquet and Arrow streams are written a slice of rows at a time.
+        let rows = matches!(
+            options.format,
+            OutputFormat::Parquet | OutputFormat::ArrowStream
+        )
+        .then_some(batch.num_rows());
+        write_bar(bar, rows)?;
+        let encoding = (Instant::now(), timing::writing());
+        match options.format {
+            OutputFormat::Parquet => upload::retrying(&output_path, || {
+                bar.set_position(0);
+                output::write_parquet_with_progress(
+                    &output_path,
+                    &batch,
+                    options.compression,
+                    manifest::footer(input_path, &written, crs),
+                    |rows| bar.inc(rows),
+                )
+            })?,
+            OutputFormat::ArrowStream => upload::retrying(&output_path, || {
+                bar.set_position(0);
+                output::write_arrow_stream_with_progress(
+                    &output_path,
+                    &batch,
+                    manifest::footer(input_path, &written, crs),
+                    |rows| bar.inc(rows),
+                )
+            })?,
+            OutputFormat::Delta => {
+                delta::commit(
+                    &output_path,
+                    &batch,
+                    &options.partition_by,
+                    options.compression,
+                    manifest::footer(input_path, &written, crs),
+                )?;
+            }
+            OutputFormat::Fgb => fgb::write_fgb(
+                &output_path,
+                &batch,
+                options.geometry,
+                options.binning().as_ref(),
+                pixel,
+                crs,
+            )?,
+            OutputFormat::Shp => shp::write_shp(
+                &output_path,
+                &batch,
+                options.geometry,
+                options.binning().as_ref(),
+                pixel,
+                crs,
+            )?,
+            OutputFormat::Gpkg => gpkg::write_gpkg(
+                &output_path,
+                &options.layer_name(input_path),
+                &batch,
+                options.geometry,
+                options.binning().as_ref(),
+                pixel,
+                crs,
+            )?,
+            OutputFormat::Mvt | OutputFormat::Mbtiles => mvt::write_tiles(
+                &output_path,
+                match options.format {
+                    OutputFormat::Mvt => mvt::TileStore::Directory,
+                    _ => mvt::TileStore::MbTiles,
+                },
+                &options.layer_name(input_path),
+                &batch,
+                options.geometry,
+                options.binning().as_ref(),
+                pixel,
+                crs,
+                options.min_zoom..=options.max_zoom,
+                options.max_tile_features.map(|max| max as usize),
+            )?,
+        }
+        record_output(watchdog, encoding, batch.num_rows());
+        bar.disable_steady_tick();
+
+        if let Some(style_path) = &options.style_out {
+            style::Style {
+                data_path: &output_path,
+                format: options.format,
+                layer: &options.layer_name(input_path),
+                geometry: options.geometry,
+                value: &options.value_column(),
+                breaks: style::quantile_breaks(
+                    &batch,
+                    &options.value_column(),
+                    options.style_classes as usize,
+                )?,
+            }
+            .write(style_path)?;
+        }
+        self.finish(
+            input_path,
+            output_path,
+            bar,
+            watchdog,
+            &summary,
+            verified.as_ref(),
+            started,
+        )
+    }
+
+    /// Splits the image into bands of rows to convert and write one at a time when
+    /// `--max-memory` can't hold all of its rows, or fails up front when the output
+    /// might not fit and can only be built whole.
+    fn bands(
+        &self,
+        input_path: &Path,
+        tif: &(TifContents, u32),
+    ) -> Result<Option<Vec<Range<u32>>>> {
+        let options = &self.options;
+        let Some(budget) = options.budget(&tif.0)? else {
+            return Ok(None);
+        };
+        let mut decoder = Decoder::new(Cursor::new(&tif.0))?.with_limits(Limits::unlimited());
+        let (width, height) = decoder.dimensions()?;
+        let (_, chunk_height) = Layout::from_decoder(&mut decoder)?.chunk_dimensions();
+        // Transforms added by the builder may need the whole table too.
+        if !options.streams_bands() || !self.transforms.is_empty() {
+            let mut rows = width as u64 * height as u64;
+            // Pixels grouped as they are read only hold a row for each cell.
+            if options.groups_as_read() {
+                let src_crs = sidecar::src_crs(input_path, options.src_crs, &mut decoder)?;
+                let transform = GeoTransform::resolve(&mut decoder, src_crs, options.dst_crs)?;
+                rows = rows.min(options.max_cells(&transform).unwrap_or(u64::MAX));
+            }
+            budget.check_rows(rows, memory::WHOLE, options.force)?;
+            return Ok(None);
+        }
+        let bands = budget.bands(width, height, chunk_height);
+        let band_rows = bands[0].len() as u64 * width as u64;
+        budget.check_rows(band_rows, memory::SMALLEST_BAND, options.force)?;
+        Ok((bands.len() > 1).then_some(bands))
+    }
+
+    /// Converts the image a band of rows at a time, appending each to the parquet or Arrow
+    /// stream output before the next is read.
+    #[allow(clippy::too_many_arguments)]
+    fn write_bands(
+        &self,
+        input_path: &Path,
+        tif: &(TifContents, u32),
+        output_path: PathBuf,
+        bar: &ProgressBar,
+        watchdog: &Watchdog,
+        bands: Vec<Range<u32>>,
+        verified: Option<&Verified>,
+        started: Instant,
+    ) -> Result<Outcome> {
+        let options = &self.options;
+        let budget = options.budget(&tif.0)?.expect("bands need a budget");
+        let mut summary = Summary::new();
+        let mut stream = None;
+        let mut crs = Crs::wgs84();
+        let seen = self.seen(input_path)?;
+        for (i, band) in bands.iter().enumerate() {
+            bar.set_position(0);
+            let part = Part::Rows(band.start, band.end);
+            let (batch, transform) = self.read(input_path, tif, bar, watchdog, part)?;
+            crs = transform.crs().map_or(Crs::wgs84(), |(_, dst)| dst);
+            watchdog.check()?;
+            let batch = match (&options.append, &stream) {
+                // The output being written is part of the dataset once its first band is.
+                (Some(_), Some(_)) => append::dedupe(&batch, &seen)?,
+                _ => self.join_dataset(batch, &seen)?,
+            };
+            summary.add(&batch, &options.value_column());
+            let stream = match &mut stream {
+                Some(stream) => stream,
+                None => {
+                    // The first band stands in for the others, which are as large but
+                    // for the last.
+                    let needed = options.estimate_size(&batch) * bands.len() as u64;
+                    output::check_free_space(&output_path, needed, options.force)?;
+                    stream.insert(match options.format {
+                        // A stream's metadata goes ahead of its rows, so has no bounds.
+                        OutputFormat::ArrowStream => {
+                            output::Stream::Arrow(output::ArrowStream::create(
+                                &output_path,
+                                batch.schema(),
+                                manifest::footer(input_path, &Summary::new(), crs),
+                            )?)
+                        }
+                        _ => output::Stream::Parquet(output::ParquetStream::create(
+                            &output_path,
+                            batch.schema(),
+                            options.compression,
+                            budget.rows() as usize,
+                        )?),
+                    })
+                }
+            };
+            watchdog.stage(bar, format!("writing band {} of {}", i + 1, bands.len()));
+            let encoding = (Instant::now(), timing::writing());
+            stream.write(&batch)?;
+            record_output(watchdog, encoding, batch.num_rows());
+        }
+        if let Some(stream) = stream {
+            let encoding = (Instant::now(), timing::writing());
+            stream.close(manifest::footer(input_path, &summary, crs))?;
+            record_output(watchdog, encoding, 0);
+        }
+        self.finish(
+            input_path,
+            output_path,
+            bar,
+            watchdog,
+            &summary,
+            verified,
+            started,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn finish(
+        &self,
+        input_path: &Path,
+        output_path: PathBuf,
+        bar: &ProgressBar,
+        watchdog: &Watchdog,
+        summary: &Summary,
+        verified: Option<&Verified>,
+        started: Instant,
+    ) -> Result<Outcome> {
+        if self.options.manifest {
+            let registration = match self.options.binning() {
+                Some(_) => None,
+                None => Some(self.options.registration),
+            };
+            manifest::write(
+                input_path,
+                &output_path,
+                summary,
+                registration,
+                verified,
+                started.elapsed(),
+            )?;
+        }
+        if self.options.timing.is_some() {
+            let timing = (input_path.to_path_buf(), watchdog.timing());
+            self.timings.lock().unwrap().push(timing);
+        }
+        bar.finish_with_message("done");
+        Ok(Outcome::Written {
+            output: output_path,
+            rows: summary.rows() as usize,
+        })
+    }
+
+    /// Decodes, transforms and groups the pixels in `part` of `input_path`'s image, loaded
+    /// as `tif` by [`Options::read_band`], returning the table and the transform its
+    /// positions were computed with.
+    fn read(
+        &self,
+        input_path: &Path,
+        tif: &(TifContents, u32),
+        bar: &ProgressBar,
+        watchdog: &Watchdog,
+        part: Part,
+    ) -> Result<(RecordBatch, GeoTransform)> {
+        let options = &self.options;
+        let (tif_contents, band) = (&tif.0, tif.1);
+
+        watchdog.stage(bar, "decoding tif");
+        let decoding = Instant::now();
+        let mut decoder = Decoder::new(Cursor::new(tif_contents))?.with_limits(Limits::unlimited());
+        let layout = Layout::from_decoder(&mut decoder)?.with_band(band)?;
+        let source = options.source_metadata(&mut decoder)?;
+        let (width, _) = decoder.dimensions()?;
+        let chunk_size = options.unit_size(tif_contents, &layout, width)?;
+        let aggregated =
+            options.binning().is_some() || options.resample.is_some() || options.multires.is_some();
+        if layout.color().is_some() && aggregated {
+            bail!(Classified::new(
+                Unsupported,
+                "Packed colors can't be aggregated, so true color images can't be grouped or resampled"
+            ));
+        }
+
+        let src_crs = sidecar::src_crs(input_path, options.src_crs, &mut decoder)?;
+        let source_transform = GeoTransform::resolve(&mut decoder, src_crs, options.dst_crs)?;
+        let transform = source_transform.resampled(options.resample.unwrap_or(1));
+        let mask = options.mask.as_deref().map(Mask::load).transpose()?;
+        let strata = match &options.stratify_by {
+            Some(path) => {
+                let (width, height) = decoder.dimensions()?;
+                Some(Strata::load(path, &options.strata, width, height)?)
+            }
+            None => None,
+        };
+        let in_bbox = |lon: f64, lat: f64| {
+            options.bbox.is_none_or(|b| b.contains(lon, lat)) && part.keeps(lon, lat)
+        };
+        let wrap = |lon: f64| options.lon_range.map_or(lon, |range| range.wrap(lon));
+        let stride = options.stride.unwrap_or(1);
+        // Whether `len` pixels from `start` hold one on the stride.
+        let on_stride = |start: u32, len: u32| start.next_multiple_of(stride) < start + len;
+        let keep_chunk = |x, y, w, h| {
+            let bounds = source_transform.rect_bounds(x, y, w, h);
+            let bounds = options
+                .lon_range
+                .map_or(bounds, |range| range.wrap_bounds(bounds));
+            options.bbox.is_none_or(|b| b.intersects(&bounds))
+                && mask.as_ref().is_none_or(|m| m.bounds().intersects(&bounds))
+                && part.keeps_chunk(&bounds)
+                && part.keeps_rows(y, h)
+                && on_stride(x, w)
+                && on_stride(y, h)
+                // Chunks left once the watchdog expires are skipped, so the read ends soon.
+                && !watchdog.expired()
+        };
+
+        watchdog.record(Stage::Decode, decoding.elapsed(), 0);
+        watchdog.stage(bar, "processing image");
+        let processing = Instant::now();
+        let clocks = PixelClocks::default();
+        // Resampling happens after the pixels are read, and counts as grouping.
+        let mut grouping = (Duration::ZERO, 0);
+        let mut group = |pixels: &[(u32, u32, f64)], factor| {
+            let started = Instant::now();
+            let blocks = resample::resample(pixels, factor, options.resample_method);
+            grouping = (
+                grouping.0 + started.elapsed(),
+                grouping.1 + pixels.len() as u64,
+            );
+            blocks
+        };
+        let (chunk_width, chunk_height) = layout.chunk_dimensions();
+        let chunk_pixels = chunk_width as u64 * chunk_height as u64;
+        bar.set_length(layout.chunk_count() as u64 * chunk_pixels);
+        bar.set_style(counting_style("pixels")?);
+
+        // Positions pixels of `transform`'s raster, dropping those outside the bbox or mask.
+        let locate = |transform: &GeoTransform, x: u32, y: u32, value: f64| {
+            let in_mask = mask.as_ref().is_none_or(|m| {
+                let (lon, lat) = transform.position(x as f64 + 0.5, y as f64 + 0.5);
+                m.contains(wrap(lon), lat)
+            });
+            let offset = options.registration.offset();
+            let (lon, lat) = transform.position(x as f64 + offset, y as f64 + offset);
+            let lon = wrap(lon);
+            if !(in_mask && in_bbox(lon, lat)) {
+                return None;
+            }
+            // Pixels `--keep-nan` keeps have no value to transform or filter.
+            if options.keep_nan && value.is_nan() {
+                return Some((lon, lat, value));
+            }
+            let value = options
+                .expr
+                .as_ref()
+                .map_or(value, |e| e.eval(value, lon, lat));
+            let value = self
+                .transforms
+                .iter()
+                .try_fold(value, |value, t| t.pixel(lon, lat, value))
+                .filter(|&value| options.keeps_value(value));
+            match value {
+                Some(value) if options.per_area_to_total => {
+                    Some((lon, lat, value * transform.pixel_area_km2(x, y)))
+                }
+                Some(value) => Some((lon, lat, value)),
+                // Dense outputs keep dropped pixels, as nulls.
+                None if options.dense => Some((lon, lat, f64::NAN)),
+                None => None,
+            }
+        };
+        let (scale, offset) = options.scaling(&source).unwrap_or((1.0, 0.0));
+        // Pixels equal to the tif's nodata value are missing. Float rasters may also have
+        // NaN or infinite ones, which are dropped whatever `--min-value` allows, unless
+        // `--keep-nan` keeps them. Colors are packed, so no nodata value applies to them.
+        let missing = source.nodata_value().filter(|_| layout.color().is_none());
+        let not_a_number = |value: f64| !value.is_finite();
+        let transparent = layout.color().map(|_| raster::TRANSPARENT as f64);
+        let marked =
+            |value| Some(value) == missing || not_a_number(value) || Some(value) == transparent;
+        // Scaled pixels in image coordinates, for combining into blocks before positioning.
+        let read_scaled = || {
+            raster::read_pixels_timed(
+                tif_contents,
+                &layout,
+                chunk_size,
+                keep_chunk,
+                |chunks| bar.inc(chunks * chunk_pixels),
+                |x, y, value| {
+                    (options.keeps_stored(value) && !marked(value)).then_some((
+                        x,
+                        y,
+                        value * scale + offset,
+                    ))
+                },
+                &clocks,
+            )
+        };
+        // The row of a native pixel kept on its own, with its column and row and its
+        // stratum.
+        let pixel_row = |x, y, value| {
+            if !part.keeps_row(y) || x % stride != 0 || y % stride != 0 {
+                return None;
+            }
+            if let Some(fraction) = options.sample {
+                if !sample::keeps(fraction, options.seed, x, y) {
+                    return None;
+                }
+            }
+            let stratum = match &strata {
+                Some(strata) => strata.stratum(x, y)?,
+                None => 0,
+            };
+            let value = match options.keeps_stored(value) && !marked(value) {
+                _ if options.keep_nan && not_a_number(value) => f64::NAN,
+                true => value * scale + offset,
+                false if options.dense => f64::NAN,
+                false => return None,
+            };
+            let row = locate(&transform, x, y, value)?;
+            let stratum = match &options.reclass {
+                Some(classes) => classes.index(row.2)?,
+                None => stratum,
+            };
+            Some(((row, (x, y)), stratum))
+        };
+        let mut levels = vec![];
+        let mut row_strata = vec![];
+        let mut cells = None;
+        // Rows with the column and row of the pixel or block they came from.
+        let rows: Vec<(_, (u32, u32))> = match (options.resample, options.multires) {
+            (None, None) if options.groups_as_read() => {
+                let binning = options.binning().expect("grouping has a binning");
+                let weight = pixel_weight(options, &transform);
+                cells = Some(raster::fold_pixels_timed(
+                    tif_contents,
+                    &layout,
+                    chunk_size,
+                    keep_chunk,
+                    |chunks| bar.inc(chunks * chunk_pixels),
+                    Cells::default,
+                    |cells, x, y, value| {
+                        let Some((((lon, lat, value), _), _)) = pixel_row(x, y, value) else {
+                            return;
+                        };
+                        // Pixels `--keep-nan` kept have no value to add to their cell's.
+                        if !(options.keep_nan && value.is_nan()) {
+                            cells.add(&binning, lon, lat, value, weight(lon, lat));
+                        }
+                    },
+                    Cells::merge,
+                    &clocks,
+                )?);
+                vec![]
+            }
+            (None, None) => {
+                let pixels = raster::read_pixels_timed(
+                    tif_contents,
+                    &layout,
+                    chunk_size,
+                    keep_chunk,
+                    |chunks| bar.inc(chunks * chunk_pixels),
+                    pixel_row,
+                    &clocks,
+                )?;
+                let rows;
+                (rows, row_strata) = pixels.into_iter().unzip();
+                rows
+            }
+            (Some(factor), _) => group(&read_scaled()?, factor)
+                .into_iter()
+                .filter_map(|(x, y, value)| Some((locate(&transform, x, y, value)?, (x, y))))
+                .collect(),
+            (None, Some(count)) => {
+                let pixels = read_scaled()?;
+                let mut data = vec![];
+                for level in 0..=count {
+                    // Each level's blocks are combined from the native pixels, so averages
+                    // aren't taken of averages.
+                    let factor = 1 << level;
+                    let level_transform = source_transform.resampled(factor);
+                    let resampled;
+                    let blocks = match level {
+                        0 => &pixels,
+                        _ => {
+                            resampled = group(&pixels, factor);
+                            &resampled
+                        }
+                    };
+                    let rows: Vec<_> = blocks
+                        .iter()
+                        .filter_map(|&(x, y, value)| {
+                            Some((locate(&level_transform, x, y, value)?, (x, y)))
+                        })
+                        .collect();
+                    levels.extend(std::iter::repeat_n(level, rows.len()));
+                    data.extend(rows);
+                }
+                data
+            }
+        };
+        let (decoding, transforming) =
+            clocks.split(processing.elapsed().saturating_sub(grouping.0));
+        // Pixels added to their cells as they were read were grouped as they were
+        // positioned, so count as transformed.
+        let positioned = cells.as_ref().map_or(rows.len() as u64, Cells::rows);
+        watchdog.record(Stage::Decode, decoding, clocks.pixels());
+        watchdog.record(Stage::Transform, transforming, positioned);
+        watchdog.record(Stage::Group, grouping.0, grouping.1);
+        let (data, indices): (Vec<_>, Vec<_>) = rows.into_iter().unzip();
+        let indices = match options.with_indices {
+            true => indices,
+            false => vec![],
+        };
+
+        let (building, built_rows) = (Instant::now(), positioned);
+        let rows = match (cells, options.binning()) {
+            (Some(cells), Some(binning)) => Rows::Grouped(cells.finish(&binning, options.agg)),
+            _ => Rows::Pixels(data),
+        };
+        let mut batch = build_batch(
+            rows,
+            levels,
+            row_strata,
+            indices,
+            options,
+            &source,
+            &transform,
+            options.time(input_path)?,
+        )?;
+        // Rows were counted through the transform stage as they were positioned.
+        match options.binning() {
+            Some(_) => watchdog.record(Stage::Group, building.elapsed(), built_rows),
+            None => watchdog.record(Stage::Transform, building.elapsed(), 0),
+        }
+        let transforming = Instant::now();
+        if let Some(tolerance) = options.thin {
+            batch = thin::thin(&batch, tolerance)?;
+        }
+        if let Some(order) = options.sort {
+            batch = sort::sort(&batch, order)?;
+        }
+        for step in &self.transforms {
+            batch = step.batch(batch)?;
+        }
+        if let Some(condition) = &options.row_filter {
+            batch = condition.filter(&batch)?;
+        }
+        let batch = columns::rename(batch, &options.renames())?;
+        watchdog.record(Stage::Transform, transforming.elapsed(), 0);
+        Ok((batch, transform))
+    }
+
+    /// The `--timing` of each input converted so far, taking them so they are reported
+    /// once.
+    pub fn take_timings(&self) -> Vec<(PathBuf, Timing)> {
+        std::mem::take(&mut self.timings.lock().unwrap())
+    }
+}
+
+/// Turns `bar` into one for writing the output, counting its `rows` as they are written
+/// when they are known, and ticking on its own so long encodes don't look stalled.
+fn write_bar(bar: &ProgressBar, rows: Option<usize>) -> Result<()> {
+    bar.reset();
+    match rows {
+        Some(rows) => {
+            bar.set_length(rows as u64);
+            bar.set_style(counting_style("rows")?);
+        }
+        None => bar.set_style(ProgressStyle::with_template(
+            "{prefix:<30} {spinner} {msg} {elapsed_precise}",
+        )?),
+    }
+    bar.enable_steady_tick(Duration::from_millis(100));
+    Ok(())
+}
+
+/// A bar style counting `unit`s, with how many go by each second and the time left.
+fn counting_style(unit: &str) -> Result<ProgressStyle> {
+    let template = format!(
+        "{{prefix:<30}} {{msg}} {{percent}}% {{human_pos}}/{{human_len}} {unit}, \
+         {{rate}} {unit}/s, {{eta}} left {{bar_wide}}"
+    );
+    let rate = |state: &ProgressState, out: &mut dyn std::fmt::Write| {
+        let _ = write!(out, "{}", HumanCount(state.per_sec() as u64));
+    };
+    Ok(ProgressStyle::with_template(&template)?.with_key("rate", rate))
+}
+
+/// Records the encoding and writing of `rows` rows, started at `encoding` with the file
+/// writes up to then.
+fn record_output(watchdog: &Watchdog, encoding: (Instant, (Duration, u64)), rows: usize) {
+    let (started, (time, bytes)) = encoding;
+    let (written_time, written_bytes) = timing::writing();
+    let writing = written_time.saturating_sub(time);
+    watchdog.record(
+        Stage::Encode,
+        started.elapsed().saturating_sub(writing),
+        rows as u64,
+    );
+    watchdog.record(Stage::Write, writing, written_bytes - bytes);
+}
+
+/// The command line arguments that set `command`'s option named `key` to `value`, as
+/// `--key=value` so that no value is mistaken for a flag or an input.
+pub(crate) fn flag_arguments(
+    command: &clap::Command,
+    key: &str,
+    value: Value,
+) -> Result<Vec<String>> {
+    let long = key.replace('_', "-");
+    let flag = format!("--{}", long);
+    // Lists are joined for options that split on a delimiter and repeated for the rest.
+    let delimited = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()))
+        .is_some_and(|arg| arg.get_value_delimiter().is_some());
+    Ok(match value {
+        Value::Null | Value::Bool(false) => vec![],
+        Value::Bool(true) => vec![flag],
+        Value::Array(items) if !delimited => items
+            .iter()
+            .map(|item| Ok(format!("{}={}", flag, flag_argument(key, item)?)))
+            .collect::<Result<_>>()?,
+        Value::Number(_) | Value::String(_) | Value::Array(_) => {
+            vec![format!("{}={}", flag, flag_argument(key, &value)?)]
+        }
+        Value::Object(_) => bail!("Option {} can't be an object", key),
+    })
+}
+
+/// Renders a value as it would be written on the command line, lists joined with commas.
+/// Nested lists are refused.
+fn flag_argument(key: &str, value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::String(_) | Value::Number(_) => flag_argument(key, item),
+                _ => bail!("Option {} can only list strings and numbers", key),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join(","),
+        _ => bail!("Option {} needs a string or number", key),
+    })
+}
+
+/// Which pixels a pass over the image keeps, by their position.
+#[derive(Clone, Copy)]
+enum Part {
+    Whole,
+    /// Those inside a `--stream-priority` region.
+    Inside(BBox),
+    /// Those outside it.
+    Outside(BBox),
+    /// Those in image rows from the first up to the second, for a `--max-memory` band.
+    Rows(u32, u32),
+}
+
+impl Part {
+    fn keeps(&self, lon: f64, lat: f64) -> bool {
+        match self {
+            Part::Whole | Part::Rows(..) => true,
+            Part::Inside(region) => region.contains(lon, lat),
+            Part::Outside(region) => !region.contains(lon, lat),
+        }
+    }
+
+    /// Whether a strip or tile within `bounds` may hold pixels the pass keeps.
+    fn keeps_chunk(&self, bounds: &BBox) -> bool {
+        match self {
+            Part::Whole | Part::Rows(..) => true,
+            Part::Inside(region) => region.intersects(bounds),
+            Part::Outside(region) => !region.covers(bounds),
+        }
+    }
+
+    /// Whether a strip or tile of `height` rows from image row `y` may hold pixels the
+    /// pass keeps.
+    fn keeps_rows(&self, y: u32, height: u32) -> bool {
+        match self {
+            Part::Rows(start, end) => y < *end && y + height > *start,
+            _ => true,
+        }
+    }
+
+    fn keeps_row(&self, y: u32) -> bool {
+        self.keeps_rows(y, 1)
+    }
+}
+
+/// Where the pixels of a `--stream-priority` region are written, beside the output.
+pub fn priority_path(output_path: &Path) -> PathBuf {
+    output_path.with_extension("priority.parquet")
+}
+
+/// What an output table is built from: rows of pixels, or the cells they were grouped
+/// into as they were read.
+pub(crate) enum Rows {
+    Pixels(Vec<(f64, f64, f64)>),
+    Grouped(Binned),
+}
+
+/// The weight of a pixel at a position in its cell's `--agg`: its relative area, or 1 when
+/// `--per-area-to-total` made its value a total that already accounts for it.
+fn pixel_weight<'a>(
+    options: &'a Options,
+    transform: &'a GeoTransform,
+) -> impl Fn(f64, f64) -> f64 + Sync + 'a {
+    move |x, y| match options.per_area_to_total {
+        true => 1.0,
+        false => transform.pixel_weight(x, y),
+    }
+}
+
+/// Groups the pixel rows if requested and lays them out as the output table. `levels`
+/// gives each row's `--multires` level, `row_strata` its `--stratify-by` stratum and
+/// `indices` its `--with-indices` column and row.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_batch(
+    rows: Rows,
+    levels: Vec<u8>,
+    mut row_strata: Vec<u8>,
+    indices: Vec<(u32, u32)>,
+    options: &Options,
+    source: &SourceMetadata,
+    transform: &GeoTransform,
+    time: Option<i64>,
+) -> Result<RecordBatch> {
+    let (mut data, mut grouped) = match rows {
+        Rows::Pixels(data) => (data, None),
+        Rows::Grouped(binned) => (vec![], Some(binned)),
+    };
+    let mut key_columns = vec![];
+    // The `--categorical` class of each row, when grouping counts the pixels of each.
+    let mut row_classes = vec![];
+    if let Some(binning) = options.binning() {
+        // Pixels `--keep-nan` kept have no value to add to their cell's.
+        if options.keep_nan {
+            (data, row_strata) = data
+                .into_iter()
+                .zip(row_strata)
+                .filter(|(row, _)| !row.2.is_nan())
+                .unzip();
+        }
+        let bin = |data: &[(f64, f64, f64)]| {
+            group::bin(
+                data,
+                &binning,
+                options.agg,
+                pixel_weight(options, transform),
+            )
+        };
+        if options.categorical {
+            let binned;
+            (binned, row_classes) = categorical::bin(&data, &binning, options.class_counts)?;
+            data = binned.rows;
+            key_columns = binned.columns;
+        } else if let Some(count) = options.strata_count() {
+            // Each stratum is grouped on its own, so a cell spanning strata gets a row in
+            // each.
+            let mut by_stratum: Vec<Vec<(f64, f64, f64)>> = vec![vec![]; count];
+            for (row, stratum) in data.iter().zip(&row_strata) {
+                by_stratum[*stratum as usize].push(*row);
+            }
+            let binned: Vec<_> = by_stratum
+                .iter()
+                .filter(|rows| !rows.is_empty())
+                .map(|rows| bin(rows))
+                .collect();
+            data = binned.iter().flat_map(|b| b.rows.clone()).collect();
+            row_strata = by_stratum
+                .iter()
+                .enumerate()
+                .filter(|(_, rows)| !rows.is_empty())
+                .zip(&binned)
+                .flat_map(|((stratum, _), b)| std::iter::repeat_n(stratum as u8, b.rows.len()))
+                .collect();
+            key_columns = match binned.first() {
+                // Binning nothing still gives the key columns their types.
+                None => bin(&[]).columns,
+                Some(first) => first
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, _))| {
+                        let parts: Vec<&dyn Array> =
+                            binned.iter().map(|b| b.columns[i].1.as_ref()).collect();
+                        Ok((*name, arrow_select::concat::concat(&parts)?))
+                    })
+                    .collect::<Result<_>>()?,
+            };
+        } else {
+            let binned = grouped.take().unwrap_or_else(|| bin(&data));
+            data = binned.rows;
+            key_columns = binned.columns;
+        }
+        if let Some(range) = options.lon_range {
+            for row in &mut data {
+                row.0 = range.wrap(row.0);
+            }
+        }
+    }
+
+    let lon_col = Float32Array::from_iter(data.iter().map(|r| r.0 as f32));
+    let lat_col = Float32Array::from_iter(data.iter().map(|r| r.1 as f32));
+    // Only dense and `--keep-nan` outputs have rows without a value, which are NaN until
+    // here.
+    let value_col = Float32Array::from_iter(data.iter().map(|r| {
+        let nullable = options.dense || options.keep_nan;
+        (!nullable || !r.2.is_nan()).then_some(r.2 as f32)
+    }));
+
+    // Positions in projected CRSs are metres, not degrees.
+    let (x_name, y_name) = match transform.crs() {
+        Some((_, dst)) if !dst.is_geographic() => ("easting", "northing"),
+        _ => ("lon", "lat"),
+    };
+    let mut columns = vec![
+        (x_name, Arc::new(lon_col) as ArrayRef),
+        (y_name, Arc::new(lat_col) as ArrayRef),
+        ("value", Arc::new(value_col) as ArrayRef),
+    ];
+    columns.extend(key_columns);
+    if options.with_indices {
+        let (cols, rows): (Vec<u32>, Vec<u32>) = indices.into_iter().unzip();
+        columns.push(("col", Arc::new(UInt32Array::from(cols)) as ArrayRef));
+        columns.push(("row", Arc::new(UInt32Array::from(rows)) as ArrayRef));
+    }
+    if options.multires.is_some() {
+        columns.push(("level", Arc::new(UInt8Array::from(levels)) as ArrayRef));
+    }
+    if options.stratify_by.is_some() {
+        let labels = strata::labels(&options.strata);
+        let stratum_col = StringArray::from_iter_values(
+            row_strata
+                .iter()
+                .map(|&stratum| labels[stratum as usize].as_str()),
+        );
+        columns.push(("stratum", Arc::new(stratum_col) as ArrayRef));
+    }
+    if options.categorical {
+        let codes: Vec<i64> = match options.class_counts {
+            true => row_classes,
+            false => data.iter().map(|r| r.2 as i64).collect(),
+        };
+        if options.class_counts {
+            let class_col = Int32Array::from_iter_values(codes.iter().map(|&code| code as i32));
+            columns.push(("class", Arc::new(class_col) as ArrayRef));
+        }
+        if let Some(labels) = &options.labels {
+            let label_col = StringArray::from_iter(codes.iter().map(|&code| labels.get(code)));
+            columns.push(("label", Arc::new(label_col) as ArrayRef));
+        }
+        if let Some(color_map) = &source.color_map {
+            let color_col = StringArray::from_iter(
+                codes
+                    .iter()
+                    .map(|&code| categorical::color(color_map, code)),
+            );
+            columns.push(("color", Arc::new(color_col) as ArrayRef));
+        }
+    }
+    if let Some(classes) = &options.reclass {
+        let class_col =
+            Int32Array::from_iter_values(row_strata.iter().map(|&class| classes.code(class)));
+        columns.push(("class", Arc::new(class_col) as ArrayRef));
+    }
+    // The levels of true color pixels, which are packed into their values.
+    if source.color.is_some() && !options.packed_color {
+        for (name, shift) in [("r", 16), ("g", 8), ("b", 0)] {
+            let level_col = UInt8Array::from_iter(
+                data.iter()
+                    .map(|r| (!r.2.is_nan()).then_some((r.2 as u32 >> shift) as u8)),
+            );
+            columns.push((name, Arc::new(level_col) as ArrayRef));
+        }
+    }
+    if let Some(precision) = options.geohash {
+        let geohash_col = StringArray::from_iter_values(
+            data.iter()
+                .map(|r| geohash::encode(r.0, r.1, precision as usize)),
+        );
+        columns.push(("geohash", Arc::new(geohash_col) as ArrayRef));
+    }
+    if let Some(path) = &options.distance_to {
+        let features = Features::load(path)?;
+        let distances: Vec<f32> = data
+            .par_iter()
+            .map(|r| features.distance_km(r.0, r.1) as f32)
+            .collect();
+        columns.push((
+            "distance_km",
+            Arc::new(Float32Array::from(distances)) as ArrayRef,
+        ));
+    }
+    if let Some(time) = time {
+        let time_col = TimestampSecondArray::from(vec![time; data.len()]).with_timezone("UTC");
+        columns.push(("time", Arc::new(time_col) as ArrayRef));
+    }
+    let fields: Vec<Field> = columns
+        .iter()
+        .map(|(name, array)| {
+            Field::new(
+                *name,
+                array.data_type().clone(),
+                // Dropped pixels kept as rows have no value or color, and classes
+                // without a label or palette entry have none.
+                ((options.dense || options.keep_nan) && *name == "value")
+                    || (options.dense && matches!(*name, "r" | "g" | "b"))
+                    || matches!(*name, "label" | "color"),
+            )
+            .with_metadata(metadata::column_metadata(name, options, source))
+        })
+        .collect();
+    let arrays = columns.into_iter().map(|(_, array)| array).collect();
+    let file_metadata = source
+        .items()
+        .into_iter()
+        .filter(|(key, _)| metadata::key_matches(&options.metadata_filter, key))
+        .collect();
+    let schema = Schema::new(fields).with_metadata(file_metadata);
+    Ok(RecordBatch::try_new(Arc::new(schema), arrays)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{priority_path, Options, Processor, ProcessorBuilder};
+    use crate::{group::Align, json, manifest, notify::Outcome, resample::Method};
+    use arrow_array::{Array, Float32Array, RecordBatch, StringArray, UInt32Array, UInt8Array};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::fs::File;
+    use tiff::{
+        encoder::{
+            colortype::{Gray16, Gray32, GrayI32, RGB32Float},
+            TiffEncoder,
+        },
+        tags::Tag,
+    };
+
+    #[test]
+    fn test_processor() {
+        let defaults = Options::default();
+        assert_eq!(defaults.max_zoom, 10);
+        assert_eq!(defaults.metadata_filter, vec!["*".to_string()]);
+        assert!(Processor::builder().zooms(5, 2).build().is_err());
+
+        let path = std::env::temp_dir().join(format!("processor-test-{}.tif", std::process::id()));
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        encoder
+            .write_image::<GrayI32>(4, 3, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, -1])
+            .unwrap();
+
+        let pixels = Processor::builder()
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        // Zero and negative stored values are dropped as empty.
+        assert_eq!(pixels.num_rows(), 10);
+        // The first kept pixel is the second of four across the world, placed at its
+        // center unless asked for its corner.
+        let first_lon = |batch: &RecordBatch| {
+            let lon = batch.column(0).as_any().downcast_ref::<Float32Array>();
+            lon.unwrap().value(0)
+        };
+        assert_eq!(first_lon(&pixels), -45.0);
+        let corners = Processor::builder()
+            .registration(Align::Corner)
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        assert_eq!(first_lon(&corners), -90.0);
+        // Dense outputs have every pixel, with the dropped ones' values null.
+        let dense = Processor::builder()
+            .dense(true)
+            .with_indices(true)
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        assert_eq!(dense.num_rows(), 12);
+        assert_eq!(dense.column_by_name("value").unwrap().null_count(), 2);
+        assert!(dense
+            .schema()
+            .field_with_name("value")
+            .unwrap()
+            .is_nullable());
+        // Each row says which pixel it came from.
+        let index = |name| {
+            let column = dense.column_by_name(name).unwrap();
+            column
+                .as_any()
+                .downcast_ref::<UInt32Array>()
+                .unwrap()
+                .value(11)
+        };
+        assert_eq!((index("col"), index("row")), (3, 2));
+        let grouped = Processor::builder()
+            .group(360.0)
+            .keep_zero(true)
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        // One cell for each quadrant of the world around the grid origin.
+        assert_eq!(grouped.num_rows(), 4);
+        let levels = Processor::builder()
+            .multires(1, Method::Sum)
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        // The 10 pixels, then the 4 blocks of 2x2 pixels that hold any of them.
+        assert_eq!(levels.num_rows(), 14);
+        let level = levels.column_by_name("level").unwrap();
+        let level = level.as_any().downcast_ref::<UInt8Array>().unwrap();
+        assert_eq!(level.values().iter().filter(|&&l| l == 1).count(), 4);
+        // Every other pixel across and down leaves 0, 2, 8 and 10, and the 0 is empty.
+        let strided = Processor::builder()
+            .stride(2)
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        assert_eq!(strided.num_rows(), 3);
+
+        let output = path.with_extension("parquet");
+        // Grouping needs every row at once, so a budget too small for them fails before
+        // anything is written.
+        let tif_bytes = std::fs::metadata(&path).unwrap().len();
+        let budgeted = Processor::builder()
+            .group(360.0)
+            .max_memory(tif_bytes + 100)
+            .build()
+            .unwrap()
+            .process_to(&path, &output);
+        assert!(budgeted.is_err_and(|e| e.to_string().contains("--max-memory")));
+        assert!(!output.exists());
+        let outcome = Processor::builder()
+            .stream_priority("0,-90,180,90".parse().unwrap())
+            .build()
+            .unwrap()
+            .process_to(&path, &output)
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Written { rows: 10, .. }));
+        // The 5 kept pixels of the east half come first, in a file of their own.
+        let priority = SerializedFileReader::new(File::open(priority_path(&output)).unwrap());
+        assert_eq!(priority.unwrap().metadata().file_metadata().num_rows(), 5);
+        std::fs::remove_file(priority_path(&output)).unwrap();
+
+        // The output now exists, so it is kept while newer than the input and only
+        // replaced when asked.
+        let written = std::fs::metadata(&output).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(written - std::time::Duration::from_secs(60))
+            .unwrap();
+        let again = |builder: ProcessorBuilder| builder.build().unwrap().process_to(&path, &output);
+        assert!(again(Processor::builder()).is_err());
+        let skipped = again(Processor::builder().skip_existing(true)).unwrap();
+        assert!(matches!(skipped, Outcome::UpToDate { .. }));
+        let replaced = again(Processor::builder().overwrite(true).manifest(true)).unwrap();
+        assert!(matches!(replaced, Outcome::Written { rows: 10, .. }));
+        let manifest_path = manifest::manifest_path(&output);
+        let manifest = json::parse(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        assert_eq!(manifest.get("rows").and_then(|r| r.as_f64()), Some(10.0));
+        let registration = manifest.get("registration").and_then(|r| r.as_str());
+        assert_eq!(registration, Some("center"));
+        let sha256 = manifest.get("input").and_then(|i| i.get("sha256"));
+        assert_eq!(sha256.and_then(|s| s.as_str()).map(str::len), Some(64));
+        let value = manifest.get("value").unwrap();
+        assert!(
+            value.get("min").and_then(|m| m.as_f64()) <= value.get("max").and_then(|m| m.as_f64())
+        );
+        // The footer says the same of where the output came from and what it covers, and
+        // each column chunk has statistics to prune by.
+        let reader = SerializedFileReader::new(File::open(&output).unwrap()).unwrap();
+        let footer = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        let footer = |key: &str| {
+            let pair = footer.iter().find(|pair| pair.key == key);
+            pair.and_then(|pair| pair.value.clone())
+        };
+        assert_eq!(
+            footer("image_stats:source").as_deref(),
+            path.file_name().and_then(|name| name.to_str())
+        );
+        assert_eq!(footer("image_stats:crs").as_deref(), Some("EPSG:4326"));
+        let bbox = json::parse(&footer("image_stats:bbox").unwrap()).unwrap();
+        assert_eq!(bbox.as_array().map(<[_]>::len), Some(4));
+        assert!(reader
+            .metadata()
+            .row_group(0)
+            .column(0)
+            .statistics()
+            .is_some());
+        std::fs::remove_file(&manifest_path).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_column_order() {
+        let path = std::env::temp_dir().join(format!("column-order-{}.tif", std::process::id()));
+        TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<GrayI32>(4, 2, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        let batch = Processor::builder()
+            .geohash(5)
+            .build()
+            .unwrap()
+            .to_batch(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let names: Vec<&str> = batch
+            .schema_ref()
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect();
+        assert_eq!(names[..3], ["lon", "lat", "value"]);
+        // Pixels of the 4x2 world grid, clipped at 85° north and south, are 90° wide and
+        // 85° tall, so the lon and lat of its corner pixels can't be mistaken for each other.
+        let column = |name| {
+            let column = batch.column_by_name(name).unwrap();
+            column
+                .as_any()
+                .downcast_ref::<Float32Array>()
+                .unwrap()
+                .clone()
+        };
+        let (lon, lat) = (column("lon"), column("lat"));
+        assert_eq!((lon.value(0), lat.value(0)), (-135.0, 42.5));
+        assert_eq!((lon.value(7), lat.value(7)), (135.0, -42.5));
+        let geohash = batch.column_by_name("geohash").unwrap();
+        let geohash = geohash.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(geohash.value(0), crate::geohash::encode(-135.0, 42.5, 5));
+    }
+
+    /// A classic tif of 4x2 I32 pixels `1..=8` in one strip, in either byte order and
+    /// stored through the given predictor.
+    fn tif(little_endian: bool, predictor: u16) -> Vec<u8> {
+        let u16_bytes = |v: u16| match little_endian {
+            true => v.to_le_bytes(),
+            false => v.to_be_bytes(),
+        };
+        let u32_bytes = |v: u32| match little_endian {
+            true => v.to_le_bytes(),
+            false => v.to_be_bytes(),
+        };
+        let tags: [(u16, u16); 10] = [
+            (256, 4),
+            (257, 2),
+            (258, 32),
+            (262, 1),
+            (273, 134),
+            (277, 1),
+            (278, 2),
+            (279, 32),
+            (317, predictor),
+            (339, 2),
+        ];
+        let mut tif = match little_endian {
+            true => b"II".to_vec(),
+            false => b"MM".to_vec(),
+        };
+        tif.extend(u16_bytes(42));
+        tif.extend(u32_bytes(8));
+        tif.extend(u16_bytes(tags.len() as u16));
+        for (tag, value) in tags {
+            tif.extend(u16_bytes(tag));
+            tif.extend(u16_bytes(3));
+            tif.extend(u32_bytes(1));
+            tif.extend(u16_bytes(value));
+            tif.extend([0, 0]);
+        }
+        tif.extend(u32_bytes(0));
+        for row in [[1, 2, 3, 4], [5, 6, 7, 8]] {
+            for (x, value) in row.iter().enumerate() {
+                // The horizontal predictor stores each sample as the difference from the
+                // one before it in the row.
+                let stored = match (predictor, x) {
+                    (2, 1..) => value - row[x - 1],
+                    _ => *value,
+                };
+                tif.extend(u32_bytes(stored as u32));
+            }
+        }
+        tif
+    }
+
+    #[test]
+    fn test_sample_types() {
+        let path =
+            std::env::temp_dir().join(format!("sample-type-test-{}.tif", std::process::id()));
+        let processor = Processor::builder().build().unwrap();
+        TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<Gray16>(2, 1, &[1, 65535])
+            .unwrap();
+        let values = processor.to_batch(&path).unwrap();
+        let values = values.column_by_name("value").unwrap().as_any();
+        let values = values.downcast_ref::<Float32Array>().unwrap();
+        assert_eq!(values.values().to_vec(), vec![1.0, 65535.0]);
+        // Values I32 can't hold are read as they are, and nodata is left out.
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        let mut image = encoder.new_image::<Gray32>(3, 1).unwrap();
+        image
+            .encoder()
+            .write_tag(Tag::GdalNodata, "4294967295")
+            .unwrap();
+        image.write_data(&[1, 3_000_000_000, u32::MAX]).unwrap();
+        let values = processor.to_batch(&path).unwrap();
+        let values = values.column_by_name("value").unwrap().as_any();
+        let values = values.downcast_ref::<Float32Array>().unwrap();
+        assert_eq!(values.values().to_vec(), vec![1.0, 3e9]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_float_samples() {
+        let path = std::env::temp_dir().join(format!("float-test-{}.tif", std::process::id()));
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        let mut image = encoder.new_image::<RGB32Float>(3, 2).unwrap();
+        image.encoder().write_tag(Tag::GdalNodata, "-9999").unwrap();
+        // The second band holds values far apart in size, NaN, -inf and nodata.
+        #[rustfmt::skip]
+        let samples = [
+            0.0, 1e7, 0.0,
+            0.0, 0.001, 0.0,
+            0.0, 0.5, 0.0,
+            0.0, f32::NAN, 0.0,
+            0.0, f32::NEG_INFINITY, 0.0,
+            0.0, -9999.0, 0.0,
+        ];
+        image.write_data(&samples).unwrap();
+        let values = |batch: &RecordBatch| {
+            let values = batch.column_by_name("value").unwrap().as_any();
+            let values = values.downcast_ref::<Float32Array>().unwrap();
+            (0..values.len())
+                .map(|i| values.is_valid(i).then(|| values.value(i)))
+                .collect::<Vec<_>>()
+        };
+
+        // Small values are kept as they are next to large ones.
+        let batch = Processor::builder().band(2).build().unwrap();
+        let batch = batch.to_batch(&path).unwrap();
+        assert_eq!(values(&batch), [Some(1e7), Some(0.001), Some(0.5)]);
+        // NaN and infinite pixels are kept as nulls with --keep-nan, but nodata isn't.
+        let batch = Processor::builder().band(2).keep_nan(true).build().unwrap();
+        let batch = batch.to_batch(&path).unwrap();
+        assert_eq!(
+            values(&batch),
+            [Some(1e7), Some(0.001), Some(0.5), None, None]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_byte_orders() {
+        let processor = Processor::builder().build().unwrap();
+        let path = std::env::temp_dir().join(format!("byte-order-test-{}.tif", std::process::id()));
+        for little_endian in [true, false] {
+            for predictor in [1, 2] {
+                std::fs::write(&path, tif(little_endian, predictor)).unwrap();
+                let batch = processor.to_batch(&path).unwrap();
+                let values = batch
+                    .column_by_name("value")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Float32Array>()
+                    .unwrap();
+                assert_eq!(
+                    values.values().to_vec(),
+                    vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0],
+                    "little endian {}, predictor {}",
+                    little_endian,
+                    predictor
+                );
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
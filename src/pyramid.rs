@@ -0,0 +1,193 @@
+//! Rendering a raster into a `z/x/y.png` pyramid of web mercator tiles, for dropping
+//! straight onto a web map.
+
+use crate::{
+    crs::Crs,
+    georef::GeoTransform,
+    load_tif_contents, raster,
+    raster::{ChunkSize, Layout},
+    render::{Colormap, Range},
+    sidecar, tile, DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{bail, Context, Result};
+use image::{Rgba, RgbaImage};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use std::{fs, io::Cursor, path::PathBuf};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct TilesArgs {
+    /// The raster to draw.
+    raster: PathBuf,
+    /// Directory the `z/x/y.png` tiles are written under. Defaults to the raster's path
+    /// with a `.tiles` extension.
+    #[arg(long = "output", short = 'o')]
+    output: Option<PathBuf>,
+    /// Lowest zoom level tiles are drawn for.
+    #[arg(long = "min-zoom", default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=24))]
+    min_zoom: u8,
+    /// Highest zoom level tiles are drawn for.
+    #[arg(long = "max-zoom", default_value_t = 6, value_parser = clap::value_parser!(u8).range(0..=24))]
+    max_zoom: u8,
+    /// `viridis`, `magma`, or breakpoints as `value:#rrggbb,value:#rrggbb,...` with colors
+    /// blended between them.
+    #[arg(long = "colormap", default_value = "viridis")]
+    colormap: Colormap,
+    /// Values mapped to the ends of the colormap as `min,max`. Defaults to the smallest
+    /// and largest value in the raster, so every zoom is colored alike.
+    #[arg(long = "range")]
+    range: Option<Range>,
+    /// CRS of the raster, read with its GeoTIFF tags. Without it the raster is taken to be
+    /// a global grid.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+}
+
+/// Width and height of each tile in pixels.
+const TILE_SIZE: u32 = 256;
+
+/// The raster's values, row by row, with `NaN` where a pixel isn't drawn.
+struct Grid {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl Grid {
+    fn get(&self, x: f64, y: f64) -> Option<f64> {
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            return None;
+        }
+        let value = self.values[y as usize * self.width as usize + x as usize];
+        (!value.is_nan()).then_some(value as f64)
+    }
+}
+
+pub fn run(args: &TilesArgs) -> Result<()> {
+    if args.min_zoom > args.max_zoom {
+        bail!(
+            "--min-zoom {} is above --max-zoom {}",
+            args.min_zoom,
+            args.max_zoom
+        );
+    }
+    let contents = load_tif_contents(&args.raster)?;
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let src_crs = sidecar::src_crs(&args.raster, args.src_crs, &mut decoder)?;
+    let transform = GeoTransform::resolve(&mut decoder, src_crs, None)?;
+    let (width, height) = decoder.dimensions()?;
+    let pixels = raster::read_pixels(
+        &contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |_, _, _, _| true,
+        |_| {},
+        |x, y, value| (value > 0.0 && value.is_finite()).then_some((x, y, value)),
+    )?;
+    let mut grid = Grid {
+        width,
+        height,
+        values: vec![f32::NAN; width as usize * height as usize],
+    };
+    for &(x, y, value) in &pixels {
+        grid.values[y as usize * width as usize + x as usize] = value as f32;
+    }
+    let range = args
+        .colormap
+        .range(args.range, pixels.iter().map(|&(_, _, value)| value));
+    drop(pixels);
+
+    // Every tile the raster touches, from the top left to the bottom right of each zoom.
+    let bounds = transform.rect_bounds(0, 0, width, height);
+    let tiles: Vec<(u8, u32, u32)> = (args.min_zoom..=args.max_zoom)
+        .flat_map(|zoom| {
+            let (west, north) = tile::tile_for(bounds.west, bounds.north, zoom);
+            let (east, south) = tile::tile_for(bounds.east, bounds.south, zoom);
+            (north..=south).flat_map(move |y| (west..=east).map(move |x| (zoom, x, y)))
+        })
+        .collect();
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.raster.with_extension("tiles"));
+    let bar = ProgressBar::new(tiles.len() as u64);
+    bar.set_style(ProgressStyle::with_template(
+        "{prefix:<30} {msg} {pos}/{len} {elapsed_precise} {bar_wide}",
+    )?);
+    bar.set_prefix(output.to_string_lossy().to_string());
+    bar.set_message("drawing tiles");
+    let written = tiles
+        .into_par_iter()
+        .map(|(zoom, x, y)| {
+            let drawn = match draw(&grid, &transform, &args.colormap, range, zoom, x, y) {
+                // Tiles with nothing on them are left out; maps show them as empty.
+                None => false,
+                Some(image) => {
+                    let dir = output.join(zoom.to_string()).join(x.to_string());
+                    fs::create_dir_all(&dir)?;
+                    let path = dir.join(format!("{}.png", y));
+                    image
+                        .save(&path)
+                        .with_context(|| format!("Could not write {}", path.to_string_lossy()))?;
+                    true
+                }
+            };
+            bar.inc(1);
+            Ok(drawn as u64)
+        })
+        .sum::<Result<u64>>()?;
+    bar.finish_with_message(format!("wrote {} tiles", written));
+    Ok(())
+}
+
+/// Draws tile `x`, `y` of `zoom`, taking for each of its pixels the raster pixel under
+/// its center. Returns `None` if no pixel of the tile has a value.
+fn draw(
+    grid: &Grid,
+    transform: &GeoTransform,
+    colormap: &Colormap,
+    range: Range,
+    zoom: u8,
+    x: u32,
+    y: u32,
+) -> Option<RgbaImage> {
+    let mut image = RgbaImage::new(TILE_SIZE, TILE_SIZE);
+    let mut empty = true;
+    for row in 0..TILE_SIZE {
+        for column in 0..TILE_SIZE {
+            let (lon, lat) = tile::tile_position(
+                x as f64 + (column as f64 + 0.5) / TILE_SIZE as f64,
+                y as f64 + (row as f64 + 0.5) / TILE_SIZE as f64,
+                zoom,
+            );
+            let (px, py) = transform.pixel_at(lon, lat);
+            if let Some(value) = grid.get(px, py) {
+                let (r, g, b) = colormap.color(value, range);
+                image.put_pixel(column, row, Rgba([r, g, b, 255]));
+                empty = false;
+            }
+        }
+    }
+    (!empty).then_some(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Grid;
+
+    #[test]
+    fn test_grid_get() {
+        let grid = Grid {
+            width: 2,
+            height: 2,
+            values: vec![1.0, f32::NAN, 3.0, 4.0],
+        };
+        assert_eq!(grid.get(0.5, 1.9), Some(3.0));
+        assert_eq!(grid.get(1.0, 0.0), None);
+        assert_eq!(grid.get(-0.1, 0.0), None);
+        assert_eq!(grid.get(2.0, 0.0), None);
+    }
+}
@@ -0,0 +1,286 @@
+//! Running SQL over a parquet output, for quick checks on machines without a database.
+//!
+//! The output is loaded into an in-memory SQLite table named `output`, so the query can
+//! use anything SQLite supports. A query without a `FROM` clause reads from `output`.
+
+use crate::{gpkg, json, output};
+use anyhow::{anyhow, bail, Result};
+use arrow_array::{Array, ArrayRef, Int64Array, RecordBatch};
+use arrow_schema::DataType;
+use rusqlite::{types::Value, Connection};
+use std::path::PathBuf;
+
+/// The name of the table holding the output.
+const TABLE: &str = "output";
+
+#[derive(clap::Args)]
+pub struct QueryArgs {
+    /// A parquet output.
+    input: PathBuf,
+    /// The query, such as `SELECT count(*), sum(value) WHERE lat > 0`.
+    sql: String,
+    /// Print the result as a JSON list of rows instead of tab separated text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+pub fn run(args: &QueryArgs) -> Result<()> {
+    let connection = load(&output::read_parquet(&args.input)?)?;
+    let (columns, rows) = query(&connection, &args.sql)?;
+    if args.json {
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                json::Value::object(columns.iter().cloned().zip(row.into_iter().map(to_json)))
+            })
+            .collect::<Vec<_>>();
+        println!("{}", json::Value::Array(rows).pretty());
+        return Ok(());
+    }
+    println!("{}", columns.join("\t"));
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(to_text).collect();
+        println!("{}", cells.join("\t"));
+    }
+    Ok(())
+}
+
+/// Copies `batch` into an in-memory SQLite table. Integers of every width are stored as
+/// SQLite integers, and columns of types SQLite has no counterpart for as text.
+fn load(batch: &RecordBatch) -> Result<Connection> {
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(batch.schema().fields())
+        .map(|(column, field)| {
+            let cast = match column.data_type() {
+                DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::UInt16 => {
+                    arrow_cast::cast(column, &DataType::Int64)
+                }
+                DataType::Int64 => Ok(column.clone()),
+                DataType::Float16 => arrow_cast::cast(column, &DataType::Float64),
+                data_type => match gpkg::column_type(data_type) {
+                    Ok(_) => Ok(column.clone()),
+                    Err(_) => arrow_cast::cast(column, &DataType::Utf8),
+                },
+            };
+            cast.map_err(|_| {
+                anyhow!(
+                    "Cannot query column {} of type {}",
+                    field.name(),
+                    field.data_type()
+                )
+            })
+        })
+        .collect::<Result<Vec<ArrayRef>>>()?;
+
+    let mut connection = Connection::open_in_memory()?;
+    let transaction = connection.transaction()?;
+    let names: Vec<String> = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| gpkg::quote(field.name()))
+        .collect();
+    transaction.execute_batch(&format!("CREATE TABLE {} ({});", TABLE, names.join(", ")))?;
+    {
+        let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO {} VALUES ({})",
+            TABLE,
+            placeholders.join(", ")
+        ))?;
+        let mut values = Vec::with_capacity(columns.len());
+        for row in 0..batch.num_rows() {
+            values.clear();
+            values.extend(columns.iter().map(|column| match column.is_null(row) {
+                true => Value::Null,
+                false => sql_value(column.as_ref(), row),
+            }));
+            insert.execute(rusqlite::params_from_iter(&values))?;
+        }
+    }
+    transaction.commit()?;
+    Ok(connection)
+}
+
+/// The value of a cell of a column `load` has cast.
+fn sql_value(column: &dyn Array, row: usize) -> Value {
+    match column.data_type() {
+        DataType::Int64 => {
+            let column: &Int64Array = column.as_any().downcast_ref().expect("cast by load");
+            Value::Integer(column.value(row))
+        }
+        _ => gpkg::sql_value(column, row),
+    }
+}
+
+/// Runs `sql`, returning the result's column names and rows.
+fn query(connection: &Connection, sql: &str) -> Result<(Vec<String>, Vec<Vec<Value>>)> {
+    let mut statement = connection.prepare(&with_from(sql))?;
+    if !statement.readonly() {
+        bail!("Only queries that read the output can be run");
+    }
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let count = columns.len();
+    let rows = statement
+        .query_map([], |row| {
+            (0..count).map(|i| row.get::<_, Value>(i)).collect()
+        })?
+        .collect::<Result<Vec<Vec<Value>>, _>>()?;
+    Ok((columns, rows))
+}
+
+/// Adds `FROM output` to a query that has no `FROM` clause, before the first clause that
+/// must follow it.
+fn with_from(sql: &str) -> String {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut word_start = None;
+    let mut insert_at = None;
+    for (i, c) in sql.char_indices().chain([(sql.len(), ' ')]) {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' {
+            word_start.get_or_insert(i);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            if depth == 0 {
+                match sql[start..i].to_ascii_uppercase().as_str() {
+                    "FROM" => return sql.to_string(),
+                    "WHERE" | "GROUP" | "HAVING" | "WINDOW" | "ORDER" | "LIMIT" => {
+                        insert_at.get_or_insert(start);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        match c {
+            '\'' | '"' | '`' => quote = Some(c),
+            '[' => quote = Some(']'),
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    let trimmed = sql.trim_end().trim_end_matches(';');
+    match insert_at {
+        Some(at) => format!("{}FROM {} {}", &sql[..at], TABLE, &sql[at..]),
+        None => format!("{} FROM {}", trimmed, TABLE),
+    }
+}
+
+fn to_json(value: Value) -> json::Value {
+    match value {
+        Value::Null => json::Value::Null,
+        Value::Integer(n) => json::Value::Number(n as f64),
+        Value::Real(x) => json::Value::Number(x),
+        Value::Text(text) => json::Value::String(text),
+        Value::Blob(bytes) => json::Value::String(format!("<{} bytes>", bytes.len())),
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(n) => n.to_string(),
+        Value::Real(x) => x.to_string(),
+        Value::Text(text) => text.clone(),
+        Value::Blob(bytes) => format!("<{} bytes>", bytes.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, query, with_from};
+    use crate::output::{read_parquet, write_parquet, Codec};
+    use arrow_array::{
+        ArrayRef, Date32Array, Float32Array, Float64Array, Int16Array, Int32Array, Int64Array,
+        RecordBatch,
+    };
+    use rusqlite::types::Value;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_query() {
+        assert_eq!(
+            with_from("SELECT count(*), sum(value) WHERE lat > 0"),
+            "SELECT count(*), sum(value) FROM output WHERE lat > 0"
+        );
+        assert_eq!(
+            with_from("select max(value);"),
+            "select max(value) FROM output"
+        );
+        assert_eq!(
+            with_from("SELECT (SELECT 1 WHERE 1) AS 'where' LIMIT 1"),
+            "SELECT (SELECT 1 WHERE 1) AS 'where' FROM output LIMIT 1"
+        );
+        assert_eq!(with_from("SELECT * FROM output"), "SELECT * FROM output");
+
+        let batch = RecordBatch::try_from_iter([
+            (
+                "lon",
+                Arc::new(Float32Array::from(vec![1.0, 2.0, 3.0])) as ArrayRef,
+            ),
+            ("lat", Arc::new(Float32Array::from(vec![-1.0, 1.0, 2.0]))),
+            ("value", Arc::new(Float64Array::from(vec![5.0, 6.0, 7.5]))),
+            ("band", Arc::new(Int16Array::from(vec![1, 1, 2]))),
+            ("class", Arc::new(Int32Array::from(vec![-3, 0, 40_000]))),
+            ("count", Arc::new(Int64Array::from(vec![0, 1, i64::MAX]))),
+            ("day", Arc::new(Date32Array::from(vec![0, 1, 2]))),
+        ])
+        .unwrap();
+        let connection = load(&batch).unwrap();
+        let (columns, rows) = query(
+            &connection,
+            "SELECT count(*), sum(value) AS total WHERE lat > 0",
+        )
+        .unwrap();
+        assert_eq!(columns, ["count(*)", "total"]);
+        assert_eq!(rows, [vec![Value::Integer(2), Value::Real(13.5)]]);
+        // Integers of any width are stored as integers, so compare and sum as numbers.
+        let (_, rows) = query(
+            &connection,
+            "SELECT band, class, count, typeof(band) WHERE value > 7",
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [vec![
+                Value::Integer(2),
+                Value::Integer(40_000),
+                Value::Integer(i64::MAX),
+                Value::Text("integer".into()),
+            ]]
+        );
+        let (_, rows) = query(&connection, "SELECT sum(band) WHERE band > 1").unwrap();
+        assert_eq!(rows, [vec![Value::Integer(2)]]);
+        // Columns SQLite has no type for are kept as text.
+        let (_, rows) = query(&connection, "SELECT day WHERE value > 7").unwrap();
+        assert_eq!(rows, [vec![Value::Text("1970-01-03".into())]]);
+        assert!(query(&connection, "DELETE FROM output").is_err());
+
+        // Outputs with file metadata, such as that copied from the tif, read back whole.
+        let schema = batch
+            .schema()
+            .as_ref()
+            .clone()
+            .with_metadata([("gdal:band1:units".to_string(), "K".to_string())].into());
+        let batch = RecordBatch::try_new(Arc::new(schema), batch.columns().to_vec()).unwrap();
+        let path = std::env::temp_dir().join(format!("query-test-{}.parquet", std::process::id()));
+        write_parquet(&path, &batch, Codec::Snappy.into()).unwrap();
+        let read = read_parquet(&path).unwrap();
+        assert_eq!(read.num_rows(), 3);
+        assert_eq!(read.schema().metadata()["gdal:band1:units"], "K");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
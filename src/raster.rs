@@ -0,0 +1,637 @@
+use crate::{
+    failure::{Classified, FailureClass::Unsupported},
+    ifd::{Change, Directory, BITS_PER_SAMPLE, SAMPLES_PER_PIXEL, SAMPLE_FORMAT, SHORT},
+    packed,
+    timing::PixelClocks,
+};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::{io::Cursor, ops::Range, sync::atomic::Ordering};
+use tiff::{
+    decoder::{ChunkType, Decoder, DecodingResult, Limits},
+    tags::Tag,
+    ColorType,
+};
+
+/// How many chunks of the tif are decoded and processed together as one unit of work.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkSize {
+    Rows(u32),
+    Tiles(u32),
+}
+
+/// How stored samples are interpreted, overriding the tif's SampleFormat tag.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum SampleFormat {
+    Unsigned,
+    Signed,
+    Float,
+}
+
+/// The stored value of RGBA pixels whose alpha is 0, left out as negative values are.
+pub const TRANSPARENT: i32 = -1;
+
+/// Which samples of a true color image are decoded packed into one value, `0xRRGGBB`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    Rgb,
+    /// With a fourth, alpha, sample, so pixels whose alpha is 0 are [`TRANSPARENT`].
+    Rgba,
+}
+
+impl Color {
+    /// How the decoder's image is packed, if its pixels are 8 or 16 bit RGB colors with
+    /// their samples stored together.
+    pub fn of<R: std::io::Read + std::io::Seek>(decoder: &mut Decoder<R>) -> Result<Option<Color>> {
+        let photometric = decoder.find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)?;
+        let samples = decoder.find_tag_unsigned::<u32>(Tag::SamplesPerPixel)?;
+        let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)?;
+        let bits = decoder
+            .find_tag_unsigned_vec::<u8>(Tag::BitsPerSample)?
+            .and_then(|bits| bits.first().copied());
+        if photometric != Some(2)
+            || samples.unwrap_or(1) < 3
+            || planar == Some(2)
+            || !matches!(bits, Some(8 | 16))
+        {
+            return Ok(None);
+        }
+        // ExtraSamples of 1 or 2 mark the first extra sample as associated or unassociated
+        // alpha.
+        let alpha = decoder
+            .find_tag_unsigned_vec::<u16>(Tag::ExtraSamples)?
+            .and_then(|extra| extra.first().copied())
+            .is_some_and(|extra| matches!(extra, 1 | 2));
+        Ok(Some(match alpha {
+            true => Color::Rgba,
+            false => Color::Rgb,
+        }))
+    }
+
+    /// Packs each pixel's color of `samples` samples, keeping the top 8 bits of 16 bit
+    /// ones.
+    fn pack(self, pixels: DecodingResult, samples: u32) -> Result<Vec<i32>> {
+        let levels: Vec<u32> = match pixels {
+            DecodingResult::U8(levels) => levels.into_iter().map(u32::from).collect(),
+            DecodingResult::U16(levels) => levels.into_iter().map(|l| l as u32 >> 8).collect(),
+            other => bail!(
+                "Color samples decoded as {}, not 8 or 16 bit levels",
+                decoding_result_type(&other)
+            ),
+        };
+        Ok(levels
+            .chunks_exact(samples as usize)
+            .map(|pixel| match self {
+                Color::Rgba if pixel[3] == 0 => TRANSPARENT,
+                _ => (pixel[0] << 16 | pixel[1] << 8 | pixel[2]) as i32,
+            })
+            .collect())
+    }
+}
+
+/// Where the strips or tiles of an image sit within it.
+pub struct Layout {
+    chunk_type: ChunkType,
+    chunk_width: u32,
+    chunk_height: u32,
+    chunks_across: u32,
+    chunk_count: u32,
+    samples: u32,
+    band: u32,
+    band_separate: bool,
+    /// Bits in each sample when they are packed below a byte.
+    packed_bits: Option<u8>,
+    color: Option<Color>,
+}
+
+/// A decoded rectangle of the image, positioned in image pixel coordinates.
+pub struct Window {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub pixels: DecodingResult,
+}
+
+impl Layout {
+    pub fn from_decoder<R: std::io::Read + std::io::Seek>(
+        decoder: &mut Decoder<R>,
+    ) -> Result<Layout> {
+        let (width, _) = decoder.dimensions()?;
+        let chunk_type = decoder.get_chunk_type();
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        if chunk_width == 0 || chunk_height == 0 {
+            bail!("Image has empty strips or tiles");
+        }
+        let chunk_count = match chunk_type {
+            ChunkType::Strip => decoder.strip_count()?,
+            ChunkType::Tile => decoder.tile_count()?,
+        };
+        let samples = decoder
+            .find_tag_unsigned::<u32>(Tag::SamplesPerPixel)?
+            .unwrap_or(1)
+            .max(1);
+        let band_separate =
+            samples > 1 && decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2);
+        let bits = decoder
+            .find_tag_unsigned_vec::<u8>(Tag::BitsPerSample)?
+            .and_then(|bits| bits.first().copied())
+            .unwrap_or(1);
+        Ok(Layout {
+            chunk_type,
+            chunk_width,
+            chunk_height,
+            chunks_across: width.div_ceil(chunk_width),
+            chunk_count,
+            samples,
+            band: 0,
+            band_separate,
+            packed_bits: matches!(bits, 1 | 2 | 4).then_some(bits),
+            color: Color::of(decoder)?,
+        })
+    }
+
+    /// Reads `band` (counting from zero) of a pixel interleaved image instead of the first.
+    pub fn with_band(mut self, band: u32) -> Result<Layout> {
+        if self.color.is_some() && band > 0 {
+            bail!(Classified::new(
+                Unsupported,
+                "The image's colors are read packed together, so a band can't be chosen"
+            ));
+        }
+        if band >= self.samples {
+            bail!(
+                "Band {} requested but the image has {}",
+                band + 1,
+                self.samples
+            );
+        }
+        self.band = band;
+        Ok(self)
+    }
+
+    /// Splits the image's chunks into consecutive runs of roughly `size`.
+    ///
+    /// Row counts are rounded down to whole strips or rows of tiles, and a strip counts
+    /// as one tile, so every unit holds at least one chunk.
+    pub fn units(&self, size: ChunkSize) -> Vec<Range<u32>> {
+        let per_unit = match (size, self.chunk_type) {
+            (ChunkSize::Rows(rows), ChunkType::Strip) => rows / self.chunk_height,
+            (ChunkSize::Rows(rows), ChunkType::Tile) => {
+                rows / self.chunk_height * self.chunks_across
+            }
+            (ChunkSize::Tiles(tiles), _) => tiles,
+        }
+        .max(1);
+        (0..self.chunk_count)
+            .step_by(per_unit as usize)
+            .map(|start| start..(start + per_unit).min(self.chunk_count))
+            .collect()
+    }
+
+    /// Bits in each sample when they are packed below a byte. Such samples are decoded to
+    /// I32.
+    pub fn packed_bits(&self) -> Option<u8> {
+        self.packed_bits
+    }
+
+    /// How a true color image's samples are packed into one value. Such images are
+    /// decoded to I32.
+    pub fn color(&self) -> Option<Color> {
+        self.color
+    }
+
+    pub fn chunk_count(&self) -> u32 {
+        self.chunk_count
+    }
+
+    pub fn chunk_type(&self) -> &'static str {
+        match self.chunk_type {
+            ChunkType::Strip => "strip",
+            ChunkType::Tile => "tile",
+        }
+    }
+
+    /// Samples in each pixel, one per band.
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn chunk_dimensions(&self) -> (u32, u32) {
+        (self.chunk_width, self.chunk_height)
+    }
+
+    /// The image pixel coordinate of the top left corner of `chunk`.
+    pub fn origin(&self, chunk: u32) -> (u32, u32) {
+        match self.chunk_type {
+            ChunkType::Strip => (0, chunk * self.chunk_height),
+            ChunkType::Tile => (
+                chunk % self.chunks_across * self.chunk_width,
+                chunk / self.chunks_across * self.chunk_height,
+            ),
+        }
+    }
+}
+
+/// Decodes one unit of chunks from the raw tif bytes, with integer samples widened by
+/// [`widen`] and floats kept as they are.
+///
+/// Chunks are only decoded if `keep` accepts their `(x, y, width, height)` rectangle. Each
+/// call opens its own decoder, so units can be read from several threads at once.
+pub fn read_unit(
+    contents: &[u8],
+    layout: &Layout,
+    chunks: Range<u32>,
+    keep: impl Fn(u32, u32, u32, u32) -> bool,
+) -> Result<Vec<Window>> {
+    if layout.band_separate {
+        bail!("The image stores its bands separately, so it has to be read one band at a time");
+    }
+    let mut decoder = Decoder::new(Cursor::new(contents))?.with_limits(Limits::unlimited());
+    // Samples narrower than a byte are decoded as bytes of their packed rows, then split.
+    let mut packed = match layout.packed_bits {
+        Some(bits) => Some((
+            Decoder::new(packed::byte_view(contents)?)?.with_limits(Limits::unlimited()),
+            bits,
+        )),
+        None => None,
+    };
+    let mut windows = vec![];
+    for chunk in chunks {
+        let (x, y) = layout.origin(chunk);
+        let (width, height) = decoder.chunk_data_dimensions(chunk);
+        if !keep(x, y, width, height) {
+            continue;
+        }
+        let pixels = match &mut packed {
+            Some((bytes, bits)) => match bytes.read_chunk(chunk)? {
+                DecodingResult::U8(bytes) => {
+                    DecodingResult::I32(packed::unpack(&bytes, *bits, width, height))
+                }
+                other => bail!(
+                    "Packed samples decoded as {}, not bytes",
+                    decoding_result_type(&other)
+                ),
+            },
+            None => match layout.color {
+                Some(color) => {
+                    DecodingResult::I32(color.pack(decoder.read_chunk(chunk)?, layout.samples)?)
+                }
+                None => widen(decoder.read_chunk(chunk)?),
+            },
+        };
+        windows.push(Window {
+            x,
+            y,
+            width,
+            pixels,
+        });
+    }
+    Ok(windows)
+}
+
+/// Decodes the whole image across the thread pool and collects what `visit` returns for
+/// each pixel, given its `(x, y, value)`. Integer and float samples alike are given as
+/// `f64`, which holds any I32 exactly.
+///
+/// `keep` chooses chunks as in [`read_unit`], and `progress` is told how many chunks each
+/// finished unit held.
+pub fn read_pixels<T: Send, I: IntoIterator<Item = T>>(
+    contents: &[u8],
+    layout: &Layout,
+    size: ChunkSize,
+    keep: impl Fn(u32, u32, u32, u32) -> bool + Sync,
+    progress: impl Fn(u64) + Sync,
+    visit: impl Fn(u32, u32, f64) -> I + Sync,
+) -> Result<Vec<T>> {
+    read_pixels_timed(
+        contents,
+        layout,
+        size,
+        keep,
+        progress,
+        visit,
+        &PixelClocks::default(),
+    )
+}
+
+/// Like [`read_pixels`], adding the time spent decoding and visiting to `clocks`.
+pub fn read_pixels_timed<T: Send, I: IntoIterator<Item = T>>(
+    contents: &[u8],
+    layout: &Layout,
+    size: ChunkSize,
+    keep: impl Fn(u32, u32, u32, u32) -> bool + Sync,
+    progress: impl Fn(u64) + Sync,
+    visit: impl Fn(u32, u32, f64) -> I + Sync,
+    clocks: &PixelClocks,
+) -> Result<Vec<T>> {
+    fold_pixels_timed(
+        contents,
+        layout,
+        size,
+        keep,
+        progress,
+        Vec::new,
+        |items, x, y, value| items.extend(visit(x, y, value)),
+        |mut left, mut right| {
+            left.append(&mut right);
+            left
+        },
+        clocks,
+    )
+}
+
+/// Decodes the whole image across the thread pool, folding each unit's pixels into an
+/// accumulator from `init` and merging the units' accumulators in image order, so nothing
+/// per pixel has to be kept.
+#[allow(clippy::too_many_arguments)]
+pub fn fold_pixels<A: Send>(
+    contents: &[u8],
+    layout: &Layout,
+    size: ChunkSize,
+    keep: impl Fn(u32, u32, u32, u32) -> bool + Sync,
+    progress: impl Fn(u64) + Sync,
+    init: impl Fn() -> A + Sync,
+    visit: impl Fn(&mut A, u32, u32, f64) + Sync,
+    merge: impl Fn(A, A) -> A + Sync,
+) -> Result<A> {
+    fold_pixels_timed(
+        contents,
+        layout,
+        size,
+        keep,
+        progress,
+        init,
+        visit,
+        merge,
+        &PixelClocks::default(),
+    )
+}
+
+/// Like [`fold_pixels`], adding the time spent decoding and visiting to `clocks`.
+#[allow(clippy::too_many_arguments)]
+pub fn fold_pixels_timed<A: Send>(
+    contents: &[u8],
+    layout: &Layout,
+    size: ChunkSize,
+    keep: impl Fn(u32, u32, u32, u32) -> bool + Sync,
+    progress: impl Fn(u64) + Sync,
+    init: impl Fn() -> A + Sync,
+    visit: impl Fn(&mut A, u32, u32, f64) + Sync,
+    merge: impl Fn(A, A) -> A + Sync,
+    clocks: &PixelClocks,
+) -> Result<A> {
+    layout
+        .units(size)
+        .into_par_iter()
+        .map(|chunks| {
+            let mut acc = init();
+            let chunk_count = chunks.len() as u64;
+            let windows = clocks
+                .decoding
+                .time(|| read_unit(contents, layout, chunks, &keep))?;
+            for window in windows {
+                // Packed colors are one value for each pixel.
+                let (band, samples) = match layout.color {
+                    Some(_) => (0, 1),
+                    None => (layout.band, layout.samples),
+                };
+                let at = (window.x, window.y, window.width);
+                let visited = match window.pixels {
+                    DecodingResult::I32(pixels) => clocks.visiting.time(|| {
+                        let pixels = pixels.into_iter().map(f64::from);
+                        visit_window(&mut acc, at, pixels, band, samples, &visit)
+                    }),
+                    DecodingResult::F32(pixels) => clocks.visiting.time(|| {
+                        let pixels = pixels.into_iter().map(f64::from);
+                        visit_window(&mut acc, at, pixels, band, samples, &visit)
+                    }),
+                    DecodingResult::F64(pixels) => clocks.visiting.time(|| {
+                        visit_window(&mut acc, at, pixels.into_iter(), band, samples, &visit)
+                    }),
+                    other => bail!(Classified::new(
+                        Unsupported,
+                        format!(
+                            "Unsupported sample type {}, expected integers or floats",
+                            decoding_result_type(&other)
+                        )
+                    )),
+                };
+                clocks.pixels.fetch_add(visited, Ordering::Relaxed);
+            }
+            progress(chunk_count);
+            Ok(acc)
+        })
+        .try_reduce(&init, |left, right| Ok(merge(left, right)))
+}
+
+/// Visits sample `band` of each pixel of a window `width` pixels wide with its top left at
+/// `x`, `y`, returning how many pixels it visited.
+fn visit_window<A>(
+    acc: &mut A,
+    (x, y, width): (u32, u32, u32),
+    pixels: impl Iterator<Item = f64>,
+    band: u32,
+    samples: u32,
+    visit: &impl Fn(&mut A, u32, u32, f64),
+) -> u64 {
+    let mut visited = 0;
+    for (idx, value) in pixels
+        .skip(band as usize)
+        .step_by(samples as usize)
+        .enumerate()
+    {
+        let x = x + (idx % width as usize) as u32;
+        let y = y + (idx / width as usize) as u32;
+        visit(acc, x, y, value);
+        visited += 1;
+    }
+    visited
+}
+
+/// Widens integer samples to I32, or to F64 for the 32-bit unsigned and 64-bit ones I32
+/// can't hold all of. F64 holds those up to 2^53 exactly, and rounds larger ones rather
+/// than failing on them.
+pub fn widen(pixels: DecodingResult) -> DecodingResult {
+    match pixels {
+        DecodingResult::U8(samples) => {
+            DecodingResult::I32(samples.into_iter().map(i32::from).collect())
+        }
+        DecodingResult::U16(samples) => {
+            DecodingResult::I32(samples.into_iter().map(i32::from).collect())
+        }
+        DecodingResult::I8(samples) => {
+            DecodingResult::I32(samples.into_iter().map(i32::from).collect())
+        }
+        DecodingResult::I16(samples) => {
+            DecodingResult::I32(samples.into_iter().map(i32::from).collect())
+        }
+        DecodingResult::U32(samples) => {
+            DecodingResult::F64(samples.into_iter().map(f64::from).collect())
+        }
+        DecodingResult::U64(samples) => {
+            DecodingResult::F64(samples.into_iter().map(|v| v as f64).collect())
+        }
+        DecodingResult::I64(samples) => {
+            DecodingResult::F64(samples.into_iter().map(|v| v as f64).collect())
+        }
+        other => other,
+    }
+}
+
+/// Names the sample type the decoder will produce, in the same terms as
+/// [`decoding_result_type`].
+pub fn sample_type<R: std::io::Read + std::io::Seek>(decoder: &mut Decoder<R>) -> Result<String> {
+    let packed = decoder
+        .find_tag_unsigned_vec::<u8>(Tag::BitsPerSample)?
+        .and_then(|bits| bits.first().copied())
+        .filter(|bits| matches!(bits, 1 | 2 | 4));
+    // Packed samples are decoded as bytes, so the decoder needn't support their color type.
+    let bits = match packed {
+        Some(bits) => bits,
+        None => match decoder.colortype()? {
+            ColorType::Gray(bits)
+            | ColorType::RGB(bits)
+            | ColorType::RGBA(bits)
+            | ColorType::CMYK(bits)
+            | ColorType::Palette(bits) => bits,
+            other => bail!("Unsupported color type {:?}", other),
+        },
+    };
+    // Interleaved images give one format for each sample, which the decoder requires to
+    // be the same.
+    let format = decoder
+        .find_tag_unsigned_vec::<u16>(Tag::SampleFormat)?
+        .and_then(|formats| formats.first().copied());
+    let prefix = match format {
+        None | Some(1) => "U",
+        Some(2) => "I",
+        Some(3) => "F",
+        Some(format) => bail!("Unsupported sample format {}", format),
+    };
+    Ok(format!("{}{}", prefix, bits))
+}
+
+/// Rewrites the tif's first image so all of its samples are read as `format`, whatever
+/// its SampleFormat tag says.
+pub fn with_sample_format(contents: &[u8], format: SampleFormat) -> Result<Vec<u8>> {
+    let mut directory = Directory::read(contents)?;
+    let samples = directory.value(contents, SAMPLES_PER_PIXEL)?.unwrap_or(1);
+    let bits = directory.value(contents, BITS_PER_SAMPLE)?.unwrap_or(1);
+    let value = match format {
+        SampleFormat::Unsigned => 1,
+        SampleFormat::Signed => 2,
+        SampleFormat::Float if matches!(bits, 32 | 64) => 3,
+        SampleFormat::Float => bail!("{} bit samples can't be read as floats", bits),
+    };
+    directory.set(SAMPLE_FORMAT, SHORT, vec![value; samples as usize]);
+    Ok(directory.rewrite(contents, |_| Ok(Change::Keep))?.to_vec())
+}
+
+pub fn decoding_result_type(result: &DecodingResult) -> &'static str {
+    match result {
+        DecodingResult::U8(_) => "U8",
+        DecodingResult::U16(_) => "U16",
+        DecodingResult::U32(_) => "U32",
+        DecodingResult::U64(_) => "U64",
+        DecodingResult::F32(_) => "F32",
+        DecodingResult::F64(_) => "F64",
+        DecodingResult::I8(_) => "I8",
+        DecodingResult::I16(_) => "I16",
+        DecodingResult::I32(_) => "I32",
+        DecodingResult::I64(_) => "I64",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_unit, with_sample_format, ChunkSize, Color, Layout, SampleFormat};
+    use std::io::Cursor;
+    use tiff::{
+        decoder::{ChunkType, Decoder, DecodingResult},
+        encoder::{
+            colortype::{Gray16, RGBA8},
+            TiffEncoder,
+        },
+        tags::Tag,
+    };
+
+    fn tiled() -> Layout {
+        Layout {
+            chunk_type: ChunkType::Tile,
+            chunk_width: 256,
+            chunk_height: 256,
+            chunks_across: 4,
+            chunk_count: 12,
+            samples: 1,
+            band: 0,
+            band_separate: false,
+            packed_bits: None,
+            color: None,
+        }
+    }
+
+    #[test]
+    fn test_units_rows_of_tiles() {
+        let units = tiled().units(ChunkSize::Rows(512));
+        assert_eq!(units, vec![0..8, 8..12]);
+        assert_eq!(tiled().origin(6), (512, 256));
+    }
+
+    #[test]
+    fn test_units_at_least_one_chunk() {
+        let strips = Layout {
+            chunk_type: ChunkType::Strip,
+            chunk_width: 100,
+            chunk_height: 8,
+            chunks_across: 1,
+            chunk_count: 3,
+            samples: 1,
+            band: 0,
+            band_separate: false,
+            packed_bits: None,
+            color: None,
+        };
+        assert_eq!(strips.units(ChunkSize::Rows(4)), vec![0..1, 1..2, 2..3]);
+        assert_eq!(strips.units(ChunkSize::Tiles(2)), vec![0..2, 2..3]);
+    }
+
+    #[test]
+    fn test_with_sample_format() {
+        let mut contents = Cursor::new(vec![]);
+        TiffEncoder::new(&mut contents)
+            .unwrap()
+            .write_image::<Gray16>(2, 1, &[65535, 7])
+            .unwrap();
+        let contents = contents.into_inner();
+        let signed = with_sample_format(&contents, SampleFormat::Signed).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(signed)).unwrap();
+        let DecodingResult::I16(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected i16 samples");
+        };
+        assert_eq!(pixels, vec![-1, 7]);
+        assert!(with_sample_format(&contents, SampleFormat::Float).is_err());
+    }
+
+    #[test]
+    fn test_color() {
+        let mut contents = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut contents).unwrap();
+        let mut image = encoder.new_image::<RGBA8>(2, 1).unwrap();
+        image.encoder().write_tag(Tag::ExtraSamples, 2u16).unwrap();
+        image.write_data(&[255, 0, 16, 255, 1, 2, 3, 0]).unwrap();
+        let contents = contents.into_inner();
+
+        let mut decoder = Decoder::new(Cursor::new(&contents)).unwrap();
+        let layout = Layout::from_decoder(&mut decoder).unwrap();
+        assert_eq!(layout.color(), Some(Color::Rgba));
+        assert!(Layout::from_decoder(&mut decoder)
+            .unwrap()
+            .with_band(1)
+            .is_err());
+        let windows = read_unit(&contents, &layout, 0..1, |_, _, _, _| true).unwrap();
+        let DecodingResult::I32(pixels) = &windows[0].pixels else {
+            panic!("expected packed colors");
+        };
+        assert_eq!(pixels, &vec![0xff0010, super::TRANSPARENT]);
+    }
+}
@@ -0,0 +1,403 @@
+//! Turning a table of positions and values, such as an output of this tool, back into a
+//! GeoTIFF, so a table that was edited can become a corrected raster.
+//!
+//! The pixel grid is the one a table grouped with `--group` records, the grid of an
+//! `--align-to` reference, or pixels `--resolution` across placed around the rows as
+//! conversion's `--registration` placed the rows in their pixels.
+
+use crate::{
+    align,
+    crs::Crs,
+    georef::BBox,
+    grid::Grid,
+    group::{Aggregation, Align, CellSize, LonLat},
+    output,
+};
+use anyhow::{bail, Context, Result};
+use arrow_array::{cast::as_primitive_array, types::Float64Type, Array, RecordBatch};
+use arrow_schema::DataType;
+use std::path::{Path, PathBuf};
+
+#[derive(clap::Args)]
+pub struct RasterizeArgs {
+    /// The `.parquet` or `.csv` table to rasterize, with `lon` and `lat`, or `easting`
+    /// and `northing`, columns.
+    input: PathBuf,
+    /// The GeoTIFF to write.
+    #[arg(short = 'o', long = "output")]
+    output: PathBuf,
+    /// Column holding the values to write.
+    #[arg(long = "column", default_value = "value")]
+    column: String,
+    /// Width and height of the pixels, in the units of the positions, as `0.5` or
+    /// `0.1x0.05` as for `--group`. Defaults to the cells of a table grouped with `--group`.
+    #[arg(long = "resolution", conflicts_with = "align_to")]
+    resolution: Option<CellSize>,
+    /// Where in its pixel each row's position is, as conversion's `--registration`: the
+    /// center, or the top left corner.
+    #[arg(long = "registration", value_enum, default_value_t = Align::Center)]
+    registration: Align,
+    /// Put the pixels on the grid of this tif, or of a parquet output grouped with
+    /// `--group`, as conversion's `--align-to` does.
+    #[arg(long = "align-to")]
+    align_to: Option<PathBuf>,
+    /// Extent of the raster as `minLon,minLat,maxLon,maxLat`, in the CRS of the
+    /// positions. Defaults to the pixels the rows fall in.
+    #[arg(long = "bbox", allow_hyphen_values = true)]
+    bbox: Option<BBox>,
+    /// CRS of the positions. Defaults to the CRS recorded on an `easting` column, or
+    /// EPSG:4326 for lon/lat.
+    #[arg(long = "crs")]
+    crs: Option<Crs>,
+    /// How the values of rows in the same pixel are combined, unweighted.
+    #[arg(long = "agg", value_enum, default_value_t = Aggregation::Mean)]
+    agg: Aggregation,
+}
+
+/// How far, as a fraction of a pixel, a position may be off the edge of its pixel, so
+/// rounding in the positions doesn't move rows between pixels.
+const EPSILON: f64 = 1e-6;
+
+/// More pixels than a table is sensibly rasterized into, which a resolution far too fine
+/// would otherwise try to allocate.
+const MAX_PIXELS: u64 = 1 << 30;
+
+/// How pixels are laid out around the rows.
+#[derive(Clone, Copy)]
+enum Lattice {
+    /// Pixels of `size` with edges on multiples of it from `origin`.
+    Edges { size: CellSize, origin: LonLat },
+    /// Pixels of `size` with each row's position at `registration` in its pixel.
+    Positions { size: CellSize, registration: Align },
+}
+
+/// A table's rows, as `(x, y, value)` with NaN for missing values, and what its columns
+/// say about them.
+struct Table {
+    rows: Vec<(f64, f64, f64)>,
+    crs: Option<Crs>,
+    grid: Option<(CellSize, LonLat)>,
+    units: Option<String>,
+    description: Option<String>,
+}
+
+pub fn run(args: &RasterizeArgs) -> Result<()> {
+    let table = match args.input.extension().and_then(|e| e.to_str()) {
+        Some("csv") => read_csv(&args.input, &args.column)?,
+        _ => read_parquet(&args.input, &args.column)?,
+    };
+    let lattice = match (&args.align_to, args.resolution, table.grid) {
+        (Some(reference), _, _) => {
+            let (size, origin) = align::reference_grid(reference, None, None)?;
+            Lattice::Edges { size, origin }
+        }
+        (None, Some(size), _) => Lattice::Positions {
+            size,
+            registration: args.registration,
+        },
+        (None, None, Some((size, origin))) => Lattice::Edges { size, origin },
+        (None, None, None) => bail!(
+            "{} wasn't grouped with --group, so --resolution or --align-to is needed",
+            args.input.display()
+        ),
+    };
+    let grid = Grid {
+        crs: args.crs.or(table.crs),
+        units: table.units,
+        description: table.description,
+        ..rasterize(&table.rows, lattice, args.bbox, args.agg)?
+    };
+    std::fs::write(&args.output, grid.to_tif()?)
+        .with_context(|| format!("Could not write {}", args.output.display()))?;
+    println!(
+        "Wrote {} rows as a {}x{} raster to {}",
+        table.rows.len(),
+        grid.width,
+        grid.height,
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Lays the rows on `lattice`'s pixels within `bbox`, or those the rows fall in,
+/// combining the values of rows in the same pixel with `agg`. Pixels no row falls in are
+/// missing.
+fn rasterize(
+    rows: &[(f64, f64, f64)],
+    lattice: Lattice,
+    bbox: Option<BBox>,
+    agg: Aggregation,
+) -> Result<Grid> {
+    let (Lattice::Edges { size, .. } | Lattice::Positions { size, .. }) = lattice;
+    if !(size.lon > 0.0 && size.lat > 0.0) {
+        bail!("Pixels must have a positive size, not {}", size);
+    }
+    let placed = rows.iter().filter(|r| r.0.is_finite() && r.1.is_finite());
+    let extent = placed.fold(None, |extent: Option<BBox>, &(x, y, _)| {
+        let BBox {
+            west,
+            south,
+            east,
+            north,
+        } = extent.unwrap_or(BBox {
+            west: x,
+            south: y,
+            east: x,
+            north: y,
+        });
+        Some(BBox {
+            west: west.min(x),
+            south: south.min(y),
+            east: east.max(x),
+            north: north.max(y),
+        })
+    });
+    let Some(extent) = bbox.or(extent) else {
+        bail!("The table has no rows to rasterize");
+    };
+
+    // The raster's west and north edges, and its width and height in pixels.
+    let (west, north, width, height) = match lattice {
+        Lattice::Edges { origin, .. } => {
+            let index = |v: f64, origin: f64, size: f64| ((v - origin) / size + EPSILON).floor();
+            let end = |v: f64, origin: f64, size: f64| match bbox {
+                Some(_) => ((v - origin) / size - EPSILON).ceil(),
+                None => index(v, origin, size) + 1.0,
+            };
+            let (first_column, first_row) = (
+                index(extent.west, origin.lon, size.lon),
+                index(extent.south, origin.lat, size.lat),
+            );
+            let (end_column, end_row) = (
+                end(extent.east, origin.lon, size.lon),
+                end(extent.north, origin.lat, size.lat),
+            );
+            (
+                origin.lon + first_column * size.lon,
+                origin.lat + end_row * size.lat,
+                end_column - first_column,
+                end_row - first_row,
+            )
+        }
+        Lattice::Positions { registration, .. } => {
+            let offset = registration.offset();
+            let (west, north) = match bbox {
+                Some(_) => (extent.west, extent.north),
+                None => (
+                    extent.west - offset * size.lon,
+                    extent.north + offset * size.lat,
+                ),
+            };
+            let across = |start: f64, end: f64, size: f64| match bbox {
+                Some(_) => ((end - start) / size - EPSILON).ceil(),
+                None => ((end - start) / size).round() + 1.0,
+            };
+            (
+                west,
+                north,
+                across(extent.west, extent.east, size.lon),
+                across(extent.south, extent.north, size.lat),
+            )
+        }
+    };
+    let pixels = width.max(0.0) * height.max(0.0);
+    if pixels > MAX_PIXELS as f64 {
+        bail!(
+            "The raster would be {}x{} pixels; pass a larger --resolution or a smaller --bbox",
+            width,
+            height
+        );
+    }
+    let (width, height) = (width.max(0.0) as u64, height.max(0.0) as u64);
+    // The index of the pixel a position is in, counting from the north west.
+    let pixel = |x: f64, y: f64| -> Option<usize> {
+        let (column, row) = match lattice {
+            Lattice::Edges { .. } => (
+                ((x - west) / size.lon + EPSILON).floor(),
+                ((north - y) / size.lat - EPSILON).ceil() - 1.0,
+            ),
+            Lattice::Positions { registration, .. } => (
+                ((x - west) / size.lon - registration.offset()).round(),
+                ((north - y) / size.lat - registration.offset()).round(),
+            ),
+        };
+        let inside = (0.0..width as f64).contains(&column) && (0.0..height as f64).contains(&row);
+        inside.then_some(row as usize * width as usize + column as usize)
+    };
+
+    let mut sums = vec![0.0; (width * height) as usize];
+    let mut counts = vec![0u32; sums.len()];
+    let mut extremes = vec![f64::NAN; sums.len()];
+    for &(x, y, value) in rows {
+        let Some(i) = pixel(x, y).filter(|_| !value.is_nan()) else {
+            continue;
+        };
+        sums[i] += value;
+        counts[i] += 1;
+        extremes[i] = match agg {
+            Aggregation::Min => extremes[i].min(value),
+            _ => extremes[i].max(value),
+        };
+    }
+    let values = (0..sums.len())
+        .map(|i| match (counts[i], agg) {
+            (0, _) => f64::NAN,
+            (count, Aggregation::Count) => count as f64,
+            (_, Aggregation::Sum) => sums[i],
+            (count, Aggregation::Mean) => sums[i] / count as f64,
+            (_, Aggregation::Min | Aggregation::Max) => extremes[i],
+        })
+        .collect();
+    Ok(Grid {
+        width,
+        height,
+        values,
+        extent: Some([west, north, size.lon, size.lat]),
+        ..Grid::default()
+    })
+}
+
+/// The names of the position columns of a table with `columns`.
+fn position_columns<'a>(
+    columns: impl Iterator<Item = &'a str> + Clone,
+) -> Result<[&'static str; 2]> {
+    for names in [["lon", "lat"], ["easting", "northing"]] {
+        if names.iter().all(|name| columns.clone().any(|c| c == *name)) {
+            return Ok(names);
+        }
+    }
+    bail!("The table has neither lon and lat, nor easting and northing, columns")
+}
+
+fn read_parquet(path: &Path, column: &str) -> Result<Table> {
+    let batch = output::read_parquet(path)?;
+    let schema = batch.schema();
+    let [x, y] = position_columns(schema.fields().iter().map(|f| f.name().as_str()))?;
+    let numbers = |batch: &RecordBatch, name: &str| -> Result<Vec<f64>> {
+        let Some(array) = batch.column_by_name(name) else {
+            bail!("The table has no {} column", name);
+        };
+        let array = arrow_cast::cast(array, &DataType::Float64)?;
+        let array = as_primitive_array::<Float64Type>(&array);
+        Ok((0..array.len())
+            .map(|i| match array.is_null(i) {
+                true => f64::NAN,
+                false => array.value(i),
+            })
+            .collect())
+    };
+    let (xs, ys, values) = (
+        numbers(&batch, x)?,
+        numbers(&batch, y)?,
+        numbers(&batch, column)?,
+    );
+    let item = |name: &str, key: &str| -> Option<String> {
+        let field = schema.field_with_name(name).ok()?;
+        field.metadata().get(key).cloned()
+    };
+    Ok(Table {
+        rows: (0..xs.len()).map(|i| (xs[i], ys[i], values[i])).collect(),
+        crs: match x {
+            "lon" => Some(Crs::wgs84()),
+            _ => item(x, "crs").and_then(|crs| crs.parse().ok()),
+        },
+        grid: align::recorded_grid(&schema).ok(),
+        units: item(column, "unit"),
+        description: item(column, "description"),
+    })
+}
+
+/// Reads a CSV table with a header of column names, where an empty value is missing.
+fn read_csv(path: &Path, column: &str) -> Result<Table> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let mut lines = text.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header
+            .split(',')
+            .map(|name| name.trim().trim_matches('"'))
+            .collect(),
+        None => bail!("{} is empty", path.display()),
+    };
+    let [x, y] = position_columns(header.iter().copied())?;
+    let index = |name: &str| {
+        header
+            .iter()
+            .position(|c| *c == name)
+            .with_context(|| format!("The table has no {} column", name))
+    };
+    let indices = [index(x)?, index(y)?, index(column)?];
+    let mut rows = vec![];
+    for (i, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [x, y, value] =
+            indices.map(
+                |index| match fields.get(index).map(|field| field.trim_matches('"')) {
+                    None | Some("") => Some(f64::NAN),
+                    Some(field) => field.parse().ok(),
+                },
+            );
+        let (Some(x), Some(y), Some(value)) = (x, y, value) else {
+            bail!(
+                "Line {} of {} has a value that isn't a number",
+                i + 2,
+                path.display()
+            );
+        };
+        rows.push((x, y, value));
+    }
+    Ok(Table {
+        rows,
+        crs: (x == "lon").then_some(Crs::wgs84()),
+        grid: None,
+        units: None,
+        description: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rasterize, Lattice};
+    use crate::group::{Aggregation, Align, LonLat};
+
+    #[test]
+    fn test_rasterize() {
+        // Pixel centers of a 3x2 raster with 0.5 wide pixels, missing its middle top pixel.
+        let rows = [
+            (10.25, 50.75, 1.0),
+            (11.25, 50.75, 3.0),
+            (10.25, 50.25, 4.0),
+            (10.75, 50.25, 5.0),
+            (11.25, 50.25, 6.0),
+            (11.25, 50.25, 8.0),
+        ];
+        let lattice = Lattice::Positions {
+            size: 0.5.into(),
+            registration: Align::Center,
+        };
+        let grid = rasterize(&rows, lattice, None, Aggregation::Mean).unwrap();
+        assert_eq!((grid.width, grid.height), (3, 2));
+        assert_eq!(grid.extent, Some([10.0, 51.0, 0.5, 0.5]));
+        assert_eq!(grid.values[0], 1.0);
+        assert!(grid.values[1].is_nan());
+        assert_eq!(grid.values[2..], [3.0, 4.0, 5.0, 7.0]);
+
+        // The lower left corners of grouped cells, on a grid from the origin.
+        let lattice = Lattice::Edges {
+            size: 0.5.into(),
+            origin: LonLat { lon: 0.0, lat: 0.0 },
+        };
+        let corners: Vec<_> = rows.iter().map(|r| (r.0 - 0.25, r.1 - 0.25, r.2)).collect();
+        let grid = rasterize(&corners, lattice, None, Aggregation::Max).unwrap();
+        assert_eq!(grid.extent, Some([10.0, 51.0, 0.5, 0.5]));
+        assert_eq!(grid.values[3..], [4.0, 5.0, 8.0]);
+
+        let bbox = "9,50,12,51".parse().unwrap();
+        let grid = rasterize(&rows, lattice, Some(bbox), Aggregation::Count).unwrap();
+        assert_eq!((grid.width, grid.height), (6, 2));
+        assert_eq!(grid.values[6 + 4], 2.0);
+    }
+}
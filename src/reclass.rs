@@ -0,0 +1,147 @@
+//! Mapping value ranges to integer class codes with `--reclass`, such as land cover types
+//! or risk levels, written to a `class` column. Grouped outputs are aggregated separately
+//! for each class, as they are for `--stratify-by` strata.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{fmt, path::Path, str::FromStr};
+
+/// Value ranges and the class code of each, as `(min, max, code)`, holding values from
+/// `min` up to but not including `max`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Classes(Vec<(f64, f64, i32)>);
+
+/// Classes are told apart by a byte, as strata are.
+const MAX_CLASSES: usize = 256;
+
+impl FromStr for Classes {
+    type Err = anyhow::Error;
+
+    /// Parses classes such as `0-10:1,10-50:2,50+:3`, or reads them from a CSV file of
+    /// `min,max,class` lines, where an empty `min` or `max` leaves the range open.
+    fn from_str(s: &str) -> Result<Self> {
+        let classes = match s.ends_with(".csv") {
+            true => read_csv(Path::new(s))?,
+            false => s.split(',').map(parse_class).collect::<Result<_>>()?,
+        };
+        if classes.is_empty() || classes.len() > MAX_CLASSES {
+            bail!("Expected from 1 to {} classes", MAX_CLASSES);
+        }
+        if let Some((min, max, _)) = classes.iter().find(|(min, max, _)| min >= max) {
+            bail!("The class from {} to {} holds no values", min, max);
+        }
+        Ok(Classes(classes))
+    }
+}
+
+/// Parses `MIN-MAX:CLASS`, or `MIN+:CLASS` for a range with no maximum.
+fn parse_class(text: &str) -> Result<(f64, f64, i32)> {
+    let expected = || {
+        anyhow!(
+            "Expected a class like `10-50:2` or `50+:3` but got {}",
+            text
+        )
+    };
+    let (range, code) = text.trim().split_once(':').ok_or_else(expected)?;
+    let number = |text: &str| text.trim().parse::<f64>().map_err(|_| expected());
+    let (min, max) = match range.strip_suffix('+') {
+        Some(min) => (number(min)?, f64::INFINITY),
+        // The separating `-` is the one with a number on each side, unlike a minus sign.
+        None => (1..range.len())
+            .filter(|&at| range.as_bytes()[at] == b'-')
+            .find_map(|at| Some((number(&range[..at]).ok()?, number(&range[at + 1..]).ok()?)))
+            .ok_or_else(expected)?,
+    };
+    Ok((min, max, code.trim().parse().map_err(|_| expected())?))
+}
+
+/// Reads `min,max,class` lines, skipping a header.
+fn read_csv(path: &Path) -> Result<Vec<(f64, f64, i32)>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let mut classes = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let bound = |field: &str, open: f64| match field {
+            "" => Ok(open),
+            field => field.parse::<f64>(),
+        };
+        match fields[..] {
+            [""] => continue,
+            [min, max, code] => match (
+                bound(min, f64::NEG_INFINITY),
+                bound(max, f64::INFINITY),
+                code.parse(),
+            ) {
+                (Ok(min), Ok(max), Ok(code)) => classes.push((min, max, code)),
+                _ if i == 0 => continue,
+                _ => bail!("Line {} of {} isn't `min,max,class`", i + 1, path.display()),
+            },
+            _ => bail!("Line {} of {} isn't `min,max,class`", i + 1, path.display()),
+        }
+    }
+    Ok(classes)
+}
+
+impl fmt::Display for Classes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let classes: Vec<String> = self
+            .0
+            .iter()
+            .map(|(min, max, code)| match max.is_infinite() {
+                true => format!("{}+:{}", min, code),
+                false => format!("{}-{}:{}", min, max, code),
+            })
+            .collect();
+        write!(f, "{}", classes.join(","))
+    }
+}
+
+impl Classes {
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Which class, counting from 0, holds `value`, with the first given winning where
+    /// ranges overlap.
+    pub fn index(&self, value: f64) -> Option<u8> {
+        let index = self
+            .0
+            .iter()
+            .position(|(min, max, _)| (*min..*max).contains(&value));
+        index.map(|index| index as u8)
+    }
+
+    /// The code of the class at `index`.
+    pub fn code(&self, index: u8) -> i32 {
+        self.0[index as usize].2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Classes;
+
+    #[test]
+    fn test_classes() {
+        let classes: Classes = "0-10:1, 10-50:2, 50+:3".parse().unwrap();
+        assert_eq!(classes.to_string(), "0-10:1,10-50:2,50+:3");
+        let code = |value| classes.index(value).map(|index| classes.code(index));
+        assert_eq!(code(0.0), Some(1));
+        assert_eq!(code(10.0), Some(2));
+        assert_eq!(code(1e9), Some(3));
+        assert_eq!(code(-1.0), None);
+        let negative: Classes = "-10--5:7,1e-5-1:8".parse().unwrap();
+        assert_eq!(negative.index(-6.0), Some(0));
+        assert_eq!(negative.index(0.5), Some(1));
+        assert!("10-0:1".parse::<Classes>().is_err());
+        assert!("low:1".parse::<Classes>().is_err());
+
+        let path = std::env::temp_dir().join(format!("reclass-test-{}.csv", std::process::id()));
+        std::fs::write(&path, "min,max,class\n,0,9\n0,1,10\n1,,11\n").unwrap();
+        let classes: Classes = path.to_str().unwrap().parse().unwrap();
+        assert_eq!(classes.index(-100.0).map(|i| classes.code(i)), Some(9));
+        assert_eq!(classes.to_string().parse::<Classes>().unwrap(), classes);
+        assert_eq!(classes.count(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
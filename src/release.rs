@@ -0,0 +1,240 @@
+//! Which release of the tool is running: `--require-version` asserts it's a compatible
+//! one, and the `self-update` command replaces the binary with one from GitHub releases.
+
+use crate::{
+    checksum,
+    json::{self, Value},
+    serve::fetch,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use semver::{Version, VersionReq};
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+};
+
+/// The running release.
+pub const CURRENT: &str = env!("CARGO_PKG_VERSION");
+
+/// Parses a version such as `0.5.1-rc1`, allowing a leading `v` as release tags have.
+pub fn parse_version(s: &str) -> Result<Version> {
+    Version::parse(s.strip_prefix('v').unwrap_or(s))
+        .map_err(|err| anyhow!("Expected a version such as 0.5.1 but got {:?}: {}", s, err))
+}
+
+/// Fails unless the running release meets `requirement`, a comma separated list of
+/// comparators such as `>=0.5, <1`.
+pub fn require(requirement: &VersionReq) -> Result<()> {
+    let current = parse_version(CURRENT)?;
+    if !requirement.matches(&current) {
+        bail!(
+            "This is version {} of {}, but --require-version asks for {}",
+            current,
+            env!("CARGO_PKG_NAME"),
+            requirement
+        );
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub struct SelfUpdateArgs {
+    /// Install this release tag, such as `v0.5.2`, instead of the latest, so a fleet can
+    /// be moved to the same version or back to an earlier one.
+    #[arg(long = "to", value_name = "TAG")]
+    to: Option<String>,
+    /// Only report whether the release differs from the running one.
+    #[arg(long = "check")]
+    check: bool,
+    /// The GitHub repository whose releases are installed.
+    #[arg(long = "repo", default_value = "mythmon/geotif-image-processing")]
+    repo: String,
+}
+
+/// The asset of `release` built for this platform: a binary, or a zip holding one, whose
+/// name has both the OS and architecture in it.
+fn platform_asset(release: &Value) -> Result<Asset<'_>> {
+    let assets: Vec<(&str, &str)> = release
+        .get("assets")
+        .and_then(|assets| assets.as_array())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|asset| {
+            let name = asset.get("name")?.as_str()?;
+            let url = asset.get("browser_download_url")?.as_str()?;
+            Some((name, url))
+        })
+        .collect();
+    let (os, arch) = (std::env::consts::OS, std::env::consts::ARCH);
+    let (name, url) = *assets
+        .iter()
+        .find(|(name, _)| {
+            let lower = name.to_lowercase();
+            lower.contains(os) && lower.contains(arch) && !lower.ends_with(".sha256")
+        })
+        .ok_or_else(|| anyhow!("The release has no build for {} on {}", os, arch))?;
+    // The digest is published as `<asset>.sha256`, or in place of the asset's extension.
+    let stem = Path::new(name).file_stem().unwrap_or_default();
+    let (_, sha256_url) = *assets
+        .iter()
+        .find(|(sha256, _)| {
+            *sha256 == format!("{}.sha256", name)
+                || *sha256 == format!("{}.sha256", stem.to_string_lossy())
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "The release publishes no .sha256 of {} to check it with",
+                name
+            )
+        })?;
+    Ok(Asset {
+        name,
+        url,
+        sha256_url,
+    })
+}
+
+/// A release's build for this platform, and where the SHA-256 digest of it is published.
+struct Asset<'a> {
+    name: &'a str,
+    url: &'a str,
+    sha256_url: &'a str,
+}
+
+/// The executable in a downloaded asset.
+fn executable(name: &str, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if !name.ends_with(".zip") {
+        return Ok(bytes);
+    }
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let index = (0..archive.len())
+        .find(|&i| {
+            archive.by_index(i).is_ok_and(|file| {
+                let file_name = file.enclosed_name().and_then(|path| path.file_name());
+                file_name.is_some_and(|file_name| {
+                    file_name
+                        .to_string_lossy()
+                        .starts_with(env!("CARGO_PKG_NAME"))
+                })
+            })
+        })
+        .ok_or_else(|| anyhow!("{} holds no {} executable", name, env!("CARGO_PKG_NAME")))?;
+    let mut executable = vec![];
+    archive.by_index(index)?.read_to_end(&mut executable)?;
+    Ok(executable)
+}
+
+pub fn run(args: &SelfUpdateArgs) -> Result<()> {
+    let url = match &args.to {
+        Some(tag) => format!(
+            "https://api.github.com/repos/{}/releases/tags/{}",
+            args.repo, tag
+        ),
+        None => format!("https://api.github.com/repos/{}/releases/latest", args.repo),
+    };
+    let release = json::parse(&String::from_utf8_lossy(&fetch(&url)?))
+        .with_context(|| format!("Could not read the release from {}", url))?;
+    let tag = release
+        .get("tag_name")
+        .and_then(|tag| tag.as_str())
+        .ok_or_else(|| anyhow!("The release from {} has no tag", url))?;
+    let version = parse_version(tag)?;
+    let current = parse_version(CURRENT)?;
+    if version == current {
+        println!("Already on {}", current);
+        return Ok(());
+    }
+    if args.check {
+        println!("{} is available, this is {}", version, current);
+        return Ok(());
+    }
+
+    let asset = platform_asset(&release)?;
+    let bytes = fetch(asset.url)?;
+    // Nothing is replaced unless the download is what the release published.
+    let published = String::from_utf8_lossy(&fetch(asset.sha256_url)?).to_string();
+    checksum::verify_published(&bytes, &published, asset.name)
+        .with_context(|| format!("Not installing {}", asset.url))?;
+    let executable = executable(asset.name, bytes)?;
+    // Written beside the running binary and renamed over it, so it is replaced whole and
+    // a failed download leaves it alone.
+    let path = std::env::current_exe()?;
+    let partial = path.with_extension("update");
+    std::fs::write(&partial, executable)
+        .with_context(|| format!("Could not write {}", partial.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&partial, std::fs::Permissions::from_mode(0o755))?;
+    }
+    if let Err(err) = replace(&partial, &path) {
+        let _ = std::fs::remove_file(&partial);
+        return Err(err.context(format!("Could not replace {}", path.display())));
+    }
+    println!("Updated {} from {} to {}", path.display(), current, version);
+    Ok(())
+}
+
+/// Renames `partial` over the executable at `path`. Windows won't replace a running
+/// executable, but lets it be moved aside, so there it is moved to `<path>.old` first and
+/// moved back if the new one can't take its place.
+fn replace(partial: &Path, path: &Path) -> Result<()> {
+    if cfg!(windows) {
+        let old = path.with_extension("old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(path, &old)?;
+        if let Err(err) = std::fs::rename(partial, path) {
+            std::fs::rename(&old, path)?;
+            return Err(err.into());
+        }
+        return Ok(());
+    }
+    std::fs::rename(partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_version, platform_asset};
+    use crate::{checksum, json};
+    use semver::VersionReq;
+
+    #[test]
+    fn test_requirements() {
+        let matches = |requirement: &str, version: &str| {
+            let requirement: VersionReq = requirement.parse().unwrap();
+            requirement.matches(&parse_version(version).unwrap())
+        };
+        assert!(matches(">=0.5", "0.5.0"));
+        assert!(!matches(">=0.5", "0.4.9"));
+        assert!(matches(">=0.5, <1", "0.9.3"));
+        assert!(!matches(">=0.5, <1", "1.0.0"));
+        assert!(matches("=0.5", "0.5.7"));
+        assert!(matches("0.5.2", "0.5.9"));
+        assert!(!matches("^0.5.2", "0.6.0"));
+        assert!(matches("^1.2", "1.9.0"));
+        assert!(!matches("~1.2", "1.3.0"));
+        assert!(".5".parse::<VersionReq>().is_err());
+        // Pre-releases come before the release, and only match requirements naming one.
+        let rc = parse_version("v0.5.1-rc1").unwrap();
+        assert_eq!(rc.to_string(), "0.5.1-rc1");
+        assert!(rc < parse_version("0.5.1").unwrap());
+        assert!(!matches(">=0.5", "0.5.1-rc1"));
+        assert!(matches(">=0.5.1-rc1", "0.5.1-rc1"));
+
+        let release = json::parse(&format!(
+            r#"{{"assets": [
+                {{"name": "image-stats-{arch}-{os}.sha256", "browser_download_url": "a"}},
+                {{"name": "image-stats-{arch}-{os}.zip", "browser_download_url": "b"}}
+            ]}}"#,
+            os = std::env::consts::OS,
+            arch = std::env::consts::ARCH
+        ))
+        .unwrap();
+        let asset = platform_asset(&release).unwrap();
+        assert_eq!((asset.url, asset.sha256_url), ("b", "a"));
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  a.zip";
+        assert!(checksum::verify_published(b"abc", abc, "a.zip").is_ok());
+        assert!(checksum::verify_published(b"abd", abc, "a.zip").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Drawing a raster's values to a PNG for a quick look, without a GIS.
+
+use crate::{
+    crs::Crs,
+    georef::GeoTransform,
+    group::{self, Aggregation, Align, Binning, Grid, LonLat},
+    load_tif_contents, raster,
+    raster::{ChunkSize, Layout},
+    sidecar, style, DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use image::{Rgba, RgbaImage};
+use std::{io::Cursor, path::PathBuf, str::FromStr};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct RenderArgs {
+    /// The raster to draw.
+    raster: PathBuf,
+    /// Where to write the PNG. Defaults to the raster's path with a `.png` extension.
+    #[arg(long = "output", short = 'o')]
+    output: Option<PathBuf>,
+    /// `viridis`, `magma`, or breakpoints as `value:#rrggbb,value:#rrggbb,...` with colors
+    /// blended between them.
+    #[arg(long = "colormap", default_value = "viridis")]
+    colormap: Colormap,
+    /// Values mapped to the ends of the colormap as `min,max`; values outside get the end
+    /// colors. Defaults to the smallest and largest value drawn. Breakpoints set their own
+    /// range.
+    #[arg(long = "range")]
+    range: Option<Range>,
+    /// Draw a grid of cells this many degrees across instead of the raster's pixels.
+    #[arg(long = "group")]
+    group: Option<f64>,
+    /// How the pixels in each `--group` cell are combined.
+    #[arg(long = "agg", value_enum, default_value_t = Aggregation::Sum, requires = "group")]
+    agg: Aggregation,
+    /// CRS of the raster, read with its GeoTIFF tags. Without it the raster is taken to be
+    /// a global grid.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Colormap {
+    Viridis,
+    Magma,
+    /// Colors at values, from the smallest value to the largest.
+    Breakpoints(Vec<(f64, (u8, u8, u8))>),
+}
+
+/// Magma, sampled evenly.
+const MAGMA: [(u8, u8, u8); 7] = [
+    (0, 0, 4),
+    (44, 17, 95),
+    (114, 31, 129),
+    (183, 55, 121),
+    (241, 96, 93),
+    (254, 176, 120),
+    (252, 253, 191),
+];
+
+impl FromStr for Colormap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viridis" => return Ok(Colormap::Viridis),
+            "magma" => return Ok(Colormap::Magma),
+            _ if !s.contains(':') => {
+                bail!(
+                    "Expected `viridis`, `magma` or `value:#rrggbb` breakpoints but got {}",
+                    s
+                )
+            }
+            _ => {}
+        }
+        let mut stops = s
+            .split(',')
+            .map(|stop| {
+                let (value, color) = stop
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Expected `value:#rrggbb` but got {}", stop))?;
+                Ok((value.trim().parse::<f64>()?, parse_color(color.trim())?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if stops.len() < 2 {
+            bail!("Expected `viridis`, `magma`, or at least two `value:#rrggbb` breakpoints");
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Colormap::Breakpoints(stops))
+    }
+}
+
+fn parse_color(text: &str) -> Result<(u8, u8, u8)> {
+    let hex = text
+        .strip_prefix('#')
+        .filter(|hex| hex.len() == 6)
+        .ok_or_else(|| anyhow!("Expected a `#rrggbb` color but got {}", text))?;
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16)
+            .with_context(|| format!("Expected a `#rrggbb` color but got {}", text))
+    };
+    Ok((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Range {
+    min: f64,
+    max: f64,
+}
+
+impl FromStr for Range {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (min, max) = s
+            .split_once(',')
+            .ok_or_else(|| anyhow!("Expected `min,max` but got {}", s))?;
+        Ok(Range {
+            min: min.trim().parse()?,
+            max: max.trim().parse()?,
+        })
+    }
+}
+
+impl Colormap {
+    /// The values the ramps are stretched over: `given`, or else the smallest and largest
+    /// of `values`. Breakpoints carry their own values, so ignore it.
+    pub fn range(&self, given: Option<Range>, values: impl Iterator<Item = f64>) -> Range {
+        match (self, given) {
+            (Colormap::Breakpoints(_), _) => Range { min: 0.0, max: 0.0 },
+            (_, Some(range)) => range,
+            (_, None) => values.fold(
+                Range {
+                    min: f64::INFINITY,
+                    max: f64::NEG_INFINITY,
+                },
+                |range, value| Range {
+                    min: range.min.min(value),
+                    max: range.max.max(value),
+                },
+            ),
+        }
+    }
+
+    /// The color of `value`, which for the ramps is placed within `range`.
+    pub fn color(&self, value: f64, range: Range) -> (u8, u8, u8) {
+        let ramp = |ramp: &[(u8, u8, u8)]| {
+            let t = if range.max > range.min {
+                ((value - range.min) / (range.max - range.min)).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let position = t * (ramp.len() - 1) as f64;
+            let low = position.floor() as usize;
+            let high = (low + 1).min(ramp.len() - 1);
+            mix(ramp[low], ramp[high], position - low as f64)
+        };
+        match self {
+            Colormap::Viridis => ramp(&style::RAMP),
+            Colormap::Magma => ramp(&MAGMA),
+            Colormap::Breakpoints(stops) => {
+                let above = stops.partition_point(|(v, _)| *v <= value);
+                match above {
+                    0 => stops[0].1,
+                    n if n == stops.len() => stops[n - 1].1,
+                    n => {
+                        let (low, high) = (stops[n - 1], stops[n]);
+                        mix(low.1, high.1, (value - low.0) / (high.0 - low.0))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn mix(a: (u8, u8, u8), b: (u8, u8, u8), t: f64) -> (u8, u8, u8) {
+    let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    (channel(a.0, b.0), channel(a.1, b.1), channel(a.2, b.2))
+}
+
+pub fn run(args: &RenderArgs) -> Result<()> {
+    let contents = load_tif_contents(&args.raster)?;
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let src_crs = sidecar::src_crs(&args.raster, args.src_crs, &mut decoder)?;
+    let transform = GeoTransform::resolve(&mut decoder, src_crs, None)?;
+    let (width, height) = decoder.dimensions()?;
+    let pixels = raster::read_pixels(
+        &contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |_, _, _, _| true,
+        |_| {},
+        |x, y, value| (value > 0.0 && value.is_finite()).then_some((x, y, value)),
+    )?;
+
+    // Where each value goes in the image, counting rows down from the top.
+    let (width, height, cells) = match args.group {
+        None => (width, height, pixels),
+        Some(size) => {
+            let rows: Vec<_> = pixels
+                .iter()
+                .map(|&(x, y, value)| {
+                    let (lon, lat) = transform.position(x as f64, y as f64);
+                    (lon, lat, value)
+                })
+                .collect();
+            let binning = Binning::Grid(Grid {
+                size: size.into(),
+                origin: LonLat { lon: 0.0, lat: 0.0 },
+                align: Align::Corner,
+                wraps: false,
+            });
+            let binned = group::bin(&rows, &binning, args.agg, |x, y| {
+                transform.pixel_weight(x, y)
+            });
+            let bounds = transform.rect_bounds(0, 0, width, height);
+            let west = (bounds.west / size).floor() as i64;
+            let north = (bounds.north / size).ceil() as i64;
+            let columns = (bounds.east / size).ceil() as i64 - west;
+            let rows = north - (bounds.south / size).floor() as i64;
+            let cells = binned
+                .rows
+                .into_iter()
+                .map(|(lon, lat, value)| {
+                    let column = (lon / size).round() as i64 - west;
+                    let row = north - 1 - (lat / size).round() as i64;
+                    (column as u32, row as u32, value)
+                })
+                .collect();
+            (columns as u32, rows as u32, cells)
+        }
+    };
+
+    let range = args
+        .colormap
+        .range(args.range, cells.iter().map(|&(_, _, value)| value));
+
+    let mut image = RgbaImage::new(width, height);
+    for (x, y, value) in cells {
+        let (r, g, b) = args.colormap.color(value, range);
+        image.put_pixel(x, y, Rgba([r, g, b, 255]));
+    }
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.raster.with_extension("png"));
+    image
+        .save(&output)
+        .with_context(|| format!("Could not write {}", output.to_string_lossy()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Colormap, Range};
+
+    #[test]
+    fn test_colormap() {
+        let range = Range {
+            min: 0.0,
+            max: 10.0,
+        };
+        assert_eq!(Colormap::Viridis.color(-5.0, range), (68, 1, 84));
+        assert_eq!(Colormap::Magma.color(10.0, range), (252, 253, 191));
+        let breaks: Colormap = "10:#ffffff, 0:#000000".parse().unwrap();
+        assert_eq!(breaks.color(5.0, range), (128, 128, 128));
+        assert_eq!(breaks.color(20.0, range), (255, 255, 255));
+        assert!("0:#fff,1:#000000".parse::<Colormap>().is_err());
+        assert!("0:#000000".parse::<Colormap>().is_err());
+    }
+}
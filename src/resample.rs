@@ -0,0 +1,76 @@
+//! Downsampling the raster into blocks of pixels before anything else is done with it.
+
+use std::collections::HashMap;
+
+/// How the pixels of a block become the block's value.
+///
+/// Only pixels with values take part, so `average` is over those rather than the whole
+/// block, and blocks without any are dropped.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Method {
+    /// The pixel at the block's center.
+    Nearest,
+    Average,
+    Sum,
+    Max,
+}
+
+/// Combines `(x, y, value)` pixels into `factor` by `factor` blocks, returning each
+/// block's `(x, y, value)` in the coordinates of the downsampled raster.
+pub fn resample(pixels: &[(u32, u32, f64)], factor: u32, method: Method) -> Vec<(u32, u32, f64)> {
+    let mut blocks = HashMap::<(u32, u32), (f64, u32)>::new();
+    for &(x, y, value) in pixels {
+        let block = (x / factor, y / factor);
+        match method {
+            Method::Nearest => {
+                if (x % factor, y % factor) == (factor / 2, factor / 2) {
+                    blocks.insert(block, (value, 1));
+                }
+            }
+            Method::Average | Method::Sum => {
+                let entry = blocks.entry(block).or_insert((0.0, 0));
+                entry.0 += value;
+                entry.1 += 1;
+            }
+            Method::Max => {
+                let entry = blocks.entry(block).or_insert((value, 1));
+                entry.0 = entry.0.max(value);
+            }
+        }
+    }
+    blocks
+        .into_iter()
+        .map(|((x, y), (total, count))| match method {
+            Method::Average => (x, y, total / count as f64),
+            _ => (x, y, total),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resample, Method};
+
+    #[test]
+    fn test_resample() {
+        let pixels = [
+            (0, 0, 2.0),
+            (1, 0, 4.0),
+            (1, 1, 9.0),
+            (2, 0, 5.0),
+            (4, 5, 1.0),
+        ];
+        let run = |method| {
+            let mut blocks = resample(&pixels, 2, method);
+            blocks.sort_by_key(|&(x, y, _)| (y, x));
+            blocks
+        };
+        assert_eq!(
+            run(Method::Average),
+            [(0, 0, 5.0), (1, 0, 5.0), (2, 2, 1.0)]
+        );
+        assert_eq!(run(Method::Sum), [(0, 0, 15.0), (1, 0, 5.0), (2, 2, 1.0)]);
+        assert_eq!(run(Method::Max), [(0, 0, 9.0), (1, 0, 5.0), (2, 2, 1.0)]);
+        assert_eq!(run(Method::Nearest), [(0, 0, 9.0)]);
+    }
+}
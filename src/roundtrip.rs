@@ -0,0 +1,154 @@
+//! Checking that converting a raster to a table loses nothing, by turning each row back
+//! into the pixel it came from.
+
+use crate::{
+    crs::Crs,
+    explain,
+    georef::GeoTransform,
+    group::Align,
+    json::Value,
+    load_tif_contents,
+    metadata::SourceMetadata,
+    raster::{self, ChunkSize, Layout},
+    sidecar, DEFAULT_CHUNK_ROWS,
+};
+use anyhow::Result;
+use std::{io::Cursor, path::PathBuf};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct RoundtripArgs {
+    /// The raster to check.
+    raster: PathBuf,
+    /// CRS of the tif, as for a conversion.
+    #[arg(long = "src-crs")]
+    src_crs: Option<Crs>,
+    /// CRS positions are written in, as for a conversion.
+    #[arg(long = "dst-crs")]
+    dst_crs: Option<Crs>,
+    /// Where in each pixel its position is, as for a conversion.
+    #[arg(long = "registration", value_enum, default_value_t = Align::Center)]
+    registration: Align,
+    /// Print the report as JSON instead of text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+/// Running totals of how far rows land from the pixels they came from.
+#[derive(Default)]
+struct Errors {
+    pixels: u64,
+    /// Rows whose position maps back to a different pixel.
+    misplaced: u64,
+    /// Largest distance, in pixels, between a row's position mapped back and its pixel.
+    max_position: f64,
+    /// Total and largest difference between a stored value and the one recovered from
+    /// its row, in stored units.
+    total_value: f64,
+    max_value: f64,
+}
+
+impl Errors {
+    fn merge(mut self, other: Errors) -> Errors {
+        self.pixels += other.pixels;
+        self.misplaced += other.misplaced;
+        self.max_position = self.max_position.max(other.max_position);
+        self.total_value += other.total_value;
+        self.max_value = self.max_value.max(other.max_value);
+        self
+    }
+}
+
+pub fn run(args: &RoundtripArgs) -> Result<()> {
+    let contents = load_tif_contents(&args.raster)?;
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let source = SourceMetadata::read(&mut decoder)?;
+    let src_crs = sidecar::src_crs(&args.raster, args.src_crs, &mut decoder)?;
+    let transform = GeoTransform::resolve(&mut decoder, src_crs, args.dst_crs)?;
+    let (scale, offset) = match source.scale_offset() {
+        (None, None) => (1.0, 0.0),
+        (scale, offset) => (scale.unwrap_or(1.0), offset.unwrap_or(0.0)),
+    };
+
+    let errors = raster::fold_pixels(
+        &contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |_, _, _, _| true,
+        |_| {},
+        Errors::default,
+        |errors, x, y, value| {
+            if !(value > 0.0 && value.is_finite()) {
+                return;
+            }
+            // The row as it is written: Float32 position and value.
+            let at = args.registration.offset();
+            let (px, py) = transform.position(x as f64 + at, y as f64 + at);
+            let row = (px as f32, py as f32, (value * scale + offset) as f32);
+
+            let (back_x, back_y) = transform.pixel_at(row.0 as f64, row.1 as f64);
+            let (back_x, back_y) = (back_x - at, back_y - at);
+            let distance = (back_x - x as f64).hypot(back_y - y as f64);
+            let back_value = (row.2 as f64 - offset) / scale;
+            let value_error = (back_value - value).abs();
+
+            errors.pixels += 1;
+            if (back_x.round(), back_y.round()) != (x as f64, y as f64) {
+                errors.misplaced += 1;
+            }
+            errors.max_position = errors.max_position.max(distance);
+            errors.total_value += value_error;
+            errors.max_value = errors.max_value.max(value_error);
+        },
+        Errors::merge,
+    )?;
+
+    let report = report(&errors);
+    if args.json {
+        println!("{}", report.pretty());
+    } else {
+        print!("{}", explain::to_text(&report, 0));
+    }
+    Ok(())
+}
+
+fn report(errors: &Errors) -> Value {
+    let mean_value = match errors.pixels {
+        0 => 0.0,
+        n => errors.total_value / n as f64,
+    };
+    Value::object([
+        ("pixels", Value::from(errors.pixels)),
+        ("misplaced", errors.misplaced.into()),
+        ("max_position_error_pixels", errors.max_position.into()),
+        ("max_value_error", errors.max_value.into()),
+        ("mean_value_error", mean_value.into()),
+        (
+            "lossless",
+            (errors.misplaced == 0 && errors.max_value < 0.5).into(),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{report, Errors};
+
+    #[test]
+    fn test_report_lossless() {
+        let lossless = |errors: Errors| report(&errors).get("lossless").cloned();
+        let exact = Errors {
+            pixels: 4,
+            max_position: 0.01,
+            ..Default::default()
+        };
+        assert_eq!(lossless(exact), Some(true.into()));
+        let misplaced = Errors {
+            pixels: 4,
+            misplaced: 1,
+            ..Default::default()
+        };
+        assert_eq!(lossless(misplaced), Some(false.into()));
+    }
+}
@@ -0,0 +1,174 @@
+//! Just enough of the S2 cell hierarchy to bin points into cells and find their centers.
+
+pub const MAX_LEVEL: u8 = 30;
+
+const SWAP_MASK: usize = 1;
+const INVERT_MASK: usize = 2;
+const IJ_TO_POS: [[u64; 4]; 4] = [[0, 1, 3, 2], [0, 3, 1, 2], [2, 3, 1, 0], [2, 1, 3, 0]];
+const POS_TO_IJ: [[usize; 4]; 4] = [[0, 1, 3, 2], [0, 2, 3, 1], [3, 2, 0, 1], [3, 1, 0, 2]];
+const POS_TO_ORIENTATION: [usize; 4] = [SWAP_MASK, 0, 0, SWAP_MASK | INVERT_MASK];
+
+/// Returns the id of the cell at `level` containing the point.
+pub fn cell_id(lon: f64, lat: f64, level: u8) -> u64 {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    let point = [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()];
+    let (face, u, v) = xyz_to_face_uv(point);
+    let i = st_to_ij(uv_to_st(u));
+    let j = st_to_ij(uv_to_st(v));
+
+    let mut orientation = face as usize & SWAP_MASK;
+    let mut pos = 0;
+    for bit in (0..MAX_LEVEL).rev() {
+        let ij = ((i >> bit & 1) << 1 | (j >> bit & 1)) as usize;
+        let child = IJ_TO_POS[orientation][ij];
+        pos = pos << 2 | child;
+        orientation ^= POS_TO_ORIENTATION[child as usize];
+    }
+    let leaf = (face as u64) << 61 | pos << 1 | 1;
+    parent(leaf, level)
+}
+
+/// Returns the center of a cell as `(lon, lat)`.
+pub fn cell_center(id: u64) -> (f64, f64) {
+    let (face, i, j, size) = cell_ij(id);
+    face_st_to_lon_lat(face, (i as f64 + 0.5) * size, (j as f64 + 0.5) * size)
+}
+
+/// Returns the four corners of a cell as `(lon, lat)`, counter-clockwise.
+pub fn cell_vertices(id: u64) -> [(f64, f64); 4] {
+    let (face, i, j, size) = cell_ij(id);
+    let corner =
+        |di: u64, dj: u64| face_st_to_lon_lat(face, (i + di) as f64 * size, (j + dj) as f64 * size);
+    [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)]
+}
+
+/// Splits a cell id into its face, its `i`/`j` position among the cells of its level,
+/// and the size of a cell at that level in face `st` units.
+fn cell_ij(id: u64) -> (usize, u64, u64, f64) {
+    let face = (id >> 61) as usize;
+    let level = MAX_LEVEL - id.trailing_zeros() as u8 / 2;
+
+    let mut orientation = face & SWAP_MASK;
+    let (mut i, mut j) = (0u64, 0u64);
+    for l in 0..level {
+        let child = (id >> (59 - 2 * l as u32) & 3) as usize;
+        let ij = POS_TO_IJ[orientation][child];
+        i = i << 1 | (ij >> 1) as u64;
+        j = j << 1 | (ij & 1) as u64;
+        orientation ^= POS_TO_ORIENTATION[child];
+    }
+    (face, i, j, 1.0 / (1u64 << level) as f64)
+}
+
+fn face_st_to_lon_lat(face: usize, s: f64, t: f64) -> (f64, f64) {
+    let [x, y, z] = face_uv_to_xyz(face, st_to_uv(s), st_to_uv(t));
+    (
+        y.atan2(x).to_degrees(),
+        z.atan2((x * x + y * y).sqrt()).to_degrees(),
+    )
+}
+
+fn parent(id: u64, level: u8) -> u64 {
+    let lsb = 1u64 << (2 * (MAX_LEVEL - level) as u32);
+    (id & lsb.wrapping_neg()) | lsb
+}
+
+fn xyz_to_face_uv([x, y, z]: [f64; 3]) -> (u8, f64, f64) {
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+    let axis = if ax > ay {
+        if ax > az {
+            0
+        } else {
+            2
+        }
+    } else if ay > az {
+        1
+    } else {
+        2
+    };
+    let face = if [x, y, z][axis] < 0.0 {
+        axis + 3
+    } else {
+        axis
+    };
+    let (u, v) = match face {
+        0 => (y / x, z / x),
+        1 => (-x / y, z / y),
+        2 => (-x / z, -y / z),
+        3 => (z / x, y / x),
+        4 => (z / y, -x / y),
+        _ => (-y / z, -x / z),
+    };
+    (face as u8, u, v)
+}
+
+fn face_uv_to_xyz(face: usize, u: f64, v: f64) -> [f64; 3] {
+    match face {
+        0 => [1.0, u, v],
+        1 => [-u, 1.0, v],
+        2 => [-u, -v, 1.0],
+        3 => [-1.0, -v, -u],
+        4 => [v, -1.0, -u],
+        _ => [v, u, -1.0],
+    }
+}
+
+/// The quadratic projection S2 uses to make cells closer to equal area.
+fn uv_to_st(u: f64) -> f64 {
+    if u >= 0.0 {
+        0.5 * (1.0 + 3.0 * u).sqrt()
+    } else {
+        1.0 - 0.5 * (1.0 - 3.0 * u).sqrt()
+    }
+}
+
+fn st_to_uv(s: f64) -> f64 {
+    if s >= 0.5 {
+        (4.0 * s * s - 1.0) / 3.0
+    } else {
+        (1.0 - 4.0 * (1.0 - s) * (1.0 - s)) / 3.0
+    }
+}
+
+fn st_to_ij(s: f64) -> u64 {
+    let max = (1u64 << MAX_LEVEL) as f64;
+    (s * max).floor().clamp(0.0, max - 1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cell_center, cell_id, cell_vertices};
+
+    #[test]
+    fn test_cell_id() {
+        assert_eq!(cell_id(0.0, 0.0, 30), 0x1000000000000001);
+        assert_eq!(cell_id(0.0, 0.0, 0), 0x1000000000000000);
+        assert_eq!(cell_id(11.770681595, 49.703498679, 30), 0x47a1cbd595522b39);
+    }
+
+    #[test]
+    fn test_cell_center_round_trip() {
+        let id = cell_id(-122.4194, 37.7749, 12);
+        let (lon, lat) = cell_center(id);
+        assert!((lon - -122.4194).abs() < 0.05 && (lat - 37.7749).abs() < 0.05);
+        assert_eq!(cell_id(lon, lat, 12), id);
+    }
+
+    #[test]
+    fn test_cell_vertices_surround_center() {
+        let id = cell_id(10.0, 45.0, 8);
+        let vertices = cell_vertices(id);
+        let (lon, lat) = cell_center(id);
+        let min_lon = vertices.iter().map(|v| v.0).fold(f64::INFINITY, f64::min);
+        let max_lon = vertices
+            .iter()
+            .map(|v| v.0)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let min_lat = vertices.iter().map(|v| v.1).fold(f64::INFINITY, f64::min);
+        let max_lat = vertices
+            .iter()
+            .map(|v| v.1)
+            .fold(f64::NEG_INFINITY, f64::max);
+        assert!(min_lon < lon && lon < max_lon && min_lat < lat && lat < max_lat);
+    }
+}
@@ -0,0 +1,52 @@
+//! `--sample`: a random fraction of the pixels, for small representative extracts of
+//! rasters too large to prototype on. Whether a pixel is kept depends only on its
+//! position and `--seed`, so the same pixels are kept however the image is split into
+//! chunks, bands or threads.
+
+use crate::synth::splitmix64;
+
+/// Parses a fraction of pixels to keep, above 0 and at most 1.
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a fraction such as 0.01, not {:?}", s))?;
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(format!(
+            "expected a fraction above 0 and at most 1, not {:?}",
+            s
+        ));
+    }
+    Ok(fraction)
+}
+
+/// Whether the pixel at `(x, y)` is among the `fraction` of pixels kept with `seed`.
+pub fn keeps(fraction: f64, seed: u64, x: u32, y: u32) -> bool {
+    let index = (y as u64) << 32 | x as u64;
+    let bits = splitmix64(seed ^ splitmix64(index));
+    // The top 53 bits give a uniform number in [0, 1).
+    ((bits >> 11) as f64 / (1u64 << 53) as f64) < fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keeps, parse_fraction};
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(parse_fraction("0.25"), Ok(0.25));
+        assert!(parse_fraction("0").is_err() && parse_fraction("1.5").is_err());
+
+        let kept = |seed| {
+            (0..100)
+                .flat_map(|y| (0..100).map(move |x| (x, y)))
+                .filter(|&(x, y)| keeps(0.1, seed, x, y))
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = (kept(1), kept(2));
+        assert!((800..1200).contains(&a.len()));
+        assert_eq!(a, kept(1));
+        assert_ne!(a, b);
+        assert!(keeps(1.0, 7, 12, 34));
+    }
+}
@@ -0,0 +1,547 @@
+//! Converting each input in a restricted child process, for inputs that can't be trusted.
+//!
+//! The child is this binary again, run with the same arguments and told which input to
+//! convert through [`CHILD_INPUT`]. Before it reads the input it caps its own memory and
+//! CPU time, confines its reads to the input's directory and the files its options name
+//! and its writes to the output's directory with landlock, and installs a seccomp filter
+//! refusing network access, asynchronous I/O rings, running programs, and tracing or
+//! signalling other processes. A kernel whose landlock can't confine all of that fails the
+//! conversion, unless `--sandbox-best-effort` lets it go on.
+//! The parent kills it if it runs past the time limit, so an input that crashes, hangs or
+//! exhausts the child only fails that input.
+
+use crate::{
+    archive,
+    failure::{Classified, FailureClass},
+    json::{self, Value},
+    notify::Outcome,
+    processor::{Options, ProcessorBuilder},
+    stdin,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressBar;
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+/// Set in the child's environment to the input it converts.
+pub const CHILD_INPUT: &str = "GEOTIF_SANDBOX_INPUT";
+
+/// How much of the machine a sandboxed conversion may use.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Bytes of heap and other private data.
+    pub memory: u64,
+    /// Seconds of CPU time, summed across threads.
+    pub cpu_seconds: Option<u64>,
+    /// Seconds from start to finish.
+    pub timeout: Duration,
+    /// Whether to go on, with a warning, where the kernel can confine only some of the
+    /// conversion's file access, or none of it.
+    pub best_effort: bool,
+}
+
+/// The input to convert when this process is a sandboxed child.
+pub fn child_input() -> Option<PathBuf> {
+    std::env::var_os(CHILD_INPUT).map(PathBuf::from)
+}
+
+/// Fails before any input is read if processes can't be sandboxed here.
+pub fn check_supported() -> Result<()> {
+    sys::check_supported()
+}
+
+/// Converts `input_path` in a child process held to `limits`, showing progress on `bar`.
+pub fn process(input_path: &Path, limits: &Limits, bar: &ProgressBar) -> Result<Outcome> {
+    bar.set_message("converting in sandbox");
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(CHILD_INPUT, input_path)
+        .stdin(match stdin::is_stdin(input_path) {
+            true => Stdio::piped(),
+            false => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Could not start the sandboxed conversion")?;
+    // The child can't read the stdin this process already has, so pass on what was piped.
+    if let Some(mut pipe) = child.stdin.take() {
+        let contents = stdin::contents()?;
+        std::thread::spawn(move || pipe.write_all(contents));
+    }
+    // Read as the child runs, so it never blocks on a full pipe.
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut text = String::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_string(&mut text);
+            }
+            text
+        })
+    };
+    let stdout = drain(
+        child
+            .stdout
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    );
+    let stderr = drain(
+        child
+            .stderr
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    );
+
+    let deadline = Instant::now() + limits.timeout;
+    let (status, timed_out) = loop {
+        if let Some(status) = child.try_wait()? {
+            break (status, false);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            break (child.wait()?, true);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if status.success() {
+        eprint!("{}", stderr);
+        bar.finish_with_message("done");
+        return parse_outcome(&stdout);
+    }
+    Err(failure(status, timed_out, &stderr, limits))
+}
+
+/// The error for a child that exited with `status`: its own, or which limit stopped it.
+fn failure(status: ExitStatus, timed_out: bool, stderr: &str, limits: &Limits) -> anyhow::Error {
+    let exceeded = |message: String| anyhow!(Classified::new(FailureClass::Resource, message));
+    if timed_out {
+        return exceeded(format!(
+            "The sandboxed conversion was stopped after --sandbox-timeout {} seconds",
+            limits.timeout.as_secs_f64()
+        ));
+    }
+    // Failing allocations abort the child with Rust's own message.
+    if stderr.contains("memory allocation of") {
+        return exceeded(format!(
+            "The sandboxed conversion ran out of its --sandbox-memory of {} bytes",
+            limits.memory
+        ));
+    }
+    match (status.code(), signal(status)) {
+        (_, Some(signal)) if is_cpu_limit(signal) => exceeded(format!(
+            "The sandboxed conversion used its --sandbox-cpu of {} seconds",
+            limits.cpu_seconds.unwrap_or_default()
+        )),
+        (_, Some(signal)) => anyhow!(Classified::new(
+            FailureClass::Unsupported,
+            format!("The sandboxed conversion was killed by signal {}", signal)
+        )),
+        (Some(code), None) => {
+            // The child prints its error as `main` does, after any warnings.
+            let (warnings, message) = match stderr.rfind("Error: ") {
+                Some(at) => (&stderr[..at], stderr[at + "Error: ".len()..].trim()),
+                None => (stderr, "The sandboxed conversion failed"),
+            };
+            eprint!("{}", warnings);
+            let class = [
+                FailureClass::BadInput,
+                FailureClass::Unsupported,
+                FailureClass::Io,
+                FailureClass::Resource,
+            ]
+            .into_iter()
+            .find(|class| class.exit_code() == code)
+            .unwrap_or(FailureClass::Other);
+            anyhow!(Classified::new(class, message))
+        }
+        (None, None) => anyhow!("The sandboxed conversion ended without an exit code"),
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+#[cfg(unix)]
+fn is_cpu_limit(signal: i32) -> bool {
+    signal == libc::SIGXCPU
+}
+
+#[cfg(not(unix))]
+fn is_cpu_limit(_signal: i32) -> bool {
+    false
+}
+
+/// The line a child prints for the parent on success.
+fn outcome_line(outcome: &Outcome) -> Result<String> {
+    let value = match outcome {
+        Outcome::Written { output, rows } => Value::object([
+            ("status", Value::from("written")),
+            ("output", output.to_string_lossy().to_string().into()),
+            ("rows", (*rows as u64).into()),
+        ]),
+        Outcome::UpToDate { output } => Value::object([
+            ("status", Value::from("up_to_date")),
+            ("output", output.to_string_lossy().to_string().into()),
+        ]),
+        Outcome::Failed(_) | Outcome::Skipped | Outcome::Taken => {
+            bail!("A sandboxed conversion only writes or skips its input")
+        }
+    };
+    Ok(value.to_string())
+}
+
+fn parse_outcome(stdout: &str) -> Result<Outcome> {
+    let line = stdout.lines().last().unwrap_or_default();
+    let value = json::parse(line)
+        .with_context(|| format!("The sandboxed conversion reported {:?}", line))?;
+    let output = value
+        .get("output")
+        .and_then(|output| output.as_str())
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("The sandboxed conversion reported no output"))?;
+    match value.get("status").and_then(|status| status.as_str()) {
+        Some("written") => Ok(Outcome::Written {
+            output,
+            rows: value
+                .get("rows")
+                .and_then(|rows| rows.as_f64())
+                .unwrap_or(0.0) as usize,
+        }),
+        Some("up_to_date") => Ok(Outcome::UpToDate { output }),
+        _ => bail!("The sandboxed conversion reported {:?}", line),
+    }
+}
+
+/// Converts `input_path` as the sandboxed child: restricts this process, then converts
+/// and reports the outcome on stdout.
+pub fn convert(input_path: &Path, mut options: Options, limits: &Limits) -> Result<()> {
+    // Half the memory is left for the program itself and the allocator's slack.
+    options.max_memory = options.max_memory.or(Some(limits.memory / 2));
+    let processor = ProcessorBuilder::from_options(options).build()?;
+    let output_path = processor.options().output_path(input_path)?;
+    let writable: Vec<PathBuf> = std::iter::once(output_path.as_path())
+        .chain(processor.options().style_out.as_deref())
+        .map(|path| match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        })
+        .collect();
+    let options = processor.options();
+    // Sidecars such as `.prj` and `.aux.xml` files sit beside the input.
+    let input_dir =
+        (!stdin::is_stdin(input_path)).then(|| match archive::file(input_path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        });
+    let readable: Vec<PathBuf> = input_dir
+        .into_iter()
+        .chain(
+            [
+                &options.align_to,
+                &options.stratify_by,
+                &options.mask,
+                &options.distance_to,
+                &options.append,
+                &options.table_uri,
+                &options.contract,
+            ]
+            .into_iter()
+            .flatten()
+            .cloned(),
+        )
+        .filter(|path| path.exists())
+        .collect();
+    sys::restrict(limits, &readable, &writable)?;
+    let outcome = processor.process(input_path)?;
+    println!("{}", outcome_line(&outcome)?);
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use super::Limits;
+    use crate::failure::{Classified, FailureClass};
+    use anyhow::{anyhow, bail, Context, Result};
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule,
+    };
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    /// Syscalls the child is refused: talking to the network, running programs and
+    /// reaching into other processes. io_uring is refused too, as its operations are
+    /// submitted through shared memory rather than syscalls the filter can see.
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+        libc::SYS_tkill,
+        libc::SYS_pidfd_send_signal,
+    ];
+
+    /// Syscalls sending signals to the process whose id is their first argument, which
+    /// the child is refused for any process but itself, as it signals itself to abort.
+    const SIGNALS: &[libc::c_long] = &[
+        libc::SYS_kill,
+        libc::SYS_tgkill,
+        libc::SYS_rt_sigqueueinfo,
+        libc::SYS_rt_tgsigqueueinfo,
+    ];
+
+    /// The landlock ABI whose file access rights the child is held to, the first to
+    /// cover truncating files and device ioctls.
+    const ABI: ABI = ABI::V5;
+
+    pub fn check_supported() -> Result<()> {
+        Ok(())
+    }
+
+    /// Applies `limits` and the landlock and seccomp rules to this process. It must run
+    /// before any other thread is started, as landlock rules only pass to threads started
+    /// after them.
+    pub fn restrict(limits: &Limits, readable: &[PathBuf], writable: &[PathBuf]) -> Result<()> {
+        set_limit(libc::RLIMIT_DATA, limits.memory, limits.memory)?;
+        if let Some(seconds) = limits.cpu_seconds {
+            // SIGXCPU at the soft limit, and SIGKILL a second later if that is ignored.
+            set_limit(libc::RLIMIT_CPU, seconds, seconds + 1)?;
+        }
+        confine_files(readable, writable, limits.best_effort)?;
+        let program = syscall_filter(std::process::id())?;
+        seccompiler::apply_filter_all_threads(&program).context("Could not install seccomp filter")
+    }
+
+    fn set_limit(resource: libc::__rlimit_resource_t, soft: u64, hard: u64) -> Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            bail!(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Allows reads only of the `readable` files and beneath the `readable` directories,
+    /// and reads and writes only beneath the `writable` directories.
+    ///
+    /// Kernels with an older landlock enforce the rights they know, and those without it
+    /// none. Either fails the conversion unless `best_effort` lets it go on with a warning.
+    fn confine_files(readable: &[PathBuf], writable: &[PathBuf], best_effort: bool) -> Result<()> {
+        let read = AccessFs::ReadFile | AccessFs::ReadDir;
+        let write = AccessFs::WriteFile
+            | AccessFs::Truncate
+            | AccessFs::RemoveDir
+            | AccessFs::RemoveFile
+            | AccessFs::MakeDir
+            | AccessFs::MakeReg;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(ABI))?
+            .create()?
+            .add_rules(path_beneath_rules(readable, read))?
+            .add_rules(path_beneath_rules(writable, read | write))?
+            .restrict_self()
+            .context("Could not confine the sandbox's reads and writes")?;
+        let gap = match status.ruleset {
+            RulesetStatus::FullyEnforced => return Ok(()),
+            RulesetStatus::PartiallyEnforced => "only confines some of the sandbox's file access",
+            RulesetStatus::NotEnforced => "has no landlock to confine the sandbox's file access",
+        };
+        if !best_effort {
+            bail!(Classified::new(
+                FailureClass::Unsupported,
+                format!(
+                    "This kernel {}; pass --sandbox-best-effort to convert anyway",
+                    gap
+                )
+            ));
+        }
+        eprintln!("Warning: this kernel {}", gap);
+        Ok(())
+    }
+
+    /// A seccomp filter failing the [`DENIED`] syscalls with EPERM, and the [`SIGNALS`]
+    /// ones unless aimed at `pid`. Syscalls made through another architecture's numbers
+    /// kill the process.
+    fn syscall_filter(pid: u32) -> Result<BpfProgram> {
+        let other_process = || -> Result<Vec<SeccompRule>> {
+            let condition =
+                SeccompCondition::new(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Ne, pid.into())?;
+            Ok(vec![SeccompRule::new(vec![condition])?])
+        };
+        let mut rules = BTreeMap::new();
+        for &syscall in DENIED {
+            for number in numbers(syscall) {
+                rules.insert(number, vec![]);
+            }
+        }
+        for &syscall in SIGNALS {
+            for number in numbers(syscall) {
+                rules.insert(number, other_process()?);
+            }
+        }
+        let arch = std::env::consts::ARCH
+            .try_into()
+            .map_err(|err| anyhow!("{}", err))?;
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            arch,
+        )?;
+        Ok(filter.try_into()?)
+    }
+
+    /// The numbers `syscall` can be made through. On x86_64 those include its x32 ones,
+    /// which share the architecture but set a bit in their numbers, and for syscalls
+    /// whose arguments differ in layout are numbered from 512.
+    #[cfg(target_arch = "x86_64")]
+    fn numbers(syscall: libc::c_long) -> Vec<i64> {
+        const X32_SYSCALL_BIT: i64 = 0x4000_0000;
+        let x32 = match syscall {
+            libc::SYS_execve => Some(520),
+            libc::SYS_ptrace => Some(521),
+            libc::SYS_rt_sigqueueinfo => Some(524),
+            libc::SYS_rt_tgsigqueueinfo => Some(536),
+            libc::SYS_process_vm_readv => Some(539),
+            libc::SYS_process_vm_writev => Some(540),
+            libc::SYS_execveat => Some(545),
+            _ => None,
+        };
+        [Some(syscall), Some(syscall | X32_SYSCALL_BIT), x32]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn numbers(syscall: libc::c_long) -> Vec<i64> {
+        vec![syscall]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::syscall_filter;
+
+        #[test]
+        fn test_syscall_filter() {
+            // Building it is as far as a test can go without restricting the test runner.
+            assert!(!syscall_filter(std::process::id()).unwrap().is_empty());
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod sys {
+    use super::Limits;
+    use anyhow::{bail, Result};
+    use std::path::PathBuf;
+
+    pub fn check_supported() -> Result<()> {
+        bail!("--sandbox is only supported on Linux on x86_64 and aarch64")
+    }
+
+    pub fn restrict(_limits: &Limits, _readable: &[PathBuf], _writable: &[PathBuf]) -> Result<()> {
+        check_supported()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{failure, outcome_line, parse_outcome, Limits};
+    use crate::{failure::FailureClass, notify::Outcome};
+    use std::{
+        os::unix::process::ExitStatusExt,
+        path::{Path, PathBuf},
+        process::ExitStatus,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_child_results() {
+        let written = Outcome::Written {
+            output: PathBuf::from("out/a.parquet"),
+            rows: 12,
+        };
+        let line = outcome_line(&written).unwrap();
+        assert!(matches!(
+            parse_outcome(&format!("{}\n", line)).unwrap(),
+            Outcome::Written { rows: 12, output } if output == Path::new("out/a.parquet")
+        ));
+        assert!(parse_outcome("").is_err());
+
+        let limits = Limits {
+            memory: 1 << 30,
+            cpu_seconds: Some(60),
+            timeout: Duration::from_secs(10),
+            best_effort: false,
+        };
+        // A child's own error keeps its class and message.
+        let exited = ExitStatus::from_raw(3 << 8);
+        let error = failure(exited, false, "Warning: x\nError: Truncated tif\n", &limits);
+        assert_eq!(FailureClass::of(&error), FailureClass::BadInput);
+        assert_eq!(error.to_string(), "Truncated tif");
+        // Limits that stop the child say which.
+        let aborted = ExitStatus::from_raw(libc::SIGABRT);
+        let error = failure(
+            aborted,
+            false,
+            "memory allocation of 8 bytes failed",
+            &limits,
+        );
+        assert!(error.to_string().contains("--sandbox-memory"));
+        assert_eq!(FailureClass::of(&error), FailureClass::Resource);
+        assert_eq!(FailureClass::of(&error).exit_code(), 6);
+        let killed = ExitStatus::from_raw(libc::SIGKILL);
+        assert!(failure(killed, true, "", &limits)
+            .to_string()
+            .contains("--sandbox-timeout"));
+        let cpu = ExitStatus::from_raw(libc::SIGXCPU);
+        let error = failure(cpu, false, "", &limits);
+        assert!(error.to_string().contains("--sandbox-cpu"));
+        assert_eq!(FailureClass::of(&error), FailureClass::Resource);
+        // A child that failed on its own memory budget keeps that class.
+        let exited = ExitStatus::from_raw(6 << 8);
+        let error = failure(exited, false, "Error: Over --max-memory\n", &limits);
+        assert_eq!(FailureClass::of(&error), FailureClass::Resource);
+    }
+}
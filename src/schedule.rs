@@ -0,0 +1,162 @@
+//! Spreading a batch's inputs over concurrent jobs so they finish together, using costs
+//! estimated from each tif's header.
+
+use crate::{archive, inspect::compression_name, load_tif_contents};
+use anyhow::Result;
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{BufReader, Cursor},
+    path::{Path, PathBuf},
+};
+use tiff::{
+    decoder::{Decoder, Limits},
+    tags::Tag,
+};
+
+/// The expected cost of converting one input.
+pub struct Estimate {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub compression: Option<u16>,
+    /// Megapixels, scaled up by how much slower the compression is to decode than raw
+    /// pixels.
+    pub cost: f64,
+    /// Why the header couldn't be read. The input then costs nothing here and fails when
+    /// its turn comes.
+    pub error: Option<String>,
+}
+
+/// Reads the input's header and estimates its cost. Plain tifs are only read as far as
+/// their tags; zipped ones have to be extracted.
+pub fn estimate(path: &Path) -> Estimate {
+    let header = match path.extension().and_then(|e| e.to_str()) {
+        Some("tif") if archive::split(path).is_none() => File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| read_header(BufReader::new(file))),
+        _ => load_tif_contents(path).and_then(|contents| read_header(Cursor::new(contents))),
+    };
+    match header {
+        Ok((width, height, compression)) => Estimate {
+            path: path.to_path_buf(),
+            width,
+            height,
+            compression,
+            cost: width as f64 * height as f64 / 1e6 * decode_factor(compression),
+            error: None,
+        },
+        Err(err) => Estimate {
+            path: path.to_path_buf(),
+            width: 0,
+            height: 0,
+            compression: None,
+            cost: 0.0,
+            error: Some(format!("{:#}", err)),
+        },
+    }
+}
+
+fn read_header<R: std::io::Read + std::io::Seek>(reader: R) -> Result<(u32, u32, Option<u16>)> {
+    let mut decoder = Decoder::new(reader)?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let compression = decoder.find_tag_unsigned::<u16>(Tag::Compression)?;
+    Ok((width, height, compression))
+}
+
+/// A rough guess at how long a compression takes to decode relative to raw pixels.
+fn decode_factor(compression: Option<u16>) -> f64 {
+    match compression {
+        None | Some(1) => 1.0,
+        Some(32773) => 1.2,
+        Some(50000) => 1.4,
+        Some(5) => 1.6,
+        Some(8) | Some(32946) => 2.0,
+        Some(_) => 2.5,
+    }
+}
+
+/// Splits the inputs between `jobs` jobs. With one job they keep their order; otherwise
+/// the most expensive inputs are handed out first, each to the job with the least work
+/// so far.
+pub fn assign(mut estimates: Vec<Estimate>, jobs: usize) -> Vec<Vec<Estimate>> {
+    let mut slots: Vec<Vec<Estimate>> = (0..jobs.max(1)).map(|_| vec![]).collect();
+    if slots.len() == 1 {
+        slots[0] = estimates;
+        return slots;
+    }
+    estimates.sort_by(|a, b| b.cost.total_cmp(&a.cost));
+    for estimate in estimates {
+        let slot = slots
+            .iter_mut()
+            .min_by(|a, b| total_cost(a).total_cmp(&total_cost(b)))
+            .expect("there is at least one job");
+        slot.push(estimate);
+    }
+    slots
+}
+
+pub fn total_cost(slot: &[Estimate]) -> f64 {
+    slot.iter().map(|e| e.cost).sum()
+}
+
+/// Lays the schedule out for `--dry-run`, one line per job and one per input below it,
+/// each readable one followed by what `output` says it would write.
+pub fn describe(slots: &[Vec<Estimate>], output: impl Fn(&Path) -> String) -> String {
+    let mut out = String::new();
+    for (i, slot) in slots.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "job {}: {} inputs, cost {:.3}",
+            i + 1,
+            slot.len(),
+            total_cost(slot)
+        );
+        for e in slot {
+            let _ = match &e.error {
+                Some(error) => writeln!(out, "  {}: unreadable, {}", e.path.display(), error),
+                None => writeln!(
+                    out,
+                    "  {}: {}x{}, {} compression, cost {:.3}\n    {}",
+                    e.path.display(),
+                    e.width,
+                    e.height,
+                    e.compression.map_or("no".to_string(), compression_name),
+                    e.cost,
+                    output(&e.path)
+                ),
+            };
+        }
+    }
+    out.push_str("costs are in megapixels, weighted by how slow each compression is to decode\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assign, total_cost, Estimate};
+
+    fn estimate(name: &str, cost: f64) -> Estimate {
+        Estimate {
+            path: name.into(),
+            width: 0,
+            height: 0,
+            compression: None,
+            cost,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_assign_balances() {
+        let estimates = [8.0, 7.0, 6.0, 5.0, 4.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &cost)| estimate(&i.to_string(), cost))
+            .collect();
+        let slots = assign(estimates, 2);
+        let costs: Vec<f64> = slots.iter().map(|s| total_cost(s)).collect();
+        assert_eq!(costs, [17.0, 13.0]);
+        assert_eq!(slots[0][0].path.to_string_lossy(), "0");
+    }
+}
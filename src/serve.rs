@@ -0,0 +1,609 @@
+//! A small HTTP API for running conversions as a service.
+//!
+//! `POST /convert` takes a tif or zip as the request body, or with `--allow-url`, `url=` to
+//! fetch one, with the conversion's options as query parameters named after the command
+//! line's long flags, such as `/convert?group=0.5&keep-zero=true`. Only the options in
+//! [`QUERY_OPTIONS`], which shape the output but name no files or hosts, can be set, and
+//! `reclass` only with its classes inline. The response is the converted file. `GET /health` reports that the service is up and how
+//! busy it is.
+//!
+//! Each connection carries one request. Conversions past `--max-concurrent` are refused
+//! with 503 before their body is read rather than queued, so callers can back off or go
+//! to another instance. Clients that stall for [`IO_TIMEOUT`] are disconnected.
+
+use crate::{
+    json::Value,
+    notify::Outcome,
+    output::OutputFormat,
+    processor::{Options, ProcessorBuilder},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(clap::Args)]
+pub struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long = "listen", default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// Most conversions run at once; further requests get 503 until one finishes.
+    #[arg(long = "max-concurrent", default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrent: u64,
+    /// Largest request body accepted, in megabytes.
+    #[arg(long = "max-body-mb", default_value_t = 1024)]
+    max_body_mb: u64,
+    /// Let requests give a `url=` to download their input from, which makes the server
+    /// fetch any address its callers name, including those on its own network.
+    #[arg(long = "allow-url")]
+    allow_url: bool,
+}
+
+/// The conversion options requests can set, which shape the output but don't name files,
+/// tables or hosts on the server. Any other is refused with 400.
+pub const QUERY_OPTIONS: &[&str] = &[
+    "group",
+    "grid-origin",
+    "align",
+    "s2",
+    "h3",
+    "tile-zoom",
+    "quadkey",
+    "agg",
+    "categorical",
+    "class-counts",
+    "reclass",
+    "src-crs",
+    "dst-crs",
+    "lon-range",
+    "registration",
+    "bbox",
+    "band",
+    "variable",
+    "sample-format",
+    "unit",
+    "description",
+    "metadata-filter",
+    "scale",
+    "offset",
+    "no-scale",
+    "min-value",
+    "max-value",
+    "keep-zero",
+    "keep-nan",
+    "packed-color",
+    "dense",
+    "with-indices",
+    "per-area-to-total",
+    "time",
+    "expr",
+    "transform",
+    "sample",
+    "seed",
+    "stride",
+    "resample",
+    "multires",
+    "resample-method",
+    "geohash",
+    "format",
+    "layer",
+    "min-zoom",
+    "max-zoom",
+    "max-tile-features",
+    "thin",
+    "sort",
+    "where",
+    "columns",
+    "value-name",
+    "geometry",
+    "compression",
+    "compression-level",
+];
+
+/// How long a client may leave a read or write of its connection waiting.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest request line or header accepted, in bytes.
+const MAX_LINE: usize = 8 * 1024;
+
+/// Most headers accepted on a request.
+const MAX_HEADERS: usize = 100;
+
+/// Most connections held open at once, each on its own thread. Those past it are closed
+/// straight away.
+const MAX_CONNECTIONS: usize = 256;
+
+/// Limits shared by every connection.
+struct Server {
+    max_concurrent: usize,
+    max_body: u64,
+    allow_url: bool,
+    active: AtomicUsize,
+    connections: AtomicUsize,
+    /// Numbers each conversion's scratch directory.
+    requests: AtomicUsize,
+}
+
+/// A parsed request: method, path, query parameters and body.
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// A response ready to be written.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            headers: vec![],
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Response {
+        Response::json(
+            status,
+            Value::object([("error", Value::from(message.into()))]),
+        )
+    }
+}
+
+pub fn run(args: &ServeArgs) -> Result<()> {
+    let listener = TcpListener::bind(&args.listen)
+        .with_context(|| format!("Could not listen on {}", args.listen))?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    serve(
+        listener,
+        args.max_concurrent as usize,
+        args.max_body_mb * 1024 * 1024,
+        args.allow_url,
+    )
+}
+
+/// Answers connections on `listener` until the process is stopped, each on its own thread.
+fn serve(
+    listener: TcpListener,
+    max_concurrent: usize,
+    max_body: u64,
+    allow_url: bool,
+) -> Result<()> {
+    let server = Arc::new(Server {
+        max_concurrent,
+        max_body,
+        allow_url,
+        active: AtomicUsize::new(0),
+        connections: AtomicUsize::new(0),
+        requests: AtomicUsize::new(0),
+    });
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if server.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+        let server = server.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = server.answer(stream) {
+                eprintln!("{:#}", err);
+            }
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+impl Server {
+    fn answer(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let response = match read_head(&mut reader) {
+            Ok((_, length)) if length > self.max_body => Response::error(
+                413,
+                format!(
+                    "The body of {} bytes is over the limit of {}",
+                    length, self.max_body
+                ),
+            ),
+            Ok((request, length)) => self.route(request, length, &mut reader),
+            Err(err) => Response::error(400, format!("{:#}", err)),
+        };
+        write_response(&stream, response)
+    }
+
+    /// Answers `request`, reading its body of `length` bytes from `reader` only once a
+    /// conversion has been let through.
+    fn route(&self, mut request: Request, length: u64, reader: &mut impl Read) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => Response::json(
+                200,
+                Value::object([
+                    ("status", Value::from("ok")),
+                    ("active", (self.active.load(Ordering::SeqCst) as u64).into()),
+                    ("max_concurrent", (self.max_concurrent as u64).into()),
+                ]),
+            ),
+            ("POST", "/convert") => {
+                let claimed = self
+                    .active
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        (n < self.max_concurrent).then_some(n + 1)
+                    });
+                if claimed.is_err() {
+                    let mut response = Response::error(503, "Too many conversions running");
+                    response.headers.push(("Retry-After", "5".into()));
+                    return response;
+                }
+                // Read as it arrives rather than into a buffer of the claimed length, so a
+                // request can't hold memory it doesn't send.
+                let response = match reader.by_ref().take(length).read_to_end(&mut request.body) {
+                    Ok(read) if read as u64 == length => self.convert(request),
+                    Ok(read) => Response::error(
+                        400,
+                        format!("The body ended after {} of {} bytes", read, length),
+                    ),
+                    Err(err) => Response::error(400, format!("Could not read the body: {}", err)),
+                };
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                response
+            }
+            (_, "/health" | "/convert") => Response::error(405, "Method not allowed"),
+            _ => Response::error(404, "Not found"),
+        }
+    }
+
+    /// Converts the request's tif in a scratch directory that is removed afterwards.
+    fn convert(&self, request: Request) -> Response {
+        let id = self.requests.fetch_add(1, Ordering::SeqCst);
+        let dir =
+            std::env::temp_dir().join(format!("image-stats-serve-{}-{}", std::process::id(), id));
+        let response = match fs::create_dir_all(&dir) {
+            Ok(()) => convert_in(&dir, request, self.allow_url),
+            Err(err) => {
+                Response::error(500, format!("Could not create {}: {}", dir.display(), err))
+            }
+        };
+        let _ = fs::remove_dir_all(&dir);
+        response
+    }
+}
+
+fn convert_in(dir: &Path, request: Request, allow_url: bool) -> Response {
+    let mut url = None;
+    let mut entries: Vec<(String, Value)> = vec![];
+    for (key, value) in request.query {
+        if key == "url" {
+            if !allow_url {
+                return Response::error(
+                    400,
+                    "Inputs can't be fetched by url unless the server runs with --allow-url",
+                );
+            }
+            url = Some(value);
+            continue;
+        }
+        if !QUERY_OPTIONS.contains(&key.as_str()) {
+            return Response::error(400, format!("The option {} can't be set by requests", key));
+        }
+        // A value ending in `.csv` is read as a file of classes on the server.
+        if key == "reclass" && value.ends_with(".csv") {
+            return Response::error(400, "Requests can only give reclass classes inline");
+        }
+        let value = match value.as_str() {
+            "" | "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(value),
+        };
+        // Repeated parameters become a list, as for a repeated flag.
+        match entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, Value::Array(items))) => items.push(value),
+            Some((_, existing)) => *existing = Value::Array(vec![existing.clone(), value]),
+            None => entries.push((key, value)),
+        }
+    }
+    let options = match Options::from_json(Value::Object(entries)) {
+        Ok(options) => options,
+        Err(err) => return Response::error(400, format!("{:#}", err)),
+    };
+    let content_type = match options.format {
+        OutputFormat::Parquet => "application/vnd.apache.parquet",
+        OutputFormat::Fgb => "application/flatgeobuf",
+        OutputFormat::Gpkg => "application/geopackage+sqlite3",
+        OutputFormat::Mbtiles => "application/vnd.sqlite3",
+        OutputFormat::ArrowStream => "application/vnd.apache.arrow.stream",
+        OutputFormat::Shp | OutputFormat::Mvt | OutputFormat::Delta => {
+            return Response::error(
+                400,
+                "Only single file formats can be returned, not shp, mvt or delta",
+            )
+        }
+    };
+
+    let bytes = match url {
+        Some(url) => match fetch(&url) {
+            Ok(bytes) => bytes,
+            Err(err) => return Response::error(502, format!("{:#}", err)),
+        },
+        None => request.body,
+    };
+    let extension = if bytes.starts_with(b"PK") {
+        "zip"
+    } else {
+        "tif"
+    };
+    let input_path = dir.join(format!("input.{}", extension));
+    let output_path = dir.join(format!("output.{}", options.format.extension()));
+    let result = fs::write(&input_path, &bytes)
+        .map_err(Into::into)
+        .and_then(|()| ProcessorBuilder::from_options(options).build())
+        .and_then(|processor| processor.process_to(&input_path, &output_path))
+        .and_then(|outcome| Ok((outcome, fs::read(&output_path)?)));
+    match result {
+        Ok((outcome, body)) => {
+            let mut headers = vec![];
+            if let Outcome::Written { rows, .. } = outcome {
+                headers.push(("X-Rows", rows.to_string()));
+            }
+            Response {
+                status: 200,
+                content_type,
+                headers,
+                body,
+            }
+        }
+        Err(err) => Response::error(422, format!("{:#}", err)),
+    }
+}
+
+/// Downloads an http or https `url` with `curl`, which must be on the `PATH`.
+pub(crate) fn fetch(url: &str) -> Result<Vec<u8>> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        bail!("Expected an http or https URL but got {}", url);
+    }
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location", url])
+        .output()
+        .context("Could not run curl")?;
+    if !output.status.success() {
+        bail!(
+            "Could not fetch {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Reads the request line and headers, returning the request without its body and the
+/// body's length. Lines past [`MAX_LINE`] bytes and headers past [`MAX_HEADERS`] are
+/// refused.
+fn read_head(reader: &mut impl BufRead) -> Result<(Request, u64)> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("Malformed request line {:?}", line.trim());
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut content_length = 0u64;
+    for headers in 0.. {
+        if read_line(reader, &mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            bail!("Over {} headers", MAX_HEADERS);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().context("Bad Content-Length")?;
+            }
+        }
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect::<Result<_>>()?;
+    let request = Request {
+        method,
+        path: path.to_string(),
+        query,
+        body: vec![],
+    };
+    Ok((request, content_length))
+}
+
+/// Reads a line of at most [`MAX_LINE`] bytes into `line`, returning its length.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    line.clear();
+    let read = reader.by_ref().take(MAX_LINE as u64 + 1).read_line(line)?;
+    if read > MAX_LINE {
+        bail!("A line of the request is over {} bytes", MAX_LINE);
+    }
+    Ok(read)
+}
+
+/// Decodes `%XX` escapes and `+` for spaces in a query string component.
+fn percent_decode(text: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = text.bytes();
+    while let Some(byte) = rest.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [rest.next(), rest.next()];
+                let [Some(high), Some(low)] = hex else {
+                    bail!("Truncated escape in {}", text);
+                };
+                let hex = std::str::from_utf8(&[high, low])?.to_string();
+                bytes.push(
+                    u8::from_str_radix(&hex, 16)
+                        .map_err(|_| anyhow!("Bad escape %{} in {}", hex, text))?,
+                );
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn write_response(mut stream: &TcpStream, response: Response) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_head, serve, MAX_LINE, QUERY_OPTIONS};
+    use crate::processor::Options;
+    use clap::Args;
+    use std::{
+        io::{Cursor, Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+    use tiff::encoder::{colortype::GrayI32, TiffEncoder};
+
+    fn request(address: &str, head: &str, body: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{}Content-Length: {}\r\n\r\n", head, body.len()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || serve(listener, 1, 1 << 20, false));
+
+        let command = Options::augment_args(clap::Command::new("options"));
+        for name in QUERY_OPTIONS {
+            assert!(
+                command.get_arguments().any(|a| a.get_long() == Some(*name)),
+                "{}",
+                name
+            );
+        }
+
+        let health = request(&address, "GET /health HTTP/1.1\r\n", b"");
+        assert!(health.starts_with(b"HTTP/1.1 200 OK"));
+
+        let mut tif = Cursor::new(vec![]);
+        TiffEncoder::new(&mut tif)
+            .unwrap()
+            .write_image::<GrayI32>(2, 2, &[1, 2, 3, 4])
+            .unwrap();
+        let converted = request(
+            &address,
+            "POST /convert?keep-zero=true&metadata-filter=gdal%3A* HTTP/1.1\r\n",
+            tif.get_ref(),
+        );
+        let text = String::from_utf8_lossy(&converted);
+        assert!(text.starts_with("HTTP/1.1 200 OK"), "{}", text);
+        assert!(text.contains("X-Rows: 4\r\n"));
+        assert!(converted.ends_with(b"PAR1"));
+
+        let refused = request(&address, "POST /convert?group=x HTTP/1.1\r\n", b"");
+        assert!(refused.starts_with(b"HTTP/1.1 400"));
+        // Options naming files or hosts on the server, and fetching, aren't for callers.
+        for query in [
+            "manifest=true",
+            "mask=%2Fetc%2Fpasswd",
+            "reclass=%2Ftmp%2Fclasses.csv",
+            "url=http://10.0.0.1/a.tif",
+        ] {
+            let head = format!("POST /convert?{} HTTP/1.1\r\n", query);
+            let refused = request(&address, &head, tif.get_ref());
+            assert!(refused.starts_with(b"HTTP/1.1 400"), "{}", query);
+        }
+        let reclassed = request(
+            &address,
+            "POST /convert?reclass=0-2:1,2%2B:2 HTTP/1.1\r\n",
+            tif.get_ref(),
+        );
+        assert!(reclassed.starts_with(b"HTTP/1.1 200 OK"));
+
+        // A body shorter than its Content-Length isn't converted.
+        let mut stream = TcpStream::connect(&address).unwrap();
+        write!(
+            stream,
+            "POST /convert HTTP/1.1\r\nContent-Length: 1000\r\n\r\nII"
+        )
+        .unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_read_head() {
+        let head = b"POST /convert?group=0.5&unit=m%2Fs HTTP/1.1\r\nContent-Length: 12\r\n\r\n";
+        let (request, length) = read_head(&mut &head[..]).unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/convert")
+        );
+        assert_eq!(request.query[1], ("unit".into(), "m/s".into()));
+        assert_eq!(length, 12);
+
+        // Heads too long to be requests are refused without being read in full.
+        let head = format!("GET /health?{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert!(read_head(&mut head.as_bytes()).is_err());
+        let head = format!("GET /health HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(200));
+        assert!(read_head(&mut head.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! ESRI shapefile output: `.shp` geometries, their `.shx` index, `.dbf` attributes and a
+//! `.prj` describing the CRS.
+
+use crate::{
+    crs::Crs,
+    geometry::{self, GeometryKind, Pixel},
+    group::Binning,
+    time::iso8601,
+    timing::TimedFile,
+};
+use anyhow::{bail, Result};
+use arrow_array::{
+    Array, Float32Array, Float64Array, RecordBatch, StringArray, TimestampSecondArray, UInt32Array,
+    UInt64Array, UInt8Array,
+};
+use arrow_schema::{DataType, TimeUnit};
+use std::{
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Readers use signed 32 bit offsets, so neither the `.shp` nor the `.dbf` may grow past
+/// 2 GB. Larger outputs are split into several shapefiles.
+const SIZE_LIMIT: u64 = (1 << 31) - 1;
+
+const SHP_HEADER_BYTES: u64 = 100;
+const SHAPE_POINT: i32 = 1;
+const SHAPE_POLYGON: i32 = 5;
+
+/// A `.dbf` column: its name, type letter, width and decimal places.
+struct DbfField {
+    name: String,
+    kind: u8,
+    width: usize,
+    decimals: usize,
+}
+
+/// Writes every row of the batch as a shape, with the first two columns giving its
+/// position in `crs` and all columns written as attributes. Outputs too large for one
+/// shapefile continue in `name_2.shp`, `name_3.shp` and so on.
+pub fn write_shp(
+    path: &Path,
+    batch: &RecordBatch,
+    kind: GeometryKind,
+    binning: Option<&Binning>,
+    pixel: Pixel,
+    crs: Crs,
+) -> Result<()> {
+    let shape_type = match kind {
+        GeometryKind::Point => SHAPE_POINT,
+        GeometryKind::Cell => SHAPE_POLYGON,
+    };
+    let paths = write_parts(path, batch, SIZE_LIMIT, shape_type, |lon, lat| match kind {
+        GeometryKind::Point => vec![(lon, lat)],
+        GeometryKind::Cell => geometry::footprint(lon, lat, binning, pixel),
+    })?;
+    match crs.esri_wkt() {
+        Some(wkt) => {
+            for path in &paths {
+                std::fs::write(path.with_extension("prj"), wkt)?;
+            }
+        }
+        None => eprintln!(
+            "Warning: there is no ESRI WKT of {}, so {} has no .prj",
+            crs,
+            path.display()
+        ),
+    }
+    if paths.len() > 1 {
+        eprintln!(
+            "Warning: {} is larger than a shapefile can hold, so it was split into {} files: {}",
+            path.to_string_lossy(),
+            paths.len(),
+            paths
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// A rough guess at the combined size of the files, for the free space check.
+pub fn estimate_size(batch: &RecordBatch, kind: GeometryKind) -> u64 {
+    let record = match dbf_fields(batch) {
+        Ok(fields) => 1 + fields.iter().map(|f| f.width).sum::<usize>() as u64,
+        Err(_) => 0,
+    };
+    let shape = match kind {
+        GeometryKind::Point => 8 + 20,
+        GeometryKind::Cell => 8 + 44 + 4 + 5 * 16,
+    };
+    // Each shape also has an 8 byte entry in the `.shx`.
+    batch.num_rows() as u64 * (shape + 8 + record) + 4096
+}
+
+/// Writes the shapefiles, splitting whenever a `.shp` or `.dbf` would pass `limit`
+/// bytes, and returns the path of each `.shp`.
+fn write_parts(
+    path: &Path,
+    batch: &RecordBatch,
+    limit: u64,
+    shape_type: i32,
+    geometry: impl Fn(f64, f64) -> Vec<(f64, f64)>,
+) -> Result<Vec<PathBuf>> {
+    let column = |index: usize| -> Result<&Float32Array> {
+        match batch
+            .columns()
+            .get(index)
+            .and_then(|c| c.as_any().downcast_ref::<Float32Array>())
+        {
+            Some(array) => Ok(array),
+            None => bail!("Output has no position column {}", index),
+        }
+    };
+    let (lons, lats) = (column(0)?, column(1)?);
+    let fields = dbf_fields(batch)?;
+    let record_length = 1 + fields.iter().map(|f| f.width).sum::<usize>();
+
+    let mut paths = vec![path.to_path_buf()];
+    let mut part = Part::create(path, shape_type, &fields)?;
+    let mut record = Vec::with_capacity(record_length);
+    for row in 0..batch.num_rows() {
+        let ring = geometry(lons.value(row) as f64, lats.value(row) as f64);
+        let shape = shape_content(&ring);
+
+        record.clear();
+        record.push(b' ');
+        for (field, array) in fields.iter().zip(batch.columns()) {
+            write_dbf_value(field, array.as_ref(), row, &mut record);
+        }
+
+        if part.count > 0
+            && (part.shp_bytes + 8 + shape.len() as u64 > limit
+                || part.dbf_bytes + record.len() as u64 + 1 > limit)
+        {
+            part.finish()?;
+            let next = path.with_file_name(format!(
+                "{}_{}.shp",
+                path.file_stem().unwrap_or_default().to_string_lossy(),
+                paths.len() + 1
+            ));
+            part = Part::create(&next, shape_type, &fields)?;
+            paths.push(next);
+        }
+        part.add(&shape, &ring, &record)?;
+    }
+    part.finish()?;
+    Ok(paths)
+}
+
+/// One shapefile being written. The `.shp`/`.shx` headers and the `.dbf` record count
+/// are filled in by `finish`, once they are known.
+struct Part {
+    shp: BufWriter<TimedFile>,
+    shx: BufWriter<TimedFile>,
+    dbf: BufWriter<TimedFile>,
+    shape_type: i32,
+    bounds: [f64; 4],
+    count: u32,
+    shp_bytes: u64,
+    dbf_bytes: u64,
+}
+
+impl Part {
+    fn create(path: &Path, shape_type: i32, fields: &[DbfField]) -> Result<Part> {
+        let create = |extension| -> Result<BufWriter<TimedFile>> {
+            Ok(BufWriter::new(TimedFile::create(
+                &path.with_extension(extension),
+            )?))
+        };
+        let mut part = Part {
+            shp: create("shp")?,
+            shx: create("shx")?,
+            dbf: create("dbf")?,
+            shape_type,
+            bounds: [
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ],
+            count: 0,
+            shp_bytes: SHP_HEADER_BYTES,
+            dbf_bytes: 0,
+        };
+        part.shp.write_all(&[0; SHP_HEADER_BYTES as usize])?;
+        part.shx.write_all(&[0; SHP_HEADER_BYTES as usize])?;
+        let header = dbf_header(fields, 0);
+        part.dbf.write_all(&header)?;
+        part.dbf_bytes = header.len() as u64;
+        Ok(part)
+    }
+
+    fn add(&mut self, shape: &[u8], ring: &[(f64, f64)], record: &[u8]) -> Result<()> {
+        self.count += 1;
+        for &(x, y) in ring {
+            self.bounds = [
+                self.bounds[0].min(x),
+                self.bounds[1].min(y),
+                self.bounds[2].max(x),
+                self.bounds[3].max(y),
+            ];
+        }
+
+        // Offsets and lengths are counted in 16 bit words.
+        self.shx
+            .write_all(&((self.shp_bytes / 2) as i32).to_be_bytes())?;
+        self.shx
+            .write_all(&((shape.len() / 2) as i32).to_be_bytes())?;
+        self.shp.write_all(&(self.count as i32).to_be_bytes())?;
+        self.shp
+            .write_all(&((shape.len() / 2) as i32).to_be_bytes())?;
+        self.shp.write_all(shape)?;
+        self.shp_bytes += 8 + shape.len() as u64;
+
+        self.dbf.write_all(record)?;
+        self.dbf_bytes += record.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if self.count == 0 {
+            self.bounds = [0.0; 4];
+        }
+        let shx_bytes = SHP_HEADER_BYTES + 8 * self.count as u64;
+        for (file, length) in [(&mut self.shp, self.shp_bytes), (&mut self.shx, shx_bytes)] {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&shp_header(self.shape_type, self.bounds, length))?;
+            file.flush()?;
+        }
+
+        self.dbf.write_all(&[0x1a])?;
+        self.dbf.seek(SeekFrom::Start(4))?;
+        self.dbf.write_all(&self.count.to_le_bytes())?;
+        self.dbf.flush()?;
+        Ok(())
+    }
+}
+
+fn shp_header(shape_type: i32, bounds: [f64; 4], length: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SHP_HEADER_BYTES as usize);
+    header.extend(9994i32.to_be_bytes());
+    header.extend([0; 20]);
+    header.extend(((length / 2) as i32).to_be_bytes());
+    header.extend(1000i32.to_le_bytes());
+    header.extend(shape_type.to_le_bytes());
+    for v in bounds {
+        header.extend(v.to_le_bytes());
+    }
+    // No Z or M ranges.
+    header.extend([0; 32]);
+    header
+}
+
+/// The content of a point record, or of a single ring polygon record. Shapefiles want
+/// outer rings clockwise, the reverse of our footprints.
+fn shape_content(ring: &[(f64, f64)]) -> Vec<u8> {
+    let mut content = vec![];
+    if let [(x, y)] = ring {
+        content.extend(SHAPE_POINT.to_le_bytes());
+        content.extend(x.to_le_bytes());
+        content.extend(y.to_le_bytes());
+        return content;
+    }
+    content.extend(SHAPE_POLYGON.to_le_bytes());
+    let (xs, ys): (Vec<f64>, Vec<f64>) = ring.iter().copied().unzip();
+    for v in [
+        xs.iter().copied().fold(f64::INFINITY, f64::min),
+        ys.iter().copied().fold(f64::INFINITY, f64::min),
+        xs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        ys.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+    ] {
+        content.extend(v.to_le_bytes());
+    }
+    content.extend(1i32.to_le_bytes());
+    content.extend((ring.len() as i32).to_le_bytes());
+    content.extend(0i32.to_le_bytes());
+    for (x, y) in ring.iter().rev() {
+        content.extend(x.to_le_bytes());
+        content.extend(y.to_le_bytes());
+    }
+    content
+}
+
+fn dbf_fields(batch: &RecordBatch) -> Result<Vec<DbfField>> {
+    batch
+        .schema()
+        .fields()
+        .iter()
+        .zip(batch.columns())
+        .map(|(field, array)| {
+            let (kind, width, decimals) = match array.data_type() {
+                DataType::UInt8 => (b'N', 3, 0),
+                DataType::UInt32 => (b'N', 10, 0),
+                DataType::UInt64 => (b'N', 20, 0),
+                DataType::Float32 | DataType::Float64 => (b'N', 24, 15),
+                DataType::Utf8 => {
+                    let strings = array.as_any().downcast_ref::<StringArray>().unwrap();
+                    let longest = strings.iter().flatten().map(str::len).max().unwrap_or(0);
+                    (b'C', longest.clamp(1, 254), 0)
+                }
+                DataType::Timestamp(TimeUnit::Second, _) => (b'D', 8, 0),
+                other => bail!("Cannot write {} columns to a shapefile", other),
+            };
+            Ok(DbfField {
+                // Field names are limited to 10 characters.
+                name: field.name().chars().take(10).collect(),
+                kind,
+                width,
+                decimals,
+            })
+        })
+        .collect()
+}
+
+fn dbf_header(fields: &[DbfField], count: u32) -> Vec<u8> {
+    let record_length = 1 + fields.iter().map(|f| f.width).sum::<usize>();
+    let (year, month, day) = today();
+    let mut header = vec![0x03, (year - 1900) as u8, month, day];
+    header.extend(count.to_le_bytes());
+    header.extend(((32 + 32 * fields.len() + 1) as u16).to_le_bytes());
+    header.extend((record_length as u16).to_le_bytes());
+    header.extend([0; 20]);
+    for field in fields {
+        let mut name = [0u8; 11];
+        name[..field.name.len()].copy_from_slice(field.name.as_bytes());
+        header.extend(name);
+        header.push(field.kind);
+        header.extend([0; 4]);
+        header.push(field.width as u8);
+        header.push(field.decimals as u8);
+        header.extend([0; 14]);
+    }
+    header.push(0x0d);
+    header
+}
+
+/// The current UTC date, for the `.dbf` header.
+fn today() -> (i64, u8, u8) {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // Howard Hinnant's days to civil date algorithm.
+    let z = (seconds / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Appends a value as fixed width text: numbers right aligned, strings left aligned.
+fn write_dbf_value(field: &DbfField, array: &dyn Array, row: usize, out: &mut Vec<u8>) {
+    fn typed<T: 'static>(array: &dyn Array) -> &T {
+        array
+            .as_any()
+            .downcast_ref()
+            .expect("checked by dbf_fields")
+    }
+    let float = |v: f64| {
+        let text = format!("{:.*}", field.decimals, v);
+        if text.len() <= field.width {
+            text
+        } else {
+            format!("{:.*e}", field.width.saturating_sub(8), v)
+        }
+    };
+    let text = match array.data_type() {
+        DataType::UInt8 => typed::<UInt8Array>(array).value(row).to_string(),
+        DataType::UInt32 => typed::<UInt32Array>(array).value(row).to_string(),
+        DataType::UInt64 => typed::<UInt64Array>(array).value(row).to_string(),
+        DataType::Float32 => float(typed::<Float32Array>(array).value(row) as f64),
+        DataType::Float64 => float(typed::<Float64Array>(array).value(row)),
+        DataType::Utf8 => {
+            let value = typed::<StringArray>(array).value(row).as_bytes();
+            let value = &value[..value.len().min(field.width)];
+            out.extend(value);
+            out.resize(out.len() + field.width - value.len(), b' ');
+            return;
+        }
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            // Dates are `YYYYMMDD`; the time of day has nowhere to go.
+            let date = iso8601(typed::<TimestampSecondArray>(array).value(row));
+            out.extend(date[..10].bytes().filter(u8::is_ascii_digit));
+            return;
+        }
+        _ => unreachable!("checked by dbf_fields"),
+    };
+    out.extend(format!("{:>1$.1$}", text, field.width).bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_parts, SHAPE_POINT};
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_split_at_limit() {
+        let column = |values: Vec<f32>| Arc::new(Float32Array::from(values)) as ArrayRef;
+        let batch = RecordBatch::try_from_iter([
+            ("lon", column(vec![1.0, 2.0, 3.0])),
+            ("lat", column(vec![4.0, 5.0, 6.0])),
+            ("value", column(vec![7.5, 8.0, 9.0])),
+        ])
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("shp-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Room for the `.dbf`'s 129 byte header, two 73 byte records and its end marker.
+        let limit = 129 + 2 * 73 + 1;
+        let paths = write_parts(&dir.join("out.shp"), &batch, limit, SHAPE_POINT, |x, y| {
+            vec![(x, y)]
+        })
+        .unwrap();
+        assert_eq!(paths, [dir.join("out.shp"), dir.join("out_2.shp")]);
+
+        let shp = std::fs::read(dir.join("out.shp")).unwrap();
+        assert_eq!(shp.len(), 156);
+        assert_eq!(i32::from_be_bytes(shp[24..28].try_into().unwrap()), 78);
+        let dbf = std::fs::read(dir.join("out_2.dbf")).unwrap();
+        assert_eq!(u32::from_le_bytes(dbf[4..8].try_into().unwrap()), 1);
+        let record = &dbf[dbf.len() - 1 - 3 * 24..dbf.len() - 1];
+        assert_eq!(
+            std::str::from_utf8(&record[48..]).unwrap(),
+            "       9.000000000000000"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+//! World files and `.prj` files beside plain tifs, which place images that carry no
+//! GeoTIFF tags of their own. A world file (`.tfw`, `.tifw` or `.wld`) gives the pixel
+//! size and the center of the top left pixel, and is read as the model tags it stands
+//! for; a `.prj` gives the CRS in WKT, read as GeoKeys. Tags in the tif win over both.
+//!
+//! Without `--src-crs` or `--dst-crs`, inputs are taken as global grids, unless a `.prj`
+//! says otherwise: then the image is placed in its CRS. A world file without one still
+//! needs `--src-crs`.
+
+use crate::{
+    archive,
+    crs::{self, Crs},
+    failure::{
+        Classified,
+        FailureClass::{BadInput, Unsupported},
+    },
+    ifd::{
+        Change, Directory, DOUBLE, GEO_KEY_DIRECTORY, MODEL_PIXEL_SCALE, MODEL_TIEPOINT,
+        MODEL_TRANSFORMATION, SHORT,
+    },
+    stdin,
+};
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use tiff::decoder::Decoder;
+
+/// Extensions of world files, in the order they are looked for.
+const WORLD_FILES: [&str; 3] = ["tfw", "tifw", "wld"];
+
+/// The sidecar of `path` with `extension`, if the input is a file that has one.
+fn find(path: &Path, extension: &str) -> Option<PathBuf> {
+    if stdin::is_stdin(path) || archive::split(path).is_some() {
+        return None;
+    }
+    let sidecar = path.with_extension(extension);
+    sidecar.is_file().then_some(sidecar)
+}
+
+fn world_file(path: &Path) -> Option<PathBuf> {
+    WORLD_FILES
+        .iter()
+        .find_map(|extension| find(path, extension))
+}
+
+/// Whether `path` has a world file or `.prj` beside it.
+pub fn exists(path: &Path) -> bool {
+    world_file(path).is_some() || find(path, "prj").is_some()
+}
+
+/// The model pixel scale and tiepoint of the six lines of a world file: the pixel width,
+/// two rotations, the pixel height, which is negative, and the center of the top left
+/// pixel.
+fn read_world_file(text: &str) -> Result<([f64; 3], [f64; 6])> {
+    let numbers = text
+        .split_ascii_whitespace()
+        .map(|n| n.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>();
+    let Ok([a, d, b, e, c, f]) = numbers.as_deref() else {
+        bail!(Classified::new(
+            BadInput,
+            "A world file should be six numbers, one per line"
+        ));
+    };
+    if *d != 0.0 || *b != 0.0 || *a <= 0.0 || *e >= 0.0 {
+        bail!(Classified::new(
+            Unsupported,
+            "World files of rotated or flipped images aren't supported"
+        ));
+    }
+    Ok((
+        [*a, -e, 0.0],
+        [0.0, 0.0, 0.0, c - a / 2.0, f - e / 2.0, 0.0],
+    ))
+}
+
+/// The CRS of the `.prj` at `prj`, which must cite one of the known EPSG codes.
+fn read_prj(prj: &Path) -> Result<Crs> {
+    match Crs::from_wkt(&std::fs::read_to_string(prj)?) {
+        Some(crs) => Ok(crs),
+        None => bail!(Classified::new(
+            Unsupported,
+            format!(
+                "The CRS in {} cites no EPSG code known here, pass --src-crs",
+                prj.display()
+            )
+        )),
+    }
+}
+
+/// Adds the tags the sidecars of the tif at `path` stand for to its `contents`, where the
+/// tif doesn't have them already.
+pub fn apply(path: &Path, contents: Vec<u8>) -> Result<Vec<u8>> {
+    let (world_file, prj) = (world_file(path), find(path, "prj"));
+    if world_file.is_none() && prj.is_none() {
+        return Ok(contents);
+    }
+    let mut directory = Directory::read(&contents)?;
+    let mut changed = false;
+    if let Some(world_file) = world_file
+        .filter(|_| !directory.has(MODEL_PIXEL_SCALE) && !directory.has(MODEL_TRANSFORMATION))
+    {
+        let (scale, tiepoint) = read_world_file(&std::fs::read_to_string(world_file)?)?;
+        let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect();
+        directory.set(MODEL_PIXEL_SCALE, DOUBLE, bits(&scale));
+        directory.set(MODEL_TIEPOINT, DOUBLE, bits(&tiepoint));
+        changed = true;
+    }
+    if let Some(prj) = prj.filter(|_| !directory.has(GEO_KEY_DIRECTORY)) {
+        let keys = crs::geokeys(read_prj(&prj)?);
+        directory.set(
+            GEO_KEY_DIRECTORY,
+            SHORT,
+            keys.into_iter().map(u64::from).collect(),
+        );
+        changed = true;
+    }
+    match changed {
+        true => Ok(directory.rewrite(&contents, |_| Ok(Change::Keep))?.to_vec()),
+        false => Ok(contents),
+    }
+}
+
+/// The CRS the image at `path` is in: `given` by `--src-crs`, or else the one in its
+/// GeoKeys if it has a `.prj` beside it, or none to take it as a global grid.
+pub fn src_crs<R: std::io::Read + std::io::Seek>(
+    path: &Path,
+    given: Option<Crs>,
+    decoder: &mut Decoder<R>,
+) -> Result<Option<Crs>> {
+    if given.is_some() {
+        return Ok(given);
+    }
+    let Some(prj) = find(path, "prj") else {
+        return Ok(None);
+    };
+    match crs::read_geokeys(decoder)? {
+        Some(code) => Ok(Some(Crs::from_epsg(code)?)),
+        None => read_prj(&prj).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, src_crs};
+    use crate::{crs::Crs, georef::GeoTransform};
+    use std::io::Cursor;
+    use tiff::{
+        decoder::Decoder,
+        encoder::{colortype::Gray8, TiffEncoder},
+    };
+
+    #[test]
+    fn test_sidecars() {
+        let dir = std::env::temp_dir().join(format!("sidecar-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut contents = Cursor::new(vec![]);
+        TiffEncoder::new(&mut contents)
+            .unwrap()
+            .write_image::<Gray8>(4, 2, &[1; 8])
+            .unwrap();
+        let contents = contents.into_inner();
+        let path = dir.join("plain.tif");
+        assert_eq!(apply(&path, contents.clone()).unwrap(), contents);
+
+        std::fs::write(dir.join("plain.tfw"), "0.5\n0\n0\n-0.5\n10.25\n49.75\n").unwrap();
+        std::fs::write(dir.join("plain.prj"), Crs::wgs84().esri_wkt().unwrap()).unwrap();
+        let placed = apply(&path, contents.clone()).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(&placed)).unwrap();
+        let src = src_crs(&path, None, &mut decoder).unwrap();
+        assert_eq!(src, Some(Crs::wgs84()));
+        let bounds = GeoTransform::resolve(&mut decoder, src, None)
+            .unwrap()
+            .bounds();
+        assert_eq!(
+            (bounds.west, bounds.south, bounds.east, bounds.north),
+            (10.0, 49.0, 12.0, 50.0)
+        );
+
+        std::fs::write(dir.join("plain.tfw"), "0.5\n0.1\n0\n-0.5\n10.25\n49.75\n").unwrap();
+        assert!(apply(&path, contents.clone()).is_err());
+        std::fs::remove_file(dir.join("plain.tfw")).unwrap();
+        std::fs::write(dir.join("plain.prj"), r#"PROJCS["NAD_1983_UTM_Zone_10N"]"#).unwrap();
+        assert!(apply(&path, contents).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+//! Ordering output rows by position, with `--sort`, so that each parquet row group covers
+//! a small region and the min/max statistics of its position columns let readers such as
+//! DuckDB and Spark skip the row groups outside a spatial filter.
+
+use anyhow::Result;
+use arrow_array::{cast::as_primitive_array, types::Float32Type, RecordBatch, UInt32Array};
+
+/// What order rows are written in.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Sort {
+    /// Along a Hilbert curve through the output's extent, which keeps nearby rows
+    /// together best.
+    Hilbert,
+    /// Along a Z-order (Morton) curve, which is cheaper to compute by other tools.
+    Zorder,
+    /// By latitude, then longitude, from the south west.
+    LatLon,
+}
+
+/// Reorders the rows of `batch`, whose first two columns are its Float32 positions.
+pub fn sort(batch: &RecordBatch, order: Sort) -> Result<RecordBatch> {
+    if batch.num_rows() == 0 {
+        return Ok(batch.clone());
+    }
+    let xs = as_primitive_array::<Float32Type>(batch.column(0)).values();
+    let ys = as_primitive_array::<Float32Type>(batch.column(1)).values();
+    let mut indices: Vec<u32> = (0..batch.num_rows() as u32).collect();
+    match order {
+        Sort::LatLon => indices.sort_by(|&a, &b| {
+            let (a, b) = (a as usize, b as usize);
+            ys[a].total_cmp(&ys[b]).then(xs[a].total_cmp(&xs[b]))
+        }),
+        Sort::Hilbert | Sort::Zorder => {
+            let range = |values: &[f32]| {
+                let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+                let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                (min as f64, max as f64)
+            };
+            let ((min_x, max_x), (min_y, max_y)) = (range(xs), range(ys));
+            let scale = |v: f32, min: f64, max: f64| match max > min {
+                true => (65535.0 * (v as f64 - min) / (max - min)).floor() as u32,
+                false => 0,
+            };
+            let key = |i: &u32| {
+                let x = scale(xs[*i as usize], min_x, max_x);
+                let y = scale(ys[*i as usize], min_y, max_y);
+                match order {
+                    Sort::Hilbert => hilbert(x, y),
+                    _ => (spread(y) << 1) | spread(x),
+                }
+            };
+            indices.sort_by_cached_key(key);
+        }
+    }
+    let indices = UInt32Array::from(indices);
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| arrow_select::take::take(column, &indices, None))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+/// Spreads the bits of a 16 bit number out to every other bit.
+fn spread(mut v: u32) -> u32 {
+    v = (v | (v << 8)) & 0x00ff00ff;
+    v = (v | (v << 4)) & 0x0f0f0f0f;
+    v = (v | (v << 2)) & 0x33333333;
+    (v | (v << 1)) & 0x55555555
+}
+
+/// Maps 16 bit coordinates to their index on the Hilbert curve, following the
+/// branchless construction used by flatbush and FlatGeobuf.
+pub(crate) fn hilbert(x: u32, y: u32) -> u32 {
+    let mut a = x ^ y;
+    let mut b = 0xffff ^ a;
+    let mut c = 0xffff ^ (x | y);
+    let mut d = x & (y ^ 0xffff);
+
+    let (mut na, mut nb, mut nc, mut nd) = (
+        a | (b >> 1),
+        (a >> 1) ^ a,
+        ((c >> 1) ^ (b & (d >> 1))) ^ c,
+        ((a & (c >> 1)) ^ (d >> 1)) ^ d,
+    );
+    for shift in [2, 4] {
+        (a, b, c, d) = (na, nb, nc, nd);
+        na = (a & (a >> shift)) ^ (b & (b >> shift));
+        nb = (a & (b >> shift)) ^ (b & ((a ^ b) >> shift));
+        nc ^= (a & (c >> shift)) ^ (b & (d >> shift));
+        nd ^= (b & (c >> shift)) ^ ((a ^ b) & (d >> shift));
+    }
+    (a, b, c, d) = (na, nb, nc, nd);
+    nc ^= (a & (c >> 8)) ^ (b & (d >> 8));
+    nd ^= (b & (c >> 8)) ^ ((a ^ b) & (d >> 8));
+
+    let a = nc ^ (nc >> 1);
+    let b = nd ^ (nd >> 1);
+    let i0 = x ^ y;
+    let i1 = b | (0xffff ^ (i0 | a));
+    (spread(i1) << 1) | spread(i0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hilbert, sort, Sort};
+    use arrow_array::{cast::as_primitive_array, types::Float32Type, Float32Array, RecordBatch};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_hilbert() {
+        // On a 4x4 grid the curve must visit every cell once, stepping to a neighbour
+        // each time.
+        let mut cells: Vec<(u32, u32, u32)> = (0..16)
+            .map(|i| (i % 4, i / 4))
+            .map(|(x, y)| (hilbert(x << 14, y << 14) >> 28, x, y))
+            .collect();
+        cells.sort();
+        for (i, pair) in cells.windows(2).enumerate() {
+            assert_eq!(pair[0].0, i as u32);
+            assert_eq!(
+                pair[0].1.abs_diff(pair[1].1) + pair[0].2.abs_diff(pair[1].2),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_sort() {
+        // The four cells of a 2x2 grid, given as values 0 to 3 from the north west.
+        let column = |values: [f32; 4]| Arc::new(Float32Array::from(values.to_vec())) as _;
+        let batch = RecordBatch::try_from_iter([
+            ("lon", column([0.0, 1.0, 0.0, 1.0])),
+            ("lat", column([1.0, 1.0, 0.0, 0.0])),
+            ("value", column([0.0, 1.0, 2.0, 3.0])),
+        ])
+        .unwrap();
+        let values = |order| {
+            let sorted = sort(&batch, order).unwrap();
+            as_primitive_array::<Float32Type>(sorted.column(2))
+                .values()
+                .to_vec()
+        };
+        assert_eq!(values(Sort::LatLon), [2.0, 3.0, 0.0, 1.0]);
+        assert_eq!(values(Sort::Zorder), [2.0, 3.0, 0.0, 1.0]);
+        // The Hilbert curve steps to a neighbour each time, so never across a diagonal.
+        assert_eq!(values(Sort::Hilbert), [2.0, 0.0, 1.0, 3.0]);
+    }
+}
@@ -0,0 +1,499 @@
+//! The processing state of an incremental pipeline, kept with `--state` so that a batch
+//! run again converts only the inputs that are new or have changed since.
+//!
+//! Each converted input is recorded under its path as given, with its size, modification
+//! time and SHA-256, a hash of the options it was converted with and of the files they
+//! name, such as its mask, and where its output went. An input is up to date while its
+//! contents and the options are the same and its output is still there; its digest is
+//! only taken again when its size or modification time has changed. The state is a SQLite
+//! database, or a JSON file when its path ends in `.json`.
+
+use crate::{
+    archive,
+    checksum::{self, Algorithm},
+    inputs,
+    json::{self, Value},
+    manifest, output,
+    processor::Options,
+    stdin, upload,
+};
+use anyhow::{bail, Context, Result};
+use clap::{ArgMatches, Args};
+use rusqlite::{params, Connection};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+#[derive(Args)]
+pub struct StatusArgs {
+    /// The state file the batch was run with.
+    #[arg(long = "state", value_name = "PATH")]
+    state: PathBuf,
+    /// Inputs to report on, as they would be given to convert them. Defaults to every
+    /// input the state records.
+    inputs: Vec<PathBuf>,
+    /// Extensions of the files taken from inputs that are directories.
+    #[arg(
+        long = "extensions",
+        value_delimiter = ',',
+        default_value = "tif,tif.gz,zip,tar,tar.gz,tgz,7z,nc,asc"
+    )]
+    extensions: Vec<String>,
+    /// The conversion's options, which must be the ones the batch is run with for its
+    /// inputs to be up to date.
+    #[command(flatten)]
+    options: Options,
+}
+
+/// Options that change how a conversion runs but not what it writes, so are left out of
+/// the hash of those an input was converted with.
+const RUN_ONLY: &[&str] = &[
+    "force",
+    "skip_existing",
+    "overwrite",
+    "timing",
+    "verify_checksum",
+    "sha256",
+    "mmap",
+    "max_memory",
+    "chunk_rows",
+    "chunk_tiles",
+    "stream_priority",
+];
+
+/// Options naming files whose contents shape the output, so whose digests are hashed along
+/// with their paths. `reclass` names one only when its value ends in `.csv`.
+const FILE_OPTIONS: &[&str] = &[
+    "mask",
+    "align_to",
+    "stratify_by",
+    "distance_to",
+    "reclass",
+    "labels",
+];
+
+/// A digest of the conversion options in `matches`, the arguments of a command that
+/// flattens [`Options`], given or defaulted, and of the files the [`FILE_OPTIONS`] name.
+pub fn options_hash(matches: &ArgMatches) -> Result<String> {
+    let command = Options::augment_args(clap::Command::new("options"));
+    let mut text = String::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if RUN_ONLY.contains(&id) {
+            continue;
+        }
+        if let Some(values) = matches.try_get_raw(id)? {
+            let values: Vec<String> = values.map(|v| v.to_string_lossy().to_string()).collect();
+            writeln!(text, "{}={}", id, Value::from(values.clone()))?;
+            let files = values.iter().map(Path::new).filter(|path| {
+                FILE_OPTIONS.contains(&id)
+                    && path.is_file()
+                    && (id != "reclass" || path.extension() == Some("csv".as_ref()))
+            });
+            for path in files {
+                let file = fs::File::open(path)
+                    .with_context(|| format!("Could not read {}", path.display()))?;
+                let digest = checksum::hash(file, Algorithm::Sha256)?;
+                writeln!(text, "{}:{}={}", id, path.display(), digest)?;
+            }
+        }
+    }
+    checksum::hash(text.as_bytes(), Algorithm::Sha256)
+}
+
+/// What an input held when it was converted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    size: u64,
+    /// Seconds since the epoch.
+    modified: f64,
+    sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    fingerprint: Fingerprint,
+    options: String,
+    output: String,
+}
+
+/// Where an input stands against its record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    /// Converted as it is now, with the same options.
+    UpToDate { output: PathBuf },
+    /// Not converted yet.
+    New,
+    /// Converted, but its contents have changed since.
+    Changed,
+    /// Converted as it is now, but with other options.
+    Reconfigured,
+    /// Converted as it is now, but its output has since been removed.
+    OutputMissing,
+}
+
+impl Status {
+    fn label(&self) -> &'static str {
+        match self {
+            Status::UpToDate { .. } => "up to date",
+            Status::New => "new",
+            Status::Changed => "changed",
+            Status::Reconfigured => "options changed",
+            Status::OutputMissing => "output missing",
+        }
+    }
+}
+
+enum Store {
+    Sqlite(Connection),
+    Json,
+}
+
+struct Inner {
+    store: Store,
+    records: BTreeMap<String, Record>,
+}
+
+/// A state file, open for checking inputs against and recording them in as they convert.
+pub struct State {
+    path: PathBuf,
+    /// The [`options_hash`] of this batch.
+    options: String,
+    inner: Mutex<Inner>,
+}
+
+impl State {
+    /// Opens the state at `path`, which is created with the first record if it doesn't
+    /// exist.
+    pub fn open(path: &Path, options: String) -> Result<State> {
+        let context = || format!("Could not read state {}", path.display());
+        let (store, records) = match is_json(path) {
+            true => (Store::Json, read_json(path).with_context(context)?),
+            false => {
+                let connection = Connection::open(path).with_context(context)?;
+                let records = read_sqlite(&connection).with_context(context)?;
+                (Store::Sqlite(connection), records)
+            }
+        };
+        Ok(State {
+            path: path.to_path_buf(),
+            options,
+            inner: Mutex::new(Inner { store, records }),
+        })
+    }
+
+    /// Where `input_path` stands against its record, with what it holds now, to record
+    /// once it converts so that changes made while it converts aren't missed.
+    pub fn check(&self, input_path: &Path) -> Result<(Status, Fingerprint)> {
+        let record = self
+            .inner
+            .lock()
+            .unwrap()
+            .records
+            .get(&key(input_path))
+            .cloned();
+        let fingerprint = fingerprint(input_path, record.as_ref().map(|r| &r.fingerprint))?;
+        let status = match record {
+            None => Status::New,
+            Some(record) if record.fingerprint.sha256 != fingerprint.sha256 => Status::Changed,
+            Some(record) if record.options != self.options => Status::Reconfigured,
+            Some(record) if !output_exists(&record.output) => Status::OutputMissing,
+            Some(record) => Status::UpToDate {
+                output: PathBuf::from(record.output),
+            },
+        };
+        Ok((status, fingerprint))
+    }
+
+    /// Records that `input_path`, holding `fingerprint`, was converted to `output`.
+    pub fn record(&self, input_path: &Path, fingerprint: Fingerprint, output: &Path) -> Result<()> {
+        let record = Record {
+            fingerprint,
+            options: self.options.clone(),
+            output: output.display().to_string(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let key = key(input_path);
+        let written = match &inner.store {
+            Store::Sqlite(connection) => write_sqlite(connection, &key, &record),
+            Store::Json => {
+                let mut records = inner.records.clone();
+                records.insert(key.clone(), record.clone());
+                write_json(&self.path, &records)
+            }
+        };
+        written.with_context(|| format!("Could not write state {}", self.path.display()))?;
+        inner.records.insert(key, record);
+        Ok(())
+    }
+
+    /// The inputs recorded, in order of their paths.
+    fn inputs(&self) -> Vec<PathBuf> {
+        let inner = self.inner.lock().unwrap();
+        inner.records.keys().map(PathBuf::from).collect()
+    }
+}
+
+/// Inputs are recorded under their paths as given.
+/// Whether the recorded `output` is still there. Only local files are looked for, so
+/// uploads, tables and stdout are taken to be.
+fn output_exists(output: &str) -> bool {
+    let path = Path::new(output);
+    output == output::STDOUT
+        || output.starts_with("postgres table ")
+        || upload::is_url(path)
+        || path.exists()
+}
+
+fn key(input_path: &Path) -> String {
+    input_path.display().to_string()
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// What `input_path` holds now, taking its digest only if its size or modification time
+/// differ from those `known`.
+fn fingerprint(input_path: &Path, known: Option<&Fingerprint>) -> Result<Fingerprint> {
+    let file = archive::file(input_path);
+    let metadata =
+        fs::metadata(file).with_context(|| format!("Could not read {}", file.display()))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since| since.as_secs_f64());
+    let size = metadata.len();
+    let sha256 = match known {
+        Some(known) if known.size == size && known.modified == modified => known.sha256.clone(),
+        _ => manifest::sha256_file(input_path)?,
+    };
+    Ok(Fingerprint {
+        size,
+        modified,
+        sha256,
+    })
+}
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS inputs (
+    path TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    modified REAL NOT NULL,
+    sha256 TEXT NOT NULL,
+    options TEXT NOT NULL,
+    output TEXT NOT NULL
+)";
+
+fn read_sqlite(connection: &Connection) -> Result<BTreeMap<String, Record>> {
+    connection.execute(SCHEMA, [])?;
+    let mut statement =
+        connection.prepare("SELECT path, size, modified, sha256, options, output FROM inputs")?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            Record {
+                fingerprint: Fingerprint {
+                    size: row.get::<_, i64>(1)? as u64,
+                    modified: row.get(2)?,
+                    sha256: row.get(3)?,
+                },
+                options: row.get(4)?,
+                output: row.get(5)?,
+            },
+        ))
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn write_sqlite(connection: &Connection, key: &str, record: &Record) -> Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO inputs (path, size, modified, sha256, options, output)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            key,
+            record.fingerprint.size as i64,
+            record.fingerprint.modified,
+            record.fingerprint.sha256,
+            record.options,
+            record.output,
+        ],
+    )?;
+    Ok(())
+}
+
+fn read_json(path: &Path) -> Result<BTreeMap<String, Record>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let value = json::parse(&fs::read_to_string(path)?)?;
+    let Some(entries) = value.get("inputs").and_then(Value::as_array) else {
+        bail!("expected an object with an array of inputs");
+    };
+    entries
+        .iter()
+        .map(|entry| {
+            let string = |name| match entry.get(name).and_then(Value::as_str) {
+                Some(s) => Ok(s.to_string()),
+                None => bail!("expected each input to have a string {}", name),
+            };
+            let number = |name| match entry.get(name).and_then(Value::as_f64) {
+                Some(n) => Ok(n),
+                None => bail!("expected each input to have a number {}", name),
+            };
+            Ok((
+                string("path")?,
+                Record {
+                    fingerprint: Fingerprint {
+                        size: number("size")? as u64,
+                        modified: number("modified")?,
+                        sha256: string("sha256")?,
+                    },
+                    options: string("options")?,
+                    output: string("output")?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Writes the records under another name and renames it, so an interrupted write leaves
+/// the last state whole.
+fn write_json(path: &Path, records: &BTreeMap<String, Record>) -> Result<()> {
+    let inputs: Vec<Value> = records
+        .iter()
+        .map(|(path, record)| {
+            Value::object([
+                ("path", Value::from(path.as_str())),
+                ("size", record.fingerprint.size.into()),
+                ("modified", record.fingerprint.modified.into()),
+                ("sha256", record.fingerprint.sha256.clone().into()),
+                ("options", record.options.clone().into()),
+                ("output", record.output.clone().into()),
+            ])
+        })
+        .collect();
+    let partial = path.with_extension("json.partial");
+    fs::write(
+        &partial,
+        Value::object([("inputs", inputs.into())]).pretty() + "\n",
+    )?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Prints where each input stands, for `status`. `matches` are the subcommand's.
+pub fn run(args: &StatusArgs, matches: &ArgMatches) -> Result<()> {
+    if !args.state.exists() {
+        bail!("{} doesn't exist", args.state.display());
+    }
+    let state = State::open(&args.state, options_hash(matches)?)?;
+    let inputs = match args.inputs.is_empty() {
+        true => state.inputs(),
+        false => inputs::expand(&args.inputs, &args.extensions)?,
+    };
+    let mut up_to_date = 0;
+    for input_path in &inputs {
+        // What is piped on stdin is new each time.
+        if stdin::is_stdin(input_path) {
+            println!("{:<16} {}", Status::New.label(), input_path.display());
+            continue;
+        }
+        if !archive::file(input_path).exists() {
+            println!("{:<16} {}", "missing", input_path.display());
+            continue;
+        }
+        match state.check(input_path)?.0 {
+            Status::UpToDate { output } => {
+                up_to_date += 1;
+                println!(
+                    "{:<16} {} -> {}",
+                    "up to date",
+                    input_path.display(),
+                    output.display()
+                );
+            }
+            status => println!("{:<16} {}", status.label(), input_path.display()),
+        }
+    }
+    println!("{} of {} inputs up to date", up_to_date, inputs.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{options_hash, State, Status};
+    use crate::processor::Options;
+    use clap::Args;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_state() {
+        let dir = std::env::temp_dir().join(format!("state-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("a.tif");
+        std::fs::write(&input, b"first").unwrap();
+        let output = dir.join("a.parquet");
+        std::fs::write(&output, b"rows").unwrap();
+        for name in ["state.sqlite", "state.json"] {
+            let path = dir.join(name);
+            let state = State::open(&path, "options".into()).unwrap();
+            let (status, fingerprint) = state.check(&input).unwrap();
+            assert_eq!(status, Status::New);
+            state.record(&input, fingerprint, &output).unwrap();
+
+            // Records are read back by a later run, which the options are checked against.
+            let state = State::open(&path, "options".into()).unwrap();
+            let up_to_date = Status::UpToDate {
+                output: output.clone(),
+            };
+            assert_eq!(state.check(&input).unwrap().0, up_to_date);
+            assert_eq!(state.inputs(), vec![PathBuf::from(&input)]);
+            let other = State::open(&path, "other".into()).unwrap();
+            assert_eq!(other.check(&input).unwrap().0, Status::Reconfigured);
+        }
+        // An input whose output was removed is converted again.
+        std::fs::remove_file(&output).unwrap();
+        let state = State::open(&dir.join("state.json"), "options".into()).unwrap();
+        assert_eq!(state.check(&input).unwrap().0, Status::OutputMissing);
+        std::fs::write(&output, b"rows").unwrap();
+        assert!(matches!(
+            state.check(&input).unwrap().0,
+            Status::UpToDate { .. }
+        ));
+        std::fs::write(&input, b"second").unwrap();
+        assert_eq!(state.check(&input).unwrap().0, Status::Changed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_options_hash() {
+        let dir = std::env::temp_dir().join(format!("options-hash-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mask = dir.join("mask.geojson");
+        let classes = dir.join("classes.csv");
+        std::fs::write(&mask, "{}").unwrap();
+        std::fs::write(&classes, "0,10,1\n").unwrap();
+        let hash = |args: &[&str]| {
+            let command = Options::augment_args(clap::Command::new("options"));
+            let matches = command.try_get_matches_from([&["options"], args].concat());
+            options_hash(&matches.unwrap()).unwrap()
+        };
+        let (mask, classes) = (mask.to_str().unwrap(), classes.to_str().unwrap());
+        let first = hash(&["--mask", mask, "--reclass", classes]);
+        // Options that only change how the conversion runs don't count.
+        let chunked = hash(&["--mask", mask, "--reclass", classes, "--chunk-rows", "8"]);
+        assert_eq!(chunked, first);
+        // Files the options name count by their contents, not only their paths.
+        std::fs::write(mask, "{ }").unwrap();
+        let second = hash(&["--mask", mask, "--reclass", classes]);
+        assert_ne!(second, first);
+        std::fs::write(classes, "0,10,2\n").unwrap();
+        assert_ne!(hash(&["--mask", mask, "--reclass", classes]), second);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
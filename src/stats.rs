@@ -0,0 +1,397 @@
+//! Summary statistics of a raster's pixel values.
+
+use crate::{
+    explain,
+    json::Value,
+    load_tif_contents,
+    metadata::SourceMetadata,
+    planar,
+    raster::{self, ChunkSize, Layout},
+    DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{bail, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{borrow::Cow, collections::HashMap, io::Cursor, path::PathBuf};
+use tiff::{
+    decoder::{Decoder, DecodingResult, Limits},
+    tags::Tag,
+};
+
+#[derive(clap::Args)]
+pub struct StatsArgs {
+    /// The raster to summarize.
+    raster: PathBuf,
+    /// Percentiles to report, as a comma separated list between 0 and 100.
+    #[arg(
+        long = "percentiles",
+        value_delimiter = ',',
+        default_value = "5,25,50,75,95"
+    )]
+    percentiles: Vec<f64>,
+    /// Number of equal width histogram bins between the smallest and largest value.
+    #[arg(long = "bins", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    bins: u32,
+    /// Only report each band's min, max, mean and nodata fraction, taken from the smallest
+    /// overview or, without one, a sample of the strips or tiles.
+    #[arg(long = "quick")]
+    quick: bool,
+    /// Print the statistics as JSON instead of text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+/// How many strips or tiles a quick scan of a raster without overviews decodes.
+const SAMPLED_CHUNKS: u32 = 16;
+
+/// How often each value occurs, keyed by its bits. Rasters usually hold far fewer distinct
+/// values than pixels, so this stays small while giving exact percentiles.
+type Counts = HashMap<u64, u64>;
+
+pub fn run(args: &StatsArgs) -> Result<()> {
+    if let Some(p) = args
+        .percentiles
+        .iter()
+        .find(|p| !(0.0..=100.0).contains(*p))
+    {
+        bail!("Percentile {} is not between 0 and 100", p);
+    }
+    if args.quick {
+        let tif_contents = load_tif_contents(&args.raster)?;
+        let summary = Value::object([
+            (
+                "path",
+                Value::from(args.raster.to_string_lossy().to_string()),
+            ),
+            ("bands", quick_band_stats(&tif_contents)?),
+        ]);
+        if args.json {
+            println!("{}", summary.pretty());
+        } else {
+            print!("{}", explain::to_text(&summary, 0));
+        }
+        return Ok(());
+    }
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(ProgressStyle::with_template("{prefix:<30} {msg}")?);
+    bar.set_prefix(args.raster.to_string_lossy().to_string());
+    bar.set_message("reading file");
+    let tif_contents = load_tif_contents(&args.raster)?;
+    let mut decoder = Decoder::new(Cursor::new(&tif_contents))?.with_limits(Limits::unlimited());
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let source = SourceMetadata::read(&mut decoder)?;
+    let nodata: Option<f64> = source.nodata.as_deref().and_then(|n| n.trim().parse().ok());
+
+    bar.set_message("processing image");
+    bar.set_length(layout.chunk_count() as u64);
+    bar.set_style(ProgressStyle::with_template(
+        "{prefix:<30} {msg} {percent}% {elapsed_precise} {bar_wide}",
+    )?);
+    let counts = raster::fold_pixels(
+        &tif_contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |_, _, _, _| true,
+        |chunks| bar.inc(chunks),
+        Counts::new,
+        |counts, _, _, value| {
+            if value > 0.0 && value.is_finite() && Some(value) != nodata {
+                *counts.entry(value.to_bits()).or_default() += 1;
+            }
+        },
+        |mut left, right| {
+            for (value, count) in right {
+                *left.entry(value).or_default() += count;
+            }
+            left
+        },
+    )?;
+    bar.finish_and_clear();
+
+    let mut summary = summarize(counts, &args.percentiles, args.bins);
+    if let Value::Object(entries) = &mut summary {
+        entries.insert(
+            0,
+            (
+                "path".into(),
+                args.raster.to_string_lossy().to_string().into(),
+            ),
+        );
+    }
+    if args.json {
+        println!("{}", summary.pretty());
+    } else {
+        print!("{}", explain::to_text(&summary, 0));
+    }
+    Ok(())
+}
+
+/// Running statistics of one band's samples.
+#[derive(Clone, Default)]
+struct Band {
+    count: u64,
+    nodata: u64,
+    min: Option<f64>,
+    max: Option<f64>,
+    sum: f64,
+}
+
+impl Band {
+    /// Adds the samples of each band from pixel interleaved `samples`. Samples equal to
+    /// `nodata`, and `NaN`s, only count towards the nodata fraction.
+    fn add_all(bands: &mut [Band], samples: &[f64], nodata: Option<f64>) {
+        for pixel in samples.chunks_exact(bands.len()) {
+            for (band, &value) in bands.iter_mut().zip(pixel) {
+                band.count += 1;
+                if value.is_nan() || Some(value) == nodata {
+                    band.nodata += 1;
+                    continue;
+                }
+                band.min = Some(band.min.map_or(value, |m| m.min(value)));
+                band.max = Some(band.max.map_or(value, |m| m.max(value)));
+                band.sum += value;
+            }
+        }
+    }
+
+    fn summary(&self) -> Value {
+        let valid = self.count - self.nodata;
+        Value::object([
+            ("min", Value::from(self.min)),
+            ("max", self.max.into()),
+            ("mean", (valid > 0).then(|| self.sum / valid as f64).into()),
+            (
+                "nodata_fraction",
+                (self.nodata as f64 / self.count.max(1) as f64).into(),
+            ),
+        ])
+    }
+}
+
+/// Estimates each band's min, max, mean and nodata fraction without decoding the whole
+/// image: from its smallest overview if it has one, otherwise from a few strips or tiles
+/// spread evenly through it.
+///
+/// Band separate tifs are sampled one band at a time.
+pub fn quick_band_stats(contents: &[u8]) -> Result<Value> {
+    let views = match planar::separate_bands(contents)? {
+        Some(samples) => (0..samples)
+            .map(|band| Ok(Cow::Owned(planar::band_view(contents, band)?)))
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![Cow::Borrowed(contents)],
+    };
+    let mut source = String::new();
+    let mut bands = vec![];
+    for view in &views {
+        let (sampled, found) = sample_bands(view)?;
+        source = sampled;
+        bands.extend(found);
+    }
+    let mut entries = vec![("source".to_string(), Value::from(source))];
+    entries.extend(
+        bands
+            .iter()
+            .enumerate()
+            .map(|(i, band)| (format!("band {}", i + 1), band.summary())),
+    );
+    Ok(Value::object(entries))
+}
+
+/// Gathers the statistics of each band of the tif's first image, returning them with a
+/// description of what was sampled.
+fn sample_bands(contents: &[u8]) -> Result<(String, Vec<Band>)> {
+    let mut decoder = Decoder::new(Cursor::new(contents))?.with_limits(Limits::unlimited());
+    let source = SourceMetadata::read(&mut decoder)?;
+    let nodata: Option<f64> = source.nodata.as_deref().and_then(|n| n.trim().parse().ok());
+    let samples = decoder
+        .find_tag_unsigned::<usize>(Tag::SamplesPerPixel)?
+        .unwrap_or(1);
+
+    let mut smallest: Option<(usize, u64)> = None;
+    let mut ifd = 0;
+    while decoder.more_images() {
+        decoder.next_image()?;
+        ifd += 1;
+        // Bit 0 marks a reduced resolution copy and bit 2 a transparency mask.
+        let kind = decoder
+            .find_tag_unsigned::<u32>(Tag::NewSubfileType)?
+            .unwrap_or(0);
+        let (width, height) = decoder.dimensions()?;
+        let pixels = width as u64 * height as u64;
+        // Only the first image of a band separate tif is given a view of one band.
+        let separate = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2)
+            && decoder.find_tag_unsigned::<u16>(Tag::SamplesPerPixel)? > Some(1);
+        if kind & 1 != 0 && kind & 4 == 0 && !separate && smallest.is_none_or(|(_, p)| pixels < p) {
+            smallest = Some((ifd, pixels));
+        }
+    }
+    decoder.seek_to_image(smallest.map_or(0, |(ifd, _)| ifd))?;
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let (width, height) = decoder.dimensions()?;
+    let (chunks, source): (Vec<u32>, String) = match smallest {
+        Some(_) => (
+            (0..layout.chunk_count()).collect(),
+            format!("overview of {}x{}", width, height),
+        ),
+        None => {
+            let count = layout.chunk_count();
+            let sampled = SAMPLED_CHUNKS.min(count);
+            (
+                (0..sampled).map(|i| i * count / sampled).collect(),
+                format!("{} of {} {}s", sampled, count, layout.chunk_type()),
+            )
+        }
+    };
+
+    let mut bands = vec![Band::default(); samples.max(1)];
+    for chunk in chunks {
+        let pixels = match (smallest, layout.packed_bits()) {
+            // Samples packed below a byte have to be unpacked, which `read_unit` does for
+            // the first image.
+            (None, Some(_)) => {
+                let windows =
+                    raster::read_unit(contents, &layout, chunk..chunk + 1, |_, _, _, _| true)?;
+                windows
+                    .into_iter()
+                    .map(|w| w.pixels)
+                    .next()
+                    .expect("one chunk is read")
+            }
+            _ => decoder.read_chunk(chunk)?,
+        };
+        let values: Vec<f64> = match pixels {
+            DecodingResult::U8(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::U16(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::U32(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::U64(v) => v.into_iter().map(|v| v as f64).collect(),
+            DecodingResult::I8(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::I16(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::I32(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::I64(v) => v.into_iter().map(|v| v as f64).collect(),
+            DecodingResult::F32(v) => v.into_iter().map(f64::from).collect(),
+            DecodingResult::F64(v) => v,
+        };
+        Band::add_all(&mut bands, &values, nodata);
+    }
+    Ok((source, bands))
+}
+
+/// Works the statistics out from the value counts.
+fn summarize(counts: Counts, percentiles: &[f64], bins: u32) -> Value {
+    let mut counts: Vec<(f64, u64)> = counts
+        .into_iter()
+        .map(|(bits, count)| (f64::from_bits(bits), count))
+        .collect();
+    counts.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let n: u64 = counts.iter().map(|(_, c)| c).sum();
+    let (Some(&(min, _)), Some(&(max, _))) = (counts.first(), counts.last()) else {
+        return Value::object([("count", Value::from(0u64))]);
+    };
+    let mean = counts.iter().map(|&(v, c)| v * c as f64).sum::<f64>() / n as f64;
+    let variance = counts
+        .iter()
+        .map(|&(v, c)| (v - mean).powi(2) * c as f64)
+        .sum::<f64>()
+        / n as f64;
+
+    // Nearest rank: the smallest value at least `p` percent of pixels are at or below.
+    let percentile = |p: f64| {
+        let rank = ((p / 100.0 * n as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for &(value, count) in &counts {
+            seen += count;
+            if seen >= rank {
+                return value;
+            }
+        }
+        max
+    };
+
+    // Integers each cover a bin one wide, so the largest is counted in full.
+    let integral = counts.iter().all(|(value, _)| value.fract() == 0.0);
+    let span = max - min + if integral { 1.0 } else { 0.0 };
+    let width = if span > 0.0 { span / bins as f64 } else { 1.0 };
+    let mut histogram = vec![0u64; bins as usize];
+    for &(value, count) in &counts {
+        let bin = ((value - min) / width) as usize;
+        histogram[bin.min(bins as usize - 1)] += count;
+    }
+
+    Value::object([
+        ("count", Value::from(n)),
+        ("min", min.into()),
+        ("max", max.into()),
+        ("mean", mean.into()),
+        ("stddev", variance.sqrt().into()),
+        (
+            "percentiles",
+            Value::object(
+                percentiles
+                    .iter()
+                    .map(|&p| (format!("p{}", p), Value::from(percentile(p)))),
+            ),
+        ),
+        (
+            "histogram",
+            Value::Array(
+                histogram
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &count)| {
+                        Value::object([
+                            ("lower", Value::from(min + i as f64 * width)),
+                            ("upper", (min + (i + 1) as f64 * width).into()),
+                            ("count", count.into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{summarize, Band, Counts};
+
+    #[test]
+    fn test_band_stats() {
+        let mut bands = vec![Band::default(); 2];
+        Band::add_all(
+            &mut bands,
+            &[1.0, -9.0, 3.0, 5.0, f64::NAN, 7.0],
+            Some(-9.0),
+        );
+        let number = |v: &crate::json::Value, key: &str| v.get(key).and_then(|v| v.as_f64());
+        let first = bands[0].summary();
+        assert_eq!(number(&first, "mean"), Some(2.0));
+        assert_eq!(number(&first, "nodata_fraction"), Some(1.0 / 3.0));
+        let second = bands[1].summary();
+        assert_eq!(number(&second, "min"), Some(5.0));
+        assert_eq!(number(&second, "max"), Some(7.0));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let counts = Counts::from([
+            (1f64.to_bits(), 2),
+            (2f64.to_bits(), 1),
+            (4f64.to_bits(), 1),
+        ]);
+        let summary = summarize(counts, &[50.0, 100.0], 2);
+        let number = |v: &crate::json::Value, key: &str| v.get(key).and_then(|v| v.as_f64());
+        assert_eq!(number(&summary, "count"), Some(4.0));
+        assert_eq!(number(&summary, "mean"), Some(2.0));
+        let percentiles = summary.get("percentiles").unwrap();
+        assert_eq!(number(percentiles, "p50"), Some(1.0));
+        assert_eq!(number(percentiles, "p100"), Some(4.0));
+        let histogram = summary.get("histogram").and_then(|h| h.as_array()).unwrap();
+        assert_eq!(number(&histogram[0], "count"), Some(3.0));
+        assert_eq!(number(&histogram[1], "count"), Some(1.0));
+        // Float values split their range evenly, without a bin's width added for the largest.
+        let counts = Counts::from([(0.25f64.to_bits(), 1), (0.75f64.to_bits(), 1)]);
+        let summary = summarize(counts, &[50.0], 2);
+        let histogram = summary.get("histogram").and_then(|h| h.as_array()).unwrap();
+        assert_eq!(number(&histogram[0], "upper"), Some(0.5));
+        assert_eq!(number(&histogram[1], "count"), Some(1.0));
+    }
+}
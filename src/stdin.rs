@@ -0,0 +1,55 @@
+//! Reading a tif piped on stdin, given as the input `-`, so a download can be converted
+//! as it arrives without saving it first. The decoder seeks around the file, so stdin is
+//! buffered in memory, once, however many times the input is loaded.
+
+use anyhow::{Context, Result};
+use std::{
+    io::{self, Read},
+    path::Path,
+    sync::OnceLock,
+};
+
+/// The input path that stands for stdin.
+pub const PATH: &str = "-";
+
+/// The name outputs of stdin are given, in the current directory, in place of the input's.
+const NAME: &str = "stdin";
+
+/// Whether `path` stands for stdin.
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new(PATH)
+}
+
+/// The path outputs of `input_path` are named after: `stdin` for stdin, or else the input.
+pub fn name(input_path: &Path) -> &Path {
+    match is_stdin(input_path) {
+        true => Path::new(NAME),
+        false => input_path,
+    }
+}
+
+/// Everything piped on stdin, read the first time it is asked for.
+pub fn contents() -> Result<&'static [u8]> {
+    static CONTENTS: OnceLock<Vec<u8>> = OnceLock::new();
+    if let Some(contents) = CONTENTS.get() {
+        return Ok(contents);
+    }
+    let mut contents = vec![];
+    io::stdin()
+        .lock()
+        .read_to_end(&mut contents)
+        .context("Could not read the input from stdin")?;
+    Ok(CONTENTS.get_or_init(|| contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::name;
+    use std::path::Path;
+
+    #[test]
+    fn test_stdin_names() {
+        assert_eq!(name(Path::new("-")), Path::new("stdin"));
+        assert_eq!(name(Path::new("dir/-.tif")), Path::new("dir/-.tif"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Breaking the output down by strata of a second raster on the same grid, such as
+//! population by elevation band, with `--stratify-by` and `--strata`.
+
+use crate::{
+    load_tif_contents,
+    metadata::SourceMetadata,
+    raster::{self, ChunkSize, Layout},
+    DEFAULT_CHUNK_ROWS,
+};
+use anyhow::{bail, Result};
+use std::{io::Cursor, path::Path};
+use tiff::decoder::{Decoder, Limits};
+
+/// The stratum of each pixel of the stratifying raster.
+pub struct Strata {
+    width: u32,
+    /// One more than each pixel's stratum, or 0 for pixels outside every stratum.
+    classes: Vec<u8>,
+}
+
+impl Strata {
+    /// Reads the raster at `path`, which must be `width` by `height` pixels, and places
+    /// each pixel in the stratum between consecutive `breaks` that holds its value.
+    pub fn load(path: &Path, breaks: &[f64], width: u32, height: u32) -> Result<Strata> {
+        check_breaks(breaks)?;
+        let contents = load_tif_contents(path)?;
+        let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+        if decoder.dimensions()? != (width, height) {
+            let (w, h) = decoder.dimensions()?;
+            bail!(
+                "{} is {}x{} pixels but the input is {}x{}, the rasters must share a grid",
+                path.to_string_lossy(),
+                w,
+                h,
+                width,
+                height
+            );
+        }
+        let layout = Layout::from_decoder(&mut decoder)?;
+        let nodata: Option<f64> = SourceMetadata::read(&mut decoder)?
+            .nodata
+            .as_deref()
+            .and_then(|n| n.trim().parse().ok());
+        let pixels = raster::read_pixels(
+            &contents,
+            &layout,
+            ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+            |_, _, _, _| true,
+            |_| {},
+            |x, y, value| {
+                let stratum = breaks.partition_point(|&b| b <= value);
+                (Some(value) != nodata && (1..breaks.len()).contains(&stratum)).then_some((
+                    x,
+                    y,
+                    stratum as u8,
+                ))
+            },
+        )?;
+        let mut classes = vec![0; width as usize * height as usize];
+        for (x, y, class) in pixels {
+            classes[y as usize * width as usize + x as usize] = class;
+        }
+        Ok(Strata { width, classes })
+    }
+
+    /// The stratum of pixel `x`, `y`, counting from 0, if it is in one.
+    pub fn stratum(&self, x: u32, y: u32) -> Option<u8> {
+        let class = self.classes[y as usize * self.width as usize + x as usize];
+        class.checked_sub(1)
+    }
+}
+
+/// Checks that there are at least two breaks, in increasing order, and no more strata than
+/// fit a `u8`.
+pub fn check_breaks(breaks: &[f64]) -> Result<()> {
+    if breaks.len() < 2 {
+        bail!("--strata needs at least two boundaries, such as 0,500");
+    }
+    if breaks.len() > 256 {
+        bail!("--strata can't make more than 255 strata");
+    }
+    if let Some(pair) = breaks.windows(2).find(|pair| pair[0] >= pair[1]) {
+        bail!(
+            "--strata boundaries must increase, but {} is followed by {}",
+            pair[0],
+            pair[1]
+        );
+    }
+    Ok(())
+}
+
+/// Names each stratum by its range, as `MIN..MAX`.
+pub fn labels(breaks: &[f64]) -> Vec<String> {
+    breaks
+        .windows(2)
+        .map(|pair| format!("{}..{}", pair[0], pair[1]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{labels, Strata};
+    use crate::{
+        group::{Aggregation, LonLat},
+        processor::Processor,
+    };
+    use arrow_array::{Array, Float32Array, StringArray};
+    use std::fs::File;
+    use tiff::encoder::{colortype::GrayI32, TiffEncoder};
+
+    #[test]
+    fn test_strata() {
+        assert_eq!(labels(&[0.0, 500.0, 1000.0]), ["0..500", "500..1000"]);
+        let dir = std::env::temp_dir().join(format!("strata-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, pixels: &[i32]| {
+            let path = dir.join(name);
+            TiffEncoder::new(File::create(&path).unwrap())
+                .unwrap()
+                .write_image::<GrayI32>(4, 1, pixels)
+                .unwrap();
+            path
+        };
+        let population = write("population.tif", &[1, 2, 3, 4]);
+        let dem = write("dem.tif", &[100, 700, 600, 5000]);
+        let breaks = [0.0, 500.0, 1000.0];
+
+        let strata = Strata::load(&dem, &breaks, 4, 1).unwrap();
+        assert_eq!(strata.stratum(0, 0), Some(0));
+        assert_eq!(strata.stratum(2, 0), Some(1));
+        assert_eq!(strata.stratum(3, 0), None);
+        assert!(Strata::load(&dem, &breaks, 2, 2).is_err());
+        assert!(Strata::load(&dem, &[5.0, 1.0], 4, 1).is_err());
+
+        // Grouped into one cell covering the whole world, each stratum gets its own row,
+        // and the pixel above the last boundary is dropped.
+        let batch = Processor::builder()
+            .group(360.0)
+            .grid_origin(LonLat {
+                lon: -180.0,
+                lat: -90.0,
+            })
+            .agg(Aggregation::Max)
+            .stratify_by(&dem, breaks.to_vec())
+            .build()
+            .unwrap()
+            .to_batch(&population)
+            .unwrap();
+        let strata = batch.column_by_name("stratum").unwrap();
+        let strata = strata.as_any().downcast_ref::<StringArray>().unwrap();
+        let values = batch.column_by_name("value").unwrap();
+        let values = values.as_any().downcast_ref::<Float32Array>().unwrap();
+        let mut totals: Vec<(&str, f32)> = (0..batch.num_rows())
+            .map(|i| (strata.value(i), values.value(i)))
+            .collect();
+        totals.sort_by(|a, b| a.0.cmp(b.0));
+        assert_eq!(totals, [("0..500", 1.0), ("500..1000", 3.0)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
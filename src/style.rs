@@ -0,0 +1,228 @@
+//! Map styles for the output, with a color ramp over class breaks taken from the values
+//! actually written.
+//!
+//! A `.qml` path gets a QGIS graduated renderer and anything else a MapLibre style.
+
+use crate::{geometry::GeometryKind, json::Value, output::OutputFormat};
+use anyhow::{bail, Result};
+use arrow_array::{Array, Float32Array, RecordBatch};
+use std::path::Path;
+
+/// Viridis, sampled evenly; class colors are interpolated between these.
+pub const RAMP: [(u8, u8, u8); 7] = [
+    (68, 1, 84),
+    (68, 57, 131),
+    (49, 104, 142),
+    (33, 145, 140),
+    (53, 183, 121),
+    (144, 215, 67),
+    (253, 231, 37),
+];
+
+/// What the style draws: the output it was made for and how its values divide.
+pub struct Style<'a> {
+    pub data_path: &'a Path,
+    pub format: OutputFormat,
+    pub layer: &'a str,
+    pub geometry: GeometryKind,
+    /// Name of the value column.
+    pub value: &'a str,
+    /// Class boundaries, from the smallest value to the largest.
+    pub breaks: Vec<f64>,
+}
+
+/// Splits the batch's column named `value` into up to `classes` classes holding roughly
+/// equal numbers of rows. Classes that would repeat a boundary are merged, leaving a
+/// single class when every value is the same.
+pub fn quantile_breaks(batch: &RecordBatch, value: &str, classes: usize) -> Result<Vec<f64>> {
+    let Some(values) = batch
+        .column_by_name(value)
+        .and_then(|c| c.as_any().downcast_ref::<Float32Array>())
+    else {
+        bail!("Output has no value column to style");
+    };
+    let mut sorted: Vec<f64> = values.values().iter().map(|&v| v as f64).collect();
+    sorted.sort_by(f64::total_cmp);
+    if sorted.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut breaks: Vec<f64> = (0..=classes)
+        .map(|i| sorted[(i * (sorted.len() - 1)) / classes])
+        .collect();
+    breaks.dedup();
+    if let [only] = breaks[..] {
+        breaks.push(only);
+    }
+    Ok(breaks)
+}
+
+/// The color of class `index` of `count`, spreading the classes over the whole ramp.
+fn class_color(index: usize, count: usize) -> (u8, u8, u8) {
+    let position = if count > 1 {
+        index as f64 / (count - 1) as f64 * (RAMP.len() - 1) as f64
+    } else {
+        0.0
+    };
+    let low = position.floor() as usize;
+    let high = (low + 1).min(RAMP.len() - 1);
+    let t = position - low as f64;
+    let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    (
+        mix(RAMP[low].0, RAMP[high].0),
+        mix(RAMP[low].1, RAMP[high].1),
+        mix(RAMP[low].2, RAMP[high].2),
+    )
+}
+
+impl Style<'_> {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let text = match path.extension().and_then(|e| e.to_str()) {
+            Some("qml") => self.qgis(),
+            _ => self.maplibre().pretty(),
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    fn class_count(&self) -> usize {
+        self.breaks.len().saturating_sub(1).max(1)
+    }
+
+    /// A MapLibre style with the output as its only source, colored with a `step`
+    /// expression over `value`. Only vector tile outputs can be loaded by MapLibre as
+    /// they are; for the others the source's URL is the file, to be replaced by however
+    /// it is served.
+    fn maplibre(&self) -> Value {
+        let path = self.data_path.to_string_lossy();
+        let source = match self.format {
+            OutputFormat::Mvt => Value::object([
+                ("type", Value::from("vector")),
+                (
+                    "tiles",
+                    vec![format!("{}/{{z}}/{{x}}/{{y}}.mvt", path)].into(),
+                ),
+            ]),
+            OutputFormat::Mbtiles => Value::object([
+                ("type", Value::from("vector")),
+                ("url", format!("mbtiles://{}", path).into()),
+            ]),
+            _ => Value::object([
+                ("type", Value::from("vector")),
+                ("url", path.to_string().into()),
+            ]),
+        };
+
+        let css = |(r, g, b): (u8, u8, u8)| Value::from(format!("rgb({}, {}, {})", r, g, b));
+        let mut color = vec![
+            Value::from("step"),
+            Value::Array(vec!["get".into(), self.value.into()]),
+            css(class_color(0, self.class_count())),
+        ];
+        for (i, boundary) in self.breaks.iter().enumerate().skip(1) {
+            if i < self.breaks.len() - 1 {
+                color.push((*boundary).into());
+                color.push(css(class_color(i, self.class_count())));
+            }
+        }
+        let color = Value::Array(color);
+        let (layer_type, paint) = match self.geometry {
+            GeometryKind::Point => (
+                "circle",
+                Value::object([("circle-color", color), ("circle-radius", Value::from(3.0))]),
+            ),
+            GeometryKind::Cell => (
+                "fill",
+                Value::object([("fill-color", color), ("fill-opacity", Value::from(0.8))]),
+            ),
+        };
+
+        Value::object([
+            ("version", Value::from(8u32)),
+            ("name", self.layer.into()),
+            ("sources", Value::object([(self.layer, source)])),
+            (
+                "layers",
+                Value::Array(vec![Value::object([
+                    ("id", Value::from(self.layer)),
+                    ("type", layer_type.into()),
+                    ("source", self.layer.into()),
+                    ("source-layer", self.layer.into()),
+                    ("paint", paint),
+                ])]),
+            ),
+        ])
+    }
+
+    /// A QGIS layer style with a graduated renderer over the value column.
+    fn qgis(&self) -> String {
+        let attr = self
+            .value
+            .replace('&', "&amp;")
+            .replace('"', "&quot;")
+            .replace('<', "&lt;");
+        let (symbol_type, layer_class) = match self.geometry {
+            GeometryKind::Point => ("marker", "SimpleMarker"),
+            GeometryKind::Cell => ("fill", "SimpleFill"),
+        };
+        let mut ranges = String::new();
+        let mut symbols = String::new();
+        for (i, pair) in self.breaks.windows(2).enumerate() {
+            let (r, g, b) = class_color(i, self.class_count());
+            ranges.push_str(&format!(
+                "      <range lower=\"{0}\" upper=\"{1}\" symbol=\"{2}\" label=\"{0} - {1}\" \
+                 render=\"true\"/>\n",
+                pair[0], pair[1], i
+            ));
+            symbols.push_str(&format!(
+                "      <symbol type=\"{symbol_type}\" name=\"{i}\" alpha=\"1\" \
+                 clip_to_extent=\"1\" force_rhr=\"0\">\n\
+                 \x20       <layer class=\"{layer_class}\" enabled=\"1\" pass=\"0\" locked=\"0\">\n\
+                 \x20         <Option type=\"Map\">\n\
+                 \x20           <Option name=\"color\" type=\"QString\" value=\"{r},{g},{b},255\"/>\n\
+                 \x20           <Option name=\"outline_style\" type=\"QString\" value=\"no\"/>\n\
+                 \x20         </Option>\n\
+                 \x20       </layer>\n\
+                 \x20     </symbol>\n"
+            ));
+        }
+        format!(
+            "<!DOCTYPE qgis PUBLIC 'http://mrcc.com/qgis.dtd' 'SYSTEM'>\n\
+             <qgis version=\"3.28\" styleCategories=\"Symbology\">\n\
+             \x20 <renderer-v2 type=\"graduatedSymbol\" attr=\"{attr}\" \
+             graduatedMethod=\"GraduatedColor\" symbollevels=\"0\" enableorderby=\"0\" \
+             forceraster=\"0\">\n\
+             \x20   <ranges>\n{ranges}    </ranges>\n\
+             \x20   <symbols>\n{symbols}    </symbols>\n\
+             \x20 </renderer-v2>\n\
+             </qgis>\n"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{class_color, quantile_breaks, RAMP};
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_quantile_breaks() {
+        let values: Vec<f32> = (1..=9).map(|v| v as f32).chain([1.0, 1.0, 1.0]).collect();
+        let batch = RecordBatch::try_from_iter([(
+            "value",
+            Arc::new(Float32Array::from(values)) as ArrayRef,
+        )])
+        .unwrap();
+        assert_eq!(
+            quantile_breaks(&batch, "value", 4).unwrap(),
+            [1.0, 3.0, 6.0, 9.0]
+        );
+    }
+
+    #[test]
+    fn test_class_color() {
+        assert_eq!(class_color(0, 3), RAMP[0]);
+        assert_eq!(class_color(1, 3), RAMP[3]);
+        assert_eq!(class_color(2, 3), RAMP[6]);
+    }
+}
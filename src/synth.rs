@@ -0,0 +1,214 @@
+//! Generating rasters with known contents, for tests, benchmarks and reproducing bug
+//! reports without the data they came from.
+
+use anyhow::{anyhow, Context, Result};
+use std::{fs::File, io::BufWriter, path::PathBuf, str::FromStr};
+use tiff::encoder::{
+    colortype::{self, ColorType},
+    TiffEncoder, TiffKind, TiffValue,
+};
+
+#[derive(clap::Args)]
+pub struct SynthArgs {
+    /// What the pixel values look like.
+    #[arg(long = "pattern", value_enum, default_value_t = Pattern::Gradient)]
+    pattern: Pattern,
+    /// Dimensions of the raster as `WIDTHxHEIGHT`.
+    #[arg(long = "size", default_value = "1024x1024")]
+    size: Size,
+    /// Sample type of the pixels. Conversion reads them all, widening `u8` and `u16` to
+    /// `i32`.
+    #[arg(long = "dtype", value_enum, default_value_t = DataType::I32)]
+    dtype: DataType,
+    /// The largest value written. Patterns run from 0 up to this.
+    #[arg(long = "max", default_value_t = 100.0)]
+    max: f64,
+    /// Width in pixels of the squares of the `checker` pattern.
+    #[arg(long = "square", default_value_t = 64)]
+    square: u32,
+    /// Seed of the `random` pattern; the same seed gives the same raster.
+    #[arg(long = "seed", default_value_t = 0)]
+    seed: u64,
+    /// Where to write the raster.
+    #[arg(long = "output", short = 'o')]
+    output: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Pattern {
+    /// Rising from 0 in the top left corner to the maximum in the bottom right.
+    Gradient,
+    /// Squares alternating between 0 and the maximum.
+    Checker,
+    /// Independent values spread evenly between 0 and the maximum.
+    Random,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum DataType {
+    U8,
+    U16,
+    I32,
+    F32,
+    F64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Size {
+    width: u32,
+    height: u32,
+}
+
+impl FromStr for Size {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| anyhow!("Expected `WIDTHxHEIGHT` but got {}", s))?;
+        Ok(Size {
+            width: width.trim().parse()?,
+            height: height.trim().parse()?,
+        })
+    }
+}
+
+/// Samples a pattern's `f64` values are converted to, saturating at the type's limits.
+trait Sample: Copy {
+    fn from_f64(value: f64) -> Self;
+}
+
+macro_rules! sample {
+    ($($t:ty),*) => {
+        $(impl Sample for $t {
+            fn from_f64(value: f64) -> Self {
+                value as $t
+            }
+        })*
+    };
+}
+sample!(u8, u16, i32, f32, f64);
+
+/// Rows written per strip, matching the chunks conversion reads.
+const ROWS_PER_STRIP: u32 = 64;
+
+pub fn run(args: &SynthArgs) -> Result<()> {
+    let file = File::create(&args.output)
+        .with_context(|| format!("Could not create {}", args.output.to_string_lossy()))?;
+    let mut writer = BufWriter::new(file);
+    let bytes_per_sample = match args.dtype {
+        DataType::U8 => 1,
+        DataType::U16 => 2,
+        DataType::I32 | DataType::F32 => 4,
+        DataType::F64 => 8,
+    };
+    // Classic TIFF offsets are 32 bits, so larger rasters need BigTIFF.
+    let bytes = args.size.width as u64 * args.size.height as u64 * bytes_per_sample;
+    if bytes < u32::MAX as u64 / 2 {
+        write(TiffEncoder::new(&mut writer)?, args)
+    } else {
+        write(TiffEncoder::new_big(&mut writer)?, args)
+    }
+}
+
+fn write<W, K>(encoder: TiffEncoder<W, K>, args: &SynthArgs) -> Result<()>
+where
+    W: std::io::Write + std::io::Seek,
+    K: TiffKind,
+{
+    match args.dtype {
+        DataType::U8 => write_image::<_, _, colortype::Gray8>(encoder, args),
+        DataType::U16 => write_image::<_, _, colortype::Gray16>(encoder, args),
+        DataType::I32 => write_image::<_, _, colortype::GrayI32>(encoder, args),
+        DataType::F32 => write_image::<_, _, colortype::Gray32Float>(encoder, args),
+        DataType::F64 => write_image::<_, _, colortype::Gray64Float>(encoder, args),
+    }
+}
+
+fn write_image<W, K, C>(mut encoder: TiffEncoder<W, K>, args: &SynthArgs) -> Result<()>
+where
+    W: std::io::Write + std::io::Seek,
+    K: TiffKind,
+    C: ColorType,
+    C::Inner: Sample,
+    [C::Inner]: TiffValue,
+{
+    let Size { width, height } = args.size;
+    let mut image = encoder.new_image::<C>(width, height)?;
+    image.rows_per_strip(ROWS_PER_STRIP)?;
+    for top in (0..height).step_by(ROWS_PER_STRIP as usize) {
+        let bottom = (top + ROWS_PER_STRIP).min(height);
+        let strip: Vec<C::Inner> = (top..bottom)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| C::Inner::from_f64(value(args, x, y)))
+            .collect();
+        image.write_strip(&strip)?;
+    }
+    image.finish()?;
+    Ok(())
+}
+
+/// The pattern's value at pixel `(x, y)`.
+fn value(args: &SynthArgs, x: u32, y: u32) -> f64 {
+    let Size { width, height } = args.size;
+    match args.pattern {
+        Pattern::Gradient => {
+            let span = (width + height).saturating_sub(2).max(1) as f64;
+            ((x + y) as f64 / span * args.max).round()
+        }
+        Pattern::Checker => {
+            let square = args.square.max(1);
+            if (x / square + y / square).is_multiple_of(2) {
+                0.0
+            } else {
+                args.max
+            }
+        }
+        Pattern::Random => {
+            let index = y as u64 * width as u64 + x as u64;
+            let bits = splitmix64(args.seed ^ splitmix64(index));
+            // The top 53 bits give a uniform number in [0, 1).
+            ((bits >> 11) as f64 / (1u64 << 53) as f64 * (args.max + 1.0))
+                .floor()
+                .min(args.max)
+        }
+    }
+}
+
+pub(crate) fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{value, DataType, Pattern, Size, SynthArgs};
+
+    #[test]
+    fn test_patterns() {
+        let mut args = SynthArgs {
+            pattern: Pattern::Gradient,
+            size: "4x3".parse().unwrap(),
+            dtype: DataType::I32,
+            max: 10.0,
+            square: 2,
+            seed: 7,
+            output: "unused.tif".into(),
+        };
+        assert_eq!(value(&args, 0, 0), 0.0);
+        assert_eq!(value(&args, 3, 2), 10.0);
+        args.pattern = Pattern::Checker;
+        assert_eq!(value(&args, 1, 1), 0.0);
+        assert_eq!(value(&args, 2, 1), 10.0);
+        args.pattern = Pattern::Random;
+        let values: Vec<f64> = (0..4).map(|x| value(&args, x, 0)).collect();
+        assert!(values.iter().all(|v| (0.0..=10.0).contains(v)));
+        assert_eq!(
+            values,
+            (0..4).map(|x| value(&args, x, 0)).collect::<Vec<_>>()
+        );
+        assert!("8192".parse::<Size>().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Reading the files in a tar archive with the tar crate, which follows the GNU and pax
+//! extensions for long names and large sizes that archivers fall back on.
+
+use crate::{
+    archive,
+    failure::{Classified, FailureClass::BadInput},
+};
+use anyhow::Result;
+use std::io::{self, Read};
+
+/// Calls `visit` with the name of each file in the tar read from `reader` and a reader of
+/// its contents, until `visit` returns true or the archive ends. Files whose names lead
+/// outside the archive are skipped with a warning.
+pub(crate) fn each(
+    reader: impl Read,
+    mut visit: impl FnMut(&str, &mut dyn Read) -> Result<bool>,
+) -> Result<()> {
+    let mut tar = ::tar::Archive::new(reader);
+    for entry in tar.entries().map_err(damaged)? {
+        let mut entry = entry.map_err(damaged)?;
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_contiguous() {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let Some(name) = archive::member_name(&path) else {
+            eprintln!(
+                "Warning: skipping {} in the tar archive, as its name leads outside it",
+                path
+            );
+            continue;
+        };
+        if visit(&name, &mut entry)? {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Errors reading the archive itself, rather than the disk it is on, mean it is damaged
+/// or not a tar at all.
+fn damaged(error: io::Error) -> anyhow::Error {
+    match error.kind() {
+        io::ErrorKind::Other | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            anyhow::anyhow!(Classified::new(
+                BadInput,
+                format!("Not a readable tar archive: {}", error)
+            ))
+        }
+        _ => error.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::each;
+
+    /// A tar header block for a file of `size` bytes.
+    fn header(name: &str, kind: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    fn entry(tar: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+        tar.extend(header(name, kind, data.len()));
+        tar.extend(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    #[test]
+    fn test_each() {
+        let mut tar = vec![];
+        entry(&mut tar, "./a.tif", b'0', b"first");
+        entry(&mut tar, "dir", b'5', b"");
+        let long = format!("{}/b.tif", "d".repeat(120));
+        entry(&mut tar, "././@LongLink", b'L', long.as_bytes());
+        entry(&mut tar, "short-name", b'0', &[7; 600]);
+        entry(&mut tar, "pax", b'x', b"20 path=renamed.tif\n");
+        entry(&mut tar, "c.tif", b'0', b"third");
+        // Names that climb out of the archive, or start at the root, are skipped.
+        entry(&mut tar, "../up.tif", b'0', b"outside");
+        entry(&mut tar, "pax", b'x', b"22 path=/etc/root.tif\n");
+        entry(&mut tar, "d.tif", b'0', b"rooted");
+        tar.extend([0; 1024]);
+
+        let mut seen = vec![];
+        each(&tar[..], |name, data| {
+            let mut contents = vec![];
+            data.read_to_end(&mut contents)?;
+            seen.push((name.to_string(), contents.len()));
+            Ok(false)
+        })
+        .unwrap();
+        assert_eq!(
+            seen,
+            [
+                ("a.tif".to_string(), 5),
+                (long, 600),
+                ("renamed.tif".to_string(), 5)
+            ]
+        );
+        // Stopping early leaves the rest unread.
+        let mut first = vec![];
+        each(&tar[..], |name, _| {
+            first.push(name.to_string());
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(first, ["a.tif"]);
+        assert!(each(&b"not a tar"[..], |_, _| Ok(false)).is_err());
+        assert!(each(&[1; 512][..], |_, _| Ok(false)).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Output file names built from a template of `{placeholder}`s.
+
+use crate::time;
+use anyhow::{bail, Result};
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The placeholders a template may use.
+const PLACEHOLDERS: [&str; 10] = [
+    "stem",
+    "ext",
+    "format",
+    "band",
+    "group",
+    "s2",
+    "h3",
+    "zoom",
+    "date",
+    "timestamp",
+];
+
+/// A file name like `{stem}_{group}deg_{date}.parquet`.
+#[derive(Clone, Debug)]
+pub struct Template(String);
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        for name in placeholders(s)? {
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(
+                    "Unknown placeholder {{{}}} in output template, expected one of {}",
+                    name,
+                    PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+                );
+            }
+        }
+        Ok(Template(s.to_string()))
+    }
+}
+
+/// The names of the placeholders in `template`, in order.
+fn placeholders(template: &str) -> Result<Vec<&str>> {
+    let mut names = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed {{ in output template {}", template);
+        };
+        names.push(&rest[start + 1..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+    Ok(names)
+}
+
+impl Template {
+    /// Fills in the placeholders with `values`, failing for any used without a value.
+    pub fn render(&self, values: &[(&str, Option<String>)]) -> Result<String> {
+        let mut out = self.0.clone();
+        for name in placeholders(&self.0)? {
+            let value = match name {
+                "date" | "timestamp" => Some(now(name == "timestamp")),
+                _ => values
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .and_then(|(_, value)| value.clone()),
+            };
+            let Some(value) = value else {
+                bail!(
+                    "The output template uses {{{}}}, which has no value here",
+                    name
+                );
+            };
+            out = out.replacen(&format!("{{{}}}", name), &value, 1);
+        }
+        Ok(out)
+    }
+}
+
+/// The current UTC date as `YYYY-MM-DD`, or with `time` the date and time as
+/// `YYYYMMDDTHHMMSSZ`.
+fn now(time: bool) -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format_utc(seconds, time)
+}
+
+fn format_utc(seconds: u64, time: bool) -> String {
+    let (year, month, day) = time::civil_from_days((seconds / 86_400) as i64);
+    let of_day = seconds % 86_400;
+    if time {
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            year,
+            month,
+            day,
+            of_day / 3600,
+            of_day / 60 % 60,
+            of_day % 60
+        )
+    } else {
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_utc, Template};
+
+    #[test]
+    fn test_render() {
+        let template: Template = "{stem}_{group}deg.{ext}".parse().unwrap();
+        let values = [
+            ("stem", Some("ships".to_string())),
+            ("group", Some("0.5".to_string())),
+            ("ext", Some("parquet".to_string())),
+        ];
+        assert_eq!(template.render(&values).unwrap(), "ships_0.5deg.parquet");
+        assert!(template.render(&values[..1]).is_err());
+        assert!("{stem}_{nope}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0, false), "1970-01-01");
+        assert_eq!(format_utc(951_782_400 + 3_661, true), "20000229T010101Z");
+    }
+}
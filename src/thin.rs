@@ -0,0 +1,65 @@
+//! Dropping rows that would crowd vector outputs, keeping the most important ones.
+//!
+//! A row's importance is its value, so where rows compete the largest values survive.
+
+use anyhow::{bail, Result};
+use arrow_array::{Array, BooleanArray, Float32Array, RecordBatch};
+use arrow_select::filter::filter_record_batch;
+use std::collections::HashMap;
+
+/// Keeps only the most important row in each `tolerance` by `tolerance` square of the
+/// position columns' units.
+pub fn thin(batch: &RecordBatch, tolerance: f64) -> Result<RecordBatch> {
+    let column = |index: usize| -> Result<&Float32Array> {
+        match batch
+            .columns()
+            .get(index)
+            .and_then(|c| c.as_any().downcast_ref::<Float32Array>())
+        {
+            Some(array) => Ok(array),
+            None => bail!("Output has no float column {}", index),
+        }
+    };
+    let (xs, ys, values) = (column(0)?, column(1)?, column(2)?);
+
+    let mut best = HashMap::<(i64, i64), usize>::new();
+    for row in 0..batch.num_rows() {
+        let square = (
+            (xs.value(row) as f64 / tolerance).floor() as i64,
+            (ys.value(row) as f64 / tolerance).floor() as i64,
+        );
+        let kept = best.entry(square).or_insert(row);
+        if values.value(row) > values.value(*kept) {
+            *kept = row;
+        }
+    }
+    let mut keep = vec![false; batch.num_rows()];
+    for row in best.into_values() {
+        keep[row] = true;
+    }
+    Ok(filter_record_batch(batch, &BooleanArray::from(keep))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::thin;
+    use arrow_array::{Array, ArrayRef, Float32Array, RecordBatch};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_thin() {
+        let column = |values: Vec<f32>| Arc::new(Float32Array::from(values)) as ArrayRef;
+        let batch = RecordBatch::try_from_iter([
+            ("lon", column(vec![0.1, 0.4, 0.6, -0.2])),
+            ("lat", column(vec![0.1, 0.2, 0.1, 0.1])),
+            ("value", column(vec![3.0, 5.0, 1.0, 2.0])),
+        ])
+        .unwrap();
+        let thinned = thin(&batch, 0.5).unwrap();
+        let values = thinned.column(2);
+        let values = values.as_any().downcast_ref::<Float32Array>().unwrap();
+        let mut values: Vec<f32> = values.values().to_vec();
+        values.sort_by(f32::total_cmp);
+        assert_eq!(values, [1.0, 2.0, 5.0]);
+    }
+}
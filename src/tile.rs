@@ -0,0 +1,79 @@
+//! Slippy map (web mercator z/x/y) tile math.
+
+use std::f64::consts::PI;
+
+/// Web mercator is undefined at the poles; latitudes are clamped to the square map's edge.
+const MAX_LAT: f64 = 85.051_128_779_806_59;
+
+/// Returns the `(x, y)` of the tile at `zoom` containing the point.
+pub fn tile_for(lon: f64, lat: f64, zoom: u8) -> (u32, u32) {
+    let n = (1u64 << zoom) as f64;
+    let (x, y) = tile_coordinates(lon, lat, zoom);
+    let clamp = |v: f64| v.floor().clamp(0.0, n - 1.0) as u32;
+    (clamp(x), clamp(y))
+}
+
+/// Converts `(lon, lat)` to fractional tile coordinates at `zoom`, the inverse of
+/// `tile_position`.
+pub fn tile_coordinates(lon: f64, lat: f64, zoom: u8) -> (f64, f64) {
+    let n = (1u64 << zoom) as f64;
+    let lat = lat.clamp(-MAX_LAT, MAX_LAT).to_radians();
+    let x = (lon + 180.0) / 360.0 * n;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n;
+    (x, y)
+}
+
+/// Returns the center of a tile as `(lon, lat)`.
+pub fn tile_center(x: u32, y: u32, zoom: u8) -> (f64, f64) {
+    tile_position(x as f64 + 0.5, y as f64 + 0.5, zoom)
+}
+
+/// Returns the `(west, south, east, north)` edges of a tile.
+pub fn tile_bounds(x: u32, y: u32, zoom: u8) -> (f64, f64, f64, f64) {
+    let (west, north) = tile_position(x as f64, y as f64, zoom);
+    let (east, south) = tile_position(x as f64 + 1.0, y as f64 + 1.0, zoom);
+    (west, south, east, north)
+}
+
+/// Converts fractional tile coordinates to `(lon, lat)`.
+pub fn tile_position(x: f64, y: f64, zoom: u8) -> (f64, f64) {
+    let n = (1u64 << zoom) as f64;
+    let lon = x / n * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * y / n)).sinh().atan();
+    (lon, lat.to_degrees())
+}
+
+pub fn quadkey(x: u32, y: u32, zoom: u8) -> String {
+    (1..=zoom)
+        .rev()
+        .map(|level| {
+            let mask = 1 << (level - 1);
+            let digit = (x & mask != 0) as u8 + 2 * (y & mask != 0) as u8;
+            (b'0' + digit) as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quadkey, tile_center, tile_for};
+
+    #[test]
+    fn test_tile_for() {
+        assert_eq!(tile_for(0.0, 0.0, 0), (0, 0));
+        assert_eq!(tile_for(13.4050, 52.5200, 10), (550, 335));
+        assert_eq!(tile_for(180.0, -90.0, 2), (3, 3));
+    }
+
+    #[test]
+    fn test_tile_center() {
+        let (lon, lat) = tile_center(550, 335, 10);
+        assert_eq!(tile_for(lon, lat, 10), (550, 335));
+    }
+
+    #[test]
+    fn test_quadkey() {
+        assert_eq!(quadkey(3, 5, 3), "213");
+        assert_eq!(quadkey(0, 0, 0), "");
+    }
+}
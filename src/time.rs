@@ -0,0 +1,236 @@
+//! Timestamps for time-series inputs: read from file names or given outright, and
+//! written as UTC seconds.
+
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+/// A file name pattern locating a date in names like `emissions_2020_01.tif`.
+///
+/// `%Y` matches a four digit year, `%m`, `%d`, `%H`, `%M` and `%S` two digit months,
+/// days, hours, minutes and seconds, `%j` a three digit day of the year, `*` any run of
+/// characters and `%%` a percent sign. Everything else matches itself. Missing parts
+/// default to the start of the year, month or day.
+#[derive(Clone, Debug)]
+pub struct TimePattern(Vec<Token>);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Literal(char),
+    Field(char),
+    Any,
+}
+
+impl FromStr for TimePattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = vec![];
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '*' => Token::Any,
+                '%' => match chars.next() {
+                    Some('%') => Token::Literal('%'),
+                    Some(field @ ('Y' | 'm' | 'd' | 'H' | 'M' | 'S' | 'j')) => Token::Field(field),
+                    Some(other) => bail!("Unknown field %{} in time pattern {}", other, s),
+                    None => bail!("Time pattern {} ends with a lone %", s),
+                },
+                c => Token::Literal(c),
+            });
+        }
+        if !tokens.contains(&Token::Field('Y')) {
+            bail!("Time pattern {} has no %Y year", s);
+        }
+        Ok(TimePattern(tokens))
+    }
+}
+
+impl TimePattern {
+    /// The time in `text`, as seconds since 1970-01-01 UTC, if the whole of it matches.
+    pub fn parse(&self, text: &str) -> Option<i64> {
+        let mut fields = Fields::default();
+        matches(&self.0, text, &mut fields).then(|| fields.seconds())?
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    year: i64,
+    month: u32,
+    day: u32,
+    day_of_year: Option<u32>,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Fields {
+            year: 1970,
+            month: 1,
+            day: 1,
+            day_of_year: None,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+}
+
+impl Fields {
+    fn seconds(&self) -> Option<i64> {
+        let valid = (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 61;
+        if !valid {
+            return None;
+        }
+        let days = match self.day_of_year {
+            Some(day) if (1..=366).contains(&day) => {
+                days_from_civil(self.year, 1, 1) + day as i64 - 1
+            }
+            Some(_) => return None,
+            None => days_from_civil(self.year, self.month, self.day),
+        };
+        Some(days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64)
+    }
+}
+
+/// Matches `tokens` against all of `text`, filling in `fields`. `*` tries the shortest
+/// run first.
+fn matches(tokens: &[Token], text: &str, fields: &mut Fields) -> bool {
+    let Some((&token, rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+    match token {
+        Token::Literal(c) => text
+            .strip_prefix(c)
+            .is_some_and(|text| matches(rest, text, fields)),
+        Token::Any => text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .any(|i| {
+                let mut attempt = *fields;
+                matches(rest, &text[i..], &mut attempt) && {
+                    *fields = attempt;
+                    true
+                }
+            }),
+        Token::Field(field) => {
+            let width = match field {
+                'Y' => 4,
+                'j' => 3,
+                _ => 2,
+            };
+            let Some(digits) = text
+                .get(..width)
+                .filter(|d| d.bytes().all(|b| b.is_ascii_digit()))
+            else {
+                return false;
+            };
+            let value: u32 = digits.parse().expect("checked to be digits");
+            match field {
+                'Y' => fields.year = value as i64,
+                'm' => fields.month = value,
+                'd' => fields.day = value,
+                'j' => fields.day_of_year = Some(value),
+                'H' => fields.hour = value,
+                'M' => fields.minute = value,
+                _ => fields.second = value,
+            }
+            matches(rest, &text[width..], fields)
+        }
+    }
+}
+
+/// Parses a `--time` value: a year, `YYYY-MM`, `YYYY-MM-DD`, or a date and time like
+/// `YYYY-MM-DDTHH:MM:SSZ`, all in UTC.
+pub fn parse_time(s: &str) -> Result<i64> {
+    const FORMATS: [&str; 7] = [
+        "%Y",
+        "%Y-%m",
+        "%Y-%m-%d",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%dT%H:%MZ",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M:%SZ",
+    ];
+    FORMATS
+        .iter()
+        .find_map(|format| TimePattern::from_str(format).ok()?.parse(s.trim()))
+        .ok_or_else(|| anyhow::anyhow!("Expected a time like 2020-01-31T12:00:00Z but got {}", s))
+}
+
+/// Formats seconds since 1970-01-01 UTC as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn iso8601(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let of_day = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60
+    )
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian `(year, month, day)`, after
+/// Howard Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The inverse of [`civil_from_days`].
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{iso8601, parse_time, TimePattern};
+
+    #[test]
+    fn test_pattern() {
+        let pattern: TimePattern = "emissions_%Y_%m.tif".parse().unwrap();
+        let time = pattern.parse("emissions_2020_02.tif").unwrap();
+        assert_eq!(iso8601(time), "2020-02-01T00:00:00Z");
+        assert_eq!(pattern.parse("emissions_2020_13.tif"), None);
+        assert_eq!(pattern.parse("other_2020_02.tif"), None);
+
+        let pattern: TimePattern = "*_%Y%j*".parse().unwrap();
+        let time = pattern.parse("MOD_a_2021060_v2.tif").unwrap();
+        assert_eq!(iso8601(time), "2021-03-01T00:00:00Z");
+        assert!("no_year_%m".parse::<TimePattern>().is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1970-01-02").unwrap(), 86_400);
+        assert_eq!(
+            iso8601(parse_time("2024-02-29T23:59:59Z").unwrap()),
+            "2024-02-29T23:59:59Z"
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
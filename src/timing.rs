@@ -0,0 +1,325 @@
+//! `--timing`: how long each stage of a conversion took and how much it got through, for
+//! each input and the whole batch, so bottlenecks and regressions show without a
+//! profiler.
+//!
+//! Decoding and per-pixel work such as positioning and `--expr` run together across the
+//! thread pool, so the wall time they share is split in proportion to the thread time
+//! each took. Encoding is told apart from writing by the time spent in file writes,
+//! which SQLite outputs and tile directories don't report, so they count as encoding.
+
+use crate::json::Value;
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How `--timing` is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimingFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Loading the input file.
+    Read,
+    /// Decompressing strips or tiles into pixels.
+    Decode,
+    /// Positioning and filtering pixels, and the steps on the finished table.
+    Transform,
+    /// Grouping pixels into cells, or resampling them into blocks.
+    Group,
+    /// Turning the table into the output format.
+    Encode,
+    /// Writing the output to disk.
+    Write,
+}
+
+const STAGES: [Stage; 6] = [
+    Stage::Read,
+    Stage::Decode,
+    Stage::Transform,
+    Stage::Group,
+    Stage::Encode,
+    Stage::Write,
+];
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Decode => "decode",
+            Stage::Transform => "transform",
+            Stage::Group => "group",
+            Stage::Encode => "encode",
+            Stage::Write => "write",
+        }
+    }
+
+    /// What the stage's throughput is counted in.
+    fn unit(self) -> &'static str {
+        match self {
+            Stage::Read | Stage::Write => "bytes",
+            Stage::Decode => "pixels",
+            Stage::Transform | Stage::Group | Stage::Encode => "rows",
+        }
+    }
+}
+
+/// Wall time and amount handled by each stage of one or more conversions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timing {
+    stages: [(Duration, u64); STAGES.len()],
+}
+
+impl Timing {
+    pub fn add(&mut self, stage: Stage, time: Duration, amount: u64) {
+        let entry = &mut self.stages[stage as usize];
+        entry.0 += time;
+        entry.1 += amount;
+    }
+
+    pub fn merge(&mut self, other: &Timing) {
+        for (stage, &(time, amount)) in STAGES.iter().zip(&other.stages) {
+            self.add(*stage, time, amount);
+        }
+    }
+
+    pub fn get(&self, stage: Stage) -> (Duration, u64) {
+        self.stages[stage as usize]
+    }
+
+    pub fn total(&self) -> Duration {
+        self.stages.iter().map(|(time, _)| *time).sum()
+    }
+
+    fn text(&self, title: &str) -> String {
+        let mut text = format!("{}\n", title);
+        for (stage, &(time, amount)) in STAGES.iter().zip(&self.stages) {
+            let rate = amount as f64 / time.as_secs_f64();
+            let rate = match rate {
+                rate if !rate.is_finite() => "-".to_string(),
+                rate if rate >= 1e9 => format!("{:.1} G{}/s", rate / 1e9, stage.unit()),
+                rate if rate >= 1e6 => format!("{:.1} M{}/s", rate / 1e6, stage.unit()),
+                rate if rate >= 1e3 => format!("{:.1} k{}/s", rate / 1e3, stage.unit()),
+                rate => format!("{:.1} {}/s", rate, stage.unit()),
+            };
+            text.push_str(&format!(
+                "  {:<10} {:>10.3}s {:>14} {:<6} {}\n",
+                stage.name(),
+                time.as_secs_f64(),
+                amount,
+                stage.unit(),
+                rate
+            ));
+        }
+        text.push_str(&format!(
+            "  {:<10} {:>10.3}s\n",
+            "total",
+            self.total().as_secs_f64()
+        ));
+        text
+    }
+
+    fn json(&self) -> [(&'static str, Value); 2] {
+        let stages = STAGES
+            .iter()
+            .zip(&self.stages)
+            .map(|(stage, &(time, amount))| {
+                let seconds = time.as_secs_f64();
+                let rate = amount as f64 / seconds;
+                let entry = Value::object([
+                    ("seconds", Value::from(seconds)),
+                    (stage.unit(), Value::from(amount)),
+                    (
+                        "per_second",
+                        match rate.is_finite() {
+                            true => rate.into(),
+                            false => Value::Null,
+                        },
+                    ),
+                ]);
+                (stage.name(), entry)
+            });
+        [
+            ("stages", Value::object(stages)),
+            ("seconds", Value::from(self.total().as_secs_f64())),
+        ]
+    }
+}
+
+/// Reports the timing of each input and, when there is more than one, of them all.
+pub fn report(timings: &[(PathBuf, Timing)], format: TimingFormat) -> String {
+    let mut total = Timing::default();
+    for (_, timing) in timings {
+        total.merge(timing);
+    }
+    match format {
+        TimingFormat::Text => {
+            let mut text: String = timings
+                .iter()
+                .map(|(input, timing)| timing.text(&input.to_string_lossy()))
+                .collect();
+            if timings.len() > 1 {
+                text.push_str(&total.text(&format!("all {} inputs", timings.len())));
+            }
+            text
+        }
+        TimingFormat::Json => {
+            let inputs = timings.iter().map(|(input, timing)| {
+                let input = ("input", Value::from(input.to_string_lossy().to_string()));
+                Value::object(std::iter::once(input).chain(timing.json()))
+            });
+            let report = Value::object([
+                ("inputs", Value::Array(inputs.collect())),
+                ("total", Value::object(total.json())),
+            ]);
+            format!("{}\n", report.pretty())
+        }
+    }
+}
+
+/// Time summed over the threads that add to it.
+#[derive(Default)]
+pub struct Clock(AtomicU64);
+
+impl Clock {
+    /// Runs `work`, adding the time it takes.
+    pub fn time<T>(&self, work: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = work();
+        self.0
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Thread time spent decoding pixels and visiting them once decoded, with the number of
+/// pixels visited.
+#[derive(Default)]
+pub struct PixelClocks {
+    pub decoding: Clock,
+    pub visiting: Clock,
+    pub pixels: AtomicU64,
+}
+
+impl PixelClocks {
+    /// Splits `wall`, the time decoding and visiting took together, between them.
+    pub fn split(&self, wall: Duration) -> (Duration, Duration) {
+        let (decoding, visiting) = (self.decoding.total(), self.visiting.total());
+        let busy = decoding + visiting;
+        if busy.is_zero() {
+            return (wall, Duration::ZERO);
+        }
+        let decoding = wall.mul_f64(decoding.as_secs_f64() / busy.as_secs_f64());
+        (decoding, wall.saturating_sub(decoding))
+    }
+
+    pub fn pixels(&self) -> u64 {
+        self.pixels.load(Ordering::Relaxed)
+    }
+}
+
+thread_local! {
+    static WRITING: Cell<(Duration, u64)> = const { Cell::new((Duration::ZERO, 0)) };
+}
+
+/// Time this thread has spent in writes to [`TimedFile`]s, and the bytes written.
+pub fn writing() -> (Duration, u64) {
+    WRITING.with(Cell::get)
+}
+
+/// A file, or another writer such as an upload, that adds the time its writes and seeks
+/// take, and what it writes, to [`writing`].
+pub struct TimedFile<W = File>(W);
+
+impl TimedFile {
+    pub fn create(path: &Path) -> std::io::Result<TimedFile> {
+        Ok(TimedFile(File::create(path)?))
+    }
+}
+
+impl<W> TimedFile<W> {
+    pub fn new(inner: W) -> TimedFile<W> {
+        TimedFile(inner)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+
+    fn time<T>(bytes: impl FnOnce(&T) -> usize, work: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = work();
+        let (time, written) = writing();
+        let written = written + bytes(&result) as u64;
+        WRITING.with(|writing| writing.set((time + started.elapsed(), written)));
+        result
+    }
+}
+
+impl<W: Write> Write for TimedFile<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = |result: &std::io::Result<usize>| *result.as_ref().unwrap_or(&0);
+        TimedFile::<W>::time(written, || self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        TimedFile::<W>::time(|_| 0, || self.0.flush())
+    }
+}
+
+impl<W: Seek> Seek for TimedFile<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        TimedFile::<W>::time(|_| 0, || self.0.seek(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{report, PixelClocks, Stage, Timing, TimingFormat};
+    use crate::json::{self, Value};
+    use std::{path::PathBuf, time::Duration};
+
+    #[test]
+    fn test_timing() {
+        let mut a = Timing::default();
+        a.add(Stage::Read, Duration::from_secs(2), 4_000_000);
+        a.add(Stage::Decode, Duration::from_secs(1), 100);
+        let mut b = Timing::default();
+        b.add(Stage::Read, Duration::from_secs(2), 2_000_000);
+        let timings = vec![(PathBuf::from("a.tif"), a), (PathBuf::from("b.tif"), b)];
+
+        let text = report(&timings, TimingFormat::Text);
+        assert!(text.contains("all 2 inputs"));
+        assert!(text.contains("2.0 Mbytes/s"));
+        let json = json::parse(&report(&timings, TimingFormat::Json)).unwrap();
+        let read = json
+            .get("total")
+            .and_then(|total| total.get("stages"))
+            .and_then(|stages| stages.get("read"))
+            .unwrap();
+        assert_eq!(read.get("seconds").and_then(Value::as_f64), Some(4.0));
+        assert_eq!(read.get("per_second").and_then(Value::as_f64), Some(1.5e6));
+
+        // Threads spent three times as long decoding as visiting.
+        let clocks = PixelClocks::default();
+        clocks
+            .decoding
+            .time(|| std::thread::sleep(Duration::from_millis(30)));
+        clocks
+            .visiting
+            .time(|| std::thread::sleep(Duration::from_millis(10)));
+        let (decoding, visiting) = clocks.split(Duration::from_secs(4));
+        assert!(decoding > visiting * 2 && decoding + visiting == Duration::from_secs(4));
+    }
+}
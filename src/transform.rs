@@ -0,0 +1,184 @@
+//! Changes applied to pixels as they are read and to the finished table, chained with
+//! `--transform` or added to a [`crate::processor::ProcessorBuilder`].
+
+use anyhow::{anyhow, bail, Result};
+use arrow_array::RecordBatch;
+use std::{fmt, str::FromStr};
+
+/// A step of the conversion that library users can implement to mask, reclassify or
+/// convert values. Both methods default to leaving their input unchanged.
+pub trait Transform: Send + Sync {
+    /// Changes the value of the pixel at `lon`, `lat`, or drops it by returning `None`.
+    /// Called after scaling and `--expr` and before the value filters, from many threads.
+    fn pixel(&self, lon: f64, lat: f64, value: f64) -> Option<f64> {
+        let _ = (lon, lat);
+        Some(value)
+    }
+
+    /// Changes the table once pixels are grouped and thinned, before it is written.
+    fn batch(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        Ok(batch)
+    }
+
+    /// Describes the transform for `--explain`.
+    fn describe(&self) -> String {
+        "custom transform".to_string()
+    }
+}
+
+/// The transforms `--transform` can chain.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Builtin {
+    /// `scale:F` multiplies values by `F`, such as to convert units.
+    Scale(f64),
+    /// `offset:F` adds `F` to values.
+    Offset(f64),
+    /// `clamp:MIN,MAX` limits values to a range.
+    Clamp(f64, f64),
+    /// `nodata:V` drops pixels whose value is `V`.
+    Nodata(f64),
+    /// `reclass:MIN..MAX=V,...` replaces values from `MIN` up to but not including `MAX`
+    /// with `V`, keeping values outside every range.
+    Reclass(Vec<(f64, f64, f64)>),
+}
+
+impl FromStr for Builtin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, args) = s.split_once(':').ok_or_else(|| {
+            anyhow!(
+                "Expected a transform like `scale:0.001` but got {}; the transforms are scale, offset, clamp, nodata and reclass",
+                s
+            )
+        })?;
+        let number = |text: &str| -> Result<f64> {
+            text.trim()
+                .parse()
+                .map_err(|_| anyhow!("Expected a number in transform {} but got {}", s, text))
+        };
+        Ok(match name {
+            "scale" => Builtin::Scale(number(args)?),
+            "offset" => Builtin::Offset(number(args)?),
+            "nodata" => Builtin::Nodata(number(args)?),
+            "clamp" => {
+                let (min, max) = args
+                    .split_once(',')
+                    .ok_or_else(|| anyhow!("Expected `clamp:MIN,MAX` but got {}", s))?;
+                let (min, max) = (number(min)?, number(max)?);
+                if min > max {
+                    bail!("The clamp's minimum {} is above its maximum {}", min, max);
+                }
+                Builtin::Clamp(min, max)
+            }
+            "reclass" => Builtin::Reclass(
+                args.split(',')
+                    .map(|class| {
+                        let (range, value) = class
+                            .split_once('=')
+                            .ok_or_else(|| anyhow!("Expected `MIN..MAX=V` but got {}", class))?;
+                        let (min, max) = range
+                            .split_once("..")
+                            .ok_or_else(|| anyhow!("Expected `MIN..MAX=V` but got {}", class))?;
+                        Ok((number(min)?, number(max)?, number(value)?))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            other => bail!(
+                "Unknown transform {}; the transforms are scale, offset, clamp, nodata and reclass",
+                other
+            ),
+        })
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Builtin::Scale(factor) => write!(f, "scale:{}", factor),
+            Builtin::Offset(offset) => write!(f, "offset:{}", offset),
+            Builtin::Clamp(min, max) => write!(f, "clamp:{},{}", min, max),
+            Builtin::Nodata(nodata) => write!(f, "nodata:{}", nodata),
+            Builtin::Reclass(classes) => {
+                let classes: Vec<String> = classes
+                    .iter()
+                    .map(|(min, max, value)| format!("{}..{}={}", min, max, value))
+                    .collect();
+                write!(f, "reclass:{}", classes.join(","))
+            }
+        }
+    }
+}
+
+impl Transform for Builtin {
+    fn pixel(&self, _lon: f64, _lat: f64, value: f64) -> Option<f64> {
+        Some(match self {
+            Builtin::Scale(factor) => value * factor,
+            Builtin::Offset(offset) => value + offset,
+            Builtin::Clamp(min, max) => value.clamp(*min, *max),
+            Builtin::Nodata(nodata) if value == *nodata => return None,
+            Builtin::Nodata(_) => value,
+            Builtin::Reclass(classes) => classes
+                .iter()
+                .find(|(min, max, _)| (*min..*max).contains(&value))
+                .map_or(value, |(_, _, class)| *class),
+        })
+    }
+
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Builtin, Transform};
+    use crate::processor::{Processor, ProcessorBuilder};
+    use anyhow::Result;
+    use arrow_array::RecordBatch;
+    use std::fs::File;
+    use tiff::encoder::{colortype::GrayI32, TiffEncoder};
+
+    /// Drops every pixel west of the prime meridian and then the table's last row.
+    struct EastButLast;
+
+    impl Transform for EastButLast {
+        fn pixel(&self, lon: f64, _lat: f64, value: f64) -> Option<f64> {
+            (lon >= 0.0).then_some(value)
+        }
+
+        fn batch(&self, batch: RecordBatch) -> Result<RecordBatch> {
+            Ok(batch.slice(0, batch.num_rows().saturating_sub(1)))
+        }
+    }
+
+    #[test]
+    fn test_transforms() {
+        let reclass: Builtin = "reclass:0..10=1,10..100=2".parse().unwrap();
+        assert_eq!(reclass.to_string(), "reclass:0..10=1,10..100=2");
+        assert_eq!(reclass.pixel(0.0, 0.0, 5.0), Some(1.0));
+        assert_eq!(reclass.pixel(0.0, 0.0, 100.0), Some(100.0));
+        let clamp: Builtin = "clamp:-1,1".parse().unwrap();
+        assert_eq!(clamp.pixel(0.0, 0.0, 4.0), Some(1.0));
+        assert_eq!(Builtin::Nodata(3.0).pixel(0.0, 0.0, 3.0), None);
+        assert!("clamp:2,1".parse::<Builtin>().is_err());
+        assert!("round:2".parse::<Builtin>().is_err());
+
+        let path = std::env::temp_dir().join(format!("transform-test-{}.tif", std::process::id()));
+        TiffEncoder::new(File::create(&path).unwrap())
+            .unwrap()
+            .write_image::<GrayI32>(4, 1, &[1, 2, 3, 4])
+            .unwrap();
+        let rows = |builder: ProcessorBuilder| {
+            builder.build().unwrap().to_batch(&path).unwrap().num_rows()
+        };
+        // Scaled to 0.5, 1, 1.5 and 2, the first falls below the filter's minimum.
+        let builder = Processor::builder()
+            .transform(Builtin::Scale(0.5))
+            .min_value(1.0);
+        assert_eq!(rows(builder), 3);
+        // The two pixels in the east half of the world's grid, less the last row.
+        assert_eq!(rows(Processor::builder().transform(EastButLast)), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+//! Writing parquet outputs straight to object storage with `--output s3://bucket/prefix/`
+//! or `gs://bucket/prefix/`, so batch jobs on workers without room for their results
+//! never put them on disk. Each output is piped into `aws s3 cp` or `gcloud storage cp`,
+//! which must be on the `PATH` and send it as a multipart upload, retrying parts that
+//! fail. An output built whole is encoded and sent again if its upload still fails.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    fmt,
+    io::Write,
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+};
+
+/// Times an output built whole is sent before its conversion fails.
+const ATTEMPTS: u32 = 3;
+
+/// Times the CLIs try each request of an upload, such as sending one part, unless their
+/// own settings say otherwise.
+const REQUEST_ATTEMPTS: &str = "10";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Store {
+    S3,
+    Gcs,
+}
+
+impl Store {
+    fn scheme(self) -> &'static str {
+        match self {
+            Store::S3 => "s3://",
+            Store::Gcs => "gs://",
+        }
+    }
+
+    fn of(url: &str) -> Option<Store> {
+        [Store::S3, Store::Gcs]
+            .into_iter()
+            .find(|store| url.starts_with(store.scheme()))
+    }
+}
+
+/// A bucket, and the prefix the names of outputs uploaded to it are put after.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Destination {
+    store: Store,
+    bucket: String,
+    prefix: String,
+}
+
+impl FromStr for Destination {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(store) = Store::of(s) else {
+            bail!("Expected an `s3://` or `gs://` URL but got {}", s);
+        };
+        let path = &s[store.scheme().len()..];
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            bail!("{} names no bucket", s);
+        }
+        // A prefix is a directory of the bucket, whether or not it ends with a slash.
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+        Ok(Destination {
+            store,
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", self.store.scheme(), self.bucket, self.prefix)
+    }
+}
+
+impl Destination {
+    /// The URL of the object `name` is uploaded to.
+    pub fn url(&self, name: &str) -> String {
+        format!("{}{}", self, name)
+    }
+}
+
+/// Whether `path` is the URL of an object to upload to rather than a local file.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().and_then(Store::of).is_some()
+}
+
+/// An upload sending what is written to it to the object at a URL. One dropped before
+/// it is finished is abandoned, so no object is left holding part of an output.
+pub struct Upload {
+    url: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Upload {
+    pub fn start(url: &Path) -> Result<Upload> {
+        let url = url.to_string_lossy().to_string();
+        let mut command = match Store::of(&url) {
+            Some(Store::S3) => aws(&["s3", "cp", "--only-show-errors", "-", &url]),
+            Some(Store::Gcs) => {
+                let mut command = Command::new("gcloud");
+                command.args(["storage", "cp", "--no-user-output-enabled", "-", &url]);
+                if std::env::var_os("CLOUDSDK_STORAGE_MAX_RETRIES").is_none() {
+                    command.env("CLOUDSDK_STORAGE_MAX_RETRIES", REQUEST_ATTEMPTS);
+                }
+                command
+            }
+            None => bail!("Expected an `s3://` or `gs://` URL but got {}", url),
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| {
+                let program = command.get_program().to_string_lossy().to_string();
+                format!("Could not run {} to upload {}", program, url)
+            })?;
+        let stdin = child.stdin.take();
+        Ok(Upload { url, child, stdin })
+    }
+
+    /// Waits for everything written to be stored, failing if the upload did.
+    pub fn finish(mut self) -> Result<()> {
+        // Closing the pipe tells the CLI the output is complete.
+        drop(self.stdin.take());
+        let status = self.child.wait()?;
+        if !status.success() {
+            bail!("Uploading {} failed: {}", self.url, status);
+        }
+        Ok(())
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stdin.as_mut().expect("unfinished upload").write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stdin.as_mut().expect("unfinished upload").flush()
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Killed before its pipe closes, the CLI never completes the upload.
+        if self.stdin.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// The `aws` CLI run with `args`, trying each request as many times as uploads do.
+pub fn aws(args: &[&str]) -> Command {
+    let mut command = Command::new("aws");
+    command.args(args);
+    if std::env::var_os("AWS_MAX_ATTEMPTS").is_none() {
+        command
+            .env("AWS_RETRY_MODE", "standard")
+            .env("AWS_MAX_ATTEMPTS", REQUEST_ATTEMPTS);
+    }
+    command
+}
+
+/// Runs `write`, which writes a whole output to `path`, again if it fails while `path`
+/// is being uploaded to.
+pub fn retrying(path: &Path, mut write: impl FnMut() -> Result<()>) -> Result<()> {
+    let attempts = if is_url(path) { ATTEMPTS } else { 1 };
+    let mut attempt = 1;
+    loop {
+        match write() {
+            Err(e) if attempt < attempts => {
+                eprintln!("Warning: {:#}; trying again", e);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_url, Destination};
+    use std::path::Path;
+
+    #[test]
+    fn test_destination() {
+        let destination: Destination = "s3://bucket/runs/2023".parse().unwrap();
+        assert_eq!(destination.to_string(), "s3://bucket/runs/2023/");
+        assert_eq!(
+            destination.url("a.parquet"),
+            "s3://bucket/runs/2023/a.parquet"
+        );
+        let destination: Destination = "gs://bucket".parse().unwrap();
+        assert_eq!(destination.url("a.parquet"), "gs://bucket/a.parquet");
+        assert!(is_url(Path::new(&destination.url("a.parquet"))));
+        assert!(!is_url(Path::new("out/a.parquet")));
+        assert!("s3:///prefix".parse::<Destination>().is_err());
+        assert!("https://bucket/prefix".parse::<Destination>().is_err());
+    }
+}
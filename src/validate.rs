@@ -0,0 +1,97 @@
+//! Pre-flight checks that a batch's inputs will convert, without converting them.
+
+use crate::{
+    load_tif_contents,
+    output::{self, Codec},
+    planar,
+    raster::{self, ChunkSize, Layout},
+};
+use anyhow::{bail, Result};
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct ValidateArgs {
+    /// The `.tif` files or `.zip` archives to check.
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Codec the parquet size estimate assumes.
+    #[arg(long = "compression", value_enum, default_value_t = Codec::Uncompressed)]
+    compression: Codec,
+}
+
+/// Bytes per output row: `lon`, `lat` and `value` as 32 bit floats.
+const ROW_BYTES: u64 = 12;
+
+/// What was learned about an input that would convert.
+struct Check {
+    width: u32,
+    height: u32,
+    estimated_rows: u64,
+}
+
+pub fn run(args: &ValidateArgs) -> Result<()> {
+    let mut failures = vec![];
+    for input in &args.inputs {
+        match check(input) {
+            Ok(check) => println!(
+                "ok    {}: {}x{}, about {} rows, about {} bytes of parquet",
+                input.to_string_lossy(),
+                check.width,
+                check.height,
+                check.estimated_rows,
+                output::estimate_raw_parquet_size(
+                    check.estimated_rows * ROW_BYTES,
+                    args.compression
+                )
+            ),
+            Err(err) => {
+                println!("FAIL  {}: {:#}", input.to_string_lossy(), err);
+                failures.push(input.to_string_lossy().to_string());
+            }
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "{} of {} inputs would fail: {}",
+            failures.len(),
+            args.inputs.len(),
+            failures.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Opens the input as a conversion would and decodes its first strip or tile, estimating
+/// the output's rows from the share of that chunk's pixels that would be kept.
+fn check(input: &Path) -> Result<Check> {
+    let mut contents = load_tif_contents(input)?;
+    if planar::separate_bands(&contents)?.is_some() {
+        contents = planar::band_view(&contents, 0)?;
+    }
+    let mut decoder = Decoder::new(Cursor::new(&contents))?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let (kept, pixels) = raster::fold_pixels(
+        &contents,
+        &layout,
+        ChunkSize::Tiles(1),
+        |x, y, _, _| (x, y) == (0, 0),
+        |_| {},
+        || (0u64, 0u64),
+        |(kept, pixels), _, _, value| {
+            *kept += (value > 0.0 && value.is_finite()) as u64;
+            *pixels += 1;
+        },
+        |left, right| (left.0 + right.0, left.1 + right.1),
+    )?;
+    let share = kept as f64 / pixels.max(1) as f64;
+    Ok(Check {
+        width,
+        height,
+        estimated_rows: (share * width as f64 * height as f64).round() as u64,
+    })
+}
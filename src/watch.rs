@@ -0,0 +1,210 @@
+//! Converting `.tif` and `.zip` files as they arrive in a directory.
+//!
+//! The directory is listed again whenever the operating system reports a change in it,
+//! through notify, and at least every poll interval besides, as network mounts may report
+//! no changes at all. A file is only taken once its size and modification time have
+//! stopped changing for the debounce period, which lets downloads finish first. Each file is handled once
+//! for each version of it: one that fails is reported and tried again only if it changes.
+
+use ::notify::{RecursiveMode, Watcher as _};
+use anyhow::{Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// What a file looked like when it was last listed.
+#[derive(Clone, Copy, PartialEq)]
+struct Version {
+    len: u64,
+    modified: SystemTime,
+}
+
+/// Tracks the files of a watched directory between listings.
+pub struct Watcher {
+    dir: PathBuf,
+    debounce: Duration,
+    /// Files waiting to settle, with when their current version was first seen.
+    pending: HashMap<PathBuf, (Version, Instant)>,
+    /// The version of each file that has already been handed out.
+    handled: HashMap<PathBuf, Version>,
+}
+
+impl Watcher {
+    pub fn new(dir: &Path, debounce: Duration) -> Watcher {
+        Watcher {
+            dir: dir.to_path_buf(),
+            debounce,
+            pending: HashMap::new(),
+            handled: HashMap::new(),
+        }
+    }
+
+    /// Lists the directory and returns the files that have settled since the last call,
+    /// in name order.
+    pub fn poll(&mut self, now: Instant) -> Result<Vec<PathBuf>> {
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("Could not list {}", self.dir.display()))?;
+        let mut present = HashSet::new();
+        let mut ready = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            let wanted = matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("tif" | "zip")
+            );
+            // The file may have been moved away since it was listed.
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            if !wanted || !metadata.is_file() {
+                continue;
+            }
+            present.insert(path.clone());
+            let version = Version {
+                len: metadata.len(),
+                modified: metadata.modified()?,
+            };
+            if self.handled.get(&path) == Some(&version) {
+                continue;
+            }
+            let first_seen = match self.pending.get(&path) {
+                Some(&(pending, seen)) if pending == version => seen,
+                _ => {
+                    self.pending.insert(path.clone(), (version, now));
+                    now
+                }
+            };
+            if now.duration_since(first_seen) >= self.debounce {
+                self.pending.remove(&path);
+                self.handled.insert(path.clone(), version);
+                ready.push(path);
+            }
+        }
+        // Forget files that are gone, so one arriving later under the same name is new.
+        self.pending.retain(|path, _| present.contains(path));
+        self.handled.retain(|path, _| present.contains(path));
+        ready.sort();
+        Ok(ready)
+    }
+
+    /// How long after `now` the first file waiting to settle will have, if any is.
+    pub fn next_settled(&self, now: Instant) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|&(_, seen)| (seen + self.debounce).saturating_duration_since(now))
+            .min()
+    }
+}
+
+/// Watches `dir` until the process is stopped, passing each settled file to `convert`,
+/// which returns whether it converted the file rather than leaving it to another worker.
+/// Converted files are moved into `done_dir` if one is given; errors converting a file are
+/// printed and don't stop the watch. The directory is listed on each change reported in
+/// it, and every `poll_interval` without one.
+pub fn run(
+    dir: &Path,
+    debounce: Duration,
+    poll_interval: Duration,
+    done_dir: Option<&Path>,
+    mut convert: impl FnMut(&Path) -> Result<bool>,
+) -> Result<()> {
+    if let Some(done_dir) = done_dir {
+        fs::create_dir_all(done_dir)
+            .with_context(|| format!("Could not create {}", done_dir.display()))?;
+    }
+    let (changed, changes) = mpsc::channel();
+    // Kept alive for as long as the watch runs, as dropping it stops the reports.
+    let _events = ::notify::recommended_watcher(move |_| {
+        let _ = changed.send(());
+    })
+    .and_then(|mut events| {
+        events.watch(dir, RecursiveMode::NonRecursive)?;
+        Ok(events)
+    })
+    .map_err(|err| {
+        eprintln!(
+            "Warning: changes in {} can't be watched for ({}), so it is listed every {:?}",
+            dir.display(),
+            err,
+            poll_interval
+        );
+    });
+    let mut watcher = Watcher::new(dir, debounce);
+    loop {
+        for path in watcher.poll(Instant::now())? {
+            let result = convert(&path).and_then(|converted| match done_dir {
+                Some(done_dir) if converted => move_into(&path, done_dir),
+                _ => Ok(()),
+            });
+            if let Err(err) = result {
+                eprintln!("{}: {:#}", path.display(), err);
+            }
+        }
+        let wait = match watcher.next_settled(Instant::now()) {
+            Some(settled) => settled.min(poll_interval),
+            None => poll_interval,
+        };
+        // Any change wakes the watch early; a burst of them is taken as one.
+        if changes.recv_timeout(wait).is_ok() {
+            while changes.try_recv().is_ok() {}
+        }
+    }
+}
+
+/// Moves a converted file into `dir`, copying it when `dir` is on another filesystem.
+fn move_into(path: &Path, dir: &Path) -> Result<()> {
+    let target = dir.join(path.file_name().context("Watched file has no name")?);
+    if fs::rename(path, &target).is_err() {
+        fs::copy(path, &target)
+            .with_context(|| format!("Could not move {} to {}", path.display(), dir.display()))?;
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watcher;
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_poll() {
+        let dir = std::env::temp_dir().join(format!("watch-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tif = dir.join("a.tif");
+        fs::write(&tif, b"part").unwrap();
+        fs::write(dir.join("notes.txt"), b"ignored").unwrap();
+
+        let debounce = Duration::from_secs(10);
+        let mut watcher = Watcher::new(&dir, debounce);
+        let start = Instant::now();
+        assert!(watcher.poll(start).unwrap().is_empty());
+        // Still growing, so the wait starts again.
+        fs::write(&tif, b"partial").unwrap();
+        assert!(watcher.poll(start + debounce).unwrap().is_empty());
+        assert_eq!(
+            watcher.poll(start + debounce * 2).unwrap(),
+            vec![tif.clone()]
+        );
+        // Handed out once until it changes.
+        assert!(watcher.poll(start + debounce * 3).unwrap().is_empty());
+        assert_eq!(watcher.next_settled(start), None);
+        fs::write(&tif, b"replaced with more").unwrap();
+        assert!(watcher.poll(start + debounce * 4).unwrap().is_empty());
+        assert_eq!(watcher.poll(start + debounce * 5).unwrap(), vec![tif]);
+        fs::write(dir.join("b.zip"), b"arriving").unwrap();
+        assert!(watcher.poll(start + debounce * 6).unwrap().is_empty());
+        assert_eq!(
+            watcher.next_settled(start + debounce * 6 + Duration::from_secs(4)),
+            Some(Duration::from_secs(6))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
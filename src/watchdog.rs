@@ -0,0 +1,203 @@
+//! Stopping conversions that run too long: `--timeout` bounds the whole batch and
+//! `--stage-timeout` each stage of an input, such as decoding its pixels or writing its
+//! output, so one pathological input fails instead of hanging the batch.
+
+use crate::{
+    failure::{Classified, FailureClass},
+    timing::{Stage, Timing},
+};
+use anyhow::{anyhow, Result};
+use indicatif::ProgressBar;
+use std::{
+    borrow::Cow,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Parses a duration such as `90s`, `30m`, `1.5h` or `500ms`, or a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let number = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let seconds = match &s[number.len()..] {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        unit => return Err(format!("unknown unit {:?}, expected ms, s, m or h", unit)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration such as 90s or 30m, not {:?}", s))?;
+    if !(number > 0.0 && number.is_finite()) {
+        return Err(format!("expected a positive duration, not {:?}", s));
+    }
+    Ok(Duration::from_secs_f64(number * seconds))
+}
+
+/// Renders a duration as it would be given on the command line.
+fn describe(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    match seconds {
+        s if s >= 3600.0 && s % 3600.0 == 0.0 => format!("{}h", s / 3600.0),
+        s if s >= 60.0 && s % 60.0 == 0.0 => format!("{}m", s / 60.0),
+        s => format!("{}s", s),
+    }
+}
+
+/// Watches one input's conversion, stage by stage.
+pub struct Watchdog {
+    stage_timeout: Option<Duration>,
+    /// When `--timeout` passes for the whole batch.
+    deadline: Option<(Instant, Duration)>,
+    stage: Mutex<(Cow<'static, str>, Instant)>,
+    expired: Mutex<Option<(FailureClass, String)>>,
+    /// What each stage has taken so far, for `--timing`.
+    timing: Mutex<Timing>,
+}
+
+impl Watchdog {
+    pub fn new(stage_timeout: Option<Duration>, deadline: Option<(Instant, Duration)>) -> Watchdog {
+        Watchdog {
+            stage_timeout,
+            deadline,
+            stage: Mutex::new(("waiting to start".into(), Instant::now())),
+            expired: Mutex::new(None),
+            timing: Mutex::new(Timing::default()),
+        }
+    }
+
+    /// A watchdog that never stops anything.
+    pub fn unlimited() -> Watchdog {
+        Watchdog::new(None, None)
+    }
+
+    /// Starts `stage`, showing it on `bar`, with the stage timeout counting from now.
+    pub fn stage(&self, bar: &ProgressBar, stage: impl Into<Cow<'static, str>>) {
+        let stage = stage.into();
+        bar.set_message(stage.clone());
+        *self.stage.lock().unwrap() = (stage, Instant::now());
+    }
+
+    /// Adds `time` spent in `stage` handling `amount` bytes, pixels or rows.
+    pub fn record(&self, stage: Stage, time: Duration, amount: u64) {
+        self.timing.lock().unwrap().add(stage, time, amount);
+    }
+
+    /// What each stage has taken so far.
+    pub fn timing(&self) -> Timing {
+        self.timing.lock().unwrap().clone()
+    }
+
+    /// Whether a limit has passed, after which the conversion should do no more work.
+    /// Once one has, it stays passed.
+    pub fn expired(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// Fails if a limit has passed, for the points where a conversion can stop cleanly.
+    pub fn check(&self) -> Result<()> {
+        match self.reason() {
+            Some((class, reason)) => Err(anyhow!(Classified::new(class, reason))),
+            None => Ok(()),
+        }
+    }
+
+    fn reason(&self) -> Option<(FailureClass, String)> {
+        let mut expired = self.expired.lock().unwrap();
+        if expired.is_none() {
+            let (stage, started) = &*self.stage.lock().unwrap();
+            *expired = match (self.stage_timeout, self.deadline) {
+                // The batch running out of time says nothing about the input itself.
+                (_, Some((deadline, timeout))) if Instant::now() >= deadline => Some((
+                    FailureClass::Other,
+                    format!(
+                        "Stopped while {} as --timeout {} passed",
+                        stage,
+                        describe(timeout)
+                    ),
+                )),
+                (Some(limit), _) if started.elapsed() >= limit => Some((
+                    FailureClass::Unsupported,
+                    format!(
+                        "Stopped as {} took longer than --stage-timeout {}",
+                        stage,
+                        describe(limit)
+                    ),
+                )),
+                _ => None,
+            };
+        }
+        expired.clone()
+    }
+}
+
+/// Runs `work` on a thread of its own and returns its result, or fails as soon as
+/// `watchdog` expires. A stage stuck where it can't check the watchdog, such as inside
+/// the decoder, is left to finish in the background; it stops at its next check without
+/// writing anything.
+pub fn run<T: Send + 'static>(
+    watchdog: Arc<Watchdog>,
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(work());
+    });
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(result) => return result,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("The conversion thread panicked"))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => watchdog.check()?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_duration, run, Watchdog};
+    use crate::failure::FailureClass;
+    use indicatif::ProgressBar;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_watchdog() {
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert!(parse_duration("3d").is_err());
+        assert!(parse_duration("0s").is_err());
+
+        // A stage that never checks is abandoned once it runs too long.
+        let watchdog = Arc::new(Watchdog::new(Some(Duration::from_millis(50)), None));
+        watchdog.stage(&ProgressBar::hidden(), "decoding tif");
+        let started = Instant::now();
+        let stuck = run(watchdog.clone(), || {
+            std::thread::sleep(Duration::from_secs(5));
+            Ok(())
+        });
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let error = stuck.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Stopped as decoding tif took longer than --stage-timeout 0.05s"
+        );
+        assert_eq!(FailureClass::of(&error), FailureClass::Unsupported);
+        // Each stage gets the full limit, and what finishes in time succeeds.
+        let watchdog = Arc::new(Watchdog::new(Some(Duration::from_secs(5)), None));
+        assert_eq!(run(watchdog, || Ok(7)).unwrap(), 7);
+
+        let passed = Watchdog::new(None, Some((Instant::now(), Duration::from_secs(1800))));
+        let error = passed.check().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Stopped while waiting to start as --timeout 30m passed"
+        );
+        assert_eq!(FailureClass::of(&error), FailureClass::Other);
+    }
+}
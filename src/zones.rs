@@ -0,0 +1,251 @@
+//! Zonal statistics: summaries of the raster's pixels inside each polygon of a vector file.
+
+use crate::{
+    georef::GeoTransform,
+    group::{Accumulator, Aggregation},
+    load_tif_contents,
+    mask::{self, Mask},
+    output::{self, Compression},
+    raster::{self, ChunkSize, Layout},
+    DEFAULT_CHUNK_ROWS,
+};
+use anyhow::Result;
+use arrow_array::{
+    Array, ArrayRef, Float64Array, RecordBatch, StringArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{Field, Schema};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tiff::decoder::{Decoder, Limits};
+
+#[derive(clap::Args)]
+pub struct ZonesArgs {
+    /// The raster to summarize.
+    raster: PathBuf,
+    /// The zones, as a `.geojson` or `.shp` file. Each feature is one zone, numbered in
+    /// file order.
+    zones: PathBuf,
+    /// Where to write the statistics. Defaults to the raster's path with a `.zones.parquet`
+    /// or `.zones.csv` extension.
+    #[arg(long = "output", short = 'o')]
+    output: Option<PathBuf>,
+    #[arg(long = "format", value_enum, default_value_t = ZonesFormat::Parquet)]
+    format: ZonesFormat,
+    #[command(flatten)]
+    compression: Compression,
+    /// Write output even when it looks like it will not fit on disk.
+    #[arg(long = "force")]
+    force: bool,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum ZonesFormat {
+    Parquet,
+    Csv,
+}
+
+/// The statistics reported for each zone, in column order, with their descriptions.
+const STATISTICS: [(&str, Aggregation, &str); 4] = [
+    (
+        "sum",
+        Aggregation::Sum,
+        "Sum of the pixel values, weighted by the pixels' relative areas",
+    ),
+    (
+        "mean",
+        Aggregation::Mean,
+        "Mean of the pixel values, weighted by the pixels' relative areas",
+    ),
+    ("min", Aggregation::Min, "Smallest pixel value"),
+    ("max", Aggregation::Max, "Largest pixel value"),
+];
+
+pub fn run(args: &ZonesArgs) -> Result<()> {
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(ProgressStyle::with_template("{prefix:<30} {msg}")?);
+    bar.set_prefix(args.raster.to_string_lossy().to_string());
+    bar.set_message("reading zones");
+    let zones = mask::read_zones(&args.zones)?;
+    let index = Mask::new(&zones.iter().map(|z| z.polygon.clone()).collect::<Vec<_>>())?;
+
+    bar.set_message("reading file");
+    let tif_contents = load_tif_contents(&args.raster)?;
+    let mut decoder =
+        Decoder::new(std::io::Cursor::new(&tif_contents))?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let layout = Layout::from_decoder(&mut decoder)?;
+    let transform = GeoTransform::global(width, height);
+
+    bar.set_message("processing image");
+    bar.set_length(layout.chunk_count() as u64);
+    bar.set_style(ProgressStyle::with_template(
+        "{prefix:<30} {msg} {percent}% {elapsed_precise} {bar_wide}",
+    )?);
+    let pixels = raster::read_pixels(
+        &tif_contents,
+        &layout,
+        ChunkSize::Rows(DEFAULT_CHUNK_ROWS),
+        |x, y, w, h| {
+            index
+                .bounds()
+                .intersects(&transform.rect_bounds(x, y, w, h))
+        },
+        |chunks| bar.inc(chunks),
+        |x, y, value| {
+            (value > 0.0 && value.is_finite())
+                .then(|| {
+                    let (lon, lat) = transform.position(x as f64 + 0.5, y as f64 + 0.5);
+                    let weight = transform.pixel_weight(lon, lat);
+                    index
+                        .polygons_at(lon, lat)
+                        .into_iter()
+                        .map(move |zone| (zone, weight, value))
+                })
+                .into_iter()
+                .flatten()
+        },
+    )?;
+
+    let mut stats = vec![Accumulator::default(); zones.len()];
+    for (zone, weight, value) in pixels {
+        stats[zone as usize].add(value, weight);
+    }
+    let names: Vec<Option<&str>> = zones.iter().map(|z| z.name.as_deref()).collect();
+    let batch = build_batch(&names, &stats)?;
+
+    let output_path = args.output.clone().unwrap_or_else(|| {
+        args.raster.with_extension(match args.format {
+            ZonesFormat::Parquet => "zones.parquet",
+            ZonesFormat::Csv => "zones.csv",
+        })
+    });
+    match args.format {
+        ZonesFormat::Parquet => {
+            let estimate = output::estimate_parquet_size(&batch, args.compression.codec);
+            output::check_free_space(&output_path, estimate, args.force)?;
+            bar.set_message("writing parquet");
+            output::write_parquet(&output_path, &batch, args.compression)?;
+        }
+        ZonesFormat::Csv => {
+            bar.set_message("writing csv");
+            write_csv(&output_path, &names, &stats)?;
+        }
+    }
+
+    bar.finish_with_message("done");
+    Ok(())
+}
+
+/// Finishes one statistic of a zone. Zones without pixels sum to zero but have no mean,
+/// min or max.
+fn statistic(acc: &Accumulator, aggregation: Aggregation) -> Option<f64> {
+    match aggregation {
+        Aggregation::Sum => Some(acc.finish(aggregation)),
+        _ => (acc.count() > 0).then(|| acc.finish(aggregation)),
+    }
+}
+
+/// Lays the statistics out as a table with one row per zone. The `name` column is only
+/// present if some zone has a name.
+fn build_batch(names: &[Option<&str>], stats: &[Accumulator]) -> Result<RecordBatch> {
+    let mut columns = vec![(
+        "zone",
+        Arc::new(UInt32Array::from_iter_values(0..stats.len() as u32)) as ArrayRef,
+        "Position of the zone's feature in the zones file",
+    )];
+    if names.iter().any(Option::is_some) {
+        columns.push((
+            "name",
+            Arc::new(StringArray::from(names.to_vec())),
+            "The feature's `name` property or id",
+        ));
+    }
+    for (name, aggregation, description) in STATISTICS {
+        let values = stats.iter().map(|acc| statistic(acc, aggregation));
+        columns.push((name, Arc::new(Float64Array::from_iter(values)), description));
+    }
+    columns.push((
+        "count",
+        Arc::new(UInt64Array::from_iter_values(
+            stats.iter().map(|acc| acc.count()),
+        )),
+        "Number of pixels with values above zero whose centers are inside the zone",
+    ));
+
+    let fields: Vec<Field> = columns
+        .iter()
+        .map(|(name, array, description)| {
+            let nullable = !matches!(*name, "zone" | "sum" | "count");
+            Field::new(*name, array.data_type().clone(), nullable).with_metadata(HashMap::from([(
+                "description".to_string(),
+                description.to_string(),
+            )]))
+        })
+        .collect();
+    let arrays = columns.into_iter().map(|(_, array, _)| array).collect();
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+}
+
+fn write_csv(path: &Path, names: &[Option<&str>], stats: &[Accumulator]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let has_names = names.iter().any(Option::is_some);
+    let mut header = vec!["zone"];
+    if has_names {
+        header.push("name");
+    }
+    header.extend(STATISTICS.map(|(name, _, _)| name));
+    header.push("count");
+    writeln!(writer, "{}", header.join(","))?;
+
+    for (zone, (name, acc)) in names.iter().zip(stats).enumerate() {
+        let mut fields = vec![zone.to_string()];
+        if has_names {
+            fields.push(name.map(csv_quote).unwrap_or_default());
+        }
+        for (_, aggregation, _) in STATISTICS {
+            fields.push(
+                statistic(acc, aggregation)
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+            );
+        }
+        fields.push(acc.count().to_string());
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn csv_quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_batch, csv_quote};
+    use crate::group::Accumulator;
+
+    #[test]
+    fn test_empty_zones_are_null() {
+        let mut stats = vec![Accumulator::default(); 2];
+        stats[1].add(3.0, 1.0);
+        let batch = build_batch(&[None, None], &stats).unwrap();
+        let schema = batch.schema();
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, ["zone", "sum", "mean", "min", "max", "count"]);
+        assert!(batch.column(1).is_valid(0));
+        assert!(batch.column(2).is_null(0));
+        assert!(batch.column(2).is_valid(1));
+        assert_eq!(csv_quote("a \"b\", c"), "\"a \"\"b\"\", c\"");
+    }
+}